@@ -0,0 +1,109 @@
+//! How fast lines with the wrong number of fields get through parsing and
+//! the warning the serial thread logs for each, and whether the warnings
+//! cost anything while the log file leaves them out.
+//!
+//! `cargo bench --bench line_warnings` parses a million such lines three
+//! times: with no log file (no subscriber), with one at `--log-level error`
+//! and with one taking warnings, written to nowhere. It prints the lines per
+//! second and the allocations per line of each, and fails if leaving the
+//! warnings out allocates anything beyond what having no log file does.
+
+use collect_acc_data::encoding::Encoding;
+use collect_acc_data::parser::{Framing, LineFormat, Parsed};
+use collect_acc_data::schema::FloatFormat;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tracing_subscriber::filter::EnvFilter;
+
+const LINES: usize = 1_000_000;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+// The system allocator, counting allocations
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Parses every line and warns about it as the serial thread does; the time
+// taken and the allocations made
+fn run(format: &LineFormat, lines: &[String]) -> (Duration, u64) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for line in lines {
+        if let Parsed::WrongCount { payload, got } = format.parse(line) {
+            if tracing::enabled!(tracing::Level::WARN) {
+                tracing::warn!(
+                    "Warning: Unexpected number of fields (expected {}, got {}). Data: {}",
+                    format.fields,
+                    got,
+                    payload
+                );
+            }
+        }
+    }
+    (start.elapsed(), ALLOCATIONS.load(Ordering::Relaxed) - allocations)
+}
+
+fn with_log(filter: &str, format: &LineFormat, lines: &[String]) -> (Duration, u64) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(filter))
+        .with_writer(std::io::sink)
+        .with_ansi(false)
+        .finish();
+    tracing::subscriber::with_default(subscriber, || run(format, lines))
+}
+
+fn report(name: &str, (elapsed, allocations): (Duration, u64)) {
+    println!(
+        "{:<24} {:>12.0} lines/s  {:>5.2} allocations/line",
+        name,
+        LINES as f64 / elapsed.as_secs_f64(),
+        allocations as f64 / LINES as f64
+    );
+}
+
+fn main() {
+    let format = LineFormat {
+        pattern: "ACC:".to_string(),
+        delimiter: ',',
+        fields: 4,
+        select: None,
+        computed: Vec::new(),
+        frames: None,
+        framing: Framing::Plain,
+        encoding: Encoding::Plain,
+        inner_frames: None,
+        decimal_comma: false,
+        types: None,
+        float_format: FloatFormat::Passthrough,
+        names: None,
+    };
+    // Three fields where four are expected
+    let lines: Vec<String> = (0..LINES).map(|i| format!("ACC:{},{},{}", i, i * 2, i * 3)).collect();
+    let none = run(&format, &lines);
+    let off = with_log("error", &format, &lines);
+    let on = with_log("warn", &format, &lines);
+    report("no log file", none);
+    report("warnings left out", off);
+    report("warnings logged", on);
+    assert!(
+        off.1 <= none.1,
+        "leaving warnings out made {} allocations more than having no log file",
+        off.1 - none.1
+    );
+}
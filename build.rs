@@ -0,0 +1,11 @@
+fn main() {
+    // The gRPC service of --grpc-listen, from its proto
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/serial_logger.proto");
+        let descriptors = protox::compile(["proto/serial_logger.proto"], ["proto"]).expect("Failed to parse the proto");
+        tonic_build::configure()
+            .compile_fds(descriptors)
+            .expect("Failed to generate the gRPC code");
+    }
+}
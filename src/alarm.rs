@@ -0,0 +1,215 @@
+//! Threshold alarms with `--alarm "CONDITION:LABEL"`.
+//!
+//! Each alarm's condition is evaluated on every data row of a run. When it
+//! becomes true an `alarm` row with the label and the row's values is written
+//! right after that row, a message is printed and an `alarm` event goes to
+//! the event stream, webhook and `--notify`. The alarm then stays raised
+//! until the condition is false again or, with an `--alarm-clear` condition,
+//! until that one is true, which keeps a value hovering around the limit from
+//! raising it over and over. Rows whose values can't be evaluated leave the
+//! alarm as it is. Alarms start cleared in every run and are counted per run.
+
+use collect_acc_data::expr::Expr;
+use serde::Serialize;
+
+/// One `--alarm`, with its state in the current run.
+#[derive(Debug, Clone)]
+pub struct Alarm {
+    pub label: String,
+    // Condition as given, for messages
+    pub condition_text: String,
+    condition: Expr,
+    clear: Option<Expr>,
+    raised: bool,
+    // Times the alarm was raised in the current run
+    count: u64,
+}
+
+/// How often an alarm was raised in a run.
+#[derive(Debug, Clone, Serialize)]
+pub struct AlarmCount {
+    pub label: String,
+    pub count: u64,
+}
+
+impl Alarm {
+    /// Parses `CONDITION:LABEL`; without a label the condition is the label.
+    pub fn parse(spec: &str, fields: usize) -> Result<Alarm, String> {
+        let (text, label) = match spec.rsplit_once(':') {
+            Some((text, label)) if !label.trim().is_empty() => (text, label.trim()),
+            _ => (spec, spec.trim()),
+        };
+        let condition = parse_condition("--alarm", spec, text, fields)?;
+        Ok(Alarm {
+            label: label.to_string(),
+            condition_text: text.trim().to_string(),
+            condition,
+            clear: None,
+            raised: false,
+            count: 0,
+        })
+    }
+
+    /// Sets the `--alarm-clear` condition that lowers the alarm again.
+    pub fn set_clear(&mut self, text: &str, fields: usize) -> Result<(), String> {
+        if self.clear.is_some() {
+            return Err(format!("Alarm '{}' has more than one --alarm-clear", self.label));
+        }
+        self.clear = Some(parse_condition("--alarm-clear", text, text, fields)?);
+        Ok(())
+    }
+
+    /// Updates the alarm with a row's values; true if it was raised by this row.
+    pub fn check(&mut self, values: &[Option<f64>]) -> bool {
+        if self.raised {
+            let cleared = match &self.clear {
+                Some(clear) => clear.holds(values),
+                None => self.condition.holds(values).map(|holds| !holds),
+            };
+            if cleared == Some(true) {
+                self.raised = false;
+            }
+            return false;
+        }
+        if self.condition.holds(values) != Some(true) {
+            return false;
+        }
+        self.raised = true;
+        self.count += 1;
+        true
+    }
+
+    /// Clears the alarm and its count for a new run.
+    pub fn reset(&mut self) {
+        self.raised = false;
+        self.count = 0;
+    }
+
+    pub fn count(&self) -> AlarmCount {
+        AlarmCount {
+            label: self.label.clone(),
+            count: self.count,
+        }
+    }
+}
+
+/// Builds the alarms from the `--alarm` and `--alarm-clear` arguments with
+/// their positions on the command line; each `--alarm-clear` belongs to the
+/// `--alarm` before it.
+pub fn from_args(alarms: Vec<(usize, String)>, clears: Vec<(usize, String)>, fields: usize) -> Result<Vec<Alarm>, String> {
+    let mut parsed: Vec<(usize, Alarm)> = Vec::new();
+    for (index, spec) in alarms {
+        parsed.push((index, Alarm::parse(&spec, fields)?));
+    }
+    for (index, text) in clears {
+        let Some((_, alarm)) = parsed.iter_mut().rev().find(|(alarm_index, _)| *alarm_index < index) else {
+            return Err(format!("--alarm-clear '{}' must follow the --alarm it belongs to", text));
+        };
+        alarm.set_clear(&text, fields)?;
+    }
+    Ok(parsed.into_iter().map(|(_, alarm)| alarm).collect())
+}
+
+fn parse_condition(option: &str, arg: &str, text: &str, fields: usize) -> Result<Expr, String> {
+    let expr = Expr::parse(text).map_err(|e| format!("Invalid {} '{}': {}", option, arg, e))?;
+    if let Some(var) = expr.max_var().filter(|var| *var >= fields) {
+        return Err(format!(
+            "Invalid {} '{}': f{} doesn't exist; rows have {} fields (f0 to f{})",
+            option,
+            arg,
+            var,
+            fields,
+            fields - 1
+        ));
+    }
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A signal in f2 that dips below -9.5 three times, hovering around the
+    // limit on the way
+    const SIGNAL: [f64; 14] = [-1.0, -9.6, -9.4, -9.7, -8.0, -2.0, -9.8, -9.2, -9.6, -8.9, -1.0, -10.0, -9.9, -3.0];
+
+    fn rows(signal: &[f64]) -> impl Iterator<Item = Vec<Option<f64>>> + '_ {
+        signal.iter().map(|value| vec![Some(0.0), Some(1.0), Some(*value), Some(3.0)])
+    }
+
+    fn raised(alarm: &mut Alarm, signal: &[f64]) -> u64 {
+        rows(signal).filter(|values| alarm.check(values)).count() as u64
+    }
+
+    #[test]
+    fn an_alarm_is_raised_on_each_crossing() {
+        let mut alarm = Alarm::parse("f2 < -9.5:impact", 4).unwrap();
+        assert_eq!(alarm.label, "impact");
+        assert_eq!(alarm.condition_text, "f2 < -9.5");
+        // -9.6, -9.7 after -9.4, -9.8, -9.6 after -9.2, -10.0
+        assert_eq!(raised(&mut alarm, &SIGNAL), 5);
+        assert_eq!(alarm.count().count, 5);
+    }
+
+    #[test]
+    fn a_clear_condition_stops_chattering() {
+        let mut alarm = Alarm::parse("f2 < -9.5:impact", 4).unwrap();
+        alarm.set_clear("f2 > -9.0", 4).unwrap();
+        // -9.6, then -9.8 after -8.0, then -10.0 after -8.9
+        assert_eq!(raised(&mut alarm, &SIGNAL), 3);
+    }
+
+    #[test]
+    fn a_raised_alarm_is_not_raised_again() {
+        let mut alarm = Alarm::parse("f2 < -9.5", 4).unwrap();
+        assert_eq!(alarm.label, "f2 < -9.5");
+        assert_eq!(raised(&mut alarm, &[-10.0; 50]), 1);
+    }
+
+    #[test]
+    fn rows_that_cant_be_evaluated_leave_the_alarm_as_it_is() {
+        let mut alarm = Alarm::parse("f2 < -9.5:impact", 4).unwrap();
+        let missing = vec![Some(0.0), Some(1.0), None, Some(3.0)];
+        assert!(!alarm.check(&missing));
+        assert_eq!(raised(&mut alarm, &[-10.0]), 1);
+        assert!(!alarm.check(&missing));
+        // Still raised: no second alarm without a clear row between
+        assert_eq!(raised(&mut alarm, &[-10.0]), 0);
+    }
+
+    #[test]
+    fn reset_clears_the_alarm_and_its_count() {
+        let mut alarm = Alarm::parse("f2 < -9.5:impact", 4).unwrap();
+        assert_eq!(raised(&mut alarm, &[-10.0]), 1);
+        alarm.reset();
+        assert_eq!(alarm.count().count, 0);
+        assert_eq!(raised(&mut alarm, &[-10.0]), 1);
+    }
+
+    #[test]
+    fn clears_belong_to_the_alarm_before_them() {
+        let alarms = from_args(
+            vec![(1, "f2 < -9.5:impact".to_string()), (5, "f1 > 100:overload".to_string())],
+            vec![(3, "f2 > -9.0".to_string())],
+            4,
+        )
+        .unwrap();
+        assert!(alarms[0].clear.is_some());
+        assert!(alarms[1].clear.is_none());
+        let error = from_args(vec![(4, "f2 < 0".to_string())], vec![(2, "f2 > 1".to_string())], 4).unwrap_err();
+        assert!(error.contains("must follow the --alarm"), "{}", error);
+        let error = from_args(
+            vec![(1, "f2 < 0".to_string())],
+            vec![(2, "f2 > 1".to_string()), (3, "f2 > 2".to_string())],
+            4,
+        )
+        .unwrap_err();
+        assert!(error.contains("more than one --alarm-clear"), "{}", error);
+    }
+
+    #[test]
+    fn conditions_must_name_existing_fields() {
+        let error = Alarm::parse("f4 > 1:x", 4).unwrap_err();
+        assert_eq!(error, "Invalid --alarm 'f4 > 1:x': f4 doesn't exist; rows have 4 fields (f0 to f3)");
+    }
+}
@@ -0,0 +1,138 @@
+//! Local notifications with `--notify bell` or `--notify desktop`.
+//!
+//! When a run stops, an `--alarm` is raised, the serial port is lost, the
+//! disk fills up or a `--strict` check fails, and when the logger ends with a fatal error, `bell` rings
+//! the terminal bell and `desktop` shows a desktop notification with a short
+//! summary. Desktop notifications are shown with `notify-rust` (D-Bus on
+//! Linux and the BSDs, the notification centre on macOS, toasts on Windows)
+//! in the background, and need a build with the `desktop-notify` feature;
+//! where showing one fails (no notification daemon, a headless session) one
+//! warning is printed and later notifications are dropped silently.
+
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    thread,
+};
+
+use crate::events::Event;
+
+#[cfg(feature = "desktop-notify")]
+const TITLE: &str = "Serial Logger";
+
+static STYLE: OnceLock<Style> = OnceLock::new();
+// Set once a desktop notification has failed; no more are tried
+static DESKTOP_FAILED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    Bell,
+    Desktop,
+}
+
+impl Style {
+    pub fn parse(text: &str) -> Result<Style, String> {
+        match text {
+            "bell" => Ok(Style::Bell),
+            "desktop" if cfg!(feature = "desktop-notify") => Ok(Style::Desktop),
+            "desktop" => Err("--notify desktop needs a build with the 'desktop-notify' feature".to_string()),
+            _ => Err(format!("Invalid --notify '{}': expected bell or desktop", text)),
+        }
+    }
+}
+
+/// Turns notifications on for the rest of the process, including one for a
+/// fatal error.
+pub fn init(style: Style) {
+    if STYLE.set(style).is_err() {
+        return;
+    }
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let message = info
+            .payload()
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| info.payload().downcast_ref::<&str>().copied())
+            .unwrap_or("The logger stopped with an error");
+        // The process is ending; the notification must be out before it does
+        notify(&format!("Fatal error: {}", message), true);
+        previous(info);
+    }));
+}
+
+/// Notifies about the event if it is one worth walking over for.
+pub fn on_event(event: &Event) {
+    if STYLE.get().is_none() {
+        return;
+    }
+    let text = match event {
+        Event::RunSummary {
+            run,
+            file,
+            rows,
+            duration_secs,
+            ..
+        } => format!("Run {} stopped: {} rows in {:.0}s\n{}", run, rows, duration_secs, file),
+        Event::Alarm {
+            run, label, condition, ..
+        } => format!("Alarm {} in run {}: {}", label, run, condition),
+        Event::SerialError { port, error } => format!("Serial port {} lost: {}", port, error),
+        Event::DiskFull { run, file } => format!("Disk full; run {} stopped\n{}", run, file),
+        Event::CapReached { cap, detail } => format!("--{} reached: {}", cap, detail),
+        Event::StrictFailure { check, detail } => format!("--strict check {} failed: {}", check, detail),
+        Event::OutputError { file, error } => format!("Writing the output keeps failing: {}\n{}", error, file),
+        _ => return,
+    };
+    notify(&text, false);
+}
+
+fn notify(text: &str, wait: bool) {
+    match STYLE.get() {
+        Some(Style::Bell) => {
+            // Stderr, so the bell doesn't end up in the JSON event stream
+            let mut err = std::io::stderr().lock();
+            let _ = err.write_all(b"\x07");
+            let _ = err.flush();
+        }
+        Some(Style::Desktop) if !DESKTOP_FAILED.load(Ordering::Relaxed) => {
+            let text = text.to_string();
+            let show = move || {
+                if let Err(e) = show_desktop(&text) {
+                    desktop_failed(&e);
+                }
+            };
+            if wait {
+                show();
+            } else {
+                thread::spawn(show);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn desktop_failed(reason: &str) {
+    if !DESKTOP_FAILED.swap(true, Ordering::Relaxed) {
+        esay!("Warning: Desktop notifications are unavailable ({}); not sending any more.", reason);
+    }
+}
+
+#[cfg(feature = "desktop-notify")]
+fn show_desktop(text: &str) -> Result<(), String> {
+    notify_rust::Notification::new()
+        .summary(TITLE)
+        .body(text)
+        .show()
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// Style::parse refuses `desktop` in a build without the feature
+#[cfg(not(feature = "desktop-notify"))]
+fn show_desktop(_text: &str) -> Result<(), String> {
+    Err("this build has no desktop notifications".to_string())
+}
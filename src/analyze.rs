@@ -0,0 +1,390 @@
+//! `analyze`: per-run statistics of an existing output file, read-only.
+//!
+//! Runs are found by the library's run segmentation, so a run without a stop
+//! row (a crashed session) is analyzed up to where it ends and flagged as
+//! truncated. For each run the duration, the number of data rows, the
+//! effective sample rate and per-field min/max/mean/stddev are reported;
+//! fields without numeric values (e.g. `--operator` columns) are left out.
+//! Pauses between consecutive data rows longer than the gap threshold are
+//! counted. In a run with `heartbeat` rows each gap is also classified: if
+//! rows of some kind kept arriving within the threshold, the logger was alive
+//! and the device was silent; otherwise the logger itself wasn't running.
+//! `gap` rows written with `--gap-marker` are counted and their durations,
+//! measured by the logger with sub-second precision, summed. `alarm` rows are
+//! counted per label, and `cmd` rows written with `--audit` are listed. Units
+//! from a `units` row are shown with the field names. A `clockstep` row moves
+//! the times before it onto the stepped clock, so a step of the system clock
+//! is neither a gap nor a shorter run.
+//! Timestamps have whole-second resolution, so durations, rates and gaps are
+//! accurate to about a second.
+
+use collect_acc_data::logfile::{parse_timestamp, Kind, LogReader, Row};
+use collect_acc_data::runs::{self, Segment};
+use serde::Serialize;
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::Path};
+
+use crate::alarm::AlarmCount;
+use crate::stats::{FieldStats, FieldSummary};
+
+/// Statistics of one run.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunAnalysis {
+    pub run: Option<i64>,
+    pub run_id: String,
+    pub started: String,
+    // Time of the stop row, or of the last row of a truncated run
+    pub stopped: String,
+    // The run has no stop row
+    pub truncated: bool,
+    pub duration_secs: f64,
+    pub rows: u64,
+    // Data rows per second over the run's duration
+    pub sample_rate: Option<f64>,
+    pub fields: Vec<FieldSummary>,
+    // Pauses between data rows longer than the threshold, and the longest pause
+    pub gaps: u64,
+    pub longest_gap_secs: f64,
+    pub heartbeats: u64,
+    // Gaps with the logger alive (heartbeats kept coming) and with the logger
+    // not running; None in a run without heartbeats
+    pub silent_gaps: Option<u64>,
+    pub dead_gaps: Option<u64>,
+    // Gap rows written by the logger (--gap-marker) and their total duration
+    pub gap_markers: u64,
+    pub gap_marker_secs: f64,
+    // Steps of the system clock (--clock-step) and their sum
+    pub clock_steps: u64,
+    pub clock_step_secs: f64,
+    // Alarm rows by label, in the order each was first raised
+    pub alarms: Vec<AlarmCount>,
+    // Operator commands written with --audit, in order
+    pub commands: Vec<Command>,
+}
+
+/// An operator command from a `cmd` row.
+#[derive(Debug, Clone, Serialize)]
+pub struct Command {
+    pub timestamp: String,
+    pub command: String,
+}
+
+/// Everything `analyze` found in a file.
+#[derive(Debug, Default, Serialize)]
+pub struct Analysis {
+    pub file: String,
+    pub gap_threshold_secs: f64,
+    // Units of the columns from the file's units row (--units)
+    pub units: BTreeMap<String, String>,
+    pub runs: Vec<RunAnalysis>,
+    // Data rows outside any run
+    pub rows_outside_runs: u64,
+    // Commands issued between runs
+    pub commands_outside_runs: Vec<Command>,
+    // Rows that couldn't be read
+    pub unreadable_rows: u64,
+}
+
+// A run being read
+struct RunState {
+    analysis: RunAnalysis,
+    start: Option<chrono::NaiveDateTime>,
+    // Time of the latest data row, for gaps, and of the latest row of any kind
+    last_data: Option<chrono::NaiveDateTime>,
+    last_seen: String,
+    fields: Vec<FieldStats>,
+    // Time of the latest row of any kind, and whether the rows since the
+    // latest data row had a pause longer than the gap threshold
+    last_row: Option<chrono::NaiveDateTime>,
+    logger_paused: bool,
+    silent_gaps: u64,
+    dead_gaps: u64,
+}
+
+impl RunState {
+    fn new(run: Option<i64>, run_id: &str, timestamp: &str) -> RunState {
+        RunState {
+            analysis: RunAnalysis {
+                run,
+                run_id: run_id.to_string(),
+                started: timestamp.to_string(),
+                stopped: String::new(),
+                truncated: false,
+                duration_secs: 0.0,
+                rows: 0,
+                sample_rate: None,
+                fields: Vec::new(),
+                gaps: 0,
+                longest_gap_secs: 0.0,
+                heartbeats: 0,
+                silent_gaps: None,
+                dead_gaps: None,
+                gap_markers: 0,
+                gap_marker_secs: 0.0,
+                clock_steps: 0,
+                clock_step_secs: 0.0,
+                alarms: Vec::new(),
+                commands: Vec::new(),
+            },
+            start: parse_timestamp(timestamp),
+            last_data: None,
+            last_seen: timestamp.to_string(),
+            fields: Vec::new(),
+            last_row: parse_timestamp(timestamp),
+            logger_paused: false,
+            silent_gaps: 0,
+            dead_gaps: 0,
+        }
+    }
+
+    // Notes that the logger wrote a row at `timestamp`
+    fn alive_at(&mut self, timestamp: &str, gap_threshold: f64) {
+        let Some(time) = parse_timestamp(timestamp) else { return };
+        if let Some(last) = self.last_row {
+            if (time - last).num_milliseconds() as f64 / 1000.0 > gap_threshold {
+                self.logger_paused = true;
+            }
+        }
+        self.last_row = Some(time);
+    }
+
+    fn add_row(&mut self, timestamp: &str, values: &[String], gap_threshold: f64) {
+        self.analysis.rows += 1;
+        if self.fields.len() < values.len() {
+            self.fields.resize(values.len(), FieldStats::default());
+        }
+        for (stats, value) in self.fields.iter_mut().zip(values) {
+            if let Ok(value) = value.trim().parse::<f64>() {
+                stats.add(value);
+            }
+        }
+        self.alive_at(timestamp, gap_threshold);
+        let time = parse_timestamp(timestamp);
+        if let (Some(last), Some(time)) = (self.last_data, time) {
+            let gap = (time - last).num_milliseconds() as f64 / 1000.0;
+            if gap > gap_threshold {
+                self.analysis.gaps += 1;
+                if self.logger_paused {
+                    self.dead_gaps += 1;
+                } else {
+                    self.silent_gaps += 1;
+                }
+            }
+            self.analysis.longest_gap_secs = self.analysis.longest_gap_secs.max(gap);
+        }
+        self.last_data = time.or(self.last_data);
+        self.logger_paused = false;
+    }
+
+    // A gap row; its text is the duration, e.g. `5.213s`
+    fn gap_marker(&mut self, text: &str) {
+        self.analysis.gap_markers += 1;
+        if let Ok(secs) = text.trim().trim_end_matches('s').parse::<f64>() {
+            self.analysis.gap_marker_secs += secs;
+        }
+    }
+
+    // A clockstep row; its text is the step, e.g. `-40.012s`. The times kept
+    // so far are moved by it, so they compare with the rows after it.
+    fn clock_step(&mut self, text: &str) {
+        let Ok(secs) = text.trim().trim_end_matches('s').parse::<f64>() else { return };
+        self.analysis.clock_steps += 1;
+        self.analysis.clock_step_secs += secs;
+        let step = chrono::TimeDelta::milliseconds((secs * 1000.0).round() as i64);
+        for time in [&mut self.start, &mut self.last_data, &mut self.last_row] {
+            *time = time.map(|time| time + step);
+        }
+    }
+
+    fn alarm(&mut self, label: &str) {
+        let alarms = &mut self.analysis.alarms;
+        match alarms.iter_mut().find(|alarm| alarm.label == label) {
+            Some(alarm) => alarm.count += 1,
+            None => alarms.push(AlarmCount {
+                label: label.to_string(),
+                count: 1,
+            }),
+        }
+    }
+
+    fn finish(mut self, stopped: Option<&str>, columns: &[String]) -> RunAnalysis {
+        let analysis = &mut self.analysis;
+        analysis.truncated = stopped.is_none();
+        analysis.stopped = stopped.unwrap_or(&self.last_seen).to_string();
+        if let (Some(start), Some(stop)) = (self.start, parse_timestamp(&analysis.stopped)) {
+            analysis.duration_secs = ((stop - start).num_milliseconds() as f64 / 1000.0).max(0.0);
+        }
+        if analysis.heartbeats > 0 {
+            analysis.silent_gaps = Some(self.silent_gaps);
+            analysis.dead_gaps = Some(self.dead_gaps);
+        }
+        if analysis.duration_secs > 0.0 {
+            analysis.sample_rate = Some(analysis.rows as f64 / analysis.duration_secs);
+        }
+        analysis.fields = self
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(i, stats)| stats.summary(columns.get(i).map_or("field", String::as_str)))
+            .filter(|summary| summary.count > 0)
+            .collect();
+        self.analysis
+    }
+}
+
+/// Reads the output file at `path` and analyzes each of its runs.
+pub fn run(path: &Path, gap_threshold: f64) -> Result<Analysis, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let mut analysis = Analysis {
+        file: path.display().to_string(),
+        gap_threshold_secs: gap_threshold,
+        ..Analysis::default()
+    };
+    let mut segments = runs::segment(LogReader::new(BufReader::new(file)));
+    let mut columns = Vec::new();
+    let mut current: Option<RunState> = None;
+    while let Some(segment) = segments.next() {
+        if columns.is_empty() {
+            columns = segments.inner().columns();
+            analysis.units = segments.inner().column_units().into_iter().collect();
+        }
+        let segment = match segment {
+            Ok(segment) => segment,
+            Err(e) => {
+                esay!("Warning: {}; skipped.", e);
+                analysis.unreadable_rows += 1;
+                continue;
+            }
+        };
+        match segment {
+            Segment::Begin(line) => {
+                if let Row::Start { timestamp, run, run_id } | Row::Continued { timestamp, run, run_id } = &line.row {
+                    current = Some(RunState::new(*run, run_id, timestamp));
+                }
+            }
+            Segment::Row(line) => {
+                let Some(state) = current.as_mut() else { continue };
+                match &line.row {
+                    Row::Data { timestamp, values } => {
+                        state.add_row(timestamp, values, gap_threshold);
+                        state.last_seen = timestamp.clone();
+                    }
+                    Row::Marker { kind, timestamp, text, .. } => {
+                        match kind {
+                            Kind::Heartbeat => state.analysis.heartbeats += 1,
+                            Kind::Gap => state.gap_marker(text),
+                            Kind::ClockStep => state.clock_step(text),
+                            Kind::Alarm => state.alarm(text),
+                            Kind::Cmd => state.analysis.commands.push(Command {
+                                timestamp: timestamp.clone(),
+                                command: text.clone(),
+                            }),
+                            _ => {}
+                        }
+                        state.alive_at(timestamp, gap_threshold);
+                        state.last_seen = timestamp.clone();
+                    }
+                    _ => {}
+                }
+            }
+            Segment::End(stop) => {
+                let stopped = stop.as_ref().and_then(|line| match &line.row {
+                    Row::Stop { timestamp, .. } | Row::Continued { timestamp, .. } => Some(timestamp.as_str()),
+                    _ => None,
+                });
+                if let Some(state) = current.take() {
+                    analysis.runs.push(state.finish(stopped, &columns));
+                }
+            }
+            Segment::Outside(line) => match line.row {
+                Row::Data { .. } => analysis.rows_outside_runs += 1,
+                Row::Marker { kind: Kind::Cmd, timestamp, text, .. } => {
+                    analysis.commands_outside_runs.push(Command { timestamp, command: text });
+                }
+                _ => {}
+            },
+        }
+    }
+    Ok(analysis)
+}
+
+impl Analysis {
+    /// Prints the analysis as a table per run.
+    pub fn print(&self) {
+        say!("{}: {} runs", self.file, self.runs.len());
+        for run in &self.runs {
+            let number = run.run.map_or("?".to_string(), |n| n.to_string());
+            say!("");
+            say!(
+                "run {} {}  {} to {}{}",
+                number,
+                run.run_id,
+                run.started,
+                run.stopped,
+                if run.truncated { "  (no stop row, truncated)" } else { "" }
+            );
+            let rate = run.sample_rate.map_or("-".to_string(), |rate| format!("{:.1}", rate));
+            say!("  {:.1}s, {} rows, {} rows/s", run.duration_secs, run.rows, rate);
+            if run.gaps > 0 {
+                say!(
+                    "  GAPS: {} pauses longer than {}s (longest {:.1}s)",
+                    run.gaps,
+                    self.gap_threshold_secs,
+                    run.longest_gap_secs
+                );
+                if let (Some(silent), Some(dead)) = (run.silent_gaps, run.dead_gaps) {
+                    say!("    {} with the device silent, {} with the logger not running", silent, dead);
+                }
+            }
+            if run.gap_markers > 0 {
+                say!("  GAP ROWS: {} totalling {:.3}s", run.gap_markers, run.gap_marker_secs);
+            }
+            if run.clock_steps > 0 {
+                say!("  CLOCK STEPS: {} totalling {:+.3}s (left out of the duration and gaps)", run.clock_steps, run.clock_step_secs);
+            }
+            if !run.alarms.is_empty() {
+                let alarms: Vec<String> =
+                    run.alarms.iter().map(|alarm| format!("{} x{}", alarm.label, alarm.count)).collect();
+                say!("  ALARMS: {}", alarms.join(", "));
+            }
+            if !run.commands.is_empty() {
+                say!("  COMMANDS:");
+                for command in &run.commands {
+                    say!("    {}  {}", command.timestamp, command.command);
+                }
+            }
+            if run.fields.is_empty() {
+                continue;
+            }
+            say!("  {:<14} {:>12} {:>12} {:>12} {:>12}", "field", "min", "max", "mean", "stddev");
+            for field in &run.fields {
+                let value = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{:.4}", v));
+                let name = match self.units.get(&field.name) {
+                    Some(unit) => format!("{} ({})", field.name, unit),
+                    None => field.name.clone(),
+                };
+                say!(
+                    "  {:<14} {:>12} {:>12} {:>12} {:>12}",
+                    name,
+                    value(field.min),
+                    value(field.max),
+                    value(field.mean),
+                    value(field.stddev)
+                );
+            }
+        }
+        if self.rows_outside_runs > 0 {
+            say!("");
+            say!("{} data rows are outside any run.", self.rows_outside_runs);
+        }
+        if !self.commands_outside_runs.is_empty() {
+            say!("");
+            say!("Commands between runs:");
+            for command in &self.commands_outside_runs {
+                say!("  {}  {}", command.timestamp, command.command);
+            }
+        }
+        if self.unreadable_rows > 0 {
+            say!("{} rows could not be read and were skipped.", self.unreadable_rows);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Removal of terminal escape sequences (colors, cursor movement, titles)
+//! that firmware consoles embed in their output.
+//!
+//! Lines are stripped once they have been assembled, so a sequence split
+//! across two reads is removed like any other. Handled are CSI sequences
+//! (`ESC [ ... final`, e.g. `ESC[0m`), OSC and other string sequences
+//! (`ESC ] ... BEL` or `ESC ] ... ESC \`) and short `ESC <final>` forms such
+//! as `ESC ( B`. An unterminated sequence at the end of a line is dropped.
+
+use std::borrow::Cow;
+
+const ESC: char = '\x1b';
+const BEL: char = '\x07';
+// Single-character (C1) forms of `ESC [` and `ESC ]`
+const CSI: char = '\u{9b}';
+const OSC: char = '\u{9d}';
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Text,
+    // After ESC
+    Escape,
+    // ESC followed by intermediate bytes, waiting for the final byte
+    EscapeIntermediate,
+    // Inside `ESC [`, up to the final byte
+    Csi,
+    // Inside an OSC/DCS/PM/APC string, up to BEL or ESC \
+    String,
+    // ESC seen inside a string, possibly the start of ESC \
+    StringEscape,
+}
+
+/// Returns `line` without escape sequences; borrows when there are none.
+pub fn strip(line: &str) -> Cow<'_, str> {
+    if !line.contains([ESC, CSI, OSC]) {
+        return Cow::Borrowed(line);
+    }
+    let mut out = String::with_capacity(line.len());
+    let mut state = State::Text;
+    for c in line.chars() {
+        state = match (state, c) {
+            (State::Text, ESC) => State::Escape,
+            (State::Text, CSI) => State::Csi,
+            (State::Text, OSC) => State::String,
+            (State::Text, c) => {
+                out.push(c);
+                State::Text
+            }
+            (State::Escape, '[') => State::Csi,
+            (State::Escape, ']' | 'P' | 'X' | '^' | '_') => State::String,
+            (State::Escape, '\x20'..='\x2f') => State::EscapeIntermediate,
+            (State::EscapeIntermediate, '\x20'..='\x2f') => State::EscapeIntermediate,
+            // The final byte ends a short sequence
+            (State::Escape | State::EscapeIntermediate, _) => State::Text,
+            (State::Csi, '\x40'..='\x7e') => State::Text,
+            (State::Csi, _) => State::Csi,
+            (State::String, BEL) => State::Text,
+            (State::String, ESC) => State::StringEscape,
+            (State::String, _) => State::String,
+            (State::StringEscape, '\\') => State::Text,
+            (State::StringEscape, _) => State::String,
+        };
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colored_lines() {
+        assert_eq!(strip("\x1b[32mUDP packet contents:\x1b[0m1,2,3"), "UDP packet contents:1,2,3");
+        assert_eq!(strip("\x1b[1;31;40mERR\x1b[m 5"), "ERR 5");
+        assert_eq!(strip("\x1b[38;5;208m-2.45\x1b[39m,0.12"), "-2.45,0.12");
+        assert_eq!(strip("\x1b[38;2;255;128;0mtrue color\x1b[0m"), "true color");
+        assert_eq!(strip("\u{9b}31mC1 form\u{9b}0m"), "C1 form");
+    }
+
+    #[test]
+    fn cursor_movement_and_erasing() {
+        assert_eq!(strip("\x1b[2K\rvalue: 42"), "\rvalue: 42");
+        assert_eq!(strip("\x1b[10;20H\x1b[?25lx\x1b[?25h\x1b[3A\x1b[J"), "x");
+        assert_eq!(strip("a\x1b7b\x1b8c\x1b(Bd\x1b%Ge"), "abcde");
+    }
+
+    #[test]
+    fn titles_and_strings() {
+        assert_eq!(strip("\x1b]0;device console\x07data"), "data");
+        assert_eq!(strip("\x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\"), "link");
+        assert_eq!(strip("\x1bPq#0;2;0;0;0\x1b\\after"), "after");
+        assert_eq!(strip("\u{9d}title\x07rest"), "rest");
+    }
+
+    #[test]
+    fn nothing_but_escapes() {
+        assert_eq!(strip("\x1b[0m\x1b[2J\x1b[H\x1b]0;t\x07"), "");
+        // Unterminated at the end of the line
+        assert_eq!(strip("1,2,3\x1b[38;5"), "1,2,3");
+        assert_eq!(strip("\x1b"), "");
+    }
+
+    #[test]
+    fn plain_lines_are_borrowed() {
+        assert!(matches!(strip("UDP packet contents:1,2,3"), Cow::Borrowed(_)));
+        assert!(matches!(strip("\x1b[0m1"), Cow::Owned(_)));
+    }
+
+    #[test]
+    fn sequences_split_across_reads() {
+        // The serial thread appends each read to the line until its
+        // terminator arrives, so the halves of a sequence meet again
+        let reads: [&[u8]; 3] = [b"\x1b[3", b"2mUDP packet contents:1,\x1b", b"[0m2,3\r\n"];
+        let line = reads.concat();
+        assert_eq!(crate::parser::clean_line(&line, true, ','), "UDP packet contents:1,2,3");
+        assert_eq!(crate::parser::clean_line(&line, false, ','), "\x1b[32mUDP packet contents:1,\x1b[0m2,3");
+    }
+}
@@ -0,0 +1,316 @@
+//! Writing output as an Arrow IPC stream, for `--format arrow`.
+//!
+//! The stream is a schema message followed by record batches, each holding
+//! up to a fixed number of rows, and an end-of-stream marker when the file
+//! is closed. Readers such as Polars (`read_ipc_stream`) and pyarrow
+//! (`pa.ipc.open_stream`) take it directly, without parsing text. The
+//! messages are written by the `arrow-ipc` crate.
+//!
+//! Every column is nullable; an empty cell, or one that doesn't parse as the
+//! column's type, is null. Timestamps are stored as milliseconds since 1970
+//! in UTC, converted from the local time of the Timestamp column.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use arrow_array::builder::{Float64Builder, Int64Builder, StringBuilder, TimestampMillisecondBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{ArrowError, Schema, SchemaRef, TimeUnit};
+
+use crate::logfile::utc_millis;
+use crate::parser::LineFormat;
+use crate::schema::ColumnType;
+
+/// Column types the logger writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataType {
+    Utf8,
+    Int64,
+    Float64,
+    // Milliseconds since 1970 in UTC
+    TimestampMs,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub data_type: DataType,
+}
+
+/// The type of each value column of `format`, as named by
+/// `LineFormat::column_names`: fields declared as integers with `--types`
+/// are Int64, declared floats and computed columns Float64, and the other
+/// fields Utf8.
+pub fn column_types(format: &LineFormat) -> Vec<DataType> {
+    let mut types: Vec<DataType> = match &format.types {
+        Some(schema) => schema
+            .columns
+            .iter()
+            .map(|column| match column {
+                ColumnType::Signed { .. } | ColumnType::Unsigned { .. } => DataType::Int64,
+                ColumnType::Float { .. } => DataType::Float64,
+                ColumnType::Text => DataType::Utf8,
+            })
+            .collect(),
+        None => vec![DataType::Utf8; format.field_count()],
+    };
+    types.extend(format.computed.iter().map(|_| DataType::Float64));
+    types
+}
+
+/// Writes rows to an Arrow IPC stream, a record batch at a time.
+pub struct StreamWriter<W: Write> {
+    out: arrow_ipc::writer::StreamWriter<W>,
+    rows: Rows,
+    batch_rows: usize,
+    finished: bool,
+}
+
+impl<W: Write> StreamWriter<W> {
+    /// Writes the schema, with `metadata` as its custom metadata.
+    pub fn new(out: W, fields: Vec<Field>, metadata: &[(String, String)], batch_rows: usize) -> io::Result<Self> {
+        let rows = Rows::new(fields, metadata);
+        let out = arrow_ipc::writer::StreamWriter::try_new(out, rows.schema()).map_err(io_error)?;
+        Ok(StreamWriter {
+            out,
+            rows,
+            batch_rows: batch_rows.max(1),
+            finished: false,
+        })
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        self.rows.fields()
+    }
+
+    /// Adds a row of text cells, one per field (missing ones are null), and
+    /// writes a record batch once enough rows have been collected.
+    pub fn push_row<T: AsRef<[u8]>>(&mut self, cells: &[T]) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other("the Arrow stream has already been finished"));
+        }
+        self.rows.push(cells);
+        if self.rows.len() >= self.batch_rows {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the batches written so far; rows of an incomplete batch stay
+    /// in memory until it fills up or the stream is finished.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.get_mut().flush()
+    }
+
+    /// Writes the rows collected so far and the end-of-stream marker; the
+    /// stream is complete afterwards. Finishing again does nothing.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        if !self.rows.is_empty() {
+            self.write_batch()?;
+        }
+        self.out.finish().map_err(io_error)?;
+        self.finished = true;
+        self.out.get_mut().flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.out.get_ref()
+    }
+
+    fn write_batch(&mut self) -> io::Result<()> {
+        let batch = self.rows.take()?;
+        self.out.write(&batch).map_err(io_error)
+    }
+}
+
+/// Rows of text cells collected into a record batch of the logger's
+/// columns, for the Arrow and Parquet writers.
+pub(crate) struct Rows {
+    schema: SchemaRef,
+    fields: Vec<Field>,
+    columns: Vec<Column>,
+    len: usize,
+}
+
+impl Rows {
+    /// No rows yet, for a schema with `metadata` as its custom metadata.
+    pub(crate) fn new(fields: Vec<Field>, metadata: &[(String, String)]) -> Rows {
+        let schema = Arc::new(
+            Schema::new(fields.iter().map(|field| arrow_schema::Field::new(&field.name, arrow_type(field.data_type), true)).collect::<Vec<_>>())
+                .with_metadata(metadata.iter().cloned().collect::<HashMap<_, _>>()),
+        );
+        let columns = fields.iter().map(|field| Column::new(field.data_type)).collect();
+        Rows {
+            schema,
+            fields,
+            columns,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    pub(crate) fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds a row, one cell per field (missing ones are null).
+    pub(crate) fn push<T: AsRef<[u8]>>(&mut self, cells: &[T]) {
+        for (i, column) in self.columns.iter_mut().enumerate() {
+            let cell = cells.get(i).map_or(&b""[..], |cell| cell.as_ref());
+            column.push(&String::from_utf8_lossy(cell));
+        }
+        self.len += 1;
+    }
+
+    /// The rows so far as a batch, leaving none.
+    pub(crate) fn take(&mut self) -> io::Result<RecordBatch> {
+        let columns = self.columns.iter_mut().map(Column::finish).collect();
+        self.len = 0;
+        RecordBatch::try_new(Arc::clone(&self.schema), columns).map_err(io_error)
+    }
+}
+
+pub(crate) fn io_error(error: ArrowError) -> io::Error {
+    match error {
+        ArrowError::IoError(_, error) => error,
+        other => io::Error::other(other),
+    }
+}
+
+fn arrow_type(data_type: DataType) -> arrow_schema::DataType {
+    match data_type {
+        DataType::Utf8 => arrow_schema::DataType::Utf8,
+        DataType::Int64 => arrow_schema::DataType::Int64,
+        DataType::Float64 => arrow_schema::DataType::Float64,
+        DataType::TimestampMs => arrow_schema::DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+    }
+}
+
+// Values of one column for the batch being collected
+enum Column {
+    Utf8(StringBuilder),
+    Int64(Int64Builder),
+    Float64(Float64Builder),
+    TimestampMs(TimestampMillisecondBuilder),
+}
+
+impl Column {
+    fn new(data_type: DataType) -> Column {
+        match data_type {
+            DataType::Utf8 => Column::Utf8(StringBuilder::new()),
+            DataType::Int64 => Column::Int64(Int64Builder::new()),
+            DataType::Float64 => Column::Float64(Float64Builder::new()),
+            DataType::TimestampMs => Column::TimestampMs(TimestampMillisecondBuilder::new().with_timezone("UTC")),
+        }
+    }
+
+    fn push(&mut self, cell: &str) {
+        match self {
+            Column::Utf8(builder) => builder.append_option((!cell.is_empty()).then_some(cell)),
+            Column::Int64(builder) => builder.append_option(cell.trim().parse().ok()),
+            Column::Float64(builder) => builder.append_option(cell.trim().parse().ok()),
+            Column::TimestampMs(builder) => builder.append_option(utc_millis(cell)),
+        }
+    }
+
+    // The batch's array, leaving the column empty for the next batch
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Column::Utf8(builder) => Arc::new(builder.finish()),
+            Column::Int64(builder) => Arc::new(builder.finish()),
+            Column::Float64(builder) => Arc::new(builder.finish()),
+            Column::TimestampMs(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, Int64Type, TimestampMillisecondType};
+    use arrow_array::Array;
+    use arrow_ipc::reader::StreamReader;
+
+    fn fields() -> Vec<Field> {
+        [("Timestamp", DataType::TimestampMs), ("name", DataType::Utf8), ("count", DataType::Int64), ("x", DataType::Float64)]
+            .into_iter()
+            .map(|(name, data_type)| Field {
+                name: name.to_string(),
+                data_type,
+            })
+            .collect()
+    }
+
+    fn read(data: &[u8]) -> (SchemaRef, Vec<RecordBatch>) {
+        let reader = StreamReader::try_new(io::Cursor::new(data.to_vec()), None).unwrap();
+        let schema = reader.schema();
+        (schema, reader.map(Result::unwrap).collect())
+    }
+
+    #[test]
+    fn a_reference_reader_reads_every_batch() {
+        let metadata = [("port".to_string(), "COM3".to_string())];
+        let mut writer = StreamWriter::new(Vec::new(), fields(), &metadata, 2).unwrap();
+        for row in [
+            ["2024-05-01 12:00:00.250", "a", "1", "0.5"],
+            ["", "", "n/a", "-2"],
+            ["2024-05-01 12:00:02", "c", "3", ""],
+        ] {
+            writer.push_row(&row).unwrap();
+        }
+        writer.finish().unwrap();
+        writer.finish().unwrap();
+        let (schema, batches) = read(writer.get_ref());
+
+        assert_eq!(schema.metadata().get("port").map(String::as_str), Some("COM3"));
+        assert_eq!(schema.field(0).data_type(), &arrow_schema::DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())));
+        assert!(schema.fields().iter().all(|field| field.is_nullable()));
+        assert_eq!(batches.iter().map(RecordBatch::num_rows).collect::<Vec<_>>(), [2, 1]);
+        let all = arrow_select::concat::concat_batches(&schema, &batches).unwrap();
+        let times = all.column(0).as_primitive::<TimestampMillisecondType>();
+        assert_eq!(times.value(0), utc_millis("2024-05-01 12:00:00.250").unwrap());
+        assert!(times.is_null(1));
+        assert_eq!(times.value(2) - times.value(0), 1750);
+        let names = all.column(1).as_string::<i32>();
+        assert_eq!((names.value(0), names.is_null(1), names.value(2)), ("a", true, "c"));
+        let counts = all.column(2).as_primitive::<Int64Type>();
+        assert_eq!((counts.value(0), counts.is_null(1), counts.value(2)), (1, true, 3));
+        let x = all.column(3).as_primitive::<Float64Type>();
+        assert_eq!((x.value(0), x.value(1), x.is_null(2)), (0.5, -2.0, true));
+    }
+
+    #[test]
+    fn timestamps_are_utc_whatever_the_local_zone() {
+        // Read as local time, then stored as the instant it names
+        let local = chrono::DateTime::parse_from_rfc3339("2024-05-01T12:00:00.500+02:00").unwrap().with_timezone(&chrono::Local);
+        let cell = local.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+        assert_eq!(utc_millis(&cell), Some(1_714_557_600_500));
+        assert_eq!(utc_millis(""), None);
+    }
+
+    #[test]
+    fn a_stream_without_rows_has_its_schema() {
+        let mut writer = StreamWriter::new(Vec::new(), fields(), &[], 10).unwrap();
+        writer.finish().unwrap();
+        let (schema, batches) = read(writer.get_ref());
+        assert_eq!(schema.fields().len(), 4);
+        assert!(batches.is_empty());
+        assert!(writer.push_row(&["x"]).is_err());
+    }
+}
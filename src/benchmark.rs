@@ -0,0 +1,356 @@
+//! `--benchmark`: how long a line takes from the port to the file, and how
+//! fast lines can come before some are lost.
+//!
+//! A simulator writes numbered lines into a pseudo-terminal at each rate from
+//! 100 Hz to 10 kHz for a few seconds, while the logger reads the other end
+//! exactly as it reads a serial port, parses the lines with the given
+//! options and writes the rows to a scratch file. For every data row the
+//! time from its arrival (when the read that completed the line returned) to
+//! its write to the file is recorded. Outside a benchmark that hook is a
+//! single flag check. A rate is sustained if every line sent became a row and
+//! the simulator could send at the full rate.
+
+use collect_acc_data::encoding::Encoding;
+use collect_acc_data::parser::{Framing, LineFormat};
+use serde::Serialize;
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::holdoff::Policy;
+use crate::hooks::Hooks;
+use crate::meta::{MetaStyle, Metadata};
+use crate::outage::Recovery;
+use crate::serial::{self, InputOptions, PortList};
+use crate::session::{OutputSettings, Session};
+use crate::sink::{CsvDialect, SyncPolicy};
+
+pub const RATES: [u32; 7] = [100, 200, 500, 1000, 2000, 5000, 10000];
+// How long each rate is sent for
+const STEP: Duration = Duration::from_secs(2);
+// Rows still coming in after the simulator stops are waited for this long
+// past the last one
+const DRAIN: Duration = Duration::from_secs(1);
+// Below this share of the rate the simulator couldn't keep up
+const KEPT_UP: f64 = 0.95;
+
+// Latencies are only recorded while a benchmark is running
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SAMPLES: Mutex<Vec<Duration>> = Mutex::new(Vec::new());
+
+/// Records the time from a row's arrival until now, when it has been
+/// written; a no-op unless `--benchmark` is running.
+pub fn record_latency(arrival: Instant) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let latency = arrival.elapsed();
+    SAMPLES.lock().unwrap().push(latency);
+}
+
+/// What the benchmark is run with; parsing and output settings are the
+/// user's, so their cost is measured too.
+pub struct Options {
+    pub format: LineFormat,
+    pub strip_ansi: bool,
+    pub sync: SyncPolicy,
+    pub dialect: CsvDialect,
+    pub meta_style: MetaStyle,
+    // Keep a raw capture as logging does unless --no-raw
+    pub raw: bool,
+}
+
+/// One rate's results; latencies in microseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct Step {
+    pub rate_hz: u32,
+    pub achieved_hz: f64,
+    pub sent: u64,
+    pub written: u64,
+    pub dropped: u64,
+    pub p50_us: f64,
+    pub p95_us: f64,
+    pub p99_us: f64,
+    pub max_us: f64,
+    pub sustained: bool,
+}
+
+/// Written with `--benchmark-json`, to compare releases.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub version: String,
+    pub step_secs: f64,
+    pub sync: String,
+    pub steps: Vec<Step>,
+    // Highest rate sustained, 0 if none was
+    pub max_sustained_hz: u32,
+}
+
+impl Report {
+    pub fn print(&self) {
+        say!("");
+        say!("Benchmark ({}s per rate, --sync {}); latency from arrival to write:", self.step_secs, self.sync);
+        say!(
+            "  {:>8}  {:>10}  {:>7}  {:>7}  {:>7}  {:>9}  {:>9}  {:>9}  {:>9}  sustained",
+            "rate", "achieved", "sent", "written", "dropped", "p50", "p95", "p99", "max"
+        );
+        for step in &self.steps {
+            say!(
+                "  {:>5} Hz  {:>7.0} Hz  {:>7}  {:>7}  {:>7}  {:>9}  {:>9}  {:>9}  {:>9}  {}",
+                step.rate_hz,
+                step.achieved_hz,
+                step.sent,
+                step.written,
+                step.dropped,
+                micros(step.p50_us),
+                micros(step.p95_us),
+                micros(step.p99_us),
+                micros(step.max_us),
+                if step.sustained { "yes" } else { "no" }
+            );
+        }
+        match self.max_sustained_hz {
+            0 => say!("No rate was sustained."),
+            rate => say!("Highest rate sustained: {} Hz.", rate),
+        }
+    }
+
+    pub fn write_json(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).expect("Benchmark report always serializes");
+        std::fs::write(path, json + "\n").map_err(|e| format!("Failed to write benchmark file {}: {}", path, e))
+    }
+}
+
+fn micros(us: f64) -> String {
+    format!("{:.0} µs", us)
+}
+
+/// Runs the simulator at every rate in turn and reports on each.
+pub fn run(options: Options) -> Result<Report, String> {
+    let format = options.format;
+    if format.frames.is_some() || format.inner_frames.is_some() || format.framing != Framing::Plain {
+        return Err("--benchmark sends text lines; it can't be used with binary frames or --framing cobs".to_string());
+    }
+    if format.encoding != Encoding::Plain {
+        return Err("--benchmark sends plain text fields; it can't be used with --payload-encoding".to_string());
+    }
+    let (mut simulator, port, device) = pty::open()?;
+    let scratch = std::env::temp_dir().join(format!("serial_logger_benchmark_{}", std::process::id()));
+    std::fs::create_dir_all(&scratch)
+        .map_err(|e| format!("Failed to create benchmark directory {}: {}", scratch.display(), e))?;
+    let result = (|| {
+        let sample = Sample::new(&format);
+        let settings = OutputSettings {
+            template: scratch.join("benchmark.csv").display().to_string(),
+            per_run: false,
+            port: port.clone(),
+            baud: 115200,
+            meta: Metadata::for_session(options.meta_style, &port, 115200, &[]),
+            sidecar: false,
+            sync: options.sync,
+            dialect: options.dialect,
+            columns: format.column_names(),
+            id_columns: Vec::new(),
+            units: Vec::new(),
+            alarms: Vec::new(),
+            retention: None,
+            manifest: None,
+            dry_run: false,
+            columnar: None,
+            prebuffer: None,
+            dedup_window: None,
+            on_change: None,
+            clock_step: None,
+            xlsx_max_rows: None,
+            tail: None,
+            audit: false,
+            recovery: Recovery::default(),
+            run_policy: Policy::default(),
+            prompt_notes: false,
+            summary_rows: false,
+            rotate_align: None,
+            strict: Vec::new(),
+            raw_file: options.raw.then(|| scratch.join("benchmark.raw")),
+            rejects_file: None,
+            state_file: None,
+        };
+        let mut session = Session::new(settings, Hooks::default())?;
+        let shared = Arc::clone(&session.shared);
+        let input = InputOptions {
+            format,
+            strip_ansi: options.strip_ansi,
+            skip_blank_lines: true,
+            max_line_length: usize::MAX,
+            skip_lines: 0,
+            read_timeout: serial::DEFAULT_READ_TIMEOUT,
+            wait_for: None,
+            auto_start: None,
+            device_commands: None,
+            hw_trigger: None,
+            modem_interval: None,
+            log_breaks: false,
+            observers: Vec::new(),
+            gap_marker: None,
+            port: None,
+            rotate: None,
+        };
+        let (_control, control_rx) = mpsc::channel();
+        let ports = PortList {
+            ports: vec![port.clone()],
+            failback: false,
+        };
+        let _serial_thread = serial::spawn(ports, 115200, Arc::clone(&shared), control_rx, input);
+
+        let mut steps = Vec::new();
+        for rate in RATES {
+            say!("Sending {} Hz for {}s...", rate, STEP.as_secs());
+            let before = shared.rows_written.load(Ordering::Relaxed);
+            session.start(&format!("{} Hz", rate));
+            SAMPLES.lock().unwrap().clear();
+            ENABLED.store(true, Ordering::Relaxed);
+            let (sent, elapsed) = sample.send(&mut simulator, rate)?;
+            // Let the rows still on their way come in
+            let mut written = 0;
+            let mut last_progress = Instant::now();
+            while written < sent && last_progress.elapsed() < DRAIN {
+                thread::sleep(Duration::from_millis(10));
+                let now_written = shared.rows_written.load(Ordering::Relaxed) - before;
+                if now_written > written {
+                    written = now_written;
+                    last_progress = Instant::now();
+                }
+            }
+            ENABLED.store(false, Ordering::Relaxed);
+            session.stop();
+            let mut latencies = std::mem::take(&mut *SAMPLES.lock().unwrap());
+            latencies.sort();
+            let achieved_hz = tenths(sent as f64 / elapsed.as_secs_f64());
+            let dropped = sent.saturating_sub(written);
+            steps.push(Step {
+                rate_hz: rate,
+                achieved_hz,
+                sent,
+                written,
+                dropped,
+                p50_us: percentile(&latencies, 0.50),
+                p95_us: percentile(&latencies, 0.95),
+                p99_us: percentile(&latencies, 0.99),
+                max_us: percentile(&latencies, 1.0),
+                sustained: dropped == 0 && achieved_hz >= rate as f64 * KEPT_UP,
+            });
+        }
+        Ok(Report {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            step_secs: STEP.as_secs_f64(),
+            sync: sync_name(options.sync),
+            max_sustained_hz: steps.iter().filter(|step| step.sustained).map(|step| step.rate_hz).max().unwrap_or(0),
+            steps,
+        })
+    })();
+    let _ = std::fs::remove_dir_all(&scratch);
+    // The serial thread reads the terminal until exit; closing it now would
+    // have it report the device as lost
+    std::mem::forget(simulator);
+    std::mem::forget(device);
+    result
+}
+
+// Nearest-rank percentile in microseconds, 0 without samples
+fn percentile(sorted: &[Duration], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((p * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+    tenths(sorted[rank - 1].as_secs_f64() * 1e6)
+}
+
+fn tenths(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+fn sync_name(sync: SyncPolicy) -> String {
+    match sync {
+        SyncPolicy::Never => "never".to_string(),
+        SyncPolicy::EveryFlush => "every-flush".to_string(),
+        SyncPolicy::Interval(interval) => format!("interval:{}", interval.as_secs_f64()),
+    }
+}
+
+// Lines the parser accepts: the --match text, then a sequence number and
+// small integers (valid for any --types) joined by the delimiter
+struct Sample {
+    pattern: String,
+    delimiter: char,
+    fields: usize,
+}
+
+impl Sample {
+    fn new(format: &LineFormat) -> Sample {
+        Sample {
+            pattern: format.pattern.clone(),
+            delimiter: format.delimiter,
+            fields: format.fields,
+        }
+    }
+
+    fn line(&self, seq: u64) -> String {
+        let mut line = self.pattern.clone();
+        line.push_str(&seq.to_string());
+        for field in 1..self.fields {
+            line.push(self.delimiter);
+            line.push_str(&((seq + field as u64) % 1000).to_string());
+        }
+        line.push('\n');
+        line
+    }
+
+    // Sends STEP's worth of lines at `rate`, in small batches as they fall
+    // due; returns how many were sent and how long it took. A logger that
+    // can't keep up slows the sending down as the terminal's buffer fills,
+    // and sending gives up at twice STEP.
+    fn send(&self, port: &mut impl Write, rate: u32) -> Result<(u64, Duration), String> {
+        let total = (rate as f64 * STEP.as_secs_f64()) as u64;
+        let started = Instant::now();
+        let mut sent = 0;
+        while sent < total && started.elapsed() < STEP * 2 {
+            let due = ((started.elapsed().as_secs_f64() * rate as f64) as u64 + 1).min(total);
+            if due > sent {
+                let batch: String = (sent..due).map(|seq| self.line(seq)).collect();
+                port.write_all(batch.as_bytes())
+                    .map_err(|e| format!("Failed to write to the benchmark port: {}", e))?;
+                sent = due;
+            }
+            thread::sleep(Duration::from_micros(200));
+        }
+        Ok((sent, started.elapsed()))
+    }
+}
+
+#[cfg(unix)]
+mod pty {
+    use serialport::TTYPort;
+
+    /// The simulator's end of a new pseudo-terminal, the name of the end the
+    /// logger opens, and that end, which must stay open for the terminal to
+    /// keep working.
+    pub fn open() -> Result<(TTYPort, String, TTYPort), String> {
+        let (simulator, device) =
+            TTYPort::pair().map_err(|e| format!("Failed to create a pseudo-terminal for --benchmark: {}", e))?;
+        let name = serialport::SerialPort::name(&device)
+            .ok_or_else(|| "Failed to create a pseudo-terminal for --benchmark: it has no name".to_string())?;
+        Ok((simulator, name, device))
+    }
+}
+
+#[cfg(not(unix))]
+mod pty {
+    pub fn open() -> Result<(std::io::Sink, String, ()), String> {
+        Err("--benchmark simulates a device on a pseudo-terminal, which this system doesn't have".to_string())
+    }
+}
@@ -0,0 +1,716 @@
+//! A scripted stand-in for a serial device, for testing the logger end to end
+//! without hardware.
+//!
+//! `mock_device SCRIPT --pty /tmp/mockdev` plays a JSON script into a new
+//! pseudo-terminal linked at `/tmp/mockdev`, which the logger opens as its
+//! port. `--device PATH` writes to an existing terminal instead (e.g. one end
+//! of a `socat` pair) and `--tcp ADDRESS` to each client that connects. The
+//! script is a list of steps:
+//!
+//! ```json
+//! {
+//!   "match": "UDP packet contents:",
+//!   "fields": 4,
+//!   "steps": [
+//!     {"send": "BOOT mock v1"},
+//!     {"send": "READY"},
+//!     {"data": {"count": 200, "rate_hz": 100}},
+//!     {"corrupt": 3},
+//!     {"pause_ms": 1500},
+//!     {"disconnect_ms": 2000},
+//!     {"data": {"count": 50}}
+//!   ]
+//! }
+//! ```
+//!
+//! `send` writes one line as given. `data` writes numbered lines the logger
+//! accepts with the same `--match`, `--delimiter` and `--fields`: a sequence
+//! number, which continues from one `data` step to the next, then small
+//! integers derived from it, so every run of a script produces the same rows.
+//! `corrupt` writes lines with the match text but one field too few.
+//! `disconnect_ms` drops the connection (the terminal disappears, or the
+//! client is hung up on) and comes back after the given time. When the script
+//! ends, so does the mock.
+//!
+//! `--faults "disconnect@30s,garbage@60s:5s,dup@90s"` injects anomalies into
+//! the data at the given times since the script started, for seeing what the
+//! logger does when things go wrong: `disconnect@T[:D]` drops the connection
+//! for D (2s), and the lines that fall due meanwhile are lost;
+//! `garbage@T[:D]` replaces lines with bytes like those of a wrong baud rate
+//! for D (5s); `dup@T[:N]` sends the next N lines (1) twice; `oversize@T[:B]`
+//! sends one line of B bytes (65536) with the match text; `gap@T[:D]` sends
+//! nothing for D (5s), and the lines due meanwhile are lost. Times are like
+//! `500ms`, `30s` or `2m`. Faults fall due while a `data` step is sending. Each
+//! one is written to stdout as a line of JSON in the style of the logger's
+//! `--events-json`, with the sequence number of the next data line, so a test
+//! can match a fault to what the logger made of it.
+
+use clap::{Arg, ArgGroup, Command};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    io::Write,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Script {
+    // The logger's --match, --delimiter and --fields for data lines
+    #[serde(rename = "match", default = "default_match")]
+    match_text: String,
+    #[serde(default = "default_delimiter")]
+    delimiter: char,
+    #[serde(default = "default_fields")]
+    fields: usize,
+    #[serde(default = "default_line_end")]
+    line_end: String,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum Step {
+    Send(String),
+    Data(Data),
+    Corrupt(u64),
+    PauseMs(u64),
+    DisconnectMs(u64),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Data {
+    count: u64,
+    #[serde(default = "default_rate")]
+    rate_hz: f64,
+}
+
+/// An anomaly `--faults` injects into the data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fault {
+    Disconnect(Duration),
+    Garbage(Duration),
+    Dup(u64),
+    Oversize(usize),
+    Gap(Duration),
+}
+
+impl Fault {
+    fn name(self) -> &'static str {
+        match self {
+            Fault::Disconnect(_) => "disconnect",
+            Fault::Garbage(_) => "garbage",
+            Fault::Dup(_) => "dup",
+            Fault::Oversize(_) => "oversize",
+            Fault::Gap(_) => "gap",
+        }
+    }
+
+    fn detail(self) -> String {
+        match self {
+            Fault::Disconnect(time) | Fault::Garbage(time) | Fault::Gap(time) => format!("{:.3}s", time.as_secs_f64()),
+            Fault::Dup(lines) => format!("{} lines", lines),
+            Fault::Oversize(bytes) => format!("{} bytes", bytes),
+        }
+    }
+}
+
+/// The faults still to come and those going on, timed from when the script
+/// started.
+struct Faults {
+    started: Instant,
+    // Sorted by time
+    pending: VecDeque<(Duration, Fault)>,
+    garbage_until: Option<Instant>,
+    gap_until: Option<Instant>,
+    // Lines still to be sent twice
+    dups: u64,
+}
+
+#[derive(Serialize)]
+struct FaultEvent {
+    event: &'static str,
+    timestamp: String,
+    fault: &'static str,
+    detail: String,
+    elapsed_secs: f64,
+    // The sequence number of the next data line
+    seq: u64,
+}
+
+fn default_match() -> String {
+    "UDP packet contents:".to_string()
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn default_fields() -> usize {
+    4
+}
+
+fn default_line_end() -> String {
+    "\n".to_string()
+}
+
+fn default_rate() -> f64 {
+    10.0
+}
+
+/// Where the script is played.
+enum Target {
+    Pty(PathBuf),
+    Device(String),
+    Tcp(TcpListener),
+}
+
+/// An open connection; dropping it disconnects.
+struct Connection {
+    writer: Box<dyn Write>,
+    // The terminal end the logger opens, kept open while connected so the
+    // terminal stays up
+    device: Option<Box<dyn serialport::SerialPort>>,
+    // The link made for --pty, removed on disconnect
+    link: Option<PathBuf>,
+}
+
+// Longest a disconnect waits for the logger to read what was sent
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // A pseudo-terminal loses what is still unread when it goes away
+        if let Some(device) = &self.device {
+            let started = Instant::now();
+            while device.bytes_to_read().is_ok_and(|queued| queued > 0) && started.elapsed() < DRAIN_TIMEOUT {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        if let Some(link) = &self.link {
+            let _ = std::fs::remove_file(link);
+        }
+    }
+}
+
+impl Target {
+    fn connect(&self) -> Result<Connection, String> {
+        match self {
+            Target::Pty(link) => pty::open(link),
+            Target::Device(path) => {
+                let port = serialport::new(path, 115_200)
+                    .open()
+                    .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+                Ok(Connection {
+                    writer: Box::new(port),
+                    device: None,
+                    link: None,
+                })
+            }
+            Target::Tcp(listener) => {
+                eprintln!("Waiting for a client on {}...", listener.local_addr().map_err(|e| e.to_string())?);
+                let (stream, peer) = listener.accept().map_err(|e| format!("Failed to accept a client: {}", e))?;
+                eprintln!("Client connected from {}.", peer);
+                let _ = stream.set_nodelay(true);
+                Ok(Connection {
+                    writer: Box::new(stream),
+                    device: None,
+                    link: None,
+                })
+            }
+        }
+    }
+}
+
+fn main() {
+    let matches = Command::new("mock_device")
+        .about("Plays a scripted device into a pseudo-terminal, a terminal or a TCP connection, for testing the logger")
+        .arg(Arg::new("script").value_name("SCRIPT").help("JSON script to play").required(true))
+        .arg(
+            Arg::new("pty")
+                .long("pty")
+                .value_name("LINK")
+                .help("Create a pseudo-terminal and link it at LINK, for the logger's --port"),
+        )
+        .arg(
+            Arg::new("device")
+                .long("device")
+                .value_name("PATH")
+                .help("Write to an existing terminal, e.g. one end of a socat pair"),
+        )
+        .arg(
+            Arg::new("tcp")
+                .long("tcp")
+                .value_name("ADDRESS")
+                .help("Listen on ADDRESS (e.g., 127.0.0.1:5555) and play the script to the client"),
+        )
+        .arg(
+            Arg::new("faults")
+                .long("faults")
+                .value_name("FAULTS")
+                .help("Inject faults into the data, e.g. disconnect@30s,garbage@60s:5s,dup@90s,oversize@100s,gap@120s:5s"),
+        )
+        .group(ArgGroup::new("target").args(["pty", "device", "tcp"]).required(true))
+        .get_matches();
+
+    let path = matches.get_one::<String>("script").expect("The script is required");
+    let script = load(Path::new(path)).unwrap_or_else(|e| fail(&e));
+    let faults = matches
+        .get_one::<String>("faults")
+        .map_or(Ok(Vec::new()), |text| parse_faults(text))
+        .unwrap_or_else(|e| fail(&e));
+    let target = if let Some(link) = matches.get_one::<String>("pty") {
+        Target::Pty(PathBuf::from(link))
+    } else if let Some(path) = matches.get_one::<String>("device") {
+        Target::Device(path.clone())
+    } else {
+        let address = matches.get_one::<String>("tcp").expect("One target is required");
+        Target::Tcp(TcpListener::bind(address).unwrap_or_else(|e| fail(&format!("Failed to listen on {}: {}", address, e))))
+    };
+    if let Err(e) = play(&script, &target, faults) {
+        fail(&e);
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("mock_device: {}", message);
+    std::process::exit(1);
+}
+
+fn load(path: &Path) -> Result<Script, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let script: Script = serde_json::from_str(&text).map_err(|e| format!("Invalid script {}: {}", path.display(), e))?;
+    if script.fields == 0 {
+        return Err(format!("Invalid script {}: fields must be at least 1", path.display()));
+    }
+    Ok(script)
+}
+
+fn play(script: &Script, target: &Target, faults: Vec<(Duration, Fault)>) -> Result<(), String> {
+    let mut faults = Faults {
+        started: Instant::now(),
+        pending: faults.into(),
+        garbage_until: None,
+        gap_until: None,
+        dups: 0,
+    };
+    let mut connection = target.connect()?;
+    let mut seq = 0;
+    for step in &script.steps {
+        let written = match step {
+            Step::Send(text) => write_line(&mut connection, text, script),
+            Step::Data(data) => {
+                send_data(&mut connection, target, data, &mut seq, script, &mut faults)?;
+                Ok(())
+            }
+            Step::Corrupt(count) => (0..*count).try_for_each(|n| {
+                let fields = data_fields(seq + n, script.fields - 1, script.delimiter);
+                write_line(&mut connection, &format!("{}{}", script.match_text, fields), script)
+            }),
+            Step::PauseMs(ms) => {
+                thread::sleep(Duration::from_millis(*ms));
+                Ok(())
+            }
+            Step::DisconnectMs(ms) => {
+                disconnect(&mut connection, target, Duration::from_millis(*ms))?;
+                Ok(())
+            }
+        };
+        written.map_err(|e| format!("Failed to write: {}", e))?;
+    }
+    connection.writer.flush().map_err(|e| format!("Failed to write: {}", e))
+}
+
+// Drops the connection and makes a new one after `time`
+fn disconnect(connection: &mut Connection, target: &Target, time: Duration) -> Result<(), String> {
+    // The old connection has to go before the new one is made, as both would
+    // use the same link
+    *connection = Connection {
+        writer: Box::new(std::io::sink()),
+        device: None,
+        link: None,
+    };
+    eprintln!("Disconnected for {} ms.", time.as_millis());
+    thread::sleep(time);
+    *connection = target.connect()?;
+    Ok(())
+}
+
+fn write_line(connection: &mut Connection, text: &str, script: &Script) -> std::io::Result<()> {
+    connection.writer.write_all(format!("{}{}", text, script.line_end).as_bytes())
+}
+
+// Sends `count` data lines at `rate_hz`, writing each batch as it falls due,
+// with the faults due meanwhile
+fn send_data(
+    connection: &mut Connection,
+    target: &Target,
+    data: &Data,
+    seq: &mut u64,
+    script: &Script,
+    faults: &mut Faults,
+) -> Result<(), String> {
+    let write_failed = |e: std::io::Error| format!("Failed to write: {}", e);
+    let started = Instant::now();
+    let first = *seq;
+    let end = first + data.count;
+    while *seq < end {
+        let mut lost = false;
+        while let Some(fault) = faults.next_due(*seq) {
+            match fault {
+                Fault::Disconnect(time) => {
+                    disconnect(connection, target, time)?;
+                    lost = true;
+                }
+                Fault::Garbage(time) => faults.garbage_until = Some(Instant::now() + time),
+                Fault::Gap(time) => faults.gap_until = Some(Instant::now() + time),
+                Fault::Dup(lines) => faults.dups += lines,
+                Fault::Oversize(bytes) => {
+                    let filler = "9".repeat(bytes.saturating_sub(script.match_text.len()));
+                    write_line(connection, &format!("{}{}", script.match_text, filler), script).map_err(write_failed)?;
+                }
+            }
+        }
+        let due = (first + (started.elapsed().as_secs_f64() * data.rate_hz) as u64 + 1).min(end);
+        let now = Instant::now();
+        if lost || faults.gap_until.is_some_and(|until| now < until) {
+            // Lines due while the device was away are never sent
+            *seq = due;
+        }
+        let garbage = faults.garbage_until.is_some_and(|until| now < until);
+        let mut batch = Vec::new();
+        while *seq < due {
+            let mut line = script.match_text.clone().into_bytes();
+            line.extend(data_fields(*seq, script.fields, script.delimiter).into_bytes());
+            if garbage {
+                line = scramble(&line, *seq);
+            }
+            line.extend(script.line_end.as_bytes());
+            if faults.dups > 0 && !garbage {
+                faults.dups -= 1;
+                batch.extend(&line);
+            }
+            batch.extend(line);
+            *seq += 1;
+        }
+        connection.writer.write_all(&batch).map_err(write_failed)?;
+        thread::sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+impl Faults {
+    // Takes the next fault that has fallen due and reports it on stdout
+    fn next_due(&mut self, seq: u64) -> Option<Fault> {
+        let elapsed = self.started.elapsed();
+        let (at, fault) = *self.pending.front().filter(|(at, _)| *at <= elapsed)?;
+        self.pending.pop_front();
+        eprintln!("Injecting {} ({}) at {:.3}s.", fault.name(), fault.detail(), at.as_secs_f64());
+        let event = FaultEvent {
+            event: "fault_injected",
+            timestamp: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            fault: fault.name(),
+            detail: fault.detail(),
+            elapsed_secs: elapsed.as_secs_f64(),
+            seq,
+        };
+        println!("{}", serde_json::to_string(&event).expect("Fault events serialize"));
+        Some(fault)
+    }
+}
+
+// What a line looks like at the wrong baud rate: as many bytes, mostly
+// outside ASCII, and none of them a line break
+fn scramble(line: &[u8], seq: u64) -> Vec<u8> {
+    let mut state = seq.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    line.iter()
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            match (state >> 56) as u8 {
+                b'\n' | b'\r' => 0xFF,
+                byte => byte,
+            }
+        })
+        .collect()
+}
+
+// The sequence number and small integers derived from it, like the
+// logger's --benchmark lines
+fn data_fields(seq: u64, fields: usize, delimiter: char) -> String {
+    if fields == 0 {
+        return String::new();
+    }
+    let mut line = seq.to_string();
+    for field in 1..fields {
+        line.push(delimiter);
+        line.push_str(&((seq + field as u64) % 1000).to_string());
+    }
+    line
+}
+
+// A comma-separated list such as `disconnect@30s,garbage@60s:5s,dup@90s`,
+// sorted by time
+fn parse_faults(text: &str) -> Result<Vec<(Duration, Fault)>, String> {
+    let mut faults = Vec::new();
+    for item in text.split(',').map(str::trim) {
+        let invalid = |reason: &str| format!("Invalid fault '{}' in --faults: {}", item, reason);
+        let (name, rest) = item.split_once('@').ok_or_else(|| invalid("expected NAME@TIME[:ARG]"))?;
+        let (at, arg) = match rest.split_once(':') {
+            Some((at, arg)) => (at, Some(arg)),
+            None => (rest, None),
+        };
+        let at = parse_time(at).ok_or_else(|| invalid("expected a time such as 500ms, 30s or 2m"))?;
+        let time = |default: u64| {
+            arg.map_or(Some(Duration::from_secs(default)), parse_time)
+                .ok_or_else(|| invalid("expected a duration such as 500ms, 5s or 1m"))
+        };
+        let count = |default: u64| {
+            arg.map_or(Some(default), |arg| arg.parse().ok().filter(|count| *count > 0))
+                .ok_or_else(|| invalid("expected a count of at least 1"))
+        };
+        let fault = match name {
+            "disconnect" => Fault::Disconnect(time(2)?),
+            "garbage" => Fault::Garbage(time(5)?),
+            "dup" => Fault::Dup(count(1)?),
+            "oversize" => Fault::Oversize(count(65536)? as usize),
+            "gap" => Fault::Gap(time(5)?),
+            _ => return Err(invalid("expected disconnect, garbage, dup, oversize or gap")),
+        };
+        faults.push((at, fault));
+    }
+    faults.sort_by_key(|(at, _)| *at);
+    Ok(faults)
+}
+
+// A time such as `500ms`, `1.5s` or `2m`
+fn parse_time(text: &str) -> Option<Duration> {
+    let split = text.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().ok().filter(|number: &f64| number.is_finite())?;
+    let secs = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        _ => return None,
+    };
+    Some(Duration::from_secs_f64(secs))
+}
+
+#[cfg(unix)]
+mod pty {
+    use serialport::TTYPort;
+    use std::path::Path;
+
+    use super::Connection;
+
+    // A new pseudo-terminal with its device end linked at `link`
+    pub fn open(link: &Path) -> Result<Connection, String> {
+        let (mock, device) = TTYPort::pair().map_err(|e| format!("Failed to create a pseudo-terminal: {}", e))?;
+        let name = serialport::SerialPort::name(&device).ok_or("Failed to create a pseudo-terminal: it has no name")?;
+        if std::fs::symlink_metadata(link).is_ok_and(|meta| meta.file_type().is_symlink()) {
+            let _ = std::fs::remove_file(link);
+        }
+        std::os::unix::fs::symlink(&name, link)
+            .map_err(|e| format!("Failed to link {} to {}: {}", link.display(), name, e))?;
+        eprintln!("Device at {} ({}).", link.display(), name);
+        Ok(Connection {
+            writer: Box::new(mock),
+            device: Some(Box::new(device)),
+            link: Some(link.to_path_buf()),
+        })
+    }
+}
+
+#[cfg(not(unix))]
+mod pty {
+    use std::path::Path;
+
+    use super::Connection;
+
+    pub fn open(_link: &Path) -> Result<Connection, String> {
+        Err("--pty needs pseudo-terminals, which this system doesn't have; use --device with a virtual COM port pair or --tcp".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    const DATA: &str = r#"{"steps": [{"data": {"count": 100, "rate_hz": 100}}]}"#;
+
+    // Plays `script` with `faults` to a TCP client that connects again
+    // whenever it is hung up on: what it received on each connection
+    fn play_to_client(script: &str, faults: Vec<(Duration, Fault)>) -> Vec<Vec<u8>> {
+        let script: Script = serde_json::from_str(script).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let client = {
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut connections = Vec::new();
+                while !done.load(Ordering::Relaxed) {
+                    let Ok(mut stream) = TcpStream::connect(address) else {
+                        thread::sleep(Duration::from_millis(10));
+                        continue;
+                    };
+                    let mut received = Vec::new();
+                    let _ = stream.read_to_end(&mut received);
+                    if !received.is_empty() {
+                        connections.push(received);
+                    }
+                }
+                connections
+            })
+        };
+        let target = Target::Tcp(listener);
+        play(&script, &target, faults).unwrap();
+        done.store(true, Ordering::Relaxed);
+        // Hangs up on a client waiting to be accepted again
+        drop(target);
+        client.join().unwrap()
+    }
+
+    fn lines(received: &[u8]) -> Vec<&[u8]> {
+        received.strip_suffix(b"\n").unwrap_or(received).split(|byte| *byte == b'\n').collect()
+    }
+
+    // The sequence numbers of the data lines received
+    fn sequence(received: &[u8]) -> Vec<u64> {
+        lines(received)
+            .into_iter()
+            .map(|line| {
+                let line = std::str::from_utf8(line).unwrap().strip_prefix("UDP packet contents:").unwrap();
+                line.split(',').next().unwrap().parse().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn faults_are_parsed_and_sorted_by_time() {
+        let faults = parse_faults("gap@2m:5s, disconnect@30s,garbage@60s:500ms,dup@1.5s:3,oversize@90s:100").unwrap();
+        assert_eq!(
+            faults,
+            [
+                (Duration::from_millis(1500), Fault::Dup(3)),
+                (Duration::from_secs(30), Fault::Disconnect(Duration::from_secs(2))),
+                (Duration::from_secs(60), Fault::Garbage(Duration::from_millis(500))),
+                (Duration::from_secs(90), Fault::Oversize(100)),
+                (Duration::from_secs(120), Fault::Gap(Duration::from_secs(5))),
+            ]
+        );
+        // Defaults for what isn't given
+        assert_eq!(
+            parse_faults("garbage@0s,dup@0s,oversize@0s,gap@0s").unwrap(),
+            [
+                (Duration::ZERO, Fault::Garbage(Duration::from_secs(5))),
+                (Duration::ZERO, Fault::Dup(1)),
+                (Duration::ZERO, Fault::Oversize(65536)),
+                (Duration::ZERO, Fault::Gap(Duration::from_secs(5))),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_faults_are_refused() {
+        for (text, reason) in [
+            ("disconnect", "expected NAME@TIME[:ARG]"),
+            ("disconnect@30", "expected a time"),
+            ("disconnect@30h", "expected a time"),
+            ("disconnect@30s:soon", "expected a duration"),
+            ("dup@1s:0", "expected a count of at least 1"),
+            ("oversize@1s:-5", "expected a count of at least 1"),
+            ("noise@1s", "expected disconnect, garbage, dup, oversize or gap"),
+            ("dup@1s,,gap@2s", "expected NAME@TIME[:ARG]"),
+        ] {
+            let e = parse_faults(text).unwrap_err();
+            assert!(e.contains(reason), "{}: {}", text, e);
+        }
+    }
+
+    #[test]
+    fn times_take_a_unit() {
+        assert_eq!(parse_time("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_time("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_time("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_time("0s"), Some(Duration::ZERO));
+        for text in ["30", "s", "", "1.2.3s", "-1s", "5 s"] {
+            assert_eq!(parse_time(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn without_faults_every_line_is_sent_once() {
+        let connections = play_to_client(DATA, Vec::new());
+        assert_eq!(connections.len(), 1);
+        assert_eq!(sequence(&connections[0]), (0..100).collect::<Vec<u64>>());
+        assert_eq!(lines(&connections[0])[7], b"UDP packet contents:7,8,9,10");
+    }
+
+    #[test]
+    fn dup_sends_lines_twice() {
+        let connections = play_to_client(DATA, vec![(Duration::ZERO, Fault::Dup(2))]);
+        let mut expected = vec![0, 0, 1, 1];
+        expected.extend(2..100);
+        assert_eq!(sequence(&connections[0]), expected);
+    }
+
+    #[test]
+    fn oversize_sends_one_long_line_with_the_match_text() {
+        let connections = play_to_client(DATA, vec![(Duration::from_millis(200), Fault::Oversize(4096))]);
+        let lines = lines(&connections[0]);
+        let long: Vec<&&[u8]> = lines.iter().filter(|line| line.len() > 100).collect();
+        assert_eq!(long.len(), 1);
+        assert_eq!(long[0].len(), 4096);
+        assert!(long[0].starts_with(b"UDP packet contents:999"));
+        // The data lines all come through besides it
+        assert_eq!(lines.len(), 101);
+    }
+
+    #[test]
+    fn garbage_replaces_lines_with_unreadable_bytes() {
+        let connections = play_to_client(DATA, vec![(Duration::ZERO, Fault::Garbage(Duration::from_secs(60)))]);
+        let lines = lines(&connections[0]);
+        // A line for every data line, as long as the one it replaces, but
+        // without the match text and not text at all
+        assert_eq!(lines.len(), 100);
+        assert_eq!(lines[42].len(), "UDP packet contents:42,43,44,45".len());
+        assert!(lines.iter().all(|line| !line.starts_with(b"UDP") && !line.contains(&b'\r')));
+        assert!(lines.iter().all(|line| std::str::from_utf8(line).is_err()));
+        // The same bytes every time
+        assert_eq!(scramble(b"UDP packet contents:1,2,3,4", 1), scramble(b"UDP packet contents:1,2,3,4", 1));
+    }
+
+    #[test]
+    fn a_gap_loses_the_lines_due_meanwhile() {
+        let connections = play_to_client(DATA, vec![(Duration::from_millis(300), Fault::Gap(Duration::from_millis(300)))]);
+        assert_eq!(connections.len(), 1);
+        let sequence = sequence(&connections[0]);
+        assert_eq!(sequence.first(), Some(&0));
+        assert_eq!(sequence.last(), Some(&99));
+        assert!(sequence.windows(2).all(|pair| pair[0] < pair[1]));
+        // About 30 lines at 100 per second
+        let lost = 100 - sequence.len();
+        assert!((15..=45).contains(&lost), "lost {}: {:?}", lost, sequence);
+    }
+
+    #[test]
+    fn a_disconnect_hangs_up_and_loses_the_lines_due_meanwhile() {
+        let faults = vec![(Duration::from_millis(300), Fault::Disconnect(Duration::from_millis(300)))];
+        let connections = play_to_client(DATA, faults);
+        assert_eq!(connections.len(), 2);
+        let (before, after) = (sequence(&connections[0]), sequence(&connections[1]));
+        assert_eq!(before.first(), Some(&0));
+        assert_eq!(after.last(), Some(&99));
+        let lost = after[0] - before.last().unwrap() - 1;
+        assert!((15..=45).contains(&lost), "lost {}: {:?} {:?}", lost, before, after);
+    }
+}
@@ -0,0 +1,98 @@
+//! Safety caps for unattended sessions: `--max-duration` and `--max-file-size`.
+//!
+//! A thread of its own checks the caps once a second, never per record: the
+//! time since the program started, and the bytes in every file written this
+//! session (output files, compressed or not, and what the raw capture and the
+//! rejects file gained). When one is reached the active run is stopped with
+//! the cap named in its stop row, so hooks, events and notifications fire as
+//! for any stop, and the program exits with its own exit code, or with
+//! `--cap-action stop-only` stays up without starting new runs.
+
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::events::{self, Event};
+use crate::session::Session;
+use crate::units::format_size;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which cap was reached.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cap {
+    Duration,
+    FileSize,
+}
+
+impl Cap {
+    pub fn name(self) -> &'static str {
+        match self {
+            Cap::Duration => "max-duration",
+            Cap::FileSize => "max-file-size",
+        }
+    }
+}
+
+/// What happens when a cap is reached (`--cap-action`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Exit,
+    StopOnly,
+}
+
+impl Action {
+    pub fn parse(text: &str) -> Result<Action, String> {
+        match text {
+            "exit" => Ok(Action::Exit),
+            "stop-only" => Ok(Action::StopOnly),
+            _ => Err(format!("Invalid --cap-action '{}': expected exit or stop-only", text)),
+        }
+    }
+}
+
+pub struct Caps {
+    pub max_duration: Option<Duration>,
+    pub max_size: Option<u64>,
+    pub action: Action,
+}
+
+/// Starts checking the caps, counting time from `started`. `exit` ends the
+/// program once the run is stopped, with `--cap-action exit`.
+pub fn spawn(caps: Caps, started: Instant, session: Arc<Mutex<Session>>, exit: Box<dyn Fn() + Send>) {
+    if caps.max_duration.is_none() && caps.max_size.is_none() {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        let mut session = session.lock().unwrap();
+        let reached = if caps.max_duration.is_some_and(|max| started.elapsed() >= max) {
+            Some((Cap::Duration, format!("ran for {:.0}s", started.elapsed().as_secs_f64())))
+        } else {
+            caps.max_size
+                .map(|max| (max, session.output_bytes()))
+                .filter(|(max, bytes)| bytes >= max)
+                .map(|(_, bytes)| (Cap::FileSize, format!("wrote {}", format_size(bytes))))
+        };
+        let Some((cap, what)) = reached else { continue };
+        esay!("The --{} cap was reached (the session {}); stopping.", cap.name(), what);
+        events::emit(Event::CapReached {
+            cap: cap.name().to_string(),
+            detail: format!("the session {}", what),
+        });
+        session.stop_for_cap(cap);
+        drop(session);
+        match caps.action {
+            Action::Exit => {
+                say!("Exiting...");
+                exit()
+            }
+            Action::StopOnly => {
+                say!("Staying up without recording (--cap-action stop-only); type 'exit' to quit.");
+                return;
+            }
+        }
+    });
+}
@@ -0,0 +1,221 @@
+//! `--log-on-change`: writing a data row only when something changed.
+//!
+//! A row is written when one of the watched fields (every field, or those
+//! listed, counted from 0 as written like `f0`, `f1` in `--compute`) differs
+//! from the last row written. Fields that are numbers in both rows differ by
+//! more than `--change-epsilon`; anything else by its text. Comparing with
+//! the last row written rather than the last one received keeps a slow drift
+//! in steps below the epsilon from going unrecorded. With `--keepalive` a row
+//! is written anyway once that long has passed since the last one, so a
+//! silent stretch in the file means no data rather than no change. Marker
+//! rows never go through here, and every run starts afresh with its first
+//! row written.
+
+use std::time::{Duration, Instant};
+
+/// What counts as a change, from the command line.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    // Indices of the watched fields; None for all of them
+    pub fields: Option<Vec<usize>>,
+    pub epsilon: f64,
+    pub keepalive: Option<Duration>,
+}
+
+impl Policy {
+    /// Parses the `--log-on-change` field list (`all`, or indices such as
+    /// `1,2`) for rows of `count` fields.
+    pub fn parse_fields(text: &str, count: usize) -> Result<Option<Vec<usize>>, String> {
+        if text == "all" {
+            return Ok(None);
+        }
+        let mut fields = Vec::new();
+        for index in text.split(',').map(str::trim) {
+            match index.parse::<usize>() {
+                Ok(i) if i < count => fields.push(i),
+                _ => {
+                    return Err(format!(
+                        "Invalid --log-on-change field '{}': expected all or indices of the {} fields as written (0 to {})",
+                        index,
+                        count,
+                        count.saturating_sub(1)
+                    ))
+                }
+            }
+        }
+        Ok(Some(fields))
+    }
+}
+
+/// The last row written and when, against which rows are compared.
+pub struct ChangeFilter {
+    policy: Policy,
+    last: Option<(Vec<String>, Instant)>,
+}
+
+impl ChangeFilter {
+    pub fn new(policy: Policy) -> ChangeFilter {
+        ChangeFilter { policy, last: None }
+    }
+
+    /// Whether `payload`, arriving at `now`, is to be left out as unchanged;
+    /// if not, it becomes the row compared with from now on.
+    pub fn is_unchanged(&mut self, payload: &[&str], now: Instant) -> bool {
+        if let Some((last, written)) = &self.last {
+            let due = self.policy.keepalive.is_some_and(|keepalive| now.duration_since(*written) >= keepalive);
+            if !due && !self.changed(last, payload) {
+                return true;
+            }
+        }
+        self.last = Some((payload.iter().map(|field| field.to_string()).collect(), now));
+        false
+    }
+
+    fn changed(&self, last: &[String], payload: &[&str]) -> bool {
+        let differs = |i: usize| match (last.get(i), payload.get(i)) {
+            (Some(old), Some(new)) => differ(old, new, self.policy.epsilon),
+            (old, new) => old.is_some() != new.is_some(),
+        };
+        match &self.policy.fields {
+            Some(fields) => fields.iter().any(|&i| differs(i)),
+            None => (0..last.len().max(payload.len())).any(differs),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+// Numbers differ by more than `epsilon`, other text by any difference. The
+// subtraction itself is off by a few units in the last place (1.01 - 1.00 is
+// 0.010000000000000009), so a step of exactly the epsilon is no change.
+fn differ(old: &str, new: &str, epsilon: f64) -> bool {
+    match (old.trim().parse::<f64>(), new.trim().parse::<f64>()) {
+        // NaN never equals itself; two of them are no change
+        (Ok(a), Ok(b)) if a.is_nan() || b.is_nan() => a.is_nan() != b.is_nan(),
+        (Ok(a), Ok(b)) if a == b => false,
+        // The noise of an infinity would be infinite too
+        (Ok(a), Ok(b)) if a.is_infinite() || b.is_infinite() => true,
+        (Ok(a), Ok(b)) => {
+            let noise = 4.0 * f64::EPSILON * a.abs().max(b.abs());
+            (a - b).abs() > epsilon + noise
+        }
+        _ => old != new,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(fields: Option<Vec<usize>>, epsilon: f64, keepalive: Option<Duration>) -> ChangeFilter {
+        ChangeFilter::new(Policy {
+            fields,
+            epsilon,
+            keepalive,
+        })
+    }
+
+    // The values of a single field that `filter` writes, all arriving at once
+    fn written<'a>(filter: &mut ChangeFilter, values: &[&'a str]) -> Vec<&'a str> {
+        let now = Instant::now();
+        values.iter().copied().filter(|value| !filter.is_unchanged(&[value], now)).collect()
+    }
+
+    #[test]
+    fn a_step_of_the_epsilon_is_no_change() {
+        let mut filter = filter(None, 0.01, None);
+        // 1.01 - 1.00 is 0.010000000000000009 in binary, and -0.99 + 1.00 is
+        // 0.010000000000000009 too
+        assert_eq!(written(&mut filter, &["1.00", "1.01", "0.99", "1.0100000001", "1.005"]), ["1.00", "1.0100000001"]);
+        let mut filter = self::filter(None, 0.1, None);
+        assert_eq!(written(&mut filter, &["0.3", "0.4", "0.2", "0.19999"]), ["0.3", "0.19999"]);
+        // Around a large value as well as a small one
+        let mut filter = self::filter(None, 0.01, None);
+        assert_eq!(written(&mut filter, &["1000.00", "1000.01", "999.99", "1000.02"]), ["1000.00", "1000.02"]);
+    }
+
+    #[test]
+    fn floating_point_noise_is_no_change_without_an_epsilon() {
+        let mut filter = filter(None, 0.0, None);
+        // 0.1 + 0.2 as a device printing all digits of an f64 writes it
+        assert_eq!(written(&mut filter, &["0.3", "0.30000000000000004", "0.29999999999999993"]), ["0.3"]);
+        // The same number written differently
+        assert_eq!(written(&mut filter, &["1", "1.0", "+1.000", "1e0", " 1 "]), ["1"]);
+        // Any real step is a change, however small the values
+        assert_eq!(written(&mut filter, &["1e-300", "2e-300", "2e-300"]), ["1e-300", "2e-300"]);
+        assert_eq!(written(&mut filter, &["-0", "0"]), ["-0"]);
+    }
+
+    #[test]
+    fn a_slow_drift_is_written_once_it_adds_up() {
+        let mut filter = filter(None, 0.01, None);
+        let drift = ["1.000", "1.004", "1.008", "1.012", "1.016", "1.020", "1.024"];
+        // Compared with the last row written, not the last one received
+        assert_eq!(written(&mut filter, &drift), ["1.000", "1.012", "1.024"]);
+    }
+
+    #[test]
+    fn text_and_non_finite_values_compare_as_they_are() {
+        let mut filter = filter(None, 0.5, None);
+        assert_eq!(written(&mut filter, &["OK", "OK", "ok", "FAULT", ""]), ["OK", "ok", "FAULT", ""]);
+        let mut filter = self::filter(None, 0.5, None);
+        assert_eq!(written(&mut filter, &["nan", "NaN", "1", "inf", "inf", "-inf", "1e308"]), ["nan", "1", "inf", "-inf", "1e308"]);
+        // A number against text is always a change
+        let mut filter = self::filter(None, 100.0, None);
+        assert_eq!(written(&mut filter, &["1", "x1", "1"]), ["1", "x1", "1"]);
+    }
+
+    #[test]
+    fn only_the_watched_fields_count() {
+        let mut filter = filter(Some(vec![1, 2]), 0.01, None);
+        let now = Instant::now();
+        assert!(!filter.is_unchanged(&["0", "1.00", "OK"], now));
+        assert!(filter.is_unchanged(&["1", "1.005", "OK"], now));
+        assert!(filter.is_unchanged(&["2", "0.995", "OK", "extra"], now));
+        assert!(!filter.is_unchanged(&["3", "1.00", "FAULT"], now));
+        // Every field, and a field coming or going, counts without a list
+        let mut filter = self::filter(None, 0.01, None);
+        assert!(!filter.is_unchanged(&["0", "1.00"], now));
+        assert!(!filter.is_unchanged(&["1", "1.00"], now));
+        assert!(!filter.is_unchanged(&["1", "1.00", "x"], now));
+        assert!(!filter.is_unchanged(&["1", "1.00"], now));
+    }
+
+    #[test]
+    fn keepalive_writes_a_row_once_it_is_due() {
+        let mut filter = filter(None, 0.01, Some(Duration::from_secs(10)));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert!(!filter.is_unchanged(&["1.00"], at(0)));
+        assert!(filter.is_unchanged(&["1.00"], at(9)));
+        assert!(!filter.is_unchanged(&["1.00"], at(10)));
+        // Counted from that row on, and from a change too
+        assert!(filter.is_unchanged(&["1.00"], at(19)));
+        assert!(!filter.is_unchanged(&["2.00"], at(15)));
+        assert!(filter.is_unchanged(&["2.00"], at(24)));
+        assert!(!filter.is_unchanged(&["2.00"], at(25)));
+    }
+
+    #[test]
+    fn reset_writes_the_next_row() {
+        let mut filter = filter(None, 0.01, None);
+        let now = Instant::now();
+        assert!(!filter.is_unchanged(&["1.00"], now));
+        assert!(filter.is_unchanged(&["1.00"], now));
+        filter.reset();
+        assert!(!filter.is_unchanged(&["1.00"], now));
+        assert!(filter.is_unchanged(&["1.00"], now));
+    }
+
+    #[test]
+    fn field_lists_are_checked_against_the_row() {
+        assert_eq!(Policy::parse_fields("all", 4), Ok(None));
+        assert_eq!(Policy::parse_fields("1, 3", 4), Ok(Some(vec![1, 3])));
+        for text in ["4", "1,x", "", "-1"] {
+            let e = Policy::parse_fields(text, 4).unwrap_err();
+            assert!(e.contains("(0 to 3)"), "{}", e);
+        }
+    }
+}
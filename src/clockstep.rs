@@ -0,0 +1,127 @@
+//! Noticing steps of the system clock, e.g. NTP setting it back by 40
+//! seconds mid-run, with `--clock-step`.
+//!
+//! The Timestamp column is the wall clock and jumps with it. Everything the
+//! logger measures as a duration (gap rows, heartbeats, `--sync interval`,
+//! `--keepalive`, hold-offs, run lengths, the time since data on the status
+//! line) is taken on the monotonic clock and doesn't. Between consecutive
+//! data rows of a run the two are compared: when the wall clock moved more or
+//! less than the monotonic one by over the threshold, a `clockstep` row with
+//! the difference (`-40.012s` for a clock set back) is written before the
+//! row, so the file tells a step from a gap or from rows out of order, and
+//! `analyze` takes it out of durations and gaps.
+
+use chrono::{DateTime, Local, TimeDelta};
+use std::time::{Duration, Instant};
+
+/// `--clock-step` unless given.
+pub const DEFAULT_THRESHOLD: &str = "2";
+
+pub struct ClockWatch {
+    threshold: TimeDelta,
+    // Both clocks at the previous data row of the run
+    last: Option<(DateTime<Local>, Instant)>,
+}
+
+impl ClockWatch {
+    pub fn new(threshold: Duration) -> ClockWatch {
+        ClockWatch {
+            threshold: TimeDelta::from_std(threshold).unwrap_or(TimeDelta::MAX),
+            last: None,
+        }
+    }
+
+    /// Notes a data row that arrived at `wall` on the wall clock and `at` on
+    /// the monotonic one. Returns how far the wall clock was stepped since
+    /// the previous row if that is more than the threshold.
+    pub fn check(&mut self, wall: DateTime<Local>, at: Instant) -> Option<TimeDelta> {
+        let (last_wall, last_at) = self.last.replace((wall, at))?;
+        let elapsed = TimeDelta::from_std(at.saturating_duration_since(last_at)).ok()?;
+        let step = (wall - last_wall) - elapsed;
+        (step.abs() > self.threshold).then_some(step)
+    }
+
+    /// Forgets the previous row, for a new run.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+/// The text of a `clockstep` row, e.g. `-40.012s`.
+pub fn describe(step: TimeDelta) -> String {
+    format!("{:+.3}s", step.num_milliseconds() as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn watch() -> ClockWatch {
+        ClockWatch::new(Duration::from_secs(2))
+    }
+
+    fn wall(secs: f64) -> DateTime<Local> {
+        Local.timestamp_opt(1_736_850_000, 0).unwrap() + TimeDelta::milliseconds((secs * 1000.0) as i64)
+    }
+
+    fn after(start: Instant, secs: f64) -> Instant {
+        start + Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn clocks_moving_together_are_no_step() {
+        let start = Instant::now();
+        let mut watch = watch();
+        assert_eq!(watch.check(wall(0.0), start), None);
+        for i in 1..100 {
+            let secs = i as f64 * 0.1;
+            assert_eq!(watch.check(wall(secs), after(start, secs)), None);
+        }
+        // Drift and a long gap between rows on both clocks aren't steps either
+        assert_eq!(watch.check(wall(3600.5), after(start, 3600.0)), None);
+    }
+
+    #[test]
+    fn a_backward_step_is_reported() {
+        let start = Instant::now();
+        let mut watch = watch();
+        watch.check(wall(100.0), start);
+        let step = watch.check(wall(60.5), after(start, 0.5)).unwrap();
+        assert_eq!(step, TimeDelta::seconds(-40));
+        assert_eq!(describe(step), "-40.000s");
+        // Compared with the stepped clock from then on
+        assert_eq!(watch.check(wall(61.5), after(start, 1.5)), None);
+    }
+
+    #[test]
+    fn a_forward_step_is_reported() {
+        let start = Instant::now();
+        let mut watch = watch();
+        watch.check(wall(0.0), start);
+        let step = watch.check(wall(3601.012), after(start, 1.0)).unwrap();
+        assert_eq!(step, TimeDelta::milliseconds(3_600_012));
+        assert_eq!(describe(step), "+3600.012s");
+    }
+
+    #[test]
+    fn steps_within_the_threshold_are_ignored() {
+        let start = Instant::now();
+        let mut watch = watch();
+        watch.check(wall(0.0), start);
+        assert_eq!(watch.check(wall(2.9), after(start, 1.0)), None);
+        assert_eq!(watch.check(wall(1.9), after(start, 2.0)), None);
+        assert!(watch.check(wall(5.0), after(start, 2.5)).is_some());
+    }
+
+    #[test]
+    fn reset_forgets_the_previous_row() {
+        let start = Instant::now();
+        let mut watch = watch();
+        watch.check(wall(100.0), start);
+        watch.reset();
+        // The first row of a new run has nothing to compare with
+        assert_eq!(watch.check(wall(0.0), after(start, 1.0)), None);
+        assert!(watch.check(wall(-60.0), after(start, 2.0)).is_some());
+    }
+}
@@ -0,0 +1,195 @@
+//! Consistent Overhead Byte Stuffing, for `--framing cobs`.
+//!
+//! COBS removes every zero byte from a packet so that zero can separate
+//! packets on the wire. The encoded packet is a series of blocks: a code byte
+//! `n` (1 to 255) followed by `n - 1` data bytes; each block except the last
+//! stands for its data plus one zero byte, unless its code is 255, which is a
+//! full block of 254 data bytes with no zero after it. The empty packet
+//! encodes as the single byte `01`.
+
+use std::fmt;
+
+/// Why an encoded packet couldn't be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CobsError {
+    // A zero byte inside the packet, where COBS never puts one
+    ZeroByte { offset: usize },
+    // A code byte promising more bytes than the packet has left
+    Truncated { offset: usize },
+    // Nothing between two delimiters
+    Empty,
+}
+
+impl fmt::Display for CobsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CobsError::ZeroByte { offset } => write!(f, "zero byte at offset {}", offset),
+            CobsError::Truncated { offset } => write!(f, "block at offset {} runs past the end of the packet", offset),
+            CobsError::Empty => f.write_str("empty packet"),
+        }
+    }
+}
+
+/// Decodes one packet, without its zero delimiter.
+pub fn decode(encoded: &[u8]) -> Result<Vec<u8>, CobsError> {
+    if encoded.is_empty() {
+        return Err(CobsError::Empty);
+    }
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut offset = 0;
+    while offset < encoded.len() {
+        let code = encoded[offset] as usize;
+        if code == 0 {
+            return Err(CobsError::ZeroByte { offset });
+        }
+        let end = offset + code;
+        if end > encoded.len() {
+            return Err(CobsError::Truncated { offset });
+        }
+        let data = &encoded[offset + 1..end];
+        if let Some(zero) = data.iter().position(|&b| b == 0) {
+            return Err(CobsError::ZeroByte {
+                offset: offset + 1 + zero,
+            });
+        }
+        decoded.extend_from_slice(data);
+        offset = end;
+        if code < 0xFF && offset < encoded.len() {
+            decoded.push(0);
+        }
+    }
+    Ok(decoded)
+}
+
+/// Encodes one packet, without the zero delimiter that follows it on the wire.
+pub fn encode(packet: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(packet.len() + packet.len() / 254 + 1);
+    // Where the code byte of the current block goes
+    let mut code_at = 0;
+    encoded.push(0);
+    for (i, &byte) in packet.iter().enumerate() {
+        if byte == 0 {
+            encoded[code_at] = (encoded.len() - code_at) as u8;
+            code_at = encoded.len();
+            encoded.push(0);
+            continue;
+        }
+        encoded.push(byte);
+        if encoded.len() - code_at == 0xFF {
+            encoded[code_at] = 0xFF;
+            // A full block that ends the packet needs no empty one after it
+            if i + 1 == packet.len() {
+                return encoded;
+            }
+            code_at = encoded.len();
+            encoded.push(0);
+        }
+    }
+    encoded[code_at] = (encoded.len() - code_at) as u8;
+    encoded
+}
+
+/// Decodes a packet as read from the input, up to and including its zero
+/// delimiter (missing at the end of the input). None for an empty packet, as
+/// between two delimiters in a row, which senders use to resynchronize.
+pub fn unpack(received: &[u8]) -> Option<Result<Vec<u8>, CobsError>> {
+    let encoded = received.strip_suffix(&[0]).unwrap_or(received);
+    (!encoded.is_empty()).then(|| decode(encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The examples of the COBS paper and Wikipedia: packet, then encoding
+    fn examples() -> Vec<(Vec<u8>, Vec<u8>)> {
+        let run = |from: u8, to: u8| (from..=to).collect::<Vec<u8>>();
+        let join = |parts: &[&[u8]]| parts.concat();
+        vec![
+            (vec![], vec![0x01]),
+            (vec![0x00], vec![0x01, 0x01]),
+            (vec![0x00, 0x00], vec![0x01, 0x01, 0x01]),
+            (vec![0x00, 0x11, 0x00], vec![0x01, 0x02, 0x11, 0x01]),
+            (vec![0x11, 0x22, 0x00, 0x33], vec![0x03, 0x11, 0x22, 0x02, 0x33]),
+            (vec![0x11, 0x22, 0x33, 0x44], vec![0x05, 0x11, 0x22, 0x33, 0x44]),
+            (vec![0x11, 0x00, 0x00, 0x00], vec![0x02, 0x11, 0x01, 0x01, 0x01]),
+            // 254 bytes without a zero fill one block exactly
+            (run(0x01, 0xFE), join(&[&[0xFF], &run(0x01, 0xFE)])),
+            (join(&[&[0x00], &run(0x01, 0xFE)]), join(&[&[0x01, 0xFF], &run(0x01, 0xFE)])),
+            // 255 bytes without a zero take a second block
+            (run(0x01, 0xFF), join(&[&[0xFF], &run(0x01, 0xFE), &[0x02, 0xFF]])),
+            (join(&[&run(0x02, 0xFF), &[0x00]]), join(&[&[0xFF], &run(0x02, 0xFF), &[0x01, 0x01]])),
+            (join(&[&run(0x03, 0xFF), &[0x00, 0x01]]), join(&[&[0xFE], &run(0x03, 0xFF), &[0x02, 0x01]])),
+        ]
+    }
+
+    #[test]
+    fn the_published_examples_encode_and_decode() {
+        for (packet, encoded) in examples() {
+            assert_eq!(encode(&packet), encoded, "encoding {:02X?}", packet);
+            assert_eq!(decode(&encoded), Ok(packet.clone()), "decoding {:02X?}", encoded);
+        }
+    }
+
+    #[test]
+    fn every_length_and_zero_pattern_round_trips() {
+        // Packets without zeros, of zeros only, and with zeros every few
+        // bytes, across several full blocks
+        for len in 0..=800 {
+            for zero_every in [0, 1, 2, 3, 253, 254, 255, 256] {
+                let packet: Vec<u8> = (0..len)
+                    .map(|i| if zero_every > 0 && i % zero_every == zero_every - 1 { 0 } else { (i % 255 + 1) as u8 })
+                    .collect();
+                let encoded = encode(&packet);
+                assert!(!encoded.contains(&0), "{} bytes, zero every {}", len, zero_every);
+                assert!(encoded.len() <= packet.len() + packet.len() / 254 + 1);
+                assert_eq!(decode(&encoded), Ok(packet), "{} bytes, zero every {}", len, zero_every);
+            }
+        }
+    }
+
+    #[test]
+    fn every_single_byte_packet_round_trips() {
+        for byte in 0..=255u8 {
+            assert_eq!(decode(&encode(&[byte])), Ok(vec![byte]));
+        }
+    }
+
+    #[test]
+    fn zero_bytes_inside_a_packet_are_errors() {
+        assert_eq!(decode(&[0x00]), Err(CobsError::ZeroByte { offset: 0 }));
+        assert_eq!(decode(&[0x03, 0x11, 0x00]), Err(CobsError::ZeroByte { offset: 2 }));
+        assert_eq!(decode(&[0x02, 0x11, 0x00, 0x01]), Err(CobsError::ZeroByte { offset: 2 }));
+    }
+
+    #[test]
+    fn blocks_running_past_the_end_are_errors() {
+        assert_eq!(decode(&[0x05, 0x11, 0x22]), Err(CobsError::Truncated { offset: 0 }));
+        assert_eq!(decode(&[0x02, 0x11, 0x03, 0x22]), Err(CobsError::Truncated { offset: 2 }));
+        // A full block cut short
+        let mut full = encode(&[0x11; 254]);
+        full.pop();
+        assert_eq!(decode(&full), Err(CobsError::Truncated { offset: 0 }));
+        assert_eq!(
+            CobsError::Truncated { offset: 2 }.to_string(),
+            "block at offset 2 runs past the end of the packet"
+        );
+    }
+
+    #[test]
+    fn empty_frames_are_skipped_when_received() {
+        assert_eq!(decode(&[]), Err(CobsError::Empty));
+        assert_eq!(unpack(&[]), None);
+        assert_eq!(unpack(&[0x00]), None);
+        // The empty packet itself is 01
+        assert_eq!(unpack(&[0x01, 0x00]), Some(Ok(vec![])));
+    }
+
+    #[test]
+    fn received_packets_lose_their_delimiter() {
+        assert_eq!(unpack(&[0x03, 0x11, 0x22, 0x02, 0x33, 0x00]), Some(Ok(vec![0x11, 0x22, 0x00, 0x33])));
+        // The last packet of the input may have none
+        assert_eq!(unpack(&[0x03, 0x11, 0x22, 0x02, 0x33]), Some(Ok(vec![0x11, 0x22, 0x00, 0x33])));
+        assert_eq!(unpack(&[0x05, 0x11, 0x00]), Some(Err(CobsError::Truncated { offset: 0 })));
+    }
+}
@@ -0,0 +1,98 @@
+//! The operator commands, shared by the prompt, `--grpc-listen` and
+//! `--control-file`.
+//!
+//! All of them hand a command and its arguments to `dispatch`, so a run started
+//! remotely goes through exactly the same checks, messages and events as one
+//! started by typing `start`.
+
+use std::{sync::mpsc::Sender, time::Duration};
+
+use crate::peek;
+use crate::repl;
+use crate::serial::Control;
+use crate::session::Session;
+use crate::settings;
+use crate::tail;
+
+// Length of a break sent with a bare `break`, and the longest allowed
+const DEFAULT_BREAK_MS: u64 = 250;
+const MAX_BREAK_MS: u64 = 10_000;
+
+// Commands that act on the recording or the device, written with --audit;
+// `exit` is audited by the caller
+const AUDITED: &[&str] = &["start", "stop", "pause", "resume", "newfile", "baud", "port", "break"];
+
+/// Carries out one command other than `exit`, which ends the program and is
+/// handled by the caller.
+pub fn dispatch(session: &mut Session, serial_control: &Sender<Control>, command: &str, args: &str) {
+    if AUDITED.contains(&command) {
+        session.audit(format!("{} {}", command, args).trim_end());
+    }
+    match command {
+        "start" => session.start(args),
+        "stop" => session.stop(),
+        "pause" => session.pause(),
+        "resume" => session.resume(),
+        "mark" => session.mark(args),
+        "newfile" => {
+            if args.is_empty() {
+                say!("Usage: newfile <path>");
+            } else if let Err(e) = session.switch_file(args) {
+                esay!("{}; still writing to the previous file.", e);
+            }
+        }
+        "baud" => match args.parse::<u32>() {
+            Ok(rate) if rate > 0 => {
+                let _ = serial_control.send(Control::SetBaud(rate));
+            }
+            _ => say!("Usage: baud <rate>"),
+        },
+        "port" => {
+            if args.is_empty() {
+                say!("Usage: port <path>");
+            } else {
+                let _ = serial_control.send(Control::SetPort(args.to_string()));
+            }
+        }
+        "break" => {
+            let ms = if args.is_empty() { Ok(DEFAULT_BREAK_MS) } else { args.parse::<u64>() };
+            match ms {
+                Ok(ms) if (1..=MAX_BREAK_MS).contains(&ms) => {
+                    let _ = serial_control.send(Control::SendBreak(Duration::from_millis(ms)));
+                }
+                _ => say!("Usage: break [ms] (1 to {} ms, default {})", MAX_BREAK_MS, DEFAULT_BREAK_MS),
+            }
+        }
+        "peek" => {
+            if args.is_empty() {
+                session.shared.peek.lock().unwrap().print(None);
+            } else {
+                match args.parse::<usize>() {
+                    Ok(count) if count > 0 => session.shared.peek.lock().unwrap().print(Some(count)),
+                    _ => say!("Usage: peek [count] (up to {} lines)", peek::RAW_HISTORY),
+                }
+            }
+        }
+        "tail" => {
+            let Some(recent) = session.tail() else {
+                return say!("No rows are kept for tail (--tail-buffer 0).");
+            };
+            let recent = recent.lock().unwrap();
+            let count = if args.is_empty() { Ok(tail::DEFAULT_COUNT) } else { args.parse::<usize>() };
+            match count {
+                Ok(count) if count > 0 => recent.print(count),
+                _ => say!("Usage: tail [count] (default {}; the last {} rows are kept)", tail::DEFAULT_COUNT, recent.capacity()),
+            }
+        }
+        "set" => settings::set(args),
+        "show" => match args {
+            "settings" => settings::show(),
+            _ => say!("Usage: show settings"),
+        },
+        "help" => repl::print_help(),
+        _ => match repl::suggest(command) {
+            Some(suggestion) => say!("Unknown command '{}', did you mean '{}'?", command, suggestion),
+            None => say!("Unknown command. Type 'help' for a list of commands."),
+        },
+    }
+}
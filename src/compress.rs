@@ -0,0 +1,130 @@
+//! Compressing finished output files with `--compress-completed gzip`.
+//!
+//! When a run's file is closed (`--per-run`) or `newfile` moves on from a
+//! file, the file is queued for a background thread that writes `FILE.gz`
+//! next to it. The compressed data goes to `FILE.gz.tmp` first, is read back
+//! and checked against the original's size, and only then renamed and the
+//! original deleted; anything that goes wrong leaves the original in place
+//! with a warning. Only closed files are queued, never the file being
+//! written, and the thread pauses between chunks so it doesn't compete with
+//! the writer for a slow disk.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+// Read and compressed at a time, with a pause after each
+const CHUNK: usize = 64 * 1024;
+const PAUSE: Duration = Duration::from_millis(5);
+
+// Files queued or being compressed, waited on at exit
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static QUEUE: OnceLock<mpsc::Sender<PathBuf>> = OnceLock::new();
+
+/// Starts the compression thread.
+pub fn init() -> Result<(), String> {
+    let (tx, rx) = mpsc::channel::<PathBuf>();
+    thread::spawn(move || {
+        for path in rx {
+            match compress(&path) {
+                Ok(compressed) => say!("Compressed {} to {}.", path.display(), compressed.display()),
+                Err(e) => esay!("Warning: Failed to compress {}; it is kept as it is: {}", path.display(), e),
+            }
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+    });
+    QUEUE.set(tx).map_err(|_| "Compression is already set up".to_string())
+}
+
+/// Queues a closed output file; a no-op without `--compress-completed`.
+pub fn submit(path: &str) {
+    let Some(queue) = QUEUE.get() else { return };
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    if queue.send(PathBuf::from(path)).is_err() {
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Gives queued files up to `timeout` to be compressed before exit.
+pub fn wait_for_pending(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while PENDING.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            esay!(
+                "Warning: {} file(s) left uncompressed at exit",
+                PENDING.load(Ordering::SeqCst)
+            );
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Name of the compressed copy of `path`.
+pub fn compressed_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+// Writes and checks PATH.gz, then deletes the original
+fn compress(path: &Path) -> Result<PathBuf, String> {
+    let before = std::fs::metadata(path).map_err(|e| e.to_string())?;
+    let target = compressed_path(path);
+    let tmp = target.with_extension("gz.tmp");
+    let result = write_compressed(path, &tmp).and_then(|()| {
+        let size = uncompressed_size(&tmp)?;
+        if size != before.len() {
+            return Err(format!("the compressed file reads back as {} bytes, not {}", size, before.len()));
+        }
+        // A file reopened meanwhile (e.g. a run label used again) is not finished
+        let after = std::fs::metadata(path).map_err(|e| e.to_string())?;
+        if after.len() != before.len() || modified(&after) != modified(&before) {
+            return Err("it changed while it was being compressed".to_string());
+        }
+        std::fs::rename(&tmp, &target).map_err(|e| e.to_string())
+    });
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    std::fs::remove_file(path).map_err(|e| format!("compressed to {}, but the original can't be deleted: {}", target.display(), e))?;
+    Ok(target)
+}
+
+fn write_compressed(path: &Path, tmp: &Path) -> Result<(), String> {
+    let mut input = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    let output = File::create(tmp).map_err(|e| format!("can't create {}: {}", tmp.display(), e))?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    let mut buffer = vec![0; CHUNK];
+    loop {
+        let read = input.read(&mut buffer).map_err(|e| e.to_string())?;
+        if read == 0 {
+            break;
+        }
+        encoder.write_all(&buffer[..read]).map_err(|e| e.to_string())?;
+        thread::sleep(PAUSE);
+    }
+    let output = encoder.finish().map_err(|e| e.to_string())?;
+    output.sync_all().map_err(|e| e.to_string())
+}
+
+fn uncompressed_size(tmp: &Path) -> Result<u64, String> {
+    let file = File::open(tmp).map_err(|e| e.to_string())?;
+    io::copy(&mut GzDecoder::new(BufReader::new(file)), &mut io::sink())
+        .map_err(|e| format!("the compressed file doesn't read back: {}", e))
+}
+
+fn modified(metadata: &std::fs::Metadata) -> Option<SystemTime> {
+    metadata.modified().ok()
+}
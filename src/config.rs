@@ -0,0 +1,686 @@
+//! Options from a file, with `--config FILE`.
+//!
+//! The file is a flat TOML table whose keys are the long option names:
+//!
+//! ```toml
+//! port = "/dev/ttyUSB0"
+//! baud = 115200
+//! match = "DATA:"
+//! names = "t,ax,ay,az"
+//! per-run = true
+//! alarm = ["f3 < -9.5:impact", "f3 > 9.5:flip"]
+//! ```
+//!
+//! Each entry becomes an option placed before those on the command line, so
+//! the command line wins for options given once: a string or number is the
+//! option's value, `true` turns a flag on (`false` leaves it off), and an
+//! array repeats the option. `serial_logger setup` writes such a file.
+//! `profiles.toml` (see `profile.rs`) holds named tables of the same entries.
+//! `--print-config` writes the options in effect back out in this form.
+//!
+//! To read several ports with settings of their own, each gets a `[[port]]`
+//! block after the top-level entries:
+//!
+//! ```toml
+//! output = "rig_{date}.csv"
+//! fields = 3
+//!
+//! [[port]]
+//! port = "/dev/ttyUSB0"
+//! label = "imu"
+//! baud = 921600
+//! fields = 7
+//! names = "t,ax,ay,az,gx,gy,gz"
+//!
+//! [[port]]
+//! port = "/dev/ttyUSB1"
+//! label = "gps"
+//! baud = 9600
+//! match = "$GPGGA,"
+//! ```
+//!
+//! The top level and the command line give the defaults, and a block's
+//! entries replace them for its port (`PORT_KEYS` are those a block may
+//! hold). Every port goes to the one file, whose Port column holds the
+//! block's `label` (its port if none) and whose columns are those of all
+//! the ports; see multiport.rs.
+
+use clap::{parser::ValueSource, ArgAction, ArgMatches, Command};
+use std::{ffi::OsString, fmt, fmt::Write, path::Path};
+
+use crate::profile;
+
+// Options that bring in others rather than set anything themselves
+const NOT_PRINTED: [&str; 3] = ["config", "profile", "print-config"];
+
+/// The entries a `[[port]]` block may hold: `port` and `label`, and the
+/// options of how a port is read and its lines are parsed.
+pub const PORT_KEYS: &[&str] = &[
+    "port",
+    "label",
+    "baud",
+    "match",
+    "delimiter",
+    "fields",
+    "names",
+    "types",
+    "select",
+    "payload-encoding",
+    "decimal-comma",
+    "float-format",
+    "on-nonfinite",
+    "keep-ansi",
+    "keep-blank-lines",
+    "skip-lines",
+    "max-line-length",
+    "read-timeout",
+    "gap-marker",
+];
+
+// Entries of a block that aren't options
+const BLOCK_ONLY: [&str; 2] = ["port", "label"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    // Numbers are kept as written
+    Number(String),
+    Bool(bool),
+    List(Vec<Value>),
+}
+
+impl fmt::Display for Value {
+    /// Writes the value in TOML syntax.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Text(text) => {
+                f.write_str("\"")?;
+                for c in text.chars() {
+                    match c {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\t' => f.write_str("\\t")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\r' => f.write_str("\\r")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                f.write_str("\"")
+            }
+            Value::Number(number) => f.write_str(number),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::List(items) => {
+                let items: Vec<String> = items.iter().map(Value::to_string).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+        }
+    }
+}
+
+/// The program's arguments with those from `--profile NAME` and `--config
+/// FILE` put in front of the command line's, in that order. The profile can
+/// also be chosen in the config file.
+pub fn expand_args(args: Vec<OsString>) -> Result<Vec<OsString>, String> {
+    let mut args = args.into_iter();
+    // The program name stays first
+    let mut expanded: Vec<OsString> = args.next().into_iter().collect();
+    let command_line: Vec<OsString> = args.collect();
+    let Config { entries: mut config, ports } = match option_value(&command_line, "config") {
+        Some(path) => load(Path::new(&path))?,
+        None => Config::default(),
+    };
+    let profile = option_value(&command_line, "profile").or_else(|| {
+        config.iter().find(|(key, _)| key == "profile").map(|(_, value)| match value {
+            Value::Text(text) | Value::Number(text) => OsString::from(text),
+            other => OsString::from(other.to_string()),
+        })
+    });
+    let mut profile = match profile {
+        Some(name) => profile::find(&name.to_string_lossy())?.entries,
+        None => Vec::new(),
+    };
+    // --port may be given more than once to read several ports, so one from
+    // the command line replaces those of the config and profile rather than
+    // adding to them, as one from the config does the profile's
+    if gives_port(&command_line) {
+        // The blocks' settings would be lost with their ports
+        if !ports.is_empty() {
+            return Err("--port can't be given with a config of [[port]] blocks, which name the ports".to_string());
+        }
+        config.retain(|(key, _)| key != "port");
+    }
+    if gives_port(&command_line) || !ports.is_empty() || config.iter().any(|(key, _)| key == "port") {
+        profile.retain(|(key, _)| key != "port");
+    }
+    for (key, value) in profile.iter().chain(&config) {
+        push_option(&mut expanded, key, value);
+    }
+    for block in &ports {
+        let port = block.iter().filter(|(key, _)| key == "port");
+        for (key, value) in port {
+            push_option(&mut expanded, key, value);
+        }
+    }
+    expanded.extend(command_line);
+    Ok(expanded)
+}
+
+// Whether the command line gives --port (or -p)
+fn gives_port(args: &[OsString]) -> bool {
+    args.iter()
+        .map(|arg| arg.to_string_lossy())
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == "--port" || arg.starts_with("--port=") || (arg.starts_with("-p") && !arg.starts_with("--")))
+}
+
+// The VALUE of `--NAME VALUE` or `--NAME=VALUE`, if given
+fn option_value(args: &[OsString], name: &str) -> Option<OsString> {
+    let option = format!("--{}", name);
+    let with_value = format!("--{}=", name);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            return None;
+        }
+        if arg == option {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg.strip_prefix(&with_value) {
+            return Some(OsString::from(value));
+        }
+    }
+    None
+}
+
+/// `args` with the options of a `[[port]]` block after them, so that they
+/// win over those given before: the options of the block's port.
+pub fn block_args(args: &[OsString], block: &Entries) -> Vec<OsString> {
+    let mut args = args.to_vec();
+    for (key, value) in block.iter().filter(|(key, _)| !BLOCK_ONLY.contains(&key.as_str())) {
+        push_option(&mut args, key, value);
+    }
+    args
+}
+
+/// What a `[[port]]` block's rows hold in the Port column: its `label`,
+/// or else its port.
+pub fn block_label(block: &Entries) -> String {
+    let text = |name: &str| {
+        block.iter().find(|(key, _)| key == name).map(|(_, value)| match value {
+            Value::Text(text) | Value::Number(text) => text.clone(),
+            other => other.to_string(),
+        })
+    };
+    text("label").or_else(|| text("port")).unwrap_or_default()
+}
+
+fn push_option(args: &mut Vec<OsString>, key: &str, value: &Value) {
+    match value {
+        Value::Bool(true) => args.push(format!("--{}", key).into()),
+        Value::Bool(false) => {}
+        Value::Text(text) | Value::Number(text) => args.push(format!("--{}={}", key, text).into()),
+        Value::List(items) => {
+            for item in items {
+                push_option(args, key, item);
+            }
+        }
+    }
+}
+
+/// The options in effect, for `--print-config`, as a config file: each one
+/// given (on the command line, in the config file or by the profile) as an
+/// entry, and each one left at its default value as a comment. The
+/// `[[port]]` blocks of the config, if any, follow and give the ports.
+pub fn render(command: &Command, matches: &ArgMatches, ports: &[Entries]) -> String {
+    let mut text = String::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(key) = arg.get_long().filter(|_| !NOT_PRINTED.contains(&id)) else {
+            continue;
+        };
+        if id == "port" && !ports.is_empty() {
+            continue;
+        }
+        let Some(source) = matches.value_source(id) else { continue };
+        let mut values = matches.get_raw(id).into_iter().flatten().map(|raw| value_of(&raw.to_string_lossy()));
+        let value = match arg.get_action() {
+            ArgAction::SetTrue if matches.get_flag(id) => Value::Bool(true),
+            ArgAction::SetTrue => continue,
+            // One value of a repeatable option is written as it is
+            ArgAction::Append => {
+                let mut values: Vec<Value> = values.collect();
+                match values.len() {
+                    0 => continue,
+                    1 => values.remove(0),
+                    _ => Value::List(values),
+                }
+            }
+            _ => match values.next() {
+                Some(value) => value,
+                None => continue,
+            },
+        };
+        let comment = if source == ValueSource::DefaultValue { "# " } else { "" };
+        let _ = writeln!(text, "{}{} = {}", comment, key, value);
+    }
+    for block in ports {
+        let _ = writeln!(text, "\n[[port]]");
+        for (key, value) in block {
+            let _ = writeln!(text, "{} = {}", key, value);
+        }
+    }
+    text
+}
+
+// A number if it reads back as the same number, text otherwise
+fn value_of(text: &str) -> Value {
+    let numeric = text.bytes().all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b));
+    if numeric && text.parse::<f64>().is_ok() {
+        Value::Number(text.to_string())
+    } else {
+        Value::Text(text.to_string())
+    }
+}
+
+/// Reads the entries of a config file, in order.
+pub fn load(path: &Path) -> Result<Config, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+}
+
+// Option names and values, in order
+pub type Entries = Vec<(String, Value)>;
+
+/// The entries of a config file: those at the top level, and those of each
+/// `[[port]]` block.
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    pub entries: Entries,
+    pub ports: Vec<Entries>,
+}
+
+/// Parses `key = value` lines, then any `[[port]]` blocks; `#` starts a
+/// comment.
+pub fn parse(text: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    for (number, line) in text.lines().enumerate() {
+        let invalid = |e: &str| format!("line {}: {}", number + 1, e);
+        match parse_line(line).map_err(|e| invalid(&e))? {
+            Line::Blank => {}
+            Line::Table(_) => return Err(invalid("tables are not supported; put every option at the top level")),
+            Line::ArrayTable(name) if name == "port" => config.ports.push(Vec::new()),
+            Line::ArrayTable(name) => return Err(invalid(&format!("[[{}]] is not supported; the only blocks are [[port]]", name))),
+            Line::Entry(key, value) => {
+                let entries = match config.ports.last_mut() {
+                    Some(block) => {
+                        check_port_entry(&key, &value).map_err(|e| invalid(&e))?;
+                        block
+                    }
+                    None => &mut config.entries,
+                };
+                if entries.iter().any(|(existing, _)| *existing == key) {
+                    return Err(invalid(&format!("'{}' is given twice", key)));
+                }
+                entries.push((key, value));
+            }
+        }
+    }
+    check_ports(&config)?;
+    Ok(config)
+}
+
+// An entry that a [[port]] block may hold
+fn check_port_entry(key: &str, value: &Value) -> Result<(), String> {
+    if key == "output" {
+        return Err(
+            "'output' can't be set per port; every port goes to the one file, with a Port column (run a logger per port for files of their own)"
+                .to_string(),
+        );
+    }
+    if !PORT_KEYS.contains(&key) {
+        return Err(format!("'{}' can't be set per port; put it at the top level", key));
+    }
+    if matches!(value, Value::List(_)) {
+        return Err(format!("'{}' takes one value in a [[port]] block", key));
+    }
+    Ok(())
+}
+
+// Blocks that each name a port of their own, with a label of their own
+fn check_ports(config: &Config) -> Result<(), String> {
+    if config.ports.is_empty() {
+        return Ok(());
+    }
+    if config.ports.len() == 1 {
+        return Err("[[port]] blocks are for reading several ports; put the options of a single port at the top level".to_string());
+    }
+    if config.entries.iter().any(|(key, _)| key == "port") {
+        return Err("the ports are given by the [[port]] blocks; leave 'port' out of the top level".to_string());
+    }
+    let mut labels: Vec<String> = Vec::new();
+    for (i, block) in config.ports.iter().enumerate() {
+        match block.iter().find(|(key, _)| key == "port") {
+            Some((_, Value::Text(_))) => {}
+            Some(_) => return Err(format!("[[port]] block {}: 'port' must be a path in quotes", i + 1)),
+            None => return Err(format!("[[port]] block {} has no 'port'", i + 1)),
+        }
+        let label = block_label(block);
+        if labels.contains(&label) {
+            return Err(format!("two [[port]] blocks are labelled {}; give each a label of its own", label));
+        }
+        labels.push(label);
+    }
+    Ok(())
+}
+
+/// Parses a file of `[name]` tables, each holding `key = value` lines, in
+/// order. Nothing may come before the first table.
+pub fn parse_tables(text: &str) -> Result<Vec<(String, Entries)>, String> {
+    let mut tables: Vec<(String, Entries)> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let invalid = |e: &str| format!("line {}: {}", number + 1, e);
+        match parse_line(line).map_err(|e| invalid(&e))? {
+            Line::Blank => {}
+            Line::ArrayTable(name) => return Err(invalid(&format!("[[{}]] blocks are only for a --config file", name))),
+            Line::Table(name) => {
+                if tables.iter().any(|(existing, _)| *existing == name) {
+                    return Err(invalid(&format!("[{}] is given twice", name)));
+                }
+                tables.push((name, Vec::new()));
+            }
+            Line::Entry(key, value) => {
+                let (_, entries) = tables.last_mut().ok_or_else(|| invalid("expected a [name] line first"))?;
+                if entries.iter().any(|(existing, _)| *existing == key) {
+                    return Err(invalid(&format!("'{}' is given twice", key)));
+                }
+                entries.push((key, value));
+            }
+        }
+    }
+    Ok(tables)
+}
+
+enum Line {
+    Blank,
+    Table(String),
+    // [[name]], one of several tables of that name
+    ArrayTable(String),
+    Entry(String, Value),
+}
+
+fn parse_line(line: &str) -> Result<Line, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(Line::Blank);
+    }
+    if let Some(rest) = line.strip_prefix("[[") {
+        let (name, rest) = rest.split_once("]]").ok_or("expected ']]' after the table name")?;
+        let name = name.trim().trim_matches('"');
+        let rest = rest.trim();
+        if name.is_empty() || (!rest.is_empty() && !rest.starts_with('#')) {
+            return Err("expected a table name, e.g. [[port]]".to_string());
+        }
+        return Ok(Line::ArrayTable(name.to_string()));
+    }
+    if let Some(rest) = line.strip_prefix('[') {
+        let (name, rest) = rest.split_once(']').ok_or("expected ']' after the table name")?;
+        let name = name.trim().trim_matches('"');
+        let rest = rest.trim();
+        if name.is_empty() || (!rest.is_empty() && !rest.starts_with('#')) {
+            return Err("expected a table name, e.g. [imu-v2]".to_string());
+        }
+        return Ok(Line::Table(name.to_string()));
+    }
+    let (key, rest) = line.split_once('=').ok_or("expected key = value")?;
+    let key = key.trim().trim_matches('"').to_string();
+    if key.is_empty() || key.starts_with('-') {
+        return Err("expected an option name without dashes, e.g. baud = 115200".to_string());
+    }
+    let (value, rest) = parse_value(rest.trim())?;
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected '{}' after the value", rest));
+    }
+    Ok(Line::Entry(key, value))
+}
+
+// One value at the start of `text`, and what follows it
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = text.strip_prefix('"') {
+        return parse_string(rest);
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        // Literal string, no escapes
+        let end = rest.find('\'').ok_or("unterminated string")?;
+        return Ok((Value::Text(rest[..end].to_string()), &rest[end + 1..]));
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::List(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            rest = match rest.strip_prefix(',') {
+                Some(after) => after,
+                None if rest.starts_with(']') => rest,
+                None => return Err("expected ',' or ']' in the array".to_string()),
+            };
+        }
+    }
+    let end = text.find(|c: char| c == ',' || c == ']' || c == '#' || c.is_whitespace()).unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        "" => return Err("missing value".to_string()),
+        _ if word.replace('_', "").parse::<f64>().is_ok() => Value::Number(word.replace('_', "")),
+        _ => return Err(format!("'{}' is not a value; put text in quotes", word)),
+    };
+    Ok((value, rest))
+}
+
+fn parse_string(text: &str) -> Result<(Value, &str), String> {
+    let mut value = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((Value::Text(value), &text[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some('t') => value.push('\t'),
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some(c) => return Err(format!("unknown escape '\\{}'", c)),
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(args: &[&str]) -> ArgMatches {
+        crate::cli().try_get_matches_from(std::iter::once("serial_logger").chain(args.iter().copied())).unwrap()
+    }
+
+    fn entries(args: &[&str]) -> Entries {
+        parse(&render(&crate::cli(), &matches(args), &[])).unwrap().entries
+    }
+
+    #[test]
+    fn given_options_are_entries() {
+        let entries = entries(&["-p", "/dev/ttyUSB0", "--baud", "9600", "--names", "t,x", "--per-run", "-o", "run_{run}.csv"]);
+        assert_eq!(
+            entries,
+            [
+                ("port".to_string(), Value::Text("/dev/ttyUSB0".to_string())),
+                ("baud".to_string(), Value::Number("9600".to_string())),
+                ("output".to_string(), Value::Text("run_{run}.csv".to_string())),
+                ("names".to_string(), Value::Text("t,x".to_string())),
+                ("per-run".to_string(), Value::Bool(true)),
+            ]
+        );
+    }
+
+    #[test]
+    fn defaults_are_comments() {
+        let text = render(&crate::cli(), &matches(&["-p", "/dev/ttyUSB0"]), &[]);
+        assert!(text.lines().any(|line| line == "# baud = 115200"));
+        assert!(text.lines().any(|line| line == "# match = \"UDP packet contents:\""));
+        // Flags that are off are left out
+        assert!(!text.contains("per-run"));
+    }
+
+    #[test]
+    fn repeated_options_are_arrays() {
+        let entries = entries(&["-p", "/dev/ttyUSB0", "--alarm", "f1 > 3:hi", "--alarm", "f2 < \"1\""]);
+        let alarms = entries.iter().find(|(key, _)| key == "alarm").map(|(_, value)| value.clone());
+        assert_eq!(
+            alarms,
+            Some(Value::List(vec![
+                Value::Text("f1 > 3:hi".to_string()),
+                Value::Text("f2 < \"1\"".to_string())
+            ]))
+        );
+    }
+
+    #[test]
+    fn the_printed_config_gives_the_same_options() {
+        let args = ["-p", "COM3,COM4", "--delimiter", "\t", "--match", "DATA #1:", "--gap-marker", "0.5", "--skip-lines", "1_000"];
+        let mut again = vec!["serial_logger".into()];
+        for (key, value) in entries(&args) {
+            push_option(&mut again, &key, &value);
+        }
+        let again = crate::cli().try_get_matches_from(again).unwrap();
+        let original = matches(&args);
+        for id in ["port", "delimiter", "match", "gap-marker", "skip-lines"] {
+            assert_eq!(again.get_one::<String>(id), original.get_one::<String>(id), "{}", id);
+        }
+    }
+
+    #[test]
+    fn several_ports_print_as_an_array() {
+        let entries = entries(&["-p", "/dev/ttyUSB0", "-p", "/dev/ttyUSB1"]);
+        assert_eq!(
+            entries[0],
+            (
+                "port".to_string(),
+                Value::List(vec![Value::Text("/dev/ttyUSB0".to_string()), Value::Text("/dev/ttyUSB1".to_string())])
+            )
+        );
+    }
+
+    // The ports given to the logger with `config` in a file and `args` after it
+    fn ports_with_config(config: &str, args: &[&str]) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logger.toml");
+        std::fs::write(&path, config).unwrap();
+        let mut given: Vec<OsString> = vec!["serial_logger".into(), "--config".into(), path.into()];
+        given.extend(args.iter().map(OsString::from));
+        let matches = crate::cli().try_get_matches_from(expand_args(given).unwrap()).unwrap();
+        matches.get_many::<String>("port").unwrap().cloned().collect()
+    }
+
+    #[test]
+    fn ports_on_the_command_line_replace_those_of_the_config() {
+        let config = "port = [\"/dev/ttyUSB0\", \"/dev/ttyUSB1\"]\nbaud = 9600\n";
+        assert_eq!(ports_with_config(config, &[]), ["/dev/ttyUSB0", "/dev/ttyUSB1"]);
+        assert_eq!(ports_with_config(config, &["-p", "/dev/ttyACM0"]), ["/dev/ttyACM0"]);
+        assert_eq!(ports_with_config(config, &["-p/dev/ttyACM0", "--port=/dev/ttyACM1"]), ["/dev/ttyACM0", "/dev/ttyACM1"]);
+        assert_eq!(ports_with_config("port = \"COM3\"\n", &["--port", "COM4"]), ["COM4"]);
+    }
+
+    const BLOCKS: &str = "fields = 3\nbaud = 115200\n\n[[port]]\nport = \"/dev/ttyUSB0\"\nlabel = \"imu\"\nbaud = 921600\nfields = 7\n\n[[port]] # the receiver\nport = \"/dev/ttyUSB1\"\nmatch = \"$GPGGA,\"\n";
+
+    #[test]
+    fn port_blocks_follow_the_top_level() {
+        let config = parse(BLOCKS).unwrap();
+        assert_eq!(
+            config.entries,
+            [("fields".to_string(), Value::Number("3".to_string())), ("baud".to_string(), Value::Number("115200".to_string()))]
+        );
+        assert_eq!(config.ports.len(), 2);
+        assert_eq!(config.ports[0][2], ("baud".to_string(), Value::Number("921600".to_string())));
+        assert_eq!(block_label(&config.ports[0]), "imu");
+        // Without a label, the port is the label
+        assert_eq!(block_label(&config.ports[1]), "/dev/ttyUSB1");
+    }
+
+    #[test]
+    fn port_blocks_only_hold_what_a_port_can_set() {
+        let error = |text: &str| parse(&format!("{}[[port]]\nport = \"COM4\"\n", text)).unwrap_err();
+        let port = "[[port]]\nport = \"COM3\"\n";
+        assert!(error(&format!("{}output = \"imu.csv\"\n", port)).contains("'output' can't be set per port"));
+        assert!(error(&format!("{}per-run = true\n", port)).contains("'per-run' can't be set per port"));
+        assert!(error(&format!("{}baud = [9600, 19200]\n", port)).contains("takes one value"));
+        assert!(error("port = \"COM1\"\n[[port]]\nport = \"COM3\"\n").contains("leave 'port' out of the top level"));
+        assert!(error("[[port]]\nbaud = 9600\n").contains("block 1 has no 'port'"));
+        assert!(error("[[port]]\nport = \"COM3\"\nlabel = \"COM4\"\n").contains("two [[port]] blocks are labelled COM4"));
+        assert!(parse(port).unwrap_err().contains("for reading several ports"));
+        assert!(parse("[[device]]\nport = \"COM3\"\n").unwrap_err().contains("the only blocks are [[port]]"));
+        assert!(parse_tables("[[imu]]\nbaud = 9600\n").unwrap_err().contains("only for a --config file"));
+    }
+
+    #[test]
+    fn port_blocks_give_the_ports_and_their_options_win() {
+        assert_eq!(ports_with_config(BLOCKS, &[]), ["/dev/ttyUSB0", "/dev/ttyUSB1"]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logger.toml");
+        std::fs::write(&path, BLOCKS).unwrap();
+        let given = |args: &[&str]| {
+            let mut given: Vec<OsString> = vec!["serial_logger".into(), "--config".into(), path.clone().into()];
+            given.extend(args.iter().map(OsString::from));
+            expand_args(given)
+        };
+        assert!(given(&["-p", "COM3"]).unwrap_err().contains("--port can't be given with a config of [[port]] blocks"));
+        let args = given(&["--baud", "57600", "--delimiter", ";"]).unwrap();
+        let blocks = load(&path).unwrap().ports;
+        let port = |block: &Entries| crate::cli().try_get_matches_from(block_args(&args, block)).unwrap();
+        let (imu, gps) = (port(&blocks[0]), port(&blocks[1]));
+        // A block's own options, then the command line's, then the config's
+        assert_eq!(imu.get_one::<String>("baud").unwrap(), "921600");
+        assert_eq!(gps.get_one::<String>("baud").unwrap(), "57600");
+        assert_eq!(imu.get_one::<String>("fields").unwrap(), "7");
+        assert_eq!(gps.get_one::<String>("fields").unwrap(), "3");
+        assert_eq!(gps.get_one::<String>("match").unwrap(), "$GPGGA,");
+        assert_eq!(imu.get_one::<String>("delimiter").unwrap(), ";");
+    }
+
+    #[test]
+    fn printed_port_blocks_read_back() {
+        let blocks = parse(BLOCKS).unwrap().ports;
+        let text = render(&crate::cli(), &matches(&["-p", "/dev/ttyUSB0", "-p", "/dev/ttyUSB1", "--fields", "3"]), &blocks);
+        let config = parse(&text).unwrap();
+        assert_eq!(config.ports, blocks);
+        assert!(config.entries.iter().all(|(key, _)| key != "port"));
+    }
+
+    #[test]
+    fn every_port_key_is_a_single_value_option() {
+        let command = crate::cli();
+        for key in PORT_KEYS.iter().filter(|key| !BLOCK_ONLY.contains(key)) {
+            let arg = command.get_arguments().find(|arg| arg.get_long() == Some(key));
+            let arg = arg.unwrap_or_else(|| panic!("{} is not an option", key));
+            assert!(!matches!(arg.get_action(), ArgAction::Append), "{} adds to the command line's", key);
+        }
+    }
+
+    #[test]
+    fn only_plain_numbers_are_numbers() {
+        assert_eq!(value_of("115200"), Value::Number("115200".to_string()));
+        assert_eq!(value_of("-0.5e3"), Value::Number("-0.5e3".to_string()));
+        for text in ["1_000", "inf", "NaN", "0x10", "5s", ""] {
+            assert_eq!(value_of(text), Value::Text(text.to_string()));
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Console output that doesn't trample the command prompt.
+//!
+//! Messages from the serial thread, the disk monitor, hooks and
+//! notifications arrive while the prompt is waiting for input. When the
+//! prompt's line editor is active on a terminal, messages go through its
+//! external printer, which prints them above the prompt and redraws the
+//! prompt with whatever has been typed so far. Without one (stdin or stdout
+//! not a terminal) they are printed plainly, in order. Output should go
+//! through `say!` (stdout, or stderr in JSON mode) and `esay!` (stderr)
+//! rather than `println!`/`eprintln!`; both also go to the `--log-file`.
+//!
+//! A port that keeps failing or a stream of bad lines must not flood the
+//! terminal: a line repeated back to back is printed once, followed by
+//! `(last message repeated 57 times)` when a different line arrives, a
+//! minute has passed or the prompt goes away, and past `LINES_PER_SECOND`
+//! lines in a second the rest of that second's lines are held back and
+//! counted. The log file gets every line, with its own collapsing of
+//! repeats.
+
+use rustyline::ExternalPrinter;
+
+use crate::logging;
+use std::{
+    io::IsTerminal,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Lines printed in a second before the rest are held back
+const LINES_PER_SECOND: u32 = 20;
+// How long repeats of a line are collapsed before their count is printed
+const REPEAT_WINDOW: Duration = Duration::from_secs(60);
+
+static PRINTER: Mutex<Option<Box<dyn ExternalPrinter + Send>>> = Mutex::new(None);
+static THROTTLE: Mutex<Option<Throttle>> = Mutex::new(None);
+
+// What `say` and `esay` have recently printed
+struct Throttle {
+    // The last line printed, whether it was a warning, and how often it has
+    // been repeated since
+    last: String,
+    last_warning: bool,
+    repeats: u64,
+    repeats_since: Instant,
+    // Start of the current second, the lines printed in it, and the lines
+    // held back since the last one printed
+    window_start: Instant,
+    window_lines: u32,
+    held_back: u64,
+}
+
+impl Throttle {
+    fn new(now: Instant) -> Throttle {
+        Throttle {
+            last: String::new(),
+            last_warning: false,
+            repeats: 0,
+            repeats_since: now,
+            window_start: now,
+            window_lines: 0,
+            held_back: 0,
+        }
+    }
+
+    // The lines to print for `text` at `now`, each with whether it is a
+    // warning: none when it repeats the last line or the second is used up,
+    // otherwise notes of what was left out before it and the line itself
+    fn admit(&mut self, text: &str, warning: bool, now: Instant) -> Vec<(String, bool)> {
+        if text == self.last && warning == self.last_warning && now - self.repeats_since < REPEAT_WINDOW {
+            self.repeats += 1;
+            return Vec::new();
+        }
+        if now - self.window_start >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.window_lines = 0;
+        }
+        if self.window_lines >= LINES_PER_SECOND {
+            self.held_back += 1;
+            return Vec::new();
+        }
+        self.window_lines += 1;
+        let mut lines = self.flush();
+        if self.held_back > 0 {
+            lines.push((format!("({} more messages not shown)", self.held_back), true));
+            self.held_back = 0;
+        }
+        lines.push((text.to_string(), warning));
+        self.last = text.to_string();
+        self.last_warning = warning;
+        self.repeats_since = now;
+        lines
+    }
+
+    // The count of repeats not printed yet, if any
+    fn flush(&mut self) -> Vec<(String, bool)> {
+        let mut lines = Vec::new();
+        if self.repeats > 0 {
+            lines.push((format!("(last message repeated {} times)", self.repeats), self.last_warning));
+            self.repeats = 0;
+        }
+        self.last.clear();
+        lines
+    }
+}
+
+/// Routes terminal output through the line editor's printer from now on.
+pub fn attach(printer: impl ExternalPrinter + Send + 'static) {
+    *PRINTER.lock().unwrap() = Some(Box::new(printer));
+}
+
+/// Prints directly again, for output after the prompt has gone away. A
+/// pending count of repeats is printed first.
+pub fn detach() {
+    let pending = THROTTLE.lock().unwrap().as_mut().map(Throttle::flush).unwrap_or_default();
+    print_lines(pending);
+    *PRINTER.lock().unwrap() = None;
+}
+
+/// Prints a line of human-oriented output.
+pub fn say(text: &str) {
+    logging::message(tracing::Level::INFO, text);
+    print_throttled(text, false);
+}
+
+/// Prints the prompt when there is no line editor; like `say`, but it isn't
+/// worth keeping in the log file.
+pub fn show(text: &str) {
+    if crate::events::json_mode() {
+        print_warning(text);
+    } else if !std::io::stdout().is_terminal() || !print_above_prompt(text) {
+        println!("{}", text);
+    }
+}
+
+/// Prints a warning or error line.
+pub fn esay(text: &str) {
+    logging::message(logging::level_of_warning(text), text);
+    print_throttled(text, true);
+}
+
+fn print_throttled(text: &str, warning: bool) {
+    let now = Instant::now();
+    let lines = THROTTLE
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| Throttle::new(now))
+        .admit(text, warning, now);
+    print_lines(lines);
+}
+
+fn print_lines(lines: Vec<(String, bool)>) {
+    for (line, warning) in lines {
+        if warning {
+            print_warning(&line);
+        } else {
+            show(&line);
+        }
+    }
+}
+
+/// Prints debugging output such as `--hexdump` to stderr; it is too bulky
+/// for the log file.
+pub fn diagnostic(text: &str) {
+    print_warning(text);
+}
+
+fn print_warning(text: &str) {
+    if !std::io::stderr().is_terminal() || !print_above_prompt(text) {
+        eprintln!("{}", text);
+    }
+}
+
+// Returns false if there is no printer, or it failed and has been dropped
+fn print_above_prompt(text: &str) -> bool {
+    let mut printer = PRINTER.lock().unwrap();
+    let Some(p) = printer.as_mut() else { return false };
+    if p.print(format!("{}\n", text)).is_ok() {
+        return true;
+    }
+    *printer = None;
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(lines: Vec<(String, bool)>) -> Vec<String> {
+        lines.into_iter().map(|(line, _)| line).collect()
+    }
+
+    #[test]
+    fn repeats_are_collapsed_into_a_count() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(start);
+        let error = "Error reading from serial port /dev/ttyUSB0: Broken pipe";
+        assert_eq!(texts(throttle.admit(error, true, start)), [error]);
+        for i in 1..=5 {
+            assert!(throttle.admit(error, true, start + Duration::from_secs(i)).is_empty());
+        }
+        let lines = throttle.admit("Serial port /dev/ttyUSB0 is back after 5.0s.", false, start + Duration::from_secs(6));
+        assert_eq!(
+            lines,
+            [
+                ("(last message repeated 5 times)".to_string(), true),
+                ("Serial port /dev/ttyUSB0 is back after 5.0s.".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeats_are_counted_at_most_a_minute() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(start);
+        throttle.admit("Warning: disk almost full", true, start);
+        assert!(throttle.admit("Warning: disk almost full", true, start + Duration::from_secs(30)).is_empty());
+        assert_eq!(
+            texts(throttle.admit("Warning: disk almost full", true, start + REPEAT_WINDOW)),
+            ["(last message repeated 1 times)", "Warning: disk almost full"]
+        );
+    }
+
+    #[test]
+    fn lines_past_the_limit_are_held_back_for_the_second() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(start);
+        let printed: usize = (0..100)
+            .map(|i| throttle.admit(&format!("Warning: bad line {}", i), true, start).len())
+            .sum();
+        assert_eq!(printed, LINES_PER_SECOND as usize);
+        assert_eq!(
+            texts(throttle.admit("Warning: bad line 100", true, start + Duration::from_secs(1))),
+            ["(80 more messages not shown)", "Warning: bad line 100"]
+        );
+    }
+
+    #[test]
+    fn flush_gives_the_pending_count_once() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(start);
+        throttle.admit("Recording started.", false, start);
+        throttle.admit("Recording started.", false, start);
+        assert_eq!(throttle.flush(), [("(last message repeated 1 times)".to_string(), false)]);
+        assert!(throttle.flush().is_empty());
+    }
+}
@@ -0,0 +1,60 @@
+//! `--control-file PATH`: commands from a file, for a logger with no console
+//! to type them at, such as the Windows service of `service.rs`.
+//!
+//! The file is checked once a second. Whenever it is written (its content or
+//! its modification time changes), its first line is carried out like a
+//! command typed at the prompt: `start [label]`, `stop`, `newfile PATH` and
+//! the rest go through the same dispatch, and `exit` ends the session like
+//! the exit command. What the file says when the logger starts is left alone,
+//! so a stale `start` or `exit` from the last session does nothing;
+//! `--auto-start` records from the start.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::commands;
+use crate::serial::Control;
+use crate::session::Session;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// The modification time and content of the file, if it can be read
+fn read(path: &Path) -> Option<(SystemTime, String)> {
+    let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok()?;
+    let text = fs::read_to_string(path).ok()?;
+    Some((modified, text))
+}
+
+/// Carries out the commands written to `path` from a background thread.
+/// `exit` ends the program on an `exit` command.
+pub fn spawn(path: PathBuf, session: Arc<Mutex<Session>>, serial_control: Sender<Control>, exit: Box<dyn Fn() + Send>) {
+    say!("Taking commands from {}.", path.display());
+    let mut last = read(&path);
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        let current = read(&path);
+        if current == last {
+            continue;
+        }
+        last = current;
+        let Some((_, text)) = &last else { continue };
+        let line = text.lines().next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        say!("{}: {}", path.display(), line);
+        let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        if command == "exit" {
+            session.lock().unwrap().audit(line);
+            say!("Exiting...");
+            exit();
+        } else {
+            commands::dispatch(&mut session.lock().unwrap(), &serial_control, command, args.trim());
+        }
+    });
+}
@@ -0,0 +1,361 @@
+//! `convert`: turns an output CSV into JSON Lines or a JSON array, offline.
+//!
+//! The file is read with the library's `logfile` reader, the same one the
+//! other offline subcommands use. With `--shape flat` every row becomes one
+//! object tagged with its `type` (`meta`, `start`, `stop`, `data` or a marker
+//! kind); data rows carry their run's number and ID and one key per column,
+//! and a `units` row becomes a `units` object with the unit of each column
+//! that has one. With `--shape nested` there is one `meta` object with all
+//! metadata (and the units under `units`) and one `run` object per run
+//! holding its data rows and markers. Values that parse
+//! as numbers are written as JSON numbers and empty values as null.
+//!
+//! JSON Lines output is streamed; a JSON array is assembled in memory first.
+
+use collect_acc_data::logfile::{Kind, LogReader, Row};
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Jsonl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Flat,
+    Nested,
+}
+
+pub struct ConvertOptions {
+    pub output: String,
+    pub format: Format,
+    pub shape: Shape,
+    // Skip rows that can't be read instead of stopping at the first
+    pub lenient: bool,
+}
+
+/// What a conversion produced.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub runs: u64,
+    pub rows: u64,
+    // Rows skipped with --lenient
+    pub skipped: u64,
+}
+
+impl Format {
+    pub fn parse(text: &str) -> Result<Format, String> {
+        match text {
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::Jsonl),
+            _ => Err(format!("Invalid --to '{}': expected json or jsonl", text)),
+        }
+    }
+}
+
+impl Shape {
+    pub fn parse(text: &str) -> Result<Shape, String> {
+        match text {
+            "flat" => Ok(Shape::Flat),
+            "nested" => Ok(Shape::Nested),
+            _ => Err(format!("Invalid --shape '{}': expected flat or nested", text)),
+        }
+    }
+}
+
+// A JSON object that keeps its keys in the order they were added
+#[derive(Default)]
+struct Object(Vec<(String, Field)>);
+
+// serde_json's own objects sort their keys, so nested objects stay `Object`s
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Field {
+    Value(Value),
+    Object(Object),
+    List(Vec<Object>),
+}
+
+impl Object {
+    fn with_type(kind: &str) -> Object {
+        let mut object = Object::default();
+        object.insert("type", kind);
+        object
+    }
+
+    fn insert(&mut self, key: &str, value: impl Into<Value>) {
+        self.0.push((key.to_string(), Field::Value(value.into())));
+    }
+
+    fn insert_field(&mut self, key: &str, field: Field) {
+        self.0.push((key.to_string(), field));
+    }
+}
+
+impl Serialize for Object {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in &self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// A CSV value as JSON: a number if it reads as one, null if empty.
+pub fn value(text: &str) -> Value {
+    if text.is_empty() {
+        return Value::Null;
+    }
+    if let Ok(n) = text.parse::<i64>() {
+        return n.into();
+    }
+    match text.parse::<f64>().ok().and_then(serde_json::Number::from_f64) {
+        Some(n) => Value::Number(n),
+        None => text.into(),
+    }
+}
+
+// Run number and ID as JSON, null outside a run
+fn run_fields(object: &mut Object, run: Option<&(Option<i64>, String)>) {
+    object.insert("run", run.and_then(|(number, _)| *number));
+    object.insert("run_id", run.map(|(_, id)| id.clone()).filter(|id| !id.is_empty()));
+}
+
+fn marker(kind: &Kind, timestamp: &str, text: &str, values: &[String]) -> Object {
+    let mut object = Object::with_type(kind.as_str());
+    object.insert("timestamp", timestamp);
+    object.insert("text", text);
+    if values.iter().any(|v| !v.is_empty()) {
+        object.insert("values", values.iter().map(|v| value(v)).collect::<Vec<_>>());
+    }
+    object
+}
+
+// All metadata of the file, for --shape nested
+fn meta_object(entries: Object) -> Object {
+    let mut object = Object::with_type(Kind::Meta.as_str());
+    object.insert_field("entries", Field::Object(entries));
+    object
+}
+
+// A run being assembled for --shape nested
+struct NestedRun {
+    object: Object,
+    rows: Vec<Object>,
+    markers: Vec<Object>,
+}
+
+impl NestedRun {
+    fn new(run: Option<i64>, run_id: &str, started: Option<&str>) -> NestedRun {
+        let mut object = Object::with_type("run");
+        object.insert("run", run);
+        object.insert("run_id", Some(run_id.to_string()).filter(|id| !id.is_empty()));
+        object.insert("started", started);
+        NestedRun {
+            object,
+            rows: Vec::new(),
+            markers: Vec::new(),
+        }
+    }
+
+    fn finish(mut self, stopped: Option<(&str, &str)>) -> Object {
+        self.object.insert("stopped", stopped.map(|(timestamp, _)| timestamp));
+        self.object.insert("stop_note", stopped.map(|(_, note)| note));
+        self.object.insert_field("rows", Field::List(self.rows));
+        self.object.insert_field("markers", Field::List(self.markers));
+        self.object
+    }
+}
+
+// Where finished objects go: straight to the file, or collected for an array
+struct Output {
+    writer: BufWriter<File>,
+    format: Format,
+    collected: Vec<Object>,
+}
+
+impl Output {
+    fn push(&mut self, object: Object) -> std::io::Result<()> {
+        match self.format {
+            Format::Jsonl => {
+                serde_json::to_writer(&mut self.writer, &object)?;
+                self.writer.write_all(b"\n")
+            }
+            Format::Json => {
+                self.collected.push(object);
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        if self.format == Format::Json {
+            serde_json::to_writer_pretty(&mut self.writer, &self.collected)?;
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()
+    }
+}
+
+/// Converts the output file at `input` into `options.output`.
+pub fn run(input: &Path, options: &ConvertOptions) -> Result<Report, String> {
+    let file = File::open(input).map_err(|e| format!("Failed to open {}: {}", input.display(), e))?;
+    let out = File::create(&options.output)
+        .map_err(|e| format!("Failed to create {}: {}", options.output, e))?;
+    let mut output = Output {
+        writer: BufWriter::new(out),
+        format: options.format,
+        collected: Vec::new(),
+    };
+    let write_failed = |e: std::io::Error| format!("Failed to write {}: {}", options.output, e);
+
+    let mut report = Report::default();
+    let mut reader = LogReader::new(file);
+    let mut columns: Vec<String> = Vec::new();
+    // Number and ID of the run the rows belong to
+    let mut current: Option<(Option<i64>, String)> = None;
+    let mut meta_entries = Object::default();
+    let mut nested: Option<NestedRun> = None;
+    while let Some(line) = reader.next() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) if options.lenient => {
+                esay!("Warning: {}; skipped.", e);
+                report.skipped += 1;
+                continue;
+            }
+            Err(e) => return Err(format!("{}: {}", input.display(), e)),
+        };
+        if columns.is_empty() {
+            columns = reader.columns();
+            // The units row is read with the header
+            let units = reader.column_units();
+            if !units.is_empty() {
+                let mut object = match options.shape {
+                    Shape::Flat => Object::with_type(Kind::Units.as_str()),
+                    Shape::Nested => Object::default(),
+                };
+                for (column, unit) in &units {
+                    object.insert(column, unit.as_str());
+                }
+                match options.shape {
+                    Shape::Flat => output.push(object).map_err(write_failed)?,
+                    Shape::Nested => meta_entries.insert_field("units", Field::Object(object)),
+                }
+            }
+        }
+        // All metadata comes before the header and goes out in one object
+        if options.shape == Shape::Nested && !matches!(line.row, Row::Meta { .. }) && !meta_entries.0.is_empty() {
+            output.push(meta_object(std::mem::take(&mut meta_entries))).map_err(write_failed)?;
+        }
+        match (&line.row, options.shape) {
+            (Row::Meta { key, value }, Shape::Flat) => {
+                let mut object = Object::with_type(Kind::Meta.as_str());
+                object.insert("key", key.as_str());
+                object.insert("value", value.as_str());
+                output.push(object).map_err(write_failed)?;
+            }
+            (Row::Meta { key, value }, Shape::Nested) => meta_entries.insert(key, value.as_str()),
+            (Row::Start { timestamp, run, run_id }, shape) => {
+                report.runs += 1;
+                current = Some((*run, run_id.clone()));
+                if shape == Shape::Flat {
+                    let mut object = Object::with_type(Kind::Start.as_str());
+                    object.insert("timestamp", timestamp.as_str());
+                    run_fields(&mut object, current.as_ref());
+                    output.push(object).map_err(write_failed)?;
+                } else {
+                    // The previous run never stopped
+                    if let Some(run) = nested.take() {
+                        output.push(run.finish(None)).map_err(write_failed)?;
+                    }
+                    nested = Some(NestedRun::new(*run, run_id, Some(timestamp)));
+                }
+            }
+            (Row::Stop { timestamp, note, run_id }, Shape::Flat) => {
+                let mut object = Object::with_type(Kind::Stop.as_str());
+                object.insert("timestamp", timestamp.as_str());
+                object.insert("note", note.as_str());
+                let run = current.take().map(|(number, id)| (number, if id.is_empty() { run_id.clone() } else { id }));
+                run_fields(&mut object, run.as_ref());
+                output.push(object).map_err(write_failed)?;
+            }
+            (Row::Stop { timestamp, note, .. }, Shape::Nested) => {
+                current = None;
+                if let Some(run) = nested.take() {
+                    output.push(run.finish(Some((timestamp, note)))).map_err(write_failed)?;
+                }
+            }
+            // Opens the part of a run in this file, or closes it
+            (Row::Continued { timestamp, run, run_id }, shape) => {
+                let opens = current.is_none();
+                if opens {
+                    report.runs += 1;
+                }
+                let part = (*run, run_id.clone());
+                if shape == Shape::Flat {
+                    let mut object = Object::with_type(Kind::Continued.as_str());
+                    object.insert("timestamp", timestamp.as_str());
+                    run_fields(&mut object, Some(&part));
+                    output.push(object).map_err(write_failed)?;
+                } else if opens {
+                    // Rows before it are a run of their own
+                    if let Some(run) = nested.take() {
+                        output.push(run.finish(None)).map_err(write_failed)?;
+                    }
+                    nested = Some(NestedRun::new(*run, run_id, Some(timestamp)));
+                } else if let Some(run) = nested.take() {
+                    output.push(run.finish(Some((timestamp, "continued")))).map_err(write_failed)?;
+                }
+                current = opens.then_some(part);
+            }
+            (Row::Data { timestamp, values }, shape) => {
+                report.rows += 1;
+                let mut object = if shape == Shape::Flat {
+                    let mut object = Object::with_type(Kind::Data.as_str());
+                    object.insert("timestamp", timestamp.as_str());
+                    run_fields(&mut object, current.as_ref());
+                    object
+                } else {
+                    let mut object = Object::default();
+                    object.insert("timestamp", timestamp.as_str());
+                    object
+                };
+                for (column, text) in columns.iter().zip(values) {
+                    object.insert(column, value(text));
+                }
+                match shape {
+                    Shape::Flat => output.push(object).map_err(write_failed)?,
+                    // Rows outside any run get a run of their own
+                    Shape::Nested => nested.get_or_insert_with(|| NestedRun::new(None, "", None)).rows.push(object),
+                }
+            }
+            (Row::Marker { kind, timestamp, text, values }, Shape::Flat) => {
+                let mut object = marker(kind, timestamp, text, values);
+                run_fields(&mut object, current.as_ref());
+                output.push(object).map_err(write_failed)?;
+            }
+            (Row::Marker { kind, timestamp, text, values }, Shape::Nested) => {
+                let object = marker(kind, timestamp, text, values);
+                nested.get_or_insert_with(|| NestedRun::new(None, "", None)).markers.push(object);
+            }
+        }
+    }
+    if !meta_entries.0.is_empty() {
+        output.push(meta_object(meta_entries)).map_err(write_failed)?;
+    }
+    // A run without a stop row (a crashed session)
+    if let Some(run) = nested.take() {
+        output.push(run.finish(None)).map_err(write_failed)?;
+    }
+    output.finish().map_err(write_failed)?;
+    Ok(report)
+}
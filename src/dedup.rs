@@ -0,0 +1,40 @@
+//! `--dedup-consecutive` and `--dedup-window`: dropping repeated rows.
+//!
+//! A flaky link can deliver the same line two or three times in a row. A
+//! data row whose parsed payload is identical to one of the last N rows
+//! written (the last one by default) is dropped and counted instead. Marker
+//! rows never go through here, and every run starts with an empty window.
+
+use std::collections::VecDeque;
+
+/// The payloads of the rows written most recently, newest last.
+pub struct Dedup {
+    window: usize,
+    recent: VecDeque<Vec<String>>,
+}
+
+impl Dedup {
+    pub fn new(window: usize) -> Dedup {
+        Dedup {
+            window: window.max(1),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Whether `payload` repeats a recent row; if not, it becomes the most
+    /// recent one.
+    pub fn is_duplicate(&mut self, payload: &[&str]) -> bool {
+        if self.recent.iter().any(|row| row.iter().map(String::as_str).eq(payload.iter().copied())) {
+            return true;
+        }
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(payload.iter().map(|field| field.to_string()).collect());
+        false
+    }
+
+    pub fn reset(&mut self) {
+        self.recent.clear();
+    }
+}
@@ -0,0 +1,61 @@
+//! Start and stop commands sent by the device, with `--device-commands`.
+//!
+//! Firmware can print a control line such as `##CMD:START drop-test##` or
+//! `##CMD:STOP##` (e.g. when a button on the device is pressed). The serial
+//! thread takes such lines out of the data and starts or stops a run exactly
+//! like the `start` and `stop` commands would, with `device` after the run ID
+//! in the start and stop rows. Commands from the device and from the operator
+//! act in the order they arrive: the last one wins, and both are logged.
+
+/// Default text that starts a command line.
+pub const DEFAULT_PREFIX: &str = "##CMD:";
+// Closes a command, optional
+const SUFFIX: &str = "##";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceCommand {
+    // START with the rest of the line as the run label
+    Start(String),
+    Stop,
+    // Anything else after the prefix
+    Unknown(String),
+}
+
+pub struct DeviceCommands {
+    prefix: String,
+    // Carries out a command; runs on the serial thread
+    handler: Box<dyn Fn(DeviceCommand) + Send>,
+}
+
+impl DeviceCommands {
+    pub fn new(prefix: &str, handler: Box<dyn Fn(DeviceCommand) + Send>) -> DeviceCommands {
+        DeviceCommands {
+            prefix: prefix.to_string(),
+            handler,
+        }
+    }
+
+    /// Carries out the command if `line` is a command line; false for any
+    /// other line.
+    pub fn handle(&self, line: &str) -> bool {
+        match self.parse(line) {
+            Some(command) => {
+                (self.handler)(command);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn parse(&self, line: &str) -> Option<DeviceCommand> {
+        let start = line.find(self.prefix.as_str())?;
+        let rest = line[start + self.prefix.len()..].trim();
+        let rest = rest.strip_suffix(SUFFIX).unwrap_or(rest).trim();
+        let (verb, argument) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        Some(match verb.to_ascii_uppercase().as_str() {
+            "START" => DeviceCommand::Start(argument.trim().to_string()),
+            "STOP" => DeviceCommand::Stop,
+            _ => DeviceCommand::Unknown(rest.to_string()),
+        })
+    }
+}
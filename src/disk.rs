@@ -0,0 +1,70 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::Duration,
+};
+
+use crate::events::{self, Event};
+use crate::session::Shared;
+use crate::units::format_size;
+
+// How often free space is re-checked while running
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Directory whose filesystem receives the output: the part of the output
+/// template before any placeholder, walked up to the nearest existing
+/// directory.
+pub fn output_dir(template: &str) -> PathBuf {
+    let fixed = template.split('{').next().unwrap_or("");
+    let mut dir = Path::new(fixed).parent().map(Path::to_path_buf).unwrap_or_default();
+    while !dir.as_os_str().is_empty() && !dir.is_dir() {
+        dir = dir.parent().map(Path::to_path_buf).unwrap_or_default();
+    }
+    if dir.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        dir
+    }
+}
+
+/// Warns (once until space recovers) when free space drops below `min_free`.
+/// Returns whether space is currently low.
+pub fn check_free_space(dir: &Path, min_free: u64, already_warned: bool) -> bool {
+    let free = match fs2::available_space(dir) {
+        Ok(free) => free,
+        Err(e) => {
+            if !already_warned {
+                esay!("Warning: Could not check free space in {}: {}", dir.display(), e);
+            }
+            return already_warned;
+        }
+    };
+    let low = free < min_free;
+    if low && !already_warned {
+        esay!(
+            "Warning: Only {} free in {} (threshold {})",
+            format_size(free),
+            dir.display(),
+            format_size(min_free)
+        );
+        events::emit(Event::LowDiskSpace {
+            path: dir.display().to_string(),
+            free_bytes: free,
+            threshold_bytes: min_free,
+        });
+    }
+    low
+}
+
+// Spawn a thread re-checking free space every minute for the whole session
+pub fn spawn_monitor(dir: PathBuf, min_free: u64, shared: Arc<Shared>) {
+    thread::spawn(move || {
+        let mut warned = shared.low_disk.load(Ordering::Relaxed);
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+            warned = check_free_space(&dir, min_free, warned);
+            shared.low_disk.store(warned, Ordering::Relaxed);
+        }
+    });
+}
@@ -0,0 +1,239 @@
+//! Payloads sent as hex or base64 text, for `--payload-encoding`.
+//!
+//! Some radios and gateways print the bytes they received as text after the
+//! prefix, e.g. `UDP packet contents: 3A4F12...`. The payload is decoded to
+//! bytes first and then read as an embedded text record or as one binary
+//! frame (`--inner-protocol`). Whitespace anywhere in the payload is ignored,
+//! so hex grouped as `3A 4F 12` and base64 wrapped over a space decode the
+//! same. Base64 uses the standard alphabet; the `=` padding is optional.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    // The payload is the record itself
+    Plain,
+    Hex,
+    Base64,
+}
+
+/// Why a payload couldn't be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    // A character outside the encoding's alphabet, at this offset of the payload
+    InvalidChar { offset: usize, found: char },
+    // Characters left over that don't make a whole byte
+    Incomplete,
+    // Base64 `=` padding followed by more data
+    Padding,
+    Empty,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::InvalidChar { offset, found } => write!(f, "invalid character '{}' at offset {}", found, offset),
+            DecodeError::Incomplete => f.write_str("incomplete last byte"),
+            DecodeError::Padding => f.write_str("data after the padding"),
+            DecodeError::Empty => f.write_str("empty payload"),
+        }
+    }
+}
+
+impl Encoding {
+    /// Parses a `--payload-encoding` value.
+    pub fn parse(name: &str) -> Option<Encoding> {
+        match name {
+            "plain" => Some(Encoding::Plain),
+            "hex" => Some(Encoding::Hex),
+            "base64" => Some(Encoding::Base64),
+            _ => None,
+        }
+    }
+
+    /// The bytes of a payload.
+    pub fn decode(self, payload: &str) -> Result<Vec<u8>, DecodeError> {
+        match self {
+            Encoding::Plain => Ok(payload.as_bytes().to_vec()),
+            Encoding::Hex => decode_hex(payload),
+            Encoding::Base64 => decode_base64(payload),
+        }
+    }
+}
+
+// The payload's characters that aren't whitespace, with their offsets
+fn significant(payload: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    payload.char_indices().filter(|(_, c)| !c.is_whitespace())
+}
+
+fn decode_hex(payload: &str) -> Result<Vec<u8>, DecodeError> {
+    let mut bytes = Vec::with_capacity(payload.len() / 2);
+    let mut high = None;
+    for (offset, c) in significant(payload) {
+        let digit = c.to_digit(16).ok_or(DecodeError::InvalidChar { offset, found: c })? as u8;
+        match high.take() {
+            Some(high) => bytes.push(high << 4 | digit),
+            None => high = Some(digit),
+        }
+    }
+    if high.is_some() {
+        return Err(DecodeError::Incomplete);
+    }
+    if bytes.is_empty() {
+        return Err(DecodeError::Empty);
+    }
+    Ok(bytes)
+}
+
+fn decode_base64(payload: &str) -> Result<Vec<u8>, DecodeError> {
+    let mut bytes = Vec::with_capacity(payload.len() * 3 / 4);
+    // Bits read but not yet a whole byte, and how many
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    let mut padding = 0;
+    for (offset, c) in significant(payload) {
+        if c == '=' {
+            padding += 1;
+            continue;
+        }
+        if padding > 0 {
+            return Err(DecodeError::Padding);
+        }
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' => 62,
+            '/' => 63,
+            _ => return Err(DecodeError::InvalidChar { offset, found: c }),
+        };
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    // Six bits left over can't be part of a byte; two or four are the
+    // padding bits of the last group
+    if count == 6 || padding > 2 {
+        return Err(DecodeError::Incomplete);
+    }
+    if bytes.is_empty() {
+        return Err(DecodeError::Empty);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+    }
+
+    fn base64(bytes: &[u8]) -> String {
+        let mut text = String::new();
+        for chunk in bytes.chunks(3) {
+            let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| group | (*byte as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    text.push(ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+                } else {
+                    text.push('=');
+                }
+            }
+        }
+        text
+    }
+
+    // Payloads of every length up to 300 bytes, of bytes that look random
+    fn payloads() -> impl Iterator<Item = Vec<u8>> {
+        let mut state: u32 = 0x2545_F491;
+        (1..=300).map(move |len| {
+            (0..len)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    (state >> 16) as u8
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        for payload in payloads() {
+            let text = hex(&payload);
+            assert_eq!(Encoding::Hex.decode(&text), Ok(payload.clone()));
+            assert_eq!(Encoding::Hex.decode(&text.to_lowercase()), Ok(payload.clone()));
+        }
+    }
+
+    #[test]
+    fn whitespace_in_hex_is_ignored() {
+        for payload in payloads() {
+            let text = hex(&payload);
+            // Grouped in bytes, in words split mid-byte, and over lines
+            let bytes: Vec<&str> = (0..text.len()).step_by(2).map(|i| &text[i..i + 2]).collect();
+            let spaced = bytes.join(" ");
+            let odd: String = text.chars().enumerate().flat_map(|(i, c)| [c].into_iter().chain((i % 3 == 2).then_some(' '))).collect();
+            let lines = format!(" {}\r\n\t", bytes.join("\n"));
+            for text in [spaced, odd, lines] {
+                assert_eq!(Encoding::Hex.decode(&text), Ok(payload.clone()), "{:?}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn base64_round_trips_with_and_without_padding() {
+        for payload in payloads() {
+            let text = base64(&payload);
+            assert_eq!(Encoding::Base64.decode(&text), Ok(payload.clone()));
+            assert_eq!(Encoding::Base64.decode(text.trim_end_matches('=')), Ok(payload.clone()));
+            let wrapped: Vec<&str> = (0..text.len()).step_by(76).map(|i| &text[i..(i + 76).min(text.len())]).collect();
+            assert_eq!(Encoding::Base64.decode(&wrapped.join("\n")), Ok(payload.clone()));
+        }
+    }
+
+    #[test]
+    fn base64_matches_the_rfc_examples() {
+        for (text, payload) in [
+            ("Zg==", "f"),
+            ("Zm8=", "fo"),
+            ("Zm9v", "foo"),
+            ("Zm9vYg==", "foob"),
+            ("Zm9vYmE=", "fooba"),
+            ("Zm9vYmFy", "foobar"),
+        ] {
+            assert_eq!(base64(payload.as_bytes()), text);
+            assert_eq!(Encoding::Base64.decode(text), Ok(payload.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn plain_payloads_are_their_bytes() {
+        assert_eq!(Encoding::Plain.decode("1,2,3"), Ok(b"1,2,3".to_vec()));
+        assert_eq!(Encoding::parse("hex"), Some(Encoding::Hex));
+        assert_eq!(Encoding::parse("HEX"), None);
+    }
+
+    #[test]
+    fn bad_payloads_are_errors() {
+        assert_eq!(Encoding::Hex.decode("3A 4G"), Err(DecodeError::InvalidChar { offset: 4, found: 'G' }));
+        assert_eq!(Encoding::Hex.decode("3A4"), Err(DecodeError::Incomplete));
+        assert_eq!(Encoding::Hex.decode(" \t"), Err(DecodeError::Empty));
+        assert_eq!(Encoding::Base64.decode("Zm9v!"), Err(DecodeError::InvalidChar { offset: 4, found: '!' }));
+        assert_eq!(Encoding::Base64.decode("Zg==Zg=="), Err(DecodeError::Padding));
+        assert_eq!(Encoding::Base64.decode("Zm9vY"), Err(DecodeError::Incomplete));
+        assert_eq!(Encoding::Base64.decode("Zg==="), Err(DecodeError::Incomplete));
+        assert_eq!(Encoding::Base64.decode(""), Err(DecodeError::Empty));
+        assert_eq!(
+            DecodeError::InvalidChar { offset: 4, found: 'G' }.to_string(),
+            "invalid character 'G' at offset 4"
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! Machine-readable event stream enabled with `--events-json`.
+//!
+//! Every event is written to stdout as a single line of JSON. All events share
+//! two fields: `event` (the event name) and `timestamp` (local time,
+//! `YYYY-MM-DD HH:MM:SS`). The remaining fields depend on the event:
+//!
+//! | event               | fields                                           |
+//! |---------------------|--------------------------------------------------|
+//! | `startup`           | `port`, `baud`, `output`, `version`              |
+//! | `port_opened`       | `port`, `baud`                                   |
+//! | `port_reconfigured` | `port`, `baud`                                   |
+//! | `port_failover`     | `from`, `to`, `baud`                             |
+//! | `recording_started` | `run`, `run_id`, `label`, `file`                 |
+//! | `recording_stopped` | `run`, `file`, `rows`                            |
+//! | `run_summary`       | `run`, `run_id`, `file`, `rows`, `duration_secs` |
+//! | `low_disk_space`    | `path`, `free_bytes`, `threshold_bytes`          |
+//! | `disk_full`         | `run`, `file`                                    |
+//! | `cap_reached`       | `cap`, `detail`                                  |
+//! | `strict_failure`    | `check`, `detail`                                |
+//! | `output_error`      | `file`, `error`                                  |
+//! | `output_recovered`  | `file`, `outage_secs`, `rows_replayed`, `rows_dropped` |
+//! | `serial_error`      | `port`, `error`                                  |
+//! | `port_reconnected`  | `port`, `baud`, `down_secs`                      |
+//! | `alarm`             | `run`, `label`, `condition`, `values`            |
+//! | `upload_completed`  | `file`, `url`, `bytes`                           |
+//! | `upload_failed`     | `file`, `url`, `attempt`, `error`, `retrying`    |
+//! | `shutdown`          | `runs`, `exit_code`                              |
+//!
+//! Field names and meanings are stable; new events and new fields may be
+//! added, so consumers should ignore anything they don't recognize. While the
+//! stream is enabled, human-oriented prompts and messages go to stderr.
+//!
+//! `serial_error` is emitted once when reading the port starts failing (e.g.
+//! the device was unplugged), not for every failed retry; `port_reconnected`
+//! when reading works again, `down_secs` after the failure. `port_failover` is
+//! emitted when the logger switches to a backup port given with `--port`, or
+//! back to the first one with `--failback`. `alarm` is emitted when an
+//! `--alarm` condition becomes true; `values` are the row's fields.
+//! `cap_reached` is emitted when `--max-duration` or `--max-file-size` is hit,
+//! before the run is stopped; `cap` names the option. `strict_failure` is
+//! emitted when a `--strict` check fails, before the run is stopped; `check`
+//! names the check.
+//! `output_error` is emitted when writing the output has failed
+//! `--write-error-limit` times in a row and rows are held in memory instead;
+//! `output_recovered` when the file (or `--fallback-output`, named in `file`)
+//! could be written again and the held rows were written.
+//! `upload_failed` is emitted for every failed attempt of an `--upload-url`
+//! upload; `retrying` is false once it is given up on for this session. The same
+//! JSON is POSTed to `--notify-url` for the events listed in `notify.rs`, and
+//! some events ring the bell or show a desktop notification with `--notify`.
+
+use serde::Serialize;
+use std::{
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::alert;
+use crate::notify;
+#[cfg(feature = "zmq")]
+use crate::zmq;
+
+static JSON_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Turns the JSON event stream on or off for the whole process.
+pub fn set_json_mode(enabled: bool) {
+    JSON_MODE.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether `--events-json` is active.
+pub fn json_mode() -> bool {
+    JSON_MODE.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Startup {
+        port: String,
+        baud: u32,
+        output: String,
+        version: String,
+    },
+    PortOpened {
+        port: String,
+        baud: u32,
+    },
+    PortReconfigured {
+        port: String,
+        baud: u32,
+    },
+    PortFailover {
+        from: String,
+        to: String,
+        baud: u32,
+    },
+    RecordingStarted {
+        run: i64,
+        run_id: String,
+        label: String,
+        file: String,
+    },
+    RecordingStopped {
+        run: i64,
+        file: String,
+        rows: u64,
+    },
+    RunSummary {
+        run: i64,
+        run_id: String,
+        file: String,
+        rows: u64,
+        duration_secs: f64,
+    },
+    LowDiskSpace {
+        path: String,
+        free_bytes: u64,
+        threshold_bytes: u64,
+    },
+    DiskFull {
+        run: i64,
+        file: String,
+    },
+    CapReached {
+        cap: String,
+        detail: String,
+    },
+    StrictFailure {
+        check: String,
+        detail: String,
+    },
+    OutputError {
+        file: String,
+        error: String,
+    },
+    OutputRecovered {
+        file: String,
+        outage_secs: f64,
+        rows_replayed: u64,
+        rows_dropped: u64,
+    },
+    SerialError {
+        port: String,
+        error: String,
+    },
+    PortReconnected {
+        port: String,
+        baud: u32,
+        down_secs: f64,
+    },
+    Alarm {
+        run: i64,
+        label: String,
+        condition: String,
+        values: Vec<String>,
+    },
+    UploadCompleted {
+        file: String,
+        url: String,
+        bytes: u64,
+    },
+    UploadFailed {
+        file: String,
+        url: String,
+        attempt: u32,
+        error: String,
+        retrying: bool,
+    },
+    Shutdown {
+        runs: i64,
+        exit_code: i32,
+    },
+}
+
+#[derive(Serialize)]
+struct EventLine<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    timestamp: String,
+}
+
+/// Serializes an event to its single-line JSON form.
+pub fn to_json_line(event: &Event, timestamp: &str) -> String {
+    let line = EventLine {
+        event,
+        timestamp: timestamp.to_string(),
+    };
+    serde_json::to_string(&line).expect("Events always serialize")
+}
+
+/// Writes the event to stdout if the event stream is enabled, and passes it
+/// on to the webhook if it is one that gets sent there.
+pub fn emit(event: Event) {
+    alert::on_event(&event);
+    #[cfg(feature = "zmq")]
+    zmq::on_event(&event);
+    let notify = notify::wanted(&event);
+    if !json_mode() && !notify {
+        return;
+    }
+    let line = to_json_line(&event, &crate::get_timestamp());
+    if notify {
+        notify::send(line.clone());
+    }
+    if !json_mode() {
+        return;
+    }
+    let mut out = std::io::stdout().lock();
+    // A closed stdout means nobody is listening; keep logging regardless
+    let _ = writeln!(out, "{}", line);
+    let _ = out.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AT: &str = "2025-01-14 10:21:07";
+
+    #[test]
+    fn recording_started_fields() {
+        let event = Event::RecordingStarted {
+            run: 3,
+            run_id: "0b6f4c1e".to_string(),
+            label: "warmup".to_string(),
+            file: "data/run_003.csv".to_string(),
+        };
+        assert_eq!(
+            to_json_line(&event, AT),
+            r#"{"event":"recording_started","run":3,"run_id":"0b6f4c1e","label":"warmup","file":"data/run_003.csv","timestamp":"2025-01-14 10:21:07"}"#
+        );
+    }
+
+    #[test]
+    fn recording_stopped_fields() {
+        let event = Event::RecordingStopped {
+            run: 3,
+            file: "data/run_003.csv".to_string(),
+            rows: 1200,
+        };
+        assert_eq!(
+            to_json_line(&event, AT),
+            r#"{"event":"recording_stopped","run":3,"file":"data/run_003.csv","rows":1200,"timestamp":"2025-01-14 10:21:07"}"#
+        );
+    }
+
+    #[test]
+    fn port_reconnected_fields() {
+        let event = Event::PortReconnected {
+            port: "/dev/ttyUSB0".to_string(),
+            baud: 115200,
+            down_secs: 4.5,
+        };
+        assert_eq!(
+            to_json_line(&event, AT),
+            r#"{"event":"port_reconnected","port":"/dev/ttyUSB0","baud":115200,"down_secs":4.5,"timestamp":"2025-01-14 10:21:07"}"#
+        );
+    }
+
+    #[test]
+    fn text_is_escaped_onto_one_line() {
+        let event = Event::SerialError {
+            port: "COM3".to_string(),
+            error: "device \"gone\"\nunplugged".to_string(),
+        };
+        let line = to_json_line(&event, AT);
+        assert!(!line.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["error"], "device \"gone\"\nunplugged");
+    }
+}
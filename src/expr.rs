@@ -0,0 +1,395 @@
+//! Arithmetic expressions over the values of a data row.
+//!
+//! Expressions are parsed once into a tree and then evaluated per row. The
+//! variables `f0`, `f1`, ... are the row's value columns in the order they
+//! are written (after `--select`). Supported are numbers, `+ - * / %`, `^`
+//! (power, right-associative), unary minus, parentheses, the constants `pi`
+//! and `e`, and the functions `sqrt abs exp ln log10 sin cos tan asin acos
+//! atan floor ceil round` (one argument) and `atan2 min max pow` (two).
+//! Comparisons (`< <= > >= == !=`) bind loosest and yield 1 or 0, so the same
+//! expressions serve as conditions.
+//!
+//! Evaluation yields None when a variable isn't a number or the result isn't
+//! finite (e.g. `sqrt(-1)` or a division by zero).
+
+/// A parsed expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Var(usize),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Func {
+    Sqrt,
+    Abs,
+    Exp,
+    Ln,
+    Log10,
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Floor,
+    Ceil,
+    Round,
+    Atan2,
+    Min,
+    Max,
+    Pow,
+}
+
+impl Func {
+    fn by_name(name: &str) -> Option<Func> {
+        Some(match name {
+            "sqrt" => Func::Sqrt,
+            "abs" => Func::Abs,
+            "exp" => Func::Exp,
+            "ln" => Func::Ln,
+            "log10" => Func::Log10,
+            "sin" => Func::Sin,
+            "cos" => Func::Cos,
+            "tan" => Func::Tan,
+            "asin" => Func::Asin,
+            "acos" => Func::Acos,
+            "atan" => Func::Atan,
+            "floor" => Func::Floor,
+            "ceil" => Func::Ceil,
+            "round" => Func::Round,
+            "atan2" => Func::Atan2,
+            "min" => Func::Min,
+            "max" => Func::Max,
+            "pow" => Func::Pow,
+            _ => return None,
+        })
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            Func::Atan2 | Func::Min | Func::Max | Func::Pow => 2,
+            _ => 1,
+        }
+    }
+
+    fn apply(self, args: &[f64]) -> f64 {
+        let x = args[0];
+        match self {
+            Func::Sqrt => x.sqrt(),
+            Func::Abs => x.abs(),
+            Func::Exp => x.exp(),
+            Func::Ln => x.ln(),
+            Func::Log10 => x.log10(),
+            Func::Sin => x.sin(),
+            Func::Cos => x.cos(),
+            Func::Tan => x.tan(),
+            Func::Asin => x.asin(),
+            Func::Acos => x.acos(),
+            Func::Atan => x.atan(),
+            Func::Floor => x.floor(),
+            Func::Ceil => x.ceil(),
+            Func::Round => x.round(),
+            Func::Atan2 => x.atan2(args[1]),
+            Func::Min => x.min(args[1]),
+            Func::Max => x.max(args[1]),
+            Func::Pow => x.powf(args[1]),
+        }
+    }
+}
+
+impl Expr {
+    /// Parses `text`; errors name the position of the problem.
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        };
+        let expr = parser.comparison()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected '{}' in '{}'", token, text)),
+        }
+    }
+
+    /// Evaluates the expression with `values` as `f0`, `f1`, ...
+    pub fn eval(&self, values: &[Option<f64>]) -> Option<f64> {
+        let result = match self {
+            Expr::Number(n) => *n,
+            Expr::Var(i) => values.get(*i).copied().flatten()?,
+            Expr::Neg(inner) => -inner.eval(values)?,
+            Expr::Binary(op, left, right) => {
+                let (a, b) = (left.eval(values)?, right.eval(values)?);
+                match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div => a / b,
+                    Op::Rem => a % b,
+                    Op::Pow => a.powf(b),
+                    Op::Lt => f64::from(a < b),
+                    Op::Le => f64::from(a <= b),
+                    Op::Gt => f64::from(a > b),
+                    Op::Ge => f64::from(a >= b),
+                    Op::Eq => f64::from(a == b),
+                    Op::Ne => f64::from(a != b),
+                }
+            }
+            Expr::Call(func, args) => {
+                let args: Option<Vec<f64>> = args.iter().map(|arg| arg.eval(values)).collect();
+                func.apply(&args?)
+            }
+        };
+        result.is_finite().then_some(result)
+    }
+
+    /// Evaluates the expression as a condition: true unless it is 0, None
+    /// when it can't be evaluated.
+    pub fn holds(&self, values: &[Option<f64>]) -> Option<bool> {
+        self.eval(values).map(|value| value != 0.0)
+    }
+
+    /// Highest variable index used, to check it against the row's columns.
+    pub fn max_var(&self) -> Option<usize> {
+        match self {
+            Expr::Number(_) => None,
+            Expr::Var(i) => Some(*i),
+            Expr::Neg(inner) => inner.max_var(),
+            Expr::Binary(_, left, right) => left.max_var().max(right.max_var()),
+            Expr::Call(_, args) => args.iter().filter_map(Expr::max_var).max(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Symbol(char),
+    // A comparison operator: < <= > >= == !=
+    Compare(Op),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Symbol(c) => write!(f, "{}", c),
+            Token::Compare(op) => f.write_str(match op {
+                Op::Lt => "<",
+                Op::Le => "<=",
+                Op::Gt => ">",
+                Op::Ge => ">=",
+                Op::Eq => "==",
+                _ => "!=",
+            }),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                // Digits, a decimal point and an exponent such as 1e-3
+                let exponent_sign = (c == '-' || c == '+') && text[..i].ends_with(['e', 'E']);
+                if !(c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign) {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let number = &text[start..end];
+            let value = number
+                .parse()
+                .map_err(|_| format!("invalid number '{}' in '{}'", number, text))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Ident(text[start..end].to_string()));
+        } else if "<>=!".contains(c) {
+            chars.next();
+            let equals = chars.next_if(|&(_, c)| c == '=').is_some();
+            let op = match (c, equals) {
+                ('<', false) => Op::Lt,
+                ('<', true) => Op::Le,
+                ('>', false) => Op::Gt,
+                ('>', true) => Op::Ge,
+                ('=', true) => Op::Eq,
+                ('!', true) => Op::Ne,
+                _ => return Err(format!("unexpected '{}' in '{}' (did you mean '{}='?)", c, text, c)),
+            };
+            tokens.push(Token::Compare(op));
+        } else if "+-*/%^(),".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(format!("unexpected '{}' in '{}'", c, text));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // comparison := expr (compare expr)?
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.expr()?;
+        match self.peek() {
+            Some(&Token::Compare(op)) => {
+                self.pos += 1;
+                Ok(Expr::Binary(op, Box::new(left), Box::new(self.expr()?)))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut left = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                Op::Add
+            } else if self.eat('-') {
+                Op::Sub
+            } else {
+                return Ok(left);
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.term()?));
+        }
+    }
+
+    // term := unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                Op::Mul
+            } else if self.eat('/') {
+                Op::Div
+            } else if self.eat('%') {
+                Op::Rem
+            } else {
+                return Ok(left);
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    // unary := '-' unary | power
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat('-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    // power := atom ('^' unary)?, so 2^3^2 is 2^(3^2) and -2^2 is -(2^2)
+    fn power(&mut self) -> Result<Expr, String> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            return Ok(Expr::Binary(Op::Pow, Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    // atom := number | variable | constant | function '(' args ')' | '(' expr ')'
+    fn atom(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Symbol('(')) => {
+                let inner = self.comparison()?;
+                if !self.eat(')') {
+                    return Err("missing ')'".to_string());
+                }
+                Ok(inner)
+            }
+            Some(Token::Ident(name)) => self.identifier(&name),
+            Some(token) => Err(format!("unexpected '{}'", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn identifier(&mut self, name: &str) -> Result<Expr, String> {
+        if let Some(func) = Func::by_name(name) {
+            if !self.eat('(') {
+                return Err(format!("'{}' needs its arguments in parentheses", name));
+            }
+            let mut args = vec![self.expr()?];
+            while self.eat(',') {
+                args.push(self.expr()?);
+            }
+            if !self.eat(')') {
+                return Err(format!("missing ')' after the arguments of '{}'", name));
+            }
+            if args.len() != func.arity() {
+                return Err(format!("'{}' takes {} argument(s), got {}", name, func.arity(), args.len()));
+            }
+            return Ok(Expr::Call(func, args));
+        }
+        match name {
+            "pi" => Ok(Expr::Number(std::f64::consts::PI)),
+            "e" => Ok(Expr::Number(std::f64::consts::E)),
+            _ => name
+                .strip_prefix('f')
+                .and_then(|index| index.parse().ok())
+                .map(Expr::Var)
+                .ok_or_else(|| format!("unknown name '{}' (variables are f0, f1, ...)", name)),
+        }
+    }
+}
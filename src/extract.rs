@@ -0,0 +1,311 @@
+//! `extract`: copies the rows of an output file between two times to a new
+//! file.
+//!
+//! The new file gets the source's metadata, header and units rows, then every
+//! row whose Timestamp falls in the window, ends included: data rows, start
+//! and stop rows and other markers, unchanged and in file order. Timestamps
+//! are read as the library reads them, with or without fractional seconds and
+//! as RFC 3339. The window's ends may be given without a date when every
+//! timestamp in the file is on the same day. With `--synthesize-markers` a run
+//! that began before the window gets a start row at its start and one that
+//! goes on past it a stop row at its end (`end of window`), copied from the
+//! run's own start row, so the slice reads as complete runs. Runs are found
+//! the same way as for `split` and `analyze`.
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use collect_acc_data::logfile::{parse_timestamp, Kind, LogReader, Row};
+use collect_acc_data::runs::{self, Segment};
+use std::{fs::File, io::BufReader, path::Path};
+
+use crate::split::{self, Layout};
+
+/// Stop note of a stop row added at the end of the window.
+pub const WINDOW_END: &str = "end of window";
+
+/// One end of the window, as given.
+#[derive(Debug, Clone, Copy)]
+pub enum Bound {
+    At(NaiveDateTime),
+    // On the day of the file's timestamps
+    TimeOfDay(NaiveTime),
+}
+
+impl Bound {
+    /// Parses `--from` or `--to`: a timestamp as the logger writes it (or
+    /// without seconds), or a time of day (`14:32:10`, `14:32:10.5` or
+    /// `14:32`).
+    pub fn parse(text: &str, option: &str) -> Result<Bound, String> {
+        let with_date =
+            parse_timestamp(text).or_else(|| NaiveDateTime::parse_from_str(text.trim(), "%Y-%m-%d %H:%M").ok());
+        if let Some(time) = with_date {
+            return Ok(Bound::At(time));
+        }
+        ["%H:%M:%S%.f", "%H:%M"]
+            .iter()
+            .find_map(|format| NaiveTime::parse_from_str(text.trim(), format).ok())
+            .map(Bound::TimeOfDay)
+            .ok_or_else(|| {
+                format!(
+                    "Invalid {} '{}': expected a time such as '2024-05-14 14:32:10' or, for a file from a single day, '14:32:10'",
+                    option, text
+                )
+            })
+    }
+
+    fn on(self, day: Option<NaiveDate>) -> NaiveDateTime {
+        match (self, day) {
+            (Bound::At(time), _) => time,
+            (Bound::TimeOfDay(time), Some(day)) => day.and_time(time),
+            (Bound::TimeOfDay(_), None) => unreachable!("The file's day is found for times without a date"),
+        }
+    }
+}
+
+pub struct ExtractOptions {
+    pub from: Bound,
+    pub to: Bound,
+    // Add start and stop rows where the window cuts through a run
+    pub synthesize_markers: bool,
+}
+
+/// What an extract copied.
+#[derive(Debug)]
+pub struct Report {
+    // The window, with dates
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    pub data_rows: u64,
+    // Start, stop and other marker rows copied
+    pub markers: u64,
+    // Start and stop rows added at the window's ends
+    pub synthesized: u64,
+    // Rows left out for a timestamp that can't be read
+    pub unreadable: u64,
+}
+
+// The file being written, created with the first row
+struct Slice<'a> {
+    path: &'a Path,
+    crlf: bool,
+    preamble: Vec<csv::StringRecord>,
+    header: csv::StringRecord,
+    units: Option<csv::StringRecord>,
+    writer: Option<csv::Writer<File>>,
+}
+
+impl Slice<'_> {
+    fn write(&mut self, record: &csv::StringRecord) -> Result<(), String> {
+        if self.writer.is_none() {
+            self.writer = Some(split::create(self.path, &self.layout())?);
+        }
+        let writer = self.writer.as_mut().expect("The file was just created");
+        writer
+            .write_record(record)
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        let mut writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => split::create(self.path, &self.layout())?,
+        };
+        writer.flush().map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))
+    }
+
+    fn layout(&self) -> Layout<'_> {
+        Layout {
+            preamble: &self.preamble,
+            header: &self.header,
+            units: self.units.as_ref(),
+            crlf: self.crlf,
+        }
+    }
+}
+
+// The run being read, as far as the slice is concerned
+#[derive(Default)]
+struct Run {
+    start: Option<csv::StringRecord>,
+    // Its start row, real or added, is in the slice and no stop row yet
+    open: bool,
+}
+
+/// Copies the rows of the output file at `input` that fall in the window to
+/// a new file at `output`.
+pub fn run(input: &Path, output: &Path, options: &ExtractOptions) -> Result<Report, String> {
+    let day = match (options.from, options.to) {
+        (Bound::At(_), Bound::At(_)) => None,
+        _ => Some(file_day(input)?),
+    };
+    let (from, to) = (options.from.on(day), options.to.on(day));
+    if to < from {
+        return Err(format!("--to {} is before --from {}", to, from));
+    }
+    let mut report = Report {
+        from,
+        to,
+        data_rows: 0,
+        markers: 0,
+        synthesized: 0,
+        unreadable: 0,
+    };
+    let mut slice = Slice {
+        path: output,
+        crlf: split::uses_crlf(input)?,
+        preamble: Vec::new(),
+        header: csv::StringRecord::new(),
+        units: None,
+        writer: None,
+    };
+    let file = File::open(input).map_err(|e| format!("Failed to open {}: {}", input.display(), e))?;
+    let mut segments = runs::segment(LogReader::new(BufReader::new(file)));
+    let mut run = Run::default();
+    while let Some(segment) = segments.next() {
+        let segment = segment.map_err(|e| format!("{}: {}", input.display(), e))?;
+        if slice.header.is_empty() {
+            slice.header = segments.inner().header().cloned().unwrap_or_default();
+            slice.units = segments.inner().units().cloned();
+        }
+        match segment {
+            Segment::Outside(line) => {
+                if let Row::Meta { .. } = line.row {
+                    slice.preamble.push(line.record);
+                    continue;
+                }
+                let Some(time) = row_time(&line.row, &mut report) else { continue };
+                if (from..=to).contains(&time) {
+                    count(&line.row, &mut report);
+                    slice.write(&line.record)?;
+                }
+            }
+            Segment::Begin(line) => {
+                run = Run {
+                    start: Some(line.record.clone()),
+                    open: false,
+                };
+                let Some(time) = row_time(&line.row, &mut report) else { continue };
+                if (from..=to).contains(&time) {
+                    report.markers += 1;
+                    slice.write(&line.record)?;
+                    run.open = true;
+                }
+            }
+            Segment::Row(line) => {
+                let Some(time) = row_time(&line.row, &mut report) else { continue };
+                if (from..=to).contains(&time) {
+                    enter(&mut run, &mut slice, options, from, &mut report)?;
+                    count(&line.row, &mut report);
+                    slice.write(&line.record)?;
+                } else if time > to {
+                    leave(&mut run, &mut slice, options, to, &mut report)?;
+                }
+            }
+            Segment::End(Some(line)) => {
+                let Some(time) = row_time(&line.row, &mut report) else { continue };
+                if (from..=to).contains(&time) {
+                    enter(&mut run, &mut slice, options, from, &mut report)?;
+                    report.markers += 1;
+                    slice.write(&line.record)?;
+                } else if time > to {
+                    leave(&mut run, &mut slice, options, to, &mut report)?;
+                }
+                run = Run::default();
+            }
+            // A run without a stop row stays that way in the slice
+            Segment::End(None) => run = Run::default(),
+        }
+    }
+    slice.finish()?;
+    Ok(report)
+}
+
+// Before a row of the window: the run's start row at the window's start if
+// it began earlier
+fn enter(run: &mut Run, slice: &mut Slice, options: &ExtractOptions, from: NaiveDateTime, report: &mut Report) -> Result<(), String> {
+    if run.open || !options.synthesize_markers {
+        return Ok(());
+    }
+    let Some(start) = &run.start else { return Ok(()) };
+    slice.write(&edited(start, &[(1, &format_time(from))]))?;
+    report.synthesized += 1;
+    run.open = true;
+    Ok(())
+}
+
+// At the first row past the window: a stop row at the window's end for a run
+// still open in the slice
+fn leave(run: &mut Run, slice: &mut Slice, options: &ExtractOptions, to: NaiveDateTime, report: &mut Report) -> Result<(), String> {
+    if !run.open || !options.synthesize_markers {
+        return Ok(());
+    }
+    let Some(start) = &run.start else { return Ok(()) };
+    // The start row's Run/End becomes the stop note; its RunId stays
+    slice.write(&edited(start, &[(0, Kind::Stop.as_str()), (1, &format_time(to)), (2, WINDOW_END)]))?;
+    report.synthesized += 1;
+    run.open = false;
+    Ok(())
+}
+
+fn timestamp(row: &Row) -> Option<&str> {
+    match row {
+        Row::Meta { .. } => None,
+        Row::Start { timestamp, .. }
+        | Row::Stop { timestamp, .. }
+        | Row::Continued { timestamp, .. }
+        | Row::Data { timestamp, .. } => Some(timestamp),
+        Row::Marker { timestamp, .. } => Some(timestamp),
+    }
+}
+
+// The time of a row, counting those whose timestamp can't be read
+fn row_time(row: &Row, report: &mut Report) -> Option<NaiveDateTime> {
+    let time = timestamp(row).and_then(parse_timestamp);
+    if time.is_none() {
+        report.unreadable += 1;
+    }
+    time
+}
+
+fn count(row: &Row, report: &mut Report) {
+    match row {
+        Row::Data { .. } => report.data_rows += 1,
+        _ => report.markers += 1,
+    }
+}
+
+// `record` with the given cells replaced
+fn edited(record: &csv::StringRecord, cells: &[(usize, &str)]) -> csv::StringRecord {
+    record
+        .iter()
+        .enumerate()
+        .map(|(i, cell)| cells.iter().find(|(at, _)| *at == i).map_or(cell, |(_, new)| *new))
+        .collect()
+}
+
+// The way the logger writes times, with fractional seconds only if there are any
+fn format_time(time: NaiveDateTime) -> String {
+    time.format("%Y-%m-%d %H:%M:%S%.f").to_string()
+}
+
+// The day every timestamp in the file is on, for a window given without dates
+fn file_day(input: &Path) -> Result<NaiveDate, String> {
+    let file = File::open(input).map_err(|e| format!("Failed to open {}: {}", input.display(), e))?;
+    let mut first: Option<NaiveDate> = None;
+    let mut last: Option<NaiveDate> = None;
+    for line in LogReader::new(BufReader::new(file)) {
+        let line = line.map_err(|e| format!("{}: {}", input.display(), e))?;
+        let Some(day) = timestamp(&line.row).and_then(parse_timestamp).map(|time| time.date()) else { continue };
+        first = Some(first.map_or(day, |first| first.min(day)));
+        last = last.max(Some(day));
+    }
+    match (first, last) {
+        (Some(first), Some(last)) if first == last => Ok(first),
+        (Some(first), Some(last)) => Err(format!(
+            "{} runs from {} to {}; give --from and --to with a date",
+            input.display(),
+            first,
+            last
+        )),
+        _ => Err(format!("{} has no timestamps to take the day from; give --from and --to with a date", input.display())),
+    }
+}
@@ -0,0 +1,83 @@
+//! Exit status for unattended captures, with `--fail-on`.
+//!
+//! Each condition is checked against the session summary when the logger
+//! exits, however it exits (`exit`, end of piped commands, or a signal). If
+//! any is violated the ones that are are printed and the process exits with
+//! the code of the first violated condition in the order they were given;
+//! a full disk keeps its own code ahead of them.
+
+use crate::summary::SessionSummary;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailOn {
+    // No data row was written all session
+    NoData,
+    // More than N data lines had the wrong number of fields
+    ParseErrors(u64),
+    // More than N data rows were parsed but not written
+    Drops(u64),
+    // The serial port was lost more than N times
+    Disconnects(u64),
+}
+
+impl FailOn {
+    /// Parses a comma-separated list such as `no-data,parse-errors>10`.
+    pub fn parse_list(text: &str) -> Result<Vec<FailOn>, String> {
+        text.split(',').map(|item| FailOn::parse(item.trim())).collect()
+    }
+
+    fn parse(text: &str) -> Result<FailOn, String> {
+        if text == "no-data" {
+            return Ok(FailOn::NoData);
+        }
+        let invalid = || {
+            format!(
+                "Invalid --fail-on condition '{}': expected no-data, parse-errors>N, drops>N or disconnects>N",
+                text
+            )
+        };
+        let (name, limit) = text.split_once('>').ok_or_else(invalid)?;
+        let limit: u64 = limit.trim().parse().map_err(|_| invalid())?;
+        match name.trim() {
+            "parse-errors" => Ok(FailOn::ParseErrors(limit)),
+            "drops" => Ok(FailOn::Drops(limit)),
+            "disconnects" => Ok(FailOn::Disconnects(limit)),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Exit status when this condition is violated.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FailOn::NoData => 4,
+            FailOn::ParseErrors(_) => 5,
+            FailOn::Drops(_) => 6,
+            FailOn::Disconnects(_) => 7,
+        }
+    }
+
+    /// Describes the violation, or None if the session met the condition.
+    pub fn check(self, summary: &SessionSummary) -> Option<String> {
+        let over = |name: &str, count: u64, limit: u64| {
+            (count > limit).then(|| format!("{}>{} ({} this session)", name, limit, count))
+        };
+        match self {
+            FailOn::NoData => (summary.total_rows == 0).then(|| "no-data (no data rows were recorded)".to_string()),
+            FailOn::ParseErrors(limit) => over("parse-errors", summary.parse_failures, limit),
+            FailOn::Drops(limit) => over("drops", summary.unquotable_rows + summary.rows_lost, limit),
+            FailOn::Disconnects(limit) => over("disconnects", summary.disconnects, limit),
+        }
+    }
+}
+
+/// Prints every violated condition and returns the exit code of the first.
+pub fn evaluate(conditions: &[FailOn], summary: &SessionSummary) -> Option<i32> {
+    let mut code = None;
+    for condition in conditions {
+        if let Some(violation) = condition.check(summary) {
+            esay!("Failed --fail-on condition {}", violation);
+            code = code.or(Some(condition.exit_code()));
+        }
+    }
+    code
+}
@@ -0,0 +1,519 @@
+//! Fixed-length binary frames, for devices that don't send text lines.
+//!
+//! A frame spec such as `sync=0xAA55,len=32,fields=u32,f32*7,endian=le`
+//! describes each frame: a sync word (its bytes in the order they arrive),
+//! followed by `len` bytes holding the fields back to back. Field types are
+//! `u8 i8 u16 i16 u32 i32 u64 i64 f32 f64`, `TYPE*N` repeats one, and bytes
+//! after the last field are ignored. `len` defaults to the fields' size and
+//! `endian` to `le`. The same keys can be kept in a schema file, one
+//! `key = value` per line with `#` comments (a subset of TOML). With
+//! `--framing cobs` each frame arrives as a packet of its own, so the sync
+//! word is optional; if given, every packet must start with it.
+//!
+//! The decoder scans for the sync word and takes a frame once the next sync
+//! word follows it directly, so a frame that lost or gained bytes is caught
+//! rather than decoded misaligned; a frame is also taken when the port goes
+//! quiet after it. After a bad frame it searches byte by byte for the next
+//! sync word. Bytes before the first frame (the port opened mid-frame) are
+//! dropped without counting as a bad frame.
+
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+}
+
+impl FieldType {
+    fn parse(name: &str) -> Option<FieldType> {
+        Some(match name {
+            "u8" => FieldType::U8,
+            "i8" => FieldType::I8,
+            "u16" => FieldType::U16,
+            "i16" => FieldType::I16,
+            "u32" => FieldType::U32,
+            "i32" => FieldType::I32,
+            "u64" => FieldType::U64,
+            "i64" => FieldType::I64,
+            "f32" => FieldType::F32,
+            "f64" => FieldType::F64,
+            _ => return None,
+        })
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            FieldType::U8 | FieldType::I8 => 1,
+            FieldType::U16 | FieldType::I16 => 2,
+            FieldType::U32 | FieldType::I32 | FieldType::F32 => 4,
+            FieldType::U64 | FieldType::I64 | FieldType::F64 => 8,
+        }
+    }
+
+    // Formats the value in `bytes` (exactly `size()` of them)
+    fn format(self, bytes: &[u8], little_endian: bool) -> String {
+        macro_rules! read {
+            ($t:ty) => {{
+                let array = bytes.try_into().expect("Field slices have the type's size");
+                if little_endian {
+                    <$t>::from_le_bytes(array).to_string()
+                } else {
+                    <$t>::from_be_bytes(array).to_string()
+                }
+            }};
+        }
+        match self {
+            FieldType::U8 => read!(u8),
+            FieldType::I8 => read!(i8),
+            FieldType::U16 => read!(u16),
+            FieldType::I16 => read!(i16),
+            FieldType::U32 => read!(u32),
+            FieldType::I32 => read!(i32),
+            FieldType::U64 => read!(u64),
+            FieldType::I64 => read!(i64),
+            FieldType::F32 => read!(f32),
+            FieldType::F64 => read!(f64),
+        }
+    }
+}
+
+/// Layout of one frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameSpec {
+    pub sync: Vec<u8>,
+    // Bytes after the sync word
+    pub len: usize,
+    pub fields: Vec<FieldType>,
+    pub little_endian: bool,
+}
+
+impl FrameSpec {
+    /// Parses a spec such as `sync=0xAA55,len=32,fields=u32,f32*7,endian=le`.
+    pub fn parse(text: &str) -> Result<FrameSpec, String> {
+        // Items without '=' continue the previous value (the field list)
+        let mut pairs: Vec<(String, String)> = Vec::new();
+        for item in text.split(',') {
+            match (item.split_once('='), pairs.last_mut()) {
+                (Some((key, value)), _) => pairs.push((key.trim().to_string(), value.trim().to_string())),
+                (None, Some((_, value))) => {
+                    value.push(',');
+                    value.push_str(item.trim());
+                }
+                (None, None) => return Err(format!("Invalid frame spec '{}': expected key=value", text)),
+            }
+        }
+        FrameSpec::from_pairs(&pairs).map_err(|e| format!("Invalid frame spec '{}': {}", text, e))
+    }
+
+    /// Reads a schema file with one `key = value` per line.
+    pub fn from_file(path: &Path) -> Result<FrameSpec, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read frame spec {}: {}", path.display(), e))?;
+        let mut pairs = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("{} line {}: expected key = value", path.display(), number + 1))?;
+            let value = value.trim().trim_matches('"');
+            pairs.push((key.trim().to_string(), value.to_string()));
+        }
+        FrameSpec::from_pairs(&pairs).map_err(|e| format!("Invalid frame spec {}: {}", path.display(), e))
+    }
+
+    fn from_pairs(pairs: &[(String, String)]) -> Result<FrameSpec, String> {
+        let mut sync = None;
+        let mut len = None;
+        let mut fields = None;
+        let mut little_endian = true;
+        for (key, value) in pairs {
+            match key.as_str() {
+                "sync" => sync = Some(parse_sync(value)?),
+                "len" => len = Some(value.parse::<usize>().map_err(|_| format!("invalid len '{}'", value))?),
+                "fields" => fields = Some(parse_fields(value)?),
+                "endian" => {
+                    little_endian = match value.as_str() {
+                        "le" => true,
+                        "be" => false,
+                        _ => return Err(format!("invalid endian '{}': expected le or be", value)),
+                    }
+                }
+                _ => return Err(format!("unknown key '{}'", key)),
+            }
+        }
+        let sync = sync.unwrap_or_default();
+        let fields = fields.ok_or("missing fields")?;
+        let size: usize = fields.iter().map(|field| field.size()).sum();
+        let len = len.unwrap_or(size);
+        if size > len {
+            return Err(format!("the fields take {} bytes, more than len={}", size, len));
+        }
+        Ok(FrameSpec {
+            sync,
+            len,
+            fields,
+            little_endian,
+        })
+    }
+
+    /// Decodes the fields of a frame's bytes after the sync word.
+    pub fn decode(&self, payload: &[u8]) -> Vec<String> {
+        let mut offset = 0;
+        self.fields
+            .iter()
+            .map(|field| {
+                let value = field.format(&payload[offset..offset + field.size()], self.little_endian);
+                offset += field.size();
+                value
+            })
+            .collect()
+    }
+
+    /// Decodes a frame that arrived as a packet of its own (`--framing cobs`).
+    pub fn decode_packet(&self, packet: &[u8]) -> Result<Vec<String>, String> {
+        if packet.len() != self.frame_len() {
+            return Err(format!("{} bytes instead of {}", packet.len(), self.frame_len()));
+        }
+        if !packet.starts_with(&self.sync) {
+            return Err("no sync word".to_string());
+        }
+        Ok(self.decode(&packet[self.sync.len()..]))
+    }
+
+    // Sync word and payload
+    fn frame_len(&self) -> usize {
+        self.sync.len() + self.len
+    }
+}
+
+// "0xAA55" as the bytes AA 55
+fn parse_sync(text: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("invalid sync '{}': expected hex bytes such as 0xAA55", text);
+    let hex = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).ok_or_else(invalid)?;
+    if hex.is_empty() || hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+// "u32,f32*7"
+fn parse_fields(text: &str) -> Result<Vec<FieldType>, String> {
+    let mut fields = Vec::new();
+    for item in text.split(',') {
+        let item = item.trim();
+        let (name, count) = match item.split_once('*') {
+            Some((name, count)) => {
+                let count = count.trim().parse::<usize>().ok().filter(|n| *n > 0);
+                (name.trim(), count.ok_or_else(|| format!("invalid repeat in '{}'", item))?)
+            }
+            None => (item, 1),
+        };
+        let field = FieldType::parse(name).ok_or_else(|| format!("unknown field type '{}'", name))?;
+        fields.extend(std::iter::repeat_n(field, count));
+    }
+    Ok(fields)
+}
+
+/// What the decoder found next in the input.
+#[derive(Debug, PartialEq)]
+pub enum Decoded {
+    // The fields of a frame
+    Frame(Vec<String>),
+    // Bytes dropped between two frames: a damaged frame or other garbage,
+    // reported once the next frame has been found
+    Bad(Vec<u8>),
+}
+
+/// Splits a byte stream into frames. The spec needs a sync word.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    spec: FrameSpec,
+    buffer: Vec<u8>,
+    // No frame has been found yet; bytes skipped until then aren't bad frames
+    started: bool,
+    // Bytes dropped since the last frame
+    dropped: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new(spec: FrameSpec) -> Decoder {
+        Decoder {
+            spec,
+            buffer: Vec::new(),
+            started: false,
+            dropped: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next frame or bad stretch of input, if the buffer holds one.
+    pub fn next_frame(&mut self) -> Option<Decoded> {
+        self.take(false)
+    }
+
+    /// Like `next_frame`, but also takes a complete frame that nothing follows yet,
+    /// for when the input has gone quiet.
+    pub fn flush(&mut self) -> Option<Decoded> {
+        self.take(true)
+    }
+
+    /// Forgets buffered input, e.g. when another port is opened.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.dropped.clear();
+        self.started = false;
+    }
+
+    fn take(&mut self, quiet: bool) -> Option<Decoded> {
+        let sync_len = self.spec.sync.len();
+        let frame_len = self.spec.frame_len();
+        loop {
+            // Drop anything before the next sync word; keep a partial one at the end
+            let skip = find(&self.buffer, &self.spec.sync).unwrap_or(self.buffer.len().saturating_sub(sync_len - 1));
+            if skip > 0 {
+                self.drop_bytes(skip);
+                continue;
+            }
+            if self.buffer.len() < frame_len || !self.buffer.starts_with(&self.spec.sync) {
+                return None;
+            }
+            // A frame is only as good as the sync word right after it
+            let next = &self.buffer[frame_len..];
+            let follows = if next.len() >= sync_len {
+                Some(next.starts_with(&self.spec.sync))
+            } else if self.spec.sync.starts_with(next) {
+                // Not here yet; it may still be
+                quiet.then_some(true)
+            } else {
+                Some(false)
+            };
+            match follows {
+                None => return None,
+                Some(false) => {
+                    // The sync word starts a frame of the wrong length (or
+                    // occurs by chance): search again from the byte after it
+                    self.drop_bytes(1);
+                }
+                Some(true) => {
+                    if !self.dropped.is_empty() {
+                        return Some(Decoded::Bad(std::mem::take(&mut self.dropped)));
+                    }
+                    let frame: Vec<u8> = self.buffer.drain(..frame_len).collect();
+                    self.started = true;
+                    return Some(Decoded::Frame(self.spec.decode(&frame[sync_len..])));
+                }
+            }
+        }
+    }
+
+    fn drop_bytes(&mut self, count: usize) {
+        let bytes = self.buffer.drain(..count);
+        if self.started {
+            self.dropped.extend(bytes);
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = "sync=0xAA55,len=32,fields=u32,f32*7,endian=le";
+
+    // A frame of SPEC: counter n and values n + 0.5, n + 1.5...
+    fn frame(n: u32) -> Vec<u8> {
+        let mut bytes = vec![0xAA, 0x55];
+        bytes.extend(n.to_le_bytes());
+        for i in 0..7 {
+            bytes.extend((n as f32 + i as f32 + 0.5).to_le_bytes());
+        }
+        bytes
+    }
+
+    fn fields(n: u32) -> Vec<String> {
+        let mut fields = vec![n.to_string()];
+        fields.extend((0..7).map(|i| (n as f32 + i as f32 + 0.5).to_string()));
+        fields
+    }
+
+    fn decode_all(decoder: &mut Decoder) -> Vec<Decoded> {
+        let mut decoded: Vec<Decoded> = std::iter::from_fn(|| decoder.next_frame()).collect();
+        decoded.extend(std::iter::from_fn(|| decoder.flush()));
+        decoded
+    }
+
+    #[test]
+    fn specs_are_parsed() {
+        let spec = FrameSpec::parse(SPEC).unwrap();
+        assert_eq!(spec.sync, [0xAA, 0x55]);
+        assert_eq!(spec.len, 32);
+        assert_eq!(spec.fields.len(), 8);
+        assert_eq!(spec.fields[0], FieldType::U32);
+        assert!(spec.fields[1..].iter().all(|field| *field == FieldType::F32));
+        assert!(spec.little_endian);
+        // len defaults to the fields' size, and there may be no sync word
+        let spec = FrameSpec::parse("fields=u8,i16,f64,endian=be").unwrap();
+        assert_eq!((spec.len, spec.sync.len(), spec.little_endian), (11, 0, false));
+    }
+
+    #[test]
+    fn invalid_specs_are_refused() {
+        for (spec, error) in [
+            ("sync=AA55,fields=u8", "invalid sync 'AA55'"),
+            ("sync=0xAA5,fields=u8", "invalid sync '0xAA5'"),
+            ("fields=u8,f32*0", "invalid repeat in 'f32*0'"),
+            ("fields=u24", "unknown field type 'u24'"),
+            ("len=3,fields=u32", "the fields take 4 bytes, more than len=3"),
+            ("len=3", "missing fields"),
+            ("fields=u8,endian=middle", "invalid endian 'middle'"),
+            ("fields=u8,crc=none", "unknown key 'crc'"),
+            ("u8", "expected key=value"),
+        ] {
+            let message = FrameSpec::parse(spec).unwrap_err();
+            assert!(message.contains(error), "{}: {}", spec, message);
+        }
+    }
+
+    #[test]
+    fn schema_files_hold_the_same_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("frame.toml");
+        std::fs::write(&path, "# Flight controller\nsync = \"0xAA55\"\nlen = 32  # bytes after sync\nfields = \"u32,f32*7\"\n").unwrap();
+        assert_eq!(FrameSpec::from_file(&path).unwrap(), FrameSpec::parse(SPEC).unwrap());
+        std::fs::write(&path, "sync 0xAA55\n").unwrap();
+        assert!(FrameSpec::from_file(&path).unwrap_err().ends_with("line 1: expected key = value"));
+    }
+
+    #[test]
+    fn fields_are_decoded_in_either_byte_order() {
+        let spec = FrameSpec::parse("fields=u8,i8,u16,i16,i32,u64,i64,f64,endian=be").unwrap();
+        let mut payload = vec![0xFF, 0xFF, 0x01, 0x02, 0xFF, 0xFE];
+        payload.extend((-5i32).to_be_bytes());
+        payload.extend(u64::MAX.to_be_bytes());
+        payload.extend(i64::MIN.to_be_bytes());
+        payload.extend((-0.25f64).to_be_bytes());
+        assert_eq!(
+            spec.decode(&payload),
+            ["255", "-1", "258", "-2", "-5", "18446744073709551615", "-9223372036854775808", "-0.25"]
+        );
+        let spec = FrameSpec::parse("fields=u16,i16").unwrap();
+        assert_eq!(spec.decode(&[0x01, 0x02, 0xFE, 0xFF]), ["513", "-2"]);
+    }
+
+    #[test]
+    fn a_stream_of_frames_is_decoded() {
+        let mut decoder = Decoder::new(FrameSpec::parse(SPEC).unwrap());
+        // Opened mid-frame: the first bytes are dropped without being bad
+        decoder.feed(&frame(0)[20..]);
+        for n in 1..4 {
+            decoder.feed(&frame(n));
+        }
+        assert_eq!(
+            decode_all(&mut decoder),
+            [Decoded::Frame(fields(1)), Decoded::Frame(fields(2)), Decoded::Frame(fields(3))]
+        );
+    }
+
+    #[test]
+    fn frames_are_taken_only_once_the_next_sync_word_follows() {
+        let mut decoder = Decoder::new(FrameSpec::parse(SPEC).unwrap());
+        decoder.feed(&frame(1));
+        assert_eq!(decoder.next_frame(), None);
+        // Half the next sync word isn't enough
+        decoder.feed(&[0xAA]);
+        assert_eq!(decoder.next_frame(), None);
+        decoder.feed(&[0x55]);
+        assert_eq!(decoder.next_frame(), Some(Decoded::Frame(fields(1))));
+        // Until the port goes quiet
+        decoder.feed(&frame(2)[2..]);
+        assert_eq!(decoder.next_frame(), None);
+        assert_eq!(decoder.flush(), Some(Decoded::Frame(fields(2))));
+    }
+
+    #[test]
+    fn a_corrupted_frame_in_the_middle_is_dropped_and_decoding_resumes() {
+        let mut decoder = Decoder::new(FrameSpec::parse(SPEC).unwrap());
+        let mut short = frame(2);
+        short.remove(10);
+        let mut long = frame(4);
+        long.insert(10, 0x00);
+        for bytes in [frame(1), short.clone(), frame(3), long.clone(), frame(5)] {
+            decoder.feed(&bytes);
+        }
+        assert_eq!(
+            decode_all(&mut decoder),
+            [
+                Decoded::Frame(fields(1)),
+                Decoded::Bad(short),
+                Decoded::Frame(fields(3)),
+                Decoded::Bad(long),
+                Decoded::Frame(fields(5))
+            ]
+        );
+    }
+
+    #[test]
+    fn the_search_for_sync_goes_byte_by_byte() {
+        let mut decoder = Decoder::new(FrameSpec::parse(SPEC).unwrap());
+        // Garbage that starts like a sync word, and a sync word a byte early
+        let garbage = [0xAA, 0xAA, 0x55, 0x01, 0xAA];
+        for bytes in [&frame(1), &frame(2), &garbage[..], &frame(3), &frame(4)] {
+            decoder.feed(bytes);
+        }
+        // Frame 2 isn't followed by a sync word, so it can't be told from
+        // the garbage
+        let mut bad = frame(2);
+        bad.extend(garbage);
+        assert_eq!(
+            decode_all(&mut decoder),
+            [
+                Decoded::Frame(fields(1)),
+                Decoded::Bad(bad),
+                Decoded::Frame(fields(3)),
+                Decoded::Frame(fields(4))
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_forgets_buffered_input() {
+        let mut decoder = Decoder::new(FrameSpec::parse(SPEC).unwrap());
+        decoder.feed(&frame(1));
+        decoder.feed(&frame(2)[..10]);
+        decoder.reset();
+        decoder.feed(&frame(3));
+        assert_eq!(decode_all(&mut decoder), [Decoded::Frame(fields(3))]);
+    }
+
+    #[test]
+    fn packets_are_decoded_whole() {
+        let spec = FrameSpec::parse(SPEC).unwrap();
+        assert_eq!(spec.decode_packet(&frame(7)), Ok(fields(7)));
+        assert_eq!(spec.decode_packet(&frame(7)[..33]), Err("33 bytes instead of 34".to_string()));
+        let mut unsynced = frame(7);
+        unsynced[0] = 0;
+        assert_eq!(spec.decode_packet(&unsynced), Err("no sync word".to_string()));
+    }
+}
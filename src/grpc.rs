@@ -0,0 +1,286 @@
+//! `--grpc-listen ADDRESS`: remote control and live records over gRPC.
+//!
+//! The `SerialLogger` service of `proto/serial_logger.proto`: `StartRun`,
+//! `StopRun` and `GetStatus` do what `start`, `stop` and the status line do
+//! at the prompt, and `StreamRecords` sends every data row written from then
+//! on until the client cancels. Start and stop go through the same command
+//! dispatch as typed commands, under the same session lock, so a remote start
+//! behaves exactly like a typed one: same checks, messages, events and hooks.
+//!
+//! The server is tonic's, with the code generated from the proto at build
+//! time, on a tokio runtime of its own; clients connect in plaintext (h2c).
+//! Needs a build with the `grpc` feature.
+
+use std::sync::{mpsc::Sender, Arc, Mutex};
+
+use crate::serial::Control;
+use crate::session::Session;
+
+#[cfg(feature = "grpc")]
+// tonic's handlers return its Status by value
+#[allow(clippy::result_large_err)]
+mod service {
+    use std::{
+        net::TcpListener,
+        sync::{atomic::Ordering, mpsc::Sender, Arc, Mutex, OnceLock},
+    };
+
+    use tokio::sync::mpsc::{self, error::TrySendError};
+    use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+    use tonic::{Request, Response, Status};
+
+    use crate::commands;
+    use crate::serial::Control;
+    use crate::session::{Session, Shared};
+
+    pub mod proto {
+        tonic::include_proto!("serial_logger");
+    }
+
+    use proto::serial_logger_server::{SerialLogger, SerialLoggerServer};
+    use proto::{
+        GetStatusRequest, GetStatusResponse, Record, RunState, StartRunRequest, StopRunRequest, StreamRecordsRequest,
+    };
+
+    // Records queued for a StreamRecords client; beyond that they are dropped for it
+    const STREAM_QUEUE: usize = 1000;
+
+    static SERVER: OnceLock<Arc<Server>> = OnceLock::new();
+
+    struct Server {
+        session: Arc<Mutex<Session>>,
+        shared: Arc<Shared>,
+        serial_control: Mutex<Sender<Control>>,
+        columns: Vec<String>,
+        // One queue per StreamRecords call
+        streams: Mutex<Vec<mpsc::Sender<Result<Record, Status>>>>,
+    }
+
+    pub fn spawn(
+        address: &str,
+        session: Arc<Mutex<Session>>,
+        serial_control: Sender<Control>,
+        columns: Vec<String>,
+    ) -> Result<(), String> {
+        let listener =
+            TcpListener::bind(address).map_err(|e| format!("Failed to listen for gRPC on {}: {}", address, e))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| format!("Failed to listen for gRPC on {}: {}", address, e))?;
+        let shared = Arc::clone(&session.lock().unwrap().shared);
+        let server = Arc::new(Server {
+            session,
+            shared,
+            serial_control: Mutex::new(serial_control),
+            columns,
+            streams: Mutex::new(Vec::new()),
+        });
+        SERVER.set(Arc::clone(&server)).map_err(|_| "The gRPC service is already set up".to_string())?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .map_err(|e| format!("Failed to start the gRPC runtime: {}", e))?;
+        let listener = {
+            let _entered = runtime.enter();
+            tokio::net::TcpListener::from_std(listener)
+                .map_err(|e| format!("Failed to listen for gRPC on {}: {}", address, e))?
+        };
+        say!("Serving gRPC on {}", address);
+        std::thread::spawn(move || {
+            let served = runtime.block_on(
+                tonic::transport::Server::builder()
+                    .add_service(SerialLoggerServer::from_arc(server))
+                    .serve_with_incoming(TcpListenerStream::new(listener)),
+            );
+            if let Err(e) = served {
+                esay!("The gRPC service stopped: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn publish_record(record: &[&str], run: i64) {
+        let Some(server) = SERVER.get() else { return };
+        let mut streams = server.streams.lock().unwrap();
+        if streams.is_empty() {
+            return;
+        }
+        let message = Record {
+            timestamp: record.get(1).copied().unwrap_or_default().to_string(),
+            run,
+            values: record.iter().skip(4).map(|value| value.to_string()).collect(),
+        };
+        streams.retain(|queue| !matches!(queue.try_send(Ok(message.clone())), Err(TrySendError::Closed(_))));
+    }
+
+    #[tonic::async_trait]
+    impl SerialLogger for Server {
+        async fn start_run(&self, request: Request<StartRunRequest>) -> Result<Response<RunState>, Status> {
+            let label = request.into_inner().label;
+            tokio::task::block_in_place(|| self.start(label.trim())).map(Response::new)
+        }
+
+        async fn stop_run(&self, _request: Request<StopRunRequest>) -> Result<Response<RunState>, Status> {
+            tokio::task::block_in_place(|| self.stop()).map(Response::new)
+        }
+
+        async fn get_status(&self, _request: Request<GetStatusRequest>) -> Result<Response<GetStatusResponse>, Status> {
+            Ok(Response::new(self.status()))
+        }
+
+        type StreamRecordsStream = ReceiverStream<Result<Record, Status>>;
+
+        // Sends records as they are written until the client goes away
+        async fn stream_records(
+            &self,
+            _request: Request<StreamRecordsRequest>,
+        ) -> Result<Response<Self::StreamRecordsStream>, Status> {
+            let (queue, records) = mpsc::channel(STREAM_QUEUE);
+            self.streams.lock().unwrap().push(queue);
+            Ok(Response::new(ReceiverStream::new(records)))
+        }
+    }
+
+    impl Server {
+        fn start(&self, label: &str) -> Result<RunState, Status> {
+            let mut session = self.session.lock().unwrap();
+            if session.is_recording() {
+                return Err(Status::failed_precondition("Recording is already started"));
+            }
+            commands::dispatch(&mut session, &self.serial_control.lock().unwrap(), "start", label);
+            // A start held back by --start-holdoff is accepted; it happens later
+            if !session.is_recording() && session.start_waiting().is_none() {
+                return Err(Status::unavailable("Recording could not be started; see the logger's output"));
+            }
+            Ok(self.run_state(&session))
+        }
+
+        fn stop(&self) -> Result<RunState, Status> {
+            let mut session = self.session.lock().unwrap();
+            if !session.is_recording() && session.start_waiting().is_none() {
+                return Err(Status::failed_precondition("Recording is not active"));
+            }
+            commands::dispatch(&mut session, &self.serial_control.lock().unwrap(), "stop", "");
+            Ok(self.run_state(&session))
+        }
+
+        fn run_state(&self, session: &Session) -> RunState {
+            let shared = &session.shared;
+            RunState {
+                recording: session.is_recording(),
+                run: session.current_run(),
+                file: shared.current_file.lock().unwrap().clone(),
+                rows: shared.run_rows.load(Ordering::Relaxed),
+            }
+        }
+
+        // Read from the shared counters, without the session lock, like the
+        // status line
+        fn status(&self) -> GetStatusResponse {
+            let shared = &self.shared;
+            let counter = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+            GetStatusResponse {
+                recording: shared.recording.load(Ordering::Relaxed),
+                run: shared.current_run.load(Ordering::Relaxed),
+                run_rows: counter(&shared.run_rows),
+                file: shared.current_file.lock().unwrap().clone(),
+                port: shared.serial_port.lock().unwrap().clone(),
+                baud: shared.serial_baud.load(Ordering::Relaxed),
+                lines_read: counter(&shared.lines_read),
+                rows_written: counter(&shared.rows_written),
+                parse_failures: counter(&shared.parse_failures),
+                serial_errors: counter(&shared.serial_errors),
+                columns: self.columns.clone(),
+                buffered_bytes: crate::memory::total_used() as u64,
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "grpc"))]
+mod service {
+    use std::sync::{mpsc::Sender, Arc, Mutex};
+
+    use crate::serial::Control;
+    use crate::session::Session;
+
+    pub fn spawn(
+        _address: &str,
+        _session: Arc<Mutex<Session>>,
+        _serial_control: Sender<Control>,
+        _columns: Vec<String>,
+    ) -> Result<(), String> {
+        Err("--grpc-listen needs a build with the 'grpc' feature".to_string())
+    }
+
+    pub fn publish_record(_record: &[&str], _run: i64) {}
+}
+
+/// Serves the gRPC service on `address` (e.g. `0.0.0.0:50051`) from a
+/// background thread. `columns` names the values of each record.
+pub fn spawn(
+    address: &str,
+    session: Arc<Mutex<Session>>,
+    serial_control: Sender<Control>,
+    columns: Vec<String>,
+) -> Result<(), String> {
+    service::spawn(address, session, serial_control, columns)
+}
+
+/// Sends a data record (as written to the file) to the `StreamRecords`
+/// clients; a no-op when there are none.
+pub fn publish_record(record: &[&str], run: i64) {
+    service::publish_record(record, run)
+}
+
+#[cfg(all(test, feature = "grpc"))]
+mod tests {
+    use super::service::proto::serial_logger_client::SerialLoggerClient;
+    use super::service::proto::{GetStatusRequest, StartRunRequest, StopRunRequest, StreamRecordsRequest};
+    use super::*;
+    use crate::session::OutputSettings;
+
+    #[test]
+    fn a_client_controls_the_logger_and_streams_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.csv");
+        let settings = OutputSettings::plain(path.to_str().unwrap(), &["ax", "ay"]);
+        let session = Arc::new(Mutex::new(Session::new(settings, crate::hooks::Hooks::default()).unwrap()));
+        let (serial_control, _control) = std::sync::mpsc::channel();
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address = format!("127.0.0.1:{}", port);
+        spawn(&address, Arc::clone(&session), serial_control, vec!["ax".into(), "ay".into()]).unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut client = SerialLoggerClient::connect(format!("http://{}", address)).await.unwrap();
+            let status = client.get_status(GetStatusRequest {}).await.unwrap().into_inner();
+            assert!(!status.recording);
+            assert_eq!(status.columns, ["ax", "ay"]);
+            let refused = client.stop_run(StopRunRequest {}).await.unwrap_err();
+            assert_eq!(refused.code(), tonic::Code::FailedPrecondition);
+
+            let started = client.start_run(StartRunRequest { label: " drop-test ".into() }).await.unwrap().into_inner();
+            assert!(started.recording);
+            assert_eq!(started.file, path.to_str().unwrap());
+            assert!(session.lock().unwrap().is_recording());
+            let refused = client.start_run(StartRunRequest::default()).await.unwrap_err();
+            assert_eq!(refused.code(), tonic::Code::FailedPrecondition);
+
+            let mut records = client.stream_records(StreamRecordsRequest {}).await.unwrap().into_inner();
+            publish_record(&["data", "2024-05-01 12:00:00", "", "", "1.5", "-2"], started.run);
+            let record = records.message().await.unwrap().unwrap();
+            assert_eq!(record.timestamp, "2024-05-01 12:00:00");
+            assert_eq!(record.run, started.run);
+            assert_eq!(record.values, ["1.5", "-2"]);
+
+            let stopped = client.stop_run(StopRunRequest {}).await.unwrap().into_inner();
+            assert!(!stopped.recording);
+            assert_eq!(stopped.run, started.run);
+        });
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.lines().any(|line| line.starts_with("start,")), "{}", log);
+        assert!(log.lines().any(|line| line.starts_with("stop,")), "{}", log);
+    }
+}
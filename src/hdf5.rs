@@ -0,0 +1,990 @@
+//! Writing output as an HDF5 file, for `--format hdf5`.
+//!
+//! Every run is a group named after it (`/run_0003`) with one dataset per
+//! numeric column plus `Timestamp`, all one-dimensional, chunked and
+//! extensible, so rows are appended a chunk at a time. The run's other rows
+//! (`stop`, `gap`, `mark`, ...) go to its `events` group, as a `Timestamp`,
+//! their `Type` and the rest of the row as `Text`, in variable-length
+//! strings kept in global heap collections. The session metadata are
+//! attributes of the root group, and a run's number, ID and start time
+//! attributes of its group. Timestamps are milliseconds since 1970 in UTC.
+//!
+//! The file stays consistent from one chunk to the next: the new chunks and
+//! index nodes are appended, the end of the file in the superblock is moved
+//! past them, and only then are the index nodes and headers that refer to
+//! them rewritten in place. A crash loses at most the rows of the chunks
+//! being collected; events are written as they come, into a chunk and heap
+//! collection that are rewritten in place until they are full.
+//!
+//! The file is laid out by hand following the HDF5 file format
+//! specification, in the structures HDF5 1.8 writes with its newer format (a
+//! version 2 superblock, version 2 object headers with the links stored in
+//! them, version 1 B-trees indexing the chunks). The `hdf5` crate needs the
+//! HDF5 C library to build, and the Rust writers only finish a file when it
+//! is closed, so a crash would lose all of it; the tests read the files back
+//! with `hdf5-reader`, a decoder written independently of this one.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::parser::LineFormat;
+use crate::schema::ColumnType;
+
+const SIGNATURE: &[u8; 8] = b"\x89HDF\r\n\x1a\n";
+// The undefined address, also an unlimited dimension
+const UNDEFINED: u64 = u64::MAX;
+const SUPERBLOCK_SIZE: u64 = 48;
+
+// Header message types
+const MSG_NIL: u8 = 0x00;
+const MSG_DATASPACE: u8 = 0x01;
+const MSG_LINK_INFO: u8 = 0x02;
+const MSG_DATATYPE: u8 = 0x03;
+const MSG_FILL_VALUE: u8 = 0x05;
+const MSG_LINK: u8 = 0x06;
+const MSG_LAYOUT: u8 = 0x08;
+const MSG_GROUP_INFO: u8 = 0x0a;
+const MSG_ATTRIBUTE: u8 = 0x0c;
+const MSG_CONTINUATION: u8 = 0x10;
+// Type, size and flags before each message's data
+const MSG_HEADER: usize = 4;
+const CONTINUATION_SIZE: usize = MSG_HEADER + 16;
+
+// Object header flags: a 4-byte chunk size, and attribute phase change
+// values stored
+const HEADER_SIZE_4: u8 = 0x02;
+const HEADER_PHASE_CHANGE: u8 = 0x10;
+// Attributes kept in the header before HDF5 would move them elsewhere
+const DEFAULT_MAX_COMPACT: usize = 8;
+
+// Chunk index nodes hold 2K entries, with HDF5's default K of 32 as the
+// version 2 superblock doesn't say otherwise. A node of a one-dimensional
+// dataset has a 24-byte prefix, 2K + 1 keys of 24 bytes and 2K addresses.
+const NODE_ENTRIES: usize = 64;
+const NODE_SIZE: u64 = 24 + (NODE_ENTRIES as u64 + 1) * 24 + NODE_ENTRIES as u64 * 8;
+
+// Room for run links in the root group's first header chunk, and in each
+// continuation chunk added when it is full
+const ROOT_LINK_SPACE: usize = 512;
+const CONTINUATION_SPACE: usize = 1024;
+
+// Rows of a chunk of events
+const EVENT_CHUNK_ROWS: usize = 64;
+// The smallest global heap collection HDF5 reads, and the header of the
+// collection and of each object in it
+const HEAP_SIZE: usize = 4096;
+const HEAP_HEADER: usize = 16;
+
+/// How a column is stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NumberType {
+    Signed(u32),
+    Unsigned(u32),
+    Float(u32),
+}
+
+impl NumberType {
+    fn size(self) -> usize {
+        match self {
+            NumberType::Signed(bits) | NumberType::Unsigned(bits) | NumberType::Float(bits) => bits as usize / 8,
+        }
+    }
+
+    // The value of a cell, little-endian; a float that doesn't parse is NaN
+    // and an integer 0
+    fn encode(self, cell: &str, out: &mut Vec<u8>) {
+        let cell = cell.trim();
+        let bytes = match self {
+            NumberType::Signed(_) => cell.parse::<i64>().unwrap_or(0).to_le_bytes(),
+            NumberType::Unsigned(_) => cell.parse::<u64>().unwrap_or(0).to_le_bytes(),
+            NumberType::Float(32) => {
+                out.extend_from_slice(&cell.parse::<f32>().unwrap_or(f32::NAN).to_le_bytes());
+                return;
+            }
+            NumberType::Float(_) => cell.parse::<f64>().unwrap_or(f64::NAN).to_le_bytes(),
+        };
+        out.extend_from_slice(&bytes[..self.size()]);
+    }
+
+    // The body of a datatype message
+    fn datatype(self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            NumberType::Signed(bits) | NumberType::Unsigned(bits) => {
+                let signed = matches!(self, NumberType::Signed(_));
+                // Class 0 (fixed-point), version 1; little-endian
+                out.extend_from_slice(&[0x10, if signed { 0x08 } else { 0x00 }, 0, 0]);
+                out.extend_from_slice(&(bits / 8).to_le_bytes());
+                out.extend_from_slice(&0u16.to_le_bytes());
+                out.extend_from_slice(&(bits as u16).to_le_bytes());
+            }
+            NumberType::Float(bits) => {
+                // Class 1 (floating-point), version 1; little-endian IEEE 754
+                // with an implied leading mantissa bit
+                let (exponent_at, exponent_bits, mantissa_bits, bias) = match bits {
+                    32 => (23u8, 8u8, 23u8, 127u32),
+                    _ => (52, 11, 52, 1023),
+                };
+                out.extend_from_slice(&[0x11, 0x20, (bits - 1) as u8, 0]);
+                out.extend_from_slice(&(bits / 8).to_le_bytes());
+                out.extend_from_slice(&0u16.to_le_bytes());
+                out.extend_from_slice(&(bits as u16).to_le_bytes());
+                out.extend_from_slice(&[exponent_at, exponent_bits, 0, mantissa_bits]);
+                out.extend_from_slice(&bias.to_le_bytes());
+            }
+        }
+        out
+    }
+}
+
+// What a dataset holds: numbers, or variable-length UTF-8 strings stored as
+// their length and where they are in a global heap collection
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Element {
+    Number(NumberType),
+    Text,
+}
+
+impl Element {
+    fn size(self) -> usize {
+        match self {
+            Element::Number(number_type) => number_type.size(),
+            Element::Text => 16,
+        }
+    }
+
+    fn datatype(self) -> Vec<u8> {
+        match self {
+            Element::Number(number_type) => number_type.datatype(),
+            Element::Text => {
+                // Class 9 (variable-length), version 1: a string, null
+                // terminated, UTF-8, of unsigned bytes
+                let mut out = vec![0x19, 0x01, 0x01, 0];
+                out.extend_from_slice(&16u32.to_le_bytes());
+                out.extend(NumberType::Unsigned(8).datatype());
+                out
+            }
+        }
+    }
+}
+
+/// A value column and how it is stored; text columns have no type and are
+/// left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub number_type: Option<NumberType>,
+}
+
+/// An attribute value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Text(String),
+}
+
+/// How each value column of `format` is stored, as named by
+/// `LineFormat::column_names`: fields declared with `--types` as their
+/// type, `str` fields not at all, and undeclared fields and computed columns
+/// as 64-bit floats.
+pub fn column_types(format: &LineFormat) -> Vec<Option<NumberType>> {
+    let mut types: Vec<Option<NumberType>> = match &format.types {
+        Some(schema) => schema
+            .columns
+            .iter()
+            .map(|column| match *column {
+                ColumnType::Signed { bits } => Some(NumberType::Signed(bits)),
+                ColumnType::Unsigned { bits } => Some(NumberType::Unsigned(bits)),
+                ColumnType::Float { bits, .. } => Some(NumberType::Float(bits)),
+                ColumnType::Text => None,
+            })
+            .collect(),
+        None => vec![Some(NumberType::Float(64)); format.field_count()],
+    };
+    types.extend(format.computed.iter().map(|_| Some(NumberType::Float(64))));
+    types
+}
+
+/// Writes runs to an HDF5 file, a chunk of rows at a time.
+pub struct FileWriter<W: Write + Seek> {
+    out: W,
+    fields: Vec<Field>,
+    chunk_rows: usize,
+    // End of the space allocated so far, where the next object goes
+    end: u64,
+    root: Header,
+    run: Option<Run>,
+    finished: bool,
+}
+
+// The run being written
+struct Run {
+    datasets: Vec<Dataset>,
+    // Rows in the chunks written, and collected for the next ones
+    rows: u64,
+    pending: usize,
+    // Timestamp, Type and Text of the events, whose last chunk holds its
+    // rows so far in `values`
+    events: Vec<Dataset>,
+    event_rows: u64,
+    heap: Option<Heap>,
+}
+
+struct Dataset {
+    header: u64,
+    element: Element,
+    // Attribute messages of the header
+    attributes: Vec<u8>,
+    chunks: Vec<u64>,
+    // Addresses of the index nodes, by level from the leaves up
+    nodes: Vec<Vec<u64>>,
+    // Values collected for the next chunk
+    values: Vec<u8>,
+}
+
+// A global heap collection that strings are added to until it is full
+struct Heap {
+    address: u64,
+    size: usize,
+    // The objects so far, each a header and its data padded to 8 bytes
+    objects: Vec<u8>,
+    count: u16,
+}
+
+// A version 2 object header that messages can be added to, which grows by
+// continuation chunks at the end of the file
+struct Header {
+    flags: u8,
+    phase_change: Option<(u16, u16)>,
+    chunks: Vec<HeaderChunk>,
+}
+
+struct HeaderChunk {
+    address: u64,
+    // Bytes for messages, the unused ones a null message
+    space: usize,
+    messages: Vec<u8>,
+}
+
+impl<W: Write + Seek> FileWriter<W> {
+    /// Starts the file, with `metadata` as attributes of its root group;
+    /// until the first run it is a valid file without groups.
+    pub fn new(out: W, fields: Vec<Field>, metadata: &[(String, String)], chunk_rows: usize) -> io::Result<Self> {
+        let mut messages = link_info();
+        messages.extend(group_info());
+        for (key, value) in metadata {
+            messages.extend(attribute(key, &Value::Text(value.clone())));
+        }
+        let phase_change = (metadata.len() > DEFAULT_MAX_COMPACT).then(|| (metadata.len().min(u16::MAX as usize) as u16, 6));
+        let space = messages.len() + ROOT_LINK_SPACE + CONTINUATION_SIZE;
+        let root = Header {
+            flags: HEADER_SIZE_4 | if phase_change.is_some() { HEADER_PHASE_CHANGE } else { 0 },
+            phase_change,
+            chunks: vec![HeaderChunk {
+                address: SUPERBLOCK_SIZE,
+                space,
+                messages,
+            }],
+        };
+        let end = SUPERBLOCK_SIZE + root.chunk_size(0);
+        let mut writer = FileWriter {
+            out,
+            fields,
+            chunk_rows: chunk_rows.max(1),
+            end,
+            root,
+            run: None,
+            finished: false,
+        };
+        let root = writer.root.image(0);
+        writer.write_at(SUPERBLOCK_SIZE, &root)?;
+        writer.write_superblock()?;
+        writer.out.flush()?;
+        Ok(writer)
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// Whether a run's group has been started.
+    pub fn has_group(&self) -> bool {
+        self.run.is_some()
+    }
+
+    /// Starts a run's group, after writing what is left of the previous
+    /// run's rows. Its datasets have the fields' names, `/` replaced.
+    pub fn start_group(&mut self, name: &str, attributes: &[(&str, Value)]) -> io::Result<()> {
+        self.check_open()?;
+        self.end_group()?;
+        let start = self.end;
+        let mut images = Vec::new();
+        let mut datasets = vec![timestamps()];
+        let mut names = vec!["Timestamp".to_string()];
+        for field in &self.fields {
+            if let Some(number_type) = field.number_type {
+                datasets.push(Dataset::new(Element::Number(number_type), Vec::new()));
+                names.push(field.name.replace('/', "_"));
+            }
+        }
+        let mut events = vec![timestamps(), Dataset::new(Element::Text, Vec::new()), Dataset::new(Element::Text, Vec::new())];
+        let events_group = self.add_group(&mut events, &["Timestamp", "Type", "Text"], Vec::new(), EVENT_CHUNK_ROWS, &mut images);
+        let mut messages = Vec::new();
+        for (key, value) in attributes {
+            messages.extend(attribute(key, value));
+        }
+        messages.extend(link("events", events_group));
+        let group = self.add_group(&mut datasets, &names, messages, self.chunk_rows, &mut images);
+        let changed = self.root.add(link(name, group), &mut self.end);
+        for chunk in &changed {
+            images.push((self.root.chunks[*chunk].address, self.root.image(*chunk)));
+        }
+        self.run = Some(Run {
+            datasets,
+            rows: 0,
+            pending: 0,
+            events,
+            event_rows: 0,
+            heap: None,
+        });
+        self.commit(start, images)
+    }
+
+    /// Adds an event of the current run: its time in milliseconds, its type
+    /// and the rest of its row as text, and writes it at once. Without a run
+    /// the event is dropped.
+    pub fn push_event(&mut self, time_ms: i64, kind: &str, text: &str) -> io::Result<()> {
+        self.check_open()?;
+        let start = self.end;
+        let Some(run) = &mut self.run else { return Ok(()) };
+        let mut images = Vec::new();
+        if run.event_rows.is_multiple_of(EVENT_CHUNK_ROWS as u64) {
+            for dataset in &mut run.events {
+                dataset.values.clear();
+                dataset.chunks.push(self.end);
+                self.end += (EVENT_CHUNK_ROWS * dataset.element.size()) as u64;
+                for (level, index) in dataset.grow_index(&mut self.end) {
+                    images.push((dataset.nodes[level][index], dataset.node(level, index, EVENT_CHUNK_ROWS)));
+                }
+            }
+        }
+        // The collections first, so the chunks never refer to a string that
+        // isn't there
+        let mut heaps = Vec::new();
+        run.events[0].values.extend_from_slice(&time_ms.to_le_bytes());
+        for (dataset, value) in run.events[1..].iter_mut().zip([kind, text]) {
+            let reference = if value.is_empty() {
+                [0; 12]
+            } else {
+                if !run.heap.as_ref().is_some_and(|heap| heap.fits(value.len())) {
+                    if let Some(full) = run.heap.replace(Heap::new(&mut self.end, value.len())) {
+                        heaps.push((full.address, full.image()));
+                    }
+                }
+                run.heap.as_mut().expect("A collection was allocated").add(value.as_bytes())
+            };
+            dataset.values.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            dataset.values.extend_from_slice(&reference);
+        }
+        run.event_rows += 1;
+        if let Some(heap) = &run.heap {
+            heaps.push((heap.address, heap.image()));
+        }
+        images.splice(0..0, heaps);
+        for dataset in &run.events {
+            let mut chunk = dataset.values.clone();
+            chunk.resize(EVENT_CHUNK_ROWS * dataset.element.size(), 0);
+            images.push((*dataset.chunks.last().expect("A chunk was allocated"), chunk));
+            images.push((dataset.header, single_chunk(&dataset.messages(run.event_rows, EVENT_CHUNK_ROWS))));
+        }
+        self.commit(start, images)
+    }
+
+    /// Adds a row of the current run: its time in milliseconds and text
+    /// cells, one per field. Writes a chunk once enough rows have been
+    /// collected; without a run the row is dropped.
+    pub fn push_row<T: AsRef<[u8]>>(&mut self, time_ms: i64, cells: &[T]) -> io::Result<()> {
+        self.check_open()?;
+        let Some(run) = &mut self.run else { return Ok(()) };
+        let mut datasets = run.datasets.iter_mut();
+        if let Some(timestamps) = datasets.next() {
+            timestamps.values.extend_from_slice(&time_ms.to_le_bytes());
+        }
+        let stored = self.fields.iter().enumerate().filter_map(|(i, field)| field.number_type.map(|number_type| (i, number_type)));
+        for ((i, number_type), dataset) in stored.zip(datasets) {
+            let cell = String::from_utf8_lossy(cells.get(i).map_or(&b""[..], |cell| cell.as_ref()));
+            number_type.encode(&cell, &mut dataset.values);
+        }
+        run.pending += 1;
+        if run.pending >= self.chunk_rows {
+            self.write_chunks()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the chunks written so far; rows of an incomplete chunk stay
+    /// in memory until it fills up or the run ends.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Writes the last rows of the run; the file takes no more.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.end_group()?;
+        self.finished = true;
+        self.out.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    fn check_open(&self) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other("the HDF5 file has already been finished"));
+        }
+        Ok(())
+    }
+
+    // Allocates the headers of `datasets` and of a group linking them under
+    // `names`, with `extra` messages of its own, and adds their images;
+    // returns the group's address
+    fn add_group(
+        &mut self,
+        datasets: &mut [Dataset],
+        names: &[impl AsRef<str>],
+        extra: Vec<u8>,
+        chunk_rows: usize,
+        images: &mut Vec<(u64, Vec<u8>)>,
+    ) -> u64 {
+        for dataset in datasets.iter_mut() {
+            let image = single_chunk(&dataset.messages(0, chunk_rows));
+            dataset.header = self.end;
+            self.end += image.len() as u64;
+            images.push((dataset.header, image));
+        }
+        let mut messages = link_info();
+        messages.extend(group_info());
+        messages.extend(extra);
+        for (name, dataset) in names.iter().zip(datasets.iter()) {
+            messages.extend(link(name.as_ref(), dataset.header));
+        }
+        let group = self.end;
+        let image = single_chunk(&messages);
+        self.end += image.len() as u64;
+        images.push((group, image));
+        group
+    }
+
+    // Writes the current run's remaining rows as a last, partial chunk
+    fn end_group(&mut self) -> io::Result<()> {
+        if self.run.as_ref().is_some_and(|run| run.pending > 0) {
+            self.write_chunks()?;
+        }
+        self.run = None;
+        Ok(())
+    }
+
+    // Writes the collected rows as a chunk of every dataset of the run and
+    // adds the chunks to the datasets
+    fn write_chunks(&mut self) -> io::Result<()> {
+        let start = self.end;
+        let chunk_rows = self.chunk_rows;
+        let Some(run) = &mut self.run else { return Ok(()) };
+        let mut images = Vec::new();
+        run.rows += run.pending as u64;
+        run.pending = 0;
+        for dataset in &mut run.datasets {
+            let mut chunk = std::mem::take(&mut dataset.values);
+            chunk.resize(chunk_rows * dataset.element.size(), 0);
+            dataset.chunks.push(self.end);
+            images.push((self.end, chunk));
+            self.end += (chunk_rows * dataset.element.size()) as u64;
+            for (level, index) in dataset.grow_index(&mut self.end) {
+                images.push((dataset.nodes[level][index], dataset.node(level, index, chunk_rows)));
+            }
+            images.push((dataset.header, single_chunk(&dataset.messages(run.rows, chunk_rows))));
+        }
+        self.commit(start, images)
+    }
+
+    // Writes what was appended at `start` or later, then the superblock with
+    // the new end of the file, then what is rewritten in place
+    fn commit(&mut self, start: u64, images: Vec<(u64, Vec<u8>)>) -> io::Result<()> {
+        let (appended, rewritten): (Vec<_>, Vec<_>) = images.into_iter().partition(|(address, _)| *address >= start);
+        for (address, image) in &appended {
+            self.write_at(*address, image)?;
+        }
+        self.write_superblock()?;
+        for (address, image) in &rewritten {
+            self.write_at(*address, image)?;
+        }
+        self.out.flush()
+    }
+
+    fn write_superblock(&mut self) -> io::Result<()> {
+        let mut image = SIGNATURE.to_vec();
+        // Version 2, 8-byte addresses and lengths, no consistency flags
+        image.extend_from_slice(&[2, 8, 8, 0]);
+        image.extend_from_slice(&0u64.to_le_bytes());
+        image.extend_from_slice(&UNDEFINED.to_le_bytes());
+        image.extend_from_slice(&self.end.to_le_bytes());
+        image.extend_from_slice(&self.root.chunks[0].address.to_le_bytes());
+        let checksum = lookup3(&image);
+        image.extend_from_slice(&checksum.to_le_bytes());
+        self.write_at(0, &image)
+    }
+
+    fn write_at(&mut self, address: u64, bytes: &[u8]) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(address))?;
+        self.out.write_all(bytes)
+    }
+}
+
+impl Dataset {
+    fn new(element: Element, attributes: Vec<u8>) -> Dataset {
+        Dataset {
+            header: UNDEFINED,
+            element,
+            attributes,
+            chunks: Vec::new(),
+            nodes: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    // The header messages for `rows` rows
+    fn messages(&self, rows: u64, chunk_rows: usize) -> Vec<u8> {
+        let size = self.element.size();
+        // Dataspace version 2: one dimension, extensible without limit
+        let mut dataspace = vec![2, 1, 1, 1];
+        dataspace.extend_from_slice(&rows.to_le_bytes());
+        dataspace.extend_from_slice(&UNDEFINED.to_le_bytes());
+        // Fill value version 2: allocated as written, filled if set, not set
+        let fill_value = vec![2, 3, 2, 0];
+        // Layout version 3, chunked: the index's root, then the chunk's rows
+        // and element size
+        let mut layout = vec![3, 2, 2];
+        let root = self.nodes.last().map_or(UNDEFINED, |level| level[0]);
+        layout.extend_from_slice(&root.to_le_bytes());
+        layout.extend_from_slice(&(chunk_rows as u32).to_le_bytes());
+        layout.extend_from_slice(&(size as u32).to_le_bytes());
+        let mut messages = message(MSG_DATASPACE, &dataspace);
+        messages.extend(message(MSG_DATATYPE, &self.element.datatype()));
+        messages.extend(message(MSG_FILL_VALUE, &fill_value));
+        messages.extend(message(MSG_LAYOUT, &layout));
+        messages.extend_from_slice(&self.attributes);
+        messages
+    }
+
+    // Allocates the index nodes the chunks now need and returns the nodes
+    // (level and index) to write: the last of each level, and the one
+    // before a new node, whose right sibling it becomes
+    fn grow_index(&mut self, end: &mut u64) -> Vec<(usize, usize)> {
+        let mut changed = Vec::new();
+        let mut entries = self.chunks.len();
+        let mut level = 0;
+        loop {
+            if self.nodes.len() == level {
+                self.nodes.push(Vec::new());
+            }
+            let needed = entries.div_ceil(NODE_ENTRIES);
+            let nodes = &mut self.nodes[level];
+            while nodes.len() < needed {
+                nodes.push(*end);
+                *end += NODE_SIZE;
+                if nodes.len() > 1 {
+                    changed.push((level, nodes.len() - 2));
+                }
+            }
+            changed.push((level, needed - 1));
+            if needed == 1 {
+                return changed;
+            }
+            entries = needed;
+            level += 1;
+        }
+    }
+
+    // A version 1 B-tree node of the chunk index. Each key has the size of
+    // the chunk after it and its first row (and 0 for the element), the last
+    // key the row after the node's chunks.
+    fn node(&self, level: usize, index: usize, chunk_rows: usize) -> Vec<u8> {
+        let children: &[u64] = if level == 0 { &self.chunks } else { &self.nodes[level - 1] };
+        let first = index * NODE_ENTRIES;
+        let last = (first + NODE_ENTRIES).min(children.len());
+        // Chunks under each child
+        let span = NODE_ENTRIES.pow(level as u32);
+        let chunk_bytes = (chunk_rows * self.element.size()) as u32;
+        let siblings = &self.nodes[level];
+        let mut image = b"TREE".to_vec();
+        // Type 1 (chunks)
+        image.extend_from_slice(&[1, level as u8]);
+        image.extend_from_slice(&((last - first) as u16).to_le_bytes());
+        let left = index.checked_sub(1).map_or(UNDEFINED, |left| siblings[left]);
+        let right = siblings.get(index + 1).copied().unwrap_or(UNDEFINED);
+        image.extend_from_slice(&left.to_le_bytes());
+        image.extend_from_slice(&right.to_le_bytes());
+        let key = |image: &mut Vec<u8>, size: u32, chunk: usize| {
+            image.extend_from_slice(&size.to_le_bytes());
+            image.extend_from_slice(&0u32.to_le_bytes());
+            image.extend_from_slice(&((chunk * chunk_rows) as u64).to_le_bytes());
+            image.extend_from_slice(&0u64.to_le_bytes());
+        };
+        for (child, address) in children[first..last].iter().enumerate() {
+            key(&mut image, chunk_bytes, (first + child) * span);
+            image.extend_from_slice(&address.to_le_bytes());
+        }
+        key(&mut image, 0, (last * span).min(self.chunks.len()));
+        image.resize(NODE_SIZE as usize, 0);
+        image
+    }
+}
+
+impl Heap {
+    // A new collection at `end` with room for a string of `len` bytes
+    fn new(end: &mut u64, len: usize) -> Heap {
+        let heap = Heap {
+            address: *end,
+            size: HEAP_SIZE.max(HEAP_HEADER * 3 + len.next_multiple_of(8)),
+            objects: Vec::new(),
+            count: 0,
+        };
+        *end += heap.size as u64;
+        heap
+    }
+
+    // Whether a string of `len` bytes fits, leaving room for the header of
+    // the free space after it
+    fn fits(&self, len: usize) -> bool {
+        self.count < u16::MAX && HEAP_HEADER * 3 + self.objects.len() + len.next_multiple_of(8) <= self.size
+    }
+
+    // Adds an object; returns its heap ID after the length of a
+    // variable-length value: the collection's address and the object's index
+    fn add(&mut self, data: &[u8]) -> [u8; 12] {
+        self.count += 1;
+        self.objects.extend_from_slice(&self.count.to_le_bytes());
+        // Reference count, reserved
+        self.objects.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        self.objects.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        self.objects.extend_from_slice(data);
+        self.objects.resize(self.objects.len().next_multiple_of(8), 0);
+        let mut id = [0; 12];
+        id[..8].copy_from_slice(&self.address.to_le_bytes());
+        id[8..].copy_from_slice(&u32::from(self.count).to_le_bytes());
+        id
+    }
+
+    // The collection, its free space at the end as object 0
+    fn image(&self) -> Vec<u8> {
+        let mut image = b"GCOL".to_vec();
+        image.extend_from_slice(&[1, 0, 0, 0]);
+        image.extend_from_slice(&(self.size as u64).to_le_bytes());
+        image.extend_from_slice(&self.objects);
+        let free = self.size - image.len();
+        image.extend_from_slice(&[0; 8]);
+        image.extend_from_slice(&(free as u64).to_le_bytes());
+        image.resize(self.size, 0);
+        image
+    }
+}
+
+impl Header {
+    // Bytes of a chunk, from its signature to its checksum
+    fn chunk_size(&self, index: usize) -> u64 {
+        let prefix = if index == 0 { self.prefix_size() } else { 4 };
+        (prefix + self.chunks[index].space + 4) as u64
+    }
+
+    fn prefix_size(&self) -> usize {
+        // Signature, version, flags, phase change values, chunk size
+        4 + 1 + 1 + if self.phase_change.is_some() { 4 } else { 0 } + 4
+    }
+
+    // Adds a message, in a new chunk at `end` when the last one is full; a
+    // chunk always keeps room for the continuation message to the next.
+    // Returns the chunks to write.
+    fn add(&mut self, message: Vec<u8>, end: &mut u64) -> Vec<usize> {
+        let last = self.chunks.len() - 1;
+        let chunk = &mut self.chunks[last];
+        if chunk.messages.len() + message.len() + CONTINUATION_SIZE <= chunk.space {
+            chunk.messages.extend(message);
+            return vec![last];
+        }
+        let space = CONTINUATION_SPACE.max(message.len() + CONTINUATION_SIZE);
+        let address = *end;
+        let length = (4 + space + 4) as u64;
+        let mut continuation = address.to_le_bytes().to_vec();
+        continuation.extend_from_slice(&length.to_le_bytes());
+        chunk.messages.extend(self::message(MSG_CONTINUATION, &continuation));
+        self.chunks.push(HeaderChunk {
+            address,
+            space,
+            messages: message,
+        });
+        *end += length;
+        vec![last, last + 1]
+    }
+
+    fn image(&self, index: usize) -> Vec<u8> {
+        let chunk = &self.chunks[index];
+        let mut image = Vec::with_capacity(self.chunk_size(index) as usize);
+        if index == 0 {
+            image.extend_from_slice(b"OHDR");
+            image.extend_from_slice(&[2, self.flags]);
+            if let Some((max_compact, min_dense)) = self.phase_change {
+                image.extend_from_slice(&max_compact.to_le_bytes());
+                image.extend_from_slice(&min_dense.to_le_bytes());
+            }
+            image.extend_from_slice(&(chunk.space as u32).to_le_bytes());
+        } else {
+            image.extend_from_slice(b"OCHK");
+        }
+        image.extend_from_slice(&chunk.messages);
+        let free = chunk.space - chunk.messages.len();
+        if free >= MSG_HEADER {
+            image.extend(message(MSG_NIL, &vec![0; free - MSG_HEADER]));
+        } else {
+            // A gap too small for a message
+            image.resize(image.len() + free, 0);
+        }
+        let checksum = lookup3(&image);
+        image.extend_from_slice(&checksum.to_le_bytes());
+        image
+    }
+}
+
+// The Timestamp dataset of a group
+fn timestamps() -> Dataset {
+    Dataset::new(
+        Element::Number(NumberType::Signed(64)),
+        attribute("units", &Value::Text("milliseconds since 1970-01-01 00:00:00 UTC".to_string())),
+    )
+}
+
+// An object header of one chunk holding exactly `messages`
+fn single_chunk(messages: &[u8]) -> Vec<u8> {
+    Header {
+        flags: HEADER_SIZE_4,
+        phase_change: None,
+        chunks: vec![HeaderChunk {
+            address: UNDEFINED,
+            space: messages.len(),
+            messages: messages.to_vec(),
+        }],
+    }
+    .image(0)
+}
+
+fn message(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    out.push(0);
+    out.extend_from_slice(data);
+    out
+}
+
+// Link info version 0: no creation order, links stored in the header
+fn link_info() -> Vec<u8> {
+    let mut data = vec![0, 0];
+    data.extend_from_slice(&UNDEFINED.to_le_bytes());
+    data.extend_from_slice(&UNDEFINED.to_le_bytes());
+    message(MSG_LINK_INFO, &data)
+}
+
+// Group info version 0, with the default limits
+fn group_info() -> Vec<u8> {
+    message(MSG_GROUP_INFO, &[0, 0])
+}
+
+// A hard link, with a UTF-8 name
+fn link(name: &str, address: u64) -> Vec<u8> {
+    let name = name.as_bytes();
+    let mut data = vec![1];
+    if name.len() <= u8::MAX as usize {
+        data.extend_from_slice(&[0x10, 1, name.len() as u8]);
+    } else {
+        data.extend_from_slice(&[0x11, 1]);
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    }
+    data.extend_from_slice(name);
+    data.extend_from_slice(&address.to_le_bytes());
+    message(MSG_LINK, &data)
+}
+
+// An attribute message (version 3) with a scalar value: a 64-bit integer or
+// a null-padded UTF-8 string
+fn attribute(name: &str, value: &Value) -> Vec<u8> {
+    let (datatype, value) = match value {
+        Value::Int(number) => (NumberType::Signed(64).datatype(), number.to_le_bytes().to_vec()),
+        Value::Text(text) => {
+            let mut bytes = text.as_bytes().to_vec();
+            if bytes.is_empty() {
+                bytes.push(0);
+            }
+            // Class 3 (string), version 1; null padding, UTF-8
+            let mut datatype = vec![0x13, 0x11, 0, 0];
+            datatype.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            (datatype, bytes)
+        }
+    };
+    // Dataspace version 2, scalar
+    let dataspace = [2, 0, 0, 0];
+    let mut data = vec![3, 0];
+    data.extend_from_slice(&(name.len() as u16 + 1).to_le_bytes());
+    data.extend_from_slice(&(datatype.len() as u16).to_le_bytes());
+    data.extend_from_slice(&(dataspace.len() as u16).to_le_bytes());
+    data.push(1);
+    data.extend_from_slice(name.as_bytes());
+    data.push(0);
+    data.extend_from_slice(&datatype);
+    data.extend_from_slice(&dataspace);
+    data.extend_from_slice(&value);
+    message(MSG_ATTRIBUTE, &data)
+}
+
+/// Bob Jenkins' lookup3 `hashlittle` with an initial value of 0, HDF5's
+/// checksum of its metadata.
+pub fn lookup3(data: &[u8]) -> u32 {
+    let mut a = 0xdead_beefu32.wrapping_add(data.len() as u32);
+    let mut b = a;
+    let mut c = a;
+    let word = |bytes: &[u8]| {
+        let mut padded = [0u8; 4];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        u32::from_le_bytes(padded)
+    };
+    let mut rest = data;
+    while rest.len() > 12 {
+        a = a.wrapping_add(word(&rest[0..4]));
+        b = b.wrapping_add(word(&rest[4..8]));
+        c = c.wrapping_add(word(&rest[8..12]));
+        a = a.wrapping_sub(c) ^ c.rotate_left(4);
+        c = c.wrapping_add(b);
+        b = b.wrapping_sub(a) ^ a.rotate_left(6);
+        a = a.wrapping_add(c);
+        c = c.wrapping_sub(b) ^ b.rotate_left(8);
+        b = b.wrapping_add(a);
+        a = a.wrapping_sub(c) ^ c.rotate_left(16);
+        c = c.wrapping_add(b);
+        b = b.wrapping_sub(a) ^ a.rotate_left(19);
+        a = a.wrapping_add(c);
+        c = c.wrapping_sub(b) ^ b.rotate_left(4);
+        b = b.wrapping_add(a);
+        rest = &rest[12..];
+    }
+    if rest.is_empty() {
+        return c;
+    }
+    a = a.wrapping_add(word(&rest[..rest.len().min(4)]));
+    if rest.len() > 4 {
+        b = b.wrapping_add(word(&rest[4..rest.len().min(8)]));
+    }
+    if rest.len() > 8 {
+        c = c.wrapping_add(word(&rest[8..]));
+    }
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(14));
+    a ^= c;
+    a = a.wrapping_sub(c.rotate_left(11));
+    b ^= a;
+    b = b.wrapping_sub(a.rotate_left(25));
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(16));
+    a ^= c;
+    a = a.wrapping_sub(c.rotate_left(4));
+    b ^= a;
+    b = b.wrapping_sub(a.rotate_left(14));
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(24));
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hdf5_reader::Hdf5File;
+
+    fn fields() -> Vec<Field> {
+        vec![
+            Field { name: "ax".into(), number_type: Some(NumberType::Float(64)) },
+            Field { name: "note".into(), number_type: None },
+            Field { name: "n".into(), number_type: Some(NumberType::Signed(32)) },
+        ]
+    }
+
+    fn writer(chunk_rows: usize) -> FileWriter<io::Cursor<Vec<u8>>> {
+        FileWriter::new(io::Cursor::new(Vec::new()), fields(), &[("port".into(), "COM3".into())], chunk_rows).unwrap()
+    }
+
+    fn read(writer: &FileWriter<io::Cursor<Vec<u8>>>) -> Hdf5File {
+        Hdf5File::from_vec(writer.get_ref().get_ref().clone()).unwrap()
+    }
+
+    #[test]
+    fn a_reference_reader_reads_every_run() {
+        let mut writer = writer(2);
+        for run in 0..2 {
+            let attributes = [("run", Value::Int(run)), ("run_id", Value::Text(format!("id{}", run)))];
+            writer.start_group(&format!("run_{:04}", run), &attributes).unwrap();
+            for i in 0..5 {
+                let cells = [format!("{}.5", i), "text".to_string(), (i * 10 + run).to_string()];
+                writer.push_row(run * 100_000 + i * 1000, &cells).unwrap();
+            }
+        }
+        writer.finish().unwrap();
+        let file = read(&writer);
+        assert_eq!(file.root_group().unwrap().attribute("port").unwrap().read_string().unwrap(), "COM3");
+        for run in 0..2i64 {
+            let group = file.group(&format!("/run_{:04}", run)).unwrap();
+            assert_eq!(group.attribute("run").unwrap().read_scalar::<i64>().unwrap(), run);
+            assert_eq!(group.attribute("run_id").unwrap().read_string().unwrap(), format!("id{}", run));
+            let path = |name: &str| format!("/run_{:04}/{}", run, name);
+            let ax = file.dataset(&path("ax")).unwrap().read_array::<f64>().unwrap();
+            assert_eq!(ax.iter().copied().collect::<Vec<_>>(), [0.5, 1.5, 2.5, 3.5, 4.5]);
+            let n = file.dataset(&path("n")).unwrap().read_array::<i32>().unwrap();
+            assert_eq!(n.iter().copied().collect::<Vec<_>>(), (0..5).map(|i| i * 10 + run as i32).collect::<Vec<_>>());
+            let times = file.dataset(&path("Timestamp")).unwrap().read_array::<i64>().unwrap();
+            assert_eq!(times.iter().copied().collect::<Vec<_>>(), (0..5).map(|i| run * 100_000 + i * 1000).collect::<Vec<_>>());
+            assert!(file.dataset(&path("note")).is_err());
+        }
+    }
+
+    #[test]
+    fn events_are_read_back_before_the_file_is_finished() {
+        let mut writer = writer(16);
+        // Rows outside a run have nowhere to go
+        writer.push_event(1, "mark", "before").unwrap();
+        writer.start_group("run_0000", &[("run", Value::Int(0))]).unwrap();
+        writer.push_row(5, &["1".to_string(), String::new(), "2".to_string()]).unwrap();
+        // Past a chunk of events, with strings past a heap collection
+        let long = "x".repeat(HEAP_SIZE);
+        let mut expected = Vec::new();
+        for i in 0..EVENT_CHUNK_ROWS as i64 + 3 {
+            let text = match i {
+                3 => String::new(),
+                10 => long.clone(),
+                _ => format!("gap {},\"a, b\"", i),
+            };
+            writer.push_event(1000 + i, if i % 2 == 0 { "gap" } else { "mark" }, &text).unwrap();
+            expected.push((1000 + i, if i % 2 == 0 { "gap" } else { "mark" }, text));
+        }
+        let file = read(&writer);
+        let times = file.dataset("/run_0000/events/Timestamp").unwrap().read_array::<i64>().unwrap();
+        let kinds = file.dataset("/run_0000/events/Type").unwrap().read_strings().unwrap();
+        let texts = file.dataset("/run_0000/events/Text").unwrap().read_strings().unwrap();
+        assert_eq!(times.len(), expected.len());
+        for (i, (time, kind, text)) in expected.iter().enumerate() {
+            assert_eq!((times[i], kinds[i].as_str(), &texts[i]), (*time, *kind, text));
+        }
+        let units = file.dataset("/run_0000/events/Timestamp").unwrap().attribute("units").unwrap();
+        assert_eq!(units.read_string().unwrap(), "milliseconds since 1970-01-01 00:00:00 UTC");
+        writer.finish().unwrap();
+        let file = read(&writer);
+        assert_eq!(file.dataset("/run_0000/events/Text").unwrap().read_strings().unwrap().len(), expected.len());
+        assert_eq!(file.dataset("/run_0000/ax").unwrap().read_array::<f64>().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,41 @@
+//! Heartbeat rows with `--heartbeat`.
+//!
+//! A thread of its own writes a `heartbeat` row every interval while
+//! recording, whether or not the device sends anything, so a pause in the
+//! data can later be told apart from a pause of the logger itself. The serial
+//! thread can't do it: it may be blocked in a read. Rows go through the
+//! writer lock like every other row, and recording is checked under that
+//! lock, so a heartbeat never lands in the middle of a record or after a
+//! run's stop row.
+
+use collect_acc_data::logfile::Kind;
+use std::{
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::get_timestamp;
+use crate::session::{write_marker, Shared};
+
+/// Starts writing a heartbeat row every `interval` while recording.
+pub fn spawn(interval: Duration, shared: Arc<Shared>) {
+    thread::spawn(move || {
+        // Keep to the interval even when a write was slow
+        let mut next = Instant::now() + interval;
+        loop {
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+            next += interval;
+            if next < Instant::now() {
+                next = Instant::now() + interval;
+            }
+            let mut writer = shared.writer.lock().unwrap();
+            if !shared.recording.load(Ordering::Acquire) {
+                continue;
+            }
+            if let Some(w) = writer.as_mut() {
+                write_marker(w, Kind::Heartbeat, &[&get_timestamp()]);
+            }
+        }
+    });
+}
@@ -0,0 +1,134 @@
+//! Hex dump of the raw serial input, with `--hexdump`.
+//!
+//! Every chunk read from the port is shown as offset, hex and ASCII, 16 bytes
+//! per row, the way `hexdump -C` shows a file:
+//!
+//! ```text
+//! 00000000  31 34 31 2c 2d 32 2e 34  35 0d 0a 00              |141,-2.45...|
+//! ```
+//!
+//! Offsets count every byte received this session. Rows start with each
+//! read, so a row is shorter than 16 bytes when the port returned fewer, and
+//! bytes show up as soon as they arrive rather than when a row fills up. On
+//! the terminal at most `ROWS_PER_SECOND` rows are printed, followed by a
+//! note of how many bytes were left out; a dump file gets every row.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::tap::Observer;
+
+/// Bytes per row.
+const WIDTH: usize = 16;
+/// Rows printed to the terminal per second; the rest are counted.
+pub const ROWS_PER_SECOND: u32 = 50;
+
+enum Target {
+    Terminal,
+    File(BufWriter<File>),
+}
+
+pub struct HexDump {
+    // None once writing the dump file has failed
+    target: Option<Target>,
+    offset: u64,
+    // Start of the current second and the rows printed in it
+    window_start: Instant,
+    window_rows: u32,
+    // Bytes left out since the last row printed
+    hidden: u64,
+}
+
+impl HexDump {
+    /// Dumps to the terminal (stderr), rate-limited.
+    pub fn to_terminal() -> HexDump {
+        HexDump::new(Target::Terminal)
+    }
+
+    /// Appends every row to the file at `path`.
+    pub fn to_file(path: &Path) -> Result<HexDump, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open hex dump file {}: {}", path.display(), e))?;
+        Ok(HexDump::new(Target::File(BufWriter::new(file))))
+    }
+
+    fn new(target: Target) -> HexDump {
+        HexDump {
+            target: Some(target),
+            offset: 0,
+            window_start: Instant::now(),
+            window_rows: 0,
+            hidden: 0,
+        }
+    }
+
+    fn show(&mut self, bytes: &[u8]) {
+        match self.target.as_mut() {
+            Some(Target::Terminal) => {
+                if self.window_start.elapsed() >= Duration::from_secs(1) {
+                    self.window_start = Instant::now();
+                    self.window_rows = 0;
+                }
+                if self.window_rows >= ROWS_PER_SECOND {
+                    self.hidden += bytes.len() as u64;
+                    return;
+                }
+                if self.hidden > 0 {
+                    crate::console::diagnostic(&format!("... {} bytes not shown", self.hidden));
+                    self.hidden = 0;
+                }
+                self.window_rows += 1;
+                crate::console::diagnostic(&format_row(self.offset, bytes));
+            }
+            Some(Target::File(file)) => {
+                if let Err(e) = writeln!(file, "{}", format_row(self.offset, bytes)) {
+                    esay!("Warning: Failed to write hex dump: {}; not writing it for the rest of the session.", e);
+                    self.target = None;
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+impl Observer for HexDump {
+    fn observe(&mut self, bytes: &[u8]) {
+        for row in bytes.chunks(WIDTH) {
+            self.show(row);
+            self.offset += row.len() as u64;
+        }
+        if let Some(Target::File(file)) = self.target.as_mut() {
+            if let Err(e) = file.flush() {
+                esay!("Warning: Failed to write hex dump: {}; not writing it for the rest of the session.", e);
+                self.target = None;
+            }
+        }
+    }
+}
+
+// One row: offset, up to 16 bytes in hex (split in two groups of 8) and the
+// printable ASCII ones, with '.' for the rest
+fn format_row(offset: u64, bytes: &[u8]) -> String {
+    let mut hex = String::new();
+    for i in 0..WIDTH {
+        if i == WIDTH / 2 {
+            hex.push(' ');
+        }
+        match bytes.get(i) {
+            Some(b) => hex.push_str(&format!("{:02x} ", b)),
+            None => hex.push_str("   "),
+        }
+    }
+    let ascii: String = bytes
+        .iter()
+        .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+        .collect();
+    format!("{:08x}  {} |{}|", offset, hex, ascii)
+}
@@ -0,0 +1,366 @@
+//! Keeping start/stop thrashing from making junk runs: `--min-run-duration`
+//! and `--start-holdoff`.
+//!
+//! Every start and stop, whoever asks for it (the operator or a gRPC client,
+//! the device, the hardware trigger), is put to the session's one `Guard`,
+//! which tracks whether a run is recording, waiting to start or neither, and
+//! decides what the request does. A stop before the run has lasted
+//! `--min-run-duration` leaves it recording with a warning or, with
+//! `--short-run-action mark`, stops it with `short run` in its stop row. A
+//! start within `--start-holdoff` of the last stop waits for the holdoff to
+//! pass or, with `--holdoff-action refuse`, is refused; a stop while a start
+//! waits cancels it. Stops for a cap, a full disk, a file switch or the end of
+//! the session are never held back.
+
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::session::Session;
+
+// How often a waiting start is checked
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a stop before `--min-run-duration` does (`--short-run-action`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShortRunAction {
+    Keep,
+    Mark,
+}
+
+impl ShortRunAction {
+    pub fn parse(text: &str) -> Result<ShortRunAction, String> {
+        match text {
+            "keep" => Ok(ShortRunAction::Keep),
+            "mark" => Ok(ShortRunAction::Mark),
+            _ => Err(format!("Invalid --short-run-action '{}': expected keep or mark", text)),
+        }
+    }
+}
+
+/// What a start within `--start-holdoff` does (`--holdoff-action`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HoldoffAction {
+    Delay,
+    Refuse,
+}
+
+impl HoldoffAction {
+    pub fn parse(text: &str) -> Result<HoldoffAction, String> {
+        match text {
+            "delay" => Ok(HoldoffAction::Delay),
+            "refuse" => Ok(HoldoffAction::Refuse),
+            _ => Err(format!("Invalid --holdoff-action '{}': expected delay or refuse", text)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub min_duration: Option<Duration>,
+    pub short_run: ShortRunAction,
+    pub holdoff: Option<Duration>,
+    pub holdoff_action: HoldoffAction,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            min_duration: None,
+            short_run: ShortRunAction::Keep,
+            holdoff: None,
+            holdoff_action: HoldoffAction::Delay,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    // Not recording, since the last stop if there was one
+    Idle(Option<Instant>),
+    // A start waits until then
+    Waiting(Instant),
+    Recording(Instant),
+}
+
+/// What a start request does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Start {
+    Now,
+    // Within the holdoff: the start happens after this long...
+    Delay(Duration),
+    // ...or not at all, and this much of the holdoff is left
+    Refuse(Duration),
+    // A start is already waiting
+    AlreadyWaiting(Duration),
+    AlreadyRecording,
+}
+
+/// What a stop request does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    // The run stops, marked short if it ended before the minimum duration
+    Now { short: bool },
+    // Before the minimum duration: the run goes on, having lasted this long
+    Keep(Duration),
+    // A waiting start is called off
+    Cancel,
+    NotRecording,
+}
+
+/// The run state of the session and the policy applied to its requests.
+#[derive(Debug)]
+pub struct Guard {
+    policy: Policy,
+    state: State,
+}
+
+impl Guard {
+    pub fn new(policy: Policy) -> Guard {
+        Guard {
+            policy,
+            state: State::Idle(None),
+        }
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Decides a start requested at `now`. A delayed start is waiting from
+    /// here on; the others leave the state as it is until `started`.
+    pub fn start(&mut self, now: Instant) -> Start {
+        match self.state {
+            State::Recording(_) => Start::AlreadyRecording,
+            State::Waiting(until) => Start::AlreadyWaiting(until.saturating_duration_since(now)),
+            State::Idle(stopped) => {
+                let holdoff = self.policy.holdoff.unwrap_or_default();
+                let left = stopped.map_or(Duration::ZERO, |stopped| holdoff.saturating_sub(now - stopped));
+                if left.is_zero() {
+                    return Start::Now;
+                }
+                match self.policy.holdoff_action {
+                    HoldoffAction::Delay => {
+                        self.state = State::Waiting(now + left);
+                        Start::Delay(left)
+                    }
+                    HoldoffAction::Refuse => Start::Refuse(left),
+                }
+            }
+        }
+    }
+
+    /// Decides a stop requested at `now`. A cancelled start leaves the guard
+    /// idle as at the last stop; the others leave the state as it is until
+    /// `stopped`.
+    pub fn stop(&mut self, now: Instant) -> Stop {
+        match self.state {
+            State::Idle(_) => Stop::NotRecording,
+            State::Waiting(until) => {
+                // The holdoff still counts from the stop before
+                let stopped = until.checked_sub(self.policy.holdoff.unwrap_or_default());
+                self.state = State::Idle(stopped);
+                Stop::Cancel
+            }
+            State::Recording(since) => {
+                let lasted = now - since;
+                match self.policy.min_duration.filter(|min| lasted < *min) {
+                    None => Stop::Now { short: false },
+                    Some(_) if self.policy.short_run == ShortRunAction::Mark => Stop::Now { short: true },
+                    Some(_) => Stop::Keep(lasted),
+                }
+            }
+        }
+    }
+
+    /// Whether a waiting start is due at `now`; it stops waiting if so, and
+    /// the caller starts the run.
+    pub fn due(&mut self, now: Instant) -> bool {
+        match self.state {
+            State::Waiting(until) if now >= until => {
+                self.state = State::Idle(None);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// How long until a waiting start, if one is waiting.
+    pub fn waiting(&self, now: Instant) -> Option<Duration> {
+        match self.state {
+            State::Waiting(until) => Some(until.saturating_duration_since(now)),
+            _ => None,
+        }
+    }
+
+    /// A run started at `now`, however it was asked for.
+    pub fn started(&mut self, now: Instant) {
+        self.state = State::Recording(now);
+    }
+
+    /// The run stopped at `now`, however it was asked for.
+    pub fn stopped(&mut self, now: Instant) {
+        self.state = State::Idle(Some(now));
+    }
+}
+
+/// Starts a delayed start once its holdoff is over, checking a few times a
+/// second; nothing to do unless starts can be delayed.
+pub fn spawn(session: Arc<Mutex<Session>>) {
+    let policy = session.lock().unwrap().run_policy().clone();
+    if policy.holdoff.is_none() || policy.holdoff_action != HoldoffAction::Delay {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        session.lock().unwrap().start_if_due();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn guard(min_duration: u32, short_run: ShortRunAction, holdoff: u32, holdoff_action: HoldoffAction) -> Guard {
+        Guard::new(Policy {
+            min_duration: (min_duration > 0).then(|| min_duration * SECOND),
+            short_run,
+            holdoff: (holdoff > 0).then(|| holdoff * SECOND),
+            holdoff_action,
+        })
+    }
+
+    fn holdoff(action: HoldoffAction) -> Guard {
+        guard(0, ShortRunAction::Keep, 5, action)
+    }
+
+    #[test]
+    fn actions_are_parsed() {
+        assert_eq!(ShortRunAction::parse("mark"), Ok(ShortRunAction::Mark));
+        assert_eq!(HoldoffAction::parse("refuse"), Ok(HoldoffAction::Refuse));
+        assert!(ShortRunAction::parse("drop").unwrap_err().contains("expected keep or mark"));
+        assert!(HoldoffAction::parse("queue").unwrap_err().contains("expected delay or refuse"));
+    }
+
+    #[test]
+    fn without_a_policy_everything_happens_now() {
+        let mut guard = Guard::new(Policy::default());
+        let t = Instant::now();
+        assert_eq!(guard.stop(t), Stop::NotRecording);
+        assert_eq!(guard.start(t), Start::Now);
+        guard.started(t);
+        assert_eq!(guard.start(t), Start::AlreadyRecording);
+        assert_eq!(guard.stop(t), Stop::Now { short: false });
+        guard.stopped(t);
+        assert_eq!(guard.start(t), Start::Now);
+        assert!(!guard.due(t));
+        assert_eq!(guard.waiting(t), None);
+    }
+
+    #[test]
+    fn the_first_start_isnt_held_back() {
+        let mut guard = holdoff(HoldoffAction::Refuse);
+        assert_eq!(guard.start(Instant::now()), Start::Now);
+    }
+
+    #[test]
+    fn a_start_within_the_holdoff_waits() {
+        let mut guard = holdoff(HoldoffAction::Delay);
+        let t = Instant::now();
+        guard.started(t);
+        guard.stopped(t + SECOND);
+        assert_eq!(guard.start(t + 3 * SECOND), Start::Delay(3 * SECOND));
+        assert_eq!(guard.waiting(t + 4 * SECOND), Some(2 * SECOND));
+        // Asking again doesn't start the wait over
+        assert_eq!(guard.start(t + 4 * SECOND), Start::AlreadyWaiting(2 * SECOND));
+        assert!(!guard.due(t + 5 * SECOND));
+        assert!(guard.due(t + 6 * SECOND));
+        // The caller starts the run; until then nothing waits
+        assert_eq!(guard.waiting(t + 6 * SECOND), None);
+        assert!(!guard.due(t + 7 * SECOND));
+        guard.started(t + 6 * SECOND);
+        assert_eq!(guard.start(t + 7 * SECOND), Start::AlreadyRecording);
+    }
+
+    #[test]
+    fn a_start_within_the_holdoff_can_be_refused() {
+        let mut guard = holdoff(HoldoffAction::Refuse);
+        let t = Instant::now();
+        guard.stopped(t);
+        assert_eq!(guard.start(t + 2 * SECOND), Start::Refuse(3 * SECOND));
+        assert_eq!(guard.waiting(t + 2 * SECOND), None);
+        assert!(!guard.due(t + 10 * SECOND));
+        assert_eq!(guard.start(t + 5 * SECOND), Start::Now);
+    }
+
+    #[test]
+    fn a_stop_cancels_a_waiting_start() {
+        let mut guard = holdoff(HoldoffAction::Delay);
+        let t = Instant::now();
+        guard.stopped(t);
+        assert_eq!(guard.start(t + SECOND), Start::Delay(4 * SECOND));
+        assert_eq!(guard.stop(t + 2 * SECOND), Stop::Cancel);
+        assert_eq!(guard.waiting(t + 2 * SECOND), None);
+        assert!(!guard.due(t + 10 * SECOND));
+        // The holdoff still counts from the stop of the run, not the cancel
+        assert_eq!(guard.start(t + 3 * SECOND), Start::Delay(2 * SECOND));
+        assert_eq!(guard.stop(t + 3 * SECOND), Stop::Cancel);
+        assert_eq!(guard.start(t + 5 * SECOND), Start::Now);
+        assert_eq!(guard.stop(t + 5 * SECOND), Stop::NotRecording);
+    }
+
+    #[test]
+    fn a_short_run_keeps_recording() {
+        let mut guard = guard(10, ShortRunAction::Keep, 0, HoldoffAction::Delay);
+        let t = Instant::now();
+        guard.started(t);
+        assert_eq!(guard.stop(t + 3 * SECOND), Stop::Keep(3 * SECOND));
+        // Still recording from the same start
+        assert_eq!(guard.start(t + 4 * SECOND), Start::AlreadyRecording);
+        assert_eq!(guard.stop(t + 9 * SECOND), Stop::Keep(9 * SECOND));
+        assert_eq!(guard.stop(t + 10 * SECOND), Stop::Now { short: false });
+    }
+
+    #[test]
+    fn a_short_run_can_be_stopped_and_marked() {
+        let mut guard = guard(10, ShortRunAction::Mark, 0, HoldoffAction::Delay);
+        let t = Instant::now();
+        guard.started(t);
+        assert_eq!(guard.stop(t + 3 * SECOND), Stop::Now { short: true });
+        guard.stopped(t + 3 * SECOND);
+        assert_eq!(guard.stop(t + 4 * SECOND), Stop::NotRecording);
+        guard.started(t + 4 * SECOND);
+        assert_eq!(guard.stop(t + 20 * SECOND), Stop::Now { short: false });
+    }
+
+    #[test]
+    fn both_policies_together() {
+        let mut guard = guard(10, ShortRunAction::Keep, 5, HoldoffAction::Delay);
+        let t = Instant::now();
+        assert_eq!(guard.start(t), Start::Now);
+        guard.started(t);
+        assert_eq!(guard.stop(t + SECOND), Stop::Keep(SECOND));
+        assert_eq!(guard.stop(t + 12 * SECOND), Stop::Now { short: false });
+        guard.stopped(t + 12 * SECOND);
+        assert_eq!(guard.start(t + 13 * SECOND), Start::Delay(4 * SECOND));
+        assert!(guard.due(t + 17 * SECOND));
+        guard.started(t + 17 * SECOND);
+        // The minimum duration counts from the delayed start
+        assert_eq!(guard.stop(t + 20 * SECOND), Stop::Keep(3 * SECOND));
+    }
+
+    #[test]
+    fn stops_the_guard_doesnt_decide_still_end_the_run() {
+        // e.g. a cap or the end of the session, before the minimum duration
+        let mut guard = guard(10, ShortRunAction::Keep, 5, HoldoffAction::Delay);
+        let t = Instant::now();
+        guard.started(t);
+        guard.stopped(t + SECOND);
+        assert_eq!(guard.stop(t + 2 * SECOND), Stop::NotRecording);
+        assert_eq!(guard.start(t + 2 * SECOND), Start::Delay(4 * SECOND));
+    }
+}
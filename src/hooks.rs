@@ -0,0 +1,87 @@
+//! External commands run on run start/stop (`--on-start`, `--on-stop`) and on
+//! serial errors (`--on-error`).
+//!
+//! Hooks run through the shell (`sh -c`, or `cmd /C` on Windows) with these
+//! environment variables:
+//!
+//! | variable       | set for            |
+//! |----------------|--------------------|
+//! | `SL_RUN`       | all hooks          |
+//! | `SL_RUN_ID`    | start, stop hooks  |
+//! | `SL_RUN_NAME`  | start, stop hooks  |
+//! | `SL_OUTPUT`    | all hooks          |
+//! | `SL_TIMESTAMP` | all hooks          |
+//! | `SL_ROW_COUNT` | `--on-stop`        |
+//! | `SL_PORT`      | `--on-error`       |
+//! | `SL_ERROR`     | `--on-error`       |
+//!
+//! Hooks are spawned in the background so a slow command never delays
+//! logging; their exit status is reported when they finish.
+
+use std::{
+    process::{Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Hooks still running, waited on briefly at exit
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    pub on_start: Option<String>,
+    pub on_stop: Option<String>,
+    pub on_error: Option<String>,
+}
+
+/// Spawns `command` in the background with the given environment.
+pub fn run(kind: &'static str, command: &str, env: Vec<(&'static str, String)>) {
+    let mut shell = if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.arg("/C");
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c");
+        c
+    };
+    shell.arg(command).envs(env).stdin(Stdio::null());
+
+    let mut child = match shell.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            esay!("Failed to run {} hook '{}': {}", kind, command, e);
+            return;
+        }
+    };
+    RUNNING.fetch_add(1, Ordering::SeqCst);
+    let command = command.to_string();
+    thread::spawn(move || {
+        match child.wait() {
+            Ok(status) if status.success() => say!("{} hook finished: {}", kind, command),
+            Ok(status) => esay!("Warning: {} hook '{}' exited with {}", kind, command, status),
+            Err(e) => esay!("Warning: Failed to wait for {} hook '{}': {}", kind, command, e),
+        }
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Gives hooks that are still running up to `timeout` to finish; anything
+/// still running afterwards is left behind.
+pub fn wait_for_running(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    if RUNNING.load(Ordering::SeqCst) > 0 {
+        say!("Waiting for running hooks...");
+    }
+    while RUNNING.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            esay!(
+                "Warning: {} hook(s) still running, not waiting any longer",
+                RUNNING.load(Ordering::SeqCst)
+            );
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,20 @@
+//! Library half of the serial logger: analysis code that doesn't touch the
+//! port, and reading back the files it writes, kept here so other tools can
+//! use it.
+
+pub mod ansi;
+pub mod arrow;
+pub mod cobs;
+pub mod encoding;
+pub mod expr;
+pub mod frame;
+#[cfg(feature = "hdf5")]
+pub mod hdf5;
+pub mod logfile;
+pub mod nmea;
+pub mod parquet;
+pub mod parser;
+pub mod probe;
+pub mod raw;
+pub mod runs;
+pub mod schema;
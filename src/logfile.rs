@@ -0,0 +1,666 @@
+//! Reading output files back, for the subcommands that work on existing logs.
+//!
+//! An output file starts with optional metadata (`meta` rows or `# key:
+//! value` comments), then the header row, optionally a `units` row (with
+//! `--units`), then one row per record with its kind in the Type column. Current files have four leading columns (`Type`,
+//! `Timestamp`, `Run/End`, `RunId`); files from before run IDs have three.
+//! Both are read, as are marker kinds this version doesn't know, which come
+//! back as `Row::Marker`.
+//!
+//! What a row is, the Type column, is a `Kind`: the logger writes only the
+//! kinds listed there, always through `Kind::as_str`, and reading maps any
+//! other text to `Kind::Other` so files from newer versions still read.
+//!
+//! `Row` is the record type for tools built on the library: it serializes
+//! with serde (tagged by `type`, e.g. `{"type":"data","timestamp":...}`),
+//! `to_csv_row` writes it the way the logger does and `from_csv_row` reads
+//! it back, and `parser::LineFormat::parse_line` makes a data row from a line
+//! of device output.
+
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+/// Columns before the payload fields; RunId is filled in on start and stop rows.
+pub const LEADING_HEADERS: [&str; 4] = ["Type", "Timestamp", "Run/End", "RunId"];
+
+/// Parses a Timestamp column value (`YYYY-MM-DD HH:MM:SS`, optionally with
+/// fractional seconds such as `.250`), as local time. RFC 3339 times
+/// (`2025-01-14T10:21:07.250+01:00`) are read too and converted to local
+/// time, and a `T` in place of the space is accepted without an offset.
+pub fn parse_timestamp(text: &str) -> Option<chrono::NaiveDateTime> {
+    let text = text.trim();
+    chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f")
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f"))
+        .ok()
+        .or_else(|| {
+            chrono::DateTime::parse_from_rfc3339(text)
+                .ok()
+                .map(|time| time.with_timezone(&chrono::Local).naive_local())
+        })
+}
+
+/// Milliseconds since 1970 in UTC of a Timestamp column value, which holds
+/// the local time; in the hour repeated when clocks go back, the first of
+/// the two. None if it is empty or doesn't parse.
+pub fn utc_millis(text: &str) -> Option<i64> {
+    use chrono::TimeZone;
+
+    let local = parse_timestamp(text)?;
+    chrono::Local.from_local_datetime(&local).earliest().map(|time| time.timestamp_millis())
+}
+
+/// The Type column of a row.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Kind {
+    /// A metadata entry before the header (`--meta-comments` writes comments
+    /// instead)
+    Meta,
+    /// The unit of each column, right after the header (`--units`)
+    Units,
+    Data,
+    /// A data row from before the start, with `--prebuffer`
+    Pre,
+    Start,
+    Stop,
+    /// A run going on across a `--rotate-align` switch: the last row of the
+    /// file it leaves and the first of the one it goes on in
+    Continued,
+    /// The `pause` command: data rows of the run are left out until `resume`
+    Pause,
+    Resume,
+    /// The `mark` command, with the operator's text
+    Mark,
+    /// Data arrived after a longer pause than `--gap-marker`
+    Gap,
+    /// `baud` or `port` changed the connection
+    Reconfig,
+    /// Reading switched to a backup port
+    Failover,
+    /// The port was lost and could be read again
+    Reconnect,
+    /// A line longer than `--max-line-length` was dropped
+    Overflow,
+    /// A serial break was received
+    Break,
+    /// Modem status lines changed (`--log-modem-lines`)
+    Modem,
+    /// The logger is alive while no data arrives (`--heartbeat`)
+    Heartbeat,
+    /// A run note (`--prompt-notes`)
+    Note,
+    /// An operator command (`--audit`)
+    Cmd,
+    /// An `--alarm` condition became true
+    Alarm,
+    /// Writing failed for a while; the rows after it were written late
+    Outage,
+    /// The host clock measured against GPS (`--time-source`)
+    ClockOffset,
+    /// The system clock was stepped between two data rows (`--clock-step`)
+    ClockStep,
+    /// Per-column statistics of a run, before its stop row (`--summary-rows`)
+    SummaryCount,
+    SummaryMin,
+    SummaryMax,
+    SummaryMean,
+    SummaryStddev,
+    /// Anything else, e.g. from a newer version
+    Other(String),
+}
+
+impl Kind {
+    /// Every kind the logger writes, in the order of the enum.
+    pub const KNOWN: [Kind; 29] = [
+        Kind::Meta,
+        Kind::Units,
+        Kind::Data,
+        Kind::Pre,
+        Kind::Start,
+        Kind::Stop,
+        Kind::Continued,
+        Kind::Pause,
+        Kind::Resume,
+        Kind::Mark,
+        Kind::Gap,
+        Kind::Reconfig,
+        Kind::Failover,
+        Kind::Reconnect,
+        Kind::Overflow,
+        Kind::Break,
+        Kind::Modem,
+        Kind::Heartbeat,
+        Kind::Note,
+        Kind::Cmd,
+        Kind::Alarm,
+        Kind::Outage,
+        Kind::ClockOffset,
+        Kind::ClockStep,
+        Kind::SummaryCount,
+        Kind::SummaryMin,
+        Kind::SummaryMax,
+        Kind::SummaryMean,
+        Kind::SummaryStddev,
+    ];
+
+    /// The text written in the Type column.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Kind::Meta => "meta",
+            Kind::Units => "units",
+            Kind::Data => "data",
+            Kind::Pre => "pre",
+            Kind::Start => "start",
+            Kind::Stop => "stop",
+            Kind::Continued => "continued",
+            Kind::Pause => "pause",
+            Kind::Resume => "resume",
+            Kind::Mark => "mark",
+            Kind::Gap => "gap",
+            Kind::Reconfig => "reconfig",
+            Kind::Failover => "failover",
+            Kind::Reconnect => "reconnect",
+            Kind::Overflow => "overflow",
+            Kind::Break => "break",
+            Kind::Modem => "modem",
+            Kind::Heartbeat => "heartbeat",
+            Kind::Note => "note",
+            Kind::Cmd => "cmd",
+            Kind::Alarm => "alarm",
+            Kind::Outage => "outage",
+            Kind::ClockOffset => "clockoffset",
+            Kind::ClockStep => "clockstep",
+            Kind::SummaryCount => "summary_count",
+            Kind::SummaryMin => "summary_min",
+            Kind::SummaryMax => "summary_max",
+            Kind::SummaryMean => "summary_mean",
+            Kind::SummaryStddev => "summary_stddev",
+            Kind::Other(text) => text,
+        }
+    }
+
+    /// Reads a Type column; text that isn't one of `KNOWN` is `Other`.
+    pub fn parse(text: &str) -> Kind {
+        Kind::KNOWN
+            .into_iter()
+            .find(|kind| kind.as_str() == text)
+            .unwrap_or_else(|| Kind::Other(text.to_string()))
+    }
+
+    /// Whether rows of this kind carry the payload: data rows and rows from
+    /// the pre-start buffer.
+    pub fn is_data(&self) -> bool {
+        matches!(self, Kind::Data | Kind::Pre)
+    }
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<String> for Kind {
+    fn from(text: String) -> Kind {
+        Kind::parse(&text)
+    }
+}
+
+impl From<Kind> for String {
+    fn from(kind: Kind) -> String {
+        kind.as_str().to_string()
+    }
+}
+
+/// One row of an output file, by its Type column.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Row {
+    Meta {
+        key: String,
+        value: String,
+    },
+    Start {
+        timestamp: String,
+        // From `run N` in the Run/End column; None if it says something else
+        run: Option<i64>,
+        // Empty in files from before run IDs
+        run_id: String,
+    },
+    Stop {
+        timestamp: String,
+        // `end of run`, `short run` (--short-run-action mark), or `recovered`
+        // for stops added by `repair`
+        note: String,
+        run_id: String,
+    },
+    // A run carried over from one file to the next, as the last row of the
+    // one and the first of the other
+    Continued {
+        timestamp: String,
+        run: Option<i64>,
+        run_id: String,
+    },
+    // The payload and constant columns, one value per header column
+    Data {
+        timestamp: String,
+        values: Vec<String>,
+    },
+    // reconfig, break, modem, failover and any other kind
+    Marker {
+        kind: Kind,
+        timestamp: String,
+        text: String,
+        values: Vec<String>,
+    },
+}
+
+impl Row {
+    /// Reads a row that follows the header from its cells. `leading` is the
+    /// number of columns before the values: 4, or 3 in files from before run
+    /// IDs, whose start and stop rows get an empty run ID.
+    pub fn from_csv_row(cells: &[&str], leading: usize) -> Result<Row, String> {
+        let field = |i: usize| cells.get(i).copied().unwrap_or("").to_string();
+        let run_id = if leading < LEADING_HEADERS.len() { String::new() } else { field(3) };
+        let values: Vec<String> = cells.iter().skip(leading).map(|cell| cell.to_string()).collect();
+        if field(0).is_empty() {
+            return Err("row without a type".to_string());
+        }
+        Ok(match Kind::parse(&field(0)) {
+            Kind::Meta => Row::Meta {
+                key: field(1),
+                value: field(2),
+            },
+            Kind::Start => Row::Start {
+                timestamp: field(1),
+                run: field(2).strip_prefix("run ").and_then(|n| n.trim().parse().ok()),
+                run_id,
+            },
+            Kind::Stop => Row::Stop {
+                timestamp: field(1),
+                note: field(2),
+                run_id,
+            },
+            Kind::Continued => Row::Continued {
+                timestamp: field(1),
+                run: field(2).strip_prefix("run ").and_then(|n| n.trim().parse().ok()),
+                run_id,
+            },
+            Kind::Data => Row::Data {
+                timestamp: field(1),
+                values,
+            },
+            kind => Row::Marker {
+                kind,
+                timestamp: field(1),
+                text: field(2),
+                values,
+            },
+        })
+    }
+
+    /// The cells of the row as the logger writes it, with the four leading
+    /// columns and blanks up to `columns` values (the header's columns after
+    /// the leading ones). Reading them with `from_csv_row` gives the row back.
+    pub fn to_csv_row(&self, columns: usize) -> Vec<String> {
+        let mut cells: Vec<String> = match self {
+            Row::Meta { key, value } => vec![Kind::Meta.to_string(), key.clone(), value.clone()],
+            Row::Start { timestamp, run, run_id } => vec![
+                Kind::Start.to_string(),
+                timestamp.clone(),
+                run.map_or(String::new(), |run| format!("run {}", run)),
+                run_id.clone(),
+            ],
+            Row::Stop { timestamp, note, run_id } => {
+                vec![Kind::Stop.to_string(), timestamp.clone(), note.clone(), run_id.clone()]
+            }
+            Row::Continued { timestamp, run, run_id } => vec![
+                Kind::Continued.to_string(),
+                timestamp.clone(),
+                run.map_or(String::new(), |run| format!("run {}", run)),
+                run_id.clone(),
+            ],
+            Row::Data { timestamp, values } => {
+                let mut cells = vec![Kind::Data.to_string(), timestamp.clone(), String::new(), String::new()];
+                cells.extend(values.iter().cloned());
+                cells
+            }
+            Row::Marker {
+                kind,
+                timestamp,
+                text,
+                values,
+            } => {
+                let mut cells = vec![kind.to_string(), timestamp.clone(), text.clone(), String::new()];
+                cells.extend(values.iter().cloned());
+                cells
+            }
+        };
+        let width = LEADING_HEADERS.len() + columns;
+        if cells.len() < width {
+            cells.resize(width, String::new());
+        }
+        cells
+    }
+}
+
+/// A row together with where it came from.
+#[derive(Debug, Clone)]
+pub struct Line {
+    // 1-based line number in the file
+    pub number: u64,
+    pub row: Row,
+    // The row as it was read, for tools that copy rows unchanged
+    pub record: csv::StringRecord,
+}
+
+/// Reads the rows of an output file in order.
+pub struct LogReader<R> {
+    reader: csv::Reader<R>,
+    // Header row once it has been read, and the units row right after it
+    header: Option<csv::StringRecord>,
+    units: Option<csv::StringRecord>,
+    // No row has been read after the header yet
+    after_header: bool,
+    // Columns before the payload: 4, or 3 in files without RunId
+    leading: usize,
+}
+
+impl<R: Read> LogReader<R> {
+    pub fn new(input: R) -> LogReader<R> {
+        LogReader {
+            reader: csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(input),
+            header: None,
+            units: None,
+            after_header: false,
+            leading: LEADING_HEADERS.len(),
+        }
+    }
+
+    /// Names of the columns after the leading ones (payload fields, then any
+    /// constant columns); empty until the header has been read.
+    pub fn columns(&self) -> Vec<String> {
+        self.header
+            .as_ref()
+            .map(|header| header.iter().skip(self.leading).map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    /// The header row, once it has been read.
+    pub fn header(&self) -> Option<&csv::StringRecord> {
+        self.header.as_ref()
+    }
+
+    /// The units row, if the file has one; it is read with the header and
+    /// isn't returned as a row.
+    pub fn units(&self) -> Option<&csv::StringRecord> {
+        self.units.as_ref()
+    }
+
+    /// Unit of each column that has one, as column name and unit.
+    pub fn column_units(&self) -> Vec<(String, String)> {
+        let Some(units) = &self.units else { return Vec::new() };
+        self.columns()
+            .into_iter()
+            .zip(units.iter().skip(self.leading))
+            .filter(|(_, unit)| !unit.is_empty())
+            .map(|(column, unit)| (column, unit.to_string()))
+            .collect()
+    }
+
+    /// Whether the file is from before run IDs (three leading columns).
+    pub fn legacy(&self) -> bool {
+        self.leading < LEADING_HEADERS.len()
+    }
+
+    // The next row, or an error naming the line of a row that can't be read
+    fn read_line(&mut self) -> Option<Result<Line, String>> {
+        loop {
+            let mut record = csv::StringRecord::new();
+            let number = self.reader.position().line();
+            match self.reader.read_record(&mut record) {
+                Ok(false) => return None,
+                Ok(true) => {}
+                Err(e) => return Some(Err(format!("Line {}: {}", number, e))),
+            }
+            let number = record.position().map_or(number, |position| position.line());
+            let field = |i: usize| record.get(i).unwrap_or("").to_string();
+            let kind = record.get(0).unwrap_or("");
+            let row = if self.header.is_none() {
+                if kind == "Type" {
+                    self.leading = if record.get(3) == Some(LEADING_HEADERS[3]) { 4 } else { 3 };
+                    self.header = Some(record);
+                    self.after_header = true;
+                    continue;
+                }
+                if kind == Kind::Meta.as_str() {
+                    Row::Meta {
+                        key: field(1),
+                        value: field(2),
+                    }
+                } else if kind.starts_with('#') {
+                    // A comment split at its commas; put back together
+                    let text = record.iter().collect::<Vec<_>>().join(",");
+                    match text.trim_start_matches('#').split_once(':') {
+                        Some((key, value)) => Row::Meta {
+                            key: key.trim().to_string(),
+                            value: value.trim().to_string(),
+                        },
+                        None => continue,
+                    }
+                } else {
+                    return Some(Err(format!("Line {}: '{}' row before the header row", number, kind)));
+                }
+            } else if std::mem::take(&mut self.after_header) && kind == Kind::Units.as_str() {
+                self.units = Some(record);
+                continue;
+            } else {
+                let cells: Vec<&str> = record.iter().collect();
+                match Row::from_csv_row(&cells, self.leading) {
+                    // Meta rows only come before the header
+                    Ok(Row::Meta { .. }) => Row::Marker {
+                        kind: Kind::Meta,
+                        timestamp: field(1),
+                        text: field(2),
+                        values: record.iter().skip(self.leading).map(str::to_string).collect(),
+                    },
+                    Ok(Row::Data { timestamp, values }) => {
+                        let width = self.header.as_ref().map_or(0, |header| header.len() - self.leading);
+                        if values.len() != width {
+                            return Some(Err(format!(
+                                "Line {}: data row has {} values, the header has {} columns",
+                                number,
+                                values.len(),
+                                width
+                            )));
+                        }
+                        Row::Data { timestamp, values }
+                    }
+                    Ok(row) => row,
+                    Err(e) => return Some(Err(format!("Line {}: {}", number, e))),
+                }
+            };
+            return Some(Ok(Line { number, row, record }));
+        }
+    }
+}
+
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = Result<Line, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_line()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_known_kind_round_trips() {
+        for kind in Kind::KNOWN {
+            assert_eq!(Kind::parse(kind.as_str()), kind);
+            assert_eq!(Kind::from(String::from(kind.clone())), kind);
+            let json = serde_json::to_string(&kind).unwrap();
+            assert_eq!(json, format!("\"{}\"", kind));
+            assert_eq!(serde_json::from_str::<Kind>(&json).unwrap(), kind);
+        }
+    }
+
+    #[test]
+    fn known_kinds_have_distinct_names() {
+        let mut names: Vec<&str> = Kind::KNOWN.iter().map(Kind::as_str).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), Kind::KNOWN.len());
+    }
+
+    #[test]
+    fn the_documented_kinds_are_known() {
+        for name in [
+            "data", "start", "stop", "continued", "pause", "resume", "mark", "gap", "reconnect", "failover", "alarm", "overflow",
+            "note", "meta", "units", "heartbeat", "cmd", "summary_count", "summary_min", "summary_max",
+            "summary_mean", "summary_stddev",
+        ] {
+            assert!(!matches!(Kind::parse(name), Kind::Other(_)), "{} should be known", name);
+        }
+    }
+
+    #[test]
+    fn unknown_kinds_are_kept_as_other() {
+        for name in ["calibration", "Data", "summary_median", ""] {
+            let kind = Kind::parse(name);
+            assert_eq!(kind, Kind::Other(name.to_string()));
+            assert_eq!(kind.as_str(), name);
+            assert_eq!(serde_json::from_str::<Kind>(&format!("\"{}\"", name)).unwrap(), kind);
+        }
+    }
+
+    // A row of each variant, with one value per column of a three-column file
+    fn rows() -> Vec<Row> {
+        let timestamp = "2025-01-14 10:21:07".to_string();
+        let values = |values: [&str; 3]| values.iter().map(|value| value.to_string()).collect::<Vec<String>>();
+        vec![
+            Row::Meta {
+                key: "port".to_string(),
+                value: "/dev/ttyUSB0".to_string(),
+            },
+            Row::Start {
+                timestamp: timestamp.clone(),
+                run: Some(3),
+                run_id: "9ab732be-82f8-4bf5-b503-ab0194ed32cc".to_string(),
+            },
+            Row::Start {
+                timestamp: timestamp.clone(),
+                run: None,
+                run_id: String::new(),
+            },
+            Row::Stop {
+                timestamp: timestamp.clone(),
+                note: "short run".to_string(),
+                run_id: "9ab732be-82f8-4bf5-b503-ab0194ed32cc".to_string(),
+            },
+            Row::Continued {
+                timestamp: timestamp.clone(),
+                run: Some(3),
+                run_id: "9ab732be-82f8-4bf5-b503-ab0194ed32cc".to_string(),
+            },
+            Row::Data {
+                timestamp: timestamp.clone(),
+                values: values(["12", "-9.81", ""]),
+            },
+            // Cells the CSV has to quote
+            Row::Data {
+                timestamp: timestamp.clone(),
+                values: values(["1,5", "say \"hi\"", "two\nlines"]),
+            },
+            Row::Marker {
+                kind: Kind::Alarm,
+                timestamp: timestamp.clone(),
+                text: "impact".to_string(),
+                values: values(["12", "-9.81", "0.1"]),
+            },
+            Row::Marker {
+                kind: Kind::Pre,
+                timestamp: timestamp.clone(),
+                text: String::new(),
+                values: values(["1", "2", "3"]),
+            },
+            Row::Marker {
+                kind: Kind::Other("calibration".to_string()),
+                timestamp,
+                text: "zero offset".to_string(),
+                values: values(["", "", ""]),
+            },
+        ]
+    }
+
+    #[test]
+    fn rows_round_trip_through_their_cells() {
+        for row in rows() {
+            let cells = row.to_csv_row(3);
+            assert_eq!(cells.len(), LEADING_HEADERS.len() + 3, "{:?}", row);
+            let cells: Vec<&str> = cells.iter().map(String::as_str).collect();
+            assert_eq!(Row::from_csv_row(&cells, LEADING_HEADERS.len()).unwrap(), row);
+        }
+    }
+
+    #[test]
+    fn rows_round_trip_through_a_file() {
+        // Metadata comes before the header, as the logger writes it
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        let (meta, others): (Vec<Row>, Vec<Row>) = rows().into_iter().partition(|row| matches!(row, Row::Meta { .. }));
+        for row in &meta {
+            writer.write_record(row.to_csv_row(3)).unwrap();
+        }
+        writer.write_record(["Type", "Timestamp", "Run/End", "RunId", "x", "y", "z"]).unwrap();
+        for row in &others {
+            writer.write_record(row.to_csv_row(3)).unwrap();
+        }
+        let file = writer.into_inner().unwrap();
+        let read: Vec<Row> = LogReader::new(file.as_slice()).map(|line| line.unwrap().row).collect();
+        assert_eq!(read, rows());
+    }
+
+    #[test]
+    fn rows_round_trip_through_json() {
+        for row in rows() {
+            let json = serde_json::to_string(&row).unwrap();
+            assert_eq!(serde_json::from_str::<Row>(&json).unwrap(), row, "{}", json);
+        }
+        let json = serde_json::to_value(&rows()[1]).unwrap();
+        assert_eq!(json["type"], "start");
+        assert_eq!(json["run"], 3);
+    }
+
+    #[test]
+    fn short_rows_are_padded_with_blanks() {
+        let row = Row::Data {
+            timestamp: "2025-01-14 10:21:07".to_string(),
+            values: vec!["1".to_string()],
+        };
+        assert_eq!(row.to_csv_row(3), ["data", "2025-01-14 10:21:07", "", "", "1", "", ""]);
+        assert_eq!(row.to_csv_row(0).len(), 5);
+    }
+
+    #[test]
+    fn files_from_before_run_ids_read_with_blank_ones() {
+        let row = Row::from_csv_row(&["start", "2025-01-14 10:21:07", "run 3", "12"], 3).unwrap();
+        assert_eq!(
+            row,
+            Row::Start {
+                timestamp: "2025-01-14 10:21:07".to_string(),
+                run: Some(3),
+                run_id: String::new(),
+            }
+        );
+        assert!(Row::from_csv_row(&["", "2025-01-14 10:21:07"], 4).is_err());
+    }
+
+    #[test]
+    fn unknown_kinds_read_as_markers() {
+        let row = Row::from_csv_row(&["calibration", "2025-01-14 10:21:07", "zero offset", ""], 4).unwrap();
+        assert!(matches!(row, Row::Marker { kind: Kind::Other(name), .. } if name == "calibration"));
+    }
+}
@@ -0,0 +1,206 @@
+//! Log file of operational messages, for `--log-file`.
+//!
+//! Messages are `tracing` events. Everything printed through `say!` and
+//! `esay!` (port switches, write failures, hook results, warnings) becomes
+//! one, at a level from how the line begins: `ERROR`, `Error` or `Failed` is
+//! an error, `Warning` or `Alarm` a warning, everything else info. Warnings
+//! about single input lines that aren't printed are logged by the serial
+//! thread with `warn!` itself, which formats nothing while warnings are off.
+//!
+//! With `--log-file` a subscriber writes the events, timestamped and with
+//! their level, through `tracing-appender` on a background thread. A new file
+//! is started every day at midnight UTC, with the date before the extension:
+//! `serial_logger.2025-01-14.log` for `--log-file serial_logger.log`. The
+//! filter is `--log-level`, or `RUST_LOG` when that is set. Without
+//! `--log-file` there is no subscriber, and an event costs a check of a
+//! cached flag.
+//!
+//! So that a port that keeps failing or a stream of garbage doesn't fill the
+//! disk, a message repeated back to back is logged once, followed by a count
+//! when a different one arrives or a minute has passed, and past
+//! `LINE_WARNINGS_PER_SECOND` warnings about input lines in a second the rest
+//! are only counted.
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::Level;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::time::ChronoLocal;
+
+// How long repeats of a message are collapsed before their count is logged
+const REPEAT_WINDOW: Duration = Duration::from_secs(60);
+// Warnings about input lines logged in a second before the rest are counted
+const LINE_WARNINGS_PER_SECOND: u32 = 20;
+
+static OPEN: AtomicBool = AtomicBool::new(false);
+// Keeps the writer thread going; dropping it writes out what is queued
+static GUARD: Mutex<Option<WorkerGuard>> = Mutex::new(None);
+static REPEATS: Mutex<Option<Repeats>> = Mutex::new(None);
+static LINE_WARNINGS: Mutex<Option<Window>> = Mutex::new(None);
+
+/// Parses a `--log-level` argument.
+pub fn parse_level(text: &str) -> Result<Level, String> {
+    match text {
+        "error" => Ok(Level::ERROR),
+        "warn" => Ok(Level::WARN),
+        "info" => Ok(Level::INFO),
+        _ => Err(format!("Invalid --log-level '{}': expected error, warn or info", text)),
+    }
+}
+
+/// Level of a warning or error line, from how it begins.
+pub fn level_of_warning(text: &str) -> Level {
+    if ["ERROR", "Error", "Failed"].iter().any(|prefix| text.starts_with(prefix)) {
+        Level::ERROR
+    } else if ["Warning", "Alarm"].iter().any(|prefix| text.starts_with(prefix)) {
+        Level::WARN
+    } else {
+        Level::INFO
+    }
+}
+
+/// Starts writing events to the log file at `path`; those less severe than
+/// `max_level` are left out unless `RUST_LOG` says otherwise.
+pub fn open(path: &str, max_level: Level) -> Result<(), String> {
+    let path = Path::new(path);
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("Invalid log file name {}", path.display()))?;
+    let mut builder = RollingFileAppender::builder().rotation(Rotation::DAILY);
+    builder = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => builder.filename_prefix(stem).filename_suffix(extension),
+        _ => builder.filename_prefix(name),
+    };
+    let appender = builder
+        .build(dir)
+        .map_err(|e| format!("Failed to open log file {}: {}", path.display(), e))?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::from_level(max_level).into())
+        .from_env_lossy();
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_target(false)
+        .with_timer(ChronoLocal::new("%Y-%m-%d %H:%M:%S%.3f".to_string()))
+        .try_init()
+        .map_err(|e| format!("Failed to start the log file {}: {}", path.display(), e))?;
+    *GUARD.lock().unwrap() = Some(guard);
+    OPEN.store(true, Ordering::Release);
+    // Fatal errors end the program through a panic; they belong in the log
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let message = info
+            .payload()
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| info.payload().downcast_ref::<&str>().copied())
+            .unwrap_or("panic");
+        tracing::error!("{}", message);
+        close();
+        previous(info);
+    }));
+    Ok(())
+}
+
+/// Logs a line printed on the console, unless it repeats the last one.
+pub fn message(level: Level, text: &str) {
+    if !OPEN.load(Ordering::Acquire) {
+        return;
+    }
+    let now = Instant::now();
+    let mut repeats = REPEATS.lock().unwrap();
+    let repeats = repeats.get_or_insert_with(|| Repeats {
+        last: String::new(),
+        count: 0,
+        since: now,
+    });
+    if text == repeats.last && now - repeats.since < REPEAT_WINDOW {
+        repeats.count += 1;
+        return;
+    }
+    repeats.flush();
+    log(level, text);
+    repeats.last = text.to_string();
+    repeats.since = now;
+}
+
+/// Whether to log a warning about a single input line: the first
+/// `LINE_WARNINGS_PER_SECOND` in each second are, and the count of the rest
+/// is logged before the next one that is.
+pub fn admit_line_warning() -> bool {
+    let now = Instant::now();
+    let mut window = LINE_WARNINGS.lock().unwrap();
+    let window = window.get_or_insert(Window {
+        start: now,
+        logged: 0,
+        held_back: 0,
+    });
+    if now - window.start >= Duration::from_secs(1) {
+        window.start = now;
+        window.logged = 0;
+    }
+    if window.logged >= LINE_WARNINGS_PER_SECOND {
+        window.held_back += 1;
+        return false;
+    }
+    window.logged += 1;
+    if window.held_back > 0 {
+        tracing::warn!("({} more warnings about input lines not logged)", window.held_back);
+        window.held_back = 0;
+    }
+    true
+}
+
+/// Logs any pending repeat count and writes out what is queued; called on
+/// exit.
+pub fn close() {
+    if let Some(repeats) = REPEATS.lock().unwrap().as_mut() {
+        repeats.flush();
+    }
+    OPEN.store(false, Ordering::Release);
+    drop(GUARD.lock().unwrap().take());
+}
+
+fn log(level: Level, text: &str) {
+    match level {
+        Level::ERROR => tracing::error!("{}", text),
+        Level::WARN => tracing::warn!("{}", text),
+        _ => tracing::info!("{}", text),
+    }
+}
+
+// The last message logged, and how often it has been repeated since
+struct Repeats {
+    last: String,
+    count: u64,
+    since: Instant,
+}
+
+impl Repeats {
+    fn flush(&mut self) {
+        if self.count > 0 {
+            tracing::info!("(last message repeated {} times)", self.count);
+            self.count = 0;
+        }
+        self.last.clear();
+    }
+}
+
+// The current second of warnings about input lines
+struct Window {
+    start: Instant,
+    logged: u32,
+    held_back: u64,
+}
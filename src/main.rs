@@ -1,31 +1,801 @@
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, ArgMatches, Command};
 use std::{
-    fs::File,
-    io::{BufReader, BufRead},
-    path::PathBuf,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-        Mutex,
-    },
-    thread,
-    time::Duration,
+    io::IsTerminal,
+    sync::{mpsc, Arc, Mutex},
 };
-use csv::Writer;
-use chrono::Local;
+
+use caps::Caps;
+use devcmd::{DeviceCommand, DeviceCommands};
+use events::Event;
+use failon::FailOn;
+use hooks::Hooks;
+use meta::{MetaStyle, Metadata};
+use collect_acc_data::arrow;
+use collect_acc_data::frame::FrameSpec;
+use collect_acc_data::encoding::Encoding;
+use collect_acc_data::parser::{self, Framing, LineFormat, Parsed};
+use collect_acc_data::schema::{ColumnType, FloatFormat, NonFinite, Schema};
+use repl::{Input, Prompt};
+use serial::{InputOptions, PortList};
+use trigger::HwTrigger;
+use manifest::Manifest;
+use retention::Retention;
+use session::{OutputSettings, Session, Source};
+use sink::{Columnar, ColumnarOutput, CsvDialect, SyncPolicy};
+
+// Human-oriented console output; goes to stderr when the JSON event stream
+// owns stdout, and above the prompt while it is waiting for input
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::console::say(&format!($($arg)*))
+    };
+}
+
+// Warnings and errors, on stderr and above the prompt
+macro_rules! esay {
+    ($($arg:tt)*) => {
+        $crate::console::esay(&format!($($arg)*))
+    };
+}
+
+mod alarm;
+mod alert;
+mod analyze;
+mod benchmark;
+mod caps;
+mod change;
+mod clockstep;
+mod commands;
+mod compress;
+mod config;
+mod console;
+mod control;
+mod convert;
+mod dedup;
+mod devcmd;
+mod disk;
+mod events;
+mod extract;
+mod failon;
+mod grpc;
+mod heartbeat;
+mod hexdump;
+mod holdoff;
+mod hooks;
+mod logging;
+mod manifest;
+mod merge;
+mod memory;
+mod meta;
+mod metrics;
+mod mirror;
+mod modem;
+mod multiport;
+mod noise;
+mod notify;
+mod outage;
+mod peek;
+mod prebuffer;
+mod preview;
+mod profile;
+mod rawlog;
+mod rejects;
+mod retention;
+mod rotate;
+mod repair;
+#[cfg(feature = "multiport")]
+mod reorder;
+mod repl;
+mod reparse;
+mod serial;
+mod service;
+mod session;
+mod settings;
+mod setup;
+mod sidecar;
+mod sink;
+mod tail;
+mod tap;
+mod split;
+mod state;
+mod stats;
+mod statusline;
+mod strict;
+mod summary;
+mod template;
+mod timesource;
+mod trigger;
+mod units;
+mod upload;
+mod validate;
+mod xlsx;
+#[cfg(feature = "zmq")]
+mod zmq;
 
 fn main() {
+    // --max-duration counts from here
+    let program_started = std::time::Instant::now();
     // Parse command-line arguments using Clap
-    let matches = Command::new("Serial Logger")
+    let args = config::expand_args(std::env::args_os().collect()).unwrap_or_else(|e| panic!("{}", e));
+    let matches = cli().get_matches_from(&args);
+
+    match matches.subcommand() {
+        Some(("reparse", sub)) => return reparse(sub),
+        Some(("repair", sub)) => return repair(sub),
+        Some(("convert", sub)) => return convert(sub),
+        Some(("analyze", sub)) => return analyze(sub),
+        Some(("split", sub)) => return split(sub),
+        Some(("extract", sub)) => return extract(sub),
+        Some(("merge", sub)) => return merge(sub),
+        Some(("verify", sub)) => return verify(sub),
+        Some(("setup", sub)) => return setup(sub),
+        Some(("completions", sub)) => return completions(sub),
+        Some(("manpage", _)) => return manpage(),
+        Some(("service", sub)) => return service(sub),
+        _ => {}
+    }
+    if matches.get_flag("list-profiles") {
+        return profile::list().unwrap_or_else(|e| panic!("{}", e));
+    }
+    if matches.get_flag("benchmark") {
+        return benchmark(&matches);
+    }
+    check_options(&matches, &args);
+    if matches.get_flag("print-config") {
+        return print!("{}", config::render(&cli(), &matches, &port_blocks(&matches)));
+    }
+    if let Some(dir) = matches.get_one::<String>("service").cloned() {
+        let body = Box::new(move || logger(&matches, &args, program_started));
+        return service::run(std::path::Path::new(&dir), body).unwrap_or_else(|e| panic!("{}", e));
+    }
+    logger(&matches, &args, program_started)
+}
+
+// The [[port]] blocks of the --config file, if any
+fn port_blocks(matches: &ArgMatches) -> Vec<config::Entries> {
+    match matches.get_one::<String>("config") {
+        Some(path) => config::load(std::path::Path::new(path)).unwrap_or_else(|e| panic!("{}", e)).ports,
+        None => Vec::new(),
+    }
+}
+
+// The label and options of the port of each [[port]] block: those of the
+// command line (`args` as expanded, with the config's) with the block's
+// over them
+fn block_options(matches: &ArgMatches, args: &[std::ffi::OsString]) -> Vec<(String, ArgMatches)> {
+    port_blocks(matches)
+        .iter()
+        .map(|block| {
+            let label = config::block_label(block);
+            let matches = cli()
+                .try_get_matches_from(config::block_args(args, block))
+                .unwrap_or_else(|e| panic!("The [[port]] block of {} doesn't fit: {}", label, e));
+            (label, matches)
+        })
+        .collect()
+}
+
+// Reports every problem with the combination of options and exits, before
+// anything is opened
+fn check_options(matches: &ArgMatches, args: &[std::ffi::OsString]) {
+    let mut problems = validate::check(matches);
+    if problems.is_empty() {
+        problems = port_problems(matches, args);
+    }
+    if problems.is_empty() {
+        return;
+    }
+    esay!("ERROR: The options don't work together:");
+    for problem in &problems {
+        esay!("  {}", problem);
+    }
+    std::process::exit(EXIT_INVALID_OPTIONS);
+}
+
+// Problems with the [[port]] blocks of the config: with the options of a
+// block, or with columns of two ports that can't share the file
+fn port_problems(matches: &ArgMatches, args: &[std::ffi::OsString]) -> Vec<String> {
+    let options = block_options(matches, args);
+    let problems: Vec<String> = options
+        .iter()
+        .flat_map(|(label, matches)| validate::check(matches).into_iter().map(move |problem| format!("{}: {}", label, problem)))
+        .collect();
+    if !problems.is_empty() {
+        return problems;
+    }
+    let formats: Vec<LineFormat> = options.iter().map(|(_, matches)| line_format(matches, None)).collect();
+    let labelled: Vec<(String, &LineFormat)> = options.iter().map(|(label, _)| label.clone()).zip(&formats).collect();
+    multiport::file_columns(&labelled).err().into_iter().collect()
+}
+
+// Prints the worst case of the in-memory buffers and, with --max-memory,
+// refuses a configuration whose buffers could hold more
+fn check_memory(matches: &ArgMatches, settings: &OutputSettings) {
+    let plan = memory::plan(settings);
+    let limit = matches
+        .get_one::<String>("max-memory")
+        .map(|text| units::parse_size(text).unwrap_or_else(|e| panic!("--max-memory: {}", e)));
+    if let Some(limit) = limit.filter(|limit| memory::total(&plan) as u64 > *limit) {
+        esay!(
+            "ERROR: The buffers could hold up to {}, more than --max-memory {}:",
+            units::format_size(memory::total(&plan) as u64),
+            units::format_size(limit)
+        );
+        esay!("  {}", memory::describe(&plan));
+        esay!("  Lower --prebuffer, --xlsx-max-rows, --outage-buffer or --tail-buffer, or raise --max-memory.");
+        std::process::exit(EXIT_INVALID_OPTIONS);
+    }
+    memory::reserve(&plan, limit.is_some());
+    // The worst case matters when there is a limit; otherwise only on request
+    if !plan.is_empty() && (limit.is_some() || settings::verbose() >= 2) {
+        say!("Buffers: {}", memory::describe(&plan));
+    }
+}
+
+// Logging itself, with commands from the prompt or, as a service, from the
+// other control channels only
+fn logger(matches: &ArgMatches, args: &[std::ffi::OsString], program_started: std::time::Instant) {
+    // Retrieve command-line arguments
+    // The first port is the primary; the others are backups for failover.
+    // Given more than once, --port names ports that are all read at once.
+    let given = matches.get_many::<String>("port").expect("Port is required");
+    let several = given.len() > 1;
+    let ports: Vec<String> = given
+        .flat_map(|ports| ports.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    let port_name = &if several {
+        ports.join(",")
+    } else {
+        ports.first().expect("Failed to parse --port: no port given").clone()
+    };
+    let baud_rate: u32 = matches
+        .get_one::<String>("baud")
+        .expect("Baud rate has a default value")
+        .parse()
+        .expect("Failed to parse baud rate");
+    let output_path = matches.get_one::<String>("output").expect("Output path has a default value");
+    let (user_meta, id_columns) = meta_and_ids(matches);
+    let meta_style = meta_style(matches);
+    let sync = SyncPolicy::parse(matches.get_one::<String>("sync").expect("Sync has a default value"))
+        .unwrap_or_else(|e| panic!("{}", e));
+    let dialect = csv_dialect(matches);
+    let min_free = units::parse_size(matches.get_one::<String>("min-free").expect("Min free has a default value"))
+        .unwrap_or_else(|e| panic!("{}", e));
+    let dry_run = matches.get_flag("dry-run");
+    let retention = matches
+        .get_many::<String>("retain")
+        .map(|policies| Retention::parse(policies.map(String::as_str), dry_run || matches.get_flag("retain-dry-run")))
+        .transpose()
+        .unwrap_or_else(|e| panic!("{}", e));
+    let summary_file = matches.get_one::<String>("summary-file").cloned();
+    let caps = caps_from_args(matches);
+    let fail_on = matches
+        .get_one::<String>("fail-on")
+        .map(|list| FailOn::parse_list(list).unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or_default();
+    let hooks = Hooks {
+        on_start: matches.get_one::<String>("on-start").cloned(),
+        on_stop: matches.get_one::<String>("on-stop").cloned(),
+        on_error: matches.get_one::<String>("on-error").cloned(),
+    };
+    events::set_json_mode(matches.get_flag("events-json"));
+    let log_file = matches.get_one::<String>("log-file").cloned();
+    if let Some(path) = log_file.or_else(|| service::running().then(|| service::DEFAULT_LOG.to_string())) {
+        let level = logging::parse_level(matches.get_one::<String>("log-level").expect("Log level has a default value"))
+            .unwrap_or_else(|e| panic!("{}", e));
+        logging::open(&path, level).unwrap_or_else(|e| panic!("{}", e));
+    }
+    if let Some(style) = matches.get_one::<String>("notify") {
+        alert::init(alert::Style::parse(style).unwrap_or_else(|e| panic!("{}", e)));
+    }
+    if let Some(url) = matches.get_one::<String>("notify-url") {
+        notify::init(url.clone()).unwrap_or_else(|e| panic!("{}", e));
+    }
+    if matches.contains_id("compress-completed") && !dry_run {
+        compress::init().unwrap_or_else(|e| panic!("{}", e));
+    }
+    if matches.get_flag("xlsx") && !dry_run {
+        xlsx::init().unwrap_or_else(|e| panic!("{}", e));
+    }
+    if let Some(url) = matches.get_one::<String>("upload-url").filter(|_| !matches.get_flag("no-upload") && !dry_run) {
+        let target = upload::Target::parse(url).unwrap_or_else(|e| panic!("{}", e));
+        upload::init(target, template::fixed_dir(output_path)).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    if let Some(secs) = matches.get_one::<String>("probe") {
+        let secs: f64 = secs.parse().ok().filter(|secs| *secs > 0.0).expect("Failed to parse --probe seconds");
+        probe(port_name, baud_rate, std::time::Duration::from_secs_f64(secs), !matches.get_flag("keep-ansi"));
+        return;
+    }
+
+    let format = line_format(matches, Some((port_name, baud_rate)));
+    let mut columns = format.column_names();
+    // Each of several ports with the options and format it is read with
+    let port_formats: Vec<(String, ArgMatches, LineFormat)> = if several {
+        let mut options = block_options(matches, args);
+        if options.is_empty() {
+            options = ports.iter().map(|name| (name.clone(), matches.clone())).collect();
+        }
+        options
+            .into_iter()
+            .map(|(label, matches)| {
+                let format = line_format(&matches, None);
+                (label, matches, format)
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    if several {
+        let labelled: Vec<(String, &LineFormat)> =
+            port_formats.iter().map(|(label, _, format)| (label.clone(), format)).collect();
+        columns = multiport::file_columns(&labelled).unwrap_or_else(|e| panic!("{}", e));
+    }
+    let with_indices = |id: &str| -> Vec<(usize, String)> {
+        matches
+            .indices_of(id)
+            .into_iter()
+            .flatten()
+            .zip(matches.get_many::<String>(id).into_iter().flatten().cloned())
+            .collect()
+    };
+    let alarms = alarm::from_args(with_indices("alarm"), with_indices("alarm-clear"), format.field_count())
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    if let Some(spec) = matches.get_one::<String>("time-source") {
+        timesource::open(spec, matches.get_flag("apply-time-offset")).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    events::emit(Event::Startup {
+        port: port_name.clone(),
+        baud: baud_rate,
+        output: output_path.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    });
+
+    let units = column_units(matches, &columns, &id_columns);
+    let strict = matches
+        .get_one::<String>("strict")
+        .map(|checks| strict::Check::parse_list(checks).unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or_default();
+    let strict_meta: Vec<(String, String)> =
+        (!strict.is_empty()).then(|| ("strict".to_string(), strict::describe(&strict))).into_iter().collect();
+    // Create the output file with its header; the writer is shared with the serial thread
+    let settings = OutputSettings {
+        template: output_path.clone(),
+        per_run: matches.get_flag("per-run"),
+        port: port_name.clone(),
+        baud: baud_rate,
+        meta: Metadata::for_session(meta_style, port_name, baud_rate, &[timesource::metadata(), strict_meta, user_meta].concat()),
+        sidecar: matches.get_flag("sidecar"),
+        sync,
+        dialect,
+        columns: columns.clone(),
+        id_columns,
+        units,
+        alarms,
+        retention,
+        manifest: (!dry_run && (matches.get_flag("manifest") || matches.get_flag("manifest-per-run"))).then(|| Manifest {
+            path: manifest::default_path(output_path),
+            per_run: matches.get_flag("manifest-per-run"),
+        }),
+        raw_file: if matches.get_flag("no-raw") || dry_run {
+            None
+        } else {
+            Some(
+                matches
+                    .get_one::<String>("raw-file")
+                    .map(std::path::PathBuf::from)
+                    .unwrap_or_else(|| rawlog::default_path(output_path)),
+            )
+        },
+        rejects_file: matches.get_one::<String>("rejects").map(std::path::PathBuf::from),
+        state_file: if matches.get_flag("no-state-file") {
+            None
+        } else {
+            Some(
+                matches
+                    .get_one::<String>("state-file")
+                    .map(std::path::PathBuf::from)
+                    .unwrap_or_else(|| state::default_path(output_path)),
+            )
+        },
+        dry_run,
+        columnar: columnar_output(matches, &format),
+        prebuffer: matches
+            .get_one::<String>("prebuffer")
+            .map(|text| prebuffer::Limit::parse(text).unwrap_or_else(|e| panic!("{}", e))),
+        dedup_window: matches
+            .get_one::<String>("dedup-window")
+            .map(|n| n.parse().ok().filter(|n| *n > 0).expect("Failed to parse --dedup-window: expected a number of rows"))
+            .or(matches.get_flag("dedup-consecutive").then_some(1)),
+        on_change: change_policy(matches, &format),
+        clock_step: clock_step(matches),
+        xlsx_max_rows: xlsx_max_rows(matches, dry_run),
+        tail: tail_buffer(matches),
+        audit: matches.get_flag("audit"),
+        recovery: recovery_from_args(matches),
+        run_policy: run_policy_from_args(matches),
+        prompt_notes: prompt_notes(matches),
+        summary_rows: matches.get_flag("summary-rows"),
+        rotate_align: matches
+            .get_one::<String>("rotate-align")
+            .map(|unit| rotate::Align::parse(unit).unwrap_or_else(|e| panic!("{}", e))),
+        strict,
+    };
+    check_memory(matches, &settings);
+    if dry_run {
+        say!("Dry run: rows are shown instead of written, and no file is created.");
+    }
+    // Names of the values in a data row, for the services that publish rows
+    let mut record_columns = settings.columns.clone();
+    record_columns.extend(settings.id_columns.iter().map(|(name, _)| name.clone()));
+    #[cfg(feature = "zmq")]
+    if let Some(endpoint) = matches.get_one::<String>("zmq-pub") {
+        let encoding = zmq::Encoding::parse(matches.get_one::<String>("zmq-encoding").expect("Encoding has a default value"))
+            .unwrap_or_else(|e| panic!("{}", e));
+        let hwm = matches
+            .get_one::<String>("zmq-hwm")
+            .expect("HWM has a default value")
+            .parse()
+            .expect("Failed to parse --zmq-hwm");
+        zmq::init(endpoint, encoding, hwm, record_columns.clone()).unwrap_or_else(|e| panic!("{}", e));
+    }
+    let session = Session::new(settings, hooks).unwrap_or_else(|e| panic!("{}", e));
+    let shared = Arc::clone(&session.shared);
+
+    // Check free space now and then once a minute
+    let output_dir = disk::output_dir(output_path);
+    let low = disk::check_free_space(&output_dir, min_free, false);
+    shared.low_disk.store(low, std::sync::atomic::Ordering::Relaxed);
+    disk::spawn_monitor(output_dir, min_free, Arc::clone(&shared));
+    if let SyncPolicy::Interval(interval) = sync {
+        sink::spawn_interval_sync(interval, Arc::clone(&shared));
+    }
+    let session = Arc::new(Mutex::new(session));
+
+    // A termination signal shuts down exactly like the exit command
+    {
+        let session = Arc::clone(&session);
+        let summary_file = summary_file.clone();
+        let fail_on = fail_on.clone();
+        ctrlc::set_handler(move || {
+            say!("Signal received, exiting...");
+            shutdown(&session, summary_file.as_deref(), &fail_on);
+        })
+        .unwrap_or_else(|e| panic!("Failed to install signal handler: {}", e));
+    }
+
+    {
+        let session_for_exit = Arc::clone(&session);
+        let summary_file = summary_file.clone();
+        let fail_on = fail_on.clone();
+        caps::spawn(
+            caps,
+            program_started,
+            Arc::clone(&session),
+            Box::new(move || shutdown(&session_for_exit, summary_file.as_deref(), &fail_on)),
+        );
+    }
+    {
+        let session_for_exit = Arc::clone(&session);
+        let summary_file = summary_file.clone();
+        let fail_on = fail_on.clone();
+        strict::spawn(
+            Arc::clone(&session),
+            Box::new(move || shutdown(&session_for_exit, summary_file.as_deref(), &fail_on)),
+        );
+    }
+    holdoff::spawn(Arc::clone(&session));
+    // Rows past a --rotate-align boundary wait for the rotate thread to open
+    // the next file, which it also does while no row comes
+    let rotate: Option<rotate::Rotate> = matches
+        .contains_id("rotate-align")
+        .then(|| rotate::spawn(Arc::clone(&session), Arc::clone(&shared)));
+
+    // Spawn serial thread to handle incoming serial data; reconfiguration
+    // requests reach it through the control channel
+    let (serial_control, control_rx) = mpsc::channel();
+    let preview_lines = matches.get_one::<String>("preview").map(|n| {
+        n.parse::<usize>()
+            .ok()
+            .filter(|n| *n > 0)
+            .expect("Failed to parse --preview: expected a positive number of lines")
+    });
+    if let Some(n) = preview_lines {
+        *shared.preview.lock().unwrap() = Some(preview::Preview::new(n));
+    }
+    // With --preview, --auto-start waits for the answer
+    let auto_start: Option<Box<dyn Fn() + Send>> = if matches.get_flag("auto-start") && preview_lines.is_none() {
+        let session = Arc::clone(&session);
+        Some(Box::new(move || session.lock().unwrap().start("")))
+    } else {
+        None
+    };
+    let device_commands = matches.get_one::<String>("device-commands").map(|prefix| {
+        if format.frames.is_some() {
+            panic!("--device-commands applies to text lines; it can't be used with --protocol binary");
+        }
+        let session = Arc::clone(&session);
+        DeviceCommands::new(
+            prefix,
+            Box::new(move |command| match command {
+                DeviceCommand::Start(label) => session.lock().unwrap().start_from(&label, Source::Device),
+                DeviceCommand::Stop => session.lock().unwrap().stop_from(Source::Device),
+                DeviceCommand::Unknown(text) => esay!("Warning: Unknown device command '{}'; ignored.", text),
+            }),
+        )
+    });
+    let hw_trigger = matches.get_one::<String>("hw-trigger").map(|arg| {
+        let (line, mode) = HwTrigger::parse(arg).unwrap_or_else(|e| panic!("{}", e));
+        let debounce = matches
+            .get_one::<String>("debounce-ms")
+            .expect("Debounce has a default value")
+            .parse()
+            .expect("Failed to parse --debounce-ms: expected milliseconds");
+        let session = Arc::clone(&session);
+        HwTrigger {
+            line,
+            mode,
+            debounce: std::time::Duration::from_millis(debounce),
+            handler: Box::new(move |action| {
+                let mut session = session.lock().unwrap();
+                let start = match action {
+                    trigger::Action::Start => true,
+                    trigger::Action::Stop => false,
+                    // A press while a start waits calls it off
+                    trigger::Action::Toggle => !session.is_recording() && session.start_waiting().is_none(),
+                };
+                if start {
+                    session.start_from("", Source::Trigger(line));
+                } else {
+                    session.stop_from(Source::Trigger(line));
+                }
+            }),
+        }
+    });
+    let mut observers: Vec<Box<dyn tap::Observer>> = Vec::new();
+    match matches.get_one::<String>("hexdump").map(String::as_str) {
+        Some("-") => observers.push(Box::new(hexdump::HexDump::to_terminal())),
+        Some(path) => observers.push(Box::new(
+            hexdump::HexDump::to_file(std::path::Path::new(path)).unwrap_or_else(|e| panic!("{}", e)),
+        )),
+        None => {}
+    }
+    if let Some(link) = matches.get_one::<String>("pty-mirror") {
+        let writeback = mirror::Writeback::parse(
+            matches.get_one::<String>("pty-writeback").expect("Writeback has a default value"),
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+        observers.push(
+            mirror::open(std::path::Path::new(link), writeback, serial_control.clone()).unwrap_or_else(|e| panic!("{}", e)),
+        );
+    }
+    let input = InputOptions {
+        format,
+        strip_ansi: !matches.get_flag("keep-ansi"),
+        skip_blank_lines: !matches.get_flag("keep-blank-lines"),
+        skip_lines: skip_lines(matches),
+        read_timeout: read_timeout(matches),
+        wait_for: matches.get_one::<String>("wait-for").cloned(),
+        auto_start,
+        device_commands,
+        hw_trigger,
+        log_breaks: matches.get_flag("log-breaks"),
+        observers,
+        max_line_length: max_line_length(matches),
+        gap_marker: gap_marker(matches),
+        modem_interval: matches.get_one::<String>("log-modem-lines").map(|ms| {
+            std::time::Duration::from_millis(ms.parse().expect("Failed to parse --log-modem-lines interval"))
+        }),
+        port: None,
+        rotate: rotate.clone(),
+    };
+    statusline::spawn(Arc::clone(&shared), matches.get_flag("no-statusline"));
+    if let Some(secs) = matches.get_one::<String>("heartbeat") {
+        let secs: f64 = secs.parse().ok().filter(|secs| *secs > 0.0).expect("Failed to parse --heartbeat seconds");
+        heartbeat::spawn(std::time::Duration::from_secs_f64(secs), Arc::clone(&shared));
+    }
+    timesource::spawn_rows(Arc::clone(&shared));
+    if let Some(address) = matches.get_one::<String>("metrics-listen") {
+        metrics::spawn(address, Arc::clone(&shared), columns.clone()).unwrap_or_else(|e| panic!("{}", e));
+    }
+    if let Some(address) = matches.get_one::<String>("grpc-listen") {
+        grpc::spawn(address, Arc::clone(&session), serial_control.clone(), record_columns)
+            .unwrap_or_else(|e| panic!("{}", e));
+    }
+    let control_file = matches.get_one::<String>("control-file").cloned();
+    if let Some(path) = control_file.or_else(|| service::running().then(|| service::DEFAULT_CONTROL.to_string())) {
+        let session_for_exit = Arc::clone(&session);
+        let summary_file = summary_file.clone();
+        let fail_on = fail_on.clone();
+        control::spawn(
+            std::path::PathBuf::from(path),
+            Arc::clone(&session),
+            serial_control.clone(),
+            Box::new(move || shutdown(&session_for_exit, summary_file.as_deref(), &fail_on)),
+        );
+    }
+    if several {
+        let ports = ports
+            .iter()
+            .zip(port_formats)
+            .map(|(name, (label, matches, format))| {
+                let layout = multiport::Layout::new(&label, &format.column_names(), &columns).unwrap_or_else(|e| panic!("{}", e));
+                multiport::Port {
+                    name: name.clone(),
+                    baud: matches
+                        .get_one::<String>("baud")
+                        .expect("Baud rate has a default value")
+                        .parse()
+                        .expect("Failed to parse baud rate"),
+                    input: port_input(&matches, format, layout, rotate.clone()),
+                }
+            })
+            .collect();
+        let ordered = matches.get_one::<String>("ordered").map(|ms| {
+            let ms = ms.parse().ok().filter(|ms| (1..=10000).contains(ms));
+            std::time::Duration::from_millis(ms.expect("Failed to parse --ordered: expected 1 to 10000 milliseconds"))
+        });
+        multiport::spawn(ports, ordered, Arc::clone(&shared), control_rx).unwrap_or_else(|e| panic!("{}", e));
+    } else {
+        let port_list = PortList {
+            ports: ports.clone(),
+            failback: matches.get_flag("failback"),
+        };
+        let _serial_thread = serial::spawn(port_list, baud_rate, Arc::clone(&shared), control_rx, input);
+    }
+
+    // Main thread: handle user commands
+    let mut prompt = Prompt::new();
+    if preview_lines.is_some() {
+        let rows = preview::collect(&shared.preview);
+        preview::print(&rows, &columns);
+        let ask = !matches.get_flag("yes") && std::io::stdin().is_terminal();
+        if ask && !preview::proceed(&mut prompt) {
+            say!("Exiting...");
+            shutdown(&session, summary_file.as_deref(), &fail_on);
+        }
+        // The previewed lines aren't recorded, not even as pre rows
+        if let Some(prebuffer) = &shared.prebuffer {
+            prebuffer.lock().unwrap().take(std::time::Instant::now());
+        }
+        if matches.get_flag("auto-start") {
+            session.lock().unwrap().start("");
+        }
+    }
+    // A service has no console; it ends when the service manager stops it
+    if service::running() {
+        service::wait_for_stop();
+        say!("Service stopped, exiting...");
+        shutdown(&session, summary_file.as_deref(), &fail_on);
+    }
+    loop {
+        // Without the session locked, so a run can start while notes are typed
+        let due = session.lock().unwrap().take_notes_due();
+        for due in due {
+            let text = repl::read_notes(&mut prompt, due.run);
+            if !text.is_empty() {
+                session.lock().unwrap().write_note(due, &text);
+            }
+        }
+        let prompt_text = repl::prompt_text(&session.lock().unwrap());
+        let input = prompt.read(&prompt_text);
+        let line = match input {
+            Input::Line(line) => line,
+            // Ctrl+C only discards the line being typed
+            Input::Interrupted => continue,
+            Input::Eof if std::io::stdin().is_terminal() => "exit".to_string(),
+            Input::Eof => headless(matches, &session, summary_file.as_deref(), &fail_on),
+        };
+        let line = line.trim();
+        let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim();
+
+        if command == "exit" {
+            session.lock().unwrap().audit(line);
+            say!("Exiting...");
+            shutdown(&session, summary_file.as_deref(), &fail_on);
+        }
+
+        commands::dispatch(&mut session.lock().unwrap(), &serial_control, command, args);
+    }
+}
+
+// Piped commands ran out without `exit` (or stdin is /dev/null, as under
+// systemd or nohup): logging goes on without a prompt while a run is recording
+// or something other than stdin can start one, until a signal or a remote
+// `exit` ends it; otherwise there is nothing left to do
+fn headless(matches: &ArgMatches, session: &Mutex<Session>, summary_file: Option<&str>, fail_on: &[FailOn]) -> ! {
+    let controls: Vec<String> = ["control-file", "grpc-listen", "device-commands", "hw-trigger"]
+        .into_iter()
+        .filter(|name| matches.get_one::<String>(name).is_some())
+        .map(|name| format!("--{}", name))
+        .collect();
+    let active = matches.get_flag("auto-start") || {
+        let session = session.lock().unwrap();
+        session.is_recording() || session.start_waiting().is_some()
+    };
+    if !active && controls.is_empty() {
+        esay!(
+            "ERROR: Input ended without 'exit' and nothing else can start a run; exiting. To log without \
+             commands on stdin, use --auto-start, --control-file, --grpc-listen, --device-commands or --hw-trigger."
+        );
+        shutdown(session, summary_file, fail_on);
+    }
+    say!(
+        "Input ended; logging on without a prompt until stopped by a signal{}.",
+        if controls.is_empty() { String::new() } else { format!(" (commands still come from {})", controls.join(", ")) }
+    );
+    loop {
+        std::thread::park();
+    }
+}
+
+// The command line, also what the completions and the man page are
+// generated from
+// --zmq-pub and its options, in a build with the 'zmq' feature
+fn zmq_args() -> Vec<Arg> {
+    if !cfg!(feature = "zmq") {
+        return Vec::new();
+    }
+    vec![
+        Arg::new("zmq-pub")
+            .long("zmq-pub")
+            .value_name("ENDPOINT")
+            .help("Publish data rows and run start/stop on a ZeroMQ PUB socket bound here (e.g., tcp://*:5556)"),
+        Arg::new("zmq-encoding")
+            .long("zmq-encoding")
+            .value_name("ENCODING")
+            .help("Encoding of --zmq-pub messages: json or msgpack")
+            .default_value("json"),
+        Arg::new("zmq-hwm")
+            .long("zmq-hwm")
+            .value_name("MESSAGES")
+            .help("Messages queued for --zmq-pub subscribers; beyond that they are dropped")
+            .default_value("1000"),
+    ]
+}
+
+fn cli() -> Command {
+    Command::new("Serial Logger")
         .version("1.0")
         .about("Reads serial data and stores it in a CSV")
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Read options from a TOML file of option = value lines (written by `setup`); the command line adds to or overrides them"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .value_name("NAME")
+                .help("Read options from the profile NAME in profiles.toml; --config and the command line add to or override them"),
+        )
+        .arg(
+            Arg::new("print-config")
+                .long("print-config")
+                .help("Check the options, print them as a --config file and exit; fails like logging would on options that don't work together")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("list-profiles")
+                .long("list-profiles")
+                .help("List the profiles in profiles.toml with their descriptions and exit")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("port")
                 .short('p')
                 .long("port")
                 .value_name("PORT")
-                .help("Serial port to connect to (e.g., COM3 or /dev/ttyUSB0)")
-                .required(true),
+                .help("Serial port to connect to (e.g., COM3 or /dev/ttyUSB0), or a comma-separated list of backups in order of preference; give it more than once to read several ports at once, with a Port column")
+                .action(ArgAction::Append)
+                .required_unless_present_any(["benchmark", "list-profiles"]),
         )
         .arg(
             Arg::new("baud")
@@ -35,218 +805,1839 @@ fn main() {
                 .help("Baud rate for the serial port (e.g., 115200)")
                 .default_value("115200"),
         )
+        .arg(
+            Arg::new("read-timeout")
+                .long("read-timeout")
+                .value_name("MS")
+                .help("Quiet time before a trailing binary frame is written (10-10000 ms); reads and exit are never held up longer than 100 ms")
+                .default_value("100"),
+        )
         .arg(
             Arg::new("output")
                 .short('o')
                 .long("output")
                 .value_name("OUTPUT")
-                .help("Path to output CSV file (e.g., ./data/output.csv)")
+                .help("Path to output CSV file (e.g., ./data/{date}/output.csv); supports placeholders")
                 .default_value("output.csv"),
         )
-        .get_matches();
+        .args(input_args())
+        .args(csv_args())
+        .arg(
+            Arg::new("probe")
+                .long("probe")
+                .value_name("SECONDS")
+                .help("Read for a few seconds, suggest --match/--delimiter/--fields and exit [default: 5]")
+                .num_args(0..=1)
+                .default_missing_value("5"),
+        )
+        .arg(
+            Arg::new("benchmark")
+                .long("benchmark")
+                .help("Measure latency and throughput on a simulated device from 100 Hz to 10 kHz, with the given parsing and output options, and exit")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("benchmark-json")
+                .long("benchmark-json")
+                .value_name("FILE")
+                .help("Also write the --benchmark results to FILE as JSON")
+                .requires("benchmark"),
+        )
+        .arg(
+            Arg::new("ordered")
+                .long("ordered")
+                .value_name("MS")
+                .help("With several ports, write rows in the order their lines arrived, holding each MS milliseconds for the other ports to catch up [default: 50]")
+                .num_args(0..=1)
+                .default_missing_value("50"),
+        )
+        .arg(
+            Arg::new("failback")
+                .long("failback")
+                .help("Return to the first --port once it can be opened again after a failover")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("auto-start")
+                .long("auto-start")
+                .help("Start recording when the --wait-for marker arrives")
+                .action(ArgAction::SetTrue)
+                .requires("wait-for"),
+        )
+        .arg(
+            Arg::new("preview")
+                .long("preview")
+                .value_name("N")
+                .help("Show the first N matching lines as they would be recorded and ask before going on"),
+        )
+        .arg(
+            Arg::new("yes")
+                .long("yes")
+                .help("Go on after --preview without asking")
+                .action(ArgAction::SetTrue)
+                .requires("preview"),
+        )
+        .arg(
+            Arg::new("prebuffer")
+                .long("prebuffer")
+                .value_name("ROWS|DURATION")
+                .help("While not recording, keep the last ROWS rows or DURATION (e.g., 2s) of data and write them when a run starts"),
+        )
+        .arg(
+            Arg::new("audit")
+                .long("audit")
+                .help("Write every operator command (start, stop, pause, resume, newfile, baud, port, break, exit) as a cmd row")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("summary-rows")
+                .long("summary-rows")
+                .help("End every run with summary_count, summary_min, summary_max, summary_mean and summary_stddev rows")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dedup-consecutive")
+                .long("dedup-consecutive")
+                .help("Drop a data row whose payload repeats the previous row's")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dedup-window")
+                .long("dedup-window")
+                .value_name("N")
+                .help("Drop a data row whose payload repeats any of the last N rows (implies --dedup-consecutive)"),
+        )
+        .arg(
+            Arg::new("log-on-change")
+                .long("log-on-change")
+                .value_name("FIELDS")
+                .help("Only write a data row when a field differs from the last row written; FIELDS (0-based, e.g. 1,2) limits which [default: all]")
+                .num_args(0..=1)
+                .default_missing_value("all"),
+        )
+        .arg(
+            Arg::new("change-epsilon")
+                .long("change-epsilon")
+                .value_name("DELTA")
+                .help("With --log-on-change, numbers must differ by more than DELTA to count as a change [default: 0]")
+                .requires("log-on-change"),
+        )
+        .arg(
+            Arg::new("keepalive")
+                .long("keepalive")
+                .value_name("SECS")
+                .help("With --log-on-change, write a row anyway when none has been written for SECS seconds")
+                .requires("log-on-change"),
+        )
+        .arg(
+            Arg::new("log-modem-lines")
+                .long("log-modem-lines")
+                .value_name("MS")
+                .help("Write a modem row when CTS/DSR/CD/RI change, polling every MS milliseconds [default: 100]")
+                .num_args(0..=1)
+                .default_missing_value("100"),
+        )
+        .arg(
+            Arg::new("heartbeat")
+                .long("heartbeat")
+                .value_name("SECONDS")
+                .help("While recording, write a heartbeat row every SECONDS, even without data"),
+        )
+        .arg(
+            Arg::new("device-commands")
+                .long("device-commands")
+                .value_name("PREFIX")
+                .help("Start and stop runs on command lines from the device, e.g. ##CMD:START name## and ##CMD:STOP##")
+                .num_args(0..=1)
+                .default_missing_value(devcmd::DEFAULT_PREFIX),
+        )
+        .arg(
+            Arg::new("hw-trigger")
+                .long("hw-trigger")
+                .value_name("LINE:MODE")
+                .help("Start and stop runs on a modem line (cts, dsr or cd): toggle on each rising edge, or record while it is high or low"),
+        )
+        .arg(
+            Arg::new("debounce-ms")
+                .long("debounce-ms")
+                .value_name("MS")
+                .help("How long a --hw-trigger line must hold a new level before it counts")
+                .default_value("50"),
+        )
+        .arg(
+            Arg::new("min-run-duration")
+                .long("min-run-duration")
+                .value_name("SECONDS")
+                .help("A stop before a run has recorded this long is ignored with a warning, whoever sends it"),
+        )
+        .arg(
+            Arg::new("short-run-action")
+                .long("short-run-action")
+                .value_name("ACTION")
+                .help("What a stop before --min-run-duration does: keep the run going, or mark it short and stop")
+                .default_value("keep"),
+        )
+        .arg(
+            Arg::new("start-holdoff")
+                .long("start-holdoff")
+                .value_name("SECONDS")
+                .help("A start within this long of the last stop waits until it has passed, whoever sends it"),
+        )
+        .arg(
+            Arg::new("holdoff-action")
+                .long("holdoff-action")
+                .value_name("ACTION")
+                .help("What a start within --start-holdoff does: delay it, or refuse it")
+                .default_value("delay"),
+        )
+        .arg(
+            Arg::new("hexdump")
+                .long("hexdump")
+                .value_name("FILE")
+                .help("Also show everything read from the port as a hex dump, on the terminal or in FILE")
+                .num_args(0..=1)
+                .default_missing_value("-"),
+        )
+        .arg(
+            Arg::new("pty-mirror")
+                .long("pty-mirror")
+                .value_name("PATH")
+                .help("Echo everything read from the port to a pseudo-terminal linked at PATH, for another program to open"),
+        )
+        .arg(
+            Arg::new("pty-writeback")
+                .long("pty-writeback")
+                .value_name("MODE")
+                .help("What happens to bytes written to the --pty-mirror terminal: discard or forward (to the port)")
+                .default_value("discard"),
+        )
+        .arg(
+            Arg::new("time-source")
+                .long("time-source")
+                .value_name("SOURCE")
+                .help("Measure the host clock against a GPS receiver on a second port: nmea:PATH or nmea:PATH@BAUD (default 9600)"),
+        )
+        .arg(
+            Arg::new("apply-time-offset")
+                .long("apply-time-offset")
+                .help("Correct every timestamp written by the offset measured with --time-source")
+                .action(ArgAction::SetTrue)
+                .requires("time-source"),
+        )
+        .arg(
+            Arg::new("max-line-length")
+                .long("max-line-length")
+                .value_name("BYTES")
+                .help("Drop a line longer than BYTES as it arrives, with an overflow row while recording")
+                .default_value(serial::DEFAULT_MAX_LINE_LENGTH),
+        )
+        .arg(
+            Arg::new("gap-marker")
+                .long("gap-marker")
+                .value_name("SECONDS")
+                .help("Write a gap row before a data row that arrives more than SECONDS after the previous one"),
+        )
+        .arg(
+            Arg::new("clock-step")
+                .long("clock-step")
+                .value_name("SECONDS")
+                .help("Write a clockstep row when the system clock jumps by more than SECONDS between data rows; 0 turns it off")
+                .default_value(clockstep::DEFAULT_THRESHOLD),
+        )
+        .arg(
+            Arg::new("per-run")
+                .long("per-run")
+                .help("Write every run to its own file; --output must contain {run} or {name}")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("rotate-align")
+                .long("rotate-align")
+                .value_name("UNIT")
+                .value_parser(["day", "hour"])
+                .conflicts_with("per-run")
+                .help("Start a new output file at each local midnight (day) or full hour; --output needs {date} (day) or {time} (hour), or {datetime}"),
+        )
+        .arg(
+            Arg::new("state-file")
+                .long("state-file")
+                .value_name("PATH")
+                .help("Where run numbering is kept between sessions [default: .serial_logger_state.json next to the output]"),
+        )
+        .arg(
+            Arg::new("no-state-file")
+                .long("no-state-file")
+                .help("Number runs from 0 every session instead of continuing from the state file")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("state-file"),
+        )
+        .arg(
+            Arg::new("raw-file")
+                .long("raw-file")
+                .value_name("PATH")
+                .help("Where everything received is captured for reparse [default: session.raw next to the output]"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .help("Read and parse as usual but show the rows instead of writing them; no file is created")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-raw")
+                .long("no-raw")
+                .help("Don't capture the raw input")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("raw-file"),
+        )
+        .arg(
+            Arg::new("rejects")
+                .long("rejects")
+                .value_name("PATH")
+                .help("Append lines rejected while recording to this CSV, with the reason"),
+        )
+        .arg(
+            Arg::new("alarm")
+                .long("alarm")
+                .value_name("CONDITION:LABEL")
+                .help("Write an alarm row and notify when the condition becomes true (e.g. \"f3 < -9.5:impact\"); repeatable")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("alarm-clear")
+                .long("alarm-clear")
+                .value_name("CONDITION")
+                .help("Only lower the preceding --alarm again once this condition is true (e.g. \"f3 > -9.0\")")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("sidecar")
+                .long("sidecar")
+                .help("Write a JSON metadata file next to the output when each run stops")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("xlsx")
+                .long("xlsx")
+                .help("Write an Excel workbook of each run (data, statistics, metadata) next to the output when it stops")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("xlsx-max-rows")
+                .long("xlsx-max-rows")
+                .value_name("N")
+                .help("Runs with more data rows get no workbook, only the CSV [default: 100000]")
+                .requires("xlsx"),
+        )
+        .arg(
+            Arg::new("prompt-notes")
+                .long("prompt-notes")
+                .help("Ask for notes on each run when it stops, written as a note row and into its sidecar")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("retain")
+                .long("retain")
+                .value_name("POLICY")
+                .help("Delete the oldest output files after each run until count:N, age:DURATION or size:SIZE holds; repeatable")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("manifest")
+                .long("manifest")
+                .help("At exit, append the SHA-256 of every file written to MANIFEST.sha256 in the output directory")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("manifest-per-run")
+                .long("manifest-per-run")
+                .help("Like --manifest, but list each run's file as soon as it is closed")
+                .requires("per-run")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("upload-url")
+                .long("upload-url")
+                .value_name("URL")
+                .help("Upload finished files and sidecars to s3://bucket/prefix/ or with HTTP PUT to an http(s):// URL"),
+        )
+        .arg(
+            Arg::new("no-upload")
+                .long("no-upload")
+                .help("Don't upload anything this session, not even uploads left over from an earlier one")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("compress-completed")
+                .long("compress-completed")
+                .value_name("FORMAT")
+                .help("Compress each finished run or rotated file in the background; only gzip")
+                .value_parser(["gzip"]),
+        )
+        .arg(
+            Arg::new("retain-dry-run")
+                .long("retain-dry-run")
+                .help("Only print which files --retain would delete")
+                .requires("retain")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("sync")
+                .long("sync")
+                .value_name("POLICY")
+                .help("Force data to disk: never, interval:<secs> or every-flush")
+                .default_value("never"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Output file format: csv, arrow for an Arrow IPC stream, parquet, or hdf5")
+                .value_parser(["csv", "arrow", "parquet", "hdf5"])
+                .default_value("csv"),
+        )
+        .arg(
+            Arg::new("arrow-batch")
+                .long("arrow-batch")
+                .value_name("ROWS")
+                .help("Rows per record batch with --format arrow")
+                .default_value("4096"),
+        )
+        .arg(
+            Arg::new("row-group")
+                .long("row-group")
+                .value_name("ROWS")
+                .help("Rows per row group with --format parquet; a crash loses at most the group being collected")
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::new("hdf5-chunk")
+                .long("hdf5-chunk")
+                .value_name("ROWS")
+                .help("Rows per chunk with --format hdf5; a crash loses at most the chunk being collected")
+                .default_value("4096"),
+        )
+        .arg(
+            Arg::new("min-free")
+                .long("min-free")
+                .value_name("SIZE")
+                .help("Warn when free space on the output disk drops below this (e.g., 500MB)")
+                .default_value("500MB"),
+        )
+        .arg(
+            Arg::new("write-error-limit")
+                .long("write-error-limit")
+                .value_name("N")
+                .help("Failed writes in a row after which rows are held in memory while the output is reopened")
+                .default_value("3"),
+        )
+        .arg(
+            Arg::new("outage-buffer")
+                .long("outage-buffer")
+                .value_name("ROWS")
+                .help("Rows held in memory while the output can't be written; beyond that the oldest are dropped")
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::new("fallback-output")
+                .long("fallback-output")
+                .value_name("PATH")
+                .help("Write here when the output can't be reopened after repeated write errors"),
+        )
+        .arg(
+            Arg::new("events-json")
+                .long("events-json")
+                .help("Emit machine-readable JSON events on stdout (human messages move to stderr)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-statusline")
+                .long("no-statusline")
+                .help("Don't show the live throughput line at the bottom of the terminal")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("tail-buffer")
+                .long("tail-buffer")
+                .value_name("ROWS")
+                .help("Keep this many of the last rows written for the tail command; 0 keeps none")
+                .default_value("1000"),
+        )
+        .arg(
+            Arg::new("notify")
+                .long("notify")
+                .value_name("HOW")
+                .help("Ring the terminal bell (bell) or show a desktop notification (desktop) when a run stops or the port is lost"),
+        )
+        .arg(
+            Arg::new("metrics-listen")
+                .long("metrics-listen")
+                .value_name("ADDRESS")
+                .help("Serve Prometheus metrics at /metrics on this address (e.g., 0.0.0.0:9200)"),
+        )
+        .arg(
+            Arg::new("grpc-listen")
+                .long("grpc-listen")
+                .value_name("ADDRESS")
+                .help("Serve the gRPC control and record streaming service on this address (e.g., 0.0.0.0:50051)"),
+        )
+        .arg(
+            Arg::new("control-file")
+                .long("control-file")
+                .value_name("PATH")
+                .help("Carry out the command (start, stop, exit, ...) written to this file, checked every second"),
+        )
+        .arg(
+            Arg::new("service")
+                .long("service")
+                .value_name("DIR")
+                .help("Run as the Windows service installed with 'service install', in DIR (set by the service manager)"),
+        )
+        .args(zmq_args())
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .value_name("PATH")
+                .help("Also write messages and warnings, timestamped, to this file (a new one each day)"),
+        )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Least severe messages written to --log-file: error, warn or info (RUST_LOG overrides it)")
+                .default_value("info"),
+        )
+        .arg(
+            Arg::new("max-duration")
+                .long("max-duration")
+                .value_name("DURATION")
+                .help("Stop recording once the program has run this long (e.g., 12h), then exit with code 8"),
+        )
+        .arg(
+            Arg::new("max-file-size")
+                .long("max-file-size")
+                .value_name("SIZE")
+                .help("Stop recording once the files written this session total this size (e.g., 2GB), then exit with code 8"),
+        )
+        .arg(
+            Arg::new("max-memory")
+                .long("max-memory")
+                .value_name("SIZE")
+                .help("Refuse to start if the in-memory buffers could hold more than this (e.g., 64MB), and hold each to its share"),
+        )
+        .arg(
+            Arg::new("cap-action")
+                .long("cap-action")
+                .value_name("ACTION")
+                .help("What a reached --max-duration or --max-file-size does: exit, or stop-only to stay up without recording")
+                .default_value("exit"),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .value_name("CHECKS")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("all")
+                .help("Stop and exit on the first wrong field count, wrong type, bad frame, lost port or dropped row while recording; --strict=fields,types,frames,reconnect,drops picks the checks"),
+        )
+        .arg(
+            Arg::new("fail-on")
+                .long("fail-on")
+                .value_name("CONDITIONS")
+                .help("Exit non-zero if any is violated: no-data, parse-errors>N, drops>N, disconnects>N (comma-separated)"),
+        )
+        .arg(
+            Arg::new("summary-file")
+                .long("summary-file")
+                .value_name("PATH")
+                .help("Also write the end-of-session summary as JSON (e.g., summary.json)"),
+        )
+        .arg(
+            Arg::new("on-start")
+                .long("on-start")
+                .value_name("COMMAND")
+                .help("Shell command run in the background when a run starts"),
+        )
+        .arg(
+            Arg::new("on-stop")
+                .long("on-stop")
+                .value_name("COMMAND")
+                .help("Shell command run in the background when a run stops"),
+        )
+        .arg(
+            Arg::new("on-error")
+                .long("on-error")
+                .value_name("COMMAND")
+                .help("Shell command run in the background when reading the serial port fails"),
+        )
+        .arg(
+            Arg::new("notify-url")
+                .long("notify-url")
+                .value_name("URL")
+                .help("POST run summaries and errors as JSON to this URL (e.g., a Slack webhook)"),
+        )
+        .subcommand(
+            Command::new("reparse")
+                .about("Regenerates a CSV from a raw capture, with the input and CSV options given now")
+                .arg(
+                    Arg::new("raw")
+                        .value_name("RAW")
+                        .help("Raw capture to replay (e.g., session.raw)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT")
+                        .help("Path of the CSV to write")
+                        .required(true),
+                )
+                .args(input_args())
+                .args(csv_args()),
+        )
+        .subcommand(
+            Command::new("repair")
+                .about("Fixes an output file after a crash: drops a partial last line and closes unterminated runs")
+                .arg(
+                    Arg::new("csv")
+                        .value_name("CSV")
+                        .help("Output file to check and repair")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("Converts an output CSV to JSON Lines or a JSON array")
+                .arg(
+                    Arg::new("csv")
+                        .value_name("CSV")
+                        .help("Output file to convert")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT")
+                        .help("Path of the JSON file to write")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("FORMAT")
+                        .help("jsonl (one object per line) or json (an array)")
+                        .default_value("jsonl"),
+                )
+                .arg(
+                    Arg::new("shape")
+                        .long("shape")
+                        .value_name("SHAPE")
+                        .help("flat (one object per row) or nested (one object per run with its rows)")
+                        .default_value("flat"),
+                )
+                .arg(
+                    Arg::new("lenient")
+                        .long("lenient")
+                        .help("Skip rows that can't be read instead of stopping")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("analyze")
+                .about("Prints per-run statistics of an output CSV")
+                .arg(
+                    Arg::new("csv")
+                        .value_name("CSV")
+                        .help("Output file to analyze")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("gap")
+                        .long("gap")
+                        .value_name("SECONDS")
+                        .help("Flag pauses between data rows longer than this")
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Print the statistics as JSON")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("split")
+                .about("Writes every run of an output CSV to its own file")
+                .arg(
+                    Arg::new("csv")
+                        .value_name("CSV")
+                        .help("Output file to split")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("out-dir")
+                        .long("out-dir")
+                        .value_name("DIR")
+                        .help("Directory for the run files (created if missing)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("keep-markers")
+                        .long("keep-markers")
+                        .help("Keep each run's start and stop rows in its file")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("skip-empty")
+                        .long("skip-empty")
+                        .help("Don't write files for runs without data rows")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("extract")
+                .about("Copies the rows of an output CSV between two times to a new file")
+                .arg(
+                    Arg::new("csv")
+                        .value_name("CSV")
+                        .help("Output file to take the rows from")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT")
+                        .help("Path of the CSV to write (not overwritten if it exists)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .value_name("TIME")
+                        .help("Start of the window, e.g. \"2024-05-14 14:32:10\", or 14:32:10 for a file from a single day")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("TIME")
+                        .help("End of the window, included, in the same form as --from")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("synthesize-markers")
+                        .long("synthesize-markers")
+                        .help("Add start and stop rows where the window cuts through a run")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("merge")
+                .about("Interleaves output CSVs from several stations by timestamp")
+                .arg(
+                    Arg::new("csv")
+                        .value_name("CSV[=LABEL]")
+                        .help("Output files to merge, each optionally labelled for the Source column")
+                        .num_args(2..)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("File to write the merged rows to")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("lenient")
+                        .long("lenient")
+                        .help("Drop rows without a readable timestamp instead of stopping")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Checks the files listed in a MANIFEST.sha256 against their SHA-256")
+                .arg(
+                    Arg::new("manifest")
+                        .value_name("MANIFEST")
+                        .help("Manifest to check (e.g., data/MANIFEST.sha256)")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("setup")
+                .about("Asks step by step how to read the device, with defaults from sampling it, and writes a --config file")
+                .arg(
+                    Arg::new("config")
+                        .value_name("CONFIG")
+                        .help("Config file to write")
+                        .default_value(setup::DEFAULT_CONFIG),
+                ),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Prints a shell completion script for all options and subcommands")
+                .arg(
+                    Arg::new("shell")
+                        .value_name("SHELL")
+                        .help("Shell to complete in")
+                        .value_parser(clap::value_parser!(clap_complete::Shell))
+                        .required(true),
+                ),
+        )
+        .subcommand(Command::new("manpage").about("Prints a man page (roff) for all options and subcommands"))
+        .subcommand(
+            Command::new("service")
+                .about("Installs or uninstalls the logger as a Windows service started at boot")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("install")
+                        .about("Installs the service, logging with the given options in the current directory")
+                        .arg(service_name_arg())
+                        .arg(
+                            Arg::new("options")
+                                .value_name("OPTIONS")
+                                .help("Logger options, after --")
+                                .num_args(1..)
+                                .last(true)
+                                .required(true),
+                        ),
+                )
+                .subcommand(Command::new("uninstall").about("Stops and removes the service").arg(service_name_arg())),
+        )
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        // So the command line overrides options from --config
+        .args_override_self(true)
+}
 
-    // Retrieve command-line arguments
-    let port_name = matches.get_one::<String>("port").expect("Port is required");
-    let baud_rate: u32 = matches
-        .get_one::<String>("baud")
-        .expect("Baud rate has a default value")
+// How lines are parsed; with `--fields auto` the fields are detected from the
+// first data line read from `port`, which only live logging has
+fn line_format(matches: &ArgMatches, port: Option<(&str, u32)>) -> LineFormat {
+    let auto_fields = matches.get_one::<String>("fields").map(String::as_str) == Some("auto");
+    let mut format = LineFormat {
+        pattern: matches.get_one::<String>("match").expect("Match has a default value").clone(),
+        delimiter: parser::parse_delimiter(matches.get_one::<String>("delimiter").expect("Delimiter has a default value"))
+            .unwrap_or_else(|e| panic!("{}", e)),
+        fields: if auto_fields {
+            0
+        } else {
+            matches
+                .get_one::<String>("fields")
+                .expect("Fields has a default value")
+                .parse()
+                .ok()
+                .filter(|fields| *fields > 0)
+                .expect("Failed to parse --fields: expected a positive number or auto")
+        },
+        select: None,
+        computed: Vec::new(),
+        frames: None,
+        framing: match matches.get_one::<String>("framing").map(String::as_str) {
+            Some("cobs") => Framing::Cobs,
+            _ => Framing::Plain,
+        },
+        encoding: matches
+            .get_one::<String>("payload-encoding")
+            .and_then(|name| Encoding::parse(name))
+            .expect("Payload encoding has a default value"),
+        inner_frames: None,
+        decimal_comma: matches.get_flag("decimal-comma"),
+        types: None,
+        float_format: FloatFormat::parse(matches.get_one::<String>("float-format").expect("Float format has a default value"))
+            .unwrap_or_else(|e| panic!("{}", e)),
+        names: None,
+    };
+    if format.decimal_comma && format.delimiter == ',' {
+        panic!("--decimal-comma needs a field delimiter other than ',' (e.g. --delimiter ';')");
+    }
+    if format.framing == Framing::Cobs && matches.value_source("log-breaks") == Some(clap::parser::ValueSource::CommandLine) {
+        panic!("--log-breaks can't be used with --framing cobs, where zero bytes end packets");
+    }
+    let spec = matches.get_one::<String>("frame-spec").map(|spec| {
+        let spec = if spec.contains('=') {
+            FrameSpec::parse(spec)
+        } else {
+            FrameSpec::from_file(std::path::Path::new(spec))
+        };
+        spec.unwrap_or_else(|e| panic!("{}", e))
+    });
+    let inner_binary = matches.get_one::<String>("inner-protocol").map(String::as_str) == Some("binary");
+    match (matches.get_one::<String>("protocol").map(String::as_str), spec) {
+        (Some("binary"), Some(spec)) => {
+            let text_only = ["skip-lines", "wait-for", "log-breaks", "payload-encoding", "inner-protocol"]
+                .into_iter()
+                .find(|id| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine));
+            if let Some(id) = text_only {
+                panic!("--{} applies to text lines; it can't be used with --protocol binary", id);
+            }
+            if spec.sync.is_empty() && format.framing == Framing::Plain {
+                panic!("The frame spec needs a sync word, unless frames come as --framing cobs packets");
+            }
+            format.fields = spec.fields.len();
+            format.frames = Some(spec);
+        }
+        (Some("binary"), None) => panic!("--protocol binary needs a --frame-spec"),
+        (_, Some(spec)) if inner_binary => {
+            format.fields = spec.fields.len();
+            format.inner_frames = Some(spec);
+        }
+        (_, None) if inner_binary => panic!("--inner-protocol binary needs a --frame-spec"),
+        (_, Some(_)) => panic!("--frame-spec only applies to --protocol binary or --inner-protocol binary"),
+        _ => {}
+    }
+    if inner_binary && format.encoding == Encoding::Plain {
+        panic!("--inner-protocol binary needs a --payload-encoding of hex or base64");
+    }
+    let numeric = auto_fields.then(|| {
+        if format.frames.is_some() || format.inner_frames.is_some() {
+            panic!("--fields auto applies to text lines; binary frames have the fields of their --frame-spec");
+        }
+        if format.framing == Framing::Cobs {
+            panic!("--fields auto reads text lines; give the number of fields with --framing cobs");
+        }
+        let (port, baud) = port.unwrap_or_else(|| panic!("--fields auto detects the fields from the port; give their number here"));
+        detect_fields(&mut format, matches, port, baud)
+    });
+    format.select = matches
+        .get_one::<String>("select")
+        .map(|select| parser::parse_select(select, format.fields).unwrap_or_else(|e| panic!("{}", e)));
+    if let Some(names) = matches.get_one::<String>("names") {
+        let names: Vec<String> = names.split(',').map(|name| name.trim().to_string()).collect();
+        if names.len() != format.fields {
+            panic!("--names gives {} names, but the payload has {} fields", names.len(), format.fields);
+        }
+        format.names = Some(names);
+    }
+    if let Some(types) = matches.get_one::<String>("types") {
+        let schema = Schema::parse(types, non_finite(matches)).unwrap_or_else(|e| panic!("{}", e));
+        if schema.columns.len() != format.field_count() {
+            panic!(
+                "--types gives {} types, but rows have {} fields{}",
+                schema.columns.len(),
+                format.field_count(),
+                if format.select.is_some() { " after --select" } else { "" }
+            );
+        }
+        format.types = Some(schema);
+    } else if let Some(numeric) = numeric {
+        // The detected fields hold the rest to numbers where they were numbers
+        let numeric = match &format.select {
+            Some(select) => select.iter().map(|&i| numeric[i]).collect(),
+            None => numeric,
+        };
+        let columns = numeric
+            .into_iter()
+            .map(|numeric| if numeric { ColumnType::Float { bits: 64, decimals: None } } else { ColumnType::Text })
+            .collect();
+        format.types = Some(Schema {
+            columns,
+            non_finite: non_finite(matches),
+        });
+    }
+    format.computed = matches
+        .get_many::<String>("compute")
+        .unwrap_or_default()
+        .map(|arg| parser::parse_compute(arg, format.field_count()).unwrap_or_else(|e| panic!("{}", e)))
+        .collect();
+    format
+}
+
+// Whether the prompt asks for notes after each run: only at a terminal, not
+// when commands come from a script
+fn prompt_notes(matches: &ArgMatches) -> bool {
+    if !matches.get_flag("prompt-notes") {
+        return false;
+    }
+    if !std::io::stdin().is_terminal() {
+        say!("Input isn't a terminal, so --prompt-notes doesn't ask for notes.");
+        return false;
+    }
+    true
+}
+
+fn non_finite(matches: &ArgMatches) -> NonFinite {
+    match matches.get_one::<String>("on-nonfinite").map(String::as_str) {
+        Some("reject") => NonFinite::Reject,
+        _ => NonFinite::Keep,
+    }
+}
+
+// Waits for the first data line on the port and takes the number of fields
+// from it, naming them Value1..N unless --names does; returns which of them
+// are numbers
+fn detect_fields(format: &mut LineFormat, matches: &ArgMatches, port: &str, baud: u32) -> Vec<bool> {
+    say!("Waiting for the first data line on {} to detect the fields...", port);
+    let strip_ansi = !matches.get_flag("keep-ansi");
+    let is_data = |line: &str| matches!(format.parse(line), Parsed::WrongCount { payload, .. } if !payload.is_empty());
+    let line = serial::first_line(port, baud, strip_ansi, format.delimiter, is_data)
+        .unwrap_or_else(|e| panic!("Failed to open serial port {}", e));
+    let Parsed::WrongCount { got, .. } = format.parse(&line) else {
+        unreachable!("The detected line is a data line")
+    };
+    format.fields = got;
+    let fields: Vec<String> = match format.parse(&line) {
+        Parsed::Fields(fields) => fields.into_iter().map(str::to_string).collect(),
+        Parsed::Decoded(fields) => fields,
+        _ => unreachable!("The detected line has the detected fields"),
+    };
+    let number = Schema {
+        columns: vec![ColumnType::Float { bits: 64, decimals: None }],
+        non_finite: NonFinite::Keep,
+    };
+    let numeric: Vec<bool> = fields
+        .iter()
+        .map(|field| number.coerce(&[field], format.decimal_comma, FloatFormat::Passthrough).is_ok())
+        .collect();
+    format.names = Some((1..=got).map(|i| format!("Value{}", i)).collect());
+    let kinds: Vec<&str> = numeric.iter().map(|numeric| if *numeric { "number" } else { "text" }).collect();
+    say!("Detected {} fields ({}) from: {}", got, kinds.join(", "), line);
+    numeric
+}
+
+// The input options of one of several ports read at once, which leave out
+// everything that acts on a single port
+fn port_input(matches: &ArgMatches, format: LineFormat, layout: multiport::Layout, rotate: Option<rotate::Rotate>) -> InputOptions {
+    InputOptions {
+        format,
+        strip_ansi: !matches.get_flag("keep-ansi"),
+        skip_blank_lines: !matches.get_flag("keep-blank-lines"),
+        skip_lines: skip_lines(matches),
+        read_timeout: read_timeout(matches),
+        wait_for: None,
+        auto_start: None,
+        device_commands: None,
+        hw_trigger: None,
+        log_breaks: false,
+        observers: Vec::new(),
+        max_line_length: max_line_length(matches),
+        gap_marker: gap_marker(matches),
+        modem_interval: None,
+        port: Some(layout),
+        rotate,
+    }
+}
+
+fn max_line_length(matches: &ArgMatches) -> usize {
+    matches
+        .get_one::<String>("max-line-length")
+        .expect("Max line length has a default value")
         .parse()
-        .expect("Failed to parse baud rate");
-    let output_path = matches.get_one::<String>("output").expect("Output path has a default value");
-    
-    // Create output directory if it doesn't exist
-    if let Some(parent) = PathBuf::from(output_path).parent() {
-        if !parent.exists() {
-            std::fs::create_dir_all(parent)
-                .unwrap_or_else(|e| panic!("Failed to create output directory: {}", e));
+        .ok()
+        .filter(|bytes| *bytes > 0)
+        .expect("Failed to parse --max-line-length: expected a number of bytes")
+}
+
+fn gap_marker(matches: &ArgMatches) -> Option<std::time::Duration> {
+    matches.get_one::<String>("gap-marker").map(|secs| {
+        let secs: f64 = secs.parse().ok().filter(|secs| *secs > 0.0).expect("Failed to parse --gap-marker seconds");
+        std::time::Duration::from_secs_f64(secs)
+    })
+}
+
+fn caps_from_args(matches: &ArgMatches) -> Caps {
+    Caps {
+        max_duration: matches
+            .get_one::<String>("max-duration")
+            .map(|text| units::parse_duration(text).map_err(|e| format!("--max-duration: {}", e)))
+            .transpose()
+            .unwrap_or_else(|e| panic!("{}", e)),
+        max_size: matches
+            .get_one::<String>("max-file-size")
+            .map(|text| units::parse_size(text).map_err(|e| format!("--max-file-size: {}", e)))
+            .transpose()
+            .unwrap_or_else(|e| panic!("{}", e)),
+        action: caps::Action::parse(matches.get_one::<String>("cap-action").expect("Cap action has a default value"))
+            .unwrap_or_else(|e| panic!("{}", e)),
+    }
+}
+
+fn run_policy_from_args(matches: &ArgMatches) -> holdoff::Policy {
+    let secs = |id: &str| {
+        matches.get_one::<String>(id).map(|secs| {
+            let secs: f64 = secs
+                .parse()
+                .ok()
+                .filter(|secs| *secs > 0.0)
+                .unwrap_or_else(|| panic!("Failed to parse --{}: expected a positive number of seconds", id));
+            std::time::Duration::from_secs_f64(secs)
+        })
+    };
+    holdoff::Policy {
+        min_duration: secs("min-run-duration"),
+        short_run: holdoff::ShortRunAction::parse(
+            matches.get_one::<String>("short-run-action").expect("Short run action has a default value"),
+        )
+        .unwrap_or_else(|e| panic!("{}", e)),
+        holdoff: secs("start-holdoff"),
+        holdoff_action: holdoff::HoldoffAction::parse(
+            matches.get_one::<String>("holdoff-action").expect("Holdoff action has a default value"),
+        )
+        .unwrap_or_else(|e| panic!("{}", e)),
+    }
+}
+
+fn recovery_from_args(matches: &ArgMatches) -> outage::Recovery {
+    outage::Recovery {
+        error_limit: matches
+            .get_one::<String>("write-error-limit")
+            .expect("Write error limit has a default value")
+            .parse()
+            .ok()
+            .filter(|limit| *limit > 0)
+            .expect("Failed to parse --write-error-limit: expected a number of writes"),
+        buffer: matches
+            .get_one::<String>("outage-buffer")
+            .expect("Outage buffer has a default value")
+            .parse()
+            .expect("Failed to parse --outage-buffer: expected a number of rows"),
+        fallback: matches.get_one::<String>("fallback-output").cloned(),
+    }
+}
+
+fn skip_lines(matches: &ArgMatches) -> u64 {
+    matches
+        .get_one::<String>("skip-lines")
+        .expect("Skip lines has a default value")
+        .parse()
+        .expect("Failed to parse --skip-lines")
+}
+
+// --log-on-change with --change-epsilon and --keepalive, checked against the
+// fields of a row as written
+fn change_policy(matches: &ArgMatches, format: &LineFormat) -> Option<change::Policy> {
+    let fields = matches.get_one::<String>("log-on-change")?;
+    let fields = change::Policy::parse_fields(fields, format.field_count()).unwrap_or_else(|e| panic!("{}", e));
+    let epsilon = matches.get_one::<String>("change-epsilon").map_or(0.0, |delta| {
+        delta
+            .parse::<f64>()
+            .ok()
+            .filter(|delta| *delta >= 0.0)
+            .expect("Failed to parse --change-epsilon: expected a number of 0 or more")
+    });
+    let keepalive = matches.get_one::<String>("keepalive").map(|secs| {
+        let secs: f64 = secs.parse().ok().filter(|secs| *secs > 0.0).expect("Failed to parse --keepalive seconds");
+        std::time::Duration::from_secs_f64(secs)
+    });
+    Some(change::Policy { fields, epsilon, keepalive })
+}
+
+// --clock-step, unless 0
+fn clock_step(matches: &ArgMatches) -> Option<std::time::Duration> {
+    let secs = matches.get_one::<String>("clock-step").expect("Clock step has a default value");
+    let secs: f64 = secs.parse().ok().filter(|secs: &f64| *secs >= 0.0).expect("Failed to parse --clock-step seconds");
+    (secs > 0.0).then(|| std::time::Duration::from_secs_f64(secs))
+}
+
+// --xlsx-max-rows if --xlsx is given; a dry run writes no workbooks
+fn xlsx_max_rows(matches: &ArgMatches, dry_run: bool) -> Option<usize> {
+    if !matches.get_flag("xlsx") || dry_run {
+        return None;
+    }
+    let max_rows = matches.get_one::<String>("xlsx-max-rows").map_or(xlsx::DEFAULT_MAX_ROWS, |rows| {
+        rows.parse()
+            .ok()
+            .filter(|rows| (1..=xlsx::SHEET_ROWS).contains(rows))
+            .unwrap_or_else(|| panic!("Invalid --xlsx-max-rows '{}': expected 1 to {}", rows, xlsx::SHEET_ROWS))
+    });
+    Some(max_rows)
+}
+
+// The ring of rows for `tail`, unless --tail-buffer is 0
+fn tail_buffer(matches: &ArgMatches) -> Option<std::sync::Arc<std::sync::Mutex<tail::Tail>>> {
+    let rows: usize = matches
+        .get_one::<String>("tail-buffer")
+        .expect("Tail buffer has a default value")
+        .parse()
+        .expect("Failed to parse --tail-buffer rows");
+    if rows > tail::MAX_ROWS {
+        panic!("--tail-buffer must be at most {} rows, got {}", tail::MAX_ROWS, rows);
+    }
+    (rows > 0).then(|| std::sync::Arc::new(std::sync::Mutex::new(tail::Tail::new(rows))))
+}
+
+// --read-timeout, 10 ms to 10 s
+fn read_timeout(matches: &ArgMatches) -> std::time::Duration {
+    let ms: u64 = matches
+        .get_one::<String>("read-timeout")
+        .expect("Read timeout has a default value")
+        .parse()
+        .expect("Failed to parse --read-timeout milliseconds");
+    if !(10..=10_000).contains(&ms) {
+        panic!("--read-timeout must be between 10 and 10000 ms, got {}", ms);
+    }
+    std::time::Duration::from_millis(ms)
+}
+
+// Names and values, e.g. metadata entries
+type Pairs = Vec<(String, String)>;
+
+// The --meta pairs and the constant columns; --operator and --experiment go
+// to one or the other depending on --id-columns
+fn meta_and_ids(matches: &ArgMatches) -> (Pairs, Pairs) {
+    let mut user_meta: Vec<(String, String)> = matches
+        .get_many::<String>("meta")
+        .unwrap_or_default()
+        .map(|arg| meta::parse_pair(arg).unwrap_or_else(|e| panic!("{}", e)))
+        .collect();
+    let ids: Vec<(&str, String)> = [("Operator", "operator"), ("Experiment", "experiment")]
+        .into_iter()
+        .filter_map(|(column, arg)| matches.get_one::<String>(arg).map(|value| (column, value.clone())))
+        .collect();
+    let id_columns: Vec<(String, String)> = match matches.get_one::<String>("id-columns").map(String::as_str) {
+        Some("rows") => ids.into_iter().map(|(column, value)| (column.to_string(), value)).collect(),
+        Some("meta") => {
+            user_meta.extend(ids.into_iter().map(|(column, value)| (column.to_lowercase(), value)));
+            Vec::new()
         }
+        other => panic!("Invalid --id-columns '{}': expected rows or meta", other.unwrap_or_default()),
+    };
+    (user_meta, id_columns)
+}
+
+// The --units of the columns; any column of the row may have one: payload,
+// computed or constant
+fn column_units(matches: &ArgMatches, columns: &[String], id_columns: &[(String, String)]) -> Vec<(String, String)> {
+    let Some(arg) = matches.get_one::<String>("units") else { return Vec::new() };
+    let mut names = columns.to_vec();
+    names.extend(id_columns.iter().map(|(name, _)| name.clone()));
+    meta::parse_units(arg, &names).unwrap_or_else(|e| panic!("{}", e))
+}
+
+fn meta_style(matches: &ArgMatches) -> MetaStyle {
+    if matches.get_flag("no-meta") {
+        MetaStyle::Off
+    } else if matches.get_flag("meta-comments") {
+        MetaStyle::Comments
+    } else {
+        MetaStyle::Rows
     }
+}
 
-    // Initialize CSV writer and protect it with Mutex for thread-safe access
-    let csv_file = File::create(output_path)
-        .unwrap_or_else(|e| panic!("Failed to create CSV file at {}: {}", output_path, e));
-    let writer = Writer::from_writer(csv_file);
-    let writer = Arc::new(Mutex::new(writer));
+fn csv_dialect(matches: &ArgMatches) -> CsvDialect {
+    CsvDialect::parse(
+        matches.get_one::<String>("quote-style").expect("Quote style has a default value"),
+        matches.get_one::<String>("csv-terminator").expect("CSV terminator has a default value"),
+    )
+    .unwrap_or_else(|e| panic!("{}", e))
+}
 
-    // Write CSV headers
-    {
-        let mut w = writer.lock().unwrap();
-        let headers = vec!["Type", "Timestamp", "Run/End", "time (ms)", "X acc", "Y acc", "Z acc"];
-        w.write_record(&headers).expect("Failed to write CSV headers");
-        w.flush().expect("Failed to flush CSV writer");
-    }
-
-    // Shared atomic flag to control recording
-    let recording = Arc::new(AtomicBool::new(false));
-
-    // Clone for serial thread
-    let recording_clone = Arc::clone(&recording);
-    let writer_clone = Arc::clone(&writer);
-    let port_name_for_thread = port_name.clone();
-
-    // Spawn serial thread to handle incoming serial data
-    let _serial_thread = thread::spawn(move || {
-        // Open the serial port
-        let port = serialport::new(&port_name_for_thread, baud_rate)
-            .timeout(Duration::from_millis(100))
-            .open()
-            .unwrap_or_else(|e| panic!("Failed to open serial port {}: {}", port_name_for_thread, e));
-
-        let mut reader = BufReader::new(port);
-        let mut buffer = String::new();
-
-        loop {
-            buffer.clear();
-            // Read a line from the serial port
-            match reader.read_line(&mut buffer) {
-                Ok(bytes_read) => {
-                    if bytes_read == 0 {
-                        // No data read; continue
-                        continue;
-                    }
-
-                    // Clean the data by removing tab characters and trimming whitespace
-                    let data = buffer.trim().replace('\t', "").to_string();
-
-                    // Process only lines containing "UDP packet contents:"
-                    if data.contains("UDP packet contents:") {
-                        if recording_clone.load(Ordering::Acquire) {
-                            let timestamp = get_timestamp();
-
-                            // Extract the actual UDP contents after the colon
-                            if let Some((_, payload)) = data.split_once(':') {
-                                let payload = payload.trim(); // e.g., "7551870,-2.45,-3.69,-9.15"
-
-                                // Split the payload by commas
-                                let fields: Vec<&str> = payload.split(',').collect();
-
-                                // Ensure the payload has the expected number of fields (4)
-                                let expected_len = 4;
-                                if fields.len() == expected_len {
-                                    let record = vec![
-                                        "data",
-                                        &timestamp,
-                                        "",
-                                        fields[0],
-                                        fields[1],
-                                        fields[2],
-                                        fields[3],
-                                    ];
-
-                                    // Write the record to CSV
-                                    let mut w = writer_clone.lock().unwrap();
-                                    if let Err(e) = w.write_record(&record) {
-                                        eprintln!("Failed to write data record to CSV: {}", e);
-                                    }
-                                    if let Err(e) = w.flush() {
-                                        eprintln!("Failed to flush CSV writer: {}", e);
-                                    }
-                                } else {
-                                    eprintln!(
-                                        "Warning: Unexpected number of fields (expected {}, got {}). Data: {}",
-                                        expected_len,
-                                        fields.len(),
-                                        payload
-                                    );
-                                }
-                            } else {
-                                eprintln!("Warning: 'UDP packet contents:' not found in data: {}", data);
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error reading from serial port: {}", e);
-                }
-            }
+// Regenerates a CSV from a raw capture and reports what it contains
+fn reparse(matches: &ArgMatches) {
+    let input = matches.get_one::<String>("raw").expect("Raw capture is required");
+    let (user_meta, id_columns) = meta_and_ids(matches);
+    let format = line_format(matches, None);
+    let units = column_units(matches, &format.column_names(), &id_columns);
+    let options = reparse::ReparseOptions {
+        output: matches.get_one::<String>("output").expect("Output is required").clone(),
+        format,
+        strip_ansi: !matches.get_flag("keep-ansi"),
+        skip_blank_lines: !matches.get_flag("keep-blank-lines"),
+        skip_lines: skip_lines(matches),
+        wait_for: matches.get_one::<String>("wait-for").cloned(),
+        log_breaks: matches.get_flag("log-breaks"),
+        meta_style: meta_style(matches),
+        user_meta,
+        id_columns,
+        units,
+        dialect: csv_dialect(matches),
+    };
+    let report = reparse::run(std::path::Path::new(input), &options).unwrap_or_else(|e| panic!("{}", e));
+    say!(
+        "Reparsed {} lines from {} into {}: {} runs, {} data rows.",
+        report.lines,
+        input,
+        options.output,
+        report.runs,
+        report.rows
+    );
+    if report.blank > 0 {
+        say!("{} blank lines were skipped.", report.blank);
+    }
+    if report.rejected > 0 {
+        say!("{} data lines had the wrong number of fields.", report.rejected);
+    }
+    if report.unquotable > 0 {
+        say!("{} rows were not written because they would need quoting.", report.unquotable);
+    }
+    if report.compute_errors > 0 {
+        say!("{} computed cells were left blank because the expression failed.", report.compute_errors);
+    }
+    if report.bad_frames > 0 {
+        say!("{} bad binary frames were skipped.", report.bad_frames);
+    }
+    if report.bad_payloads > 0 {
+        say!("{} payloads could not be decoded.", report.bad_payloads);
+    }
+    if report.type_errors > 0 {
+        say!("{} rows were not written because a field didn't match --types.", report.type_errors);
+    }
+    if report.invalid_entries > 0 {
+        say!("{} capture lines could not be read.", report.invalid_entries);
+    }
+}
+
+// Repairs an output file left behind by a crash and reports what was done
+fn repair(matches: &ArgMatches) {
+    let path = matches.get_one::<String>("csv").expect("CSV is required");
+    let repair = repair::run(std::path::Path::new(path)).unwrap_or_else(|e| panic!("{}", e));
+    if repair.is_empty() {
+        say!("{} is intact; nothing to repair.", path);
+        return;
+    }
+    if repair.truncated > 0 {
+        say!("Dropped a partial last line ({} bytes).", repair.truncated);
+    }
+    for (run, timestamp) in &repair.stops {
+        say!("Added a recovered stop marker for {} at {}.", run, timestamp);
+    }
+    say!("Repaired {}.", path);
+}
+
+// Converts an output file to JSON and reports what it contained
+fn convert(matches: &ArgMatches) {
+    let input = matches.get_one::<String>("csv").expect("CSV is required");
+    let options = convert::ConvertOptions {
+        output: matches.get_one::<String>("output").expect("Output is required").clone(),
+        format: convert::Format::parse(matches.get_one::<String>("to").expect("Format has a default value"))
+            .unwrap_or_else(|e| panic!("{}", e)),
+        shape: convert::Shape::parse(matches.get_one::<String>("shape").expect("Shape has a default value"))
+            .unwrap_or_else(|e| panic!("{}", e)),
+        lenient: matches.get_flag("lenient"),
+    };
+    let report = convert::run(std::path::Path::new(input), &options).unwrap_or_else(|e| panic!("{}", e));
+    say!(
+        "Converted {} into {}: {} runs, {} data rows.",
+        input,
+        options.output,
+        report.runs,
+        report.rows
+    );
+    if report.skipped > 0 {
+        say!("{} rows could not be read and were skipped.", report.skipped);
+    }
+}
+
+// Prints the statistics of every run in an output file
+fn analyze(matches: &ArgMatches) {
+    let input = matches.get_one::<String>("csv").expect("CSV is required");
+    let gap: f64 = matches
+        .get_one::<String>("gap")
+        .expect("Gap has a default value")
+        .parse()
+        .ok()
+        .filter(|secs: &f64| *secs >= 0.0)
+        .expect("Failed to parse --gap seconds");
+    let analysis = analyze::run(std::path::Path::new(input), gap).unwrap_or_else(|e| panic!("{}", e));
+    if matches.get_flag("json") {
+        say!("{}", serde_json::to_string_pretty(&analysis).expect("Analysis always serializes"));
+    } else {
+        analysis.print();
+    }
+}
+
+// Splits an output file into one file per run and lists them
+fn split(matches: &ArgMatches) {
+    let input = matches.get_one::<String>("csv").expect("CSV is required");
+    let options = split::SplitOptions {
+        out_dir: std::path::PathBuf::from(matches.get_one::<String>("out-dir").expect("Out dir is required")),
+        keep_markers: matches.get_flag("keep-markers"),
+        skip_empty: matches.get_flag("skip-empty"),
+    };
+    let report = split::run(std::path::Path::new(input), &options).unwrap_or_else(|e| panic!("{}", e));
+    for (path, rows) in &report.files {
+        say!("{:>8} rows  {}", rows, path.display());
+    }
+    say!("Split {} into {} files in {}.", input, report.files.len(), options.out_dir.display());
+    if report.skipped_empty > 0 {
+        say!("{} runs without data rows were skipped.", report.skipped_empty);
+    }
+    if report.truncated > 0 {
+        say!("{} runs had no stop row.", report.truncated);
+    }
+    if report.rows_outside_runs > 0 {
+        say!("{} data rows outside any run were left out.", report.rows_outside_runs);
+    }
+}
+
+fn extract(matches: &ArgMatches) {
+    let input = matches.get_one::<String>("csv").expect("CSV is required");
+    let output = matches.get_one::<String>("output").expect("Output is required");
+    let bound = |name: &str| {
+        let text = matches.get_one::<String>(name).expect("The window is required");
+        extract::Bound::parse(text, &format!("--{}", name)).unwrap_or_else(|e| panic!("{}", e))
+    };
+    let options = extract::ExtractOptions {
+        from: bound("from"),
+        to: bound("to"),
+        synthesize_markers: matches.get_flag("synthesize-markers"),
+    };
+    let report = extract::run(std::path::Path::new(input), std::path::Path::new(output), &options)
+        .unwrap_or_else(|e| panic!("{}", e));
+    say!(
+        "Extracted {} data rows and {} marker rows from {} to {} into {}.",
+        report.data_rows,
+        report.markers,
+        report.from,
+        report.to,
+        output
+    );
+    if report.synthesized > 0 {
+        say!("{} start and stop rows were added at the ends of the window.", report.synthesized);
+    }
+    if report.unreadable > 0 {
+        say!("{} rows without a readable timestamp were left out.", report.unreadable);
+    }
+    if report.data_rows == 0 {
+        esay!("Warning: No data rows of {} fall in the window.", input);
+    }
+}
+
+fn merge(matches: &ArgMatches) {
+    let inputs: Vec<merge::Input> = matches
+        .get_many::<String>("csv")
+        .expect("CSV is required")
+        .map(|arg| merge::Input::parse(arg))
+        .collect();
+    let output = matches.get_one::<String>("output").expect("Output is required");
+    let report = merge::run(&inputs, std::path::Path::new(output), matches.get_flag("lenient"))
+        .unwrap_or_else(|e| panic!("{}", e));
+    for (input, rows) in inputs.iter().zip(&report.rows) {
+        say!("{:>8} rows  {} ({})", rows, input.path.display(), input.label);
+    }
+    say!("Merged {} files with {} runs into {}.", inputs.len(), report.runs, output);
+    if report.dropped > 0 {
+        say!("{} rows without a readable timestamp were dropped.", report.dropped);
+    }
+}
+
+// Re-checks a manifest and exits non-zero if any file doesn't match
+fn verify(matches: &ArgMatches) {
+    let path = matches.get_one::<String>("manifest").expect("Manifest is required");
+    let results = manifest::verify(std::path::Path::new(path)).unwrap_or_else(|e| panic!("{}", e));
+    let mut failed = 0;
+    for (name, status) in &results {
+        match status {
+            manifest::Status::Ok => say!("{}: OK", name),
+            manifest::Status::Mismatch => say!("{}: FAILED", name),
+            manifest::Status::Missing => say!("{}: MISSING", name),
+            manifest::Status::Unreadable(e) => say!("{}: UNREADABLE ({})", name, e),
         }
-    });
+        if *status != manifest::Status::Ok {
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        esay!("{} of {} files in {} failed verification.", failed, results.len(), path);
+        std::process::exit(EXIT_VERIFY_FAILED);
+    }
+    say!("All {} files in {} verified.", results.len(), path);
+}
 
-    // Main thread: handle user commands
-    let mut run_num :i64 = 0;
-    loop {
-        println!("Enter a command (start, stop, exit):");
-        let mut command = String::new();
-        if let Err(e) = std::io::stdin().read_line(&mut command) {
-            eprintln!("Failed to read input: {}", e);
-            continue;
-        }
-        let command = command.trim();
-
-        match command {
-            "start" => {
-                if !recording.load(Ordering::Relaxed) {
-                    recording.store(true, Ordering::Relaxed);
-                    println!("Recording started.");
-
-                    // Write start marker to CSV
-                    let timestamp = get_timestamp();
-                    let run_str = format!("run {}", run_num); // You can implement run numbering if needed
-                    run_num += 1;
-                    let start_record = vec!["start", &timestamp, &run_str, "", "", "", ""];
-                    let mut w = writer.lock().unwrap();
-                    if let Err(e) = w.write_record(&start_record) {
-                        eprintln!("Failed to write start record to CSV: {}", e);
-                    }
-                    if let Err(e) = w.flush() {
-                        eprintln!("Failed to flush CSV writer: {}", e);
-                    }
-                } else {
-                    println!("Recording is already started.");
-                }
-            }
-            "stop" => {
-                if recording.load(Ordering::Relaxed) {
-                    recording.store(false, Ordering::Relaxed);
-                    println!("Recording stopped.");
-
-                    // Write stop marker to CSV
-                    let timestamp = get_timestamp();
-                    let stop_record = vec!["stop", &timestamp, "end of run", "", "", "", ""];
-                    let mut w = writer.lock().unwrap();
-                    if let Err(e) = w.write_record(&stop_record) {
-                        eprintln!("Failed to write stop record to CSV: {}", e);
-                    }
-                    if let Err(e) = w.flush() {
-                        eprintln!("Failed to flush CSV writer: {}", e);
-                    }
-                } else {
-                    println!("Recording is not active.");
-                }
-            }
-            "exit" => {
-                println!("Exiting...");
-
-                // If recording is active, stop it first
-                if recording.load(Ordering::Relaxed) {
-                    recording.store(false, Ordering::Relaxed);
-                    println!("Recording stopped.");
-
-                    // Write stop marker to CSV
-                    let timestamp = get_timestamp();
-                    let stop_record = vec!["stop", &timestamp, "end of run", "", "", "", ""];
-                    let mut w = writer.lock().unwrap();
-                    if let Err(e) = w.write_record(&stop_record) {
-                        eprintln!("Failed to write stop record to CSV: {}", e);
-                    }
-                    if let Err(e) = w.flush() {
-                        eprintln!("Failed to flush CSV writer: {}", e);
-                    }
-                }
+// The name the program was run as, which completions are registered for
+fn bin_name() -> String {
+    std::env::args()
+        .next()
+        .and_then(|arg0| std::path::Path::new(&arg0).file_name().map(|name| name.to_string_lossy().to_string()))
+        .unwrap_or_else(|| "serial_logger".to_string())
+}
 
-                // Terminate the program
-                // Note: This will forcibly terminate the serial thread
-                std::process::exit(0);
-            }
-            _ => {
-                println!("Unknown command. Use 'start', 'stop', or 'exit'.");
+fn completions(matches: &ArgMatches) {
+    let shell = *matches.get_one::<clap_complete::Shell>("shell").expect("Shell is required");
+    let bin_name = bin_name();
+    clap_complete::generate(shell, &mut cli(), &bin_name, &mut std::io::stdout());
+}
+
+fn manpage() {
+    let command = cli().name(bin_name());
+    clap_mangen::Man::new(command)
+        .render(&mut std::io::stdout())
+        .unwrap_or_else(|e| panic!("Failed to write the man page: {}", e));
+}
+
+fn service_name_arg() -> Arg {
+    Arg::new("name")
+        .long("name")
+        .value_name("NAME")
+        .help("Name of the service")
+        .default_value("SerialLogger")
+}
+
+fn service(matches: &ArgMatches) {
+    match matches.subcommand() {
+        Some(("install", sub)) => {
+            let name = sub.get_one::<String>("name").expect("Name has a default value");
+            let options: Vec<std::ffi::OsString> = sub.get_many::<String>("options").into_iter().flatten().map(Into::into).collect();
+            // Mistakes show now rather than when the service starts
+            let args = std::iter::once(bin_name().into()).chain(options.iter().cloned()).collect();
+            let args = config::expand_args(args).unwrap_or_else(|e| panic!("{}", e));
+            match cli().try_get_matches_from(&args) {
+                Ok(checked) if checked.subcommand().is_none() && !checked.contains_id("service") => check_options(&checked, &args),
+                Ok(_) => panic!("The service's options can't include a subcommand or --service"),
+                Err(e) => e.exit(),
             }
+            service::install(name, options).unwrap_or_else(|e| panic!("{}", e));
+            say!("Installed service {}; it starts at boot, or now with: sc start {}", name, name);
+        }
+        Some(("uninstall", sub)) => {
+            let name = sub.get_one::<String>("name").expect("Name has a default value");
+            service::uninstall(name).unwrap_or_else(|e| panic!("{}", e));
+            say!("Uninstalled service {}.", name);
         }
+        _ => unreachable!("A service subcommand is required"),
     }
 }
 
+fn setup(matches: &ArgMatches) {
+    let config = matches.get_one::<String>("config").expect("Config has a default value");
+    let (config, start) = setup::run(std::path::Path::new(config)).unwrap_or_else(|e| panic!("{}", e));
+    let program = std::env::args().next().unwrap_or_else(|| "serial_logger".to_string());
+    if !start {
+        say!("Start logging with: {} --config {}", program, config.display());
+        return;
+    }
+    let exe = std::env::current_exe().unwrap_or_else(|e| panic!("Failed to find this program to restart it: {}", e));
+    let status = std::process::Command::new(exe)
+        .arg("--config")
+        .arg(&config)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to start logging: {}", e));
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+// How input lines are cleaned up and parsed; shared by logging and reparse
+fn input_args() -> Vec<Arg> {
+    vec![
+        Arg::new("match")
+            .long("match")
+            .value_name("TEXT")
+            .help("Only lines containing TEXT are data; the payload follows it")
+            .default_value("UDP packet contents:"),
+        Arg::new("delimiter")
+            .long("delimiter")
+            .value_name("CHAR")
+            .help("Character separating payload fields (or tab/space)")
+            .default_value(","),
+        Arg::new("fields")
+            .long("fields")
+            .value_name("N")
+            .help("Number of fields in each payload, or auto to take it from the first data line")
+            .default_value("4"),
+        Arg::new("names")
+            .long("names")
+            .value_name("NAMES")
+            .help("Comma-separated header names of the payload fields (e.g. t,ax,ay,az)"),
+        Arg::new("select")
+            .long("select")
+            .value_name("INDICES")
+            .help("Keep only these payload fields, in this order (0-based, e.g. 0,5,2)"),
+        Arg::new("types")
+            .long("types")
+            .value_name("TYPES")
+            .help("Type of each field, to check and normalize it (e.g. i64,f32:3,f32:3,f32:3)"),
+        Arg::new("on-nonfinite")
+            .long("on-nonfinite")
+            .value_name("ACTION")
+            .help("nan and inf in --types float fields: keep them (written as nan, inf, -inf) or reject the row")
+            .value_parser(["keep", "reject"])
+            .default_value("keep"),
+        Arg::new("float-format")
+            .long("float-format")
+            .value_name("FORMAT")
+            .help("How floats in --types float fields and computed columns are written: passthrough, fixed:<decimals> or sig:<digits>")
+            .default_value("passthrough"),
+        Arg::new("decimal-comma")
+            .long("decimal-comma")
+            .help("Read numbers in fields with a decimal comma (-2,45) for computed columns, alarms and statistics")
+            .action(ArgAction::SetTrue),
+        Arg::new("compute")
+            .long("compute")
+            .value_name("NAME=EXPR")
+            .help("Add a column computed from the fields f0, f1, ... (e.g. mag=sqrt(f1*f1+f2*f2+f3*f3)); repeatable")
+            .action(ArgAction::Append),
+        Arg::new("protocol")
+            .long("protocol")
+            .value_name("PROTOCOL")
+            .help("How the device sends data: text lines or fixed-length binary frames")
+            .value_parser(["text", "binary"])
+            .default_value("text"),
+        Arg::new("frame-spec")
+            .long("frame-spec")
+            .value_name("SPEC|FILE")
+            .help("Layout of binary frames (e.g. sync=0xAA55,len=32,fields=u32,f32*7,endian=le), or a file with it"),
+        Arg::new("payload-encoding")
+            .long("payload-encoding")
+            .value_name("ENCODING")
+            .help("How the payload after the match text is encoded: plain, hex or base64")
+            .value_parser(["plain", "hex", "base64"])
+            .default_value("plain"),
+        Arg::new("inner-protocol")
+            .long("inner-protocol")
+            .value_name("PROTOCOL")
+            .help("What a hex or base64 payload holds: a text record or a binary frame (with --frame-spec)")
+            .value_parser(["text", "binary"])
+            .default_value("text"),
+        Arg::new("framing")
+            .long("framing")
+            .value_name("FRAMING")
+            .help("How packets are delimited: plain (newlines or sync words) or cobs (COBS-encoded, ending in a zero byte)")
+            .value_parser(["plain", "cobs"])
+            .default_value("plain"),
+        Arg::new("keep-ansi")
+            .long("keep-ansi")
+            .help("Keep terminal escape sequences (colors etc.) in lines instead of removing them")
+            .action(ArgAction::SetTrue),
+        Arg::new("keep-blank-lines")
+            .long("keep-blank-lines")
+            .help("Pass lines of only whitespace on to matching instead of dropping them")
+            .action(ArgAction::SetTrue),
+        Arg::new("skip-lines")
+            .long("skip-lines")
+            .value_name("N")
+            .help("Ignore the first N lines after the port is opened (e.g., a boot banner)")
+            .default_value("0"),
+        Arg::new("wait-for")
+            .long("wait-for")
+            .value_name("MARKER")
+            .help("Ignore everything until a line containing MARKER arrives (e.g., READY)"),
+        Arg::new("log-breaks")
+            .long("log-breaks")
+            .help("Write a break row whenever the device sends a serial break while recording")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+// How the CSV is laid out and what metadata it carries; shared by logging and reparse
+fn csv_args() -> Vec<Arg> {
+    vec![
+        Arg::new("meta")
+            .long("meta")
+            .value_name("KEY=VALUE")
+            .help("Extra metadata recorded at the top of each output file (repeatable)")
+            .action(ArgAction::Append),
+        Arg::new("units")
+            .long("units")
+            .value_name("COLUMN=UNIT,...")
+            .help("Units of the columns, written as a units row after the header and in sidecars (e.g., counter=ms,ax=g)"),
+        Arg::new("operator")
+            .long("operator")
+            .value_name("NAME")
+            .help("Operator recorded with the data (see --id-columns)"),
+        Arg::new("experiment")
+            .long("experiment")
+            .value_name("ID")
+            .help("Experiment ID recorded with the data (see --id-columns)"),
+        Arg::new("id-columns")
+            .long("id-columns")
+            .value_name("WHERE")
+            .help("Record --operator/--experiment as columns on every data row (rows) or once in the metadata (meta)")
+            .default_value("rows"),
+        Arg::new("meta-comments")
+            .long("meta-comments")
+            .help("Write metadata as '# key: value' comment lines instead of meta rows")
+            .action(ArgAction::SetTrue),
+        Arg::new("no-meta")
+            .long("no-meta")
+            .help("Don't write metadata; the CSV header is the first line")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("meta-comments"),
+        Arg::new("quote-style")
+            .long("quote-style")
+            .value_name("STYLE")
+            .help("Quote CSV fields: necessary, always or never (rows that would need quotes are refused)")
+            .default_value("necessary"),
+        Arg::new("csv-terminator")
+            .long("csv-terminator")
+            .value_name("ENDING")
+            .help("Line ending of CSV records: lf or crlf")
+            .default_value("lf"),
+    ]
+}
+
+// --format arrow, parquet or hdf5, with the rows per batch, row group or
+// chunk
+fn columnar_output(matches: &ArgMatches, format: &LineFormat) -> Option<ColumnarOutput> {
+    let (kind, rows_arg) = match matches.get_one::<String>("format").map(String::as_str) {
+        Some("arrow") => (Columnar::Arrow, "arrow-batch"),
+        Some("parquet") => (Columnar::Parquet, "row-group"),
+        #[cfg(feature = "hdf5")]
+        Some("hdf5") => (Columnar::Hdf5, "hdf5-chunk"),
+        #[cfg(not(feature = "hdf5"))]
+        Some("hdf5") => panic!("--format hdf5 needs a build with the 'hdf5' feature"),
+        _ => return None,
+    };
+    let batch_rows = matches
+        .get_one::<String>(rows_arg)
+        .expect("Rows have a default value")
+        .parse()
+        .ok()
+        .filter(|rows| *rows > 0)
+        .unwrap_or_else(|| panic!("Failed to parse --{}: expected a number of rows above 0", rows_arg));
+    Some(ColumnarOutput {
+        format: kind,
+        column_types: arrow::column_types(format),
+        #[cfg(feature = "hdf5")]
+        number_types: collect_acc_data::hdf5::column_types(format),
+        batch_rows,
+    })
+}
+
+// Runs the logger on a simulated device at increasing rates and reports how
+// long rows take to be written and which rates it keeps up with
+fn benchmark(matches: &ArgMatches) {
+    let options = benchmark::Options {
+        format: line_format(matches, None),
+        strip_ansi: !matches.get_flag("keep-ansi"),
+        sync: SyncPolicy::parse(matches.get_one::<String>("sync").expect("Sync has a default value"))
+            .unwrap_or_else(|e| panic!("{}", e)),
+        dialect: csv_dialect(matches),
+        meta_style: meta_style(matches),
+        raw: !matches.get_flag("no-raw"),
+    };
+    let report = benchmark::run(options).unwrap_or_else(|e| panic!("{}", e));
+    report.print();
+    if let Some(path) = matches.get_one::<String>("benchmark-json") {
+        report.write_json(path).unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+// Reads from the port for a while and reports how its output could be parsed;
+// nothing is written
+fn probe(port_name: &str, baud_rate: u32, duration: std::time::Duration, strip_ansi: bool) {
+    say!("Probing {} at {} baud for {:.1}s...", port_name, baud_rate, duration.as_secs_f64());
+    let data = serial::capture(port_name, baud_rate, duration)
+        .unwrap_or_else(|e| panic!("Failed to open serial port {}", e));
+    let report = collect_acc_data::probe::analyze(&data, strip_ansi);
+    say!("{}", report);
+    if report.lines == 0 {
+        say!("Nothing received; check the port, the baud rate and that the device is sending.");
+        return;
+    }
+    let program = std::env::args().next().unwrap_or_else(|| "serial_logger".to_string());
+    say!(
+        "Suggested: {} --port {} --baud {} {}",
+        program,
+        port_name,
+        baud_rate,
+        report.suggested_args().join(" ")
+    );
+}
+
+// Exit code when the options don't make sense together, as for clap's
+// usage errors
+const EXIT_INVALID_OPTIONS: i32 = 2;
+// Exit code when the output disk filled up during the session
+const EXIT_DISK_FULL: i32 = 3;
+// Exit code after a failed --strict check ended the session
+const EXIT_STRICT: i32 = 10;
+// Exit code after --max-duration or --max-file-size ended the session
+const EXIT_CAP_REACHED: i32 = 8;
+// Exit code when rows were lost because the output couldn't be written
+const EXIT_ROWS_LOST: i32 = 9;
+// Exit code of `verify` when a file doesn't match its manifest entry
+const EXIT_VERIFY_FAILED: i32 = 1;
+
+// How long shutdown waits for hooks that are still running
+const HOOK_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+// How long shutdown waits for webhook notifications still being sent
+const NOTIFY_GRACE: std::time::Duration = std::time::Duration::from_secs(10);
+// How long shutdown waits for finished files still being compressed
+const COMPRESS_GRACE: std::time::Duration = std::time::Duration::from_secs(30);
+// How long shutdown waits for workbooks of finished runs still being written
+const XLSX_GRACE: std::time::Duration = std::time::Duration::from_secs(60);
+// How long shutdown waits for uploads; the rest resume at the next start
+const UPLOAD_GRACE: std::time::Duration = std::time::Duration::from_secs(10);
+
+// How long shutdown waits for messages queued for ZeroMQ subscribers
+#[cfg(feature = "zmq")]
+const ZMQ_GRACE: std::time::Duration = std::time::Duration::from_secs(2);
+
+// Stops any active run, reports the session and terminates the program
+// Note: This will forcibly terminate the serial thread
+fn shutdown(session: &Mutex<Session>, summary_file: Option<&str>, fail_on: &[FailOn]) -> ! {
+    // Nothing reads the prompt any more; the summary must not wait for it
+    console::detach();
+    statusline::clear();
+    let mut session = session.lock().unwrap();
+    let summary = session.finish();
+    summary.print();
+    let failed = failon::evaluate(fail_on, &summary);
+    let exit_code = if session.disk_filled() {
+        EXIT_DISK_FULL
+    } else if session.strict_failed().is_some() {
+        EXIT_STRICT
+    } else if session.cap_reached().is_some() {
+        EXIT_CAP_REACHED
+    } else if summary.rows_lost > 0 {
+        EXIT_ROWS_LOST
+    } else {
+        failed.unwrap_or(0)
+    };
+    if let Some(path) = summary_file {
+        if let Err(e) = summary.write_json(path) {
+            esay!("{}", e);
+        }
+    }
+    events::emit(Event::Shutdown {
+        runs: session.runs(),
+        exit_code,
+    });
+    hooks::wait_for_running(HOOK_GRACE);
+    notify::wait_for_pending(NOTIFY_GRACE);
+    compress::wait_for_pending(COMPRESS_GRACE);
+    xlsx::wait_for_pending(XLSX_GRACE);
+    upload::wait_for_pending(UPLOAD_GRACE);
+    #[cfg(feature = "zmq")]
+    zmq::wait_for_pending(ZMQ_GRACE);
+    mirror::remove_link();
+    logging::close();
+    service::stopped(exit_code);
+    std::process::exit(exit_code);
+}
+
 // Function to get the current timestamp in "YYYY-MM-DD HH:MM:SS" format
 fn get_timestamp() -> String {
-    let now = Local::now();
+    let now = timesource::now();
     now.format("%Y-%m-%d %H:%M:%S").to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ValueEnum;
+
+    #[test]
+    fn the_command_line_is_consistent() {
+        cli().debug_assert();
+    }
+
+    #[test]
+    fn completions_generate_for_every_shell() {
+        for shell in clap_complete::Shell::value_variants() {
+            let matches = cli().get_matches_from(["serial_logger", "completions", &shell.to_string()]);
+            let Some(("completions", sub)) = matches.subcommand() else { panic!("completions wasn't parsed") };
+            assert_eq!(sub.get_one::<clap_complete::Shell>("shell"), Some(shell));
+            let mut script = Vec::new();
+            clap_complete::generate(*shell, &mut cli(), "serial_logger", &mut script);
+            let script = String::from_utf8(script).unwrap();
+            for word in ["serial_logger", "max-line-length", "analyze"] {
+                assert!(script.contains(word), "{} completions lack {}", shell, word);
+            }
+        }
+    }
+
+    #[test]
+    fn the_man_page_renders() {
+        let mut page = Vec::new();
+        clap_mangen::Man::new(cli().name("serial_logger")).render(&mut page).unwrap();
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.starts_with(".ie"), "{}", &page[..80]);
+        assert!(page.contains("max\\-line\\-length"));
+    }
+}
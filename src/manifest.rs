@@ -0,0 +1,140 @@
+//! SHA-256 manifest of the output files, `MANIFEST.sha256`.
+//!
+//! At exit every file written this session is hashed and listed in the
+//! manifest in the output directory (the part of `--output` before any
+//! placeholder), in `sha256sum` format with paths relative to that
+//! directory, so `sha256sum -c MANIFEST.sha256` run there checks them. An
+//! existing manifest is appended to. With `--manifest-per-run` each run's
+//! file is listed as soon as it is closed instead. A file that
+//! `--compress-completed` has already replaced is hashed through its `.gz`,
+//! so the digest is always that of the CSV as written; `verify` checks such
+//! a file the same way.
+
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::compress;
+
+pub const FILE_NAME: &str = "MANIFEST.sha256";
+
+/// Where the manifest goes for an output template.
+pub fn default_path(template: &str) -> PathBuf {
+    crate::template::fixed_dir(template).join(FILE_NAME)
+}
+
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub path: PathBuf,
+    // List each run's file when it is closed rather than at exit
+    pub per_run: bool,
+}
+
+/// Result of checking one manifest entry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Ok,
+    Mismatch,
+    Missing,
+    Unreadable(String),
+}
+
+impl Manifest {
+    /// Hashes `files` and appends them to the manifest; failures are warned
+    /// about and leave the file out.
+    pub fn append(&self, files: &[&str]) {
+        let dir = self.path.parent().unwrap_or(Path::new(""));
+        let mut lines = String::new();
+        for file in files {
+            match hash_file(Path::new(file)) {
+                Ok(digest) => {
+                    let name = Path::new(file).strip_prefix(dir).unwrap_or(Path::new(file));
+                    lines.push_str(&format!("{}  {}\n", digest, name.display()));
+                }
+                Err(e) => esay!("Warning: {} is left out of {}: {}", file, self.path.display(), e),
+            }
+        }
+        if lines.is_empty() {
+            return;
+        }
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut manifest| {
+                manifest.write_all(lines.as_bytes())?;
+                manifest.sync_all()
+            });
+        match written {
+            Ok(()) => say!("Listed {} file(s) in {}.", lines.lines().count(), self.path.display()),
+            Err(e) => esay!("Warning: Failed to write {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+// Digest of a file as written, or of the content of its compressed copy
+fn hash_file(path: &Path) -> Result<String, String> {
+    match File::open(path) {
+        Ok(file) => digest_reader(BufReader::new(file)).map_err(|e| e.to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let compressed = File::open(compress::compressed_path(path)).map_err(|_| e.to_string())?;
+            digest_reader(flate2::read::GzDecoder::new(BufReader::new(compressed))).map_err(|e| e.to_string())
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// SHA-256 of everything read, in lowercase hex as `sha256sum` prints it;
+// hashed as it is read, so a large file never has to fit in memory
+fn digest_reader(mut reader: impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Checks every entry of the manifest at `path`, in order.
+pub fn verify(path: &Path) -> Result<Vec<(String, Status)>, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut results = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // `sha256sum` marks binary mode with `*` instead of the second space
+        let (digest, name) = line
+            .split_once("  ")
+            .or_else(|| line.split_once(" *"))
+            .filter(|(digest, _)| digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or_else(|| format!("{} line {} is not a sha256sum entry: {}", path.display(), number + 1, line))?;
+        let file = dir.join(name);
+        let status = if !file.exists() && !compress::compressed_path(&file).exists() {
+            Status::Missing
+        } else {
+            match hash_file(&file) {
+                Ok(actual) if actual.eq_ignore_ascii_case(digest) => Status::Ok,
+                Ok(_) => Status::Mismatch,
+                Err(e) => Status::Unreadable(e),
+            }
+        };
+        results.push((name.to_string(), status));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_match_sha256sum() {
+        assert_eq!(
+            digest_reader(&b"abc"[..]).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,294 @@
+//! The memory held in the logger's bounded buffers, and `--max-memory`.
+//!
+//! The buffers that can grow large are bounded by a number of rows: the
+//! `--prebuffer` ring, the rows of a run kept for its `--xlsx` workbook, the
+//! rows held through an output outage (`--outage-buffer`) and the ring of the
+//! `tail` command (`--tail-buffer`). At startup each gets a capacity in bytes,
+//! its rows times the size of a row of the file's columns with cells of
+//! `CELL_BYTES` of text, and the sum is printed as the worst case the
+//! configuration implies. `--max-memory` refuses a configuration whose sum is
+//! over the limit, and then also holds each buffer to its capacity, so rows
+//! with longer cells than assumed mean fewer rows kept rather than more
+//! memory. Every buffer reports the bytes it holds as they change; the status
+//! line, the Prometheus metrics and the gRPC status show the total.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use collect_acc_data::logfile::LEADING_HEADERS;
+
+use crate::prebuffer::{self, Limit};
+use crate::session::OutputSettings;
+use crate::tail;
+use crate::units::format_size;
+
+/// Text assumed per cell for the worst case: a number, a timestamp, most of
+/// a run ID.
+pub const CELL_BYTES: usize = 16;
+
+// What a row costs besides the text of its cells: the Vec of cells and each
+// cell's own String or Vec<u8>
+const ROW_OVERHEAD: usize = std::mem::size_of::<Vec<String>>();
+const CELL_OVERHEAD: usize = std::mem::size_of::<String>();
+
+/// A bounded buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Buffer {
+    Prebuffer,
+    Workbook,
+    Outage,
+    Tail,
+}
+
+impl Buffer {
+    pub const ALL: [Buffer; 4] = [Buffer::Prebuffer, Buffer::Workbook, Buffer::Outage, Buffer::Tail];
+
+    /// Name in the startup summary and the metrics' `buffer` label.
+    pub fn name(self) -> &'static str {
+        match self {
+            Buffer::Prebuffer => "prebuffer",
+            Buffer::Workbook => "xlsx",
+            Buffer::Outage => "outage",
+            Buffer::Tail => "tail",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+static CAPACITY: [AtomicUsize; 4] = [const { AtomicUsize::new(0) }; 4];
+static USED: [AtomicUsize; 4] = [const { AtomicUsize::new(0) }; 4];
+// --max-memory was given, so buffers are held to their capacity
+static ENFORCED: AtomicBool = AtomicBool::new(false);
+
+/// The capacities and the bytes used are global; tests that set or read them
+/// take turns through this lock.
+#[cfg(test)]
+pub static TEST_GLOBALS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Bytes a row of these cells takes in a buffer.
+pub fn row_bytes<T: AsRef<[u8]>>(cells: &[T]) -> usize {
+    ROW_OVERHEAD + cells.iter().map(|cell| CELL_OVERHEAD + cell.as_ref().len()).sum::<usize>()
+}
+
+/// Records the bytes `buffer` holds now.
+pub fn report(buffer: Buffer, bytes: usize) {
+    USED[buffer.index()].store(bytes, Ordering::Relaxed);
+}
+
+/// Bytes `buffer` holds.
+pub fn used(buffer: Buffer) -> usize {
+    USED[buffer.index()].load(Ordering::Relaxed)
+}
+
+/// Bytes held by all buffers.
+pub fn total_used() -> usize {
+    Buffer::ALL.into_iter().map(used).sum()
+}
+
+/// The most `buffer` may hold: its capacity with `--max-memory`, no limit
+/// otherwise.
+pub fn budget(buffer: Buffer) -> usize {
+    if ENFORCED.load(Ordering::Relaxed) {
+        CAPACITY[buffer.index()].load(Ordering::Relaxed)
+    } else {
+        usize::MAX
+    }
+}
+
+/// The worst case of one buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct Share {
+    pub buffer: Buffer,
+    pub rows: usize,
+    pub bytes: usize,
+}
+
+/// The buffers `settings` set up, with their worst cases.
+pub fn plan(settings: &OutputSettings) -> Vec<Share> {
+    let width = LEADING_HEADERS.len() + settings.columns.len() + settings.id_columns.len();
+    let row = ROW_OVERHEAD + width * (CELL_OVERHEAD + CELL_BYTES);
+    let prebuffer = settings.prebuffer.map(|limit| match limit {
+        Limit::Rows(rows) => rows,
+        Limit::Time(_) => prebuffer::MAX_ROWS,
+    });
+    // Only a CSV file is held through an outage, and a dry run writes none
+    let csv = settings.columnar.is_none() && !settings.dry_run;
+    let outage = csv.then_some(settings.recovery.buffer);
+    let tail = settings.tail.as_ref().map(|tail| tail.lock().unwrap().capacity());
+    [
+        (Buffer::Prebuffer, prebuffer, usize::MAX),
+        (Buffer::Workbook, settings.xlsx_max_rows, usize::MAX),
+        (Buffer::Outage, outage, usize::MAX),
+        (Buffer::Tail, tail, tail::MAX_BYTES),
+    ]
+    .into_iter()
+    .filter_map(|(buffer, rows, cap)| {
+        rows.map(|rows| Share {
+            buffer,
+            rows,
+            bytes: rows.saturating_mul(row).min(cap),
+        })
+    })
+    .collect()
+}
+
+/// Sets the capacities of the buffers in `plan`, held to with `enforce`.
+pub fn reserve(plan: &[Share], enforce: bool) {
+    for share in plan {
+        CAPACITY[share.buffer.index()].store(share.bytes, Ordering::Relaxed);
+    }
+    ENFORCED.store(enforce, Ordering::Relaxed);
+}
+
+/// e.g. `prebuffer 100000 rows (34.4 MB), tail 1000 rows (344.0 KB); up to 34.7 MB in all`
+pub fn describe(plan: &[Share]) -> String {
+    let shares: Vec<String> = plan
+        .iter()
+        .map(|share| format!("{} {} rows ({})", share.buffer.name(), share.rows, format_size(share.bytes as u64)))
+        .collect();
+    format!("{}; up to {} in all", shares.join(", "), format_size(total(plan) as u64))
+}
+
+/// The worst case of all buffers in `plan`.
+pub fn total(plan: &[Share]) -> usize {
+    plan.iter().map(|share| share.bytes).fold(0, usize::saturating_add)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prebuffer::PreBuffer;
+    use crate::tail::Tail;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    // A row of `width` cells of CELL_BYTES, as the worst case assumes
+    fn worst_row(width: usize) -> usize {
+        ROW_OVERHEAD + width * (CELL_OVERHEAD + CELL_BYTES)
+    }
+
+    fn share(plan: &[Share], buffer: Buffer) -> Option<Share> {
+        plan.iter().find(|share| share.buffer == buffer).copied()
+    }
+
+    #[test]
+    fn row_bytes_counts_the_cells_and_their_text() {
+        assert_eq!(row_bytes::<&str>(&[]), ROW_OVERHEAD);
+        assert_eq!(row_bytes(&["data", ""]), ROW_OVERHEAD + 2 * CELL_OVERHEAD + 4);
+        assert_eq!(row_bytes(&[b"12.5".to_vec()]), ROW_OVERHEAD + CELL_OVERHEAD + 4);
+    }
+
+    #[test]
+    fn plan_lists_the_configured_buffers() {
+        let mut settings = OutputSettings::plain("out.csv", &["x", "y"]);
+        settings.prebuffer = Some(Limit::Rows(500));
+        settings.xlsx_max_rows = Some(2000);
+        let plan = plan(&settings);
+        let row = worst_row(LEADING_HEADERS.len() + 2);
+        assert_eq!(share(&plan, Buffer::Prebuffer).map(|share| (share.rows, share.bytes)), Some((500, 500 * row)));
+        assert_eq!(share(&plan, Buffer::Workbook).map(|share| share.bytes), Some(2000 * row));
+        assert_eq!(share(&plan, Buffer::Outage).map(|share| share.rows), Some(10_000));
+        assert!(share(&plan, Buffer::Tail).is_none());
+        assert_eq!(total(&plan), (500 + 2000 + 10_000) * row);
+    }
+
+    #[test]
+    fn constant_columns_widen_the_row() {
+        let mut settings = OutputSettings::plain("out.csv", &["x"]);
+        settings.prebuffer = Some(Limit::Rows(10));
+        settings.id_columns = vec![("operator".to_string(), "jd".to_string())];
+        let bytes = share(&plan(&settings), Buffer::Prebuffer).unwrap().bytes;
+        assert_eq!(bytes, 10 * worst_row(LEADING_HEADERS.len() + 2));
+    }
+
+    #[test]
+    fn a_time_limited_prebuffer_counts_its_most_rows() {
+        let mut settings = OutputSettings::plain("out.csv", &["x"]);
+        settings.prebuffer = Some(Limit::Time(Duration::from_secs(2)));
+        assert_eq!(share(&plan(&settings), Buffer::Prebuffer).unwrap().rows, prebuffer::MAX_ROWS);
+    }
+
+    #[test]
+    fn only_csv_output_is_held_through_an_outage() {
+        let mut settings = OutputSettings::plain("out.csv", &["x"]);
+        settings.dry_run = true;
+        assert!(share(&plan(&settings), Buffer::Outage).is_none());
+    }
+
+    #[test]
+    fn the_tail_is_capped_by_its_own_limit() {
+        let mut settings = OutputSettings::plain("out.csv", &["x"; 40]);
+        settings.tail = Some(Arc::new(Mutex::new(Tail::new(1_000_000))));
+        let tail = share(&plan(&settings), Buffer::Tail).unwrap();
+        assert_eq!(tail.rows, 1_000_000);
+        assert_eq!(tail.bytes, tail::MAX_BYTES);
+    }
+
+    #[test]
+    fn describe_lists_each_buffer_and_the_total() {
+        let plan = [
+            Share {
+                buffer: Buffer::Prebuffer,
+                rows: 500,
+                bytes: 172_000,
+            },
+            Share {
+                buffer: Buffer::Tail,
+                rows: 1000,
+                bytes: 344_000,
+            },
+        ];
+        assert_eq!(
+            describe(&plan),
+            format!(
+                "prebuffer 500 rows ({}), tail 1000 rows ({}); up to {} in all",
+                format_size(172_000),
+                format_size(344_000),
+                format_size(516_000)
+            )
+        );
+    }
+
+    #[test]
+    fn budgets_apply_only_with_max_memory() {
+        let _globals = TEST_GLOBALS.lock().unwrap();
+        let plan = [Share {
+            buffer: Buffer::Workbook,
+            rows: 100,
+            bytes: 4096,
+        }];
+        reserve(&plan, false);
+        assert_eq!(budget(Buffer::Workbook), usize::MAX);
+        reserve(&plan, true);
+        assert_eq!(budget(Buffer::Workbook), 4096);
+        reserve(&[], false);
+    }
+
+    #[test]
+    fn buffers_report_what_they_hold_and_keep_to_their_budget() {
+        let _globals = TEST_GLOBALS.lock().unwrap();
+        let record = ["data", "2025-01-14 10:21:07", "", "", "0123456789"];
+        let row = row_bytes(&record);
+        reserve(
+            &[Share {
+                buffer: Buffer::Prebuffer,
+                rows: 100,
+                bytes: 10 * row,
+            }],
+            true,
+        );
+        let mut prebuffer = PreBuffer::new(Limit::Rows(100));
+        let now = Instant::now();
+        for _ in 0..50 {
+            prebuffer.push(now, &record);
+        }
+        // Held to ten rows' worth rather than the hundred rows allowed
+        assert_eq!(used(Buffer::Prebuffer), 10 * row);
+        assert_eq!(total_used(), used(Buffer::Prebuffer) + used(Buffer::Workbook) + used(Buffer::Outage) + used(Buffer::Tail));
+        assert_eq!(prebuffer.take(now).len(), 10);
+        assert_eq!(used(Buffer::Prebuffer), 0);
+        reserve(&[], false);
+    }
+}
@@ -0,0 +1,233 @@
+//! `merge`: combines output files from several stations into one, by time.
+//!
+//! The inputs must have the same columns. Their rows are interleaved by
+//! timestamp; rows with the same timestamp keep the order of the inputs on the
+//! command line, and rows of one input keep their order. Every row gets a
+//! `Source` column, after the existing ones, with the input's label (its file
+//! name, or the label given as `a.csv=north`). Start rows are renumbered in
+//! the merged order so run numbers don't collide; the run IDs are kept, so a
+//! merged run can still be traced back. Each input's metadata is kept as
+//! `meta` rows with the label in front of the key, and the units row of those
+//! that have one (which must agree) follows the header.
+
+use collect_acc_data::logfile::{parse_timestamp, Kind, Line, LogReader, Row, LEADING_HEADERS};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+/// One input file and the label its rows get in the Source column.
+pub struct Input {
+    pub path: PathBuf,
+    pub label: String,
+}
+
+impl Input {
+    /// Parses `path` or `path=label`; without a label the file name is used.
+    pub fn parse(arg: &str) -> Input {
+        match arg.split_once('=') {
+            Some((path, label)) if !label.is_empty() => Input {
+                path: PathBuf::from(path),
+                label: label.to_string(),
+            },
+            _ => {
+                let path = PathBuf::from(arg);
+                let label = path.file_name().map_or(arg.to_string(), |name| name.to_string_lossy().to_string());
+                Input { path, label }
+            }
+        }
+    }
+}
+
+/// What a merge produced.
+#[derive(Debug, Default)]
+pub struct Report {
+    // Rows written from each input, in input order
+    pub rows: Vec<u64>,
+    pub runs: u64,
+    // Rows dropped with --lenient: unreadable, or without a usable timestamp
+    pub dropped: u64,
+}
+
+// An input being read, with its next row waiting to be merged
+struct Source {
+    label: String,
+    path: PathBuf,
+    reader: LogReader<BufReader<File>>,
+    next: Option<Line>,
+}
+
+impl Source {
+    // Puts the next row that has a timestamp, starting with `first`, into
+    // `next` and returns its time; None at the end of the input
+    fn advance(
+        &mut self,
+        first: Option<Line>,
+        lenient: bool,
+        report: &mut Report,
+    ) -> Result<Option<chrono::NaiveDateTime>, String> {
+        self.next = None;
+        for line in first.map(Ok).into_iter().chain(&mut self.reader) {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) if lenient => {
+                    esay!("Warning: {}: {}; dropped.", self.path.display(), e);
+                    report.dropped += 1;
+                    continue;
+                }
+                Err(e) => return Err(format!("{}: {}", self.path.display(), e)),
+            };
+            match row_time(&line.row) {
+                Some(time) => {
+                    self.next = Some(line);
+                    return Ok(Some(time));
+                }
+                None if lenient => report.dropped += 1,
+                None => {
+                    return Err(format!(
+                        "{}: Line {}: the timestamp can't be read (use --lenient to drop such rows)",
+                        self.path.display(),
+                        line.number
+                    ))
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Merges `inputs` into the file at `output`.
+pub fn run(inputs: &[Input], output: &Path, lenient: bool) -> Result<Report, String> {
+    let mut report = Report {
+        rows: vec![0; inputs.len()],
+        ..Report::default()
+    };
+    let mut sources = Vec::new();
+    let mut meta: Vec<Vec<String>> = Vec::new();
+    let mut heap = BinaryHeap::new();
+    let mut columns: Option<Vec<String>> = None;
+    // Units of the columns, from the first input with a units row
+    let mut units: Option<(Vec<(String, String)>, usize)> = None;
+    for (index, input) in inputs.iter().enumerate() {
+        let file =
+            File::open(&input.path).map_err(|e| format!("Failed to open {}: {}", input.path.display(), e))?;
+        let mut source = Source {
+            label: input.label.clone(),
+            path: input.path.clone(),
+            reader: LogReader::new(BufReader::new(file)),
+            next: None,
+        };
+        // Metadata comes before the header; the header is read with it
+        let first = loop {
+            match source.reader.next() {
+                Some(Ok(Line {
+                    row: Row::Meta { key, value },
+                    ..
+                })) => meta.push(vec![Kind::Meta.to_string(), format!("{}.{}", input.label, key), value]),
+                Some(Ok(line)) => break Some(line),
+                Some(Err(e)) => return Err(format!("{}: {}", input.path.display(), e)),
+                None => break None,
+            }
+        };
+        let these = source.reader.columns();
+        if source.reader.header().is_none() {
+            return Err(format!("{} has no header row", input.path.display()));
+        }
+        match &columns {
+            Some(columns) if *columns != these => {
+                return Err(format!(
+                    "{} has different columns than {}: {} vs {}",
+                    input.path.display(),
+                    inputs[0].path.display(),
+                    these.join(","),
+                    columns.join(",")
+                ))
+            }
+            Some(_) => {}
+            None => columns = Some(these),
+        }
+        let these = source.reader.column_units();
+        match &units {
+            _ if these.is_empty() => {}
+            Some((units, first)) if *units != these => {
+                return Err(format!(
+                    "{} has different units than {}",
+                    input.path.display(),
+                    inputs[*first].path.display()
+                ))
+            }
+            Some(_) => {}
+            None => units = Some((these, index)),
+        }
+        if let Some(time) = source.advance(first, lenient, &mut report)? {
+            heap.push(Reverse((time, index)));
+        }
+        sources.push(source);
+    }
+    let columns = columns.unwrap_or_default();
+
+    let out = File::create(output).map_err(|e| format!("Failed to create {}: {}", output.display(), e))?;
+    let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(out);
+    let write_failed = |e: csv::Error| format!("Failed to write {}: {}", output.display(), e);
+    let width = LEADING_HEADERS.len() + columns.len();
+    let labels: Vec<&str> = inputs.iter().map(|input| input.label.as_str()).collect();
+    writer
+        .write_record([Kind::Meta.as_str(), "merged_from", &labels.join(" ")])
+        .map_err(write_failed)?;
+    for row in &meta {
+        writer.write_record(row).map_err(write_failed)?;
+    }
+    let mut header: Vec<&str> = LEADING_HEADERS.to_vec();
+    header.extend(columns.iter().map(String::as_str));
+    header.push("Source");
+    writer.write_record(&header).map_err(write_failed)?;
+    if let Some((units, _)) = &units {
+        let mut row = vec![Kind::Units.as_str()];
+        row.resize(LEADING_HEADERS.len(), "");
+        row.extend(columns.iter().map(|column| {
+            units.iter().find(|(name, _)| name == column).map_or("", |(_, unit)| unit.as_str())
+        }));
+        row.push("");
+        writer.write_record(&row).map_err(write_failed)?;
+    }
+
+    // Earliest timestamp first; ties go to the input given first
+    while let Some(Reverse((_, index))) = heap.pop() {
+        let source = &mut sources[index];
+        let line = source.next.take().expect("Every queued input has a row waiting");
+        let mut record: Vec<String> = line.record.iter().map(str::to_string).collect();
+        if source.reader.legacy() {
+            record.insert(3, String::new());
+        }
+        if let Row::Start { .. } = line.row {
+            record[2] = format!("run {}", report.runs);
+            report.runs += 1;
+        }
+        record.resize(width.max(record.len()), String::new());
+        record.push(source.label.clone());
+        writer.write_record(&record).map_err(write_failed)?;
+        report.rows[index] += 1;
+        if let Some(time) = source.advance(None, lenient, &mut report)? {
+            heap.push(Reverse((time, index)));
+        }
+    }
+    writer
+        .flush()
+        .map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
+    Ok(report)
+}
+
+// Timestamp of a row, if it has a readable one; metadata has none
+fn row_time(row: &Row) -> Option<chrono::NaiveDateTime> {
+    match row {
+        Row::Start { timestamp, .. }
+        | Row::Stop { timestamp, .. }
+        | Row::Continued { timestamp, .. }
+        | Row::Data { timestamp, .. }
+        | Row::Marker { timestamp, .. } => parse_timestamp(timestamp),
+        Row::Meta { .. } => None,
+    }
+}
@@ -0,0 +1,107 @@
+//! Session metadata written at the top of every output file.
+//!
+//! By default each entry is a leading CSV row with Type `meta`, the key in the
+//! second column and the value in the third. With `--meta-comments` entries
+//! are `# key: value` lines instead, for tools that skip comments. Both forms
+//! come before the header row; `--no-meta` leaves the header as the first line.
+
+use collect_acc_data::logfile::Kind;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetaStyle {
+    Rows,
+    Comments,
+    Off,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub style: MetaStyle,
+    pub entries: Vec<(String, String)>,
+}
+
+impl Metadata {
+    /// Entries describing this session, followed by the user's `--meta` pairs.
+    pub fn for_session(style: MetaStyle, port: &str, baud: u32, user: &[(String, String)]) -> Metadata {
+        let mut entries = vec![
+            ("tool_version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+            ("command_line".to_string(), std::env::args().collect::<Vec<_>>().join(" ")),
+            ("port".to_string(), port.to_string()),
+            ("baud".to_string(), baud.to_string()),
+            ("serial_settings".to_string(), "8N1, no flow control".to_string()),
+            ("hostname".to_string(), gethostname::gethostname().to_string_lossy().to_string()),
+            ("session_start".to_string(), crate::get_timestamp()),
+        ];
+        entries.extend(user.iter().cloned());
+        Metadata { style, entries }
+    }
+
+    /// Writes `# key: value` lines straight to the file, before the CSV writer
+    /// takes it over.
+    pub fn write_comments(&self, out: &mut impl Write, line_end: &str) -> std::io::Result<()> {
+        if self.style != MetaStyle::Comments {
+            return Ok(());
+        }
+        for (key, value) in &self.entries {
+            write!(out, "# {}: {}{}", key, value.replace(['\r', '\n'], " "), line_end)?;
+        }
+        Ok(())
+    }
+
+    /// Leading `meta` rows, padded to `width` columns. With `unquoted`, commas,
+    /// quotes and line breaks in values become spaces so the rows can be
+    /// written without quoting.
+    pub fn rows(&self, width: usize, unquoted: bool) -> Vec<Vec<String>> {
+        if self.style != MetaStyle::Rows {
+            return Vec::new();
+        }
+        let clean = |text: &str| {
+            if unquoted {
+                text.replace([',', '"', '\r', '\n'], " ")
+            } else {
+                text.to_string()
+            }
+        };
+        self.entries
+            .iter()
+            .map(|(key, value)| {
+                let mut row = vec![Kind::Meta.to_string(), clean(key), clean(value)];
+                row.resize(width, String::new());
+                row
+            })
+            .collect()
+    }
+}
+
+/// Parses one `--meta key=value` argument.
+pub fn parse_pair(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), value.to_string())),
+        _ => Err(format!("Invalid --meta '{}': expected key=value", arg)),
+    }
+}
+
+/// Parses `--units name=unit,...` against the columns a row has, in any
+/// order; every name must be one of them, and at most once.
+pub fn parse_units(arg: &str, columns: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut units: Vec<(String, String)> = Vec::new();
+    for pair in arg.split(',') {
+        let (name, unit) = match pair.split_once('=') {
+            Some((name, unit)) if !name.trim().is_empty() && !unit.trim().is_empty() => (name.trim(), unit.trim()),
+            _ => return Err(format!("Invalid --units entry '{}': expected column=unit", pair.trim())),
+        };
+        if !columns.iter().any(|column| column == name) {
+            return Err(format!(
+                "Unknown column '{}' in --units; the columns are: {}",
+                name,
+                columns.join(", ")
+            ));
+        }
+        if units.iter().any(|(seen, _)| seen == name) {
+            return Err(format!("Column '{}' is given twice in --units", name));
+        }
+        units.push((name.to_string(), unit.to_string()));
+    }
+    Ok(units)
+}
@@ -0,0 +1,170 @@
+//! Prometheus metrics at `/metrics`, for `--metrics-listen`.
+//!
+//! A minimal HTTP/1.0 responder on its own thread: every request is answered
+//! with the current values of the counters in `Shared`, the same ones the
+//! status line and the session summary read, in the Prometheus text format.
+//! Nothing is counted for it that isn't counted anyway, so without the flag
+//! there is no thread, no socket and no extra work.
+
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::Duration,
+};
+
+use crate::memory::{self, Buffer};
+use crate::outage;
+use crate::session::Shared;
+
+// A scraper that connects and says nothing mustn't block the others
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Binds `address` (e.g. `0.0.0.0:9200`) and serves the metrics from a
+/// background thread. `columns` names the payload fields.
+pub fn spawn(address: &str, shared: Arc<Shared>, columns: Vec<String>) -> Result<(), String> {
+    let listener =
+        TcpListener::bind(address).map_err(|e| format!("Failed to listen for metrics on {}: {}", address, e))?;
+    say!("Serving metrics on http://{}/metrics", address);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            if let Err(e) = respond(stream, &shared, &columns) {
+                esay!("Warning: Metrics request failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Answers one request; only GET /metrics (and /) is served
+fn respond(stream: TcpStream, shared: &Shared, columns: &[String]) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers; no request has a body worth reading
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let method = parts.next();
+    let path = parts.next().map(|path| path.split('?').next().unwrap_or(path));
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics" | "/")) => ("200 OK", render(shared, columns)),
+        (Some("GET"), _) => ("404 Not Found", "Not found; metrics are at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// The metrics in the Prometheus text exposition format
+fn render(shared: &Shared, columns: &[String]) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+        let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n");
+    };
+    let counter = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed).to_string();
+    metric(
+        "serial_logger_lines_read_total",
+        "counter",
+        "Lines read from the serial port",
+        counter(&shared.lines_read),
+    );
+    metric(
+        "serial_logger_records_written_total",
+        "counter",
+        "Data rows written to the output",
+        counter(&shared.rows_written),
+    );
+    metric(
+        "serial_logger_parse_failures_total",
+        "counter",
+        "Data lines with the wrong number of fields",
+        counter(&shared.parse_failures),
+    );
+    metric(
+        "serial_logger_serial_errors_total",
+        "counter",
+        "Errors reading from the serial port",
+        counter(&shared.serial_errors),
+    );
+    metric(
+        "serial_logger_reconnects_total",
+        "counter",
+        "Switches to another port of the --port list",
+        counter(&shared.port_switches),
+    );
+    metric(
+        "serial_logger_bytes_received_total",
+        "counter",
+        "Bytes received from the serial port",
+        counter(&shared.bytes_received),
+    );
+    metric(
+        "serial_logger_rows_lost_total",
+        "counter",
+        "Rows lost because the output couldn't be written",
+        outage::lost().to_string(),
+    );
+    metric(
+        "serial_logger_output_failing",
+        "gauge",
+        "1 while writing the output is failing and rows are held in memory, 0 otherwise",
+        u8::from(outage::failing()).to_string(),
+    );
+    metric(
+        "serial_logger_recording",
+        "gauge",
+        "1 while recording, 0 otherwise",
+        u8::from(shared.recording.load(Ordering::Relaxed)).to_string(),
+    );
+    metric(
+        "serial_logger_current_run",
+        "gauge",
+        "Number of the active or most recent run, -1 before the first",
+        shared.current_run.load(Ordering::Relaxed).to_string(),
+    );
+    let since_data = match *shared.last_data.lock().unwrap() {
+        None => "NaN".to_string(),
+        Some(at) => at.elapsed().as_secs_f64().to_string(),
+    };
+    metric(
+        "serial_logger_seconds_since_last_data",
+        "gauge",
+        "Seconds since input last arrived, NaN before any",
+        since_data,
+    );
+
+    out.push_str("# HELP serial_logger_buffered_bytes Bytes held in memory by each bounded buffer\n");
+    out.push_str("# TYPE serial_logger_buffered_bytes gauge\n");
+    for buffer in Buffer::ALL {
+        let _ = writeln!(out, "serial_logger_buffered_bytes{{buffer=\"{}\"}} {}", buffer.name(), memory::used(buffer));
+    }
+
+    let last_values = shared.run_stats.lock().unwrap().last_values();
+    out.push_str("# HELP serial_logger_field_last_value Latest value of each payload field in the current run\n");
+    out.push_str("# TYPE serial_logger_field_last_value gauge\n");
+    for (i, value) in last_values.iter().enumerate() {
+        let Some(value) = value else { continue };
+        let name = columns.get(i).cloned().unwrap_or_else(|| format!("field {}", i + 1));
+        let _ = writeln!(out, "serial_logger_field_last_value{{field=\"{}\"}} {}", escape(&name), value);
+    }
+    out
+}
+
+// Label values escape backslashes, quotes and newlines
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
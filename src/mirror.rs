@@ -0,0 +1,232 @@
+//! `--pty-mirror PATH`: the serial input echoed to a pseudo-terminal.
+//!
+//! Another program (a vendor GUI, a terminal) can watch the same device while
+//! it is being logged: every byte read from the port is written, as it
+//! arrives, to a new pseudo-terminal whose device is linked at `PATH`. The
+//! terminal is raw, so bytes pass through unchanged. What the other program
+//! writes is read and either thrown away or, with `--pty-writeback forward`,
+//! sent on to the real port. A program that doesn't read fast enough misses
+//! bytes rather than holding up the logger. Unix only.
+
+use std::{path::Path, sync::mpsc::Sender};
+
+use crate::serial::Control;
+use crate::tap::Observer;
+
+/// What happens to bytes written to the mirror (`--pty-writeback`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Writeback {
+    Discard,
+    Forward,
+}
+
+impl Writeback {
+    pub fn parse(text: &str) -> Result<Writeback, String> {
+        match text {
+            "discard" => Ok(Writeback::Discard),
+            "forward" => Ok(Writeback::Forward),
+            _ => Err(format!("Invalid --pty-writeback '{}': expected discard or forward", text)),
+        }
+    }
+}
+
+/// Creates the pseudo-terminal, links it at `link` and returns the observer
+/// that feeds it.
+pub fn open(link: &Path, writeback: Writeback, serial_control: Sender<Control>) -> Result<Box<dyn Observer>, String> {
+    pty::open(link, writeback, serial_control)
+}
+
+/// Removes the link made by `open`, at exit.
+pub fn remove_link() {
+    pty::remove_link()
+}
+
+#[cfg(unix)]
+mod pty {
+    use std::{
+        ffi::CStr,
+        fs::{self, File},
+        io::{self, Read, Write},
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        path::{Path, PathBuf},
+        sync::{mpsc::Sender, OnceLock},
+        thread,
+    };
+
+    use super::Writeback;
+    use crate::serial::Control;
+    use crate::tap::Observer;
+
+    static LINK: OnceLock<PathBuf> = OnceLock::new();
+
+    struct Mirror {
+        master: File,
+        // Kept open so the terminal stays up while no other program has it open
+        _slave: OwnedFd,
+        // Bytes are being dropped because nobody reads the terminal
+        dropping: bool,
+    }
+
+    impl Observer for Mirror {
+        fn observe(&mut self, bytes: &[u8]) {
+            match self.master.write_all(bytes) {
+                Ok(()) => self.dropping = false,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if !self.dropping {
+                        esay!("Warning: The --pty-mirror terminal is full; dropping bytes until it is read.");
+                        self.dropping = true;
+                    }
+                }
+                Err(e) => esay!("Warning: Failed to write to the --pty-mirror terminal: {}", e),
+            }
+        }
+    }
+
+    pub fn open(link: &Path, writeback: Writeback, serial_control: Sender<Control>) -> Result<Box<dyn Observer>, String> {
+        let failed = |e: io::Error| format!("Failed to create the --pty-mirror terminal: {}", e);
+        let (mut master, mut slave) = (0, 0);
+        // SAFETY: openpty fills in the two descriptors; the name, settings and
+        // size may be null
+        let result = unsafe {
+            libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null_mut(), std::ptr::null_mut())
+        };
+        if result != 0 {
+            return Err(failed(io::Error::last_os_error()));
+        }
+        // SAFETY: both descriptors were just opened and nothing else owns them
+        let (master, slave) = unsafe { (File::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        make_raw(&slave).map_err(failed)?;
+        set_nonblocking(&master).map_err(failed)?;
+        let name = tty_name(&slave).map_err(failed)?;
+
+        // A link left behind by a crashed session is replaced
+        if let Ok(meta) = fs::symlink_metadata(link) {
+            if !meta.file_type().is_symlink() {
+                return Err(format!("--pty-mirror {} exists and isn't a symlink", link.display()));
+            }
+            fs::remove_file(link).map_err(|e| format!("Failed to replace {}: {}", link.display(), e))?;
+        }
+        std::os::unix::fs::symlink(&name, link)
+            .map_err(|e| format!("Failed to link --pty-mirror {} to {}: {}", link.display(), name, e))?;
+        let _ = LINK.set(link.to_path_buf());
+        say!("Mirroring the serial input to {} ({}).", link.display(), name);
+
+        // Whatever the other program writes is read, so it never blocks
+        let mut input = master.try_clone().map_err(failed)?;
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                match input.read(&mut buf) {
+                    Ok(n) if n > 0 && writeback == Writeback::Forward => {
+                        if serial_control.send(Control::Write(buf[..n].to_vec())).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => wait_readable(&input),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        esay!("Warning: Stopped reading the --pty-mirror terminal: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Box::new(Mirror {
+            master,
+            _slave: slave,
+            dropping: false,
+        }))
+    }
+
+    pub fn remove_link() {
+        if let Some(link) = LINK.get() {
+            let _ = fs::remove_file(link);
+        }
+    }
+
+    fn make_raw(fd: &OwnedFd) -> io::Result<()> {
+        let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: tcgetattr fills in the termios it is given when it succeeds,
+        // and only then is it read
+        unsafe {
+            if libc::tcgetattr(fd.as_raw_fd(), termios.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut termios = termios.assume_init();
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(fd.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn set_nonblocking(file: &File) -> io::Result<()> {
+        // SAFETY: F_GETFL and F_SETFL only read and set the descriptor's flags
+        unsafe {
+            let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+            if flags < 0 || libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn tty_name(fd: &OwnedFd) -> io::Result<String> {
+        let mut buf = [0 as libc::c_char; 256];
+        // SAFETY: ttyname_r writes a NUL-terminated name within the buffer's length
+        let result = unsafe { libc::ttyname_r(fd.as_raw_fd(), buf.as_mut_ptr(), buf.len()) };
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+        // SAFETY: on success the buffer holds a NUL-terminated string
+        Ok(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned())
+    }
+
+    // Sleeps until the terminal has input (or a second has passed)
+    fn wait_readable(file: &File) {
+        let mut poll = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: poll reads one valid pollfd
+        let ready = unsafe { libc::poll(&mut poll, 1, 1000) };
+        // Without a program on the other end the terminal reports a hangup
+        // instead of input; don't spin on it
+        if ready > 0 && poll.revents & libc::POLLHUP != 0 {
+            thread::sleep(std::time::Duration::from_millis(200));
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod pty {
+    use std::{path::Path, sync::mpsc::Sender};
+
+    use super::Writeback;
+    use crate::serial::Control;
+    use crate::tap::Observer;
+
+    pub fn open(_link: &Path, _writeback: Writeback, _serial_control: Sender<Control>) -> Result<Box<dyn Observer>, String> {
+        Err("--pty-mirror needs pseudo-terminals, which this system doesn't have".to_string())
+    }
+
+    pub fn remove_link() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writeback_is_parsed() {
+        assert_eq!(Writeback::parse("discard"), Ok(Writeback::Discard));
+        assert_eq!(Writeback::parse("forward"), Ok(Writeback::Forward));
+        assert_eq!(
+            Writeback::parse("echo"),
+            Err("Invalid --pty-writeback 'echo': expected discard or forward".to_string())
+        );
+    }
+}
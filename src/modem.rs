@@ -0,0 +1,110 @@
+//! Modem control line logging with `--log-modem-lines`.
+//!
+//! CTS, DSR, CD and RI are polled from the serial thread between reads. While
+//! recording, a `modem` row with the four states (1 or 0) in the value columns
+//! is written whenever one of them changes, and at the start of every run so
+//! each run records the state it began with.
+
+use collect_acc_data::logfile::Kind;
+use serialport::SerialPort;
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use crate::get_timestamp;
+use crate::session::{write_marker, Shared};
+
+pub struct ModemPoller {
+    interval: Duration,
+    last_poll: Option<Instant>,
+    // Run and states of the last row written
+    last_written: Option<(i64, [bool; 4])>,
+    // Set after the driver failed to report the lines
+    disabled: bool,
+}
+
+impl ModemPoller {
+    pub fn new(interval: Duration) -> ModemPoller {
+        ModemPoller {
+            interval,
+            last_poll: None,
+            last_written: None,
+            disabled: false,
+        }
+    }
+
+    /// Reads the lines if the interval has passed and writes a row if they
+    /// changed. A port that can't report them disables polling after one warning.
+    pub fn poll(&mut self, port: &mut dyn SerialPort, shared: &Shared) {
+        if self.disabled || self.last_poll.is_some_and(|t| t.elapsed() < self.interval) {
+            return;
+        }
+        self.last_poll = Some(Instant::now());
+        let states = match read_lines(port) {
+            Ok(states) => states,
+            Err(e) => {
+                esay!("Warning: Can't read modem control lines on this port ({}); not logging them.", e);
+                self.disabled = true;
+                return;
+            }
+        };
+        if !shared.recording.load(Ordering::Acquire) {
+            return;
+        }
+        let run = shared.current_run.load(Ordering::Relaxed);
+        if self.last_written == Some((run, states)) {
+            return;
+        }
+        self.last_written = Some((run, states));
+        let timestamp = get_timestamp();
+        let [cts, dsr, cd, ri] = states.map(|on| if on { "1" } else { "0" });
+        if let Some(w) = shared.writer.lock().unwrap().as_mut() {
+            write_marker(w, Kind::Modem, &[&timestamp, "cts dsr cd ri", "", cts, dsr, cd, ri]);
+        }
+    }
+}
+
+/// A modem status line that can be read on its own, for `--hw-trigger`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModemLine {
+    Cts,
+    Dsr,
+    Cd,
+}
+
+impl ModemLine {
+    pub fn parse(name: &str) -> Option<ModemLine> {
+        match name {
+            "cts" => Some(ModemLine::Cts),
+            "dsr" => Some(ModemLine::Dsr),
+            "cd" => Some(ModemLine::Cd),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ModemLine::Cts => "CTS",
+            ModemLine::Dsr => "DSR",
+            ModemLine::Cd => "CD",
+        }
+    }
+
+    pub fn read(self, port: &mut dyn SerialPort) -> serialport::Result<bool> {
+        match self {
+            ModemLine::Cts => port.read_clear_to_send(),
+            ModemLine::Dsr => port.read_data_set_ready(),
+            ModemLine::Cd => port.read_carrier_detect(),
+        }
+    }
+}
+
+fn read_lines(port: &mut dyn SerialPort) -> serialport::Result<[bool; 4]> {
+    Ok([
+        port.read_clear_to_send()?,
+        port.read_data_set_ready()?,
+        port.read_carrier_detect()?,
+        port.read_ring_indicator()?,
+    ])
+}
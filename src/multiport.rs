@@ -0,0 +1,546 @@
+//! Reading several ports at once, when `--port` is given more than once.
+//!
+//! Each port is read by a task of its own on a tokio runtime, which waits for
+//! its bytes without a thread per port. A task splits what arrives into
+//! lines, counts them and writes them to the raw capture, then sends them
+//! down one channel to a single writer thread that parses them and writes the
+//! rows, in the order it gets them. Nothing but the writer waits on the
+//! output file. Lines of different ports can reach the writer a little out
+//! of order; `--ordered` holds them to put them in order (see reorder.rs).
+//!
+//! Every data row starts with a Port column naming the port it came from.
+//! The ports share `--match`, `--fields` and the rest of the input options,
+//! unless the config gives each port a `[[port]]` block of its own (see
+//! config.rs); then the file has the columns of every port, and a port's
+//! rows leave the others' columns empty. Options that act on the one port being read (`--failback`, `--hw-trigger`,
+//! `--wait-for` and the like) or that compare a row with the one before it
+//! (`--alarm`, `--log-on-change`, dedup) are refused with several ports (see
+//! validate.rs), as are binary frames and output formats other than CSV. A
+//! port that fails is reopened with a growing delay, as a single port is.
+//! Needs a build with the `multiport` feature.
+
+use std::sync::{mpsc::Receiver, Arc};
+use std::time::Duration;
+
+use collect_acc_data::parser::LineFormat;
+use collect_acc_data::schema::ColumnType;
+
+use crate::serial::{Control, InputOptions};
+use crate::session::Shared;
+
+/// The column that names the port of each row in a file of several ports.
+pub const PORT_COLUMN: &str = "Port";
+
+/// A port to read, with how its lines are parsed.
+// Only the readers look inside, and there are none without the feature
+#[cfg_attr(not(feature = "multiport"), allow(dead_code))]
+pub struct Port {
+    pub name: String,
+    pub baud: u32,
+    pub input: InputOptions,
+}
+
+/// Where the cells of one port's rows go in the file: the port's label in
+/// the Port column, then each of its columns at its place among the file's.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    label: String,
+    // Position in the file's columns of each of the port's columns
+    positions: Vec<usize>,
+    width: usize,
+}
+
+impl Layout {
+    /// The layout of a port labelled `label` with `columns` (payload and
+    /// computed) in a file with `file_columns`, the Port column first.
+    pub fn new(label: &str, columns: &[String], file_columns: &[String]) -> Result<Layout, String> {
+        let positions = columns
+            .iter()
+            .map(|name| {
+                file_columns
+                    .iter()
+                    .skip(1)
+                    .position(|column| column == name)
+                    .map(|i| i + 1)
+                    .ok_or_else(|| format!("{} has a column {} that the file doesn't", label, name))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Layout {
+            label: label.to_string(),
+            positions,
+            width: file_columns.len(),
+        })
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// `cells` of the port's columns in the file's columns: `label` first and
+    /// `blank` in the columns the port doesn't have.
+    pub fn place<T: Clone>(&self, label: T, blank: T, cells: Vec<T>) -> Vec<T> {
+        let mut row = vec![blank; self.width];
+        row[0] = label;
+        for (cell, &position) in cells.into_iter().zip(&self.positions) {
+            row[position] = cell;
+        }
+        row
+    }
+}
+
+/// The columns of a file of the rows of `ports` (label and format): the Port
+/// column, then those of each port in the order they first appear. Ports
+/// may share a column only if they give it the same type; a column that is
+/// a number on one port and text on another has no one type in the file.
+pub fn file_columns(ports: &[(String, &LineFormat)]) -> Result<Vec<String>, String> {
+    let mut columns: Vec<(String, Option<ColumnType>, &str)> = Vec::new();
+    for (label, format) in ports {
+        for (name, kind) in format.column_names().into_iter().zip(column_types(format)) {
+            match columns.iter().find(|(column, _, _)| *column == name) {
+                Some((_, other, first)) if *other != kind => {
+                    return Err(format!(
+                        "Conflicting schemas: column {} is {} on {} but {} on {}; give it the same --types on both, or names of its own with --names",
+                        name,
+                        describe(*other),
+                        first,
+                        describe(kind),
+                        label
+                    ));
+                }
+                Some(_) => {}
+                None => columns.push((name, kind, label)),
+            }
+        }
+    }
+    let names = columns.into_iter().map(|(name, _, _)| name);
+    Ok(std::iter::once(PORT_COLUMN.to_string()).chain(names).collect())
+}
+
+// The declared type of each column of `format`: those of --types, none for
+// fields without, and computed columns are floats
+fn column_types(format: &LineFormat) -> Vec<Option<ColumnType>> {
+    let mut types: Vec<Option<ColumnType>> = match &format.types {
+        Some(schema) => schema.columns.iter().copied().map(Some).collect(),
+        None => vec![None; format.field_count()],
+    };
+    types.extend(format.computed.iter().map(|_| Some(ColumnType::Float { bits: 64, decimals: None })));
+    types
+}
+
+fn describe(kind: Option<ColumnType>) -> String {
+    kind.map_or_else(|| "untyped".to_string(), |kind| kind.to_string())
+}
+
+/// Opens every port and reads them all from then on, holding each line for
+/// `ordered` to write them in order if given. Fails if a port doesn't open.
+/// Requests from the command loop (`baud`, `port`, `break`) act on a single
+/// port, so they are turned down.
+pub fn spawn(ports: Vec<Port>, ordered: Option<Duration>, shared: Arc<Shared>, control: Receiver<Control>) -> Result<(), String> {
+    reader::spawn(ports, ordered, shared, control)
+}
+
+#[cfg(feature = "multiport")]
+mod reader {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc::Receiver,
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use collect_acc_data::parser::{self, Admit, Gate};
+    use collect_acc_data::raw::Entry;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::sync::mpsc;
+    use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+    use super::Port;
+    use crate::events::{self, Event};
+    use crate::reorder::Reorder;
+    use crate::serial::{self, Arrival, Control, InputOptions};
+    use crate::session::Shared;
+    use crate::settings::{self, Echo};
+    use crate::strict::Check;
+
+    // Lines on their way to the writer; a reader waits when it is full
+    const QUEUE: usize = 4096;
+    // No port captured yet
+    const NONE: usize = usize::MAX;
+
+    // What the writer gets: the lines, and with --ordered a tick now and
+    // then to release the lines whose window has passed
+    enum Message {
+        Line(Line),
+        Tick,
+    }
+
+    // A line from one of the ports, cleaned up, for the writer
+    struct Line {
+        port: usize,
+        data: String,
+        arrival: Arrival,
+        // Whether a run was recording when it went to the raw capture
+        recording: bool,
+    }
+
+    // What a reader needs of the input options; the rest is the writer's
+    struct Cleaning {
+        strip_ansi: bool,
+        skip_blank_lines: bool,
+        delimiter: char,
+        max_line_length: usize,
+    }
+
+    // A port's reader task and what it shares with the others
+    struct Reader {
+        index: usize,
+        name: String,
+        baud: u32,
+        cleaning: Cleaning,
+        shared: Arc<Shared>,
+        // The port of the latest raw capture entry, so a line from another
+        // one is preceded by an open entry for it; only changed under the
+        // writer lock, with the capture
+        captured: Arc<AtomicUsize>,
+        lines: mpsc::Sender<Message>,
+    }
+
+    pub fn spawn(ports: Vec<Port>, ordered: Option<Duration>, shared: Arc<Shared>, control: Receiver<Control>) -> Result<(), String> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("serial-ports")
+            .build()
+            .map_err(|e| format!("Failed to start the port readers: {}", e))?;
+        let (lines, queue) = mpsc::channel(QUEUE);
+        let captured = Arc::new(AtomicUsize::new(NONE));
+        let mut readers = Vec::new();
+        let mut inputs = Vec::new();
+        {
+            // Ports are registered with the runtime as they open
+            let _runtime = runtime.enter();
+            for (index, port) in ports.into_iter().enumerate() {
+                let stream = open(&port.name, port.baud).map_err(|e| format!("Failed to open serial port {}", e))?;
+                let reader = Reader {
+                    index,
+                    name: port.name,
+                    baud: port.baud,
+                    cleaning: Cleaning {
+                        strip_ansi: port.input.strip_ansi,
+                        skip_blank_lines: port.input.skip_blank_lines,
+                        delimiter: port.input.format.delimiter,
+                        max_line_length: port.input.max_line_length,
+                    },
+                    shared: Arc::clone(&shared),
+                    captured: Arc::clone(&captured),
+                    lines: lines.clone(),
+                };
+                reader.opened();
+                readers.push((reader, stream));
+                inputs.push(port.input);
+            }
+        }
+        let count = readers.len();
+        thread::spawn(move || {
+            runtime.block_on(async move {
+                for (reader, stream) in readers {
+                    tokio::spawn(reader.run(stream));
+                }
+                match ordered {
+                    Some(window) => tick(lines, window).await,
+                    None => {
+                        drop(lines);
+                        std::future::pending::<()>().await
+                    }
+                }
+            })
+        });
+        {
+            let shared = Arc::clone(&shared);
+            let reorder = ordered.map(Reorder::new);
+            thread::spawn(move || write(queue, inputs, reorder, &shared));
+        }
+        thread::spawn(move || {
+            for request in control {
+                match request {
+                    // There is no --pty-mirror to write back from
+                    Control::Write(_) => {}
+                    _ => esay!("Not changed: baud, port and break act on a single port, and {} ports are being read.", count),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn open(name: &str, baud: u32) -> Result<SerialStream, String> {
+        tokio_serial::new(serial::device_path(name), baud)
+            .open_native_async()
+            .map_err(|e| format!("{}: {}", name, e))
+    }
+
+    // Sends the writer a tick four times per window, so held lines leave
+    // on time when no new line comes to push them out
+    async fn tick(lines: mpsc::Sender<Message>, window: Duration) {
+        let mut interval = tokio::time::interval((window / 4).max(Duration::from_millis(1)));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            if lines.send(Message::Tick).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Parses the lines of every port and writes their rows, one at a time,
+    // in the order they arrived with `reorder`
+    fn write(mut queue: mpsc::Receiver<Message>, inputs: Vec<InputOptions>, mut reorder: Option<Reorder<Line>>, shared: &Shared) {
+        let mut gates: Vec<Gate> = inputs.iter().map(|input| Gate::new(input.skip_lines, None)).collect();
+        let mut write_line = |line: Line| {
+            // A run stopped while the line waited doesn't get it
+            let recording = line.recording && shared.recording.load(Ordering::Acquire);
+            match gates[line.port].admit(&line.data) {
+                Admit::Pass => serial::handle_line(&line.data, shared, &inputs[line.port], &line.arrival, recording),
+                Admit::Discard | Admit::Synced => serial::skip_line(&line.data, shared),
+            }
+        };
+        while let Some(message) = queue.blocking_recv() {
+            let Some(reorder) = reorder.as_mut() else {
+                if let Message::Line(line) = message {
+                    write_line(line);
+                }
+                continue;
+            };
+            if let Message::Line(line) = message {
+                if let Some(late) = reorder.push(line.arrival.instant, line) {
+                    shared.late_rows.fetch_add(1, Ordering::Relaxed);
+                    write_line(late);
+                }
+            }
+            for line in reorder.release(Instant::now()) {
+                write_line(line);
+            }
+        }
+        for line in reorder.iter_mut().flat_map(Reorder::drain) {
+            write_line(line);
+        }
+    }
+
+    impl Reader {
+        async fn run(self, stream: SerialStream) {
+            let mut reader = BufReader::new(stream);
+            let mut line = Vec::new();
+            // Bytes dropped so far of a line over --max-line-length
+            let mut overflow = 0;
+            loop {
+                let read = match reader.fill_buf().await {
+                    Ok([]) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the port was closed")),
+                    Ok(chunk) => {
+                        let end = chunk.iter().position(|&b| b == b'\n').map_or(chunk.len(), |i| i + 1);
+                        line.extend_from_slice(&chunk[..end]);
+                        Ok(end)
+                    }
+                    Err(e) => Err(e),
+                };
+                let end = match read {
+                    Ok(end) => end,
+                    Err(e) => {
+                        line.clear();
+                        overflow = 0;
+                        reader = BufReader::new(self.reopen(e).await);
+                        continue;
+                    }
+                };
+                reader.consume(end);
+                let arrival = Arrival::now();
+                self.shared.bytes_received.fetch_add(end as u64, Ordering::Relaxed);
+                *self.shared.last_data.lock().unwrap() = Some(arrival.instant);
+                let ended = line.ends_with(b"\n");
+                let length = line.len() - usize::from(ended);
+                if overflow > 0 || length > self.cleaning.max_line_length {
+                    overflow += length;
+                    let dropped = std::mem::take(&mut line);
+                    self.capture(&arrival, dropped);
+                    if ended {
+                        serial::overflowed(&self.shared, std::mem::take(&mut overflow), self.cleaning.max_line_length);
+                    }
+                    continue;
+                }
+                if !ended {
+                    continue;
+                }
+                let received = std::mem::take(&mut line);
+                if settings::echo() == Echo::Raw {
+                    crate::console::diagnostic(&format!("<< {}: {}", self.name, received.escape_ascii()));
+                }
+                let recording = self.capture(&arrival, received.clone());
+                for line in parser::split_lines(&received) {
+                    let data = parser::clean_line(line, self.cleaning.strip_ansi, self.cleaning.delimiter);
+                    if data.is_empty() && self.cleaning.skip_blank_lines {
+                        self.shared.blank_lines.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    if settings::echo() == Echo::On {
+                        crate::console::diagnostic(&format!("< {}: {}", self.name, data));
+                    }
+                    let line = Message::Line(Line {
+                        port: self.index,
+                        data,
+                        arrival,
+                        recording,
+                    });
+                    if self.lines.send(line).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+
+        // Writes received bytes to the raw capture, under the writer lock so
+        // they land on the same side of a start or stop as in the CSV;
+        // returns whether a run is recording
+        fn capture(&self, arrival: &Arrival, bytes: Vec<u8>) -> bool {
+            let _writer = self.shared.writer.lock().unwrap();
+            if self.captured.swap(self.index, Ordering::Relaxed) != self.index {
+                self.shared.capture(
+                    &arrival.timestamp(),
+                    Entry::Open {
+                        port: self.name.clone(),
+                        baud: self.baud,
+                    },
+                );
+            }
+            self.shared.capture(&arrival.timestamp(), Entry::Rx(bytes));
+            self.shared.recording.load(Ordering::Acquire)
+        }
+
+        fn opened(&self) {
+            events::emit(Event::PortOpened {
+                port: self.name.clone(),
+                baud: self.baud,
+            });
+        }
+
+        // Reports a failed read and opens the port again, waiting longer
+        // after every failed attempt, until it opens
+        async fn reopen(&self, error: io::Error) -> SerialStream {
+            let shared = &self.shared;
+            esay!("Error reading from serial port {}: {}; trying to reopen it.", self.name, error);
+            shared.serial_errors.fetch_add(1, Ordering::Relaxed);
+            shared.disconnects.fetch_add(1, Ordering::Relaxed);
+            if shared.recording.load(Ordering::Relaxed) {
+                shared.fail_strict(Check::Reconnect, || format!("serial port {} lost: {}", self.name, error));
+            }
+            serial::run_error_hook(shared, &self.name, &error.to_string());
+            events::emit(Event::SerialError {
+                port: self.name.clone(),
+                error: error.to_string(),
+            });
+            let since = Instant::now();
+            let mut last_reported = since;
+            let mut delay = serial::RECONNECT_FIRST;
+            loop {
+                tokio::time::sleep(delay).await;
+                match open(&self.name, self.baud) {
+                    Ok(stream) => {
+                        {
+                            let _writer = shared.writer.lock().unwrap();
+                            self.captured.store(NONE, Ordering::Relaxed);
+                        }
+                        serial::port_back(shared, &self.name, self.baud, since.elapsed());
+                        return stream;
+                    }
+                    Err(e) => {
+                        if last_reported.elapsed() >= serial::STILL_DOWN_EVERY {
+                            last_reported = Instant::now();
+                            esay!(
+                                "Serial port {} still failing after {:.0}s, retrying: {}",
+                                self.name,
+                                since.elapsed().as_secs_f64(),
+                                e
+                            );
+                        }
+                        delay = (delay * 2).min(serial::RECONNECT_MAX);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "multiport"))]
+mod reader {
+    use std::sync::{mpsc::Receiver, Arc};
+    use std::time::Duration;
+
+    use super::Port;
+    use crate::serial::Control;
+    use crate::session::Shared;
+
+    pub fn spawn(
+        _ports: Vec<Port>,
+        _ordered: Option<Duration>,
+        _shared: Arc<Shared>,
+        _control: Receiver<Control>,
+    ) -> Result<(), String> {
+        Err("Reading several ports needs a build with the 'multiport' feature".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn cells_go_under_the_columns_of_their_port() {
+        let file = names(&["Port", "t", "x", "y", "lat"]);
+        let imu = Layout::new("imu", &names(&["t", "x", "y"]), &file).unwrap();
+        assert_eq!(imu.place("imu", "", vec!["1", "2", "3"]), ["imu", "1", "2", "3", ""]);
+        let gps = Layout::new("gps", &names(&["t", "lat"]), &file).unwrap();
+        assert_eq!(gps.place("gps", "", vec!["1", "48.1"]), ["gps", "1", "", "", "48.1"]);
+        assert_eq!(gps.place(None, None, vec![Some(1.0), Some(48.1)]), [None, Some(1.0), None, None, Some(48.1)]);
+    }
+
+    fn format(args: &[&str]) -> LineFormat {
+        let matches = crate::cli().try_get_matches_from(["serial_logger", "-p", "COM3"].iter().chain(args)).unwrap();
+        crate::line_format(&matches, None)
+    }
+
+    #[test]
+    fn the_file_has_the_columns_of_every_port() {
+        let imu = format(&["--fields", "4", "--names", "seq,ax,ay,az"]);
+        let gps = format(&["--fields", "3", "--names", "seq,lat,lon", "--compute", "north=f1*2"]);
+        let columns = file_columns(&[("imu".to_string(), &imu), ("gps".to_string(), &gps)]).unwrap();
+        assert_eq!(columns, names(&["Port", "seq", "ax", "ay", "az", "lat", "lon", "north"]));
+        // Ports with the same columns share them all
+        assert_eq!(file_columns(&[("a".to_string(), &imu), ("b".to_string(), &imu)]).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn a_column_of_two_types_is_a_conflict() {
+        let imu = format(&["--fields", "2", "--names", "t,x", "--types", "f64,f64"]);
+        let gps = format(&["--fields", "2", "--names", "t,lat", "--types", "str,f64"]);
+        let error = file_columns(&[("imu".to_string(), &imu), ("gps".to_string(), &gps)]).unwrap_err();
+        assert!(error.starts_with("Conflicting schemas: column t is f64 on imu but str on gps"), "{}", error);
+        // Typed on one port and not on the other
+        let untyped = format(&["--fields", "2", "--names", "t,y"]);
+        assert!(file_columns(&[("imu".to_string(), &imu), ("b".to_string(), &untyped)]).unwrap_err().contains("untyped on b"));
+        // A computed column is a number
+        let computed = format(&["--fields", "1", "--names", "y", "--compute", "x=f0*2"]);
+        assert!(file_columns(&[("imu".to_string(), &imu), ("c".to_string(), &computed)]).is_ok());
+    }
+
+    #[test]
+    fn a_column_missing_from_the_file_is_an_error() {
+        let file = names(&["Port", "t", "x"]);
+        assert!(Layout::new("gps", &names(&["t", "lat"]), &file).unwrap_err().contains("lat"));
+        // The Port column isn't one of the port's
+        assert!(Layout::new("gps", &names(&["Port"]), &file).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! NMEA 0183 sentences from a GPS receiver.
+//!
+//! Only what `--time-source` needs: the checksum, and the UTC time and date of
+//! RMC sentences from any talker (`$GPRMC`, `$GNRMC`, ...). An RMC sentence
+//! carries its date next to its time, so a fix at 23:59:59.5 and the next one
+//! at 00:00:00.5 are half a second apart across midnight, and across the end
+//! of a month or year, without any rollover logic here.
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+
+/// The parts of an RMC sentence used for timekeeping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rmc {
+    // Status A: the receiver has a fix and the time is GPS time
+    pub valid: bool,
+    // UTC; None while the receiver has no time yet
+    pub time: Option<NaiveDateTime>,
+}
+
+/// Parses one line from the receiver. Returns None for well-formed sentences
+/// other than RMC, and an error for a line that isn't a sentence or fails its
+/// checksum.
+pub fn parse_rmc(line: &str) -> Result<Option<Rmc>, String> {
+    let fields = fields(line)?;
+    if !fields[0].ends_with("RMC") {
+        return Ok(None);
+    }
+    let field = |index: usize| fields.get(index).copied().unwrap_or_default();
+    let time = match (field(1), field(9)) {
+        ("", _) | (_, "") => None,
+        (time, date) => Some(
+            parse_date(date)
+                .and_then(|date| parse_time(time).map(|time| date.and_time(time)))
+                .ok_or_else(|| format!("Invalid RMC time '{}' or date '{}'", time, date))?,
+        ),
+    };
+    Ok(Some(Rmc {
+        valid: field(2) == "A",
+        time,
+    }))
+}
+
+// The comma-separated fields of a sentence, starting with its address (e.g.
+// GPRMC), after checking the checksum if there is one
+fn fields(line: &str) -> Result<Vec<&str>, String> {
+    let line = line.trim();
+    let body = line.strip_prefix('$').ok_or_else(|| format!("Not an NMEA sentence: '{}'", line))?;
+    let body = match body.rsplit_once('*') {
+        Some((body, checksum)) => {
+            let expected = u8::from_str_radix(checksum, 16).map_err(|_| format!("Invalid NMEA checksum in '{}'", line))?;
+            let actual = body.bytes().fold(0, |sum, byte| sum ^ byte);
+            if actual != expected {
+                return Err(format!("NMEA checksum mismatch in '{}': computed {:02X}", line, actual));
+            }
+            body
+        }
+        None => body,
+    };
+    let fields: Vec<&str> = body.split(',').collect();
+    if fields[0].len() < 5 {
+        return Err(format!("Not an NMEA sentence: '{}'", line));
+    }
+    Ok(fields)
+}
+
+// hhmmss or hhmmss.sss
+fn parse_time(text: &str) -> Option<NaiveTime> {
+    let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+    if whole.len() != 6 || !whole.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| whole[range].parse::<u32>().ok();
+    let millis = if fraction.is_empty() {
+        0
+    } else {
+        let digits: String = fraction.chars().chain("000".chars()).take(3).collect();
+        digits.parse().ok()?
+    };
+    NaiveTime::from_hms_milli_opt(number(0..2)?, number(2..4)?, number(4..6)?, millis)
+}
+
+// ddmmyy; two-digit years from 80 are taken as 19xx, like most receivers do
+fn parse_date(text: &str) -> Option<NaiveDate> {
+    if text.len() != 6 || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| text[range].parse::<u32>().ok();
+    let year = number(4..6)? as i32;
+    let year = if year >= 80 { 1900 + year } else { 2000 + year };
+    NaiveDate::from_ymd_opt(year, number(2..4)?, number(0..2)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A sentence with its checksum
+    fn sentence(body: &str) -> String {
+        format!("${}*{:02X}", body, body.bytes().fold(0, |sum, byte| sum ^ byte))
+    }
+
+    fn rmc(time: &str, status: &str, date: &str) -> String {
+        sentence(&format!("GPRMC,{},{},4807.038,N,01131.000,E,022.4,084.4,{},003.1,W", time, status, date))
+    }
+
+    fn at(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.3f").unwrap()
+    }
+
+    fn time_of(line: &str) -> NaiveDateTime {
+        parse_rmc(line).unwrap().unwrap().time.unwrap()
+    }
+
+    #[test]
+    fn rmc_time_and_status_are_read() {
+        let parsed = parse_rmc(&rmc("123519.25", "A", "230394")).unwrap().unwrap();
+        assert!(parsed.valid);
+        assert_eq!(parsed.time, Some(at("1994-03-23 12:35:19.250")));
+        let parsed = parse_rmc(&rmc("123519", "V", "230394")).unwrap().unwrap();
+        assert!(!parsed.valid);
+        assert_eq!(parsed.time, Some(at("1994-03-23 12:35:19.000")));
+        // Any talker, and fractions of any length
+        assert_eq!(time_of(&sentence("GNRMC,000001.123456,A,,,,,,,010125,,")), at("2025-01-01 00:00:01.123"));
+    }
+
+    #[test]
+    fn midnight_is_crossed_by_the_date() {
+        let before = time_of(&rmc("235959.50", "A", "140125"));
+        let after = time_of(&rmc("000000.50", "A", "150125"));
+        assert_eq!(after - before, chrono::TimeDelta::seconds(1));
+    }
+
+    #[test]
+    fn month_and_year_ends_are_crossed_by_the_date() {
+        for (before, after) in [
+            (("235959.75", "310125"), ("000000.25", "010225")),
+            // A leap day, and the day after it
+            (("235959.75", "280224"), ("000000.25", "290224")),
+            (("235959.75", "290224"), ("000000.25", "010324")),
+            (("235959.75", "311299"), ("000000.25", "010100")),
+            (("235959.75", "311224"), ("000000.25", "010125")),
+        ] {
+            let before = time_of(&rmc(before.0, "A", before.1));
+            let after = time_of(&rmc(after.0, "A", after.1));
+            assert_eq!(after - before, chrono::TimeDelta::milliseconds(500), "{} to {}", before, after);
+        }
+    }
+
+    #[test]
+    fn two_digit_years_pivot_at_1980() {
+        assert_eq!(time_of(&rmc("000000", "A", "010180")), at("1980-01-01 00:00:00.000"));
+        assert_eq!(time_of(&rmc("000000", "A", "311279")), at("2079-12-31 00:00:00.000"));
+    }
+
+    #[test]
+    fn a_receiver_without_time_has_none() {
+        let parsed = parse_rmc(&sentence("GPRMC,,V,,,,,,,,,,N")).unwrap().unwrap();
+        assert_eq!(parsed, Rmc { valid: false, time: None });
+        // Time without a date is no use either
+        assert_eq!(parse_rmc(&rmc("120000", "V", "")).unwrap().unwrap().time, None);
+    }
+
+    #[test]
+    fn impossible_times_and_dates_are_errors() {
+        for (time, date) in [("246000", "140125"), ("120060", "140125"), ("1200", "140125"), ("120000", "290223"), ("120000", "320125"), ("120000", "14012a")] {
+            let error = parse_rmc(&rmc(time, "A", date)).unwrap_err();
+            assert!(error.starts_with("Invalid RMC time"), "{} {}: {}", time, date, error);
+        }
+    }
+
+    #[test]
+    fn other_sentences_are_skipped() {
+        let gga = sentence("GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,");
+        assert_eq!(parse_rmc(&gga), Ok(None));
+    }
+
+    #[test]
+    fn lines_that_arent_sentences_are_errors() {
+        let mut damaged = rmc("123519", "A", "230394");
+        damaged.replace_range(8..9, "9");
+        assert!(parse_rmc(&damaged).unwrap_err().starts_with("NMEA checksum mismatch"));
+        assert!(parse_rmc("GPRMC,123519,A").unwrap_err().starts_with("Not an NMEA sentence"));
+        assert!(parse_rmc("$GP,1,2").unwrap_err().starts_with("Not an NMEA sentence"));
+        assert!(parse_rmc("$GPRMC,123519*ZZ").unwrap_err().starts_with("Invalid NMEA checksum"));
+        // Without a checksum the sentence is taken as it is, line ending and all
+        assert_eq!(time_of("$GPRMC,123519,A,,,,,,,230394,,\r\n"), at("1994-03-23 12:35:19.000"));
+    }
+}
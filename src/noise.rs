@@ -0,0 +1,59 @@
+//! Detection of input that looks like line noise, the usual symptom of a
+//! wrong baud rate.
+//!
+//! At the wrong rate the UART still delivers bytes, but they are mostly
+//! control characters, stray high bytes that aren't valid UTF-8 and
+//! `0x00`/`0xFF` from misread start and stop bits. Bytes are judged in windows
+//! of `WINDOW` bytes; a window in which more than `THRESHOLD` of them are
+//! suspect counts as noise.
+
+/// Bytes judged together.
+pub const WINDOW: usize = 256;
+/// Fraction of suspect bytes above which a window is noise.
+pub const THRESHOLD: f64 = 0.2;
+
+/// Fraction of `bytes` that are unlikely in a text protocol: control
+/// characters other than tab, CR, LF and ESC (used by colored output), and
+/// bytes that aren't part of valid UTF-8.
+pub fn suspect_fraction(bytes: &[u8]) -> f64 {
+    if bytes.is_empty() {
+        return 0.0;
+    }
+    let mut suspect = 0;
+    for chunk in bytes.utf8_chunks() {
+        suspect += chunk.invalid().len();
+        suspect += chunk
+            .valid()
+            .chars()
+            .filter(|c| c.is_control() && !matches!(c, '\t' | '\r' | '\n' | '\x1b'))
+            .count();
+    }
+    suspect as f64 / bytes.len() as f64
+}
+
+/// Running check over the input, one window at a time.
+#[derive(Default)]
+pub struct NoiseMonitor {
+    window: Vec<u8>,
+    noisy: bool,
+}
+
+impl NoiseMonitor {
+    /// Adds received bytes. Returns `Some(noisy)` each time a window fills
+    /// and the verdict differs from the previous one.
+    pub fn feed(&mut self, bytes: &[u8]) -> Option<bool> {
+        let mut changed = None;
+        for byte in bytes {
+            self.window.push(*byte);
+            if self.window.len() == WINDOW {
+                let noisy = suspect_fraction(&self.window) > THRESHOLD;
+                self.window.clear();
+                if noisy != self.noisy {
+                    self.noisy = noisy;
+                    changed = Some(noisy);
+                }
+            }
+        }
+        changed
+    }
+}
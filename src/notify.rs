@@ -0,0 +1,123 @@
+//! Webhook notifications sent with `--notify-url`.
+//!
+//! When a run finishes (`run_summary`) or something goes wrong that needs a
+//! person (`alarm`, `serial_error`, `port_reconnected`, `port_failover`,
+//! `disk_full`, `cap_reached`, `strict_failure`, `output_error`,
+//! `output_recovered`), the event is POSTed to the URL as the
+//! same JSON object the `--events-json` stream prints, so one parser handles
+//! both. Requests are made from a background thread; a failed request is
+//! retried twice and then dropped, and logging never waits for the network.
+//!
+//! The HTTP client is behind the `webhook` cargo feature (on by default).
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::events::Event;
+
+// Notifications queued or being sent, waited on briefly at exit
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the event is one that gets sent to the webhook.
+pub fn wanted(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::RunSummary { .. }
+            | Event::Alarm { .. }
+            | Event::SerialError { .. } | Event::PortReconnected { .. }
+            | Event::PortFailover { .. } | Event::DiskFull { .. }
+            | Event::CapReached { .. } | Event::StrictFailure { .. }
+            | Event::OutputError { .. } | Event::OutputRecovered { .. }
+    )
+}
+
+#[cfg(feature = "webhook")]
+mod client {
+    use std::{
+        sync::{atomic::Ordering, mpsc, OnceLock},
+        thread,
+        time::Duration,
+    };
+
+    use super::PENDING;
+
+    const ATTEMPTS: u32 = 3;
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    static QUEUE: OnceLock<mpsc::Sender<String>> = OnceLock::new();
+
+    pub fn init(url: String) -> Result<(), String> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("Invalid --notify-url '{}': expected an http:// or https:// URL", url));
+        }
+        let (tx, rx) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            for body in rx {
+                send_with_retries(&url, &body);
+                PENDING.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+        QUEUE.set(tx).map_err(|_| "Notifications are already set up".to_string())
+    }
+
+    fn send_with_retries(url: &str, body: &str) {
+        for attempt in 1..=ATTEMPTS {
+            let result = ureq::post(url)
+                .timeout(TIMEOUT)
+                .set("Content-Type", "application/json")
+                .send_string(body);
+            match result {
+                Ok(_) => return,
+                Err(e) if attempt == ATTEMPTS => {
+                    esay!("Warning: Dropping notification after {} attempts: {}", ATTEMPTS, e);
+                }
+                // Back off 1s, then 2s
+                Err(_) => thread::sleep(Duration::from_secs(attempt as u64)),
+            }
+        }
+    }
+
+    pub fn send(body: String) {
+        let Some(queue) = QUEUE.get() else { return };
+        PENDING.fetch_add(1, Ordering::SeqCst);
+        if queue.send(body).is_err() {
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(not(feature = "webhook"))]
+mod client {
+    pub fn init(_url: String) -> Result<(), String> {
+        Err("--notify-url needs a build with the 'webhook' feature".to_string())
+    }
+
+    pub fn send(_body: String) {}
+}
+
+/// Starts the sender thread for `url`.
+pub fn init(url: String) -> Result<(), String> {
+    client::init(url)
+}
+
+/// Queues one event line for the webhook; a no-op without `--notify-url`.
+pub fn send(body: String) {
+    client::send(body)
+}
+
+/// Gives queued notifications up to `timeout` to go out before exit.
+pub fn wait_for_pending(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while PENDING.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            esay!(
+                "Warning: {} notification(s) not sent before exit",
+                PENDING.load(Ordering::SeqCst)
+            );
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,323 @@
+//! Riding out an output file that keeps failing to write, e.g. on a network
+//! share that went away.
+//!
+//! A few failed writes or flushes in a row (`--write-error-limit`) start an
+//! outage: the file is given up on, rows are held in memory instead (up to
+//! `--outage-buffer`, or with `--max-memory` its capacity in bytes; beyond
+//! that the oldest are dropped) and the file is
+//! reopened for appending after 1, 2, 4, ... up to 60 seconds, or
+//! `--fallback-output` is created if it can't be. Once one opens, an `outage`
+//! row describing the outage is written, followed by the held rows. The
+//! prompt and the status line show the outage while it lasts, the event
+//! stream gets `output_error` and `output_recovered`, and rows lost to it make
+//! the program exit with its own exit code. Only CSV output recovers: an
+//! Arrow stream, a Parquet or an HDF5 file can't be appended to.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use collect_acc_data::logfile::Kind;
+use csv::Writer;
+
+use crate::events::{self, Event};
+use crate::get_timestamp;
+use crate::memory::{self, Buffer};
+use crate::sink::{CsvDialect, Target};
+
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+// Output is failing now, with this many rows held, and rows lost this session
+static FAILING: AtomicBool = AtomicBool::new(false);
+static HELD: AtomicU64 = AtomicU64::new(0);
+static LOST: AtomicU64 = AtomicU64::new(0);
+static OUTAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Whether an outage is going on.
+pub fn failing() -> bool {
+    FAILING.load(Ordering::Relaxed)
+}
+
+/// Rows held in memory during the current outage.
+pub fn held() -> u64 {
+    HELD.load(Ordering::Relaxed)
+}
+
+/// Rows dropped by outages this session, or still held when the file closed.
+pub fn lost() -> u64 {
+    LOST.load(Ordering::Relaxed)
+}
+
+/// Outages this session.
+pub fn outages() -> u64 {
+    OUTAGES.load(Ordering::Relaxed)
+}
+
+/// Settings from `--write-error-limit`, `--outage-buffer` and
+/// `--fallback-output`.
+#[derive(Debug, Clone)]
+pub struct Recovery {
+    // Failed writes in a row that start an outage
+    pub error_limit: u32,
+    // Rows held during an outage
+    pub buffer: usize,
+    pub fallback: Option<String>,
+}
+
+impl Default for Recovery {
+    fn default() -> Recovery {
+        Recovery {
+            error_limit: 3,
+            buffer: 10_000,
+            fallback: None,
+        }
+    }
+}
+
+/// Follows the writes of a CSV file and takes over during an outage.
+pub struct Tracker {
+    recovery: Recovery,
+    // The file being written, which becomes the fallback once it is used
+    path: String,
+    header: Vec<String>,
+    dialect: CsvDialect,
+    // Cuts the writer off from its file once it is given up on, so rows it
+    // still buffers can't land after the replayed ones
+    cut: Arc<AtomicBool>,
+    failures: u32,
+    // Rows given to the writer since it last flushed, which a failed flush
+    // may not have written
+    unflushed: Vec<Vec<Vec<u8>>>,
+    outage: Option<Outage>,
+}
+
+struct Outage {
+    since: String,
+    started: Instant,
+    error: String,
+    rows: VecDeque<Vec<Vec<u8>>>,
+    bytes: usize,
+    dropped: u64,
+    retry_at: Instant,
+    wait: Duration,
+}
+
+impl Tracker {
+    /// `cut` is the flag of the `Target::Tracked` the writer writes to.
+    pub fn new(path: &str, header: &[&str], dialect: CsvDialect, recovery: Recovery, cut: Arc<AtomicBool>) -> Tracker {
+        Tracker {
+            recovery,
+            path: path.to_string(),
+            header: header.iter().map(|name| name.to_string()).collect(),
+            dialect,
+            cut,
+            failures: 0,
+            unflushed: Vec::new(),
+            outage: None,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn in_outage(&self) -> bool {
+        self.outage.is_some()
+    }
+
+    /// A row was given to the writer.
+    pub fn written(&mut self, record: &[&[u8]]) {
+        self.unflushed.push(owned(record));
+    }
+
+    /// The writer flushed, so everything given to it is out.
+    pub fn flushed(&mut self) {
+        self.failures = 0;
+        self.unflushed.clear();
+    }
+
+    /// Counts a failed write (of `record`) or flush. Returns true when it
+    /// starts an outage, which takes over the rows the writer hadn't flushed.
+    pub fn failed(&mut self, e: &io::Error, record: Option<&[&[u8]]>) -> bool {
+        self.failures += 1;
+        if self.failures < self.recovery.error_limit {
+            return false;
+        }
+        self.cut.store(true, Ordering::Relaxed);
+        let mut rows: VecDeque<Vec<Vec<u8>>> = self.unflushed.drain(..).collect();
+        rows.extend(record.map(owned));
+        self.outage = Some(Outage {
+            since: get_timestamp(),
+            started: Instant::now(),
+            error: e.to_string(),
+            bytes: rows.iter().map(|row| memory::row_bytes(row)).sum(),
+            rows,
+            dropped: 0,
+            retry_at: Instant::now() + FIRST_RETRY,
+            wait: FIRST_RETRY,
+        });
+        self.trim();
+        FAILING.store(true, Ordering::Relaxed);
+        OUTAGES.fetch_add(1, Ordering::Relaxed);
+        esay!(
+            "ERROR: Writing {} failed {} times in a row ({}); holding up to {} rows in memory until it can be written again.",
+            self.path,
+            self.failures,
+            e,
+            self.recovery.buffer
+        );
+        events::emit(Event::OutputError {
+            file: self.path.clone(),
+            error: e.to_string(),
+        });
+        true
+    }
+
+    /// Keeps a row during an outage.
+    pub fn hold(&mut self, record: &[&[u8]]) {
+        if let Some(outage) = &mut self.outage {
+            outage.bytes += memory::row_bytes(record);
+            outage.rows.push_back(owned(record));
+            self.trim();
+        }
+    }
+
+    // Drops the oldest rows beyond the buffer
+    fn trim(&mut self) {
+        let Some(outage) = &mut self.outage else { return };
+        let budget = memory::budget(Buffer::Outage);
+        while outage.rows.len() > self.recovery.buffer || (outage.bytes > budget && outage.rows.len() > 1) {
+            if let Some(row) = outage.rows.pop_front() {
+                outage.bytes -= memory::row_bytes(&row);
+            }
+            outage.dropped += 1;
+            LOST.fetch_add(1, Ordering::Relaxed);
+        }
+        HELD.store(outage.rows.len() as u64, Ordering::Relaxed);
+        memory::report(Buffer::Outage, outage.bytes);
+    }
+
+    /// Makes the next retry happen now rather than when it's due.
+    pub fn retry_now(&mut self) {
+        if let Some(outage) = &mut self.outage {
+            outage.retry_at = Instant::now();
+        }
+    }
+
+    /// During an outage, once a retry is due: a writer on the reopened file,
+    /// or the fallback, with the outage row and the held rows written. The
+    /// caller replaces its writer with it.
+    pub fn retry(&mut self) -> Option<Writer<Target>> {
+        let outage = self.outage.as_mut()?;
+        if Instant::now() < outage.retry_at {
+            return None;
+        }
+        let mut paths = vec![self.path.clone()];
+        paths.extend(self.recovery.fallback.clone().filter(|fallback| *fallback != self.path));
+        for path in paths {
+            if let Ok((writer, cut)) = replay(&path, &self.header, self.dialect, outage) {
+                let outage = self.outage.take().expect("Retried during an outage");
+                FAILING.store(false, Ordering::Relaxed);
+                HELD.store(0, Ordering::Relaxed);
+                memory::report(Buffer::Outage, 0);
+                if path != self.path {
+                    say!("Output continues in the fallback {} instead of {}.", path, self.path);
+                }
+                say!(
+                    "Writing {} again after {:.1}s; {} held rows written, {} dropped.",
+                    path,
+                    outage.started.elapsed().as_secs_f64(),
+                    outage.rows.len(),
+                    outage.dropped
+                );
+                events::emit(Event::OutputRecovered {
+                    file: path.clone(),
+                    outage_secs: outage.started.elapsed().as_secs_f64(),
+                    rows_replayed: outage.rows.len() as u64,
+                    rows_dropped: outage.dropped,
+                });
+                self.path = path;
+                self.cut = cut;
+                self.failures = 0;
+                return Some(writer);
+            }
+        }
+        outage.wait = (outage.wait * 2).min(MAX_RETRY);
+        outage.retry_at = Instant::now() + outage.wait;
+        None
+    }
+
+    /// Ends an outage that can't be recovered from, when the file is closed;
+    /// the held rows are lost.
+    pub fn give_up(&mut self) {
+        let Some(outage) = self.outage.take() else { return };
+        FAILING.store(false, Ordering::Relaxed);
+        HELD.store(0, Ordering::Relaxed);
+        memory::report(Buffer::Outage, 0);
+        LOST.fetch_add(outage.rows.len() as u64, Ordering::Relaxed);
+        esay!(
+            "ERROR: {} could not be written again; {} held rows are lost ({} dropped before).",
+            self.path,
+            outage.rows.len(),
+            outage.dropped
+        );
+    }
+}
+
+fn owned(record: &[&[u8]]) -> Vec<Vec<u8>> {
+    record.iter().map(|field| field.to_vec()).collect()
+}
+
+// Opens `path` for appending and writes the outage row and the held rows. A
+// new file gets the header; a partial last line, from a write cut short, is
+// ended first.
+fn replay(path: &str, header: &[String], dialect: CsvDialect, outage: &Outage) -> io::Result<(Writer<Target>, Arc<AtomicBool>)> {
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    let len = file.metadata()?.len();
+    if len > 0 && !ends_with_newline(&mut file)? {
+        file.write_all(dialect.line_end().as_bytes())?;
+    }
+    let cut = Arc::new(AtomicBool::new(false));
+    let mut writer = dialect.builder().flexible(true).from_writer(Target::Tracked(file, Arc::clone(&cut)));
+    let written = (|| {
+        if len == 0 {
+            writer.write_record(header)?;
+        }
+        let detail = format!(
+            "writing failed for {:.1}s ({}); {} rows written late, {} dropped",
+            outage.started.elapsed().as_secs_f64(),
+            outage.error,
+            outage.rows.len(),
+            outage.dropped
+        );
+        let mut row = vec![Kind::Outage.as_str(), outage.since.as_str(), detail.as_str()];
+        row.resize(header.len().max(row.len()), "");
+        writer.write_record(&row)?;
+        for row in &outage.rows {
+            writer.write_record(row)?;
+        }
+        writer.flush()?;
+        Ok::<(), csv::Error>(())
+    })();
+    if let Err(e) = written {
+        // Whatever the writer still holds is replayed in full next time
+        cut.store(true, Ordering::Relaxed);
+        return Err(e.into());
+    }
+    Ok((writer, cut))
+}
+
+fn ends_with_newline(file: &mut File) -> io::Result<bool> {
+    let mut last = [0];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
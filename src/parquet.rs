@@ -0,0 +1,311 @@
+//! Writing output as a Parquet file, for `--format parquet`.
+//!
+//! Rows are collected into row groups of a fixed number of rows, written by
+//! the `parquet` crate with Snappy compression. Every column is optional, an
+//! empty cell or one that doesn't parse being null, and the column types are
+//! those of `--format arrow` (see `arrow::column_types`).
+//!
+//! Parquet keeps its metadata in a footer at the end of the file, which the
+//! crate writes when the file is closed, so a file cut off by a crash would
+//! be unreadable. Instead the file is closed after every row group: the next
+//! version, the groups so far copied over unchanged and the new one after
+//! them, is written next to it (with `.tmp` added to the name), synced, and
+//! renamed over it. Whenever the logger stops, the file holds every group
+//! written before, and a crash loses at most the rows of the group being
+//! collected. Copying the groups makes a group take longer the larger the
+//! file has grown.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use arrow_array::RecordBatch;
+use parquet::arrow::arrow_writer::{compute_leaves, get_column_writers};
+use parquet::arrow::{add_encoded_arrow_schema_to_metadata, ArrowSchemaConverter};
+use parquet::basic::Compression;
+use parquet::column::writer::ColumnCloseResult;
+use parquet::errors::ParquetError;
+use parquet::file::metadata::{KeyValue, ParquetMetaDataReader};
+use parquet::file::properties::{WriterProperties, WriterPropertiesPtr};
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::SchemaDescriptor;
+
+use crate::arrow::{Field, Rows};
+
+/// Writes rows to a Parquet file, a row group at a time.
+pub struct FileWriter {
+    path: PathBuf,
+    rows: Rows,
+    schema: SchemaDescriptor,
+    properties: WriterPropertiesPtr,
+    group_rows: usize,
+    finished: bool,
+}
+
+impl FileWriter {
+    /// Writes a file without rows to `out`, just created at `path`, with
+    /// `metadata` as its key-value metadata.
+    pub fn new(
+        path: impl Into<PathBuf>,
+        out: File,
+        fields: Vec<Field>,
+        metadata: &[(String, String)],
+        group_rows: usize,
+    ) -> io::Result<Self> {
+        let rows = Rows::new(fields, metadata);
+        let schema = ArrowSchemaConverter::new().convert(rows.schema()).map_err(io_error)?;
+        let key_values = metadata.iter().map(|(key, value)| KeyValue::new(key.clone(), value.clone())).collect::<Vec<_>>();
+        let mut properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_key_value_metadata((!key_values.is_empty()).then_some(key_values))
+            .set_created_by(concat!("serial_logger ", env!("CARGO_PKG_VERSION")).to_string())
+            .build();
+        add_encoded_arrow_schema_to_metadata(rows.schema(), &mut properties);
+        let writer = FileWriter {
+            path: path.into(),
+            rows,
+            schema,
+            properties: Arc::new(properties),
+            group_rows: group_rows.max(1),
+            finished: false,
+        };
+        writer.write_file(out, None, None).map_err(io_error)?;
+        Ok(writer)
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        self.rows.fields()
+    }
+
+    /// Adds a row of text cells, one per field (missing ones are null), and
+    /// writes a row group once enough rows have been collected.
+    pub fn push_row<T: AsRef<[u8]>>(&mut self, cells: &[T]) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other("the Parquet file has already been finished"));
+        }
+        self.rows.push(cells);
+        if self.rows.len() >= self.group_rows {
+            self.write_group()?;
+        }
+        Ok(())
+    }
+
+    /// Does nothing: the file on disk is complete after every row group, and
+    /// the rows of an incomplete group stay in memory until it fills up or
+    /// the file is finished.
+    pub fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes the rows collected so far as a last row group. Finishing again
+    /// does nothing.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        if !self.rows.is_empty() {
+            self.write_group()?;
+        }
+        self.finished = true;
+        Ok(())
+    }
+
+    // Replaces the file with one holding its groups and the new one
+    fn write_group(&mut self) -> io::Result<()> {
+        let batch = self.rows.take()?;
+        let mut tmp = OsString::from(self.path.as_os_str());
+        tmp.push(".tmp");
+        let previous = File::open(&self.path)?;
+        self.write_file(File::create(&tmp)?, Some(&previous), Some(&batch)).map_err(io_error)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    // Writes a complete file to `out`: the row groups of `previous`, copied
+    // as they are, then `batch` as a row group of its own, and syncs it
+    fn write_file(&self, out: File, previous: Option<&File>, batch: Option<&RecordBatch>) -> Result<(), ParquetError> {
+        let mut writer = SerializedFileWriter::new(out, self.schema.root_schema_ptr(), Arc::clone(&self.properties))?;
+        if let Some(previous) = previous {
+            let metadata = ParquetMetaDataReader::new().parse_and_finish(previous)?;
+            for group in metadata.row_groups() {
+                let mut group_writer = writer.next_row_group()?;
+                for column in group.columns() {
+                    let close = ColumnCloseResult {
+                        bytes_written: column.compressed_size() as u64,
+                        rows_written: group.num_rows() as u64,
+                        metadata: column.clone(),
+                        bloom_filter: None,
+                        column_index: None,
+                        offset_index: None,
+                    };
+                    group_writer.append_column(previous, close)?;
+                }
+                group_writer.close()?;
+            }
+        }
+        if let Some(batch) = batch {
+            let mut columns = get_column_writers(&self.schema, &self.properties, self.rows.schema())?;
+            for ((column, field), array) in columns.iter_mut().zip(self.rows.schema().fields()).zip(batch.columns()) {
+                for leaf in compute_leaves(field, array)? {
+                    column.write(&leaf)?;
+                }
+            }
+            let mut group_writer = writer.next_row_group()?;
+            for column in columns {
+                column.close()?.append_to_row_group(&mut group_writer)?;
+            }
+            group_writer.close()?;
+        }
+        writer.into_inner()?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn io_error(error: ParquetError) -> io::Error {
+    match error {
+        ParquetError::External(error) => match error.downcast::<io::Error>() {
+            Ok(error) => *error,
+            Err(error) => io::Error::other(error),
+        },
+        other => io::Error::other(other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arrow::DataType;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field as Value;
+    use std::path::Path;
+
+    fn fields() -> Vec<Field> {
+        [("Timestamp", DataType::TimestampMs), ("name", DataType::Utf8), ("count", DataType::Int64), ("x", DataType::Float64)]
+            .into_iter()
+            .map(|(name, data_type)| Field {
+                name: name.to_string(),
+                data_type,
+            })
+            .collect()
+    }
+
+    fn create(path: &Path, metadata: &[(String, String)], group_rows: usize) -> FileWriter {
+        FileWriter::new(path, File::create(path).unwrap(), fields(), metadata, group_rows).unwrap()
+    }
+
+    fn read(path: &Path) -> SerializedFileReader<File> {
+        SerializedFileReader::new(File::open(path).unwrap()).unwrap()
+    }
+
+    fn rows(reader: &SerializedFileReader<File>) -> Vec<Vec<Value>> {
+        reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().get_column_iter().map(|(_, value)| value.clone()).collect())
+            .collect()
+    }
+
+    fn millis(text: &str) -> i64 {
+        crate::logfile::utc_millis(text).unwrap()
+    }
+
+    #[test]
+    fn a_reference_reader_reads_every_row_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.parquet");
+        let metadata = [("port".to_string(), "COM3".to_string())];
+        let mut writer = create(&path, &metadata, 2);
+        let rows_in: [[&str; 4]; 5] = [
+            ["2024-05-01 12:00:00.250", "a", "1", "0.5"],
+            ["2024-05-01 12:00:01.000", "", "n/a", "-2"],
+            ["", "c", "3", ""],
+            ["2024-05-01 12:00:03.000", "d", "-4", "1e3"],
+            ["2024-05-01 12:00:04.000", "e", "5", "2.25"],
+        ];
+        for row in &rows_in {
+            writer.push_row(row).unwrap();
+        }
+        writer.finish().unwrap();
+        let reader = read(&path);
+
+        let meta = reader.metadata();
+        assert_eq!(meta.num_row_groups(), 3);
+        assert_eq!(meta.file_metadata().num_rows(), 5);
+        assert_eq!(meta.row_group(0).column(0).compression(), Compression::SNAPPY);
+        let names: Vec<&str> = meta.file_metadata().schema_descr().columns().iter().map(|column| column.name()).collect();
+        assert_eq!(names, ["Timestamp", "name", "count", "x"]);
+        let kv = meta.file_metadata().key_value_metadata().unwrap();
+        assert_eq!((kv[0].key.as_str(), kv[0].value.as_deref()), ("port", Some("COM3")));
+
+        let expected = vec![
+            vec![Value::TimestampMillis(millis(rows_in[0][0])), Value::Str("a".into()), Value::Long(1), Value::Double(0.5)],
+            vec![Value::TimestampMillis(millis(rows_in[1][0])), Value::Null, Value::Null, Value::Double(-2.0)],
+            vec![Value::Null, Value::Str("c".into()), Value::Long(3), Value::Null],
+            vec![Value::TimestampMillis(millis(rows_in[3][0])), Value::Str("d".into()), Value::Long(-4), Value::Double(1000.0)],
+            vec![Value::TimestampMillis(millis(rows_in[4][0])), Value::Str("e".into()), Value::Long(5), Value::Double(2.25)],
+        ];
+        assert_eq!(rows(&reader), expected);
+        let timestamp = meta.file_metadata().schema_descr().column(0).logical_type();
+        assert_eq!(
+            timestamp,
+            Some(parquet::basic::LogicalType::Timestamp {
+                is_adjusted_to_u_t_c: true,
+                unit: parquet::basic::TimeUnit::MILLIS(Default::default()),
+            })
+        );
+    }
+
+    #[test]
+    fn a_file_without_row_groups_is_read_as_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.parquet");
+        create(&path, &[], 10);
+        let reader = read(&path);
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 0);
+        assert_eq!(reader.metadata().file_metadata().schema_descr().num_columns(), 4);
+    }
+
+    #[test]
+    fn an_unfinished_file_holds_every_group_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.parquet");
+        let mut writer = create(&path, &[], 2);
+        for i in 0..5 {
+            writer.push_row(&["2024-05-01 12:00:00", "long enough to compress and then some more", &i.to_string(), "1.5"]).unwrap();
+        }
+        // As if the logger had been killed here, with the last row in memory
+        let reader = read(&path);
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        let counts: Vec<Value> = rows(&reader).into_iter().map(|row| row[2].clone()).collect();
+        assert_eq!(counts, [0, 1, 2, 3].map(Value::Long));
+    }
+
+    #[test]
+    fn a_version_left_half_written_is_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.parquet");
+        let mut writer = create(&path, &[], 1);
+        std::fs::write(dir.path().join("out.parquet.tmp"), b"PAR1 cut off").unwrap();
+        writer.push_row(&["", "a", "1", ""]).unwrap();
+        assert_eq!(rows(&read(&path)).len(), 1);
+        assert!(!dir.path().join("out.parquet.tmp").exists());
+    }
+
+    #[test]
+    fn pages_of_many_rows_decompress() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.parquet");
+        let mut writer = create(&path, &[], 5000);
+        for i in 0..5000 {
+            let name = format!("sensor {}", i % 7);
+            writer.push_row(&["2024-05-01 12:00:00", &name, &(i * 3).to_string(), &(i as f64 / 8.0).to_string()]).unwrap();
+        }
+        writer.finish().unwrap();
+        let rows = rows(&read(&path));
+        assert_eq!(rows.len(), 5000);
+        assert_eq!(rows[4999][1], Value::Str("sensor 1".into()));
+        assert_eq!(rows[4999][2], Value::Long(14997));
+        assert_eq!(rows[4999][3], Value::Double(4999.0 / 8.0));
+    }
+}
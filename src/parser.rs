@@ -0,0 +1,507 @@
+//! Which lines carry data and how they split into fields.
+//!
+//! A data line contains the `--match` text (by default `UDP packet
+//! contents:`); everything after it is the payload, split on `--delimiter`
+//! into exactly `--fields` values. A space delimiter splits on runs of
+//! whitespace. An empty `--match` makes every line a data line. With
+//! `--select` the fields are then picked and reordered by their index in the
+//! payload as sent; the count is checked before that, so `--fields` always
+//! counts the payload as the device sends it. Columns computed with
+//! `--compute` follow the selected fields. Fields are written as received;
+//! where their numeric value counts (computed columns, alarms, statistics),
+//! `--decimal-comma` reads `-2,45` as -2.45. With `--types` each field is
+//! rewritten in its type's normal form (see `schema`) before anything else
+//! sees it.
+//!
+//! With `--protocol binary` the input is split into frames by `frame`
+//! instead; each frame's decoded values take the place of a line's fields,
+//! and selection and computed columns apply to them the same way.
+//!
+//! With `--payload-encoding hex` or `base64` the payload is decoded first
+//! (see `encoding`) and the bytes are split into fields as a text record or,
+//! with `--inner-protocol binary`, decoded as one binary frame.
+//!
+//! Live logging and `reparse` both go through this module, so a raw capture
+//! parses exactly the way the port's input did. `LineFormat::parse_line` does
+//! all of it for one line, for tools that post-process captures themselves.
+
+use std::{borrow::Cow, fmt};
+
+use crate::ansi;
+use crate::encoding::Encoding;
+use crate::expr::Expr;
+use crate::frame::FrameSpec;
+use crate::logfile::Row;
+use crate::probe::split_fields;
+use crate::schema::{FloatFormat, Schema, TypeError};
+
+/// Column names for the default four-field payload.
+pub const DEFAULT_FIELD_NAMES: [&str; 4] = ["time (ms)", "X acc", "Y acc", "Z acc"];
+
+#[derive(Debug, Clone)]
+pub struct LineFormat {
+    pub pattern: String,
+    pub delimiter: char,
+    pub fields: usize,
+    // Indices of the payload fields to keep, in output order (--select)
+    pub select: Option<Vec<usize>>,
+    // Columns computed from the fields (--compute), as name and expression
+    pub computed: Vec<(String, Expr)>,
+    // Binary frames instead of text lines (--protocol binary); `fields` is
+    // then the number of fields in a frame
+    pub frames: Option<FrameSpec>,
+    pub framing: Framing,
+    // How the payload after the match text is encoded (--payload-encoding)
+    pub encoding: Encoding,
+    // The decoded payload is a binary frame rather than a text record
+    // (--inner-protocol binary)
+    pub inner_frames: Option<FrameSpec>,
+    // Fields use a decimal comma (--decimal-comma)
+    pub decimal_comma: bool,
+    // Type of each written field (--types)
+    pub types: Option<Schema>,
+    // How floats in typed fields and computed columns are written
+    // (--float-format)
+    pub float_format: FloatFormat,
+    // Header names of the payload fields as sent (--names)
+    pub names: Option<Vec<String>>,
+}
+
+/// How packets are delimited on the wire (--framing).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    // Text lines ending in a newline, or binary frames found by their sync word
+    Plain,
+    // COBS-encoded packets ending in a zero byte
+    Cobs,
+}
+
+pub enum Parsed<'a> {
+    // The line doesn't contain the match text
+    NoMatch,
+    Fields(Vec<&'a str>),
+    WrongCount { payload: &'a str, got: usize },
+    // The fields of a hex or base64 payload, already selected
+    Decoded(Vec<String>),
+    // A hex or base64 payload that didn't decode (or didn't hold a frame)
+    BadPayload { payload: &'a str, error: String },
+}
+
+/// Why a data line didn't make a data row. `code` is the reason the logger
+/// gives for it in the rejects file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    FieldCount { expected: usize, got: usize },
+    // A hex or base64 payload that didn't decode, or didn't hold a frame
+    BadPayload(String),
+    WrongType(TypeError),
+}
+
+impl ParseError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::FieldCount { .. } => "field-count",
+            ParseError::BadPayload(_) => "bad-payload",
+            ParseError::WrongType(_) => "wrong-type",
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::FieldCount { expected, got } => write!(f, "expected {} fields, got {}", expected, got),
+            ParseError::BadPayload(error) => write!(f, "payload didn't decode: {}", error),
+            ParseError::WrongType(e) => write!(f, "doesn't match --types: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl LineFormat {
+    /// Makes the data row a line of device output gives, arriving at
+    /// `timestamp`: selected and typed fields, then computed columns, as the
+    /// logger writes them (without `--operator`/`--experiment` columns).
+    /// None for a line without the match text. Binary frames aren't lines;
+    /// they are decoded with `frame` instead.
+    pub fn parse_line(&self, timestamp: &str, line: &str) -> Result<Option<Row>, ParseError> {
+        let fields: Vec<String> = match self.parse(line) {
+            Parsed::NoMatch => return Ok(None),
+            Parsed::Fields(fields) => fields.into_iter().map(str::to_string).collect(),
+            Parsed::Decoded(fields) => fields,
+            Parsed::WrongCount { got, .. } => {
+                return Err(ParseError::FieldCount {
+                    expected: self.fields,
+                    got,
+                })
+            }
+            Parsed::BadPayload { error, .. } => return Err(ParseError::BadPayload(error)),
+        };
+        let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+        let typed = self.coerce(&fields).map_err(ParseError::WrongType)?;
+        let fields: Vec<&str> = typed.iter().map(|field| field.as_ref()).collect();
+        let mut values: Vec<String> = fields.iter().map(|field| field.to_string()).collect();
+        values.extend(self.compute(&fields).into_iter().map(|value| computed_cell(value, self.float_format)));
+        Ok(Some(Row::Data {
+            timestamp: timestamp.to_string(),
+            values,
+        }))
+    }
+
+    pub fn parse<'a>(&self, line: &'a str) -> Parsed<'a> {
+        let Some(start) = line.find(self.pattern.as_str()) else {
+            return Parsed::NoMatch;
+        };
+        // e.g., "7551870,-2.45,-3.69,-9.15"
+        let payload = line[start + self.pattern.len()..].trim();
+        if self.encoding != Encoding::Plain {
+            return self.parse_encoded(payload);
+        }
+        let fields = split_fields(payload, self.delimiter);
+        if fields.len() != self.fields {
+            Parsed::WrongCount {
+                payload,
+                got: fields.len(),
+            }
+        } else {
+            Parsed::Fields(self.select_fields(fields))
+        }
+    }
+
+    fn parse_encoded<'a>(&self, payload: &'a str) -> Parsed<'a> {
+        let bytes = match self.encoding.decode(payload) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return Parsed::BadPayload {
+                    payload,
+                    error: e.to_string(),
+                }
+            }
+        };
+        let values = match &self.inner_frames {
+            Some(spec) => match spec.decode_packet(&bytes) {
+                Ok(values) => values,
+                Err(error) => return Parsed::BadPayload { payload, error },
+            },
+            None => {
+                let record = String::from_utf8_lossy(&bytes);
+                // Radios often pad the record with NULs
+                let record = record.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+                let fields = split_fields(record, self.delimiter);
+                if fields.len() != self.fields {
+                    return Parsed::WrongCount {
+                        payload,
+                        got: fields.len(),
+                    };
+                }
+                fields.into_iter().map(str::to_string).collect()
+            }
+        };
+        let fields = self.select_fields(values.iter().map(String::as_str).collect());
+        Parsed::Decoded(fields.into_iter().map(str::to_string).collect())
+    }
+
+    /// Picks the `--select`ed fields out of a full payload.
+    pub fn select_fields<'a>(&self, fields: Vec<&'a str>) -> Vec<&'a str> {
+        match &self.select {
+            Some(select) => select.iter().map(|&i| fields[i]).collect(),
+            None => fields,
+        }
+    }
+
+    /// The fields normalized to their `--types`; as they are without a schema.
+    pub fn coerce<'a>(&self, fields: &[&'a str]) -> Result<Vec<Cow<'a, str>>, TypeError> {
+        match &self.types {
+            Some(schema) => Ok(schema
+                .coerce(fields, self.decimal_comma, self.float_format)?
+                .into_iter()
+                .map(Cow::Owned)
+                .collect()),
+            None => Ok(fields.iter().map(|field| Cow::Borrowed(*field)).collect()),
+        }
+    }
+
+    /// Names of the value columns: the accelerometer names for the default
+    /// four fields, otherwise `field 1`, `field 2`, ... With `--select`, the
+    /// selected fields' names in the selected order; then the computed columns.
+    pub fn column_names(&self) -> Vec<String> {
+        let names = self.payload_names();
+        let mut columns = match &self.select {
+            Some(select) => select.iter().map(|&i| names[i].clone()).collect(),
+            None => names,
+        };
+        columns.extend(self.computed.iter().map(|(name, _)| name.clone()));
+        columns
+    }
+
+    /// Number of fields in a parsed row, before the computed columns.
+    pub fn field_count(&self) -> usize {
+        self.select.as_ref().map_or(self.fields, Vec::len)
+    }
+
+    /// Values of the computed columns for a parsed row; None where the
+    /// expression couldn't be evaluated.
+    pub fn compute(&self, fields: &[&str]) -> Vec<Option<f64>> {
+        if self.computed.is_empty() {
+            return Vec::new();
+        }
+        let values = self.values(fields);
+        self.computed.iter().map(|(_, expr)| expr.eval(&values)).collect()
+    }
+
+    /// Numeric values of a row's fields, None for fields that aren't numbers.
+    /// With `--decimal-comma`, `-2,45` is -2.45.
+    pub fn values(&self, fields: &[&str]) -> Vec<Option<f64>> {
+        fields
+            .iter()
+            .map(|field| {
+                let field = field.trim();
+                if self.decimal_comma {
+                    field.replace(',', ".").parse().ok()
+                } else {
+                    field.parse().ok()
+                }
+            })
+            .collect()
+    }
+
+    // Names of all fields of the payload as sent
+    fn payload_names(&self) -> Vec<String> {
+        if let Some(names) = &self.names {
+            names.clone()
+        } else if self.fields == DEFAULT_FIELD_NAMES.len() {
+            DEFAULT_FIELD_NAMES.iter().map(|name| name.to_string()).collect()
+        } else {
+            (1..=self.fields).map(|i| format!("field {}", i)).collect()
+        }
+    }
+}
+
+/// The cell of a computed column: its value as `float_format` writes it, or
+/// blank where the expression couldn't be evaluated.
+pub fn computed_cell(value: Option<f64>, float_format: FloatFormat) -> String {
+    value.map_or(String::new(), |value| float_format.format(value))
+}
+
+/// Parses a `--select` argument such as `0,5,2` into payload field indices;
+/// every index must be below `fields`.
+pub fn parse_select(arg: &str, fields: usize) -> Result<Vec<usize>, String> {
+    arg.split(',')
+        .map(|index| {
+            let index = index.trim();
+            match index.parse::<usize>() {
+                Ok(i) if i < fields => Ok(i),
+                Ok(i) => Err(format!(
+                    "Invalid --select index {}: the payload has {} fields (0 to {})",
+                    i,
+                    fields,
+                    fields - 1
+                )),
+                Err(_) => Err(format!("Invalid --select '{}': expected field indices such as 0,5,2", arg)),
+            }
+        })
+        .collect()
+}
+
+/// Parses a `--compute` argument such as `mag=sqrt(f1*f1+f2*f2)` for a row
+/// of `fields` values.
+pub fn parse_compute(arg: &str, fields: usize) -> Result<(String, Expr), String> {
+    let (name, text) = arg
+        .split_once('=')
+        .filter(|(name, _)| !name.trim().is_empty())
+        .ok_or_else(|| format!("Invalid --compute '{}': expected NAME=EXPRESSION", arg))?;
+    let expr = Expr::parse(text).map_err(|e| format!("Invalid --compute '{}': {}", arg, e))?;
+    if let Some(var) = expr.max_var().filter(|var| *var >= fields) {
+        return Err(format!(
+            "Invalid --compute '{}': f{} doesn't exist; rows have {} fields (f0 to f{})",
+            arg,
+            var,
+            fields,
+            fields - 1
+        ));
+    }
+    Ok((name.trim().to_string(), expr))
+}
+
+/// Parses a `--delimiter` argument: a single character, or `tab`/`space`.
+pub fn parse_delimiter(arg: &str) -> Result<char, String> {
+    match arg {
+        "tab" | "\\t" => Ok('\t'),
+        "space" => Ok(' '),
+        _ => {
+            let mut chars = arg.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(c),
+                _ => Err(format!("Invalid --delimiter '{}': expected a single character, tab or space", arg)),
+            }
+        }
+    }
+}
+
+/// The lines in the bytes of one read up to a line feed. A CR LF, a lone CR
+/// or a lone LF each end a line, and nothing between two of them is no line
+/// at all, so `\r\n\r\n` or `\r\r\n` between records give no empty lines.
+pub fn split_lines(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes.split(|b| *b == b'\r' || *b == b'\n').filter(|line| !line.is_empty())
+}
+
+/// Turns the raw bytes of a received line into the text that is parsed: bytes
+/// that aren't valid UTF-8 (e.g. at a wrong baud rate) become U+FFFD rather
+/// than losing the line, escape sequences are removed if `strip_ansi`, tabs
+/// (unless they delimit fields) and NULs left by breaks are dropped and
+/// whitespace is trimmed.
+pub fn clean_line(bytes: &[u8], strip_ansi: bool, delimiter: char) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    let line: Cow<str> = if strip_ansi { ansi::strip(&text) } else { text.as_ref().into() };
+    if delimiter == '\t' {
+        line.trim().replace('\0', "")
+    } else {
+        line.trim().replace(['\t', '\0'], "")
+    }
+}
+
+/// Discards a device's boot output: first the `--skip-lines` count, then
+/// everything up to and including the `--wait-for` marker line. A new gate is
+/// armed whenever a port is (re)opened.
+pub struct Gate {
+    skip_remaining: u64,
+    wait_for: Option<String>,
+}
+
+pub enum Admit {
+    Discard,
+    // The line carried the sync marker; processing starts with the next one
+    Synced,
+    Pass,
+}
+
+impl Gate {
+    pub fn new(skip_lines: u64, wait_for: Option<&str>) -> Gate {
+        Gate {
+            skip_remaining: skip_lines,
+            wait_for: wait_for.map(str::to_string),
+        }
+    }
+
+    /// Still waiting for the sync marker.
+    pub fn waiting(&self) -> bool {
+        self.wait_for.is_some()
+    }
+
+    pub fn admit(&mut self, line: &str) -> Admit {
+        if self.skip_remaining > 0 {
+            self.skip_remaining -= 1;
+            return Admit::Discard;
+        }
+        match &self.wait_for {
+            Some(marker) => {
+                if line.contains(marker.as_str()) {
+                    self.wait_for = None;
+                    Admit::Synced
+                } else {
+                    Admit::Discard
+                }
+            }
+            None => Admit::Pass,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+
+    fn lines(bytes: &[u8]) -> Vec<&[u8]> {
+        split_lines(bytes).collect()
+    }
+
+    #[test]
+    fn computed_columns_follow_the_float_format() {
+        let (_, ratio) = parse_compute("ratio=f0/f1", 2).unwrap();
+        let cell = |a: f64, b: f64, format| computed_cell(ratio.eval(&[Some(a), Some(b)]), format);
+        assert_eq!(cell(-2.45, 1.0, FloatFormat::Passthrough), "-2.45");
+        assert_eq!(cell(1.0, 3.0, FloatFormat::Fixed(3)), "0.333");
+        assert_eq!(cell(-1.0, 1e9, FloatFormat::Fixed(3)), "0.000");
+        assert_eq!(cell(2.0, 3.0, FloatFormat::Significant(4)), "0.6667");
+        // Division by zero and overflow have no value, so a blank cell
+        // whatever the format
+        for format in [FloatFormat::Passthrough, FloatFormat::Fixed(3), FloatFormat::Significant(6)] {
+            assert_eq!(cell(1e200, 1e-200, format), "");
+            assert_eq!(cell(1.0, 0.0, format), "");
+            assert_eq!(cell(0.0, 0.0, format), "");
+        }
+    }
+
+    // Hands out one chunk per read, as a port does with whatever has arrived
+    struct Reads<'a>(std::vec::IntoIter<&'a [u8]>);
+
+    impl Read for Reads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some(chunk) = self.0.next() else { return Ok(0) };
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    // The lines the serial thread gets from these reads: bytes are gathered
+    // up to a line feed, then split
+    fn lines_from_reads(reads: Vec<&[u8]>) -> Vec<String> {
+        let mut reader = BufReader::new(Reads(reads.into_iter()));
+        let mut lines = Vec::new();
+        let mut buffer = Vec::new();
+        while reader.read_until(b'\n', &mut buffer).unwrap() > 0 {
+            lines.extend(split_lines(&buffer).map(|line| String::from_utf8_lossy(line).into_owned()));
+            buffer.clear();
+        }
+        lines
+    }
+
+    #[test]
+    fn each_terminator_ends_a_line() {
+        assert_eq!(lines(b"1,2,3\n"), [b"1,2,3"]);
+        assert_eq!(lines(b"1,2,3\r\n"), [b"1,2,3"]);
+        assert_eq!(lines(b"1,2,3\r"), [b"1,2,3"]);
+        assert_eq!(lines(b"1,2,3"), [b"1,2,3"]);
+    }
+
+    #[test]
+    fn mixed_terminators_give_one_line_per_record() {
+        assert_eq!(lines(b"a\rb\r\nc\nd\n\re"), [&b"a"[..], b"b", b"c", b"d", b"e"]);
+        assert_eq!(lines(b"a\r\r\nb\r\n"), [b"a", b"b"]);
+    }
+
+    #[test]
+    fn nothing_between_terminators_is_no_line() {
+        assert!(lines(b"").is_empty());
+        assert!(lines(b"\r\n").is_empty());
+        assert!(lines(b"\r\n\r\n\n\r\r").is_empty());
+        assert_eq!(lines(b"\r\n\r\nx\r\n\r\n"), [b"x"]);
+    }
+
+    #[test]
+    fn whitespace_lines_are_lines_until_cleaned() {
+        // Blank lines are dropped after clean_line, not by splitting
+        assert_eq!(lines(b" \t\r\n"), [b" \t"]);
+        assert_eq!(clean_line(b" \t", true, ','), "");
+        assert_eq!(clean_line(b"\x1b[0m", true, ','), "");
+        assert_eq!(clean_line(b" 1,\t2 ", true, ','), "1,2");
+        assert_eq!(clean_line(b"1\t2", true, '\t'), "1\t2");
+    }
+
+    #[test]
+    fn a_crlf_split_across_reads_ends_one_line() {
+        assert_eq!(lines_from_reads(vec![b"1,2\r", b"\n3,4\r\n"]), ["1,2", "3,4"]);
+        assert_eq!(lines_from_reads(vec![b"1,2\r", b"\n", b"3,4\r", b"\n"]), ["1,2", "3,4"]);
+    }
+
+    #[test]
+    fn a_line_split_across_reads_is_joined() {
+        assert_eq!(lines_from_reads(vec![b"1,", b"2,3", b"\r\n"]), ["1,2,3"]);
+        // A lone CR at the end of a read still ends its line when more follows
+        assert_eq!(lines_from_reads(vec![b"1,2\r", b"3,4\n"]), ["1,2", "3,4"]);
+        // LF CR terminators leave the CR at the start of the next read
+        assert_eq!(lines_from_reads(vec![b"1,2\n", b"\r3,4\n", b"\r"]), ["1,2", "3,4"]);
+    }
+}
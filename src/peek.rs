@@ -0,0 +1,64 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// Number of raw lines kept for `peek <n>`
+pub const RAW_HISTORY: usize = 16;
+
+/// Most recent lines seen by the serial thread, for the `peek` command.
+#[derive(Default)]
+pub struct Peek {
+    raw: VecDeque<(String, Instant)>,
+    matched: Option<(String, Instant)>,
+    parsed: Option<(String, Instant)>,
+}
+
+impl Peek {
+    pub fn raw_line(&mut self, line: &str) {
+        if self.raw.len() == RAW_HISTORY {
+            self.raw.pop_front();
+        }
+        self.raw.push_back((line.to_string(), Instant::now()));
+    }
+
+    pub fn matched_line(&mut self, line: &str) {
+        self.matched = Some((line.to_string(), Instant::now()));
+    }
+
+    pub fn parsed_record(&mut self, fields: &[&str]) {
+        self.parsed = Some((fields.join(","), Instant::now()));
+    }
+
+    /// Prints the latest raw, matched and parsed lines, or with `count` the
+    /// last `count` raw lines.
+    pub fn print(&self, count: Option<usize>) {
+        match count {
+            None => {
+                say!("Last raw line:    {}", describe(self.raw.back()));
+                say!("Last matched:     {}", describe(self.matched.as_ref()));
+                say!("Last parsed:      {}", describe(self.parsed.as_ref()));
+            }
+            Some(count) => {
+                if self.raw.is_empty() {
+                    say!("No lines received yet.");
+                }
+                let skip = self.raw.len().saturating_sub(count);
+                for (line, at) in self.raw.iter().skip(skip) {
+                    say!("{:>8} ago  {}", format_age(at.elapsed()), line);
+                }
+            }
+        }
+    }
+}
+
+fn describe(entry: Option<&(String, Instant)>) -> String {
+    match entry {
+        Some((line, at)) => format!("{} ({} ago)", line, format_age(at.elapsed())),
+        None => "(none yet)".to_string(),
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    format!("{:.1}s", age.as_secs_f64())
+}
@@ -0,0 +1,179 @@
+//! `--prebuffer`: the data rows from just before a run starts.
+//!
+//! While not recording, parsed rows are kept in a ring of either the last N
+//! rows (`--prebuffer 500`) or the last stretch of time (`--prebuffer 2s`).
+//! When a run starts, they are written right after its start marker as `pre`
+//! rows with the timestamps they arrived at, and the ring starts over empty.
+//! Without the option there is no ring and nothing is kept. The bytes it
+//! holds are accounted in `memory`.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::memory::{self, Buffer};
+use crate::units;
+
+// A time window is also capped at this many rows, so a fast device can't
+// make it grow without bound
+pub const MAX_ROWS: usize = 100_000;
+
+/// How much the ring keeps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Rows(usize),
+    Time(Duration),
+}
+
+impl Limit {
+    /// Parses `--prebuffer`: a number of rows, or a duration such as `2s`.
+    pub fn parse(text: &str) -> Result<Limit, String> {
+        let text = text.trim();
+        if let Ok(rows) = text.parse::<usize>() {
+            if rows == 0 || rows > MAX_ROWS {
+                return Err(format!("Invalid --prebuffer '{}': expected 1 to {} rows", text, MAX_ROWS));
+            }
+            return Ok(Limit::Rows(rows));
+        }
+        match units::parse_duration(text) {
+            Ok(window) if !window.is_zero() => Ok(Limit::Time(window)),
+            _ => Err(format!("Invalid --prebuffer '{}': expected a number of rows or a duration such as 2s", text)),
+        }
+    }
+}
+
+/// The rows kept while not recording, oldest first.
+pub struct PreBuffer {
+    limit: Limit,
+    rows: VecDeque<(Instant, Vec<String>)>,
+    bytes: usize,
+}
+
+impl PreBuffer {
+    pub fn new(limit: Limit) -> PreBuffer {
+        PreBuffer {
+            limit,
+            rows: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Keeps a data row (as it would be written) that arrived at `arrived`.
+    pub fn push(&mut self, arrived: Instant, record: &[&str]) {
+        let max = match self.limit {
+            Limit::Rows(rows) => rows,
+            Limit::Time(_) => MAX_ROWS,
+        };
+        let record: Vec<String> = record.iter().map(|cell| cell.to_string()).collect();
+        self.bytes += memory::row_bytes(&record);
+        self.rows.push_back((arrived, record));
+        // The newest row is kept even if it alone is over the budget
+        while self.rows.len() > max || (self.bytes > memory::budget(Buffer::Prebuffer) && self.rows.len() > 1) {
+            self.pop();
+        }
+        self.expire(arrived);
+        memory::report(Buffer::Prebuffer, self.bytes);
+    }
+
+    /// Empties the ring, returning the rows still within the limit at `now`.
+    pub fn take(&mut self, now: Instant) -> Vec<Vec<String>> {
+        self.expire(now);
+        self.bytes = 0;
+        memory::report(Buffer::Prebuffer, 0);
+        self.rows.drain(..).map(|(_, record)| record).collect()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let Limit::Time(window) = self.limit else { return };
+        while self.rows.front().is_some_and(|(arrived, _)| now.duration_since(*arrived) > window) {
+            self.pop();
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some((_, record)) = self.rows.pop_front() {
+            self.bytes -= memory::row_bytes(&record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::TEST_GLOBALS;
+
+    fn row(n: u32) -> Vec<String> {
+        vec!["data".to_string(), format!("2025-01-14 10:21:{:02}", n), String::new(), String::new(), n.to_string()]
+    }
+
+    fn push(prebuffer: &mut PreBuffer, arrived: Instant, n: u32) {
+        let record = row(n);
+        prebuffer.push(arrived, &record.iter().map(String::as_str).collect::<Vec<&str>>());
+    }
+
+    #[test]
+    fn limits_are_parsed() {
+        assert_eq!(Limit::parse("500"), Ok(Limit::Rows(500)));
+        assert_eq!(Limit::parse(" 2s "), Ok(Limit::Time(Duration::from_secs(2))));
+        for text in ["0", "100001", "0s", "soon"] {
+            assert!(Limit::parse(text).unwrap_err().starts_with(&format!("Invalid --prebuffer '{}'", text)));
+        }
+    }
+
+    #[test]
+    fn the_last_rows_are_kept_in_order() {
+        let _globals = TEST_GLOBALS.lock().unwrap();
+        let mut prebuffer = PreBuffer::new(Limit::Rows(3));
+        let now = Instant::now();
+        for n in 0..10 {
+            push(&mut prebuffer, now, n);
+        }
+        // Unchanged, with the timestamps they arrived with
+        assert_eq!(prebuffer.take(now), [row(7), row(8), row(9)]);
+    }
+
+    #[test]
+    fn a_time_window_keeps_the_rows_within_it() {
+        let _globals = TEST_GLOBALS.lock().unwrap();
+        let mut prebuffer = PreBuffer::new(Limit::Time(Duration::from_secs(2)));
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        for n in 0..10 {
+            push(&mut prebuffer, at(n as u64 * 500), n);
+        }
+        // At 4.5s the rows from 2.5s on are within two seconds
+        assert_eq!(prebuffer.take(at(4500)), [row(5), row(6), row(7), row(8), row(9)]);
+        // Rows go stale while waiting for the start too
+        push(&mut prebuffer, at(5000), 10);
+        assert!(prebuffer.take(at(8000)).is_empty());
+    }
+
+    #[test]
+    fn taking_the_rows_clears_the_ring() {
+        let _globals = TEST_GLOBALS.lock().unwrap();
+        let mut prebuffer = PreBuffer::new(Limit::Rows(100));
+        let now = Instant::now();
+        for n in 0..5 {
+            push(&mut prebuffer, now, n);
+        }
+        assert!(memory::used(Buffer::Prebuffer) > 0);
+        assert_eq!(prebuffer.take(now).len(), 5);
+        assert_eq!(memory::used(Buffer::Prebuffer), 0);
+        assert!(prebuffer.take(now).is_empty());
+        push(&mut prebuffer, now, 5);
+        assert_eq!(prebuffer.take(now), [row(5)]);
+    }
+
+    #[test]
+    fn the_bytes_held_are_reported() {
+        let _globals = TEST_GLOBALS.lock().unwrap();
+        let mut prebuffer = PreBuffer::new(Limit::Rows(2));
+        let now = Instant::now();
+        for n in 0..4 {
+            push(&mut prebuffer, now, n);
+        }
+        assert_eq!(memory::used(Buffer::Prebuffer), memory::row_bytes(&row(2)) + memory::row_bytes(&row(3)));
+        prebuffer.take(now);
+    }
+}
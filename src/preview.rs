@@ -0,0 +1,124 @@
+//! `--preview N`: the first N matching lines, shown as they would be
+//! recorded before anything is.
+//!
+//! While the preview collects, the serial thread notes every line that
+//! matches `--match` here, parsed into the output columns or with the reason
+//! it would be rejected. The command loop waits for N of them, prints them as
+//! a table under the column names and asks whether to go on. Nothing is
+//! recording meanwhile, so none of these lines reach the output.
+
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::repl::{Input, Prompt};
+
+// When the wait for lines gets a hint printed
+const QUIET_HINT: Duration = Duration::from_secs(10);
+
+/// One previewed line: its cells, and what's wrong with it if anything.
+pub struct Row {
+    cells: Vec<String>,
+    problem: Option<String>,
+}
+
+/// Lines collected for the preview.
+pub struct Preview {
+    wanted: usize,
+    rows: Vec<Row>,
+}
+
+impl Preview {
+    pub fn new(wanted: usize) -> Preview {
+        Preview {
+            wanted,
+            rows: Vec::with_capacity(wanted),
+        }
+    }
+}
+
+/// Notes a matching line while the preview collects: its cells and why it
+/// would be rejected, if it would.
+pub fn note(preview: &Mutex<Option<Preview>>, row: impl FnOnce() -> (Vec<String>, Option<String>)) {
+    if let Some(preview) = preview.lock().unwrap().as_mut().filter(|preview| preview.rows.len() < preview.wanted) {
+        let (cells, problem) = row();
+        preview.rows.push(Row { cells, problem });
+    }
+}
+
+/// Waits until the preview has its lines, stops collecting and returns them.
+pub fn collect(preview: &Mutex<Option<Preview>>) -> Vec<Row> {
+    let started = Instant::now();
+    let mut hinted = false;
+    loop {
+        {
+            let mut guard = preview.lock().unwrap();
+            if guard.as_ref().is_some_and(|preview| preview.rows.len() >= preview.wanted) {
+                return guard.take().map(|preview| preview.rows).unwrap_or_default();
+            }
+        }
+        if !hinted && started.elapsed() >= QUIET_HINT {
+            let got = preview.lock().unwrap().as_ref().map_or(0, |preview| preview.rows.len());
+            esay!("Still waiting for matching lines ({} so far); check --match and the port, or press Ctrl+C to quit.", got);
+            hinted = true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Prints the rows under the column names, with a verdict for each.
+pub fn print(rows: &[Row], columns: &[String]) {
+    let mut header: Vec<&str> = columns.iter().map(String::as_str).collect();
+    header.push("verdict");
+    let lines: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            let mut cells = row.cells.clone();
+            cells.resize(columns.len(), String::new());
+            cells.push(row.problem.clone().unwrap_or_else(|| "ok".to_string()));
+            cells
+        })
+        .collect();
+    let mut widths: Vec<usize> = header.iter().map(|name| name.chars().count()).collect();
+    for line in &lines {
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let format = |cells: &[&str]| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        padded.join("  ").trim_end().to_string()
+    };
+    say!("Preview of the first {} matching lines:", rows.len());
+    say!("  {}", format(&header));
+    for line in &lines {
+        let cells: Vec<&str> = line.iter().map(String::as_str).collect();
+        say!("  {}", format(&cells));
+    }
+    let bad = rows.iter().filter(|row| row.problem.is_some()).count();
+    if bad == 0 {
+        say!("All {} lines parse into {} columns.", rows.len(), columns.len());
+    } else {
+        esay!("Warning: {} of {} lines would be rejected; check --delimiter, --fields and --types.", bad, rows.len());
+    }
+}
+
+/// Asks whether to go on; Ctrl+C or the end of input is a no.
+pub fn proceed(prompt: &mut Prompt) -> bool {
+    loop {
+        match prompt.read("Proceed? [Y/n] ") {
+            Input::Line(answer) => match answer.trim().to_ascii_lowercase().as_str() {
+                "" | "y" | "yes" => return true,
+                "n" | "no" => return false,
+                _ => say!("Please answer y or n."),
+            },
+            Input::Interrupted | Input::Eof => return false,
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! Analysis behind `--probe`: given a few seconds of raw input from an
+//! unfamiliar device, work out how its lines end, what prefix marks the data
+//! lines, which delimiter splits them and whether the fields are numeric.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::ansi;
+
+/// Delimiters considered, in order of preference when equally good.
+pub const DELIMITER_CANDIDATES: [char; 6] = [',', ';', '\t', '|', ' ', ':'];
+
+/// Sample lines shown in the report.
+const SAMPLES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Terminator {
+    CrLf,
+    Lf,
+    Cr,
+    // No line ending seen at all
+    Unknown,
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Terminator::CrLf => "CRLF (\\r\\n)",
+            Terminator::Lf => "LF (\\n)",
+            Terminator::Cr => "CR (\\r)",
+            Terminator::Unknown => "none seen",
+        })
+    }
+}
+
+/// The most frequent line ending in `bytes`.
+pub fn detect_terminator(bytes: &[u8]) -> Terminator {
+    let (mut crlf, mut lf, mut cr) = (0, 0, 0);
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1)) {
+            (b'\r', Some(b'\n')) => {
+                crlf += 1;
+                i += 1;
+            }
+            (b'\r', _) => cr += 1,
+            (b'\n', _) => lf += 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    if crlf == 0 && lf == 0 && cr == 0 {
+        Terminator::Unknown
+    } else if crlf >= lf && crlf >= cr {
+        Terminator::CrLf
+    } else if lf >= cr {
+        Terminator::Lf
+    } else {
+        Terminator::Cr
+    }
+}
+
+/// Non-empty lines of `bytes`, split on any line ending and trimmed, with
+/// escape sequences removed if `strip_ansi`.
+pub fn split_lines(bytes: &[u8], strip_ansi: bool) -> Vec<String> {
+    String::from_utf8_lossy(bytes)
+        .split(['\r', '\n'])
+        .map(|line| if strip_ansi { ansi::strip(line) } else { line.into() })
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Text up to and including the first `:` shared by at least half of the
+/// lines, e.g. `UDP packet contents:`, with the number of lines having it.
+pub fn common_prefix(lines: &[String]) -> Option<(String, usize)> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for line in lines {
+        if let Some(end) = line.find(':') {
+            *counts.entry(&line[..=end]).or_default() += 1;
+        }
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .filter(|(_, count)| *count * 2 >= lines.len())
+        .map(|(prefix, count)| (prefix.to_string(), count))
+}
+
+/// How much `bytes` look like text lines, from 0 to 1: the share of
+/// printable ASCII and line breaks, halved if no line ends at all. Input read
+/// at the wrong baud rate is mostly other bytes.
+pub fn text_score(bytes: &[u8]) -> f64 {
+    if bytes.is_empty() {
+        return 0.0;
+    }
+    let text = bytes
+        .iter()
+        .filter(|b| b.is_ascii_graphic() || matches!(b, b' ' | b'\t' | b'\r' | b'\n'))
+        .count();
+    let score = text as f64 / bytes.len() as f64;
+    if bytes.iter().any(|b| matches!(b, b'\r' | b'\n')) {
+        score
+    } else {
+        score / 2.0
+    }
+}
+
+/// How one delimiter splits the payloads.
+#[derive(Debug, Clone)]
+pub struct DelimiterStats {
+    pub delimiter: char,
+    // Field count -> number of payloads with that many fields
+    pub histogram: BTreeMap<usize, usize>,
+    pub payloads: usize,
+}
+
+impl DelimiterStats {
+    /// The most common field count and how many payloads have it.
+    pub fn dominant(&self) -> (usize, usize) {
+        self.histogram
+            .iter()
+            .max_by_key(|(fields, count)| (**count, **fields))
+            .map(|(fields, count)| (*fields, *count))
+            .unwrap_or((0, 0))
+    }
+
+    /// Fraction of payloads with the dominant field count.
+    pub fn consistency(&self) -> f64 {
+        if self.payloads == 0 {
+            return 0.0;
+        }
+        self.dominant().1 as f64 / self.payloads as f64
+    }
+}
+
+/// Splits `payload` the way the logger does for `delimiter`.
+pub fn split_fields(payload: &str, delimiter: char) -> Vec<&str> {
+    if delimiter.is_whitespace() {
+        payload.split_whitespace().collect()
+    } else {
+        payload.split(delimiter).collect()
+    }
+}
+
+/// Delimiters that split the payloads into at least two fields, best first:
+/// the most consistent field count wins, then the larger count.
+pub fn delimiter_candidates(payloads: &[&str]) -> Vec<DelimiterStats> {
+    let mut candidates: Vec<DelimiterStats> = DELIMITER_CANDIDATES
+        .iter()
+        .filter(|delimiter| payloads.iter().any(|payload| payload.contains(**delimiter)))
+        .map(|delimiter| {
+            let mut histogram = BTreeMap::new();
+            for payload in payloads {
+                *histogram.entry(split_fields(payload, *delimiter).len()).or_default() += 1;
+            }
+            DelimiterStats {
+                delimiter: *delimiter,
+                histogram,
+                payloads: payloads.len(),
+            }
+        })
+        .filter(|stats| stats.dominant().0 >= 2)
+        .collect();
+    // Stable, so ties keep the candidate order
+    candidates.sort_by(|a, b| {
+        b.consistency()
+            .total_cmp(&a.consistency())
+            .then(b.dominant().0.cmp(&a.dominant().0))
+    });
+    candidates
+}
+
+/// Whether a field is a number: decimal, exponent form or `0x` hex.
+pub fn looks_numeric(field: &str) -> bool {
+    let field = field.trim();
+    if let Some(hex) = field.strip_prefix("0x").or_else(|| field.strip_prefix("0X")) {
+        return !hex.is_empty() && hex.bytes().all(|b| b.is_ascii_hexdigit());
+    }
+    field.parse::<f64>().is_ok_and(f64::is_finite)
+}
+
+/// For each of `fields` columns, whether at least 90% of the payloads with
+/// that many fields have a number there.
+pub fn numeric_columns(payloads: &[&str], delimiter: char, fields: usize) -> Vec<bool> {
+    let rows: Vec<Vec<&str>> = payloads
+        .iter()
+        .map(|payload| split_fields(payload, delimiter))
+        .filter(|row| row.len() == fields)
+        .collect();
+    (0..fields)
+        .map(|column| {
+            let numeric = rows.iter().filter(|row| looks_numeric(row[column])).count();
+            !rows.is_empty() && numeric * 10 >= rows.len() * 9
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    pub bytes: usize,
+    pub lines: usize,
+    pub samples: Vec<String>,
+    pub terminator: Terminator,
+    // Prefix marking data lines and how many lines have it
+    pub prefix: Option<(String, usize)>,
+    pub delimiters: Vec<DelimiterStats>,
+    // Whether each field of the best delimiter's split is numeric
+    pub numeric: Vec<bool>,
+}
+
+/// Analyzes raw input captured from a device, cleaning lines up the way the
+/// logger would.
+pub fn analyze(bytes: &[u8], strip_ansi: bool) -> ProbeReport {
+    let lines = split_lines(bytes, strip_ansi);
+    let prefix = common_prefix(&lines);
+    // Only lines with the prefix carry data, and only the text after it
+    let payloads: Vec<&str> = match &prefix {
+        Some((prefix, _)) => lines
+            .iter()
+            .filter_map(|line| line.find(prefix.as_str()).map(|start| line[start + prefix.len()..].trim()))
+            .collect(),
+        None => lines.iter().map(String::as_str).collect(),
+    };
+    let delimiters = delimiter_candidates(&payloads);
+    let numeric = delimiters
+        .first()
+        .map(|best| numeric_columns(&payloads, best.delimiter, best.dominant().0))
+        .unwrap_or_default();
+    ProbeReport {
+        bytes: bytes.len(),
+        lines: lines.len(),
+        samples: lines.iter().take(SAMPLES).cloned().collect(),
+        terminator: detect_terminator(bytes),
+        prefix,
+        delimiters,
+        numeric,
+    }
+}
+
+fn delimiter_name(delimiter: char) -> String {
+    match delimiter {
+        '\t' => "tab".to_string(),
+        ' ' => "space".to_string(),
+        c => c.to_string(),
+    }
+}
+
+// Quotes an argument for a POSIX shell
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+impl ProbeReport {
+    /// Options that would parse this input, e.g. `--delimiter ',' --fields 4`.
+    pub fn suggested_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        match &self.prefix {
+            Some((prefix, _)) => args.extend(["--match".to_string(), shell_quote(prefix)]),
+            None => args.extend(["--match".to_string(), shell_quote("")]),
+        }
+        if let Some(best) = self.delimiters.first() {
+            args.extend(["--delimiter".to_string(), shell_quote(&delimiter_name(best.delimiter))]);
+            args.extend(["--fields".to_string(), best.dominant().0.to_string()]);
+        }
+        args
+    }
+}
+
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Received {} bytes in {} lines.", self.bytes, self.lines)?;
+        writeln!(f, "Line terminator: {}", self.terminator)?;
+        writeln!(f, "Sample lines:")?;
+        for line in &self.samples {
+            writeln!(f, "  {}", line)?;
+        }
+        match &self.prefix {
+            Some((prefix, count)) => writeln!(f, "Data prefix: '{}' ({} of {} lines)", prefix, count, self.lines)?,
+            None => writeln!(f, "Data prefix: none common to most lines")?,
+        }
+        if self.delimiters.is_empty() {
+            writeln!(f, "Delimiter: none found that splits the lines into fields")?;
+        } else {
+            writeln!(f, "Delimiter candidates (field count: lines):")?;
+            for stats in &self.delimiters {
+                let histogram: Vec<String> =
+                    stats.histogram.iter().map(|(fields, count)| format!("{}: {}", fields, count)).collect();
+                writeln!(
+                    f,
+                    "  {:<6} {:>3.0}% consistent  {}",
+                    delimiter_name(stats.delimiter),
+                    stats.consistency() * 100.0,
+                    histogram.join(", ")
+                )?;
+            }
+        }
+        if !self.numeric.is_empty() {
+            let kinds: Vec<&str> = self.numeric.iter().map(|n| if *n { "numeric" } else { "text" }).collect();
+            writeln!(f, "Fields: {}", kinds.join(", "))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! Named presets with `--profile NAME`, from `profiles.toml`.
+//!
+//! Each profile is a table of the same `option = value` entries as a
+//! `--config` file, plus a one-line `description` for `--list-profiles`:
+//!
+//! ```toml
+//! [imu-v2]
+//! description = "IMU v2 board, 6 axes at 100 Hz"
+//! baud = 230400
+//! match = "IMU:"
+//! names = "ax,ay,az,gx,gy,gz"
+//! ```
+//!
+//! `profiles.toml` is looked for in the user's config directory
+//! (`~/.config/serial_logger` on Linux) and next to the program; both are
+//! read, and a profile in the config directory hides one of the same name
+//! next to the program. A profile's options go before those of `--config`,
+//! which go before the command line's, so each can override the one before.
+
+use std::path::{Path, PathBuf};
+
+use crate::config::{self, Value};
+
+pub const FILE_NAME: &str = "profiles.toml";
+
+pub struct Profile {
+    pub name: String,
+    pub description: String,
+    pub entries: Vec<(String, Value)>,
+    // The profiles.toml it came from
+    pub file: PathBuf,
+}
+
+/// Where `profiles.toml` is looked for, in order of precedence.
+pub fn search_paths() -> Vec<PathBuf> {
+    let beside_program = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(FILE_NAME)));
+    config_dir().map(|dir| dir.join(FILE_NAME)).into_iter().chain(beside_program).collect()
+}
+
+// The per-user directory for the logger's settings
+fn config_dir() -> Option<PathBuf> {
+    let var = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+    let base = if cfg!(windows) {
+        var("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| home.join("Library/Application Support"))
+    } else {
+        var("XDG_CONFIG_HOME").or_else(|| var("HOME").map(|home| home.join(".config")))
+    };
+    base.map(|base| base.join("serial_logger"))
+}
+
+/// Every profile in the files that exist, without the hidden ones.
+pub fn load_all() -> Result<Vec<Profile>, String> {
+    load_first(&search_paths())
+}
+
+// The profiles of those of `paths` that exist; one in an earlier file hides
+// those of the same name in later ones
+fn load_first(paths: &[PathBuf]) -> Result<Vec<Profile>, String> {
+    let mut profiles: Vec<Profile> = Vec::new();
+    for path in paths {
+        if !path.is_file() {
+            continue;
+        }
+        for profile in load(path)? {
+            if !profiles.iter().any(|existing| existing.name == profile.name) {
+                profiles.push(profile);
+            }
+        }
+    }
+    Ok(profiles)
+}
+
+/// Reads the profiles of one file.
+pub fn load(path: &Path) -> Result<Vec<Profile>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let invalid = |e: String| format!("Invalid profiles file {}: {}", path.display(), e);
+    config::parse_tables(&text)
+        .map_err(invalid)?
+        .into_iter()
+        .map(|(name, mut entries)| {
+            if let Some((key, _)) = entries.iter().find(|(key, _)| key == "profile" || key == "config") {
+                return Err(invalid(format!("[{}] sets '{}'; a profile can't load other options files", name, key)));
+            }
+            let description = match entries.iter().position(|(key, _)| key == "description") {
+                Some(index) => match entries.remove(index).1 {
+                    Value::Text(text) => text,
+                    _ => return Err(invalid(format!("the description of [{}] must be text", name))),
+                },
+                None => String::new(),
+            };
+            Ok(Profile {
+                name,
+                description,
+                entries,
+                file: path.to_path_buf(),
+            })
+        })
+        .collect()
+}
+
+/// The options of the profile called `name`.
+pub fn find(name: &str) -> Result<Profile, String> {
+    let profiles = load_all()?;
+    let names: Vec<&str> = profiles.iter().map(|profile| profile.name.as_str()).collect();
+    if names.is_empty() {
+        return Err(format!("Unknown --profile '{}': no {} was found in {}", name, FILE_NAME, searched()));
+    }
+    let message = format!("Unknown --profile '{}': expected one of {}", name, names.join(", "));
+    profiles.into_iter().find(|profile| profile.name == name).ok_or(message)
+}
+
+/// Prints the available profiles for `--list-profiles`.
+pub fn list() -> Result<(), String> {
+    let profiles = load_all()?;
+    if profiles.is_empty() {
+        say!("No profiles: no {} was found in {}.", FILE_NAME, searched());
+        return Ok(());
+    }
+    let width = profiles.iter().map(|profile| profile.name.len()).max().unwrap_or(0);
+    let mut file: Option<&Path> = None;
+    for profile in &profiles {
+        if file != Some(profile.file.as_path()) {
+            file = Some(&profile.file);
+            say!("{}:", profile.file.display());
+        }
+        say!("  {:<width$}  {}", profile.name, profile.description, width = width);
+    }
+    Ok(())
+}
+
+fn searched() -> String {
+    let paths: Vec<String> = search_paths().iter().map(|path| path.display().to_string()).collect();
+    paths.join(" or ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, text: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn names(profiles: &[Profile]) -> Vec<&str> {
+        profiles.iter().map(|profile| profile.name.as_str()).collect()
+    }
+
+    #[test]
+    fn profiles_are_tables_of_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(
+            dir.path(),
+            FILE_NAME,
+            "[imu-v2]\ndescription = \"IMU v2 board\"\nbaud = 230400\nmatch = \"IMU:\"\n\n[gps]\nbaud = 9600\nno-raw = true\n",
+        );
+        let profiles = load(&path).unwrap();
+        assert_eq!(names(&profiles), ["imu-v2", "gps"]);
+        assert_eq!(profiles[0].description, "IMU v2 board");
+        assert_eq!(
+            profiles[0].entries,
+            [
+                ("baud".to_string(), Value::Number("230400".to_string())),
+                ("match".to_string(), Value::Text("IMU:".to_string()))
+            ]
+        );
+        // The description is optional
+        assert_eq!(profiles[1].description, "");
+        assert_eq!(profiles[1].entries[1], ("no-raw".to_string(), Value::Bool(true)));
+        assert_eq!(profiles[1].file, path);
+    }
+
+    #[test]
+    fn profiles_cant_load_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for key in ["profile = \"gps\"", "config = \"logger.toml\""] {
+            let path = write(dir.path(), FILE_NAME, &format!("[imu]\n{}\n", key));
+            let error = load(&path).err().unwrap();
+            assert!(error.contains("a profile can't load other options files"), "{}", error);
+        }
+        let path = write(dir.path(), FILE_NAME, "[imu]\ndescription = 3\n");
+        assert!(load(&path).err().unwrap().ends_with("the description of [imu] must be text"));
+        let path = write(dir.path(), FILE_NAME, "baud = 9600\n");
+        assert!(load(&path).err().unwrap().starts_with("Invalid profiles file"));
+    }
+
+    #[test]
+    fn earlier_files_hide_profiles_of_the_same_name() {
+        let config = tempfile::tempdir().unwrap();
+        let program = tempfile::tempdir().unwrap();
+        let mine = write(config.path(), FILE_NAME, "[imu]\ndescription = \"mine\"\n");
+        let shipped = write(program.path(), FILE_NAME, "[imu]\ndescription = \"shipped\"\n[gps]\n");
+        let missing = config.path().join("missing").join(FILE_NAME);
+        let profiles = load_first(&[missing, mine.clone(), shipped.clone()]).unwrap();
+        assert_eq!(names(&profiles), ["imu", "gps"]);
+        assert_eq!((profiles[0].description.as_str(), &profiles[0].file), ("mine", &mine));
+        assert_eq!(profiles[1].file, shipped);
+    }
+}
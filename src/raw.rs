@@ -0,0 +1,136 @@
+//! Format of the raw capture file (`session.raw`).
+//!
+//! Every line received is written to the capture before it is parsed, along
+//! with the events that decide what becomes of it: the port being opened or
+//! reconfigured and runs starting and stopping. `reparse` replays a capture
+//! through the parser to regenerate the CSV with different settings.
+//!
+//! Each entry is one line of tab-separated fields, the timestamp first and
+//! then the kind:
+//!
+//! | Kind      | Fields                     |
+//! |-----------|----------------------------|
+//! | `session` | program version            |
+//! | `open`    | baud rate, port            |
+//! | `baud`    | baud rate                  |
+//! | `rx`      | line as received           |
+//! | `start`   | run number, run ID, label  |
+//! | `stop`    | run number, run ID         |
+//!
+//! Text is escaped so an entry stays on one line: `\\`, `\t`, `\r`, `\n`, and
+//! `\xHH` for other control characters and bytes that aren't valid UTF-8.
+
+use std::fmt::Write;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    // A logging session began appending to the capture
+    Session { version: String },
+    // A port was opened, at startup or with the `port` command
+    Open { port: String, baud: u32 },
+    // The baud rate of the open port changed
+    Baud(u32),
+    // Raw bytes of a received line, including its line ending
+    Rx(Vec<u8>),
+    Start { run: i64, run_id: String, label: String },
+    Stop { run: i64, run_id: String },
+}
+
+/// One line of the capture for `entry`, including the newline.
+pub fn encode(timestamp: &str, entry: &Entry) -> String {
+    let fields = match entry {
+        Entry::Session { version } => vec!["session".to_string(), escape(version.as_bytes())],
+        Entry::Open { port, baud } => vec!["open".to_string(), baud.to_string(), escape(port.as_bytes())],
+        Entry::Baud(baud) => vec!["baud".to_string(), baud.to_string()],
+        Entry::Rx(bytes) => vec!["rx".to_string(), escape(bytes)],
+        Entry::Start { run, run_id, label } => {
+            vec!["start".to_string(), run.to_string(), escape(run_id.as_bytes()), escape(label.as_bytes())]
+        }
+        Entry::Stop { run, run_id } => vec!["stop".to_string(), run.to_string(), escape(run_id.as_bytes())],
+    };
+    format!("{}\t{}\n", escape(timestamp.as_bytes()), fields.join("\t"))
+}
+
+/// Parses one line of the capture (without its newline) into its timestamp
+/// and entry.
+pub fn decode(line: &str) -> Result<(String, Entry), String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let invalid = || format!("Invalid raw capture entry: {}", line);
+    let text = |i: usize| -> Result<String, String> {
+        let bytes = unescape(fields.get(i).ok_or_else(invalid)?)?;
+        String::from_utf8(bytes).map_err(|_| invalid())
+    };
+    let number = |i: usize| fields.get(i).and_then(|field| field.parse().ok()).ok_or_else(invalid);
+    let entry = match fields.get(1).copied() {
+        Some("session") => Entry::Session { version: text(2)? },
+        Some("open") => Entry::Open {
+            port: text(3)?,
+            baud: number(2)? as u32,
+        },
+        Some("baud") => Entry::Baud(number(2)? as u32),
+        Some("rx") => Entry::Rx(unescape(fields.get(2).ok_or_else(invalid)?)?),
+        Some("start") => Entry::Start {
+            run: number(2)?,
+            run_id: text(3)?,
+            label: text(4)?,
+        },
+        Some("stop") => Entry::Stop {
+            run: number(2)?,
+            run_id: text(3)?,
+        },
+        _ => return Err(invalid()),
+    };
+    Ok((text(0)?, entry))
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '\t' => out.push_str("\\t"),
+                '\r' => out.push_str("\\r"),
+                '\n' => out.push_str("\\n"),
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        write!(out, "\\x{:02x}", b).expect("Writing to a String can't fail");
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            write!(out, "\\x{:02x}", b).expect("Writing to a String can't fail");
+        }
+    }
+    out
+}
+
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'\\') => out.push(b'\\'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'r') => out.push(b'\r'),
+            Some(b'n') => out.push(b'\n'),
+            Some(b'x') => {
+                let hex = [bytes.next(), bytes.next()];
+                let value = match hex {
+                    [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                    _ => None,
+                };
+                out.push(value.ok_or_else(|| format!("Invalid escape in raw capture: {}", text))?);
+            }
+            _ => return Err(format!("Invalid escape in raw capture: {}", text)),
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,64 @@
+//! Write-ahead capture of everything received, for `reparse`.
+//!
+//! Unless `--no-raw` is given, every line read from the port is appended to
+//! `session.raw` in the output directory (or `--raw-file`) before it is
+//! parsed, together with port and run events; see `collect_acc_data::raw` for
+//! the format. Sessions append to the same file, so a restart never loses an
+//! earlier session's capture. A write error disables the capture for the rest
+//! of the session after one warning; the CSV is unaffected.
+
+use collect_acc_data::raw::{self, Entry};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::get_timestamp;
+
+pub const FILE_NAME: &str = "session.raw";
+
+/// Default capture file for an output template, next to the run state.
+pub fn default_path(template: &str) -> PathBuf {
+    crate::template::fixed_dir(template).join(FILE_NAME)
+}
+
+pub struct RawLog {
+    path: PathBuf,
+    // Unbuffered, so every entry reaches the OS as soon as it is written
+    file: Option<File>,
+}
+
+impl RawLog {
+    /// Opens the capture for appending and records the start of a session.
+    pub fn open(path: &Path) -> Result<RawLog, String> {
+        let fail = |e: std::io::Error| format!("Failed to open raw capture {}: {}", path.display(), e);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(fail)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(fail)?;
+        let mut log = RawLog {
+            path: path.to_path_buf(),
+            file: Some(file),
+        };
+        log.write(
+            &get_timestamp(),
+            &Entry::Session {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+        );
+        Ok(log)
+    }
+
+    pub fn write(&mut self, timestamp: &str, entry: &Entry) {
+        let Some(file) = self.file.as_mut() else { return };
+        if let Err(e) = file.write_all(raw::encode(timestamp, entry).as_bytes()) {
+            esay!(
+                "Warning: Failed to write raw capture {}: {}; not capturing for the rest of the session.",
+                self.path.display(),
+                e
+            );
+            self.file = None;
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Quarantine file for rejected lines, with `--rejects`.
+//!
+//! Every line that was read while recording but didn't become a data row is
+//! appended to the rejects file as `Timestamp,Reason,Line`, with the line as
+//! it was received, so what was left out can be audited and, after a
+//! misconfiguration, parsed again. The file is written with the same line
+//! ending and `--sync` policy as the output, but always quoted where needed,
+//! since rejected lines often contain the delimiter. Sessions append to the
+//! same file; the header is only written when it is new.
+
+use std::{fs::OpenOptions, path::Path};
+
+use crate::sink::{CsvDialect, CsvSink, QuoteStyle, SyncPolicy, Target};
+
+/// Why a line was rejected. Each reason has its own session counter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    // A data line with the wrong number of fields
+    FieldCount,
+    // A row that would need quoting with --quote-style never
+    NeedsQuoting,
+    // Bytes between binary frames that didn't make a frame (--protocol
+    // binary); the line is the bytes in hex
+    BadFrame,
+    // A hex or base64 payload that didn't decode (--payload-encoding)
+    BadPayload,
+    // A field that didn't fit its --types type
+    WrongType,
+}
+
+impl Reason {
+    /// The code written in the Reason column.
+    pub fn code(self) -> &'static str {
+        match self {
+            Reason::FieldCount => "field-count",
+            Reason::NeedsQuoting => "needs-quoting",
+            Reason::BadFrame => "bad-frame",
+            Reason::BadPayload => "bad-payload",
+            Reason::WrongType => "wrong-type",
+        }
+    }
+}
+
+const HEADER: [&str; 3] = ["Timestamp", "Reason", "Line"];
+
+/// Opens the rejects file at `path` for appending; with `dry_run` the lines
+/// are only shown.
+pub fn open(path: &Path, dialect: CsvDialect, sync: SyncPolicy, dry_run: bool) -> Result<CsvSink, String> {
+    let fail = |e: std::io::Error| format!("Failed to open rejects file {}: {}", path.display(), e);
+    let (target, new) = if dry_run {
+        let new = std::fs::metadata(path).map_or(true, |meta| meta.len() == 0);
+        (Target::dry_run(&path.display().to_string()), new)
+    } else {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(fail)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(fail)?;
+        let new = file.metadata().map_err(fail)?.len() == 0;
+        (Target::File(file), new)
+    };
+    let dialect = CsvDialect {
+        quote: QuoteStyle::Necessary,
+        ..dialect
+    };
+    let writer = dialect.builder().from_writer(target);
+    let mut sink = CsvSink::new(writer, HEADER.len(), dialect, sync);
+    if new {
+        sink.write_record(HEADER)
+            .and_then(|()| sink.flush().map_err(csv::Error::from))
+            .map_err(|e| format!("Failed to write rejects file {}: {}", path.display(), e))?;
+    }
+    Ok(sink)
+}
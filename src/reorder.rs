@@ -0,0 +1,193 @@
+//! `--ordered`: the lines of several ports written in the order they arrived.
+//!
+//! Each port's reader hands its lines to the writer as soon as they are
+//! complete, so lines from different ports reach it in whatever order the
+//! readers happened to run. With `--ordered` every line waits in a min-heap
+//! keyed by its arrival until the reordering window has passed since then;
+//! by that time a line that arrived earlier on another port is in the heap
+//! too, so lines leave oldest first and the Timestamp column doesn't go
+//! backwards. A line that turns up after a later one has already left (its
+//! reader held up for longer than the window) can't be put back in order:
+//! it is handed back at once, to be written straight away and counted as
+//! late.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    time::{Duration, Instant},
+};
+
+// A line waiting for its window to pass; `seq` keeps lines that arrived at
+// the same instant in the order they were pushed
+struct Held<T> {
+    at: Instant,
+    seq: u64,
+    item: T,
+}
+
+impl<T> Held<T> {
+    fn key(&self) -> (Instant, u64) {
+        (self.at, self.seq)
+    }
+}
+
+impl<T> PartialEq for Held<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<T> Eq for Held<T> {}
+
+impl<T> PartialOrd for Held<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Held<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Items held until `window` after they arrived, released oldest first.
+pub struct Reorder<T> {
+    window: Duration,
+    heap: BinaryHeap<Reverse<Held<T>>>,
+    seq: u64,
+    // Arrival of the latest item released
+    released: Option<Instant>,
+}
+
+impl<T> Reorder<T> {
+    pub fn new(window: Duration) -> Reorder<T> {
+        Reorder {
+            window,
+            heap: BinaryHeap::new(),
+            seq: 0,
+            released: None,
+        }
+    }
+
+    /// Holds `item`, which arrived `at`. An item older than one already
+    /// released is late and comes straight back.
+    pub fn push(&mut self, at: Instant, item: T) -> Option<T> {
+        if self.released.is_some_and(|released| at < released) {
+            return Some(item);
+        }
+        self.seq += 1;
+        self.heap.push(Reverse(Held { at, seq: self.seq, item }));
+        None
+    }
+
+    /// The items whose window has passed by `now`, oldest first.
+    pub fn release(&mut self, now: Instant) -> Vec<T> {
+        let mut due = Vec::new();
+        while let Some(Reverse(oldest)) = self.heap.peek() {
+            if oldest.at + self.window > now {
+                break;
+            }
+            let Some(Reverse(held)) = self.heap.pop() else { break };
+            self.released = Some(held.at);
+            due.push(held.item);
+        }
+        due
+    }
+
+    /// Everything still held, oldest first, whether due or not.
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut all = Vec::with_capacity(self.heap.len());
+        while let Some(Reverse(held)) = self.heap.pop() {
+            self.released = Some(held.at);
+            all.push(held.item);
+        }
+        all
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(50);
+
+    fn ms(base: Instant, ms: u64) -> Instant {
+        base + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn lines_leave_in_arrival_order_once_their_window_has_passed() {
+        let base = Instant::now();
+        let mut reorder = Reorder::new(WINDOW);
+        // Two ports, the second one's reader running behind
+        for (at, line) in [(10, "a1"), (12, "a2"), (5, "b1"), (11, "b2"), (30, "a3"), (20, "b3")] {
+            assert_eq!(reorder.push(ms(base, at), line), None);
+        }
+        assert!(reorder.release(ms(base, 54)).is_empty());
+        assert_eq!(reorder.release(ms(base, 61)), ["b1", "a1", "b2"]);
+        assert_eq!(reorder.release(ms(base, 80)), ["a2", "b3", "a3"]);
+        assert!(reorder.drain().is_empty());
+    }
+
+    #[test]
+    fn lines_at_the_same_instant_keep_the_order_they_came_in() {
+        let base = Instant::now();
+        let mut reorder = Reorder::new(WINDOW);
+        for line in ["x", "y", "z"] {
+            reorder.push(base, line);
+        }
+        reorder.push(ms(base, 1), "w");
+        assert_eq!(reorder.release(ms(base, 100)), ["x", "y", "z", "w"]);
+    }
+
+    #[test]
+    fn a_line_older_than_one_released_is_late() {
+        let base = Instant::now();
+        let mut reorder = Reorder::new(WINDOW);
+        reorder.push(ms(base, 100), "a1");
+        assert_eq!(reorder.release(ms(base, 150)), ["a1"]);
+        // Held up for longer than the window
+        assert_eq!(reorder.push(ms(base, 90), "b1"), Some("b1"));
+        // As old as the one released, or newer, is in time
+        assert_eq!(reorder.push(ms(base, 100), "b2"), None);
+        assert_eq!(reorder.push(ms(base, 120), "b3"), None);
+        assert_eq!(reorder.release(ms(base, 200)), ["b2", "b3"]);
+    }
+
+    #[test]
+    fn shuffled_input_comes_out_sorted_with_late_lines_apart() {
+        let base = Instant::now();
+        let mut reorder = Reorder::new(WINDOW);
+        // Arrivals 0..1000 ms, each delivered up to 40 ms late, with every
+        // 100th held up for 80 ms
+        let mut deliveries: Vec<(u64, u64)> = (0..1000u64)
+            .map(|at| {
+                let delay = if at % 100 == 37 { 80 } else { (at * 7919) % 41 };
+                (at + delay, at)
+            })
+            .collect();
+        deliveries.sort();
+        let (mut written, mut late) = (Vec::new(), Vec::new());
+        for (now, at) in deliveries {
+            written.extend(reorder.release(ms(base, now)));
+            if let Some(at) = reorder.push(ms(base, at), at) {
+                late.push(at);
+            }
+        }
+        written.extend(reorder.drain());
+        assert!(written.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", written);
+        assert_eq!(late, (0..10).map(|i| i * 100 + 37).collect::<Vec<u64>>());
+        assert_eq!(written.len() + late.len(), 1000);
+    }
+
+    #[test]
+    fn draining_releases_everything_in_order() {
+        let base = Instant::now();
+        let mut reorder = Reorder::new(WINDOW);
+        reorder.push(ms(base, 3), 3);
+        reorder.push(ms(base, 1), 1);
+        assert_eq!(reorder.drain(), [1, 3]);
+        assert_eq!(reorder.push(base, 0), Some(0));
+    }
+}
@@ -0,0 +1,244 @@
+//! `repair`: fixes up an output file after a hard kill or power loss.
+//!
+//! Two things can be wrong with such a file: the last record may be cut off
+//! mid-line, and the run that was being recorded has no stop marker (as does
+//! a run that ended because the disk filled up). The partial line is dropped
+//! and every unterminated run gets a `stop` row noted `recovered`, stamped
+//! with the time of its last data row, right where the run ends. The file is
+//! rewritten through a temporary file and a rename, and a well-formed file is
+//! left untouched.
+//!
+//! A `--format parquet` file needs no repair: it is complete after every row
+//! group (see parquet.rs).
+
+use collect_acc_data::logfile::Kind;
+use std::path::Path;
+
+/// What a repair changed; empty if the file was fine.
+#[derive(Debug, Default)]
+pub struct Repair {
+    // Bytes of a partial last line that were dropped
+    pub truncated: usize,
+    // Run (`run N`) and timestamp of each stop marker added
+    pub stops: Vec<(String, String)>,
+}
+
+impl Repair {
+    pub fn is_empty(&self) -> bool {
+        self.truncated == 0 && self.stops.is_empty()
+    }
+}
+
+// A run whose start marker has been seen but no stop yet
+struct OpenRun {
+    label: String,
+    run_id: String,
+    // Timestamp of its start marker or latest data row
+    last_seen: String,
+}
+
+// Stop marker to insert at a byte offset
+struct Insertion {
+    offset: usize,
+    label: String,
+    run_id: String,
+    timestamp: String,
+}
+
+/// Checks the file at `path` and repairs it if needed.
+pub fn run(path: &Path) -> Result<Repair, String> {
+    let data = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    // A Parquet file is replaced whole after every row group, never cut off
+    if data.starts_with(b"PAR1") {
+        return Ok(Repair::default());
+    }
+    let mut repair = Repair::default();
+    // Everything after the last line ending is a record cut off mid-write
+    let complete = data.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    repair.truncated = data.len() - complete;
+    let data = &data[..complete];
+    let line_end = if data.windows(2).any(|pair| pair == b"\r\n") { "\r\n" } else { "\n" };
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .comment(Some(b'#'))
+        .from_reader(data);
+    let mut width = 0;
+    // Files written with --quote-style always get quoted stop rows too
+    let mut quoted = false;
+    let mut open: Option<OpenRun> = None;
+    let mut insertions = Vec::new();
+    let mut record = csv::StringRecord::new();
+    loop {
+        let offset = reader.position().byte() as usize;
+        let more = reader
+            .read_record(&mut record)
+            .map_err(|e| format!("{} is not a CSV this program wrote: {}", path.display(), e))?;
+        if !more {
+            break;
+        }
+        let field = |i: usize| record.get(i).unwrap_or("").to_string();
+        match Kind::parse(record.get(0).unwrap_or("")) {
+            Kind::Other(header) if header == "Type" => {
+                width = record.len();
+                quoted = data.get(offset) == Some(&b'"');
+            }
+            Kind::Start => {
+                // The previous run never stopped: end it before this one starts
+                if let Some(run) = open.take() {
+                    insertions.push(insertion(offset, run));
+                }
+                open = Some(OpenRun {
+                    label: field(2),
+                    run_id: field(3),
+                    last_seen: field(1),
+                });
+            }
+            Kind::Stop => open = None,
+            // Ends the run's part of a file it goes on from, or begins the
+            // part of the file it goes on in
+            Kind::Continued => {
+                open = match open {
+                    Some(_) => None,
+                    None => Some(OpenRun {
+                        label: field(2),
+                        run_id: field(3),
+                        last_seen: field(1),
+                    }),
+                }
+            }
+            Kind::Data => {
+                if let Some(run) = open.as_mut() {
+                    run.last_seen = field(1);
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(run) = open.take() {
+        insertions.push(insertion(data.len(), run));
+    }
+    repair.stops = insertions.iter().map(|i| (i.label.clone(), i.timestamp.clone())).collect();
+    if repair.is_empty() {
+        return Ok(repair);
+    }
+
+    let mut out = Vec::with_capacity(data.len() + insertions.len() * 64);
+    let mut copied = 0;
+    for insertion in &insertions {
+        out.extend_from_slice(&data[copied..insertion.offset]);
+        copied = insertion.offset;
+        let mut row = vec![Kind::Stop.as_str(), insertion.timestamp.as_str(), "recovered", insertion.run_id.as_str()];
+        row.resize(width.max(row.len()), "");
+        let row: Vec<String> =
+            row.iter().map(|field| if quoted { format!("\"{}\"", field) } else { field.to_string() }).collect();
+        out.extend_from_slice(row.join(",").as_bytes());
+        out.extend_from_slice(line_end.as_bytes());
+    }
+    out.extend_from_slice(&data[copied..]);
+
+    replace(path, &out)?;
+    Ok(repair)
+}
+
+// Writes the repaired file via a temporary file and a rename
+fn replace(path: &Path, data: &[u8]) -> Result<(), String> {
+    let tmp = path.with_extension("repair.tmp");
+    std::fs::write(&tmp, data)
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|e| format!("Failed to write repaired {}: {}", path.display(), e))
+}
+
+fn insertion(offset: usize, run: OpenRun) -> Insertion {
+    Insertion {
+        offset,
+        label: run.label,
+        run_id: run.run_id,
+        timestamp: run.last_seen,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/repair").join(name)).unwrap()
+    }
+
+    // Repairs a copy of `data`; returns what was done and the file afterwards
+    fn repaired(data: &[u8]) -> (Repair, Vec<u8>, Vec<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.csv");
+        std::fs::write(&path, data).unwrap();
+        let repair = run(&path).unwrap();
+        let files = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+        (repair, std::fs::read(&path).unwrap(), files)
+    }
+
+    #[test]
+    fn truncated_last_line() {
+        let (repair, data, files) = repaired(&fixture("truncated.csv"));
+        assert_eq!(repair.truncated, "data,2025-01-14 10:21:14,,,3,4".len());
+        assert_eq!(repair.stops, vec![("run 4".to_string(), "2025-01-14 10:21:10".to_string())]);
+        assert_eq!(String::from_utf8(data).unwrap(), String::from_utf8(fixture("truncated.repaired.csv")).unwrap());
+        assert_eq!(files, ["output.csv"]);
+    }
+
+    #[test]
+    fn missing_stop_marker() {
+        let (repair, data, _) = repaired(&fixture("unterminated.csv"));
+        assert_eq!(repair.truncated, 0);
+        assert_eq!(repair.stops, vec![("run 3".to_string(), "2025-01-14 10:21:08".to_string())]);
+        assert_eq!(String::from_utf8(data).unwrap(), String::from_utf8(fixture("unterminated.repaired.csv")).unwrap());
+    }
+
+    #[test]
+    fn valid_file_is_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.csv");
+        std::fs::write(&path, fixture("valid.csv")).unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(run(&path).unwrap().is_empty());
+        assert_eq!(std::fs::read(&path).unwrap(), fixture("valid.csv"));
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), modified);
+    }
+
+    #[test]
+    fn repaired_files_are_valid() {
+        for name in ["truncated.repaired.csv", "unterminated.repaired.csv"] {
+            let (repair, data, _) = repaired(&fixture(name));
+            assert!(repair.is_empty(), "{}", name);
+            assert_eq!(data, fixture(name));
+        }
+    }
+
+    #[test]
+    fn added_stops_follow_the_dialect() {
+        let file = "\"Type\",\"Timestamp\",\"Run/End\",\"RunId\",\"v\"\r\n\
+                    \"start\",\"2025-01-14 10:21:07\",\"run 0\",\"id\",\"\"\r\n\
+                    \"data\",\"2025-01-14 10:21:08\",\"\",\"\",\"1\"\r\n";
+        let (_, data, _) = repaired(file.as_bytes());
+        let added = String::from_utf8(data).unwrap().strip_prefix(file).unwrap().to_string();
+        assert_eq!(added, "\"stop\",\"2025-01-14 10:21:08\",\"recovered\",\"id\",\"\"\r\n");
+    }
+
+    #[test]
+    fn runs_carried_over_between_files_are_complete() {
+        let header = "Type,Timestamp,Run/End,RunId,v\n";
+        let leaving = format!("{}start,2025-01-14 23:59:58,run 2,id,\ndata,2025-01-14 23:59:59,,,1\ncontinued,2025-01-15 00:00:00,run 2,id,\n", header);
+        assert!(repaired(leaving.as_bytes()).0.is_empty());
+        let going_on = format!("{}continued,2025-01-15 00:00:00,run 2,id,\ndata,2025-01-15 00:00:01,,,2\n", header);
+        let (repair, data, _) = repaired(going_on.as_bytes());
+        assert_eq!(repair.stops, vec![("run 2".to_string(), "2025-01-15 00:00:01".to_string())]);
+        assert!(String::from_utf8(data).unwrap().ends_with("stop,2025-01-15 00:00:01,recovered,id,\n"));
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(run(&dir.path().join("missing.csv")).unwrap_err().contains("Failed to read"));
+    }
+}
@@ -0,0 +1,319 @@
+//! `reparse`: regenerates a CSV from a raw capture, offline.
+//!
+//! The capture is replayed through the same cleanup, gate and parser as live
+//! input, with whatever `--match`/`--delimiter`/`--fields` (and other input
+//! and CSV options) are given now. Runs start and stop where the capture
+//! says they did; data rows keep the time their line was received. Modem rows
+//! aren't captured, so they can't be regenerated. With `--protocol binary`
+//! the captured bytes are split into frames instead; a frame that was taken
+//! live because the port went quiet after it is taken here when the next
+//! frame, the next entry of another kind or the end of the capture confirms
+//! it. With `--framing cobs` each captured packet is decoded first.
+
+use collect_acc_data::cobs;
+use collect_acc_data::frame::{Decoded, Decoder};
+use collect_acc_data::logfile::Kind;
+use collect_acc_data::parser::{self, Admit, Framing, Gate, LineFormat, Parsed};
+use collect_acc_data::raw::{self, Entry};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use crate::holdoff::Policy;
+use crate::meta::{MetaStyle, Metadata};
+use crate::outage::Recovery;
+use crate::session::{self, OutputSettings};
+use crate::sink::{CsvDialect, CsvSink, SyncPolicy};
+
+/// Settings for the regenerated CSV.
+pub struct ReparseOptions {
+    pub output: String,
+    pub format: LineFormat,
+    pub strip_ansi: bool,
+    pub skip_blank_lines: bool,
+    pub skip_lines: u64,
+    pub wait_for: Option<String>,
+    pub log_breaks: bool,
+    pub meta_style: MetaStyle,
+    pub user_meta: Vec<(String, String)>,
+    pub id_columns: Vec<(String, String)>,
+    pub units: Vec<(String, String)>,
+    pub dialect: CsvDialect,
+}
+
+/// What a reparse produced.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub lines: u64,
+    // Lines of only whitespace dropped (--keep-blank-lines keeps them)
+    pub blank: u64,
+    pub runs: u64,
+    pub rows: u64,
+    // Data lines in a run with the wrong number of fields
+    pub rejected: u64,
+    // Rows that would need quoting with --quote-style never
+    pub unquotable: u64,
+    // Computed cells left blank
+    pub compute_errors: u64,
+    // Damaged or garbled binary frames in a run
+    pub bad_frames: u64,
+    // Hex or base64 payloads in a run that didn't decode
+    pub bad_payloads: u64,
+    // Rows in a run with a field that didn't fit its --types type
+    pub type_errors: u64,
+    // Capture lines that couldn't be decoded
+    pub invalid_entries: u64,
+}
+
+// Run being replayed: its number and ID
+struct Run {
+    number: i64,
+    id: String,
+}
+
+/// Replays the capture at `input` into `options.output`.
+pub fn run(input: &Path, options: &ReparseOptions) -> Result<Report, String> {
+    let open = || File::open(input).map_err(|e| format!("Failed to open raw capture {}: {}", input.display(), e));
+    let (port, baud) = first_port(BufReader::new(open()?)).unwrap_or_default();
+    let mut user_meta = options.user_meta.clone();
+    user_meta.push(("reparsed_from".to_string(), input.display().to_string()));
+    let settings = OutputSettings {
+        template: options.output.clone(),
+        per_run: false,
+        port: port.clone(),
+        baud,
+        meta: Metadata::for_session(options.meta_style, &port, baud, &user_meta),
+        sidecar: false,
+        sync: SyncPolicy::Never,
+        dialect: options.dialect,
+        columns: options.format.column_names(),
+        id_columns: options.id_columns.clone(),
+        units: options.units.clone(),
+        state_file: None,
+        raw_file: None,
+        rejects_file: None,
+        alarms: Vec::new(),
+        retention: None,
+        manifest: None,
+        dry_run: false,
+        columnar: None,
+        prebuffer: None,
+        dedup_window: None,
+        on_change: None,
+        clock_step: None,
+        xlsx_max_rows: None,
+        tail: None,
+        audit: false,
+        recovery: Recovery::default(),
+        run_policy: Policy::default(),
+        prompt_notes: false,
+        summary_rows: false,
+        rotate_align: None,
+        strict: Vec::new(),
+    };
+    let mut w = session::open_output(&options.output, &settings, true)?;
+    let constants: Vec<String> = options.id_columns.iter().map(|(_, value)| value.clone()).collect();
+    let write_failed = |e: csv::Error| format!("Failed to write {}: {}", options.output, e);
+    let mut frames = match options.format.framing {
+        Framing::Plain => options.format.frames.clone().map(Decoder::new),
+        Framing::Cobs => None,
+    };
+    // Time of the latest bytes fed to the frame decoder
+    let mut fed_at = String::new();
+
+    let mut report = Report::default();
+    let mut gate = Gate::new(options.skip_lines, options.wait_for.as_deref());
+    let mut run: Option<Run> = None;
+    let mut port = port;
+    for (number, line) in BufReader::new(open()?).lines().enumerate() {
+        let line = line.map_err(|e| format!("Failed to read raw capture {}: {}", input.display(), e))?;
+        let (timestamp, entry) = match raw::decode(&line) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                esay!("Warning: Line {}: {}", number + 1, e);
+                report.invalid_entries += 1;
+                continue;
+            }
+        };
+        // Live, a frame is taken at the latest when the port goes quiet; any
+        // other entry means that happened before it
+        if let (Some(decoder), false) = (frames.as_mut(), matches!(entry, Entry::Rx(_))) {
+            while let Some(decoded) = decoder.flush() {
+                write_frame(&mut w, options, &constants, &mut report, &fed_at, decoded, run.is_some())?;
+            }
+        }
+        match entry {
+            // A session that ended without stopping its run (a crash or a
+            // full disk) left it without a stop marker, as in the live CSV
+            Entry::Session { .. } => {
+                run = None;
+                if let Some(decoder) = frames.as_mut() {
+                    decoder.reset();
+                }
+            }
+            Entry::Open { port: name, baud } => {
+                if let Some(decoder) = frames.as_mut() {
+                    decoder.reset();
+                }
+                gate = Gate::new(options.skip_lines, options.wait_for.as_deref());
+                port = name;
+                if run.is_some() {
+                    write_reconfig(&mut w, &timestamp, &port, baud).map_err(write_failed)?;
+                }
+            }
+            Entry::Baud(baud) => {
+                if run.is_some() {
+                    write_reconfig(&mut w, &timestamp, &port, baud).map_err(write_failed)?;
+                }
+            }
+            Entry::Start { run: number, run_id, .. } => {
+                w.write_record([Kind::Start.as_str(), &timestamp, &format!("run {}", number), &run_id])
+                    .map_err(write_failed)?;
+                report.runs += 1;
+                run = Some(Run { number, id: run_id });
+            }
+            Entry::Stop { run: number, run_id } => {
+                if run.as_ref().is_some_and(|run| run.number == number && run.id == run_id) {
+                    w.write_record([Kind::Stop.as_str(), &timestamp, "end of run", &run_id]).map_err(write_failed)?;
+                    run = None;
+                }
+            }
+            Entry::Rx(bytes) => {
+                if let Some(decoder) = frames.as_mut() {
+                    decoder.feed(&bytes);
+                    fed_at.clone_from(&timestamp);
+                    while let Some(decoded) = decoder.next_frame() {
+                        write_frame(&mut w, options, &constants, &mut report, &timestamp, decoded, run.is_some())?;
+                    }
+                    continue;
+                }
+                let bytes = match options.format.framing {
+                    Framing::Plain => bytes,
+                    Framing::Cobs => match cobs::unpack(&bytes) {
+                        None => continue,
+                        Some(Err(_)) => {
+                            report.bad_frames += u64::from(run.is_some());
+                            continue;
+                        }
+                        Some(Ok(packet)) => packet,
+                    },
+                };
+                if let Some(spec) = &options.format.frames {
+                    let decoded = spec.decode_packet(&bytes).map_or(Decoded::Bad(bytes), Decoded::Frame);
+                    write_frame(&mut w, options, &constants, &mut report, &timestamp, decoded, run.is_some())?;
+                    continue;
+                }
+                if options.log_breaks && run.is_some() {
+                    for _ in bytes.iter().filter(|b| **b == 0) {
+                        w.write_record([Kind::Break.as_str(), &timestamp, "break received"]).map_err(write_failed)?;
+                    }
+                }
+                // Split as live input is
+                let lines: Vec<&[u8]> = match options.format.framing {
+                    Framing::Plain => parser::split_lines(&bytes).collect(),
+                    Framing::Cobs => vec![&bytes],
+                };
+                for line in lines {
+                    let data = parser::clean_line(line, options.strip_ansi, options.format.delimiter);
+                    if data.is_empty() && options.skip_blank_lines {
+                        report.blank += 1;
+                        continue;
+                    }
+                    report.lines += 1;
+                    let Admit::Pass = gate.admit(&data) else { continue };
+                    if run.is_none() {
+                        continue;
+                    }
+                    match options.format.parse(&data) {
+                        Parsed::NoMatch => {}
+                        Parsed::WrongCount { .. } => report.rejected += 1,
+                        Parsed::BadPayload { .. } => report.bad_payloads += 1,
+                        Parsed::Fields(fields) => {
+                            write_row(&mut w, options, &constants, &mut report, &timestamp, &fields)?;
+                        }
+                        Parsed::Decoded(values) => {
+                            let fields: Vec<&str> = values.iter().map(String::as_str).collect();
+                            write_row(&mut w, options, &constants, &mut report, &timestamp, &fields)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if let Some(decoder) = frames.as_mut() {
+        while let Some(decoded) = decoder.flush() {
+            write_frame(&mut w, options, &constants, &mut report, &fed_at, decoded, run.is_some())?;
+        }
+    }
+    w.close().map_err(|e| format!("Failed to flush {}: {}", options.output, e))?;
+    Ok(report)
+}
+
+// Writes a data row and its computed columns, unless it would need quoting
+// that the dialect refuses
+fn write_row(
+    w: &mut CsvSink,
+    options: &ReparseOptions,
+    constants: &[String],
+    report: &mut Report,
+    timestamp: &str,
+    fields: &[&str],
+) -> Result<(), String> {
+    let Ok(typed) = options.format.coerce(fields) else {
+        report.type_errors += 1;
+        return Ok(());
+    };
+    let fields: Vec<&str> = typed.iter().map(|field| field.as_ref()).collect();
+    let fields = &fields[..];
+    let (computed, failed) = session::computed_cells(&options.format.compute(fields), options.format.float_format);
+    report.compute_errors += failed;
+    let record = session::data_record(timestamp, fields, &computed, constants);
+    if w.refuses(&record) {
+        report.unquotable += 1;
+        return Ok(());
+    }
+    w.write_record(&record)
+        .map_err(|e| format!("Failed to write {}: {}", options.output, e))?;
+    report.rows += 1;
+    Ok(())
+}
+
+// Writes a binary frame received during a run as a data row, or counts a bad one
+fn write_frame(
+    w: &mut CsvSink,
+    options: &ReparseOptions,
+    constants: &[String],
+    report: &mut Report,
+    timestamp: &str,
+    decoded: Decoded,
+    in_run: bool,
+) -> Result<(), String> {
+    report.lines += u64::from(matches!(decoded, Decoded::Frame(_)));
+    if !in_run {
+        return Ok(());
+    }
+    match decoded {
+        Decoded::Frame(values) => {
+            let fields = options.format.select_fields(values.iter().map(String::as_str).collect());
+            write_row(w, options, constants, report, timestamp, &fields)
+        }
+        Decoded::Bad(_) => {
+            report.bad_frames += 1;
+            Ok(())
+        }
+    }
+}
+
+// Port and baud rate of the capture's first open entry, for the metadata
+fn first_port(reader: impl BufRead) -> Option<(String, u32)> {
+    reader.lines().map_while(Result::ok).find_map(|line| match raw::decode(&line) {
+        Ok((_, Entry::Open { port, baud })) => Some((port, baud)),
+        _ => None,
+    })
+}
+
+fn write_reconfig(w: &mut CsvSink, timestamp: &str, port: &str, baud: u32) -> csv::Result<()> {
+    w.write_record([Kind::Reconfig.as_str(), timestamp, &format!("port {} baud {}", port, baud)])
+}
@@ -0,0 +1,215 @@
+//! Interactive command prompt with line editing, history and completion.
+//!
+//! When stdin is a terminal the prompt is driven by `rustyline`; otherwise
+//! (commands piped in from a script) lines are read plainly from stdin so
+//! scripted sessions behave exactly as before.
+
+use rustyline::{
+    completion::Completer,
+    config::{Behavior, Config},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+use std::{
+    io::IsTerminal,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::outage;
+use crate::session::Session;
+
+/// Every interactive command with a one-line description, used for `help`,
+/// tab-completion and typo suggestions.
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("start", "Start recording a new run: start [label]"),
+    ("stop", "Stop the current run"),
+    ("pause", "Leave data rows out of the current run until resume"),
+    ("resume", "Write data rows of the paused run again"),
+    ("mark", "Write a mark row in the current run: mark [text]"),
+    ("newfile", "Switch output to a new CSV file: newfile <path>"),
+    ("baud", "Change the serial baud rate without restarting: baud <rate>"),
+    ("port", "Switch to another serial port: port <path>"),
+    ("break", "Send a serial break to the device: break [ms]"),
+    ("peek", "Show the latest raw, matched and parsed lines: peek [count]"),
+    ("tail", "Show the last rows written, as a table: tail [count]"),
+    ("set", "Change a setting while logging: set <echo|verbose> <value>"),
+    ("show", "Show the current settings: show settings"),
+    ("help", "List available commands"),
+    ("exit", "Stop recording if active and quit"),
+];
+
+/// One result from reading the prompt.
+pub enum Input {
+    Line(String),
+    /// Ctrl+C cancelled the line being typed
+    Interrupted,
+    /// Ctrl+D on an interactive terminal, or the end of piped commands
+    Eof,
+}
+
+pub struct Prompt {
+    editor: Option<Editor<CommandHelper, DefaultHistory>>,
+}
+
+impl Prompt {
+    pub fn new() -> Prompt {
+        let editor = if std::io::stdin().is_terminal() {
+            // Keep the prompt off stdout when it carries the JSON event stream
+            let behavior = if crate::events::json_mode() {
+                Behavior::PreferTerm
+            } else {
+                Behavior::Stdio
+            };
+            let config = Config::builder().behavior(behavior).auto_add_history(true).build();
+            match Editor::with_config(config) {
+                Ok(mut editor) => {
+                    editor.set_helper(Some(CommandHelper));
+                    // Not available when stdout isn't a terminal; output is
+                    // then printed plainly
+                    if let Ok(printer) = editor.create_external_printer() {
+                        crate::console::attach(printer);
+                    }
+                    Some(editor)
+                }
+                Err(e) => {
+                    esay!("Line editing unavailable, falling back to plain input: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Prompt { editor }
+    }
+
+    /// Shows `prompt` and reads a single command line.
+    pub fn read(&mut self, prompt: &str) -> Input {
+        let Some(editor) = self.editor.as_mut() else {
+            // Commands from a script or /dev/null have nobody to prompt
+            if std::io::stdin().is_terminal() {
+                crate::console::show(prompt);
+            }
+            let mut line = String::new();
+            return match std::io::stdin().read_line(&mut line) {
+                Ok(0) => Input::Eof,
+                Ok(_) => Input::Line(line),
+                Err(e) => {
+                    esay!("Failed to read input: {}", e);
+                    Input::Interrupted
+                }
+            };
+        };
+        match editor.readline(prompt) {
+            Ok(line) => Input::Line(line),
+            Err(ReadlineError::Interrupted) => Input::Interrupted,
+            Err(ReadlineError::Eof) => Input::Eof,
+            Err(e) => {
+                esay!("Failed to read input: {}", e);
+                Input::Interrupted
+            }
+        }
+    }
+}
+
+/// Asks for notes on a stopped run, a line at a time until an empty one.
+/// Ctrl+C skips them; the end of input keeps the lines so far.
+pub fn read_notes(prompt: &mut Prompt, run: i64) -> String {
+    let mut lines = Vec::new();
+    let mut text = format!("Notes for run {} (empty to skip): ", run);
+    loop {
+        match prompt.read(&text) {
+            Input::Line(line) if !line.trim().is_empty() => lines.push(line.trim_end().to_string()),
+            Input::Line(_) | Input::Eof => return lines.join("\n"),
+            Input::Interrupted => return String::new(),
+        }
+        text = "... (empty line to finish): ".to_string();
+    }
+}
+
+/// Prompt text reflecting the current recording state, e.g. `[recording run 3]> `.
+pub fn prompt_text(session: &Session) -> String {
+    let flag = |f: &AtomicBool| f.load(Ordering::Relaxed);
+    let shared = &session.shared;
+    let mut state = if flag(&shared.disk_full) {
+        "DISK FULL, recording stopped".to_string()
+    } else if session.is_recording() && flag(&shared.paused) {
+        format!("paused run {}", session.current_run())
+    } else if session.is_recording() {
+        format!("recording run {}", session.current_run())
+    } else if let Some(left) = session.start_waiting() {
+        format!("starting in {:.0}s", left.as_secs_f64().ceil())
+    } else if flag(&shared.awaiting_sync) {
+        "waiting for sync marker".to_string()
+    } else {
+        "idle".to_string()
+    };
+    // Another port than the first --port, after a failover or `port`
+    let port = shared.serial_port.lock().unwrap();
+    if *port != session.primary_port() {
+        state.push_str(&format!(", on {}", port));
+    }
+    if outage::failing() {
+        state.push_str(&format!(", OUTPUT ERROR: {} rows held in memory", outage::held()));
+    }
+    if flag(&shared.line_noise) {
+        state.push_str(", input looks like noise - check baud");
+    }
+    format!("[{}]> ", state)
+}
+
+/// Prints the command list for `help`.
+pub fn print_help() {
+    say!("Available commands:");
+    for (name, description) in COMMANDS {
+        say!("  {:<8} {}", name, description);
+    }
+}
+
+/// Closest known command to a mistyped one, if any is reasonably close.
+pub fn suggest(command: &str) -> Option<&'static str> {
+    COMMANDS
+        .iter()
+        .map(|(name, _)| (*name, strsim::levenshtein(command, name)))
+        .filter(|(_, distance)| *distance <= 2)
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(name, _)| name)
+}
+
+pub struct CommandHelper;
+
+impl Completer for CommandHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        // Only the command name (first word) is completed
+        let typed = &line[..pos];
+        if typed.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = COMMANDS
+            .iter()
+            .filter(|(name, _)| name.starts_with(typed))
+            .map(|(name, _)| name.to_string())
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for CommandHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CommandHelper {}
+
+impl Validator for CommandHelper {}
+
+impl Helper for CommandHelper {}
@@ -0,0 +1,305 @@
+//! Deleting old output files with `--retain`.
+//!
+//! Policies limit the number of files (`count:200`), their age (`age:30d`)
+//! and their total size (`size:50GB`); several can be combined. After every
+//! run that ends (and every `newfile`), the files the output template could
+//! have produced are listed, oldest first by modification time, and the
+//! oldest are deleted until every policy holds. A file whose name doesn't
+//! match the template is never touched, nor are the file being written and the
+//! raw capture, state and rejects files. Files compressed with
+//! `--compress-completed` count like the originals. A run metadata sidecar
+//! goes with its file. `--retain-dry-run` only says what
+//! would be deleted.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::template;
+use crate::units;
+
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    pub count: Option<usize>,
+    pub age: Option<Duration>,
+    pub size: Option<u64>,
+    pub dry_run: bool,
+}
+
+// An output file and its sidecars, with what counts for the policies
+struct Candidate {
+    path: PathBuf,
+    sidecars: Vec<PathBuf>,
+    modified: SystemTime,
+    // Of the file and its sidecars
+    size: u64,
+}
+
+impl Retention {
+    /// Parses the `--retain` arguments, e.g. `count:200`, `age:30d`, `size:50GB`.
+    pub fn parse<'a>(args: impl Iterator<Item = &'a str>, dry_run: bool) -> Result<Retention, String> {
+        let mut retention = Retention {
+            dry_run,
+            ..Retention::default()
+        };
+        for arg in args {
+            let invalid = |e: String| format!("Invalid --retain '{}': {}", arg, e);
+            match arg.split_once(':') {
+                Some(("count", count)) => {
+                    let count = count.trim().parse().map_err(|_| invalid("expected a number of files".to_string()))?;
+                    retention.count = Some(count);
+                }
+                Some(("age", age)) => retention.age = Some(units::parse_duration(age).map_err(invalid)?),
+                Some(("size", size)) => retention.size = Some(units::parse_size(size).map_err(invalid)?),
+                _ => return Err(invalid("expected count:N, age:DURATION or size:SIZE".to_string())),
+            }
+        }
+        Ok(retention)
+    }
+
+    /// Deletes the oldest files written with `template` until the policies
+    /// hold; the files in `keep` (e.g. the one being written) are exempt.
+    pub fn apply(&self, template: &str, keep: &[&Path]) {
+        let mut candidates = find(template, keep);
+        candidates.sort_by_key(|candidate| candidate.modified);
+        let now = SystemTime::now();
+        let mut count = candidates.len();
+        let mut size: u64 = candidates.iter().map(|candidate| candidate.size).sum();
+        for candidate in candidates {
+            let too_many = self.count.is_some_and(|limit| count > limit);
+            let too_old = self
+                .age
+                .is_some_and(|limit| now.duration_since(candidate.modified).is_ok_and(|age| age > limit));
+            let too_big = self.size.is_some_and(|limit| size > limit);
+            if !(too_many || too_old || too_big) {
+                // The rest are newer, and the totals only shrink
+                break;
+            }
+            count -= 1;
+            size -= candidate.size;
+            let why = [(too_many, "count"), (too_old, "age"), (too_big, "size")]
+                .iter()
+                .filter(|(applies, _)| *applies)
+                .map(|(_, policy)| *policy)
+                .collect::<Vec<_>>()
+                .join(", ");
+            for path in std::iter::once(&candidate.path).chain(&candidate.sidecars) {
+                if self.dry_run {
+                    say!("Retention (dry run): would delete {} ({}).", path.display(), why);
+                    continue;
+                }
+                match std::fs::remove_file(path) {
+                    Ok(()) => say!("Retention: deleted {} ({}).", path.display(), why),
+                    Err(e) => esay!("Warning: Retention failed to delete {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+}
+
+// The files under the template's fixed directory whose paths match it
+fn find(template: &str, keep: &[&Path]) -> Vec<Candidate> {
+    let root = template::fixed_dir(template);
+    // Placeholders may stand for directories too
+    let depth = template.matches(['/', '\\']).count() + 1;
+    let mut files = Vec::new();
+    walk(&root, depth, &mut files);
+    files
+        .into_iter()
+        .filter(|path| {
+            let path = path.to_string_lossy();
+            // Also files compressed with --compress-completed
+            let uncompressed = path.strip_suffix(".gz").unwrap_or(&path);
+            template::matches(template, uncompressed)
+        })
+        // Sidecars are counted with their files
+        .filter(|path| !path.to_string_lossy().ends_with(".meta.json"))
+        .filter(|path| !keep.iter().any(|keep| same_file(path, keep)))
+        .filter_map(|path| {
+            let metadata = std::fs::metadata(&path).ok()?;
+            let sidecars = sidecars(&path);
+            let size = metadata.len() + sidecars.iter().filter_map(|p| std::fs::metadata(p).ok()).map(|m| m.len()).sum::<u64>();
+            Some(Candidate {
+                modified: metadata.modified().ok()?,
+                path,
+                sidecars,
+                size,
+            })
+        })
+        .collect()
+}
+
+fn walk(dir: &Path, depth: usize, files: &mut Vec<PathBuf>) {
+    let listed = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let Ok(entries) = std::fs::read_dir(listed) else { return };
+    for entry in entries.flatten() {
+        // Joined to `dir` as given, so paths read like the template
+        let path = dir.join(entry.file_name());
+        match entry.file_type() {
+            Ok(kind) if kind.is_file() => files.push(path),
+            Ok(kind) if kind.is_dir() && depth > 1 => walk(&path, depth - 1, files),
+            _ => {}
+        }
+    }
+}
+
+// Run metadata sidecars written next to an output file: `STEM.meta.json`
+// and `STEM.run_NNNN.meta.json`
+fn sidecars(path: &Path) -> Vec<PathBuf> {
+    let path = if path.extension().is_some_and(|ext| ext == "gz") { &path.with_extension("") } else { path };
+    let (Some(dir), Some(stem)) = (path.parent(), path.file_stem()) else { return Vec::new() };
+    let stem = stem.to_string_lossy();
+    let listed = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let Ok(entries) = std::fs::read_dir(listed) else { return Vec::new() };
+    entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| {
+            let Some(middle) = name.strip_prefix(stem.as_ref()).and_then(|rest| rest.strip_suffix(".meta.json")) else {
+                return false;
+            };
+            middle.is_empty() || middle.strip_prefix(".run_").is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .map(|name| dir.join(name))
+        .collect()
+}
+
+/// Whether two paths name the same file, also through links or relative
+/// parts; paths that don't exist are compared as given.
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    // A directory of ten runs, run_0000.csv ten days old to run_0009.csv one
+    // day old, 1000 bytes each, and files that aren't the logger's
+    struct Fixture {
+        dir: tempfile::TempDir,
+        template: String,
+    }
+
+    const STRANGERS: [&str; 5] = ["notes.txt", "run_abc.csv", "other_0001.csv", "run_0001.csv.bak", "sub/run_0002.csv"];
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let dir = tempfile::tempdir().unwrap();
+            let template = format!("{}/run_{{run}}.csv", dir.path().display());
+            let fixture = Fixture { dir, template };
+            for run in 0..10 {
+                fixture.create(&format!("run_{:04}.csv", run), 1000, (10 - run) as u32 * DAY);
+            }
+            fs::create_dir(fixture.path("sub")).unwrap();
+            for name in STRANGERS {
+                // Older than any run
+                fixture.create(name, 5000, 100 * DAY);
+            }
+            fixture
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.path().join(name)
+        }
+
+        fn create(&self, name: &str, size: usize, age: Duration) {
+            let path = self.path(name);
+            fs::write(&path, vec![b'x'; size]).unwrap();
+            File::options().write(true).open(&path).unwrap().set_modified(SystemTime::now() - age).unwrap();
+        }
+
+        // The runs left, by number
+        fn runs(&self) -> Vec<usize> {
+            (0..10).filter(|run| self.path(&format!("run_{:04}.csv", run)).exists()).collect()
+        }
+
+        fn apply(&self, policies: &[&str], dry_run: bool) {
+            let current = self.path("run_0009.csv");
+            Retention::parse(policies.iter().copied(), dry_run).unwrap().apply(&self.template, &[&current]);
+            for name in STRANGERS {
+                assert!(self.path(name).exists(), "{} was deleted", name);
+            }
+        }
+    }
+
+    #[test]
+    fn count_keeps_the_newest_files() {
+        let fixture = Fixture::new();
+        fixture.apply(&["count:3"], false);
+        // Three besides the file being written
+        assert_eq!(fixture.runs(), [6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn age_deletes_files_older_than_the_limit() {
+        let fixture = Fixture::new();
+        fixture.apply(&["age:132h"], false);
+        assert_eq!(fixture.runs(), [5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn size_deletes_the_oldest_until_the_total_fits() {
+        let fixture = Fixture::new();
+        fixture.apply(&["size:2.5KB"], false);
+        assert_eq!(fixture.runs(), [7, 8, 9]);
+    }
+
+    #[test]
+    fn every_policy_has_to_hold() {
+        let fixture = Fixture::new();
+        fixture.apply(&["count:6", "age:30d", "size:4KB"], false);
+        assert_eq!(fixture.runs(), [5, 6, 7, 8, 9]);
+        fixture.apply(&["count:6", "age:84h"], false);
+        assert_eq!(fixture.runs(), [7, 8, 9]);
+    }
+
+    #[test]
+    fn the_current_file_is_exempt() {
+        let fixture = Fixture::new();
+        fixture.apply(&["count:0", "age:1s"], false);
+        assert_eq!(fixture.runs(), [9]);
+    }
+
+    #[test]
+    fn a_dry_run_deletes_nothing() {
+        let fixture = Fixture::new();
+        fixture.apply(&["count:1", "age:1s", "size:1B"], true);
+        assert_eq!(fixture.runs(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sidecars_and_compressed_files_go_with_their_runs() {
+        let fixture = Fixture::new();
+        fs::rename(fixture.path("run_0001.csv"), fixture.path("run_0001.csv.gz")).unwrap();
+        fixture.create("run_0000.meta.json", 100, DAY);
+        fixture.create("run_0000.run_0000.meta.json", 100, DAY);
+        // Another number of the same run, from unused_path
+        fixture.create("run_0003-2.csv", 1000, 8 * DAY + DAY / 2);
+        fixture.apply(&["count:7"], false);
+        assert!(!fixture.path("run_0001.csv.gz").exists());
+        assert!(!fixture.path("run_0000.meta.json").exists());
+        assert!(!fixture.path("run_0000.run_0000.meta.json").exists());
+        assert!(!fixture.path("run_0003-2.csv").exists());
+        assert_eq!(fixture.runs(), [2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn policies_are_parsed() {
+        let retention = Retention::parse(["count:200", "age:30d", "size:50GB"].into_iter(), false).unwrap();
+        assert_eq!(retention.count, Some(200));
+        assert_eq!(retention.age, Some(30 * DAY));
+        assert!(retention.size.is_some());
+        for arg in ["count:many", "age:forever", "files:3", "count"] {
+            let error = Retention::parse(std::iter::once(arg), false).unwrap_err();
+            assert!(error.starts_with(&format!("Invalid --retain '{}'", arg)), "{}", error);
+        }
+    }
+}
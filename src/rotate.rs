@@ -0,0 +1,305 @@
+//! `--rotate-align day|hour`: a new output file at each calendar boundary.
+//!
+//! The session-long output file is switched, as by `newfile`, at midnight or
+//! at the top of each hour in the time zone of the Timestamp column (the
+//! system's, or `TZ`). Boundaries come from the time zone's rules rather than
+//! from adding 24 hours, so the 23- and 25-hour days of a daylight saving
+//! change still end at midnight, and the hour repeated when clocks go back
+//! gets a file of its own. The writer compares each row's arrival with the
+//! next boundary before writing it, so the first row stamped at or past the
+//! boundary is the first of the new file. The switch itself is made on a
+//! thread of its own, which the writer wakes and waits for; it also checks
+//! once a second, so a quiet port still gets its file for the day. A run
+//! going on at the boundary is the same run in both files: the old one ends
+//! and the new one begins with a `continued` row, and none of what the end
+//! or start of a run sets off (hooks, sidecars, notifications) happens.
+
+use std::{
+    sync::{atomic::Ordering, Arc, Condvar, Mutex},
+    thread,
+};
+
+use chrono::{DateTime, Local, LocalResult, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Timelike};
+
+use crate::session::{Session, Shared};
+
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The rotate thread, shared with the writers that wait on it.
+pub type Rotate = Arc<Rotator>;
+
+/// Hands a switch asked for by a writer to the rotate thread.
+pub struct Rotator {
+    // Arrival of the row waiting for the next file
+    asked: Mutex<Option<DateTime<Local>>>,
+    wake: Condvar,
+    switched: Condvar,
+}
+
+impl Rotator {
+    /// Waits until the file for a row that arrived at `at` is open, if `at`
+    /// is past the boundary; the writer calls it before writing the row.
+    pub fn wait_for_switch(&self, at: DateTime<Local>, shared: &Shared) {
+        let mut asked = self.asked.lock().unwrap();
+        while at.timestamp_millis() >= shared.next_rotation.load(Ordering::Acquire) {
+            asked.get_or_insert(at);
+            self.wake.notify_one();
+            asked = self.switched.wait(asked).unwrap();
+        }
+    }
+}
+
+/// Which calendar boundaries start a new file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Align {
+    Day,
+    Hour,
+}
+
+impl Align {
+    pub fn parse(text: &str) -> Result<Align, String> {
+        match text {
+            "day" => Ok(Align::Day),
+            "hour" => Ok(Align::Hour),
+            other => Err(format!("Unknown --rotate-align '{}': expected day or hour", other)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Align::Day => "day",
+            Align::Hour => "hour",
+        }
+    }
+
+    /// Placeholders of the output path of which one gives each file a name
+    /// of its own.
+    pub fn placeholders(self) -> &'static [&'static str] {
+        match self {
+            Align::Day => &["date", "datetime"],
+            Align::Hour => &["time", "datetime"],
+        }
+    }
+}
+
+/// The first boundary of `align` after `now`, in `now`'s time zone.
+pub fn next_boundary<Tz: TimeZone>(align: Align, now: &DateTime<Tz>) -> DateTime<Tz> {
+    let zone = now.timezone();
+    let local = now.naive_local();
+    match align {
+        Align::Day => {
+            let tomorrow = local.date().succ_opt().expect("Dates go on");
+            first_at_or_after(&zone, tomorrow.and_time(NaiveTime::MIN))
+        }
+        Align::Hour => {
+            let into_hour = TimeDelta::seconds(i64::from(local.minute() * 60 + local.second()))
+                + TimeDelta::nanoseconds(i64::from(local.nanosecond()));
+            // An hour after this one started: in the hour repeated when
+            // clocks go back, the next hour on the clock is two hours away
+            let hour_later = now.clone() - into_hour + TimeDelta::hours(1);
+            let next_on_clock = first_at_or_after(&zone, local - into_hour + TimeDelta::hours(1));
+            hour_later.min(next_on_clock)
+        }
+    }
+}
+
+// The first instant whose local time is `local` or later: the earlier one
+// when clocks go back over it, the end of the gap when they skip it
+fn first_at_or_after<Tz: TimeZone>(zone: &Tz, mut local: NaiveDateTime) -> DateTime<Tz> {
+    loop {
+        match zone.from_local_datetime(&local) {
+            LocalResult::Single(at) | LocalResult::Ambiguous(at, _) => return at,
+            LocalResult::None => local += TimeDelta::minutes(1),
+        }
+    }
+}
+
+/// Starts the thread that switches files: when a writer asks, and once a
+/// second for a boundary passed while no row came in.
+pub fn spawn(session: Arc<Mutex<Session>>, shared: Arc<Shared>) -> Rotate {
+    let rotator = Arc::new(Rotator {
+        asked: Mutex::new(None),
+        wake: Condvar::new(),
+        switched: Condvar::new(),
+    });
+    let thread_rotator = Arc::clone(&rotator);
+    thread::spawn(move || loop {
+        let asked = {
+            let asked = thread_rotator.asked.lock().unwrap();
+            let (mut asked, _) = thread_rotator
+                .wake
+                .wait_timeout_while(asked, CHECK_INTERVAL, |asked| asked.is_none())
+                .unwrap();
+            asked.take()
+        };
+        let now = asked.unwrap_or_else(crate::timesource::now);
+        if now.timestamp_millis() >= shared.next_rotation.load(Ordering::Acquire) {
+            session.lock().unwrap().rotate_if_due(now);
+        }
+        let _asked = thread_rotator.asked.lock().unwrap();
+        thread_rotator.switched.notify_all();
+    });
+    rotator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, Utc};
+    use chrono_tz::{America::Sao_Paulo, Asia::Kolkata, Europe::Berlin, Tz};
+
+    fn at(zone: Tz, text: &str) -> DateTime<Tz> {
+        let local = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S").unwrap();
+        zone.from_local_datetime(&local).earliest().unwrap()
+    }
+
+    fn hours(from: &DateTime<Tz>, to: &DateTime<Tz>) -> f64 {
+        (*to - *from).num_seconds() as f64 / 3600.0
+    }
+
+    // Every boundary from `from` until `until`
+    fn boundaries(align: Align, from: DateTime<Tz>, until: DateTime<Tz>) -> Vec<DateTime<Tz>> {
+        let mut found = Vec::new();
+        let mut now = from;
+        loop {
+            now = next_boundary(align, &now);
+            if now > until {
+                return found;
+            }
+            found.push(now);
+        }
+    }
+
+    #[test]
+    fn days_end_at_midnight_whatever_their_length() {
+        // Clocks go forward on 31 March 2024 and back on 27 October
+        let spring = boundaries(Align::Day, at(Berlin, "2024-03-30 12:00:00"), at(Berlin, "2024-04-01 12:00:00"));
+        assert_eq!(spring.iter().map(|day| day.to_rfc3339()).collect::<Vec<_>>(), ["2024-03-31T00:00:00+01:00", "2024-04-01T00:00:00+02:00"]);
+        assert_eq!(hours(&spring[0], &spring[1]), 23.0);
+        let autumn = boundaries(Align::Day, at(Berlin, "2024-10-26 23:59:59"), at(Berlin, "2024-10-28 00:00:00"));
+        assert_eq!(autumn.iter().map(|day| day.to_rfc3339()).collect::<Vec<_>>(), ["2024-10-27T00:00:00+02:00", "2024-10-28T00:00:00+01:00"]);
+        assert_eq!(hours(&autumn[0], &autumn[1]), 25.0);
+    }
+
+    #[test]
+    fn a_day_whose_midnight_is_skipped_starts_when_its_clock_does() {
+        // Clocks went from 00:00 to 01:00 on 4 November 2018
+        let day = next_boundary(Align::Day, &at(Sao_Paulo, "2018-11-03 18:30:00"));
+        assert_eq!(day.to_rfc3339(), "2018-11-04T01:00:00-02:00");
+        assert_eq!(next_boundary(Align::Day, &day).to_rfc3339(), "2018-11-05T00:00:00-02:00");
+        // And from 00:00 back to 23:00 on 17 February 2019: 23:00 to 00:00 of
+        // the 16th happened twice, and the 16th had 25 hours
+        let start = next_boundary(Align::Day, &at(Sao_Paulo, "2019-02-15 08:00:00"));
+        let end = next_boundary(Align::Day, &start);
+        assert_eq!(end.to_rfc3339(), "2019-02-17T00:00:00-03:00");
+        assert_eq!(hours(&start, &end), 25.0);
+    }
+
+    #[test]
+    fn every_hour_that_happens_starts_a_file() {
+        let spring = boundaries(Align::Hour, at(Berlin, "2024-03-31 00:30:00"), at(Berlin, "2024-03-31 04:00:00"));
+        // 02:00 never happened
+        let clock: Vec<String> = spring.iter().map(|hour| hour.format("%H:%M%z").to_string()).collect();
+        assert_eq!(clock, ["01:00+0100", "03:00+0200", "04:00+0200"]);
+        let autumn = boundaries(Align::Hour, at(Berlin, "2024-10-27 00:30:00"), at(Berlin, "2024-10-27 04:00:00"));
+        // 02:00 happened twice, an hour apart, and each began an hour
+        let clock: Vec<String> = autumn.iter().map(|hour| hour.format("%H:%M%z").to_string()).collect();
+        assert_eq!(clock, ["01:00+0200", "02:00+0200", "02:00+0100", "03:00+0100", "04:00+0100"]);
+        assert!(autumn.windows(2).all(|pair| hours(&pair[0], &pair[1]) == 1.0));
+    }
+
+    #[test]
+    fn hours_follow_the_clock_of_a_half_hour_zone() {
+        let hour = next_boundary(Align::Hour, &at(Kolkata, "2024-06-01 10:59:59"));
+        assert_eq!(hour.to_rfc3339(), "2024-06-01T11:00:00+05:30");
+        assert_eq!(next_boundary(Align::Hour, &hour).to_rfc3339(), "2024-06-01T12:00:00+05:30");
+    }
+
+    #[test]
+    fn a_boundary_is_always_later() {
+        let midnight = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_time(NaiveTime::MIN).and_utc();
+        assert_eq!(next_boundary(Align::Day, &midnight), midnight + TimeDelta::days(1));
+        assert_eq!(next_boundary(Align::Hour, &midnight), midnight + TimeDelta::hours(1));
+        let just_before = midnight - TimeDelta::nanoseconds(1);
+        assert_eq!(next_boundary(Align::Day, &just_before), midnight);
+        assert_eq!(next_boundary(Align::Hour, &just_before), midnight);
+        assert_eq!(next_boundary(Align::Hour, &Utc.with_ymd_and_hms(2024, 1, 1, 0, 59, 59).unwrap()), midnight + TimeDelta::hours(1));
+    }
+
+    #[test]
+    fn the_first_row_past_the_boundary_is_the_first_of_the_next_file() {
+        use crate::serial::{self, Arrival, InputOptions};
+        use crate::session::OutputSettings;
+
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("log_{datetime}.csv");
+        let mut settings = OutputSettings::plain(template.to_str().unwrap(), &["x"]);
+        settings.rotate_align = Some(Align::Hour);
+        let session = Session::new(settings, crate::hooks::Hooks::default()).unwrap();
+        let shared = Arc::clone(&session.shared);
+        let session = Arc::new(Mutex::new(session));
+        session.lock().unwrap().start("");
+        let rotate = spawn(Arc::clone(&session), Arc::clone(&shared));
+        let matches = crate::cli().try_get_matches_from(["serial_logger", "-p", "COM3", "--fields", "1"]).unwrap();
+        let options = InputOptions {
+            format: crate::line_format(&matches, None),
+            strip_ansi: true,
+            skip_blank_lines: true,
+            skip_lines: 0,
+            read_timeout: serial::DEFAULT_READ_TIMEOUT,
+            wait_for: None,
+            auto_start: None,
+            device_commands: None,
+            hw_trigger: None,
+            modem_interval: None,
+            log_breaks: false,
+            observers: Vec::new(),
+            gap_marker: None,
+            max_line_length: usize::MAX,
+            port: None,
+            rotate: Some(rotate),
+        };
+        let boundary = DateTime::from_timestamp_millis(shared.next_rotation.load(Ordering::Acquire)).unwrap().with_timezone(&Local);
+        for (ms, value) in [(-1, "before"), (0, "at"), (1, "after")] {
+            let arrival = Arrival {
+                wall: boundary + TimeDelta::milliseconds(ms),
+                instant: std::time::Instant::now(),
+            };
+            serial::handle_line(&format!("UDP packet contents:{}", value), &shared, &options, &arrival, true);
+        }
+        session.lock().unwrap().finish();
+        let next_path = dir.path().join(format!("log_{}.csv", boundary.format("%Y-%m-%d_%H%M%S")));
+        let next = std::fs::read_to_string(&next_path).unwrap();
+        let data: Vec<&str> = next.lines().filter(|line| line.starts_with("data,")).map(|line| line.rsplit(',').next().unwrap()).collect();
+        assert_eq!(data, ["at", "after"]);
+        let files: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 2);
+        let previous = std::fs::read_to_string(files.iter().find(|path| **path != next_path).unwrap()).unwrap();
+        // The run went on in the new file as the same run
+        let kinds = |text: &str| -> Vec<String> {
+            text.lines()
+                .filter(|line| !line.starts_with("meta,") && !line.starts_with("units,") && !line.starts_with("Type,"))
+                .map(|line| line.split(',').next().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(kinds(&previous), ["start", "data", "continued"], "{}", previous);
+        assert_eq!(kinds(&next), ["continued", "data", "data", "stop"], "{}", next);
+        let continued = |text: &str| text.lines().find(|line| line.starts_with("continued,")).unwrap().split(',').skip(1).map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(continued(&previous), continued(&next));
+        let start = previous.lines().find(|line| line.starts_with("start,")).unwrap().split(',').skip(2).map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(continued(&next)[1..3], start[..2]);
+        assert_eq!(continued(&next)[0], boundary.format("%Y-%m-%d %H:%M:%S").to_string());
+        assert_eq!(
+            shared.next_rotation.load(Ordering::Acquire),
+            next_boundary(Align::Hour, &boundary).timestamp_millis()
+        );
+    }
+
+    #[test]
+    fn align_names_parse() {
+        for align in [Align::Day, Align::Hour] {
+            assert_eq!(Align::parse(align.name()), Ok(align));
+        }
+        assert!(Align::parse("week").unwrap_err().contains("expected day or hour"));
+    }
+}
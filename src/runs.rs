@@ -0,0 +1,206 @@
+//! Splitting the rows of an output file into runs.
+//!
+//! A run begins at its `start` row and ends at its `stop` row. A run that
+//! never got a stop row (a crash, or a full disk in older versions) ends where
+//! the next run starts or at the end of the file, and is reported as
+//! truncated. Rows before the first start or between runs are outside any run.
+//! A run carried over into the next file by `--rotate-align` ends at the
+//! `continued` row that closes the one file, and begins at the `continued`
+//! row that opens the next.
+//! Every subcommand that works with the runs of an existing file goes through
+//! here, so they all agree on where a run begins and ends.
+
+use std::collections::VecDeque;
+
+use crate::logfile::{Line, Row};
+
+/// A row of the file placed relative to the runs.
+#[derive(Debug, Clone)]
+pub enum Segment {
+    /// Metadata, or a row between runs
+    Outside(Line),
+    /// A run begins, with its start row (or `continued` row)
+    Begin(Line),
+    /// A data or marker row inside the current run
+    Row(Line),
+    /// The current run ends, with its stop row (or `continued` row); None if
+    /// it was truncated
+    End(Option<Line>),
+}
+
+/// Iterator over the segments of a file's rows; read errors are passed
+/// through unchanged.
+pub struct Runs<I> {
+    lines: I,
+    in_run: bool,
+    pending: VecDeque<Segment>,
+}
+
+/// Segments the rows from `lines` (usually a `LogReader`) into runs.
+pub fn segment<I: Iterator<Item = Result<Line, String>>>(lines: I) -> Runs<I> {
+    Runs {
+        lines,
+        in_run: false,
+        pending: VecDeque::new(),
+    }
+}
+
+impl<I> Runs<I> {
+    /// The rows being segmented, e.g. to ask a `LogReader` for its columns.
+    pub fn inner(&self) -> &I {
+        &self.lines
+    }
+}
+
+impl<I: Iterator<Item = Result<Line, String>>> Iterator for Runs<I> {
+    type Item = Result<Segment, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(segment) = self.pending.pop_front() {
+            return Some(Ok(segment));
+        }
+        let line = match self.lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => return Some(Err(e)),
+            None => {
+                // The file ends inside a run
+                return std::mem::take(&mut self.in_run).then_some(Ok(Segment::End(None)));
+            }
+        };
+        let segment = match (&line.row, self.in_run) {
+            (Row::Start { .. }, true) => {
+                // The previous run never stopped
+                self.pending.push_back(Segment::Begin(line));
+                Segment::End(None)
+            }
+            (Row::Start { .. } | Row::Continued { .. }, false) => {
+                self.in_run = true;
+                Segment::Begin(line)
+            }
+            (Row::Stop { .. } | Row::Continued { .. }, true) => {
+                self.in_run = false;
+                Segment::End(Some(line))
+            }
+            (Row::Meta { .. } | Row::Stop { .. }, _) => Segment::Outside(line),
+            (Row::Data { .. } | Row::Marker { .. }, true) => Segment::Row(line),
+            (Row::Data { .. } | Row::Marker { .. }, false) => Segment::Outside(line),
+        };
+        Some(Ok(segment))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logfile::LogReader;
+
+    const HEADER: &str = "meta,port,/dev/ttyUSB0,,\nType,Timestamp,Run/End,RunId,x,y\n";
+
+    fn start(run: i64) -> String {
+        format!("start,2025-01-14 10:2{}:00,run {},id{},,\n", run, run, run)
+    }
+
+    fn stop(run: i64) -> String {
+        format!("stop,2025-01-14 10:2{}:30,end of run,id{},,\n", run, run)
+    }
+
+    fn data(x: i64) -> String {
+        format!("data,2025-01-14 10:20:01,,,{},{}\n", x, x * 2)
+    }
+
+    // The segments of a file as short names: begin 0, row, end, end truncated...
+    fn segments(file: &str) -> Vec<String> {
+        segment(LogReader::new(file.as_bytes()))
+            .map(|segment| match segment {
+                Err(_) => "error".to_string(),
+                Ok(segment) => describe(segment),
+            })
+            .collect()
+    }
+
+    fn describe(segment: Segment) -> String {
+        match segment {
+            Segment::Outside(line) => format!("outside {}", line.record.get(0).unwrap()),
+            Segment::Begin(Line { row: Row::Start { run, .. }, .. }) => format!("begin {}", run.unwrap()),
+            Segment::Begin(Line { row: Row::Continued { run, .. }, .. }) => format!("begin {} continued", run.unwrap()),
+            Segment::Begin(line) => panic!("begin at {:?}", line.row),
+            Segment::Row(line) => format!("row {}", line.record.get(0).unwrap()),
+            Segment::End(Some(_)) => "end".to_string(),
+            Segment::End(None) => "end truncated".to_string(),
+        }
+    }
+
+    #[test]
+    fn runs_begin_at_start_and_end_at_stop() {
+        let file = format!("{}{}{}{}{}", HEADER, start(0), data(1), data(2), stop(0));
+        assert_eq!(segments(&file), ["outside meta", "begin 0", "row data", "row data", "end"]);
+    }
+
+    #[test]
+    fn back_to_back_runs_are_kept_apart() {
+        let file = format!("{}{}{}{}{}{}{}", HEADER, start(0), data(1), stop(0), start(1), data(2), stop(1));
+        assert_eq!(
+            segments(&file),
+            ["outside meta", "begin 0", "row data", "end", "begin 1", "row data", "end"]
+        );
+    }
+
+    #[test]
+    fn a_run_without_a_stop_ends_at_the_next_start() {
+        let file = format!("{}{}{}{}{}{}", HEADER, start(0), data(1), start(1), data(2), stop(1));
+        assert_eq!(
+            segments(&file),
+            ["outside meta", "begin 0", "row data", "end truncated", "begin 1", "row data", "end"]
+        );
+    }
+
+    #[test]
+    fn a_truncated_final_run_ends_at_the_end_of_the_file() {
+        let file = format!("{}{}{}{}{}{}", HEADER, start(0), stop(0), start(1), data(1), data(2));
+        assert_eq!(
+            segments(&file),
+            ["outside meta", "begin 0", "end", "begin 1", "row data", "row data", "end truncated"]
+        );
+        // Also when the last line was cut off mid-row, which is a read error
+        let cut = format!("{}{}{}data,2025-01-14 10:20", HEADER, start(0), data(1));
+        assert_eq!(segments(&cut), ["outside meta", "begin 0", "row data", "error", "end truncated"]);
+    }
+
+    #[test]
+    fn rows_between_runs_are_outside() {
+        let file = format!(
+            "{}{}{}{}reconfig,2025-01-14 10:20:40,baud 9600,,,\n{}{}",
+            HEADER,
+            data(0),
+            start(0),
+            stop(0),
+            data(1),
+            stop(1)
+        );
+        assert_eq!(
+            segments(&file),
+            ["outside meta", "outside data", "begin 0", "end", "outside reconfig", "outside data", "outside stop"]
+        );
+    }
+
+    #[test]
+    fn markers_inside_a_run_belong_to_it() {
+        let file = format!("{}{}gap,2025-01-14 10:20:05,1.5s,,,\n{}{}", HEADER, start(0), data(1), stop(0));
+        assert_eq!(segments(&file), ["outside meta", "begin 0", "row gap", "row data", "end"]);
+    }
+
+    #[test]
+    fn a_run_carried_over_ends_and_begins_at_its_continued_rows() {
+        let continued = "continued,2025-01-14 11:00:00,run 0,id0,,\n";
+        let leaving = format!("{}{}{}{}", HEADER, start(0), data(1), continued);
+        assert_eq!(segments(&leaving), ["outside meta", "begin 0", "row data", "end"]);
+        let going_on = format!("{}{}{}{}", HEADER, continued, data(2), stop(0));
+        assert_eq!(segments(&going_on), ["outside meta", "begin 0 continued", "row data", "end"]);
+    }
+
+    #[test]
+    fn an_empty_file_has_no_runs() {
+        assert!(segments("").is_empty());
+        assert_eq!(segments(HEADER), ["outside meta"]);
+    }
+}
@@ -0,0 +1,362 @@
+//! Column types for `--types`, which rewrite each field in one clean form.
+//!
+//! A schema such as `i64,f32:3,f32:3,f32:3` gives one type per field as
+//! written (after `--select`): `i8 i16 i32 i64 u8 u16 u32 u64` for integers,
+//! `f32 f64` for floats, optionally with `:N` decimals, and `str` for a field
+//! left as it is. `TYPE*N` repeats a type, so the schema above can be written
+//! `i64,f32:3*3`. Every field is parsed as its type and written back
+//! normalized: integers without a leading `+` or zeros, floats with exactly
+//! N decimals (or as the shortest form that reads back as the same value),
+//! non-finite floats as `nan`, `inf` and `-inf`. A field that doesn't parse,
+//! or is out of range for its type, fails the row. Floats without `:N`
+//! follow `--float-format`, as do computed columns.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Signed { bits: u32 },
+    Unsigned { bits: u32 },
+    Float { bits: u32, decimals: Option<usize> },
+    Text,
+}
+
+/// What happens to `nan` and `inf` in float columns (--on-nonfinite).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NonFinite {
+    // Written as nan, inf or -inf
+    Keep,
+    // The row fails like any other field that doesn't fit its type
+    Reject,
+}
+
+/// How floats without decimals of their own are written (--float-format).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloatFormat {
+    // The shortest form that reads back as the same value
+    Passthrough,
+    // Exactly this many decimals
+    Fixed(usize),
+    // This many significant digits, never in scientific notation
+    Significant(usize),
+}
+
+impl FloatFormat {
+    /// Parses `passthrough`, `fixed:N` or `sig:N`.
+    pub fn parse(text: &str) -> Result<FloatFormat, String> {
+        let invalid = || {
+            format!("Invalid --float-format '{}': expected passthrough, fixed:<decimals> or sig:<digits>", text)
+        };
+        if text == "passthrough" {
+            return Ok(FloatFormat::Passthrough);
+        }
+        let (kind, count) = text.split_once(':').ok_or_else(invalid)?;
+        let count: usize = count.parse().map_err(|_| invalid())?;
+        match kind {
+            "fixed" => Ok(FloatFormat::Fixed(count)),
+            "sig" if count > 0 => Ok(FloatFormat::Significant(count)),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Writes a finite value; negative zero is written as zero, also when
+    /// a small negative value rounds to it.
+    pub fn format(self, value: f64) -> String {
+        let text = match self {
+            FloatFormat::Passthrough => return value.to_string(),
+            // Floats this large are whole numbers, and their shortest digits
+            // beat writing out the exact binary value
+            FloatFormat::Fixed(decimals) if value.abs() >= 2f64.powi(f64::MANTISSA_DIGITS as i32) => {
+                format!("{}{}{}", value, if decimals > 0 { "." } else { "" }, "0".repeat(decimals))
+            }
+            FloatFormat::Fixed(decimals) => format!("{:.*}", decimals, value),
+            FloatFormat::Significant(digits) => significant(value, digits),
+        };
+        match text.strip_prefix('-') {
+            Some(unsigned) if unsigned.bytes().all(|byte| byte == b'0' || byte == b'.') => unsigned.to_string(),
+            _ => text,
+        }
+    }
+}
+
+// `value` rounded to `digits` significant digits and written out in full
+fn significant(value: f64, digits: usize) -> String {
+    // Scientific notation does the rounding and tells where the point goes
+    let scientific = format!("{:.*e}", digits - 1, value);
+    let (mantissa, exponent) = scientific.split_once('e').expect("Scientific notation has an exponent");
+    let exponent: i64 = exponent.parse().expect("The exponent is a number");
+    let decimals = digits as i64 - 1 - exponent;
+    if decimals >= 0 {
+        return format!("{:.*}", decimals as usize, value);
+    }
+    // Past the significant digits a large number is zeros
+    let mut text = mantissa.replace('.', "");
+    text.extend(std::iter::repeat_n('0', decimals.unsigned_abs() as usize));
+    text
+}
+
+/// Why a field didn't fit its type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeError {
+    // Index of the field in the row
+    pub column: usize,
+    pub field: String,
+    pub expected: ColumnType,
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColumnType::Signed { bits } => write!(f, "i{}", bits),
+            ColumnType::Unsigned { bits } => write!(f, "u{}", bits),
+            ColumnType::Float { bits, decimals: None } => write!(f, "f{}", bits),
+            ColumnType::Float {
+                bits,
+                decimals: Some(decimals),
+            } => write!(f, "f{}:{}", bits, decimals),
+            ColumnType::Text => f.write_str("str"),
+        }
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "field {} '{}' is not a valid {}", self.column, self.field, self.expected)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    pub columns: Vec<ColumnType>,
+    pub non_finite: NonFinite,
+}
+
+impl Schema {
+    /// Parses a `--types` argument such as `i64,f32:3*3`.
+    pub fn parse(text: &str, non_finite: NonFinite) -> Result<Schema, String> {
+        let invalid = |e: String| format!("Invalid --types '{}': {}", text, e);
+        let mut columns = Vec::new();
+        for item in text.split(',') {
+            let item = item.trim();
+            let (name, count) = match item.split_once('*') {
+                Some((name, count)) => {
+                    let count = count.trim().parse::<usize>().ok().filter(|n| *n > 0);
+                    (name.trim(), count.ok_or_else(|| invalid(format!("invalid repeat in '{}'", item)))?)
+                }
+                None => (item, 1),
+            };
+            let column = parse_type(name).ok_or_else(|| invalid(format!("unknown type '{}'", name)))?;
+            columns.extend(std::iter::repeat_n(column, count));
+        }
+        Ok(Schema { columns, non_finite })
+    }
+
+    /// Normalizes a row's fields to their types; `decimal_comma` reads
+    /// `-2,45` in float columns as -2.45, and floats without decimals of
+    /// their own are written as `float_format` says.
+    pub fn coerce(&self, fields: &[&str], decimal_comma: bool, float_format: FloatFormat) -> Result<Vec<String>, TypeError> {
+        fields
+            .iter()
+            .zip(&self.columns)
+            .enumerate()
+            .map(|(column, (field, column_type))| {
+                self.coerce_field(field, *column_type, decimal_comma, float_format).ok_or_else(|| TypeError {
+                    column,
+                    field: field.to_string(),
+                    expected: *column_type,
+                })
+            })
+            .collect()
+    }
+
+    fn coerce_field(
+        &self,
+        field: &str,
+        column_type: ColumnType,
+        decimal_comma: bool,
+        float_format: FloatFormat,
+    ) -> Option<String> {
+        let field = field.trim();
+        let number = field.strip_prefix('+').unwrap_or(field);
+        match column_type {
+            ColumnType::Text => Some(field.to_string()),
+            ColumnType::Signed { bits } => {
+                let value: i64 = number.parse().ok()?;
+                let limit = 1i128 << (bits - 1);
+                (-limit..limit).contains(&(value as i128)).then(|| value.to_string())
+            }
+            ColumnType::Unsigned { bits } => {
+                let value: u64 = number.parse().ok()?;
+                (bits == 64 || value < 1 << bits).then(|| value.to_string())
+            }
+            ColumnType::Float { bits, decimals } => {
+                let value: f64 = if decimal_comma {
+                    number.replace(',', ".").parse().ok()?
+                } else {
+                    number.parse().ok()?
+                };
+                if !value.is_finite() {
+                    return match self.non_finite {
+                        NonFinite::Reject => None,
+                        NonFinite::Keep if value.is_nan() => Some("nan".to_string()),
+                        NonFinite::Keep if value > 0.0 => Some("inf".to_string()),
+                        NonFinite::Keep => Some("-inf".to_string()),
+                    };
+                }
+                // A float too large for f32 becomes inf; that's out of range
+                if bits == 32 && !(value as f32).is_finite() {
+                    return None;
+                }
+                Some(match (bits, decimals) {
+                    (32, Some(decimals)) => format!("{:.*}", decimals, value as f32),
+                    (_, Some(decimals)) => format!("{:.*}", decimals, value),
+                    (32, None) if float_format == FloatFormat::Passthrough => (value as f32).to_string(),
+                    (32, None) => float_format.format((value as f32).into()),
+                    (_, None) => float_format.format(value),
+                })
+            }
+        }
+    }
+}
+
+// "f32:3", "i64", "str"
+fn parse_type(name: &str) -> Option<ColumnType> {
+    let (base, decimals) = match name.split_once(':') {
+        Some((base, decimals)) => (base, Some(decimals.trim().parse::<usize>().ok()?)),
+        None => (name, None),
+    };
+    let column_type = match base.trim() {
+        "i8" => ColumnType::Signed { bits: 8 },
+        "i16" => ColumnType::Signed { bits: 16 },
+        "i32" => ColumnType::Signed { bits: 32 },
+        "i64" => ColumnType::Signed { bits: 64 },
+        "u8" => ColumnType::Unsigned { bits: 8 },
+        "u16" => ColumnType::Unsigned { bits: 16 },
+        "u32" => ColumnType::Unsigned { bits: 32 },
+        "u64" => ColumnType::Unsigned { bits: 64 },
+        "f32" => ColumnType::Float { bits: 32, decimals },
+        "f64" => ColumnType::Float { bits: 64, decimals },
+        "str" => ColumnType::Text,
+        _ => return None,
+    };
+    // Decimals only make sense for floats
+    match column_type {
+        ColumnType::Float { .. } => Some(column_type),
+        _ if decimals.is_none() => Some(column_type),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coerce(types: &str, non_finite: NonFinite, float_format: FloatFormat, fields: &[&str]) -> Result<Vec<String>, TypeError> {
+        Schema::parse(types, non_finite).unwrap().coerce(fields, false, float_format)
+    }
+
+    #[test]
+    fn float_formats_are_parsed() {
+        assert_eq!(FloatFormat::parse("passthrough"), Ok(FloatFormat::Passthrough));
+        assert_eq!(FloatFormat::parse("fixed:3"), Ok(FloatFormat::Fixed(3)));
+        assert_eq!(FloatFormat::parse("fixed:0"), Ok(FloatFormat::Fixed(0)));
+        assert_eq!(FloatFormat::parse("sig:6"), Ok(FloatFormat::Significant(6)));
+        for text in ["sig:0", "fixed", "fixed:", "fixed:-1", "fixed:x", "round:2", ""] {
+            assert!(FloatFormat::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn fixed_writes_exactly_the_decimals() {
+        let fixed = FloatFormat::Fixed(3);
+        assert_eq!(fixed.format(2.5), "2.500");
+        assert_eq!(fixed.format(-2.450000047683716), "-2.450");
+        assert_eq!(fixed.format(1e-10), "0.000");
+        assert_eq!(fixed.format(123456.78949), "123456.789");
+        assert_eq!(FloatFormat::Fixed(0).format(-7.6), "-8");
+        // Large values keep their shortest digits rather than the binary
+        // value's noise, and never turn scientific
+        assert_eq!(fixed.format(1e20), "100000000000000000000.000");
+        assert_eq!(fixed.format(-1e300), format!("-1{}.000", "0".repeat(300)));
+        assert_eq!(fixed.format(f64::MAX).len(), 309 + 4);
+        assert_eq!(fixed.format(9007199254740991.0), "9007199254740991.000");
+    }
+
+    #[test]
+    fn negative_zero_is_written_as_zero() {
+        for format in [FloatFormat::Fixed(3), FloatFormat::Fixed(0), FloatFormat::Significant(4)] {
+            let zero = format.format(0.0);
+            assert_eq!(format.format(-0.0), zero);
+            assert!(!zero.starts_with('-'));
+        }
+        assert_eq!(FloatFormat::Fixed(3).format(-0.0), "0.000");
+        // Also when a small negative value rounds to zero
+        assert_eq!(FloatFormat::Fixed(3).format(-0.0004), "0.000");
+        assert_eq!(FloatFormat::Fixed(3).format(-0.0005001), "-0.001");
+    }
+
+    #[test]
+    fn significant_digits_never_turn_scientific() {
+        let sig = FloatFormat::Significant(6);
+        assert_eq!(sig.format(-2.450000047683716), "-2.45000");
+        assert_eq!(sig.format(123456789.0), "123457000");
+        assert_eq!(sig.format(0.000012345678), "0.0000123457");
+        assert_eq!(sig.format(999999.5), "1000000");
+        assert_eq!(sig.format(1.0), "1.00000");
+        assert_eq!(FloatFormat::Significant(2).format(1e300), format!("1{}", "0".repeat(300)));
+        let tiny = FloatFormat::Significant(3).format(1.25e-300);
+        assert_eq!(tiny, format!("0.{}125", "0".repeat(299)));
+        assert_eq!(FloatFormat::Significant(1).format(f64::MIN_POSITIVE).len(), 2 + 308);
+    }
+
+    #[test]
+    fn passthrough_writes_the_shortest_form() {
+        let passthrough = FloatFormat::Passthrough;
+        assert_eq!(passthrough.format(2.45), "2.45");
+        assert_eq!(passthrough.format(1e20), "100000000000000000000");
+        assert_eq!(passthrough.format(1e-7), "0.0000001");
+        // f32 fields are written as the f32 they are, not its f64 expansion
+        let fields = coerce("f32,f64", NonFinite::Keep, passthrough, &["-2.45", "-2.45"]).unwrap();
+        assert_eq!(fields, ["-2.45", "-2.45"]);
+    }
+
+    #[test]
+    fn typed_floats_follow_the_format_unless_they_have_decimals() {
+        let fixed = FloatFormat::Fixed(3);
+        let fields = coerce("f32,f64,f64:1,i32", NonFinite::Keep, fixed, &["-2.45", "1e3", "2.25", "7"]).unwrap();
+        assert_eq!(fields, ["-2.450", "1000.000", "2.2", "7"]);
+        let fields = coerce("f32,f64", NonFinite::Keep, FloatFormat::Significant(4), &["-2.45", "+6.02214076e23"]).unwrap();
+        assert_eq!(fields, ["-2.450", "602200000000000000000000"]);
+        // Scientific notation from the device comes out plain
+        let fields = coerce("f64", NonFinite::Keep, fixed, &["-1.5E-9"]).unwrap();
+        assert_eq!(fields, ["0.000"]);
+    }
+
+    #[test]
+    fn non_finite_values_follow_on_nonfinite_in_every_format() {
+        for format in [FloatFormat::Passthrough, FloatFormat::Fixed(3), FloatFormat::Significant(6)] {
+            let fields = coerce("f64*3,f32", NonFinite::Keep, format, &["NaN", "inf", "-infinity", "nan"]).unwrap();
+            assert_eq!(fields, ["nan", "inf", "-inf", "nan"]);
+            for field in ["nan", "inf", "-inf"] {
+                let e = coerce("i8,f64", NonFinite::Reject, format, &["1", field]).unwrap_err();
+                assert_eq!(e.column, 1);
+                assert_eq!(e.to_string(), format!("field 1 '{}' is not a valid f64", field));
+            }
+            // Finite values are unaffected by rejecting the others
+            assert!(coerce("f64", NonFinite::Reject, format, &["1e300"]).is_ok());
+        }
+    }
+
+    #[test]
+    fn floats_out_of_range_for_f32_fail_in_every_format() {
+        for format in [FloatFormat::Passthrough, FloatFormat::Fixed(3), FloatFormat::Significant(6)] {
+            for non_finite in [NonFinite::Keep, NonFinite::Reject] {
+                assert!(coerce("f32", non_finite, format, &["1e39"]).is_err());
+                assert!(coerce("f64", non_finite, format, &["1e39"]).is_ok());
+            }
+        }
+        // Small ones are kept, down to what an f32 can hold
+        let fields = coerce("f32", NonFinite::Keep, FloatFormat::Significant(2), &["-1.5e-30"]).unwrap();
+        assert_eq!(fields, [format!("-0.{}15", "0".repeat(29))]);
+    }
+}
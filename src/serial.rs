@@ -0,0 +1,1165 @@
+use collect_acc_data::cobs;
+use collect_acc_data::frame::{Decoded, Decoder};
+use collect_acc_data::logfile::Kind;
+use collect_acc_data::parser::{self, Admit, Framing, Gate, LineFormat, Parsed};
+use collect_acc_data::raw::Entry;
+use serialport::SerialPort;
+use std::{
+    io::{self, BufRead, BufReader, Read},
+    sync::{atomic::Ordering, mpsc::Receiver, Arc},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::benchmark;
+use crate::clockstep;
+use crate::devcmd::DeviceCommands;
+use crate::events::{self, Event};
+use crate::get_timestamp;
+use crate::grpc;
+use crate::hooks;
+use crate::modem::ModemPoller;
+use crate::multiport::Layout;
+use crate::noise::NoiseMonitor;
+use crate::preview;
+use crate::rejects::Reason;
+use crate::rotate::Rotate;
+use crate::session::{self, write_marker, Shared};
+use crate::settings::{self, Echo};
+use crate::strict::Check;
+use crate::sink::CsvSink;
+use crate::tap::{Observer, Tap};
+use crate::trigger::{HwTrigger, TriggerState};
+#[cfg(feature = "zmq")]
+use crate::zmq;
+
+// Shows a warning about one line of input as `set verbose` says: in the log
+// file only at 0, on the console while recording at 1, always at 2. At 0
+// nothing is formatted unless the log file takes warnings, and those past
+// the per-second limit are only counted.
+macro_rules! warn_line {
+    ($recording:expr, $($arg:tt)*) => {
+        match settings::verbose() {
+            0 if $recording => {
+                if tracing::enabled!(tracing::Level::WARN) && crate::logging::admit_line_warning() {
+                    tracing::warn!($($arg)*);
+                }
+            }
+            0 => {}
+            1 if !$recording => {}
+            _ => esay!($($arg)*),
+        }
+    };
+}
+
+/// `--max-line-length` unless given: longer lines are dropped as they arrive.
+pub const DEFAULT_MAX_LINE_LENGTH: &str = "16384";
+
+/// Requests sent from the command loop to the serial thread. They are applied
+/// between reads, so a line is never split across two configurations.
+pub enum Control {
+    SetBaud(u32),
+    SetPort(String),
+    // Holds the line in the break condition for the given time
+    SendBreak(Duration),
+    // Bytes for the device, from --pty-writeback forward
+    Write(Vec<u8>),
+}
+
+/// When input arrived: taken as soon as the read that completed a line (or
+/// frame, or packet) returns, before any parsing or waiting for the writer,
+/// so a burst of lines keeps the times they came in at.
+#[derive(Debug, Clone, Copy)]
+pub struct Arrival {
+    pub wall: chrono::DateTime<chrono::Local>,
+    pub instant: Instant,
+}
+
+impl Arrival {
+    pub fn now() -> Arrival {
+        Arrival {
+            wall: crate::timesource::now(),
+            instant: Instant::now(),
+        }
+    }
+
+    /// The Timestamp column value, formatted as `get_timestamp` does.
+    pub fn timestamp(&self) -> String {
+        self.wall.format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+}
+
+/// Device path to open for a port name. On Windows, `COM10` and above only
+/// open through the `\\.\COM10` form, so `COM12`, `com12` and `\\.\COM12` all
+/// become `\\.\COM12`; anything else (and every name on other systems) is
+/// used as given.
+pub fn device_path(port_name: &str) -> String {
+    if cfg!(windows) {
+        normalize_com_name(port_name).unwrap_or_else(|| port_name.to_string())
+    } else {
+        port_name.to_string()
+    }
+}
+
+// `\\.\COM<n>` for COM port names with or without the device prefix
+fn normalize_com_name(port_name: &str) -> Option<String> {
+    let name = port_name.strip_prefix(r"\\.\").unwrap_or(port_name);
+    let number = name.get(..3).filter(|com| com.eq_ignore_ascii_case("com")).map(|_| &name[3..])?;
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!(r"\\.\COM{}", number))
+}
+
+/// How long the port may stay quiet before a binary frame nothing has
+/// followed is taken, unless `--read-timeout` says otherwise.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(100);
+// The longest a single read blocks, whatever --read-timeout says, so requests
+// from the prompt (baud, port, break), modem polling and failback are never
+// held up by a long timeout
+const READ_SLICE: Duration = Duration::from_millis(100);
+
+// Opens the port with reads blocking up to `timeout`; errors name both the
+// given and the opened device path when they differ
+fn open_port(port_name: &str, baud_rate: u32, timeout: Duration) -> Result<Box<dyn SerialPort>, String> {
+    let device = device_path(port_name);
+    serialport::new(&device, baud_rate)
+        .timeout(timeout)
+        .open()
+        .map_err(|e| {
+            if device == port_name {
+                format!("{}: {}", port_name, e)
+            } else {
+                format!("{} (opened as {}): {}", port_name, device, e)
+            }
+        })
+}
+
+/// How incoming lines are cleaned up, which are processed at all and how
+/// they are parsed.
+pub struct InputOptions {
+    pub format: LineFormat,
+    // Remove terminal escape sequences (on unless --keep-ansi)
+    pub strip_ansi: bool,
+    // Drop lines of only whitespace before matching (on unless
+    // --keep-blank-lines)
+    pub skip_blank_lines: bool,
+    // Lines ignored after the port is opened (--skip-lines)
+    pub skip_lines: u64,
+    // How long the port may stay quiet before a binary frame is taken at the
+    // end of the input (--read-timeout)
+    pub read_timeout: Duration,
+    // Lines are ignored until one contains this marker (--wait-for)
+    pub wait_for: Option<String>,
+    // Called when the marker arrives, to start recording (--auto-start)
+    pub auto_start: Option<Box<dyn Fn() + Send>>,
+    // Start and stop runs on command lines from the device (--device-commands)
+    pub device_commands: Option<DeviceCommands>,
+    // Start and stop runs on a modem control line (--hw-trigger)
+    pub hw_trigger: Option<HwTrigger>,
+    // Poll interval for --log-modem-lines
+    pub modem_interval: Option<Duration>,
+    // Write a break row for every break received while recording (--log-breaks)
+    pub log_breaks: bool,
+    // See every byte read from the port before lines are split (--hexdump)
+    pub observers: Vec<Box<dyn Observer>>,
+    // Write a gap row before a data row that comes longer than this after the
+    // previous one (--gap-marker)
+    pub gap_marker: Option<Duration>,
+    // Lines longer than this many bytes are dropped (--max-line-length)
+    pub max_line_length: usize,
+    // Where the row goes among the columns of a file that several ports
+    // share, with the port's label in front (see multiport.rs)
+    pub port: Option<Layout>,
+    // Waits for the next file before a row past a --rotate-align boundary
+    pub rotate: Option<Rotate>,
+}
+
+// A fresh gate for a newly opened port
+fn arm_gate(options: &InputOptions, shared: &Shared) -> Gate {
+    let gate = Gate::new(options.skip_lines, options.wait_for.as_deref());
+    shared.awaiting_sync.store(gate.waiting(), Ordering::Relaxed);
+    gate
+}
+
+/// Reads whatever the device sends for `duration`, for `--probe`. A read
+/// error ends the capture early with what was received so far.
+pub fn capture(port_name: &str, baud_rate: u32, duration: Duration) -> Result<Vec<u8>, String> {
+    let mut port = open_port(port_name, baud_rate, READ_SLICE)?;
+    let deadline = Instant::now() + duration;
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while Instant::now() < deadline {
+        match port.read(&mut chunk) {
+            Ok(n) => data.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => {
+                esay!("Error reading from serial port: {}", e);
+                break;
+            }
+        }
+    }
+    Ok(data)
+}
+
+/// Reads lines from the port until one is `wanted` and returns it, cleaned
+/// as the serial thread cleans lines; for `--fields auto`.
+pub fn first_line(port_name: &str, baud_rate: u32, strip_ansi: bool, delimiter: char, wanted: impl Fn(&str) -> bool) -> Result<String, String> {
+    let mut port = open_port(port_name, baud_rate, READ_SLICE)?;
+    let started = Instant::now();
+    let mut hinted = false;
+    let mut pending = Vec::new();
+    let mut chunk = [0u8; 1024];
+    loop {
+        match port.read(&mut chunk) {
+            Ok(n) => pending.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => return Err(format!("{}: {}", port_name, e)),
+        }
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let bytes: Vec<u8> = pending.drain(..=end).collect();
+            let line = parser::clean_line(&bytes, strip_ansi, delimiter);
+            if wanted(&line) {
+                return Ok(line);
+            }
+        }
+        if !hinted && started.elapsed() >= Duration::from_secs(10) {
+            esay!("Still waiting for a data line to detect the fields from; check --match and the port, or press Ctrl+C to quit.");
+            hinted = true;
+        }
+    }
+}
+
+/// The ports given with `--port`, most preferred first. A port that fails
+/// is reopened with a growing delay between attempts, so a device that was
+/// unplugged and comes back is read again; one that keeps failing for
+/// `FAILOVER_AFTER` is replaced by the next one that opens. With `failback`
+/// the first port is taken back as soon as it opens again.
+pub struct PortList {
+    pub ports: Vec<String>,
+    pub failback: bool,
+}
+
+// How long reads must keep failing before another port is tried
+const FAILOVER_AFTER: Duration = Duration::from_secs(3);
+// Delay before the first attempt to reopen a failing port; it doubles with
+// every failed attempt, up to RECONNECT_MAX
+pub const RECONNECT_FIRST: Duration = Duration::from_millis(100);
+pub const RECONNECT_MAX: Duration = Duration::from_secs(2);
+// How often a port that stays down is mentioned again
+pub const STILL_DOWN_EVERY: Duration = Duration::from_secs(30);
+// How often the first port is retried with --failback
+const FAILBACK_INTERVAL: Duration = Duration::from_secs(2);
+
+// Opens the first port of the list that can be opened
+fn open_first(ports: &[String], baud_rate: u32, timeout: Duration) -> Result<(String, Box<dyn SerialPort>), String> {
+    let mut errors = Vec::new();
+    for name in ports {
+        match open_port(name, baud_rate, timeout) {
+            Ok(port) => {
+                if !errors.is_empty() {
+                    esay!("Failed to open serial port {}; using {}.", errors.join("; "), name);
+                }
+                return Ok((name.clone(), port));
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    Err(errors.join("; "))
+}
+
+// The next port after `current` in the list (wrapping around) that opens
+fn open_backup(
+    ports: &[String],
+    current: &str,
+    baud_rate: u32,
+    timeout: Duration,
+) -> Option<(String, Box<dyn SerialPort>)> {
+    let start = ports.iter().position(|name| name == current).map_or(0, |i| i + 1);
+    ports
+        .iter()
+        .cycle()
+        .skip(start)
+        .take(ports.len())
+        .filter(|name| *name != current)
+        .find_map(|name| open_port(name, baud_rate, timeout).ok().map(|port| (name.clone(), port)))
+}
+
+// A failing port: since when, and when to try reopening it next
+struct Outage {
+    since: Instant,
+    next_attempt: Instant,
+    delay: Duration,
+    last_reported: Instant,
+}
+
+impl Outage {
+    fn new() -> Outage {
+        let now = Instant::now();
+        Outage {
+            since: now,
+            next_attempt: now + RECONNECT_FIRST,
+            delay: RECONNECT_FIRST,
+            last_reported: now,
+        }
+    }
+
+    // Whether an attempt to reopen is due; if so, the next one is scheduled
+    // after twice the delay
+    fn attempt_due(&mut self) -> bool {
+        let now = Instant::now();
+        if now < self.next_attempt {
+            return false;
+        }
+        self.delay = (self.delay * 2).min(RECONNECT_MAX);
+        self.next_attempt = now + self.delay;
+        true
+    }
+}
+
+// A port being read and the input received from it. Replaced as a whole when
+// another port is opened, so no partial line or state carries over.
+struct Link {
+    reader: BufReader<Tap>,
+    // Raw bytes of the line being received; decoded once it is complete
+    buffer: Vec<u8>,
+    // How much of the buffer the noise monitor has seen
+    fed: usize,
+    noise: NoiseMonitor,
+    gate: Gate,
+    modem: Option<ModemPoller>,
+    trigger: TriggerState,
+    // Splits the input into frames with --protocol binary (unless they come
+    // as COBS packets)
+    frames: Option<Decoder>,
+    // Bytes dropped so far of a line over --max-line-length, until its end
+    overflow: usize,
+}
+
+impl Link {
+    fn new(port: Box<dyn SerialPort>, options: &InputOptions, shared: &Shared, observers: Vec<Box<dyn Observer>>) -> Link {
+        Link {
+            reader: BufReader::new(Tap::new(port, observers)),
+            buffer: Vec::new(),
+            fed: 0,
+            noise: NoiseMonitor::default(),
+            gate: arm_gate(options, shared),
+            modem: options.modem_interval.map(ModemPoller::new),
+            trigger: TriggerState::default(),
+            frames: match options.format.framing {
+                Framing::Plain => options.format.frames.clone().map(Decoder::new),
+                Framing::Cobs => None,
+            },
+            overflow: 0,
+        }
+    }
+
+    // A link for a newly opened port, keeping this one's observers
+    fn reopen(&mut self, port: Box<dyn SerialPort>, options: &InputOptions, shared: &Shared) {
+        let observers = self.reader.get_mut().take_observers();
+        *self = Link::new(port, options, shared, observers);
+    }
+}
+
+// Spawn serial thread to handle incoming serial data
+pub fn spawn(
+    port_list: PortList,
+    baud_rate: u32,
+    shared: Arc<Shared>,
+    control: Receiver<Control>,
+    mut options: InputOptions,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut baud_rate = baud_rate;
+        let slice = options.read_timeout.min(READ_SLICE);
+
+        // Open the serial port
+        let (mut port_name, port) = open_first(&port_list.ports, baud_rate, slice)
+            .unwrap_or_else(|e| panic!("Failed to open serial port {}", e));
+        *shared.serial_port.lock().unwrap() = port_name.clone();
+        shared.capture(
+            &get_timestamp(),
+            Entry::Open {
+                port: port_name.clone(),
+                baud: baud_rate,
+            },
+        );
+        events::emit(Event::PortOpened {
+            port: port_name.clone(),
+            baud: baud_rate,
+        });
+
+        let observers = std::mem::take(&mut options.observers);
+        let mut link = Link::new(port, &options, &shared, observers);
+        // A trigger that can't be read would never fire
+        if let Some(trigger) = &options.hw_trigger {
+            if let Err(e) = trigger.line.read(link.reader.get_mut().port_mut()) {
+                panic!("--hw-trigger can't read {} on {}: {}", trigger.line.name(), port_name, e);
+            }
+        }
+        // Set after a read error until a read works again, so a disconnect
+        // runs --on-error and is reported once rather than on every retry
+        let mut failing: Option<Outage> = None;
+        // Set while a backup port is in use after a failover, for --failback
+        let mut failed_over = false;
+        let mut last_failback = Instant::now();
+        // When the latest bytes came in, for a binary frame taken at a timeout
+        let mut last_arrival = Arrival::now();
+
+        loop {
+            // Apply any reconfiguration requested from the command loop
+            while let Ok(request) = control.try_recv() {
+                let result = match request {
+                    Control::SendBreak(duration) => {
+                        match send_break(link.reader.get_mut().port_mut(), duration) {
+                            Ok(()) => say!("Sent a {} ms break.", duration.as_millis()),
+                            Err(e) => esay!("Failed to send break on {}: {}", port_name, e),
+                        }
+                        continue;
+                    }
+                    Control::Write(bytes) => {
+                        if let Err(e) = link.reader.get_mut().port_mut().write_all(&bytes) {
+                            esay!("Failed to forward bytes from --pty-mirror to {}: {}", port_name, e);
+                        }
+                        continue;
+                    }
+                    Control::SetBaud(rate) => link
+                        .reader
+                        .get_mut()
+                        .port_mut()
+                        .set_baud_rate(rate)
+                        .map(|()| {
+                            baud_rate = rate;
+                            shared.capture(&get_timestamp(), Entry::Baud(rate));
+                        })
+                        .map_err(|e| e.to_string()),
+                    Control::SetPort(name) => open_port(&name, baud_rate, slice).map(|port| {
+                        // Anything buffered from the old port is discarded with its reader
+                        link.reopen(port, &options, &shared);
+                        shared.capture(
+                            &get_timestamp(),
+                            Entry::Open {
+                                port: name.clone(),
+                                baud: baud_rate,
+                            },
+                        );
+                        port_name = name;
+                        failing = None;
+                        failed_over = false;
+                    }),
+                };
+                match result {
+                    Ok(()) => {
+                        // Judge the new settings afresh
+                        link.noise = NoiseMonitor::default();
+                        shared.line_noise.store(false, Ordering::Relaxed);
+                        *shared.serial_port.lock().unwrap() = port_name.clone();
+                        shared.serial_baud.store(baud_rate, Ordering::Relaxed);
+                        say!("Serial port reconfigured: {} at {} baud.", port_name, baud_rate);
+                        if shared.recording.load(Ordering::Acquire) {
+                            let timestamp = get_timestamp();
+                            let settings = format!("port {} baud {}", port_name, baud_rate);
+                            if let Some(w) = shared.writer.lock().unwrap().as_mut() {
+                                write_marker(w, Kind::Reconfig, &[&timestamp, &settings]);
+                            }
+                        }
+                        events::emit(Event::PortReconfigured {
+                            port: port_name.clone(),
+                            baud: baud_rate,
+                        });
+                    }
+                    Err(e) => esay!(
+                        "Failed to reconfigure serial port (still using {} at {} baud): {}",
+                        port_name, baud_rate, e
+                    ),
+                }
+            }
+
+            // Go back to the first port once it can be opened again
+            if port_list.failback && failed_over && last_failback.elapsed() >= FAILBACK_INTERVAL {
+                last_failback = Instant::now();
+                let primary = &port_list.ports[0];
+                if let Ok(port) = open_port(primary, baud_rate, slice) {
+                    link.reopen(port, &options, &shared);
+                    switch_port(&shared, &port_name, primary, baud_rate);
+                    port_name = primary.clone();
+                    failing = None;
+                    failed_over = false;
+                }
+            }
+
+            if let Some(modem) = link.modem.as_mut() {
+                modem.poll(link.reader.get_mut().port_mut(), &shared);
+            }
+            if let Some(trigger) = &options.hw_trigger {
+                link.trigger.poll(trigger, link.reader.get_mut().port_mut());
+            }
+
+            // Read a line (or, with binary frames, whatever has arrived; or a
+            // COBS packet) from the serial port
+            let result = match (&link.frames, options.format.framing) {
+                (Some(_), _) => read_available(&mut link.reader, &mut link.buffer),
+                (None, Framing::Plain) => link.reader.read_until(b'\n', &mut link.buffer),
+                (None, Framing::Cobs) => link.reader.read_until(0, &mut link.buffer),
+            };
+            let arrival = Arrival::now();
+            let received = &link.buffer[link.fed..];
+            if !received.is_empty() {
+                last_arrival = arrival;
+                shared.bytes_received.fetch_add(received.len() as u64, Ordering::Relaxed);
+                *shared.last_data.lock().unwrap() = Some(arrival.instant);
+            }
+            // Control characters and NULs are ordinary bytes in binary frames
+            // and COBS packets
+            if link.frames.is_none() && options.format.framing == Framing::Plain {
+                if let Some(noisy) = link.noise.feed(received) {
+                    report_noise(&shared, noisy, baud_rate);
+                }
+                count_breaks(received, &shared, options.log_breaks);
+            }
+            link.fed = link.buffer.len();
+            // A line longer than --max-line-length is dropped as it arrives,
+            // so input without line ends can't take all memory
+            let terminator = if options.format.framing == Framing::Cobs { 0 } else { b'\n' };
+            let ended = matches!(result, Ok(bytes_read) if bytes_read > 0) && link.buffer.ends_with(&[terminator]);
+            let read = match &result {
+                Ok(_) => true,
+                Err(e) => e.kind() == io::ErrorKind::TimedOut,
+            };
+            let length = link.buffer.len() - usize::from(ended);
+            if link.frames.is_none() && read && (link.overflow > 0 || length > options.max_line_length) {
+                link.overflow += length;
+                let dropped = std::mem::take(&mut link.buffer);
+                link.fed = 0;
+                {
+                    let _writer = shared.writer.lock().unwrap();
+                    shared.capture(&arrival.timestamp(), Entry::Rx(dropped));
+                }
+                if ended {
+                    overflowed(&shared, std::mem::take(&mut link.overflow), options.max_line_length);
+                }
+                continue;
+            }
+            match result {
+                Ok(bytes_read) => {
+                    if let Some(outage) = failing.take() {
+                        port_back(&shared, &port_name, baud_rate, outage.since.elapsed());
+                    }
+                    if bytes_read == 0 {
+                        // No data read; continue
+                        continue;
+                    }
+
+                    // The line goes to the raw capture before anything else;
+                    // under the writer lock, so it lands on the same side of a
+                    // start or stop marker as in the CSV
+                    let recording = {
+                        let _writer = shared.writer.lock().unwrap();
+                        shared.capture(&arrival.timestamp(), Entry::Rx(link.buffer.clone()));
+                        shared.recording.load(Ordering::Acquire)
+                    };
+                    if settings::echo() == Echo::Raw {
+                        crate::console::diagnostic(&format!("<< {}", link.buffer.escape_ascii()));
+                    }
+                    if let Some(decoder) = link.frames.as_mut() {
+                        decoder.feed(&link.buffer);
+                        link.buffer.clear();
+                        link.fed = 0;
+                        while let Some(decoded) = decoder.next_frame() {
+                            handle_frame(decoded, &shared, &options, &arrival, recording);
+                        }
+                        continue;
+                    }
+                    let mut line = std::mem::take(&mut link.buffer);
+                    link.fed = 0;
+                    if options.format.framing == Framing::Cobs {
+                        match cobs::unpack(&line) {
+                            None => continue,
+                            Some(Err(e)) => {
+                                reject_packet(&line, &e.to_string(), &shared, &arrival, recording);
+                                continue;
+                            }
+                            Some(Ok(packet)) => {
+                                if let Some(spec) = &options.format.frames {
+                                    match spec.decode_packet(&packet) {
+                                        Ok(values) => {
+                                            handle_frame(Decoded::Frame(values), &shared, &options, &arrival, recording)
+                                        }
+                                        Err(e) => reject_packet(&line, &e, &shared, &arrival, recording),
+                                    }
+                                    continue;
+                                }
+                                line = packet;
+                            }
+                        }
+                    }
+                    // A read up to a line feed may hold several lines (after
+                    // a lone CR) or none (a blank line's terminators)
+                    let lines: Vec<&[u8]> = match options.format.framing {
+                        Framing::Plain => parser::split_lines(&line).collect(),
+                        Framing::Cobs => vec![&line],
+                    };
+                    for line in lines {
+                        let data = parser::clean_line(line, options.strip_ansi, options.format.delimiter);
+                        if data.is_empty() && options.skip_blank_lines {
+                            shared.blank_lines.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+                        if settings::echo() == Echo::On {
+                            crate::console::diagnostic(&format!("< {}", data));
+                        }
+                        match link.gate.admit(&data) {
+                            Admit::Pass => {
+                                let command =
+                                    options.device_commands.as_ref().is_some_and(|commands| commands.handle(&data));
+                                if !command {
+                                    handle_line(&data, &shared, &options, &arrival, recording);
+                                }
+                            }
+                            Admit::Discard => skip_line(&data, &shared),
+                            Admit::Synced => {
+                                skip_line(&data, &shared);
+                                shared.awaiting_sync.store(false, Ordering::Relaxed);
+                                say!("Sync marker received; processing input.");
+                                if let Some(start) = &options.auto_start {
+                                    start();
+                                }
+                            }
+                        }
+                    }
+                }
+                // No complete line within the read; whatever arrived stays in
+                // the buffer and the next read continues the line. A binary
+                // frame that nothing has followed for --read-timeout is taken
+                // now.
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    // Waiting for data is working again
+                    if let Some(outage) = failing.take() {
+                        port_back(&shared, &port_name, baud_rate, outage.since.elapsed());
+                    }
+                    if last_arrival.instant.elapsed() < options.read_timeout {
+                        continue;
+                    }
+                    if let Some(decoder) = link.frames.as_mut() {
+                        let recording = shared.recording.load(Ordering::Acquire);
+                        while let Some(decoded) = decoder.flush() {
+                            handle_frame(decoded, &shared, &options, &last_arrival, recording);
+                        }
+                    }
+                }
+                Err(e) => {
+                    link.buffer.clear();
+                    link.fed = 0;
+                    shared.serial_errors.fetch_add(1, Ordering::Relaxed);
+                    let outage = match failing.as_mut() {
+                        Some(outage) => {
+                            if outage.last_reported.elapsed() >= STILL_DOWN_EVERY {
+                                outage.last_reported = Instant::now();
+                                esay!(
+                                    "Serial port {} still failing after {:.0}s, retrying: {}",
+                                    port_name,
+                                    outage.since.elapsed().as_secs_f64(),
+                                    e
+                                );
+                            }
+                            outage
+                        }
+                        None => {
+                            esay!("Error reading from serial port {}: {}; trying to reopen it.", port_name, e);
+                            shared.disconnects.fetch_add(1, Ordering::Relaxed);
+                            if shared.recording.load(Ordering::Relaxed) {
+                                shared.fail_strict(Check::Reconnect, || format!("serial port {} lost: {}", port_name, e));
+                            }
+                            run_error_hook(&shared, &port_name, &e.to_string());
+                            events::emit(Event::SerialError {
+                                port: port_name.clone(),
+                                error: e.to_string(),
+                            });
+                            failing.insert(Outage::new())
+                        }
+                    };
+                    if outage.attempt_due() {
+                        // The same port first: the device may be back
+                        if let Ok(port) = open_port(&port_name, baud_rate, slice) {
+                            link.reopen(port, &options, &shared);
+                            shared.capture(
+                                &get_timestamp(),
+                                Entry::Open {
+                                    port: port_name.clone(),
+                                    baud: baud_rate,
+                                },
+                            );
+                            continue;
+                        }
+                        // Switch to a backup port once the failure has lasted
+                        if outage.since.elapsed() >= FAILOVER_AFTER {
+                            if let Some((name, port)) = open_backup(&port_list.ports, &port_name, baud_rate, slice) {
+                                link.reopen(port, &options, &shared);
+                                switch_port(&shared, &port_name, &name, baud_rate);
+                                port_name = name;
+                                failing = None;
+                                failed_over = true;
+                                last_failback = Instant::now();
+                                continue;
+                            }
+                        }
+                    }
+                    // Don't spin on a port that has gone away
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        }
+    })
+}
+
+// Records a switch between the ports of the list: in the raw capture, as a
+// failover row while recording, and as an event
+fn switch_port(shared: &Shared, from: &str, to: &str, baud_rate: u32) {
+    let timestamp = get_timestamp();
+    shared.capture(
+        &timestamp,
+        Entry::Open {
+            port: to.to_string(),
+            baud: baud_rate,
+        },
+    );
+    *shared.serial_port.lock().unwrap() = to.to_string();
+    shared.port_switches.fetch_add(1, Ordering::Relaxed);
+    shared.line_noise.store(false, Ordering::Relaxed);
+    esay!("Serial port switched from {} to {}.", from, to);
+    if shared.recording.load(Ordering::Acquire) {
+        let ports = format!("from {} to {}", from, to);
+        if let Some(w) = shared.writer.lock().unwrap().as_mut() {
+            write_marker(w, Kind::Failover, &[&timestamp, &ports]);
+        }
+    }
+    events::emit(Event::PortFailover {
+        from: from.to_string(),
+        to: to.to_string(),
+        baud: baud_rate,
+    });
+}
+
+// Reports that reading the port works again, `down` after it started
+// failing, with a reconnect row while recording
+pub fn port_back(shared: &Shared, port_name: &str, baud_rate: u32, down: Duration) {
+    say!("Serial port {} is back after {:.1}s.", port_name, down.as_secs_f64());
+    if shared.recording.load(Ordering::Acquire) {
+        let back = format!("{} back after {:.1}s", port_name, down.as_secs_f64());
+        if let Some(w) = shared.writer.lock().unwrap().as_mut() {
+            write_marker(w, Kind::Reconnect, &[&get_timestamp(), &back]);
+        }
+    }
+    events::emit(Event::PortReconnected {
+        port: port_name.to_string(),
+        baud: baud_rate,
+        down_secs: down.as_secs_f64(),
+    });
+}
+
+// Reports a line of `length` bytes dropped for being longer than `limit`,
+// with an overflow row while recording
+pub fn overflowed(shared: &Shared, length: usize, limit: usize) {
+    shared.overflows.fetch_add(1, Ordering::Relaxed);
+    let recording = shared.recording.load(Ordering::Acquire);
+    if recording {
+        let dropped = format!("line of {} bytes dropped", length);
+        if let Some(w) = shared.writer.lock().unwrap().as_mut() {
+            write_marker(w, Kind::Overflow, &[&get_timestamp(), &dropped]);
+        }
+    }
+    warn_line!(recording, "Warning: Dropped a line of {} bytes, longer than --max-line-length {}.", length, limit);
+}
+
+// Sets the break condition, waits and clears it again
+fn send_break(port: &mut dyn SerialPort, duration: Duration) -> serialport::Result<()> {
+    port.set_break()?;
+    thread::sleep(duration);
+    port.clear_break()
+}
+
+// A received break reads as a NUL byte; counts them and, with --log-breaks,
+// writes a break row for each while recording
+fn count_breaks(bytes: &[u8], shared: &Shared, log: bool) {
+    let breaks = bytes.iter().filter(|b| **b == 0).count();
+    if breaks == 0 {
+        return;
+    }
+    shared.breaks.fetch_add(breaks as u64, Ordering::Relaxed);
+    if !log || !shared.recording.load(Ordering::Acquire) {
+        return;
+    }
+    let timestamp = get_timestamp();
+    if let Some(w) = shared.writer.lock().unwrap().as_mut() {
+        for _ in 0..breaks {
+            write_marker(w, Kind::Break, &[&timestamp, "break received"]);
+        }
+    }
+}
+
+// Warns once when input turns into what looks like line noise
+fn report_noise(shared: &Shared, noisy: bool, baud_rate: u32) {
+    shared.line_noise.store(noisy, Ordering::Relaxed);
+    if noisy {
+        esay!(
+            "Warning: Input looks like line noise - check the baud rate, currently {} (change it with 'baud <rate>').",
+            baud_rate
+        );
+    }
+}
+
+/// Runs --on-error, if given, for a serial read error or a failed --strict
+/// check.
+pub fn run_error_hook(shared: &Shared, port_name: &str, error: &str) {
+    let Some(command) = &shared.hooks.on_error else { return };
+    hooks::run(
+        "on-error",
+        command,
+        vec![
+            ("SL_RUN", shared.current_run.load(Ordering::Relaxed).to_string()),
+            ("SL_OUTPUT", shared.current_file.lock().unwrap().clone()),
+            ("SL_TIMESTAMP", get_timestamp()),
+            ("SL_PORT", port_name.to_string()),
+            ("SL_ERROR", error.to_string()),
+        ],
+    );
+}
+
+// Counts a line the gate discarded; it still shows up in `peek`
+pub fn skip_line(data: &str, shared: &Shared) {
+    shared.lines_read.fetch_add(1, Ordering::Relaxed);
+    shared.lines_filtered.fetch_add(1, Ordering::Relaxed);
+    shared.peek.lock().unwrap().raw_line(data);
+}
+
+// Parses data lines and writes them as data rows while recording
+pub fn handle_line(data: &str, shared: &Shared, options: &InputOptions, arrival: &Arrival, recording: bool) {
+    let format = &options.format;
+    shared.lines_read.fetch_add(1, Ordering::Relaxed);
+    shared.peek.lock().unwrap().raw_line(data);
+
+    // Process only lines containing the --match text
+    let parsed = format.parse(data);
+    if let Parsed::NoMatch = parsed {
+        shared.lines_filtered.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    shared.peek.lock().unwrap().matched_line(data);
+
+    match parsed {
+        Parsed::Fields(fields) => write_fields(fields, data, shared, options, arrival, recording),
+        Parsed::Decoded(values) => {
+            let fields = values.iter().map(String::as_str).collect();
+            write_fields(fields, data, shared, options, arrival, recording);
+        }
+        Parsed::WrongCount { payload, got } => {
+            preview::note(&shared.preview, || {
+                (Vec::new(), Some(format!("expected {} fields, got {}: {}", format.fields, got, payload)))
+            });
+            shared.reject(Reason::FieldCount, &arrival.timestamp(), data, recording);
+            warn_line!(
+                recording,
+                "Warning: Unexpected number of fields (expected {}, got {}). Data: {}",
+                format.fields,
+                got,
+                payload
+            );
+        }
+        Parsed::BadPayload { payload, error } => {
+            preview::note(&shared.preview, || (Vec::new(), Some(format!("payload didn't decode ({}): {}", error, payload))));
+            shared.reject(Reason::BadPayload, &arrival.timestamp(), data, recording);
+            warn_line!(recording, "Warning: Failed to decode payload ({}). Data: {}", error, payload);
+        }
+        Parsed::NoMatch => {}
+    }
+}
+
+// Writes a decoded frame as a data row while recording, or counts a bad one
+fn handle_frame(decoded: Decoded, shared: &Shared, options: &InputOptions, arrival: &Arrival, recording: bool) {
+    match decoded {
+        Decoded::Frame(values) => {
+            shared.lines_read.fetch_add(1, Ordering::Relaxed);
+            let text = values.join(",");
+            if settings::echo() == Echo::On {
+                crate::console::diagnostic(&format!("< {}", text));
+            }
+            {
+                let mut peek = shared.peek.lock().unwrap();
+                peek.raw_line(&text);
+                peek.matched_line(&text);
+            }
+            let fields = options.format.select_fields(values.iter().map(String::as_str).collect());
+            write_fields(fields, &text, shared, options, arrival, recording);
+        }
+        Decoded::Bad(bytes) => {
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            shared.reject(Reason::BadFrame, &arrival.timestamp(), &hex.join(" "), recording);
+            warn_line!(recording, "Warning: Bad binary frame; skipped {} bytes to the next sync word.", bytes.len());
+        }
+    }
+}
+
+// Counts a COBS packet that didn't decode (or, with binary frames, didn't
+// hold a frame) as a bad frame
+fn reject_packet(received: &[u8], why: &str, shared: &Shared, arrival: &Arrival, recording: bool) {
+    let received = received.strip_suffix(&[0]).unwrap_or(received);
+    let hex: Vec<String> = received.iter().map(|b| format!("{:02x}", b)).collect();
+    shared.reject(Reason::BadFrame, &arrival.timestamp(), &hex.join(" "), recording);
+    warn_line!(recording, "Warning: Bad COBS packet ({}); dropped {} bytes.", why, received.len());
+}
+
+// Writes the parsed fields of `data` as a data row, with its computed
+// columns, alarms and gap marker
+fn write_fields(
+    fields: Vec<&str>,
+    data: &str,
+    shared: &Shared,
+    options: &InputOptions,
+    arrival: &Arrival,
+    recording: bool,
+) {
+    let format = &options.format;
+    let timestamp = &arrival.timestamp();
+    shared.peek.lock().unwrap().parsed_record(&fields);
+    let typed = match format.coerce(&fields) {
+        Ok(typed) => typed,
+        Err(e) => {
+            preview::note(&shared.preview, || {
+                (fields.iter().map(|field| field.to_string()).collect(), Some(format!("doesn't match --types: {}", e)))
+            });
+            shared.reject(Reason::WrongType, timestamp, data, recording);
+            warn_line!(recording, "Warning: Row doesn't match --types: {}. Data: {}", e, data);
+            return;
+        }
+    };
+    let fields: Vec<&str> = typed.iter().map(|field| field.as_ref()).collect();
+    preview::note(&shared.preview, || {
+        let (computed, failed) = session::computed_cells(&format.compute(&fields), format.float_format);
+        let cells = row_cells(options, &fields, &computed).into_iter().map(str::to_string).collect();
+        (cells, (failed > 0).then(|| format!("{} computed columns couldn't be evaluated", failed)))
+    });
+    if !recording {
+        if let Some(prebuffer) = &shared.prebuffer {
+            let (computed, _) = session::computed_cells(&format.compute(&fields), format.float_format);
+            let record = session::data_record(timestamp, &row_cells(options, &fields, &computed), &[], &shared.row_constants);
+            prebuffer.lock().unwrap().push(arrival.instant, &record);
+        }
+        return;
+    }
+    // Paused rows are left out before any filter or statistic sees them
+    if shared.paused.load(Ordering::Relaxed) {
+        shared.paused_rows.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    if let Some(dedup) = &shared.dedup {
+        if dedup.lock().unwrap().is_duplicate(&fields) {
+            shared.duplicates.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+    if let Some(change) = &shared.change {
+        if change.lock().unwrap().is_unchanged(&fields, arrival.instant) {
+            shared.unchanged.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+    let results = format.compute(&fields);
+    let (computed, failed) = session::computed_cells(&results, format.float_format);
+    if failed > 0 {
+        shared.compute_errors.fetch_add(failed, Ordering::Relaxed);
+    }
+    let values = format.values(&fields);
+    let mut numbers = values.clone();
+    numbers.extend(results);
+    if let Some(layout) = &options.port {
+        numbers = layout.place(None, None, numbers);
+    }
+    shared.run_stats.lock().unwrap().add_row(&numbers);
+    let record = session::data_record(timestamp, &row_cells(options, &fields, &computed), &[], &shared.row_constants);
+    // The first row at or past a --rotate-align boundary is the next file's first
+    if arrival.wall.timestamp_millis() >= shared.next_rotation.load(Ordering::Acquire) {
+        if let Some(rotate) = &options.rotate {
+            rotate.wait_for_switch(arrival.wall, shared);
+        }
+    }
+
+    // Write the record to CSV
+    let mut guard = shared.writer.lock().unwrap();
+    let Some(w) = guard.as_mut() else { return };
+    // Paused while waiting for the writer: the pause row is already written
+    if shared.paused.load(Ordering::Relaxed) {
+        shared.paused_rows.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    if w.refuses(&record) {
+        drop(guard);
+        shared.reject(Reason::NeedsQuoting, timestamp, data, recording);
+        warn_line!(recording, "Warning: Not writing a row that would need quoting (--quote-style never). Data: {}", data);
+        return;
+    }
+    // Gaps are between arrivals, not between writes
+    let now = arrival.instant;
+    if let Some(clock) = &shared.clock {
+        if let Some(step) = clock.lock().unwrap().check(arrival.wall, now) {
+            let step = clockstep::describe(step);
+            write_marker(w, Kind::ClockStep, &[timestamp, &step]);
+            esay!("Warning: The system clock was stepped by {} since the previous data row.", step);
+        }
+    }
+    if let Some(threshold) = options.gap_marker {
+        mark_gap(shared, w, timestamp, now, threshold);
+    }
+    let written = match w.write_record(&record) {
+        Ok(()) => {
+            *shared.last_record.lock().unwrap() = Some(now);
+            shared.run_rows.fetch_add(1, Ordering::Relaxed);
+            if let Some(xlsx) = &shared.xlsx {
+                xlsx.lock().unwrap().push(&record);
+            }
+            true
+        }
+        Err(e) => {
+            esay!("Failed to write data record to CSV: {}", e);
+            false
+        }
+    };
+    let raised = raise_alarms(shared, w, timestamp, &fields, &values, &computed);
+    let flushed = if raised.is_empty() { w.flush() } else { w.flush_marker() };
+    if let Err(e) = flushed {
+        esay!("Failed to flush CSV writer: {}", e);
+    }
+    if written {
+        benchmark::record_latency(arrival.instant);
+        // Counted once the row is out, so --benchmark's count and latencies agree
+        shared.rows_written.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "zmq")]
+        zmq::publish_record(&record);
+        grpc::publish_record(&record, shared.current_run.load(Ordering::Relaxed));
+    }
+    let full = w.is_full();
+    drop(guard);
+    let run = shared.current_run.load(Ordering::Relaxed);
+    for (label, condition) in raised {
+        esay!("Alarm {} in run {}: {}", label, run, condition);
+        events::emit(Event::Alarm {
+            run,
+            label,
+            condition,
+            values: fields.iter().map(|field| field.to_string()).collect(),
+        });
+    }
+    if full {
+        stop_for_full_disk(shared);
+    }
+}
+
+// The payload and computed cells of a row, in the file's columns
+fn row_cells<'a>(options: &'a InputOptions, fields: &[&'a str], computed: &'a [String]) -> Vec<&'a str> {
+    let cells = fields.iter().copied().chain(computed.iter().map(String::as_str)).collect();
+    match &options.port {
+        Some(layout) => layout.place(layout.label(), "", cells),
+        None => cells,
+    }
+}
+
+// Appends whatever the reader has (waiting up to the read timeout) to `buffer`
+fn read_available(reader: &mut impl BufRead, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    let available = reader.fill_buf()?;
+    let count = available.len();
+    buffer.extend_from_slice(available);
+    reader.consume(count);
+    Ok(count)
+}
+
+// Writes a gap row if the previous data row of the run arrived more than
+// `threshold` before `now`
+fn mark_gap(shared: &Shared, w: &mut CsvSink, timestamp: &str, now: Instant, threshold: Duration) {
+    let Some(last) = *shared.last_record.lock().unwrap() else { return };
+    let gap = now.duration_since(last);
+    if gap > threshold {
+        write_marker(w, Kind::Gap, &[timestamp, &format!("{:.3}s", gap.as_secs_f64())]);
+    }
+}
+
+// Checks the alarms against a data row just written and writes an alarm row
+// after it for each one the row raised; returns their labels and conditions
+fn raise_alarms(
+    shared: &Shared,
+    w: &mut CsvSink,
+    timestamp: &str,
+    fields: &[&str],
+    values: &[Option<f64>],
+    computed: &[String],
+) -> Vec<(String, String)> {
+    let mut alarms = shared.alarms.lock().unwrap();
+    if alarms.is_empty() {
+        return Vec::new();
+    }
+    let mut raised = Vec::new();
+    for alarm in alarms.iter_mut() {
+        if !alarm.check(values) {
+            continue;
+        }
+        let mut record = vec![Kind::Alarm.as_str(), timestamp, &alarm.label, ""];
+        record.extend(fields);
+        record.extend(computed.iter().map(String::as_str));
+        if let Err(e) = w.write_record(&record) {
+            esay!("Failed to write alarm record to CSV: {}", e);
+        }
+        raised.push((alarm.label.clone(), alarm.condition_text.clone()));
+    }
+    raised
+}
+
+// Stops recording after a write failed because the disk is full. The command
+// loop finishes the run's bookkeeping; nothing more is written until an
+// explicit start.
+fn stop_for_full_disk(shared: &Shared) {
+    shared.recording.store(false, Ordering::Release);
+    if shared.disk_full.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let file = shared.current_file.lock().unwrap().clone();
+    esay!(
+        "ERROR: Output disk is full while writing {}. Recording stopped; data written so far is intact. \
+         Free some space and type 'start' to resume.",
+        file
+    );
+    events::emit(Event::DiskFull {
+        run: shared.current_run.load(Ordering::Relaxed),
+        file,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn com_ports_get_the_device_prefix() {
+        for n in 1..=256 {
+            let expected = format!(r"\\.\COM{}", n);
+            for name in [format!("COM{}", n), format!("com{}", n), format!("Com{}", n), expected.clone()] {
+                assert_eq!(normalize_com_name(&name), Some(expected.clone()), "{}", name);
+            }
+        }
+        assert_eq!(normalize_com_name(r"\\.\com12").as_deref(), Some(r"\\.\COM12"));
+    }
+
+    #[test]
+    fn other_names_are_left_alone() {
+        for name in ["/dev/ttyUSB0", "COM", "COMX", "COM1a", "COM-1", r"\\.\", r"\\.\COM", "LPT1", "", "COM 3"] {
+            assert_eq!(normalize_com_name(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn unix_paths_open_as_given() {
+        if !cfg!(windows) {
+            assert_eq!(device_path("/dev/ttyUSB0"), "/dev/ttyUSB0");
+            assert_eq!(device_path("COM12"), "COM12");
+        } else {
+            assert_eq!(device_path("COM12"), r"\\.\COM12");
+        }
+    }
+}
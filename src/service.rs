@@ -0,0 +1,240 @@
+//! Running as a Windows service: `service install`, `service uninstall` and
+//! `--service DIR`.
+//!
+//! `service install -- OPTIONS` registers the program with the service
+//! manager to start at boot as `--service DIR OPTIONS`, DIR being the
+//! directory it was installed from, so relative paths in OPTIONS mean what
+//! they meant then. Started that way the logger runs in DIR with no console:
+//! messages go to `--log-file` (`serial_logger.log` unless given) and commands
+//! come from `--control-file` (`control.txt` unless given) or
+//! `--grpc-listen`. Stopping the service, or shutting Windows down, ends the
+//! session like `exit`, stop rows and all, and the exit code is reported to
+//! the service manager. Needs a Windows build with the `service` feature.
+
+use std::{ffi::OsString, path::Path};
+
+/// `--log-file` of a service that wasn't given one.
+pub const DEFAULT_LOG: &str = "serial_logger.log";
+/// `--control-file` of a service that wasn't given one.
+pub const DEFAULT_CONTROL: &str = "control.txt";
+
+#[cfg(all(windows, feature = "service"))]
+mod scm {
+    use std::{
+        ffi::OsString,
+        path::Path,
+        sync::{
+            mpsc::{self, Receiver},
+            Mutex, OnceLock,
+        },
+        time::Duration,
+    };
+
+    use windows_service::{
+        define_windows_service,
+        service::{
+            ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo,
+            ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+        },
+        service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+        service_dispatcher,
+        service_manager::{ServiceManager, ServiceManagerAccess},
+    };
+
+    // The service manager starts an own-process service whatever name it is
+    // given here; the installed name only matters to install and uninstall
+    const NAME: &str = "SerialLogger";
+    // How long the service manager is told a stop may take: ending the
+    // session waits for hooks, notifications, compression and uploads
+    const STOP_WAIT: Duration = Duration::from_secs(60);
+
+    static BODY: Mutex<Option<Box<dyn FnOnce() + Send>>> = Mutex::new(None);
+    static STATUS: OnceLock<ServiceStatusHandle> = OnceLock::new();
+    static STOP: Mutex<Option<Receiver<()>>> = Mutex::new(None);
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    pub fn run(dir: &Path, body: Box<dyn FnOnce() + Send>) -> Result<(), String> {
+        std::env::set_current_dir(dir).map_err(|e| format!("Failed to change to {}: {}", dir.display(), e))?;
+        *BODY.lock().unwrap() = Some(body);
+        service_dispatcher::start(NAME, ffi_service_main).map_err(|e| {
+            format!("Failed to run as a service ({}); --service is for the service manager, see 'service install'", e)
+        })
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let (stop, stop_rx) = mpsc::channel();
+        *STOP.lock().unwrap() = Some(stop_rx);
+        let handler = move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = stop.send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        match service_control_handler::register(NAME, handler) {
+            Ok(handle) => {
+                let _ = STATUS.set(handle);
+            }
+            Err(e) => {
+                esay!("ERROR: Failed to register with the service manager: {}", e);
+                return;
+            }
+        }
+        set_status(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            ServiceExitCode::Win32(0),
+            Duration::ZERO,
+        );
+        let body = BODY.lock().unwrap().take();
+        if let Some(body) = body {
+            body();
+        }
+        // Logging ends the program itself; this is for when it returns
+        stopped(0);
+    }
+
+    fn set_status(state: ServiceState, accepted: ServiceControlAccept, exit_code: ServiceExitCode, wait_hint: Duration) {
+        let Some(handle) = STATUS.get() else { return };
+        let status = ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint,
+            process_id: None,
+        };
+        if let Err(e) = handle.set_service_status(status) {
+            esay!("Failed to report the service status: {}", e);
+        }
+    }
+
+    pub fn running() -> bool {
+        STATUS.get().is_some()
+    }
+
+    pub fn wait_for_stop() {
+        let stop = STOP.lock().unwrap().take();
+        if let Some(stop) = stop {
+            let _ = stop.recv();
+        }
+        set_status(ServiceState::StopPending, ServiceControlAccept::empty(), ServiceExitCode::Win32(0), STOP_WAIT);
+    }
+
+    pub fn stopped(exit_code: i32) {
+        let exit_code = match exit_code {
+            0 => ServiceExitCode::Win32(0),
+            code => ServiceExitCode::ServiceSpecific(code as u32),
+        };
+        set_status(ServiceState::Stopped, ServiceControlAccept::empty(), exit_code, Duration::ZERO);
+    }
+
+    pub fn install(name: &str, arguments: Vec<OsString>) -> Result<(), String> {
+        let exe = std::env::current_exe().map_err(|e| format!("Failed to find this program to install it: {}", e))?;
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)
+            .map_err(|e| format!("Failed to connect to the service manager (installing needs an administrator): {}", e))?;
+        let info = ServiceInfo {
+            name: OsString::from(name),
+            display_name: OsString::from(format!("Serial logger ({})", name)),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: exe,
+            launch_arguments: arguments,
+            dependencies: Vec::new(),
+            // The LocalSystem account
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager
+            .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+            .map_err(|e| format!("Failed to install service {}: {}", name, e))?;
+        if let Err(e) = service.set_description("Records serial data to CSV") {
+            esay!("Warning: Failed to set the description of service {}: {}", name, e);
+        }
+        Ok(())
+    }
+
+    pub fn uninstall(name: &str) -> Result<(), String> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .map_err(|e| format!("Failed to connect to the service manager (uninstalling needs an administrator): {}", e))?;
+        let service = manager
+            .open_service(name, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)
+            .map_err(|e| format!("Failed to open service {}: {}", name, e))?;
+        // It goes once it has stopped
+        service.delete().map_err(|e| format!("Failed to uninstall service {}: {}", name, e))?;
+        let status = service.query_status().map_err(|e| format!("Failed to query service {}: {}", name, e))?;
+        if status.current_state != ServiceState::Stopped {
+            service.stop().map_err(|e| format!("Failed to stop service {}: {}", name, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(all(windows, feature = "service")))]
+mod scm {
+    use std::{ffi::OsString, path::Path};
+
+    fn unavailable() -> String {
+        "Running as a service needs a Windows build with the 'service' feature".to_string()
+    }
+
+    pub fn run(_dir: &Path, _body: Box<dyn FnOnce() + Send>) -> Result<(), String> {
+        Err(unavailable())
+    }
+
+    pub fn running() -> bool {
+        false
+    }
+
+    pub fn wait_for_stop() {}
+
+    pub fn stopped(_exit_code: i32) {}
+
+    pub fn install(_name: &str, _arguments: Vec<OsString>) -> Result<(), String> {
+        Err(unavailable())
+    }
+
+    pub fn uninstall(_name: &str) -> Result<(), String> {
+        Err(unavailable())
+    }
+}
+
+/// Runs `body`, the logger, as the service the service manager is starting,
+/// in `dir`.
+pub fn run(dir: &Path, body: Box<dyn FnOnce() + Send>) -> Result<(), String> {
+    scm::run(dir, body)
+}
+
+/// Whether the program runs as a service.
+pub fn running() -> bool {
+    scm::running()
+}
+
+/// Waits for the service manager to stop the service.
+pub fn wait_for_stop() {
+    scm::wait_for_stop()
+}
+
+/// Tells the service manager the service has stopped with `exit_code`,
+/// right before the program exits; nothing unless running as a service.
+pub fn stopped(exit_code: i32) {
+    scm::stopped(exit_code)
+}
+
+/// Registers the program as service `name`, started at boot with `options`
+/// in the current directory.
+pub fn install(name: &str, options: Vec<OsString>) -> Result<(), String> {
+    let dir = std::env::current_dir().map_err(|e| format!("Failed to find the current directory: {}", e))?;
+    let mut arguments = vec![OsString::from("--service"), dir.into_os_string()];
+    arguments.extend(options);
+    scm::install(name, arguments)
+}
+
+/// Removes service `name`, stopping it if it runs.
+pub fn uninstall(name: &str) -> Result<(), String> {
+    scm::uninstall(name)
+}
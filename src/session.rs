@@ -0,0 +1,1593 @@
+use collect_acc_data::arrow::{self, DataType, StreamWriter};
+#[cfg(feature = "hdf5")]
+use collect_acc_data::hdf5;
+use collect_acc_data::parquet::FileWriter;
+use collect_acc_data::logfile::{Kind, LEADING_HEADERS};
+use collect_acc_data::parser;
+use collect_acc_data::raw::Entry;
+use collect_acc_data::schema::FloatFormat;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::alarm::{Alarm, AlarmCount};
+use crate::caps::Cap;
+use crate::compress;
+use crate::change::{self, ChangeFilter};
+use crate::clockstep::ClockWatch;
+use crate::dedup::Dedup;
+use crate::events::{self, Event};
+use crate::holdoff::{self, Guard, Policy};
+use crate::hooks::{self, Hooks};
+use crate::manifest::Manifest;
+use crate::meta::{MetaStyle, Metadata};
+use crate::modem::ModemLine;
+use crate::outage::{self, Recovery, Tracker};
+use crate::peek::Peek;
+use crate::prebuffer::{Limit, PreBuffer};
+use crate::preview::Preview;
+use crate::rawlog::RawLog;
+use crate::rejects::{self, Reason};
+use crate::rotate::{self, Align};
+use crate::retention::{same_file, Retention};
+use crate::sidecar::{self, RunMetadata};
+use crate::sink::{Columnar, ColumnarOutput, CsvDialect, CsvSink, QuoteStyle, SyncPolicy, Target};
+use crate::state;
+use crate::strict::Check;
+use crate::stats::RunStats;
+use crate::summary::{RunSummary, SessionSummary};
+use crate::tail::Tail;
+use crate::get_timestamp;
+use crate::template::{self, Context};
+use crate::upload;
+use crate::xlsx;
+
+/// A data row: the payload fields, the computed columns and the constant
+/// columns.
+pub fn data_record<'a>(
+    timestamp: &'a str,
+    fields: &[&'a str],
+    computed: &'a [String],
+    constants: &'a [String],
+) -> Vec<&'a str> {
+    let mut record = vec![Kind::Data.as_str(), timestamp, "", ""];
+    record.extend(fields);
+    record.extend(computed.iter().map(String::as_str));
+    record.extend(constants.iter().map(String::as_str));
+    record
+}
+
+/// Cells of the computed columns, blank where the expression failed, and
+/// how many failed.
+pub fn computed_cells(values: &[Option<f64>], float_format: FloatFormat) -> (Vec<String>, u64) {
+    let failed = values.iter().filter(|value| value.is_none()).count() as u64;
+    let cells = values.iter().map(|value| parser::computed_cell(*value, float_format)).collect();
+    (cells, failed)
+}
+
+// Creates the output file (and any missing directories) and writes the
+// metadata and the CSV header. An existing file is started afresh with
+// `replace`, and otherwise left alone with an error.
+pub fn open_output(path: &str, settings: &OutputSettings, replace: bool) -> Result<CsvSink, String> {
+    let file = if settings.dry_run {
+        None
+    } else {
+        if let Some(parent) = PathBuf::from(path).parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| format!("Failed to create output directory: {}", e))?;
+            }
+        }
+        let file = if replace { File::create(path) } else { File::create_new(path) };
+        Some(file.map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => format!("{} already exists; not overwriting it", path),
+            _ => format!("Failed to create CSV file at {}: {}", path, e),
+        })?)
+    };
+    let mut headers: Vec<&str> = LEADING_HEADERS.to_vec();
+    headers.extend(settings.columns.iter().map(String::as_str));
+    headers.extend(settings.id_columns.iter().map(|(name, _)| name.as_str()));
+    let mut sink = match (file, &settings.columnar) {
+        // A dry run shows CSV rows whatever the format
+        (None, _) => open_csv(path, Target::dry_run(path), &headers, settings)?,
+        (Some(file), Some(output)) => open_columnar(path, file, &headers, output, settings)?,
+        // A CSV file rides out repeated write errors (see outage.rs)
+        (Some(file), None) => {
+            let cut = Arc::new(AtomicBool::new(false));
+            let mut sink = open_csv(path, Target::Tracked(file, Arc::clone(&cut)), &headers, settings)?;
+            sink.track(Tracker::new(path, &headers, settings.dialect, settings.recovery.clone(), cut));
+            sink
+        }
+    };
+    if let Some(tail) = &settings.tail {
+        tail.lock().unwrap().set_columns(&headers);
+        sink.keep_tail(Arc::clone(tail));
+    }
+    Ok(sink)
+}
+
+// Writes the metadata and the header of a CSV file
+fn open_csv(path: &str, mut csv_file: Target, headers: &[&str], settings: &OutputSettings) -> Result<CsvSink, String> {
+    let meta = &settings.meta;
+    meta.write_comments(&mut csv_file, settings.dialect.line_end())
+        .map_err(|e| format!("Failed to write metadata to {}: {}", path, e))?;
+    // Flexible so that marker rows can be wider than a narrow payload
+    let mut writer = settings.dialect.builder().flexible(true).from_writer(csv_file);
+    let unquoted = settings.dialect.quote == QuoteStyle::Never;
+    for row in meta.rows(headers.len(), unquoted) {
+        writer
+            .write_record(&row)
+            .map_err(|e| format!("Failed to write metadata to {}: {}", path, e))?;
+    }
+    writer
+        .write_record(headers)
+        .map_err(|e| format!("Failed to write CSV headers: {}", e))?;
+    if !settings.units.is_empty() {
+        writer
+            .write_record(units_row(headers, &settings.units))
+            .map_err(|e| format!("Failed to write CSV headers: {}", e))?;
+    }
+    let mut sink = CsvSink::new(writer, headers.len(), settings.dialect, settings.sync);
+    sink.flush().map_err(|e| format!("Failed to flush CSV writer: {}", e))?;
+    Ok(sink)
+}
+
+// The row after the header: `units` in the Type column, then each column's
+// unit, blank for those without one
+fn units_row<'a>(headers: &[&str], units: &'a [(String, String)]) -> Vec<&'a str> {
+    let mut row = vec![Kind::Units.as_str()];
+    row.resize(LEADING_HEADERS.len(), "");
+    row.extend(headers[LEADING_HEADERS.len()..].iter().map(|column| {
+        units.iter().find(|(name, _)| name == column).map_or("", |(_, unit)| unit.as_str())
+    }));
+    row
+}
+
+// Starts an Arrow stream, Parquet file or HDF5 file whose schema has the CSV
+// header's columns; the metadata goes in the schema, footer or attributes
+// rather than in rows, and the units as a JSON object under `units`
+fn open_columnar(
+    path: &str,
+    file: File,
+    headers: &[&str],
+    output: &ColumnarOutput,
+    settings: &OutputSettings,
+) -> Result<CsvSink, String> {
+    let types = [DataType::Utf8, DataType::TimestampMs, DataType::Utf8, DataType::Utf8]
+        .into_iter()
+        .chain(output.column_types.iter().copied())
+        .chain(settings.id_columns.iter().map(|_| DataType::Utf8));
+    let fields = headers
+        .iter()
+        .zip(types)
+        .map(|(name, data_type)| arrow::Field {
+            name: name.to_string(),
+            data_type,
+        })
+        .collect();
+    let mut metadata = match settings.meta.style {
+        MetaStyle::Off => Vec::new(),
+        _ => settings.meta.entries.clone(),
+    };
+    if !settings.units.is_empty() {
+        let units: serde_json::Map<String, serde_json::Value> =
+            settings.units.iter().map(|(name, unit)| (name.clone(), unit.as_str().into())).collect();
+        metadata.push(("units".to_string(), serde_json::Value::Object(units).to_string()));
+    }
+    let metadata = &metadata[..];
+    match output.format {
+        Columnar::Arrow => {
+            let writer = StreamWriter::new(Target::File(file), fields, metadata, output.batch_rows)
+                .map_err(|e| format!("Failed to write the Arrow schema to {}: {}", path, e))?;
+            Ok(CsvSink::arrow(writer, settings.sync))
+        }
+        Columnar::Parquet => {
+            let writer = FileWriter::new(path, file, fields, metadata, output.batch_rows)
+                .map_err(|e| format!("Failed to start the Parquet file {}: {}", path, e))?;
+            Ok(CsvSink::parquet(writer, settings.sync))
+        }
+        #[cfg(feature = "hdf5")]
+        Columnar::Hdf5 => {
+            let fields = settings
+                .columns
+                .iter()
+                .zip(&output.number_types)
+                .map(|(name, number_type)| hdf5::Field {
+                    name: name.clone(),
+                    number_type: *number_type,
+                })
+                .collect();
+            let writer = hdf5::FileWriter::new(Target::File(file), fields, metadata, output.batch_rows)
+                .map_err(|e| format!("Failed to start the HDF5 file {}: {}", path, e))?;
+            Ok(CsvSink::hdf5(writer, headers.len(), settings.sync))
+        }
+    }
+}
+
+/// State shared between the command loop and the serial thread.
+pub struct Shared {
+    pub recording: AtomicBool,
+    // Always open while recording; with --per-run it is closed between runs
+    pub writer: Mutex<Option<CsvSink>>,
+    // Number of the active (or most recent) run and its data rows
+    pub current_run: AtomicI64,
+    pub run_rows: AtomicU64,
+    // Latest lines seen by the serial thread, for `peek`
+    pub peek: Mutex<Peek>,
+    // Per-field statistics and rejected lines for the current run
+    pub run_stats: Mutex<RunStats>,
+    pub run_rejected: AtomicU64,
+    // Serial settings currently in use, kept up to date by the serial thread
+    pub serial_port: Mutex<String>,
+    pub serial_baud: AtomicU32,
+    // Session-wide counters
+    pub lines_read: AtomicU64,
+    pub lines_filtered: AtomicU64,
+    // Lines of only whitespace dropped before matching
+    pub blank_lines: AtomicU64,
+    pub parse_failures: AtomicU64,
+    pub serial_errors: AtomicU64,
+    // Outages of the serial port; each may span many serial errors
+    pub disconnects: AtomicU64,
+    // Switches to another port of the --port list (failover and failback)
+    pub port_switches: AtomicU64,
+    // Bytes received from the port, data rows written and when input last
+    // arrived (milliseconds since the epoch, 0 before any), for the status line
+    pub bytes_received: AtomicU64,
+    pub rows_written: AtomicU64,
+    // When input last arrived (monotonic), for the status line and metrics
+    pub last_data: Mutex<Option<Instant>>,
+    // When the run's latest data row arrived (monotonic), for --gap-marker
+    pub last_record: Mutex<Option<Instant>>,
+    // Break conditions received (read as NUL bytes)
+    pub breaks: AtomicU64,
+    // Data rows refused because they would need quoting (--quote-style never)
+    pub unquotable_rows: AtomicU64,
+    // Computed cells left blank because the expression couldn't be evaluated
+    pub compute_errors: AtomicU64,
+    // Damaged or garbled binary frames (--protocol binary)
+    pub bad_frames: AtomicU64,
+    // Hex or base64 payloads that didn't decode (--payload-encoding)
+    pub bad_payloads: AtomicU64,
+    // Rows with a field that didn't fit its --types type
+    pub type_errors: AtomicU64,
+    // File currently being written, for messages from other threads
+    pub current_file: Mutex<String>,
+    // Set when a write failed because the disk is full; recording has been stopped
+    pub disk_full: AtomicBool,
+    // Free space is below --min-free
+    pub low_disk: AtomicBool,
+    // Input is being discarded until the --wait-for marker arrives
+    pub awaiting_sync: AtomicBool,
+    // Recent input looks like line noise, probably a wrong baud rate
+    pub line_noise: AtomicBool,
+    // External commands from --on-start, --on-stop and --on-error
+    pub hooks: Hooks,
+    // Values of the constant columns appended to every data row
+    pub row_constants: Vec<String>,
+    // Write-ahead capture of the input, unless --no-raw
+    pub raw: Mutex<Option<RawLog>>,
+    // Rejected lines with their reason, with --rejects, and how many were written
+    pub rejects: Mutex<Option<CsvSink>>,
+    pub rejects_written: AtomicU64,
+    // Threshold alarms from --alarm, with their state in the current run
+    pub alarms: Mutex<Vec<Alarm>>,
+    // Rows kept while not recording, for the next start (--prebuffer)
+    pub prebuffer: Option<Mutex<PreBuffer>>,
+    // Recent payloads for --dedup-consecutive, and the rows dropped as repeats
+    pub dedup: Option<Mutex<Dedup>>,
+    pub duplicates: AtomicU64,
+    // The last row written for --log-on-change, and the rows left out as
+    // unchanged
+    pub change: Option<Mutex<ChangeFilter>>,
+    // Both clocks at the run's previous data row, for --clock-step
+    pub clock: Option<Mutex<ClockWatch>>,
+    pub unchanged: AtomicU64,
+    // The run is paused: data rows are left out until `resume`, and counted
+    pub paused: AtomicBool,
+    pub paused_rows: AtomicU64,
+    // Lines dropped for being longer than --max-line-length
+    pub overflows: AtomicU64,
+    // Lines of several ports written out of order, held up for longer than
+    // the --ordered window
+    pub late_rows: AtomicU64,
+    // With --rotate-align, when the next file is due, in milliseconds since
+    // the epoch; i64::MAX without
+    pub next_rotation: AtomicI64,
+    // The data rows of the current run, for its --xlsx workbook
+    pub xlsx: Option<Mutex<xlsx::Collector>>,
+    // Matching lines collected for --preview before the command loop starts
+    pub preview: Mutex<Option<Preview>>,
+    // Conditions that end the session (--strict), and the first that failed
+    pub strict: Vec<Check>,
+    pub strict_failure: Mutex<Option<(Check, String)>>,
+}
+
+impl Shared {
+    /// Notes a failed `--strict` check, if it is one of those given and
+    /// nothing failed before.
+    pub fn fail_strict(&self, check: Check, detail: impl FnOnce() -> String) {
+        if !self.strict.contains(&check) {
+            return;
+        }
+        let mut failure = self.strict_failure.lock().unwrap();
+        if failure.is_none() {
+            *failure = Some((check, detail()));
+        }
+    }
+
+    /// Counts a rejected line under its reason and, while recording, against
+    /// the run and in the rejects file.
+    pub fn reject(&self, reason: Reason, timestamp: &str, line: &str, recording: bool) {
+        let counter = match reason {
+            Reason::FieldCount => &self.parse_failures,
+            Reason::NeedsQuoting => &self.unquotable_rows,
+            Reason::BadFrame => &self.bad_frames,
+            Reason::BadPayload => &self.bad_payloads,
+            Reason::WrongType => &self.type_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if !recording {
+            return;
+        }
+        let check = match reason {
+            Reason::FieldCount => Some(Check::Fields),
+            Reason::WrongType => Some(Check::Types),
+            Reason::BadFrame | Reason::BadPayload => Some(Check::Frames),
+            Reason::NeedsQuoting => None,
+        };
+        if let Some(check) = check {
+            self.fail_strict(check, || format!("rejected as {}: {}", reason.code(), line));
+        }
+        self.run_rejected.fetch_add(1, Ordering::Relaxed);
+        let mut rejects = self.rejects.lock().unwrap();
+        let Some(w) = rejects.as_mut() else { return };
+        match w.write_record([timestamp, reason.code(), line]).and_then(|()| Ok(w.flush()?)) {
+            Ok(()) => {
+                self.rejects_written.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                esay!("Warning: Failed to write rejects file: {}; not writing it for the rest of the session.", e);
+                *rejects = None;
+            }
+        }
+    }
+
+    /// Appends an entry to the raw capture, if there is one.
+    pub fn capture(&self, timestamp: &str, entry: Entry) {
+        if let Some(raw) = self.raw.lock().unwrap().as_mut() {
+            raw.write(timestamp, &entry);
+        }
+    }
+}
+
+/// Where output goes, from `--output`, `--per-run` and `--port`.
+pub struct OutputSettings {
+    pub template: String,
+    pub per_run: bool,
+    pub port: String,
+    pub baud: u32,
+    pub meta: Metadata,
+    // Write a JSON sidecar with run metadata when each run stops
+    pub sidecar: bool,
+    pub sync: SyncPolicy,
+    pub dialect: CsvDialect,
+    // Names of the payload columns, from --fields
+    pub columns: Vec<String>,
+    // Where run numbering is kept between sessions, unless --no-state-file
+    pub state_file: Option<PathBuf>,
+    // Raw capture of the input, unless --no-raw
+    pub raw_file: Option<PathBuf>,
+    // Where rejected lines are kept, with --rejects
+    pub rejects_file: Option<PathBuf>,
+    // Constant columns after the payload on every data row (--operator,
+    // --experiment), as header name and value
+    pub id_columns: Vec<(String, String)>,
+    // Units of the columns from --units, as column name and unit
+    pub units: Vec<(String, String)>,
+    // Threshold alarms checked on every data row
+    pub alarms: Vec<Alarm>,
+    // Old output files deleted after each run or newfile, with --retain
+    pub retention: Option<Retention>,
+    // SHA-256 manifest of the files written, with --manifest
+    pub manifest: Option<Manifest>,
+    // --dry-run: records are shown instead of written, and no file is created
+    pub dry_run: bool,
+    // Write an Arrow IPC stream or Parquet file instead of CSV (--format)
+    pub columnar: Option<ColumnarOutput>,
+    // Rows from before a start written at the start of the run (--prebuffer)
+    pub prebuffer: Option<Limit>,
+    // Rows repeating one of the last N are dropped (--dedup-consecutive)
+    pub dedup_window: Option<usize>,
+    // Rows are only written when a watched field changes (--log-on-change)
+    pub on_change: Option<change::Policy>,
+    // Steps of the system clock larger than this get a clockstep row
+    // (--clock-step)
+    pub clock_step: Option<std::time::Duration>,
+    // Write an Excel workbook of each run of up to this many rows when it
+    // stops (--xlsx, --xlsx-max-rows)
+    pub xlsx_max_rows: Option<usize>,
+    // The last rows written, for the `tail` command (--tail-buffer)
+    pub tail: Option<Arc<Mutex<Tail>>>,
+    // Operator commands are written as `cmd` rows (--audit)
+    pub audit: bool,
+    // How repeated write errors are ridden out (--write-error-limit,
+    // --outage-buffer, --fallback-output)
+    pub recovery: Recovery,
+    // What starts and stops in quick succession do (--min-run-duration,
+    // --start-holdoff)
+    pub run_policy: Policy,
+    // Conditions that stop the run and end the session (--strict)
+    pub strict: Vec<Check>,
+    // The prompt asks for notes on each run that stops (--prompt-notes)
+    pub prompt_notes: bool,
+    // Each run ends with rows of per-column statistics (--summary-rows)
+    pub summary_rows: bool,
+    // A new file at each midnight or hour (--rotate-align)
+    pub rotate_align: Option<Align>,
+}
+
+#[cfg(test)]
+impl OutputSettings {
+    /// Settings for tests: `columns` written to `template` as plain CSV, with
+    /// every option off.
+    pub fn plain(template: &str, columns: &[&str]) -> OutputSettings {
+        OutputSettings {
+            template: template.to_string(),
+            per_run: false,
+            port: "/dev/ttyUSB0".to_string(),
+            baud: 115200,
+            meta: Metadata::for_session(MetaStyle::Off, "/dev/ttyUSB0", 115200, &[]),
+            sidecar: false,
+            sync: SyncPolicy::Never,
+            dialect: CsvDialect::parse("necessary", "lf").unwrap(),
+            columns: columns.iter().map(|name| name.to_string()).collect(),
+            state_file: None,
+            raw_file: None,
+            rejects_file: None,
+            id_columns: Vec::new(),
+            units: Vec::new(),
+            alarms: Vec::new(),
+            retention: None,
+            manifest: None,
+            dry_run: false,
+            columnar: None,
+            prebuffer: None,
+            dedup_window: None,
+            on_change: None,
+            clock_step: None,
+            xlsx_max_rows: None,
+            tail: None,
+            audit: false,
+            recovery: Recovery::default(),
+            run_policy: Policy::default(),
+            strict: Vec::new(),
+            prompt_notes: false,
+            summary_rows: false,
+            rotate_align: None,
+        }
+    }
+}
+
+/// A stopped run whose notes the prompt asks for, with --prompt-notes.
+pub struct NoteDue {
+    pub run: i64,
+    run_id: String,
+    file: String,
+    // The run's sidecar, written again with the notes
+    sidecar: Option<(PathBuf, RunMetadata)>,
+}
+
+/// Recording state owned by the command loop.
+pub struct Session {
+    pub shared: Arc<Shared>,
+    settings: OutputSettings,
+    // File currently (or most recently) written
+    output_path: String,
+    // Number the next run will get
+    next_run: i64,
+    // A run has been started and not yet ended; it can be ended by the serial
+    // thread (disk full) without the command loop's involvement
+    run_active: bool,
+    // The disk filled up at some point this session
+    disk_filled: bool,
+    run_started: Instant,
+    run_started_at: chrono::DateTime<chrono::Local>,
+    run_label: String,
+    // Random UUID of the active (or most recent) run
+    run_id: String,
+    // When the active run was paused, while it is
+    paused_at: Option<Instant>,
+    session_started: Instant,
+    session_started_at: String,
+    // Finished runs, for the end-of-session summary
+    finished_runs: Vec<RunSummary>,
+    // Every file written this session, in order
+    output_files: Vec<String>,
+    // Files already in the manifest (--manifest-per-run)
+    listed_files: Vec<String>,
+    // A `start` command waiting for its run's start row, with --audit
+    start_command: Option<String>,
+    // Whether runs are recording or waiting to start, for --min-run-duration
+    // and --start-holdoff, and the start that waits with its audited command
+    guard: Guard,
+    held_start: Option<(String, Source, Option<String>)>,
+    // The --max-duration or --max-file-size cap that ended recording
+    cap_reached: Option<Cap>,
+    // The --strict check that failed and ended the session, and why
+    strict_failed: Option<String>,
+    // Stopped runs the prompt hasn't asked for notes on yet
+    notes_due: Vec<NoteDue>,
+    // Size of the raw capture and rejects file before this session appended
+    // to them
+    appended_from: u64,
+}
+
+impl Session {
+    /// Validates the output template and, unless every run gets its own
+    /// file, creates the session-long output file.
+    pub fn new(settings: OutputSettings, hooks: Hooks) -> Result<Session, String> {
+        let uses_per_run = template::uses_per_run_placeholders(&settings.template);
+        if settings.per_run && !uses_per_run {
+            return Err(format!(
+                "--per-run needs {{run}} or {{name}} in the output path so runs don't overwrite each other (got '{}')",
+                settings.template
+            ));
+        }
+        let (writer, output_path) = if settings.per_run {
+            // Catch unknown placeholders now rather than at the first start
+            template::expand(&settings.template, &context(&settings.port, Some((0, ""))))?;
+            (None, String::new())
+        } else {
+            let path = template::expand(&settings.template, &context(&settings.port, None))?;
+            (Some(open_output(&path, &settings, true)?), path)
+        };
+        let output_files = if writer.is_some() { vec![output_path.clone()] } else { Vec::new() };
+
+        let next_run = settings.state_file.as_deref().map(state::load).unwrap_or(0);
+        let raw = settings.raw_file.as_deref().map(RawLog::open).transpose()?;
+        let rejects = settings
+            .rejects_file
+            .as_deref()
+            .map(|path| rejects::open(path, settings.dialect, settings.sync, settings.dry_run))
+            .transpose()?;
+        let appended_from = appended_files(&settings)
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|meta| meta.len())
+            .sum();
+        let guard = Guard::new(settings.run_policy.clone());
+
+        Ok(Session {
+            shared: Arc::new(Shared {
+                recording: AtomicBool::new(false),
+                writer: Mutex::new(writer),
+                current_run: AtomicI64::new(-1),
+                run_rows: AtomicU64::new(0),
+                peek: Mutex::new(Peek::default()),
+                run_stats: Mutex::new(RunStats::default()),
+                run_rejected: AtomicU64::new(0),
+                serial_port: Mutex::new(settings.port.clone()),
+                serial_baud: AtomicU32::new(settings.baud),
+                lines_read: AtomicU64::new(0),
+                lines_filtered: AtomicU64::new(0),
+                blank_lines: AtomicU64::new(0),
+                parse_failures: AtomicU64::new(0),
+                serial_errors: AtomicU64::new(0),
+                port_switches: AtomicU64::new(0),
+                disconnects: AtomicU64::new(0),
+                bytes_received: AtomicU64::new(0),
+                rows_written: AtomicU64::new(0),
+                last_data: Mutex::new(None),
+                last_record: Mutex::new(None),
+                breaks: AtomicU64::new(0),
+                unquotable_rows: AtomicU64::new(0),
+                compute_errors: AtomicU64::new(0),
+                bad_frames: AtomicU64::new(0),
+                bad_payloads: AtomicU64::new(0),
+                type_errors: AtomicU64::new(0),
+                current_file: Mutex::new(output_path.clone()),
+                disk_full: AtomicBool::new(false),
+                low_disk: AtomicBool::new(false),
+                awaiting_sync: AtomicBool::new(false),
+                line_noise: AtomicBool::new(false),
+                hooks,
+                row_constants: settings.id_columns.iter().map(|(_, value)| value.clone()).collect(),
+                raw: Mutex::new(raw),
+                rejects: Mutex::new(rejects),
+                rejects_written: AtomicU64::new(0),
+                alarms: Mutex::new(settings.alarms.clone()),
+                prebuffer: settings.prebuffer.map(|limit| Mutex::new(PreBuffer::new(limit))),
+                dedup: settings.dedup_window.map(|window| Mutex::new(Dedup::new(window))),
+                duplicates: AtomicU64::new(0),
+                change: settings.on_change.clone().map(|policy| Mutex::new(ChangeFilter::new(policy))),
+                clock: settings.clock_step.map(|threshold| Mutex::new(ClockWatch::new(threshold))),
+                unchanged: AtomicU64::new(0),
+                paused: AtomicBool::new(false),
+                paused_rows: AtomicU64::new(0),
+                overflows: AtomicU64::new(0),
+                late_rows: AtomicU64::new(0),
+                next_rotation: AtomicI64::new(
+                    settings
+                        .rotate_align
+                        .map_or(i64::MAX, |align| rotate::next_boundary(align, &crate::timesource::now()).timestamp_millis()),
+                ),
+                xlsx: settings.xlsx_max_rows.map(|max_rows| Mutex::new(xlsx::Collector::new(max_rows))),
+                preview: Mutex::new(None),
+                strict: settings.strict.clone(),
+                strict_failure: Mutex::new(None),
+            }),
+            settings,
+            output_path,
+            next_run,
+            run_active: false,
+            disk_filled: false,
+            run_started: Instant::now(),
+            run_started_at: crate::timesource::now(),
+            run_label: String::new(),
+            run_id: String::new(),
+            paused_at: None,
+            session_started: Instant::now(),
+            session_started_at: get_timestamp(),
+            finished_runs: Vec::new(),
+            output_files,
+            listed_files: Vec::new(),
+            start_command: None,
+            guard,
+            held_start: None,
+            cap_reached: None,
+            strict_failed: None,
+            notes_due: Vec::new(),
+            appended_from,
+        })
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.shared.recording.load(Ordering::Relaxed)
+    }
+
+    // Run number of the active (or most recently finished) run
+    pub fn current_run(&self) -> i64 {
+        self.next_run - 1
+    }
+
+    // Runs recorded this session
+    pub fn runs(&self) -> i64 {
+        self.finished_runs.len() as i64 + i64::from(self.run_active)
+    }
+
+    /// The last rows written, unless `--tail-buffer 0` turned that off.
+    pub fn tail(&self) -> Option<&Mutex<Tail>> {
+        self.settings.tail.as_deref()
+    }
+
+    /// The port given first with `--port`.
+    pub fn primary_port(&self) -> &str {
+        &self.settings.port
+    }
+
+    pub fn disk_filled(&self) -> bool {
+        self.disk_filled
+    }
+
+    pub fn cap_reached(&self) -> Option<Cap> {
+        self.cap_reached
+    }
+
+    pub fn run_policy(&self) -> &Policy {
+        self.guard.policy()
+    }
+
+    // How long until a start held back by --start-holdoff, if one waits
+    pub fn start_waiting(&self) -> Option<std::time::Duration> {
+        self.guard.waiting(Instant::now())
+    }
+
+    /// Starts the run that was waiting for --start-holdoff to pass, once it has.
+    pub fn start_if_due(&mut self) {
+        if !self.guard.due(Instant::now()) {
+            return;
+        }
+        if let Some((label, source, command)) = self.held_start.take() {
+            self.start_command = command;
+            self.start_from(&label, source);
+        }
+    }
+
+    /// Stops the active run because `cap` was reached; no run starts after.
+    pub fn stop_for_cap(&mut self, cap: Cap) {
+        self.cap_reached = Some(cap);
+        self.settle_aborted_run();
+        if self.is_recording() {
+            self.stop_from(Source::Cap(cap));
+        }
+    }
+
+    /// Stops the active run because a `--strict` check failed; the failure
+    /// goes in its stop row.
+    pub fn stop_for_strict(&mut self) {
+        let failure = self.shared.strict_failure.lock().unwrap().clone();
+        let Some((check, detail)) = failure else { return };
+        self.strict_failed = Some(format!("{}: {}", check.name(), detail));
+        self.settle_aborted_run();
+        if self.is_recording() {
+            self.stop_from(Source::Strict);
+        }
+    }
+
+    pub fn strict_failed(&self) -> Option<&str> {
+        self.strict_failed.as_deref()
+    }
+
+    /// Bytes written this session: the output files (or what they were
+    /// compressed to) and what the raw capture and rejects file gained.
+    pub fn output_bytes(&self) -> u64 {
+        if self.settings.dry_run {
+            return 0;
+        }
+        let mut paths: Vec<&str> = Vec::new();
+        for path in &self.output_files {
+            if !paths.contains(&path.as_str()) {
+                paths.push(path);
+            }
+        }
+        let size = |path: &Path| std::fs::metadata(path).map(|meta| meta.len()).ok();
+        let outputs: u64 = paths
+            .iter()
+            .filter_map(|path| size(Path::new(path)).or_else(|| size(&compress::compressed_path(Path::new(path)))))
+            .sum();
+        let appended: u64 = appended_files(&self.settings).iter().filter_map(|path| size(path)).sum();
+        outputs + appended.saturating_sub(self.appended_from)
+    }
+
+    pub fn start(&mut self, label: &str) {
+        self.start_from(label, Source::Operator);
+    }
+
+    /// Starts a run on a command from `source`; whichever command comes last,
+    /// from the operator or the device, decides.
+    pub fn start_from(&mut self, label: &str, source: Source) {
+        let command = self.start_command.take();
+        self.settle_aborted_run();
+        if let Some(cap) = self.cap_reached {
+            say!("The --{} cap was reached; no new runs are started.", cap.name());
+            return;
+        }
+        if self.is_recording() {
+            match source.name() {
+                None => say!("Recording is already started."),
+                Some(name) => say!("The {} asked to start recording; it is already started.", name),
+            }
+            return;
+        }
+        match self.guard.start(Instant::now()) {
+            holdoff::Start::Now | holdoff::Start::AlreadyRecording => {}
+            holdoff::Start::Delay(left) => {
+                say!(
+                    "{} came within --start-holdoff of the last stop; recording starts in {:.1}s.",
+                    requested("start", source),
+                    left.as_secs_f64()
+                );
+                self.held_start = Some((label.to_string(), source, command));
+                return;
+            }
+            holdoff::Start::Refuse(left) => {
+                esay!(
+                    "{} came within --start-holdoff of the last stop and is refused ({:.1}s of the holdoff left).",
+                    requested("start", source),
+                    left.as_secs_f64()
+                );
+                return;
+            }
+            holdoff::Start::AlreadyWaiting(left) => {
+                say!("{} is ignored; a start is already waiting, in {:.1}s.", requested("start", source), left.as_secs_f64());
+                return;
+            }
+        }
+        let shared = Arc::clone(&self.shared);
+        let mut w = shared.writer.lock().unwrap();
+        if self.shared.disk_full.swap(false, Ordering::SeqCst) {
+            // Explicit start after a full disk: try writing again
+            match w.as_mut() {
+                Some(sink) if self.settings.per_run => {
+                    // The aborted run's file stays as it is
+                    sink.clear_full();
+                    *w = None;
+                }
+                Some(sink) => sink.clear_full(),
+                None => {}
+            }
+            say!("Retrying output after the disk was full.");
+        }
+        if self.settings.per_run {
+            let ctx = context(&self.settings.port, Some((self.next_run, label)));
+            let opened = template::expand(&self.settings.template, &ctx)
+                .map(|path| template::unused_path(&path))
+                .and_then(|path| Ok((open_output(&path, &self.settings, false)?, path)));
+            match opened {
+                Ok((writer, path)) => {
+                    say!("Writing run {} to {}.", self.next_run, path);
+                    *w = Some(writer);
+                    self.output_files.push(path.clone());
+                    self.set_output_path(path);
+                }
+                Err(e) => {
+                    esay!("{}; recording not started.", e);
+                    return;
+                }
+            }
+        }
+        self.run_label = label.to_string();
+        if !self.begin_run(w.as_mut().expect("A writer is open while recording"), source, command.as_deref()) {
+            self.shared.disk_full.store(true, Ordering::SeqCst);
+            esay!("ERROR: Output disk is still full; recording not started.");
+            return;
+        }
+        self.write_prebuffer(w.as_mut().expect("A writer is open while recording"));
+        self.shared.recording.store(true, Ordering::Relaxed);
+        match source.name() {
+            None => say!("Recording started."),
+            Some(name) => say!("Recording started by the {}.", name),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.stop_from(Source::Operator);
+    }
+
+    /// Stops the run on a command from `source`. A cap stops it whatever
+    /// --min-run-duration says.
+    pub fn stop_from(&mut self, source: Source) {
+        self.settle_aborted_run();
+        let decision = match source {
+            Source::Cap(_) | Source::Strict if self.is_recording() => holdoff::Stop::Now { short: false },
+            _ => self.guard.stop(Instant::now()),
+        };
+        let short = match decision {
+            holdoff::Stop::Now { short } => short,
+            holdoff::Stop::Keep(lasted) => {
+                esay!(
+                    "Warning: {} came after only {:.1}s of run {}, less than --min-run-duration; the run goes on.",
+                    requested("stop", source),
+                    lasted.as_secs_f64(),
+                    self.current_run()
+                );
+                return;
+            }
+            holdoff::Stop::Cancel => {
+                self.held_start = None;
+                say!("{} cancels the start waiting for --start-holdoff.", requested("stop", source));
+                return;
+            }
+            holdoff::Stop::NotRecording => {
+                match source.name() {
+                    None => say!("Recording is not active."),
+                    Some(name) => say!("The {} asked to stop recording; it is not active.", name),
+                }
+                return;
+            }
+        };
+        self.stop_run(source, short);
+        if short {
+            say!("Run {} ended before --min-run-duration and is marked short.", self.current_run());
+        }
+    }
+
+    /// Leaves the data rows of the active run out until `resume`, with a
+    /// `pause` row.
+    pub fn pause(&mut self) {
+        if !self.is_recording() {
+            return say!("Recording is not active.");
+        }
+        if self.paused_at.is_some() {
+            return say!("Run {} is already paused.", self.current_run());
+        }
+        // Under the writer lock, so no data row is written after the pause row
+        let mut w = self.shared.writer.lock().unwrap();
+        self.shared.paused.store(true, Ordering::Relaxed);
+        self.paused_at = Some(Instant::now());
+        let run = format!("run {}", self.current_run());
+        if let Some(w) = w.as_mut() {
+            write_marker(w, Kind::Pause, &[&get_timestamp(), &run]);
+        }
+        say!("Run {} paused; data rows are left out until resume.", self.current_run());
+    }
+
+    /// Writes data rows of the paused run again, after a `resume` row.
+    pub fn resume(&mut self) {
+        let Some(paused_at) = self.paused_at.take() else {
+            return say!("Recording is not paused.");
+        };
+        let mut w = self.shared.writer.lock().unwrap();
+        let paused = format!("after {:.1}s", paused_at.elapsed().as_secs_f64());
+        if let Some(w) = w.as_mut() {
+            write_marker(w, Kind::Resume, &[&get_timestamp(), &paused]);
+        }
+        // The pause is marked; it isn't a gap as well
+        *self.shared.last_record.lock().unwrap() = None;
+        self.shared.paused.store(false, Ordering::Relaxed);
+        say!("Run {} resumed {}.", self.current_run(), paused);
+    }
+
+    /// Writes a `mark` row with the operator's `text` in the active run.
+    pub fn mark(&mut self, text: &str) {
+        if !self.is_recording() {
+            return say!("Recording is not active; nothing to mark.");
+        }
+        if let Some(w) = self.shared.writer.lock().unwrap().as_mut() {
+            write_marker(w, Kind::Mark, &[&get_timestamp(), text]);
+        }
+        say!("Marked run {}.", self.current_run());
+    }
+
+    // Stops the active run, marked short if it is; no policy applies here
+    fn stop_run(&mut self, source: Source, short: bool) {
+        // Taking the writer lock first means no line is captured as part of
+        // the run after its stop marker
+        let shared = Arc::clone(&self.shared);
+        let mut w = shared.writer.lock().unwrap();
+        self.shared.recording.store(false, Ordering::Relaxed);
+        // Rows seen during the run don't count as before the next one
+        if let Some(prebuffer) = &self.shared.prebuffer {
+            prebuffer.lock().unwrap().take(Instant::now());
+        }
+        let sink = w.as_mut().expect("A writer is open while recording");
+        self.end_run(sink, source, short);
+        if self.settings.per_run {
+            if let Err(e) = sink.close() {
+                esay!("Failed to flush CSV writer: {}", e);
+            }
+            self.follow_fallback(sink);
+            // Dropping the writer closes the run's file
+            *w = None;
+            if let Some(manifest) = self.settings.manifest.as_ref().filter(|manifest| manifest.per_run) {
+                manifest.append(&[&self.output_path]);
+                self.listed_files.push(self.output_path.clone());
+            }
+            upload::submit(Path::new(&self.output_path));
+            compress::submit(&self.output_path);
+        }
+        drop(w);
+        match source.name() {
+            None => say!("Recording stopped."),
+            Some(name) => say!("Recording stopped by the {}.", name),
+        }
+        if source.name().is_some() && self.settings.prompt_notes {
+            say!("Press Enter to add notes for run {}.", self.current_run());
+        }
+        self.apply_retention();
+    }
+
+    /// The stopped runs to ask for notes on, which are asked for only once.
+    pub fn take_notes_due(&mut self) -> Vec<NoteDue> {
+        std::mem::take(&mut self.notes_due)
+    }
+
+    /// Writes the notes on a stopped run as a `note` row, if the run's file is
+    /// still the one being written, and into its sidecar.
+    pub fn write_note(&mut self, due: NoteDue, text: &str) {
+        let in_file = !self.settings.per_run && due.file == self.output_path;
+        if in_file {
+            if let Some(w) = self.shared.writer.lock().unwrap().as_mut() {
+                write_marker(w, Kind::Note, &[&get_timestamp(), text, &due.run_id]);
+            }
+        }
+        match due.sidecar {
+            Some((path, mut metadata)) => {
+                metadata.note = Some(text.to_string());
+                match sidecar::write(&path, &metadata) {
+                    Ok(()) => upload::submit(&path),
+                    Err(e) => esay!("{}", e),
+                }
+            }
+            None if !in_file => {
+                esay!("Warning: {} is no longer being written, so the notes for run {} are not kept.", due.file, due.run);
+                return;
+            }
+            None => {}
+        }
+        say!("Notes saved for run {}.", due.run);
+    }
+
+    /// Switches output to a new file. The new file is created before anything
+    /// else happens, so a failure leaves the current writer untouched. An
+    /// active run is ended in the old file and a new run is started in the new
+    /// one, so every file is self-contained.
+    pub fn switch_file(&mut self, path: &str) -> Result<(), String> {
+        if self.settings.per_run {
+            return Err("newfile is not available with --per-run".to_string());
+        }
+        let path = template::expand(path, &context(&self.settings.port, None))?;
+        self.switch_to(path, None)
+    }
+
+    /// With --rotate-align, switches to the next file once `at` has reached
+    /// the boundary, and sets the one after. A file that can't be opened is
+    /// warned about, and the current one is written on until the next.
+    pub fn rotate_if_due(&mut self, at: chrono::DateTime<chrono::Local>) {
+        let Some(align) = self.settings.rotate_align else { return };
+        // Another thread may have switched first
+        if at.timestamp_millis() < self.shared.next_rotation.load(Ordering::Acquire) {
+            return;
+        }
+        let ctx = Context {
+            now: at,
+            port: &self.settings.port,
+            run: None,
+        };
+        // A name taken already, as in the hour repeated when clocks go back,
+        // is numbered rather than overwritten
+        let switched = template::expand(&self.settings.template, &ctx).and_then(|path| self.switch_to(template::unused_path(&path), Some(at)));
+        if let Err(e) = switched {
+            esay!("Warning: Failed to start the next file (--rotate-align {}): {}; still writing {}", align.name(), e, self.output_path);
+        }
+        // Only once the next file is open, so rows waiting for it land there
+        let next = rotate::next_boundary(align, &at);
+        self.shared.next_rotation.store(next.timestamp_millis(), Ordering::Release);
+    }
+
+    // Switches output to `path`, expanded already. With `carry_over` (the
+    // time of the switch) a run going on stays the same run, with a
+    // continued row at the end of the old file and the start of the new one
+    fn switch_to(&mut self, path: String, carry_over: Option<chrono::DateTime<chrono::Local>>) -> Result<(), String> {
+        // Only a new file: opening the one being written (or any other
+        // existing file) would truncate it
+        if same_file(Path::new(&path), Path::new(&self.output_path)) {
+            return Err(format!("{} is the file being written", path));
+        }
+        let new_writer = open_output(&path, &self.settings, false)?;
+        self.settle_aborted_run();
+        // A fresh file (possibly on another disk) is a way out of a full disk
+        self.shared.disk_full.store(false, Ordering::SeqCst);
+
+        // Holding the lock across the swap means the serial thread's next
+        // write lands in the new file, after its start marker
+        let shared = Arc::clone(&self.shared);
+        let mut guard = shared.writer.lock().unwrap();
+        let w = guard.as_mut().expect("A session-long writer is always open");
+        let was_recording = self.is_recording();
+        let continued = carry_over
+            .filter(|_| was_recording)
+            .map(|at| (format_timestamp(&at), format!("run {}", self.current_run())));
+        if let Some((timestamp, run)) = &continued {
+            write_marker(w, Kind::Continued, &[timestamp, run, &self.run_id]);
+        } else if was_recording {
+            self.end_run(w, Source::Operator, false);
+        }
+        if !w.is_full() {
+            if let Err(e) = w.close() {
+                esay!("Failed to flush CSV writer: {}", e);
+            }
+        }
+        self.follow_fallback(w);
+        let old_path = self.output_path.clone();
+        self.set_output_path(path.clone());
+        self.output_files.push(path.clone());
+        // The old writer is dropped here, closing its file
+        *w = new_writer;
+        if let Some((timestamp, run)) = &continued {
+            write_marker(w, Kind::Continued, &[timestamp, run, &self.run_id]);
+        } else if was_recording {
+            self.begin_run(w, Source::Operator, None);
+        }
+        drop(guard);
+        if old_path != path {
+            upload::submit(Path::new(&old_path));
+            compress::submit(&old_path);
+        }
+
+        say!("Output switched from {} to {}.", old_path, path);
+        self.apply_retention();
+        Ok(())
+    }
+
+    // Deletes old output files as --retain asks, never the file being
+    // written or the files kept beside the output
+    fn apply_retention(&self) {
+        let Some(retention) = &self.settings.retention else { return };
+        let mut keep = vec![
+            self.settings.state_file.as_deref(),
+            self.settings.raw_file.as_deref(),
+            self.settings.rejects_file.as_deref(),
+        ];
+        if !self.settings.per_run || self.is_recording() {
+            keep.push(Some(Path::new(&self.output_path)));
+        }
+        let keep: Vec<&Path> = keep.into_iter().flatten().collect();
+        retention.apply(&self.settings.template, &keep);
+    }
+
+    // Takes on --fallback-output as the output once the sink has moved there
+    // to ride out write errors
+    fn follow_fallback(&mut self, sink: &CsvSink) {
+        if let Some(path) = sink.path().filter(|path| *path != self.output_path) {
+            self.output_files.push(path.to_string());
+            self.set_output_path(path.to_string());
+        }
+    }
+
+    fn set_output_path(&mut self, path: String) {
+        *self.shared.current_file.lock().unwrap() = path.clone();
+        self.output_path = path;
+    }
+
+    // A run the serial thread stopped because the disk filled up ends here,
+    // without a stop marker: nothing more is written to a full disk
+    fn settle_aborted_run(&mut self) {
+        if !self.run_active || self.is_recording() {
+            return;
+        }
+        self.disk_filled = true;
+        esay!(
+            "Run {} was stopped because the output disk is full; its data up to that point is intact.",
+            self.current_run()
+        );
+        self.finish_run(crate::timesource::now(), false);
+    }
+
+    // Writes the rows kept from before the start (--prebuffer) right after
+    // its marker, as `pre` rows; caller holds the writer lock
+    fn write_prebuffer(&self, w: &mut CsvSink) {
+        let Some(prebuffer) = &self.shared.prebuffer else { return };
+        let rows = prebuffer.lock().unwrap().take(Instant::now());
+        let mut written = 0;
+        for mut record in rows {
+            record[0] = Kind::Pre.to_string();
+            if w.refuses(&record) {
+                continue;
+            }
+            if let Err(e) = w.write_record(&record) {
+                esay!("Failed to write pre-start record to CSV: {}", e);
+                break;
+            }
+            written += 1;
+        }
+        if let Err(e) = w.flush() {
+            esay!("Failed to flush CSV writer: {}", e);
+        }
+        if written > 0 {
+            say!("Wrote {} rows from before the start.", written);
+        }
+    }
+
+    /// Writes an operator command as a `cmd` row, with --audit. A `start` is
+    /// written right after the start row of the run it begins, so it is part
+    /// of that run; any other command before it takes effect. Between runs
+    /// with --per-run there is no file to write it to.
+    pub fn audit(&mut self, command_line: &str) {
+        if !self.settings.audit {
+            return;
+        }
+        let is_start = command_line.split_whitespace().next() == Some("start");
+        if is_start && !self.is_recording() {
+            self.start_command = Some(command_line.to_string());
+            return;
+        }
+        if let Some(w) = self.shared.writer.lock().unwrap().as_mut() {
+            write_marker(w, Kind::Cmd, &[&get_timestamp(), command_line]);
+        }
+    }
+
+    // Writes the start marker for a new run, and the command that started it
+    // with --audit; caller holds the writer lock.
+    // Returns false if the marker could not be written because the disk is full.
+    fn begin_run(&mut self, w: &mut CsvSink, source: Source, command: Option<&str>) -> bool {
+        let run = self.next_run;
+        self.next_run += 1;
+        self.shared.current_run.store(run, Ordering::Relaxed);
+        self.shared.run_rows.store(0, Ordering::Relaxed);
+        self.shared.run_rejected.store(0, Ordering::Relaxed);
+        *self.shared.last_record.lock().unwrap() = None;
+        *self.shared.run_stats.lock().unwrap() = RunStats::default();
+        self.shared.alarms.lock().unwrap().iter_mut().for_each(Alarm::reset);
+        if let Some(dedup) = &self.shared.dedup {
+            dedup.lock().unwrap().reset();
+        }
+        if let Some(change) = &self.shared.change {
+            change.lock().unwrap().reset();
+        }
+        if let Some(clock) = &self.shared.clock {
+            clock.lock().unwrap().reset();
+        }
+        self.run_started = Instant::now();
+        self.run_id = uuid::Uuid::new_v4().to_string();
+
+        self.run_started_at = crate::timesource::now();
+        let timestamp = format_timestamp(&self.run_started_at);
+        let run_str = format!("run {}", run);
+        let mut marker = vec![timestamp.as_str(), &run_str, &self.run_id];
+        marker.extend(source.cell());
+        write_marker(w, Kind::Start, &marker);
+        if w.is_full() {
+            self.next_run -= 1;
+            return false;
+        }
+        if let Some(command) = command {
+            write_marker(w, Kind::Cmd, &[&timestamp, command]);
+        }
+        self.shared.capture(
+            &timestamp,
+            Entry::Start {
+                run,
+                run_id: self.run_id.clone(),
+                label: self.run_label.clone(),
+            },
+        );
+        // A dry run reads the run numbering but leaves it as it was
+        if let Some(path) = self.settings.state_file.as_ref().filter(|_| !self.settings.dry_run) {
+            if let Err(e) = state::save(path, self.next_run) {
+                esay!("{}", e);
+            }
+        }
+        self.run_active = true;
+        self.guard.started(Instant::now());
+        events::emit(Event::RecordingStarted {
+            run,
+            run_id: self.run_id.clone(),
+            label: self.run_label.clone(),
+            file: self.output_path.clone(),
+        });
+        if let Some(command) = &self.shared.hooks.on_start {
+            hooks::run("on-start", command, self.hook_env(run, timestamp, None));
+        }
+        true
+    }
+
+    // Writes the stop marker, which says `short run` for a run that ended
+    // before --min-run-duration, and reports the finished run; caller holds
+    // the writer lock
+    fn end_run(&mut self, w: &mut CsvSink, source: Source, short: bool) {
+        self.follow_fallback(w);
+        let stopped_at = crate::timesource::now();
+        let timestamp = format_timestamp(&stopped_at);
+        let note = match (&self.strict_failed, source) {
+            (Some(failure), Source::Strict) => format!("strict {}", failure),
+            _ if short => "short run".to_string(),
+            _ => "end of run".to_string(),
+        };
+        if self.settings.summary_rows {
+            self.write_summary_rows(w, &timestamp);
+        }
+        let mut marker = vec![timestamp.as_str(), &note, &self.run_id];
+        marker.extend(source.cell());
+        write_marker(w, Kind::Stop, &marker);
+        self.shared.capture(
+            &timestamp,
+            Entry::Stop {
+                run: self.current_run(),
+                run_id: self.run_id.clone(),
+            },
+        );
+        self.finish_run(stopped_at, short);
+    }
+
+    // Writes the run's count, min, max, mean and standard deviation of every
+    // numeric column, as one row each under the columns
+    fn write_summary_rows(&self, w: &mut CsvSink, timestamp: &str) {
+        let fields = self.shared.run_stats.lock().unwrap().summaries(&[]);
+        let run = format!("run {}", self.current_run());
+        let cell = |value: Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+        let rows: [(Kind, Vec<String>); 5] = [
+            (Kind::SummaryCount, fields.iter().map(|field| field.count.to_string()).collect()),
+            (Kind::SummaryMin, fields.iter().map(|field| cell(field.min)).collect()),
+            (Kind::SummaryMax, fields.iter().map(|field| cell(field.max)).collect()),
+            (Kind::SummaryMean, fields.iter().map(|field| cell(field.mean)).collect()),
+            (Kind::SummaryStddev, fields.iter().map(|field| cell(field.stddev)).collect()),
+        ];
+        for (kind, values) in &rows {
+            let mut cells = vec![timestamp, run.as_str(), self.run_id.as_str()];
+            cells.extend(values.iter().map(String::as_str));
+            write_marker(w, kind.clone(), &cells);
+        }
+    }
+
+    // Reports a run that has ended and records it for the summary
+    fn finish_run(&mut self, stopped_at: chrono::DateTime<chrono::Local>, short: bool) {
+        self.run_active = false;
+        self.paused_at = None;
+        self.shared.paused.store(false, Ordering::Relaxed);
+        self.guard.stopped(Instant::now());
+        let run = self.current_run();
+        let timestamp = format_timestamp(&stopped_at);
+
+        let rows = self.shared.run_rows.load(Ordering::Relaxed);
+        let duration_secs = self.run_started.elapsed().as_secs_f64();
+        let alarms: Vec<AlarmCount> = self.shared.alarms.lock().unwrap().iter().map(Alarm::count).collect();
+        events::emit(Event::RecordingStopped {
+            run,
+            file: self.output_path.clone(),
+            rows,
+        });
+        events::emit(Event::RunSummary {
+            run,
+            run_id: self.run_id.clone(),
+            file: self.output_path.clone(),
+            rows,
+            duration_secs,
+        });
+        self.finished_runs.push(RunSummary {
+            run,
+            run_id: self.run_id.clone(),
+            file: self.output_path.clone(),
+            started: format_timestamp(&self.run_started_at),
+            stopped: timestamp.clone(),
+            rows,
+            duration_secs,
+            short,
+            alarms: alarms.clone(),
+        });
+        self.queue_workbook(run, stopped_at);
+        let sidecar = if self.settings.sidecar {
+            self.write_sidecar(run, rows, alarms, stopped_at)
+        } else {
+            None
+        };
+        if self.settings.prompt_notes {
+            self.notes_due.push(NoteDue {
+                run,
+                run_id: self.run_id.clone(),
+                file: self.output_path.clone(),
+                sidecar,
+            });
+        }
+        if let Some(command) = &self.shared.hooks.on_stop {
+            hooks::run("on-stop", command, self.hook_env(run, timestamp, Some(rows)));
+        }
+    }
+
+    // Environment passed to the start and stop hooks
+    fn hook_env(&self, run: i64, timestamp: String, rows: Option<u64>) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("SL_RUN", run.to_string()),
+            ("SL_RUN_ID", self.run_id.clone()),
+            ("SL_RUN_NAME", self.run_label.clone()),
+            ("SL_OUTPUT", self.output_path.clone()),
+            ("SL_TIMESTAMP", timestamp),
+        ];
+        if let Some(rows) = rows {
+            env.push(("SL_ROW_COUNT", rows.to_string()));
+        }
+        env
+    }
+
+    // Hands the rows of the run that just stopped to the --xlsx writer
+    fn queue_workbook(&self, run: i64, stopped_at: chrono::DateTime<chrono::Local>) {
+        let Some(collector) = &self.shared.xlsx else { return };
+        let mut collector = collector.lock().unwrap();
+        let Some(rows) = collector.take() else {
+            esay!(
+                "Warning: Run {} has more than {} rows (--xlsx-max-rows); no workbook is written, its data is in {}.",
+                run,
+                collector.max_rows(),
+                self.output_path
+            );
+            return;
+        };
+        let field_names: Vec<&str> = self.settings.columns.iter().map(String::as_str).collect();
+        let mut columns = self.settings.columns.clone();
+        columns.extend(self.settings.id_columns.iter().map(|(name, _)| name.clone()));
+        let mut meta = self.settings.meta.entries.clone();
+        meta.extend([
+            ("run".to_string(), run.to_string()),
+            ("run_id".to_string(), self.run_id.clone()),
+            ("label".to_string(), self.run_label.clone()),
+            ("file".to_string(), self.output_path.clone()),
+            ("started".to_string(), format_timestamp(&self.run_started_at)),
+            ("stopped".to_string(), format_timestamp(&stopped_at)),
+            ("rows".to_string(), rows.len().to_string()),
+        ]);
+        xlsx::submit(xlsx::Workbook {
+            path: xlsx::workbook_path(&self.output_path, run, self.settings.per_run),
+            columns,
+            rows,
+            fields: self.shared.run_stats.lock().unwrap().summaries(&field_names),
+            meta,
+        });
+    }
+
+    // Writes the run's sidecar, returning it for the notes to be added to
+    fn write_sidecar(
+        &self,
+        run: i64,
+        rows: u64,
+        alarms: Vec<AlarmCount>,
+        stopped_at: chrono::DateTime<chrono::Local>,
+    ) -> Option<(PathBuf, RunMetadata)> {
+        let port = self.shared.serial_port.lock().unwrap().clone();
+        let baud = self.shared.serial_baud.load(Ordering::Relaxed);
+        let field_names: Vec<&str> = self.settings.columns.iter().map(String::as_str).collect();
+        let metadata = RunMetadata {
+            run,
+            run_id: self.run_id.clone(),
+            label: self.run_label.clone(),
+            file: self.output_path.clone(),
+            started_local: format_timestamp(&self.run_started_at),
+            started_utc: self.run_started_at.with_timezone(&chrono::Utc).to_rfc3339(),
+            stopped_local: format_timestamp(&stopped_at),
+            stopped_utc: stopped_at.with_timezone(&chrono::Utc).to_rfc3339(),
+            rows,
+            rejected_lines: self.shared.run_rejected.load(Ordering::Relaxed),
+            fields: self.shared.run_stats.lock().unwrap().summaries(&field_names),
+            units: self.settings.units.iter().cloned().collect(),
+            alarms,
+            serial: sidecar::port_info(&port, baud),
+            note: None,
+        };
+        let path = sidecar::sidecar_path(&self.output_path, run, self.settings.per_run);
+        if self.settings.dry_run {
+            say!("(dry run) Would write run metadata to {}.", path.display());
+            return None;
+        }
+        match sidecar::write(&path, &metadata) {
+            Ok(()) => {
+                upload::submit(&path);
+                Some((path, metadata))
+            }
+            Err(e) => {
+                esay!("{}", e);
+                None
+            }
+        }
+    }
+
+    /// Ends the session: stops an active run, flushes and builds the summary.
+    pub fn finish(&mut self) -> SessionSummary {
+        self.settle_aborted_run();
+        self.held_start = None;
+        if self.is_recording() {
+            self.stop_run(Source::Operator, false);
+        }
+        {
+            let shared = Arc::clone(&self.shared);
+            let mut w = shared.writer.lock().unwrap();
+            if let Some(sink) = w.as_mut().filter(|sink| !sink.is_full()) {
+                if let Err(e) = sink.close() {
+                    esay!("Failed to flush CSV writer: {}", e);
+                }
+                self.follow_fallback(sink);
+            }
+            // Closed for good, so it can be hashed and uploaded
+            if w.take().is_some() {
+                upload::submit(Path::new(&self.output_path));
+            }
+        }
+        if let Some(manifest) = &self.settings.manifest {
+            let mut unlisted: Vec<&str> = Vec::new();
+            for path in &self.output_files {
+                // A path used again (e.g. the same run label) is listed once
+                if !self.listed_files.contains(path) && !unlisted.contains(&path.as_str()) {
+                    unlisted.push(path);
+                }
+            }
+            manifest.append(&unlisted);
+        }
+
+        let bytes_written = self
+            .output_files
+            .iter()
+            .filter(|_| !self.settings.dry_run)
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|meta| meta.len())
+            .sum();
+        let counter = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        SessionSummary {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            started: self.session_started_at.clone(),
+            ended: get_timestamp(),
+            runtime_secs: self.session_started.elapsed().as_secs_f64(),
+            total_rows: self.finished_runs.iter().map(|run| run.rows).sum(),
+            runs: self.finished_runs.clone(),
+            bytes_written,
+            lines_read: counter(&self.shared.lines_read),
+            lines_filtered: counter(&self.shared.lines_filtered),
+            blank_lines: counter(&self.shared.blank_lines),
+            parse_failures: counter(&self.shared.parse_failures),
+            serial_errors: counter(&self.shared.serial_errors),
+            disconnects: counter(&self.shared.disconnects),
+            breaks: counter(&self.shared.breaks),
+            unquotable_rows: counter(&self.shared.unquotable_rows),
+            compute_errors: counter(&self.shared.compute_errors),
+            bad_frames: counter(&self.shared.bad_frames),
+            bad_payloads: counter(&self.shared.bad_payloads),
+            type_errors: counter(&self.shared.type_errors),
+            duplicates: counter(&self.shared.duplicates),
+            unchanged: counter(&self.shared.unchanged),
+            paused_rows: counter(&self.shared.paused_rows),
+            overflows: counter(&self.shared.overflows),
+            late_rows: counter(&self.shared.late_rows),
+            rejects_written: counter(&self.shared.rejects_written),
+            rejects_file: self.settings.rejects_file.as_ref().map(|path| path.display().to_string()),
+            disk_full: self.disk_filled,
+            cap_reached: self.cap_reached.map(|cap| cap.name().to_string()),
+            strict_failure: self.strict_failed.clone(),
+            output_outages: outage::outages(),
+            rows_lost: outage::lost(),
+            dry_run: self.settings.dry_run,
+            output_files: self.output_files.clone(),
+        }
+    }
+}
+
+// Files that outlive a session and are appended to
+fn appended_files(settings: &OutputSettings) -> Vec<&Path> {
+    [settings.raw_file.as_deref(), settings.rejects_file.as_deref()].into_iter().flatten().collect()
+}
+
+fn format_timestamp(time: &chrono::DateTime<chrono::Local>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn context<'a>(port: &'a str, run: Option<(i64, &'a str)>) -> Context<'a> {
+    Context {
+        now: crate::timesource::now(),
+        port,
+        run,
+    }
+}
+
+/// Who started or stopped a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    // The start and stop commands, --auto-start and file switches
+    Operator,
+    // A command line sent by the device (--device-commands)
+    Device,
+    // An edge on a modem control line (--hw-trigger)
+    Trigger(ModemLine),
+    // --max-duration or --max-file-size
+    Cap(Cap),
+    // A failed --strict check
+    Strict,
+}
+
+impl Source {
+    // Extra cell for the start and stop rows: the operator's aren't marked
+    fn cell(self) -> Option<&'static str> {
+        match self {
+            Source::Operator => None,
+            Source::Device => Some("device"),
+            Source::Trigger(ModemLine::Cts) => Some("trigger cts"),
+            Source::Trigger(ModemLine::Dsr) => Some("trigger dsr"),
+            Source::Trigger(ModemLine::Cd) => Some("trigger cd"),
+            Source::Cap(cap) => Some(cap.name()),
+            Source::Strict => Some("strict"),
+        }
+    }
+
+    // For messages
+    fn name(self) -> Option<String> {
+        match self {
+            Source::Operator => None,
+            Source::Device => Some("device".to_string()),
+            Source::Trigger(line) => Some(format!("{} trigger", line.name())),
+            Source::Cap(cap) => Some(format!("--{} cap", cap.name())),
+            Source::Strict => Some("--strict check".to_string()),
+        }
+    }
+}
+
+// "The start" or "The device's start", as requested
+fn requested(what: &str, source: Source) -> String {
+    match source.name() {
+        None => format!("The {}", what),
+        Some(name) => format!("The {}'s {}", name, what),
+    }
+}
+
+// Writes a marker row of `kind` with `cells` after the Type column and
+// flushes so it is visible immediately, and synced under --sync interval
+pub fn write_marker(w: &mut CsvSink, kind: Kind, cells: &[&str]) {
+    let record = std::iter::once(kind.as_str()).chain(cells.iter().copied());
+    if let Err(e) = w.write_record(record) {
+        esay!("Failed to write {} record to CSV: {}", kind, e);
+    }
+    if let Err(e) = w.flush_marker() {
+        esay!("Failed to flush CSV writer: {}", e);
+    }
+}
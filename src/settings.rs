@@ -0,0 +1,121 @@
+//! Settings changed at the prompt while logging: `set NAME VALUE` and
+//! `show settings`.
+//!
+//! `echo` shows the input on the console as it arrives: `on` prints each
+//! cleaned line (or decoded frame), `raw` each chunk of bytes as received,
+//! escaped, and `off`, the default, nothing. `verbose` decides where warnings
+//! about single lines (wrong field count, bad frames, type mismatches) go: `0`
+//! only to the `--log-file`, `1`, the default, to the console while
+//! recording, and `2` to the console between runs as well. A change is
+//! reported like any other message, so it ends up in the log file with its
+//! time; it never touches the CSV. The settings last until the program exits.
+//!
+//! Each setting is an entry in `SETTINGS`, which `set` and `show` go by.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// What `echo` shows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Echo {
+    Off,
+    On,
+    Raw,
+}
+
+static ECHO: AtomicU8 = AtomicU8::new(0);
+static VERBOSE: AtomicU8 = AtomicU8::new(1);
+
+const MAX_VERBOSE: u8 = 2;
+
+struct Setting {
+    name: &'static str,
+    // The values it takes, as shown by `show settings`
+    values: &'static str,
+    get: fn() -> String,
+    set: fn(&str) -> Result<(), String>,
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting {
+        name: "echo",
+        values: "on|off|raw",
+        get: || echo_name(echo()).to_string(),
+        set: set_echo,
+    },
+    Setting {
+        name: "verbose",
+        values: "0..2",
+        get: || verbose().to_string(),
+        set: set_verbose,
+    },
+];
+
+fn echo_name(echo: Echo) -> &'static str {
+    match echo {
+        Echo::Off => "off",
+        Echo::On => "on",
+        Echo::Raw => "raw",
+    }
+}
+
+fn set_echo(value: &str) -> Result<(), String> {
+    let echo = match value {
+        "off" => Echo::Off,
+        "on" => Echo::On,
+        "raw" => Echo::Raw,
+        _ => return Err("expected on, off or raw".to_string()),
+    };
+    ECHO.store(echo as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+fn set_verbose(value: &str) -> Result<(), String> {
+    match value.parse::<u8>() {
+        Ok(level) if level <= MAX_VERBOSE => {
+            VERBOSE.store(level, Ordering::Relaxed);
+            Ok(())
+        }
+        _ => Err(format!("expected 0 to {}", MAX_VERBOSE)),
+    }
+}
+
+/// The current `echo` setting.
+pub fn echo() -> Echo {
+    match ECHO.load(Ordering::Relaxed) {
+        1 => Echo::On,
+        2 => Echo::Raw,
+        _ => Echo::Off,
+    }
+}
+
+/// The current `verbose` level, 0 to 2.
+pub fn verbose() -> u8 {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Carries out `set NAME VALUE`.
+pub fn set(args: &str) {
+    let usage = || {
+        let names: Vec<String> = SETTINGS.iter().map(|s| format!("{} {}", s.name, s.values)).collect();
+        say!("Usage: set <name> <value> ({})", names.join(", "));
+    };
+    let mut words = args.split_whitespace();
+    let (Some(name), Some(value), None) = (words.next(), words.next(), words.next()) else {
+        return usage();
+    };
+    let Some(setting) = SETTINGS.iter().find(|s| s.name == name) else {
+        say!("Unknown setting '{}'.", name);
+        return usage();
+    };
+    match (setting.set)(value) {
+        Ok(()) => say!("{} set to {}.", setting.name, (setting.get)()),
+        Err(e) => say!("Invalid {} '{}': {}", setting.name, value, e),
+    }
+}
+
+/// Carries out `show settings`.
+pub fn show() {
+    for setting in SETTINGS {
+        say!("  {:<8} {:<4} ({})", setting.name, (setting.get)(), setting.values);
+    }
+}
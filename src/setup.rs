@@ -0,0 +1,271 @@
+//! `setup`: an interactive walk through the options a new device needs.
+//!
+//! It lists the serial ports, finds the baud rate by trying the common ones,
+//! samples a few seconds of input and analyzes it like `--probe`, then asks
+//! for the match text, delimiter, column names and output path, showing how
+//! a sample line parses as the answers change. Every question has a default
+//! taken from the sample, so pressing Enter throughout gives a working
+//! setup. The answers are written as a `--config` file, and logging can
+//! start with it right away.
+
+use collect_acc_data::{parser, probe};
+use std::{
+    io::{BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::config::Value;
+use crate::serial;
+
+pub const DEFAULT_CONFIG: &str = "serial_logger.toml";
+
+// Tried in this order; the first wins a tie
+const BAUD_RATES: [u32; 8] = [115200, 9600, 57600, 38400, 19200, 230400, 460800, 921600];
+const BAUD_TRIAL: Duration = Duration::from_millis(1500);
+const SAMPLE: Duration = Duration::from_secs(3);
+const DEFAULT_OUTPUT: &str = "data/{date}/output.csv";
+
+/// Runs the wizard and returns the config file written, and whether to
+/// start logging with it now.
+pub fn run(config: &Path) -> Result<(PathBuf, bool), String> {
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        return Err(
+            "setup asks questions and needs a terminal; without one, write the options to a file and pass it with --config FILE"
+                .to_string(),
+        );
+    }
+    let port = choose_port()?;
+    let baud = choose_baud(&port)?;
+
+    say!("Sampling {} at {} baud for {}s...", port, baud, SAMPLE.as_secs());
+    let data = serial::capture(&port, baud, SAMPLE).map_err(|e| format!("Failed to open serial port {}", e))?;
+    let report = probe::analyze(&data, true);
+    say!("{}", report);
+    let lines = probe::split_lines(&data, true);
+
+    let (pattern, delimiter, fields) = loop {
+        let default_pattern = report.prefix.as_ref().map_or(String::new(), |(prefix, _)| prefix.clone());
+        let pattern = ask("Text that marks data lines (empty: every line is data)", &default_pattern)?;
+        let payloads = payloads(&lines, &pattern);
+        let default_delimiter = probe::delimiter_candidates(&payloads)
+            .first()
+            .map_or(',', |best| best.delimiter);
+        let delimiter = loop {
+            let answer = ask("Field delimiter (a character, tab or space)", &delimiter_name(default_delimiter))?;
+            match parser::parse_delimiter(&answer) {
+                Ok(delimiter) => break delimiter,
+                Err(e) => say!("{}", e),
+            }
+        };
+        let fields = field_count(&payloads, delimiter);
+        preview(&payloads, delimiter, fields);
+        if fields > 0 && confirm("Does this look right?", true)? {
+            break (pattern, delimiter, fields);
+        }
+        if fields == 0 {
+            say!("No sample line matches; try another match text.");
+        }
+    };
+
+    let default_names = if fields == parser::DEFAULT_FIELD_NAMES.len() {
+        parser::DEFAULT_FIELD_NAMES.join(",")
+    } else {
+        (1..=fields).map(|i| format!("field {}", i)).collect::<Vec<_>>().join(",")
+    };
+    let names = loop {
+        let answer = ask(&format!("Names of the {} columns, comma-separated", fields), &default_names)?;
+        if answer.split(',').count() == fields {
+            break answer;
+        }
+        say!("That is {} names; the lines have {} fields.", answer.split(',').count(), fields);
+    };
+    let output = ask("Output file; {date}, {time}, {port} and {run} are filled in", DEFAULT_OUTPUT)?;
+
+    let mut entries = vec![
+        ("port", Value::Text(port)),
+        ("baud", Value::Number(baud.to_string())),
+        ("match", Value::Text(pattern)),
+        ("delimiter", Value::Text(delimiter_name(delimiter))),
+        ("fields", Value::Number(fields.to_string())),
+        ("names", Value::Text(names)),
+    ];
+    if output.contains("{run}") {
+        // Each run needs its own file for {run} to mean anything
+        entries.push(("per-run", Value::Bool(true)));
+    }
+    entries.push(("output", Value::Text(output)));
+
+    let config = choose_config_path(config)?;
+    let mut text = String::from("# Written by serial_logger setup; use with --config\n");
+    for (key, value) in &entries {
+        text.push_str(&format!("{} = {}\n", key, value));
+    }
+    std::fs::write(&config, text).map_err(|e| format!("Failed to write {}: {}", config.display(), e))?;
+    say!("Wrote {}.", config.display());
+    let start = confirm("Start logging now?", true)?;
+    Ok((config, start))
+}
+
+fn choose_port() -> Result<String, String> {
+    let ports = serialport::available_ports().unwrap_or_default();
+    if ports.is_empty() {
+        say!("No serial ports found; is the device plugged in?");
+        let default = if cfg!(windows) { "COM3" } else { "/dev/ttyUSB0" };
+        return ask("Serial port", default);
+    }
+    say!("Serial ports:");
+    for (i, port) in ports.iter().enumerate() {
+        match &port.port_type {
+            serialport::SerialPortType::UsbPort(usb) => say!(
+                "  {}) {}  {} {}",
+                i + 1,
+                port.port_name,
+                usb.manufacturer.as_deref().unwrap_or(""),
+                usb.product.as_deref().unwrap_or("")
+            ),
+            _ => say!("  {}) {}", i + 1, port.port_name),
+        }
+    }
+    // A USB adapter is the likely device
+    let default = ports
+        .iter()
+        .position(|port| matches!(port.port_type, serialport::SerialPortType::UsbPort(_)))
+        .unwrap_or(0);
+    loop {
+        let answer = ask("Port (number or name)", &(default + 1).to_string())?;
+        match answer.parse::<usize>() {
+            Ok(n) if (1..=ports.len()).contains(&n) => return Ok(ports[n - 1].port_name.clone()),
+            Ok(_) => say!("Pick a number from 1 to {}.", ports.len()),
+            Err(_) => return Ok(answer),
+        }
+    }
+}
+
+fn choose_baud(port: &str) -> Result<u32, String> {
+    loop {
+        let answer = ask("Baud rate, or auto to try the common ones", "auto")?;
+        if answer == "auto" {
+            return detect_baud(port);
+        }
+        match answer.parse() {
+            Ok(baud) => return Ok(baud),
+            Err(_) => say!("'{}' is not a baud rate.", answer),
+        }
+    }
+}
+
+fn detect_baud(port: &str) -> Result<u32, String> {
+    let mut best = (BAUD_RATES[0], 0.0);
+    for baud in BAUD_RATES {
+        let data = serial::capture(port, baud, BAUD_TRIAL).map_err(|e| format!("Failed to open serial port {}", e))?;
+        let score = probe::text_score(&data);
+        say!("  {:>7} baud: {} bytes, {:.0}% text", baud, data.len(), score * 100.0);
+        if score > best.1 {
+            best = (baud, score);
+        }
+    }
+    if best.1 == 0.0 {
+        say!("Nothing received at any rate; using {}.", best.0);
+    } else {
+        say!("Best match: {} baud.", best.0);
+    }
+    Ok(best.0)
+}
+
+// The text after the match text in each line that has it
+fn payloads<'a>(lines: &'a [String], pattern: &str) -> Vec<&'a str> {
+    lines
+        .iter()
+        .filter_map(|line| line.find(pattern).map(|start| line[start + pattern.len()..].trim()))
+        .filter(|payload| !payload.is_empty())
+        .collect()
+}
+
+// The most common number of fields, 0 without payloads
+fn field_count(payloads: &[&str], delimiter: char) -> usize {
+    let mut counts = std::collections::BTreeMap::new();
+    for payload in payloads {
+        *counts.entry(probe::split_fields(payload, delimiter).len()).or_insert(0) += 1;
+    }
+    counts.into_iter().max_by_key(|(fields, count)| (*count, *fields)).map_or(0, |(fields, _)| fields)
+}
+
+fn preview(payloads: &[&str], delimiter: char, fields: usize) {
+    let Some(sample) = payloads
+        .iter()
+        .find(|payload| probe::split_fields(payload, delimiter).len() == fields)
+    else {
+        return;
+    };
+    let matching = payloads
+        .iter()
+        .filter(|payload| probe::split_fields(payload, delimiter).len() == fields)
+        .count();
+    say!("'{}' parses as:", sample);
+    for (i, field) in probe::split_fields(sample, delimiter).iter().enumerate() {
+        say!("  f{} = {}", i, field);
+    }
+    say!("{} of {} sample lines have these {} fields.", matching, payloads.len(), fields);
+}
+
+fn choose_config_path(config: &Path) -> Result<PathBuf, String> {
+    let mut config = config.to_path_buf();
+    while config.exists() {
+        if confirm(&format!("{} exists; overwrite it?", config.display()), false)? {
+            break;
+        }
+        config = PathBuf::from(ask("Config file to write instead", &free_name(&config).display().to_string())?);
+    }
+    Ok(config)
+}
+
+// `name_1.toml`, `name_2.toml`, ... whichever doesn't exist yet
+fn free_name(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{}_{}{}", stem, n, extension)))
+        .find(|candidate| !candidate.exists())
+        .expect("Some numbered name is free")
+}
+
+fn delimiter_name(delimiter: char) -> String {
+    match delimiter {
+        '\t' => "tab".to_string(),
+        ' ' => "space".to_string(),
+        c => c.to_string(),
+    }
+}
+
+// Asks a question; an empty answer takes the default
+fn ask(question: &str, default: &str) -> Result<String, String> {
+    let mut stdout = std::io::stdout();
+    let _ = write!(stdout, "{} [{}]: ", question, default);
+    let _ = stdout.flush();
+    let mut answer = String::new();
+    let read = std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|e| format!("Failed to read the answer: {}", e))?;
+    if read == 0 {
+        return Err("setup cancelled".to_string());
+    }
+    let answer = answer.trim_end_matches(['\r', '\n']);
+    Ok(if answer.trim().is_empty() { default.to_string() } else { answer.to_string() })
+}
+
+fn confirm(question: &str, default: bool) -> Result<bool, String> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    loop {
+        let answer = ask(question, hint)?;
+        if answer == hint {
+            return Ok(default);
+        }
+        match answer.to_ascii_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => say!("Please answer y or n."),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Per-run sidecar metadata written with `--sidecar` when each run stops.
+//!
+//! With `--per-run` the sidecar sits next to the run's file with the extension
+//! replaced (`run_0003.csv` gets `run_0003.meta.json`). A session-long file
+//! gets one sidecar per run (`output.csv` gets `output.run_0003.meta.json`).
+
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::alarm::AlarmCount;
+use crate::stats::FieldSummary;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PortInfo {
+    pub port: String,
+    pub baud: u32,
+    pub serial_settings: String,
+    pub usb_vid: Option<String>,
+    pub usb_pid: Option<String>,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RunMetadata {
+    pub run: i64,
+    pub run_id: String,
+    pub label: String,
+    pub file: String,
+    pub started_local: String,
+    pub started_utc: String,
+    pub stopped_local: String,
+    pub stopped_utc: String,
+    pub rows: u64,
+    pub rejected_lines: u64,
+    pub fields: Vec<FieldSummary>,
+    // Units of the columns from --units, by column name
+    pub units: BTreeMap<String, String>,
+    // Times each --alarm was raised in the run
+    pub alarms: Vec<AlarmCount>,
+    pub serial: PortInfo,
+    // Entered at the --prompt-notes prompt once the run stopped
+    pub note: Option<String>,
+}
+
+/// Looks up USB identifiers for the port, when the OS reports any.
+pub fn port_info(port: &str, baud: u32) -> PortInfo {
+    let mut info = PortInfo {
+        port: port.to_string(),
+        baud,
+        serial_settings: "8N1, no flow control".to_string(),
+        usb_vid: None,
+        usb_pid: None,
+        serial_number: None,
+        manufacturer: None,
+        product: None,
+    };
+    let ports = serialport::available_ports().unwrap_or_default();
+    if let Some(serialport::SerialPortType::UsbPort(usb)) =
+        ports.into_iter().find(|p| p.port_name == port).map(|p| p.port_type)
+    {
+        info.usb_vid = Some(format!("{:04x}", usb.vid));
+        info.usb_pid = Some(format!("{:04x}", usb.pid));
+        info.serial_number = usb.serial_number;
+        info.manufacturer = usb.manufacturer;
+        info.product = usb.product;
+    }
+    info
+}
+
+/// Path of the sidecar for `run` written to `output`.
+pub fn sidecar_path(output: &str, run: i64, per_run: bool) -> PathBuf {
+    let output = Path::new(output);
+    let stem = output.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let name = if per_run {
+        format!("{}.meta.json", stem)
+    } else {
+        format!("{}.run_{:04}.meta.json", stem, run)
+    };
+    output.with_file_name(name)
+}
+
+/// Writes the sidecar through a temporary file and a rename, so readers never
+/// see a partially written file.
+pub fn write(path: &Path, metadata: &RunMetadata) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(metadata).expect("Run metadata always serializes");
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, json + "\n")
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|e| format!("Failed to write sidecar {}: {}", path.display(), e))
+}
@@ -0,0 +1,753 @@
+use collect_acc_data::arrow::{DataType, StreamWriter};
+#[cfg(feature = "hdf5")]
+use collect_acc_data::hdf5;
+use collect_acc_data::parquet::FileWriter;
+use csv::{Writer, WriterBuilder};
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::outage::Tracker;
+use crate::session::Shared;
+use crate::tail::Tail;
+
+/// When flushed data is also forced to disk with `File::sync_data`.
+///
+/// `flush()` only hands data to the OS cache, which a hard power loss can
+/// still lose. Syncing protects against that at a cost: writing and flushing a
+/// data row measured ~0.5 µs without syncing and ~60 µs with `every-flush` on
+/// an ext4 virtual disk, and consumer SSDs, SD cards and USB sticks are often
+/// 1–10 ms per sync, which caps `every-flush` at a few hundred rows per
+/// second. `interval:<secs>` bounds the data at risk to that many seconds for
+/// a negligible cost: a flush syncs once the interval is up, and rows flushed
+/// but not yet synced are synced by `spawn_interval_sync` when it is up even
+/// if no further row comes. The default is `never`, i.e. plain flushes. Marker rows
+/// (start, stop, cmd, alarm, ...) are synced when flushed under `interval`
+/// too, as other equipment may be synchronized on them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    Never,
+    Interval(Duration),
+    EveryFlush,
+}
+
+impl SyncPolicy {
+    pub fn parse(text: &str) -> Result<SyncPolicy, String> {
+        match text {
+            "never" => Ok(SyncPolicy::Never),
+            "every-flush" => Ok(SyncPolicy::EveryFlush),
+            _ => {
+                let secs = text
+                    .strip_prefix("interval:")
+                    .and_then(|secs| secs.parse::<f64>().ok())
+                    .filter(|secs| *secs > 0.0)
+                    .ok_or_else(|| {
+                        format!("Invalid --sync '{}': expected never, interval:<secs> or every-flush", text)
+                    })?;
+                Ok(SyncPolicy::Interval(Duration::from_secs_f64(secs)))
+            }
+        }
+    }
+}
+
+/// Quoting of CSV fields (`--quote-style`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuoteStyle {
+    // Only fields containing a delimiter, quote or line break
+    Necessary,
+    Always,
+    // Never; records that would need quoting are refused instead
+    Never,
+}
+
+/// How records are quoted and terminated.
+#[derive(Debug, Clone, Copy)]
+pub struct CsvDialect {
+    pub quote: QuoteStyle,
+    pub crlf: bool,
+}
+
+impl CsvDialect {
+    pub fn parse(quote: &str, terminator: &str) -> Result<CsvDialect, String> {
+        let quote = match quote {
+            "necessary" => QuoteStyle::Necessary,
+            "always" => QuoteStyle::Always,
+            "never" => QuoteStyle::Never,
+            _ => return Err(format!("Invalid --quote-style '{}': expected necessary, always or never", quote)),
+        };
+        let crlf = match terminator {
+            "lf" => false,
+            "crlf" => true,
+            _ => return Err(format!("Invalid --csv-terminator '{}': expected lf or crlf", terminator)),
+        };
+        Ok(CsvDialect { quote, crlf })
+    }
+
+    pub fn builder(&self) -> WriterBuilder {
+        let mut builder = WriterBuilder::new();
+        builder
+            .quote_style(match self.quote {
+                QuoteStyle::Necessary => csv::QuoteStyle::Necessary,
+                QuoteStyle::Always => csv::QuoteStyle::Always,
+                QuoteStyle::Never => csv::QuoteStyle::Never,
+            })
+            .terminator(if self.crlf { csv::Terminator::CRLF } else { csv::Terminator::Any(b'\n') });
+        builder
+    }
+
+    /// Line ending for text written around the CSV writer (comment lines).
+    pub fn line_end(&self) -> &'static str {
+        if self.crlf {
+            "\r\n"
+        } else {
+            "\n"
+        }
+    }
+}
+
+/// Whether a field can't be written unquoted without becoming ambiguous.
+fn needs_quotes(field: &[u8]) -> bool {
+    field.iter().any(|b| matches!(b, b',' | b'"' | b'\r' | b'\n'))
+}
+
+/// Where a sink's records go: a file, or with `--dry-run` the console, each
+/// line shown with the file it would have gone to once it is flushed.
+pub enum Target {
+    File(File),
+    // A file that fails every write once the flag is set, for an output
+    // given up on after repeated errors (see outage.rs)
+    Tracked(File, Arc<AtomicBool>),
+    DryRun { path: String, pending: Vec<u8> },
+}
+
+impl Target {
+    pub fn dry_run(path: &str) -> Target {
+        Target::DryRun {
+            path: path.to_string(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Write for Target {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Target::File(file) => file.write(buf),
+            Target::Tracked(_, cut) if cut.load(Ordering::Relaxed) => Err(cut_off()),
+            Target::Tracked(file, _) => file.write(buf),
+            Target::DryRun { pending, .. } => {
+                pending.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Target::File(file) => file.flush(),
+            Target::Tracked(_, cut) if cut.load(Ordering::Relaxed) => Err(cut_off()),
+            Target::Tracked(file, _) => file.flush(),
+            Target::DryRun { path, pending } => {
+                let end = pending.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+                let lines: Vec<u8> = pending.drain(..end).collect();
+                for line in String::from_utf8_lossy(&lines).lines() {
+                    say!("(dry run) {}: {}", path, line.trim_end_matches('\r'));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Only the HDF5 writer seeks, and only in a file
+impl Seek for Target {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        match self {
+            Target::File(file) | Target::Tracked(file, _) => file.seek(position),
+            Target::DryRun { .. } => Err(io::Error::new(io::ErrorKind::Unsupported, "a dry run can't seek")),
+        }
+    }
+}
+
+fn cut_off() -> io::Error {
+    io::Error::other("output given up on after repeated write errors")
+}
+
+/// Columnar output formats (`--format`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Columnar {
+    Arrow,
+    Parquet,
+    #[cfg(feature = "hdf5")]
+    Hdf5,
+}
+
+/// `--format arrow`, `parquet` or `hdf5`: the type of each column after the
+/// leading ones, and the rows in each record batch, row group or chunk.
+#[derive(Debug, Clone)]
+pub struct ColumnarOutput {
+    pub format: Columnar,
+    pub column_types: Vec<DataType>,
+    // How HDF5 stores the same columns, which --types tells more precisely
+    #[cfg(feature = "hdf5")]
+    pub number_types: Vec<Option<hdf5::NumberType>>,
+    pub batch_rows: usize,
+}
+
+// How records are encoded into the file
+enum Encoder {
+    Csv(Box<Writer<Target>>),
+    Arrow(StreamWriter<Target>),
+    Parquet(FileWriter),
+    #[cfg(feature = "hdf5")]
+    Hdf5(hdf5::FileWriter<Target>),
+}
+
+/// CSV (or Arrow, Parquet or HDF5) writer that keeps access to the underlying file so flushes
+/// can be followed by `sync_data` according to the sync policy.
+///
+/// Once a write fails because the disk is full the sink refuses all further
+/// writes, leaving what is already in the file intact, until `clear_full`.
+///
+/// A CSV file's sink can be given a `Tracker`, which holds records in memory
+/// once writing keeps failing and swaps in a writer on the reopened file.
+///
+/// Every record is padded to the file's column count, and trailing empty
+/// values beyond it are dropped, so marker rows fit any number of fields.
+pub struct CsvSink {
+    writer: Encoder,
+    width: usize,
+    // --quote-style never: refuse records that would need quoting
+    refuse_quoting: bool,
+    sync: SyncPolicy,
+    last_sync: Instant,
+    // A marker row was written since the last sync
+    marker: bool,
+    // Rows were flushed since the last sync
+    unsynced: bool,
+    full: bool,
+    tracker: Option<Tracker>,
+    // Recent rows for the `tail` command
+    tail: Option<Arc<Mutex<Tail>>>,
+}
+
+fn is_disk_full(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::StorageFull
+}
+
+fn unquotable() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "record contains a comma, quote or line break and --quote-style never doesn't quote",
+    )
+}
+
+fn refused() -> io::Error {
+    io::Error::new(io::ErrorKind::StorageFull, "output disk is full; writing stopped")
+}
+
+impl CsvSink {
+    pub fn new(writer: Writer<Target>, width: usize, dialect: CsvDialect, sync: SyncPolicy) -> CsvSink {
+        CsvSink {
+            writer: Encoder::Csv(Box::new(writer)),
+            width,
+            refuse_quoting: dialect.quote == QuoteStyle::Never,
+            sync,
+            last_sync: Instant::now(),
+            marker: false,
+            unsynced: false,
+            full: false,
+            tracker: None,
+            tail: None,
+        }
+    }
+
+    /// A sink writing an Arrow stream; a record's cells are parsed into the
+    /// schema's types, and cells past the last column are dropped.
+    pub fn arrow(writer: StreamWriter<Target>, sync: SyncPolicy) -> CsvSink {
+        CsvSink::columnar(writer.fields().len(), Encoder::Arrow(writer), sync)
+    }
+
+    /// A sink writing a Parquet file, like `arrow`.
+    pub fn parquet(writer: FileWriter, sync: SyncPolicy) -> CsvSink {
+        CsvSink::columnar(writer.fields().len(), Encoder::Parquet(writer), sync)
+    }
+
+    /// A sink writing an HDF5 file; only start and data rows are kept (see
+    /// `write_hdf5`), and `width` is that of the CSV header.
+    #[cfg(feature = "hdf5")]
+    pub fn hdf5(writer: hdf5::FileWriter<Target>, width: usize, sync: SyncPolicy) -> CsvSink {
+        CsvSink::columnar(width, Encoder::Hdf5(writer), sync)
+    }
+
+    fn columnar(width: usize, writer: Encoder, sync: SyncPolicy) -> CsvSink {
+        CsvSink {
+            width,
+            writer,
+            refuse_quoting: false,
+            sync,
+            last_sync: Instant::now(),
+            marker: false,
+            unsynced: false,
+            full: false,
+            tracker: None,
+            tail: None,
+        }
+    }
+
+    /// Keeps a copy of every row written in `tail`.
+    pub fn keep_tail(&mut self, tail: Arc<Mutex<Tail>>) {
+        self.tail = Some(tail);
+    }
+
+    /// Rides out repeated write errors with `tracker`.
+    pub fn track(&mut self, tracker: Tracker) {
+        self.tracker = Some(tracker);
+    }
+
+    /// The file being written, when tracked; the fallback after an outage
+    /// ended there.
+    pub fn path(&self) -> Option<&str> {
+        self.tracker.as_ref().map(Tracker::path)
+    }
+
+    /// Whether a write has failed because the disk is full.
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// Whether the record would be refused because it needs quoting.
+    pub fn refuses<T: AsRef<[u8]>>(&self, record: &[T]) -> bool {
+        self.refuse_quoting && record.iter().any(|field| needs_quotes(field.as_ref()))
+    }
+
+    /// Allows writing again after space has been freed.
+    pub fn clear_full(&mut self) {
+        self.full = false;
+    }
+
+    pub fn write_record<I, T>(&mut self, record: I) -> csv::Result<()>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        if self.full {
+            return Err(refused().into());
+        }
+        let record: Vec<T> = record.into_iter().collect();
+        if self.refuses(&record) {
+            return Err(unquotable().into());
+        }
+        let mut fields: Vec<&[u8]> = record.iter().map(|field| field.as_ref()).collect();
+        while fields.len() > self.width && fields.last().is_some_and(|field| field.is_empty()) {
+            fields.pop();
+        }
+        if fields.len() < self.width {
+            fields.resize(self.width, b"");
+        }
+        if let Some(tracker) = self.tracker.as_mut().filter(|tracker| tracker.in_outage()) {
+            tracker.hold(&fields);
+            self.retry();
+            self.keep(&fields);
+            return Ok(());
+        }
+        let result = match &mut self.writer {
+            Encoder::Csv(writer) => writer.write_record(&fields),
+            Encoder::Arrow(writer) => writer.push_row(&fields).map_err(csv::Error::from),
+            Encoder::Parquet(writer) => writer.push_row(&fields).map_err(csv::Error::from),
+            #[cfg(feature = "hdf5")]
+            Encoder::Hdf5(writer) => write_hdf5(writer, &fields).map_err(csv::Error::from),
+        };
+        match &result {
+            Ok(()) => {
+                if let Some(tracker) = &mut self.tracker {
+                    tracker.written(&fields);
+                }
+                self.keep(&fields);
+            }
+            Err(csv_error) => {
+                if let csv::ErrorKind::Io(e) = csv_error.kind() {
+                    self.full |= is_disk_full(e);
+                    if !self.full && self.tracker.as_mut().is_some_and(|tracker| tracker.failed(e, Some(&fields))) {
+                        self.keep(&fields);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn keep(&self, fields: &[&[u8]]) {
+        if let Some(tail) = &self.tail {
+            tail.lock().unwrap().push(fields);
+        }
+    }
+
+    /// Flushes, syncing as the policy says. During an outage it retries the
+    /// file instead, when a retry is due.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.tracker.as_ref().is_some_and(Tracker::in_outage) {
+            self.retry();
+            return Ok(());
+        }
+        match self.flush_and_sync() {
+            Ok(()) => {
+                if let Some(tracker) = &mut self.tracker {
+                    tracker.flushed();
+                }
+                Ok(())
+            }
+            Err(e) if !self.full && self.tracker.as_mut().is_some_and(|tracker| tracker.failed(&e, None)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Flushes after a marker row, syncing it right away unless syncing is
+    /// disabled, however long the `interval` has left.
+    pub fn flush_marker(&mut self) -> io::Result<()> {
+        self.marker = true;
+        self.flush()
+    }
+
+    // Swaps in a writer on the reopened file once the tracker has one; the
+    // old writer is cut off from its file
+    fn retry(&mut self) {
+        if let Some(writer) = self.tracker.as_mut().and_then(Tracker::retry) {
+            self.writer = Encoder::Csv(Box::new(writer));
+        }
+    }
+
+    fn flush_and_sync(&mut self) -> io::Result<()> {
+        self.flush_writer()?;
+        let due = match self.sync {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryFlush => true,
+            SyncPolicy::Interval(interval) => self.marker || self.last_sync.elapsed() >= interval,
+        };
+        if due {
+            self.sync_now()?;
+        } else {
+            self.unsynced = true;
+        }
+        Ok(())
+    }
+
+    /// Syncs rows flushed since the last sync once `--sync interval` is up;
+    /// see `spawn_interval_sync`.
+    pub fn sync_due(&mut self) -> io::Result<()> {
+        let SyncPolicy::Interval(interval) = self.sync else { return Ok(()) };
+        if !self.unsynced || self.full || self.last_sync.elapsed() < interval {
+            return Ok(());
+        }
+        if self.tracker.as_ref().is_some_and(Tracker::in_outage) {
+            return Ok(());
+        }
+        self.sync_now()
+    }
+
+    /// Flushes and, unless syncing is disabled, syncs regardless of the
+    /// interval. Used before a file is closed; an Arrow stream or Parquet
+    /// file gets its last batch or row group, and takes no more records.
+    pub fn close(&mut self) -> io::Result<()> {
+        if let Some(tracker) = self.tracker.as_mut().filter(|tracker| tracker.in_outage()) {
+            // A last try; what can't be written now is lost
+            tracker.retry_now();
+            self.retry();
+            if let Some(tracker) = self.tracker.as_mut().filter(|tracker| tracker.in_outage()) {
+                tracker.give_up();
+                return Ok(());
+            }
+        }
+        let finished = match &mut self.writer {
+            Encoder::Csv(_) => Ok(()),
+            _ if self.full => Err(refused()),
+            Encoder::Arrow(writer) => writer.finish(),
+            Encoder::Parquet(writer) => writer.finish(),
+            #[cfg(feature = "hdf5")]
+            Encoder::Hdf5(writer) => writer.finish(),
+        };
+        if let Err(e) = finished {
+            self.full |= is_disk_full(&e);
+            return Err(e);
+        }
+        self.flush_writer()?;
+        if self.sync != SyncPolicy::Never {
+            self.sync_now()?;
+        }
+        Ok(())
+    }
+
+    fn flush_writer(&mut self) -> io::Result<()> {
+        if self.full {
+            return Err(refused());
+        }
+        let result = match &mut self.writer {
+            Encoder::Csv(writer) => writer.flush(),
+            Encoder::Arrow(writer) => writer.flush(),
+            Encoder::Parquet(writer) => writer.flush(),
+            #[cfg(feature = "hdf5")]
+            Encoder::Hdf5(writer) => writer.flush(),
+        };
+        if let Err(e) = &result {
+            self.full |= is_disk_full(e);
+        }
+        result
+    }
+
+    fn sync_now(&mut self) -> io::Result<()> {
+        let target = match &self.writer {
+            Encoder::Csv(writer) => Some(writer.get_ref()),
+            Encoder::Arrow(writer) => Some(writer.get_ref()),
+            // Synced as each row group is written
+            Encoder::Parquet(_) => None,
+            #[cfg(feature = "hdf5")]
+            Encoder::Hdf5(writer) => Some(writer.get_ref()),
+        };
+        if let Some(Target::File(file) | Target::Tracked(file, _)) = target {
+            file.sync_data()?;
+        }
+        self.last_sync = Instant::now();
+        self.marker = false;
+        self.unsynced = false;
+        Ok(())
+    }
+}
+
+/// With `--sync interval`, syncs the output and rejects file once the
+/// interval is up after rows were flushed, so the last rows before the
+/// device goes quiet aren't left in the OS cache until the next row.
+pub fn spawn_interval_sync(interval: Duration, shared: Arc<Shared>) {
+    // Checked a few times per interval, so rows are synced at most a quarter
+    // of it late
+    let tick = (interval / 4).max(Duration::from_millis(10));
+    thread::spawn(move || {
+        // Reported once until syncing works again
+        let mut failing = false;
+        loop {
+            thread::sleep(tick);
+            let mut failed = None;
+            for sink in [&shared.writer, &shared.rejects] {
+                if let Some(w) = sink.lock().unwrap().as_mut() {
+                    failed = w.sync_due().err().or(failed);
+                }
+            }
+            if let Some(e) = &failed {
+                if !failing {
+                    esay!("Failed to sync output to disk: {}", e);
+                }
+            }
+            failing = failed.is_some();
+        }
+    });
+}
+
+// A start row begins its run's group, named after the run number, a data row
+// is appended to it, and the other rows are its events, the cells after the
+// Timestamp kept as a line of CSV. Rows before the first start row have no
+// group to go to and are dropped.
+#[cfg(feature = "hdf5")]
+fn write_hdf5(writer: &mut hdf5::FileWriter<Target>, record: &[&[u8]]) -> io::Result<()> {
+    use collect_acc_data::logfile::{utc_millis, Kind, LEADING_HEADERS};
+
+    let cell = |i: usize| String::from_utf8_lossy(record.get(i).copied().unwrap_or_default()).into_owned();
+    let kind = Kind::parse(&cell(0));
+    match kind {
+        // A run carried over from the previous file starts its group here
+        Kind::Start | Kind::Continued if kind == Kind::Start || !writer.has_group() => {
+            let run: i64 = cell(2).strip_prefix("run ").and_then(|run| run.parse().ok()).unwrap_or(0);
+            let attributes = [
+                ("run", hdf5::Value::Int(run)),
+                ("run_id", hdf5::Value::Text(cell(3))),
+                ("started", hdf5::Value::Text(cell(1))),
+            ];
+            writer.start_group(&format!("run_{:04}", run), &attributes)
+        }
+        Kind::Data => {
+            let time = utc_millis(&cell(1)).unwrap_or(0);
+            writer.push_row(time, record.get(LEADING_HEADERS.len()..).unwrap_or_default())
+        }
+        _ => {
+            let time = utc_millis(&cell(1)).unwrap_or(0);
+            let mut rest = record.get(2..).unwrap_or_default();
+            while let [cells @ .., b""] = rest {
+                rest = cells;
+            }
+            let mut text = String::new();
+            if !rest.is_empty() {
+                let mut line = WriterBuilder::new().flexible(true).from_writer(Vec::new());
+                line.write_record(rest)?;
+                let line = line.into_inner().map_err(|e| e.into_error())?;
+                text = String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string();
+            }
+            writer.push_event(time, &cell(0), &text)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A CSV sink on a new file in `dir`
+    fn sink_in(dir: &tempfile::TempDir, dialect: CsvDialect, sync: SyncPolicy) -> (CsvSink, std::path::PathBuf) {
+        let path = dir.path().join("out.csv");
+        let file = File::create(&path).unwrap();
+        let writer = dialect.builder().flexible(true).from_writer(Target::File(file));
+        (CsvSink::new(writer, 4, dialect, sync), path)
+    }
+
+    fn dialect() -> CsvDialect {
+        CsvDialect::parse("necessary", "lf").unwrap()
+    }
+
+    #[cfg(feature = "hdf5")]
+    #[test]
+    fn hdf5_keeps_the_other_rows_as_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.h5");
+        let fields = vec![hdf5::Field { name: "ax".into(), number_type: Some(hdf5::NumberType::Float(64)) }];
+        let mut writer = hdf5::FileWriter::new(Target::File(File::create(&path).unwrap()), fields, &[], 4).unwrap();
+        let rows: [&[&str]; 5] = [
+            &["mark", "2025-01-14 10:21:06", "", "", "before"],
+            &["start", "2025-01-14 10:21:07", "run 2", "id"],
+            &["data", "2025-01-14 10:21:07.500", "", "", "1.5"],
+            &["mark", "2025-01-14 10:21:08", "", "", "a, b", ""],
+            &["stop", "2025-01-14 10:21:09", "end", "", "", ""],
+        ];
+        for row in rows {
+            let record: Vec<&[u8]> = row.iter().map(|cell| cell.as_bytes()).collect();
+            write_hdf5(&mut writer, &record).unwrap();
+        }
+        writer.finish().unwrap();
+        let file = hdf5_reader::Hdf5File::from_vec(std::fs::read(&path).unwrap()).unwrap();
+        let kinds = file.dataset("/run_0002/events/Type").unwrap().read_strings().unwrap();
+        let texts = file.dataset("/run_0002/events/Text").unwrap().read_strings().unwrap();
+        assert_eq!(kinds, ["mark", "stop"]);
+        assert_eq!(texts, [",,\"a, b\"", "end"]);
+        let times = file.dataset("/run_0002/events/Timestamp").unwrap().read_array::<i64>().unwrap();
+        let utc = |text| collect_acc_data::logfile::utc_millis(text).unwrap();
+        assert_eq!(times.iter().copied().collect::<Vec<_>>(), [utc("2025-01-14 10:21:08"), utc("2025-01-14 10:21:09")]);
+        let data = file.dataset("/run_0002/Timestamp").unwrap().read_array::<i64>().unwrap();
+        assert_eq!(data.iter().copied().collect::<Vec<_>>(), [utc("2025-01-14 10:21:07.500")]);
+    }
+
+    #[test]
+    fn interval_sync_waits_for_the_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut sink, _) = sink_in(&dir, dialect(), SyncPolicy::Interval(Duration::from_millis(50)));
+        sink.write_record(["data", "2025-01-14 10:21:07", "", "", "1"]).unwrap();
+        sink.flush().unwrap();
+        assert!(sink.unsynced);
+        // Not yet due
+        sink.sync_due().unwrap();
+        assert!(sink.unsynced);
+        std::thread::sleep(Duration::from_millis(60));
+        // Due without another row
+        sink.sync_due().unwrap();
+        assert!(!sink.unsynced);
+    }
+
+    #[test]
+    fn markers_sync_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut sink, _) = sink_in(&dir, dialect(), SyncPolicy::Interval(Duration::from_secs(3600)));
+        sink.write_record(["data", "2025-01-14 10:21:07", "", "", "1"]).unwrap();
+        sink.flush().unwrap();
+        assert!(sink.unsynced);
+        sink.write_record(["start", "2025-01-14 10:21:08", "run 1", "id"]).unwrap();
+        sink.flush_marker().unwrap();
+        assert!(!sink.unsynced);
+    }
+
+    #[test]
+    fn other_policies_leave_nothing_due() {
+        let dir = tempfile::tempdir().unwrap();
+        for sync in [SyncPolicy::Never, SyncPolicy::EveryFlush] {
+            let (mut sink, _) = sink_in(&dir, dialect(), sync);
+            sink.write_record(["data", "2025-01-14 10:21:07", "", "", "1"]).unwrap();
+            sink.flush().unwrap();
+            assert_eq!(sink.unsynced, sync == SyncPolicy::Never);
+            sink.sync_due().unwrap();
+            assert_eq!(sink.unsynced, sync == SyncPolicy::Never);
+        }
+    }
+
+    // The bytes of a file holding a header and `record`, in this dialect
+    fn written(quote: &str, terminator: &str, record: &[&str]) -> Result<Vec<u8>, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let dialect = CsvDialect::parse(quote, terminator).unwrap();
+        let (mut sink, path) = sink_in(&dir, dialect, SyncPolicy::Never);
+        sink.write_record(["Type", "Timestamp", "Run/End", "RunId"]).unwrap();
+        let result = sink.write_record(record).map_err(|e| e.to_string());
+        sink.flush().unwrap();
+        result.map(|()| std::fs::read(path).unwrap())
+    }
+
+    const TRICKY: [&str; 6] = ["data", "2025-01-14 10:21:07", "", "", "a,b", "say \"hi\""];
+
+    #[test]
+    fn necessary_quotes_only_what_needs_it() {
+        assert_eq!(
+            written("necessary", "lf", &TRICKY).unwrap(),
+            b"Type,Timestamp,Run/End,RunId\ndata,2025-01-14 10:21:07,,,\"a,b\",\"say \"\"hi\"\"\"\n"
+        );
+    }
+
+    #[test]
+    fn always_quotes_every_field() {
+        assert_eq!(
+            written("always", "crlf", &TRICKY).unwrap(),
+            b"\"Type\",\"Timestamp\",\"Run/End\",\"RunId\"\r\n\"data\",\"2025-01-14 10:21:07\",\"\",\"\",\"a,b\",\"say \"\"hi\"\"\"\r\n"
+        );
+    }
+
+    #[test]
+    fn never_refuses_what_would_need_quotes() {
+        let error = written("never", "lf", &TRICKY).unwrap_err();
+        assert!(error.contains("--quote-style never"), "{}", error);
+        for field in ["a,b", "say \"hi\"", "two\nlines", "cr\r"] {
+            assert!(written("never", "lf", &["data", "t", "", "", field]).is_err(), "{:?}", field);
+        }
+        assert_eq!(
+            written("never", "crlf", &["data", "2025-01-14 10:21:07", "", "", "a b", "-2.45"]).unwrap(),
+            b"Type,Timestamp,Run/End,RunId\r\ndata,2025-01-14 10:21:07,,,a b,-2.45\r\n"
+        );
+    }
+
+    #[test]
+    fn rows_are_padded_and_trimmed_to_the_header() {
+        assert_eq!(
+            written("necessary", "lf", &["start", "2025-01-14 10:21:07"]).unwrap(),
+            b"Type,Timestamp,Run/End,RunId\nstart,2025-01-14 10:21:07,,\n"
+        );
+        assert_eq!(
+            written("necessary", "lf", &["data", "t", "", "", "1", "", ""]).unwrap(),
+            b"Type,Timestamp,Run/End,RunId\ndata,t,,,1\n"
+        );
+    }
+
+    #[test]
+    fn parses_dialects() {
+        assert!(CsvDialect::parse("sometimes", "lf").is_err());
+        assert!(CsvDialect::parse("always", "cr").is_err());
+        assert_eq!(CsvDialect::parse("always", "crlf").unwrap().line_end(), "\r\n");
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!(SyncPolicy::parse("never").unwrap(), SyncPolicy::Never);
+        assert_eq!(SyncPolicy::parse("every-flush").unwrap(), SyncPolicy::EveryFlush);
+        assert_eq!(SyncPolicy::parse("interval:2.5").unwrap(), SyncPolicy::Interval(Duration::from_millis(2500)));
+        for bad in ["interval:0", "interval:", "always", "interval:x"] {
+            assert!(SyncPolicy::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
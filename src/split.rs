@@ -0,0 +1,307 @@
+//! `split`: breaks a multi-run output file into one file per run.
+//!
+//! Runs are found by the library's run segmentation, the same as `analyze`,
+//! so both agree on where a run begins and ends, including a truncated last
+//! run. Every file gets the source's metadata, header and units rows
+//! followed by the run's rows copied unchanged; the start and stop rows are
+//! dropped unless `--keep-markers` is given. Files are named after the run
+//! number and start time, e.g. `run_0003_2025-01-14_102107.csv`, and existing
+//! files are never overwritten.
+
+use collect_acc_data::logfile::{parse_timestamp, LogReader, Row};
+use collect_acc_data::runs::{self, Segment};
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+pub struct SplitOptions {
+    pub out_dir: PathBuf,
+    // Copy each run's start and stop rows into its file
+    pub keep_markers: bool,
+    // Don't write files for runs without data rows
+    pub skip_empty: bool,
+}
+
+/// What a split produced.
+#[derive(Debug, Default)]
+pub struct Report {
+    // Files written, with the number of data rows in each
+    pub files: Vec<(PathBuf, u64)>,
+    pub skipped_empty: u64,
+    // Runs that ended without a stop row
+    pub truncated: u64,
+    // Data rows outside any run, which go nowhere
+    pub rows_outside_runs: u64,
+}
+
+// Where a run's file is being written
+struct RunFile {
+    path: PathBuf,
+    rows: u64,
+    // Rows held back until the run is known to have data (for --skip-empty)
+    pending: Vec<csv::StringRecord>,
+    writer: Option<csv::Writer<File>>,
+}
+
+// What a file cut from another starts with
+pub struct Layout<'a> {
+    pub preamble: &'a [csv::StringRecord],
+    pub header: &'a csv::StringRecord,
+    pub units: Option<&'a csv::StringRecord>,
+    pub crlf: bool,
+}
+
+impl RunFile {
+    fn push(&mut self, record: csv::StringRecord, layout: &Layout) -> Result<(), String> {
+        match self.writer.as_mut() {
+            Some(writer) => writer
+                .write_record(&record)
+                .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e)),
+            None => {
+                self.pending.push(record);
+                if self.rows > 0 {
+                    self.open(layout)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Creates the file with metadata and header and writes the held-back rows
+    fn open(&mut self, layout: &Layout) -> Result<(), String> {
+        let mut writer = create(&self.path, layout)?;
+        for record in self.pending.drain(..) {
+            writer
+                .write_record(&record)
+                .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        }
+        self.writer = Some(writer);
+        Ok(())
+    }
+
+    fn close(mut self, layout: &Layout) -> Result<(), String> {
+        if self.writer.is_none() {
+            self.open(layout)?;
+        }
+        let mut writer = self.writer.take().expect("The file was just opened");
+        writer.flush().map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))
+    }
+}
+
+/// Splits the output file at `input` into `options.out_dir`.
+pub fn run(input: &Path, options: &SplitOptions) -> Result<Report, String> {
+    let open = || File::open(input).map_err(|e| format!("Failed to open {}: {}", input.display(), e));
+    // Keep the source's line endings
+    let crlf = uses_crlf(input)?;
+    std::fs::create_dir_all(&options.out_dir)
+        .map_err(|e| format!("Failed to create {}: {}", options.out_dir.display(), e))?;
+
+    let mut report = Report::default();
+    let mut segments = runs::segment(LogReader::new(BufReader::new(open()?)));
+    // Metadata rows (or comment lines) to copy to the top of every file
+    let mut preamble: Vec<csv::StringRecord> = Vec::new();
+    let mut header = csv::StringRecord::new();
+    let mut units = None;
+    let mut names = HashSet::new();
+    let mut current: Option<RunFile> = None;
+    while let Some(segment) = segments.next() {
+        let segment = segment.map_err(|e| format!("{}: {}", input.display(), e))?;
+        if header.is_empty() {
+            header = segments.inner().header().cloned().unwrap_or_default();
+            units = segments.inner().units().cloned();
+        }
+        let layout = Layout {
+            preamble: &preamble,
+            header: &header,
+            units: units.as_ref(),
+            crlf,
+        };
+        match segment {
+            Segment::Outside(line) => match line.row {
+                Row::Meta { .. } => preamble.push(line.record),
+                Row::Data { .. } => report.rows_outside_runs += 1,
+                _ => {}
+            },
+            Segment::Begin(line) => {
+                let (Row::Start { timestamp, run, .. } | Row::Continued { timestamp, run, .. }) = &line.row else { continue };
+                let mut run = RunFile {
+                    path: options.out_dir.join(file_name(*run, timestamp, &mut names)),
+                    rows: 0,
+                    pending: Vec::new(),
+                    writer: None,
+                };
+                if options.keep_markers {
+                    run.push(line.record, &layout)?;
+                }
+                current = Some(run);
+            }
+            Segment::Row(line) => {
+                let Some(run) = current.as_mut() else { continue };
+                if let Row::Data { .. } = line.row {
+                    run.rows += 1;
+                }
+                run.push(line.record, &layout)?;
+            }
+            Segment::End(stop) => {
+                let Some(mut run) = current.take() else { continue };
+                match stop {
+                    Some(line) if options.keep_markers => run.push(line.record, &layout)?,
+                    Some(_) => {}
+                    None => report.truncated += 1,
+                }
+                if run.rows == 0 && options.skip_empty {
+                    report.skipped_empty += 1;
+                    continue;
+                }
+                let (path, rows) = (run.path.clone(), run.rows);
+                run.close(&layout)?;
+                report.files.push((path, rows));
+            }
+        }
+    }
+    Ok(report)
+}
+
+// `run_0003_2025-01-14_102107.csv`, with a suffix if that name is taken
+fn file_name(run: Option<i64>, timestamp: &str, names: &mut HashSet<String>) -> String {
+    let number = run.map_or("unknown".to_string(), |run| format!("{:04}", run));
+    let started = parse_timestamp(timestamp)
+        .map_or("undated".to_string(), |time| time.format("%Y-%m-%d_%H%M%S").to_string());
+    let base = format!("run_{}_{}", number, started);
+    let mut name = format!("{}.csv", base);
+    let mut copy = 2;
+    while !names.insert(name.clone()) {
+        name = format!("{}_{}.csv", base, copy);
+        copy += 1;
+    }
+    name
+}
+
+/// Whether the output file at `input` ends its lines with CRLF, judging by
+/// its first 64 KiB.
+pub fn uses_crlf(input: &Path) -> Result<bool, String> {
+    let mut head = Vec::new();
+    File::open(input)
+        .map_err(|e| format!("Failed to open {}: {}", input.display(), e))?
+        .take(64 * 1024)
+        .read_to_end(&mut head)
+        .map_err(|e| format!("Failed to read {}: {}", input.display(), e))?;
+    Ok(head.windows(2).any(|pair| pair == b"\r\n"))
+}
+
+/// Creates a file with the metadata, header and units rows of `layout`;
+/// fails rather than replace an existing file.
+pub fn create(path: &Path, layout: &Layout) -> Result<csv::Writer<File>, String> {
+    let write_failed = |e: std::io::Error| format!("Failed to write {}: {}", path.display(), e);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    let line_end = if layout.crlf { "\r\n" } else { "\n" };
+    // Comment lines aren't CSV records; they go in as they were
+    let (comments, rows): (Vec<_>, Vec<_>) =
+        layout.preamble.iter().partition(|record| record.get(0).is_some_and(|first| first.starts_with('#')));
+    for comment in comments {
+        let text = comment.iter().collect::<Vec<_>>().join(",");
+        write!(file, "{}{}", text, line_end).map_err(write_failed)?;
+    }
+    let mut writer = csv::WriterBuilder::new()
+        .flexible(true)
+        .terminator(if layout.crlf { csv::Terminator::CRLF } else { csv::Terminator::Any(b'\n') })
+        .from_writer(file);
+    for record in rows.into_iter().chain(std::iter::once(layout.header)).chain(layout.units) {
+        writer
+            .write_record(record)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    Ok(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "meta,port,/dev/ttyUSB0,,
+Type,Timestamp,Run/End,RunId,x,y
+start,2025-01-14 10:21:07,run 0,a,,
+data,2025-01-14 10:21:08,,,1,2
+stop,2025-01-14 10:21:09,end of run,a,,
+start,2025-01-14 10:22:00,run 1,b,,
+stop,2025-01-14 10:22:01,end of run,b,,
+start,2025-01-14 10:23:00,run 2,c,,
+data,2025-01-14 10:23:01,,,3,4
+data,2025-01-14 10:23:02,,,5,6
+";
+
+    fn split(keep_markers: bool, skip_empty: bool) -> (tempfile::TempDir, Report) {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("output.csv");
+        std::fs::write(&input, FILE).unwrap();
+        let options = SplitOptions {
+            out_dir: dir.path().join("runs"),
+            keep_markers,
+            skip_empty,
+        };
+        let report = run(&input, &options).unwrap();
+        (dir, report)
+    }
+
+    fn names(report: &Report) -> Vec<(String, u64)> {
+        report
+            .files
+            .iter()
+            .map(|(path, rows)| (path.file_name().unwrap().to_string_lossy().into_owned(), *rows))
+            .collect()
+    }
+
+    #[test]
+    fn every_run_gets_a_file_with_the_header() {
+        let (dir, report) = split(false, false);
+        assert_eq!(
+            names(&report),
+            [
+                ("run_0000_2025-01-14_102107.csv".to_string(), 1),
+                ("run_0001_2025-01-14_102200.csv".to_string(), 0),
+                ("run_0002_2025-01-14_102300.csv".to_string(), 2)
+            ]
+        );
+        assert_eq!(report.truncated, 1);
+        let last = std::fs::read_to_string(dir.path().join("runs/run_0002_2025-01-14_102300.csv")).unwrap();
+        assert_eq!(
+            last,
+            "meta,port,/dev/ttyUSB0,,\nType,Timestamp,Run/End,RunId,x,y\ndata,2025-01-14 10:23:01,,,3,4\ndata,2025-01-14 10:23:02,,,5,6\n"
+        );
+    }
+
+    #[test]
+    fn markers_are_kept_on_request() {
+        let (dir, _) = split(true, false);
+        let first = std::fs::read_to_string(dir.path().join("runs/run_0000_2025-01-14_102107.csv")).unwrap();
+        let types: Vec<&str> = first.lines().map(|line| line.split(',').next().unwrap()).collect();
+        assert_eq!(types, ["meta", "Type", "start", "data", "stop"]);
+    }
+
+    #[test]
+    fn empty_runs_can_be_skipped() {
+        let (dir, report) = split(false, true);
+        assert_eq!(report.files.len(), 2);
+        assert_eq!(report.skipped_empty, 1);
+        assert!(!dir.path().join("runs/run_0001_2025-01-14_102200.csv").exists());
+    }
+
+    #[test]
+    fn existing_files_are_not_overwritten() {
+        let (dir, _) = split(false, false);
+        let options = SplitOptions {
+            out_dir: dir.path().join("runs"),
+            keep_markers: false,
+            skip_empty: false,
+        };
+        let error = run(&dir.path().join("output.csv"), &options).unwrap_err();
+        assert!(error.starts_with("Failed to create"), "{}", error);
+    }
+}
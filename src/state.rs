@@ -0,0 +1,55 @@
+//! Run numbering that survives restarts.
+//!
+//! The number the next run will get is kept in a small JSON file, by default
+//! `.serial_logger_state.json` in the output directory, so a restart (or a
+//! crash) doesn't start again at run 0 next to files from earlier runs. It is
+//! rewritten through a temporary file and a rename on every start. A missing
+//! file starts at 0; an unreadable one does too, with a warning.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const FILE_NAME: &str = ".serial_logger_state.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    next_run: i64,
+}
+
+/// Default state file for an output template: in the directory part of the
+/// template before any placeholder.
+pub fn default_path(template: &str) -> PathBuf {
+    crate::template::fixed_dir(template).join(FILE_NAME)
+}
+
+/// Number the next run should get according to the state file.
+pub fn load(path: &Path) -> i64 {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return 0,
+        Err(e) => {
+            esay!("Warning: Could not read run state {}: {}; numbering runs from 0.", path.display(), e);
+            return 0;
+        }
+    };
+    match serde_json::from_str::<State>(&text) {
+        Ok(state) if state.next_run >= 0 => state.next_run,
+        Ok(_) | Err(_) => {
+            esay!("Warning: Run state {} is corrupted; numbering runs from 0.", path.display());
+            0
+        }
+    }
+}
+
+/// Records the number the next run will get.
+pub fn save(path: &Path, next_run: i64) -> Result<(), String> {
+    let fail = |e: std::io::Error| format!("Failed to save run state {}: {}", path.display(), e);
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent).map_err(fail)?;
+    }
+    let json = serde_json::to_string(&State { next_run }).expect("Run state always serializes");
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, json + "\n")
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(fail)
+}
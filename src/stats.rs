@@ -0,0 +1,89 @@
+use serde::Serialize;
+
+/// Running min/max/mean/standard deviation of one numeric column.
+#[derive(Debug, Clone, Default)]
+pub struct FieldStats {
+    count: u64,
+    min: f64,
+    max: f64,
+    mean: f64,
+    // Sum of squared differences from the mean (Welford's method)
+    m2: f64,
+    last: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldSummary {
+    pub name: String,
+    pub count: u64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+    // Population standard deviation
+    pub stddev: Option<f64>,
+}
+
+impl FieldStats {
+    pub fn add(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.count += 1;
+        self.last = value;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    pub fn summary(&self, name: &str) -> FieldSummary {
+        let has_values = self.count > 0;
+        FieldSummary {
+            name: name.to_string(),
+            count: self.count,
+            min: has_values.then_some(self.min),
+            max: has_values.then_some(self.max),
+            mean: has_values.then_some(self.mean),
+            stddev: has_values.then(|| (self.m2 / self.count as f64).sqrt()),
+        }
+    }
+}
+
+/// Statistics for every payload field of the current run. Fields that aren't
+/// numbers are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    fields: Vec<FieldStats>,
+}
+
+impl RunStats {
+    pub fn add_row(&mut self, values: &[Option<f64>]) {
+        if self.fields.len() < values.len() {
+            self.fields.resize(values.len(), FieldStats::default());
+        }
+        for (stats, value) in self.fields.iter_mut().zip(values) {
+            if let Some(value) = value {
+                stats.add(*value);
+            }
+        }
+    }
+
+    // Latest value of each field, None for fields without numeric values yet
+    pub fn last_values(&self) -> Vec<Option<f64>> {
+        self.fields.iter().map(|stats| (stats.count > 0).then_some(stats.last)).collect()
+    }
+
+    pub fn summaries(&self, names: &[&str]) -> Vec<FieldSummary> {
+        self.fields
+            .iter()
+            .enumerate()
+            .map(|(i, stats)| stats.summary(names.get(i).copied().unwrap_or("field")))
+            .collect()
+    }
+}
@@ -0,0 +1,204 @@
+//! Live status line at the bottom of the terminal.
+//!
+//! The bottom row is kept out of the terminal's scroll region, so messages
+//! and the prompt scroll above it while it is redrawn in place once a second
+//! with the input rate, accepted records per second, the rows of the current
+//! run and how long ago data last arrived. The counters are atomics in
+//! `Shared`, updated by the serial thread. It is only shown when stdout is a
+//! terminal whose size can be read and isn't carrying the JSON event stream;
+//! `clear` gives the row back before the program exits.
+
+use std::{
+    io::{IsTerminal, Write},
+    sync::{atomic::Ordering, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::memory;
+use crate::outage;
+use crate::session::Shared;
+use crate::units::format_size;
+
+// How often the counters are turned into rates and the line redrawn
+const REFRESH: Duration = Duration::from_secs(1);
+// How often the terminal size is checked between redraws
+const RESIZE_POLL: Duration = Duration::from_millis(250);
+
+const SAVE_CURSOR: &str = "\x1b7";
+const RESTORE_CURSOR: &str = "\x1b8";
+const CLEAR_LINE: &str = "\x1b[2K";
+
+// Terminal rows and columns the scroll region was set up for; None once the
+// status line is off or has been cleared
+static LAYOUT: Mutex<Option<(u16, u16)>> = Mutex::new(None);
+
+/// Reserves the bottom row and starts redrawing it, unless `disabled`
+/// (--no-statusline) or stdout isn't a suitable terminal. Must run before the
+/// prompt is first shown.
+pub fn spawn(shared: Arc<Shared>, disabled: bool) {
+    if disabled || crate::events::json_mode() || !std::io::stdout().is_terminal() {
+        return;
+    }
+    let Some(size) = terminal_size() else { return };
+    {
+        let mut layout = LAYOUT.lock().unwrap();
+        // Scroll everything up a line if the cursor is on the row being taken
+        let mut out = String::from("\n\x1b[1A");
+        out.push_str(&set_region(size));
+        write_out(&out);
+        *layout = Some(size);
+    }
+    // A panic mustn't leave the terminal with a shortened scroll region
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        clear();
+        previous(info);
+    }));
+    thread::spawn(move || {
+        let mut last = Sample::take(&shared);
+        let mut line = describe(&shared, &last, &last);
+        loop {
+            thread::sleep(RESIZE_POLL);
+            if last.at.elapsed() >= REFRESH {
+                let now = Sample::take(&shared);
+                line = describe(&shared, &last, &now);
+                last = now;
+            }
+            if !draw(&line) {
+                return;
+            }
+        }
+    });
+}
+
+/// Removes the status line and restores the normal scroll region; nothing
+/// is drawn afterwards.
+pub fn clear() {
+    let mut layout = LAYOUT.lock().unwrap();
+    let Some((rows, _)) = layout.take() else { return };
+    write_out(&format!(
+        "{}\x1b[r\x1b[{};1H{}{}",
+        SAVE_CURSOR, rows, CLEAR_LINE, RESTORE_CURSOR
+    ));
+}
+
+// Redraws the line, first adapting the scroll region if the terminal was
+// resized. Returns false once the status line has been cleared.
+fn draw(line: &str) -> bool {
+    let mut layout = LAYOUT.lock().unwrap();
+    let Some(old) = *layout else { return false };
+    let size = terminal_size().unwrap_or(old);
+    let mut out = String::new();
+    if size != old {
+        // A taller terminal would leave the old line behind mid-screen
+        if old.0 < size.0 {
+            out.push_str(&format!("{}\x1b[{};1H{}{}", SAVE_CURSOR, old.0, CLEAR_LINE, RESTORE_CURSOR));
+        }
+        out.push_str(&set_region(size));
+        *layout = Some(size);
+    }
+    let (rows, columns) = size;
+    // One column short of the edge, so the terminal never wraps the line
+    let text: String = line.chars().take(usize::from(columns).saturating_sub(1)).collect();
+    out.push_str(&format!("{}\x1b[{};1H{}{}{}", SAVE_CURSOR, rows, CLEAR_LINE, text, RESTORE_CURSOR));
+    write_out(&out);
+    true
+}
+
+// Limits scrolling to all rows but the last; setting the region moves the
+// cursor, so it is saved and restored around it
+fn set_region((rows, _): (u16, u16)) -> String {
+    format!("{}\x1b[1;{}r{}", SAVE_CURSOR, rows - 1, RESTORE_CURSOR)
+}
+
+// One write, so the sequence isn't interleaved with the prompt's output
+fn write_out(text: &str) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(text.as_bytes());
+    let _ = stdout.flush();
+}
+
+// Counter values at one point in time; rates are the difference of two
+struct Sample {
+    at: Instant,
+    bytes: u64,
+    rows: u64,
+}
+
+impl Sample {
+    fn take(shared: &Shared) -> Sample {
+        Sample {
+            at: Instant::now(),
+            bytes: shared.bytes_received.load(Ordering::Relaxed),
+            rows: shared.rows_written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// e.g. `/dev/ttyUSB0 | 11.5 KB/s | 98 rec/s | run 3: 1204 rows | last data 0.2s ago`
+fn describe(shared: &Shared, last: &Sample, now: &Sample) -> String {
+    let secs = now.at.duration_since(last.at).as_secs_f64().max(f64::EPSILON);
+    let bytes_per_sec = (now.bytes - last.bytes) as f64 / secs;
+    let rows_per_sec = (now.rows - last.rows) as f64 / secs;
+    let run = if shared.recording.load(Ordering::Relaxed) {
+        format!(
+            "run {}: {} rows",
+            shared.current_run.load(Ordering::Relaxed),
+            shared.run_rows.load(Ordering::Relaxed)
+        )
+    } else {
+        "not recording".to_string()
+    };
+    let last_data = match *shared.last_data.lock().unwrap() {
+        None => "no data yet".to_string(),
+        Some(at) => format!("last data {:.1}s ago", at.elapsed().as_secs_f64()),
+    };
+    let mut line = format!(
+        "{} | {}/s | {:.0} rec/s | {} | {}",
+        shared.serial_port.lock().unwrap(),
+        format_size(bytes_per_sec as u64),
+        rows_per_sec,
+        run,
+        last_data
+    );
+    let rejects = shared.rejects_written.load(Ordering::Relaxed);
+    if rejects > 0 {
+        line.push_str(&format!(" | {} rejected", rejects));
+    }
+    let duplicates = shared.duplicates.load(Ordering::Relaxed);
+    if duplicates > 0 {
+        line.push_str(&format!(" | {} duplicates", duplicates));
+    }
+    let unchanged = shared.unchanged.load(Ordering::Relaxed);
+    if unchanged > 0 {
+        line.push_str(&format!(" | {} unchanged", unchanged));
+    }
+    let late = shared.late_rows.load(Ordering::Relaxed);
+    if late > 0 {
+        line.push_str(&format!(" | {} late", late));
+    }
+    let buffered = memory::total_used();
+    if buffered > 0 {
+        line.push_str(&format!(" | {} buffered", format_size(buffered as u64)));
+    }
+    if outage::failing() {
+        line = format!("ERROR: output failing, {} rows held | {}", outage::held(), line);
+    }
+    line
+}
+
+#[cfg(unix)]
+fn terminal_size() -> Option<(u16, u16)> {
+    // SAFETY: TIOCGWINSZ only fills in the winsize passed to it
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    // A single row leaves nothing to scroll
+    (ok && size.ws_row > 1 && size.ws_col > 0).then_some((size.ws_row, size.ws_col))
+}
+
+// Not available without the Windows console API; the status line stays off
+#[cfg(not(unix))]
+fn terminal_size() -> Option<(u16, u16)> {
+    None
+}
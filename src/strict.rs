@@ -0,0 +1,103 @@
+//! Refusing questionable data with `--strict`, for qualification runs.
+//!
+//! Each check turns a condition the logger otherwise warns about and carries
+//! on from into a fatal error: `fields` (a data line with the wrong number of
+//! fields), `types` (a field that doesn't fit its `--types` type), `frames` (a
+//! bad binary frame or COBS packet, or a payload that didn't decode),
+//! `reconnect` (the serial port was lost) and `drops` (rows dropped because
+//! the output couldn't be written). Only what happens while recording counts.
+//!
+//! The serial thread notes the first failure in `Shared`; a thread of its own
+//! picks it up within a fraction of a second, stops the run with the failure
+//! in its stop row, runs `--on-error`, emits `strict_failure` and ends the
+//! program, which exits with its own exit code.
+
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::events::{self, Event};
+use crate::outage;
+use crate::serial;
+use crate::session::Session;
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A condition `--strict` makes fatal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Check {
+    Fields,
+    Types,
+    Frames,
+    Reconnect,
+    Drops,
+}
+
+const ALL: [Check; 5] = [Check::Fields, Check::Types, Check::Frames, Check::Reconnect, Check::Drops];
+
+impl Check {
+    /// Parses a comma-separated list such as `fields,types`; `all` is every
+    /// check.
+    pub fn parse_list(text: &str) -> Result<Vec<Check>, String> {
+        let mut checks = Vec::new();
+        for name in text.split(',').map(str::trim) {
+            let named: Vec<Check> = ALL.into_iter().filter(|check| name == "all" || check.name() == name).collect();
+            if named.is_empty() {
+                return Err(format!(
+                    "Invalid --strict check '{}': expected all, fields, types, frames, reconnect or drops",
+                    name
+                ));
+            }
+            for check in named {
+                if !checks.contains(&check) {
+                    checks.push(check);
+                }
+            }
+        }
+        Ok(checks)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Check::Fields => "fields",
+            Check::Types => "types",
+            Check::Frames => "frames",
+            Check::Reconnect => "reconnect",
+            Check::Drops => "drops",
+        }
+    }
+}
+
+/// The checks as written in the session metadata, e.g. `fields,types`.
+pub fn describe(checks: &[Check]) -> String {
+    checks.iter().map(|check| check.name()).collect::<Vec<_>>().join(",")
+}
+
+/// Ends the session on the first failed check. `exit` ends the program once
+/// the run is stopped.
+pub fn spawn(session: Arc<Mutex<Session>>, exit: Box<dyn Fn() + Send>) {
+    let shared = Arc::clone(&session.lock().unwrap().shared);
+    if shared.strict.is_empty() {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        let lost = outage::lost();
+        if lost > 0 {
+            shared.fail_strict(Check::Drops, || format!("{} rows dropped while the output couldn't be written", lost));
+        }
+        let Some((check, detail)) = shared.strict_failure.lock().unwrap().clone() else { continue };
+        esay!("ERROR: --strict check {} failed ({}); stopping.", check.name(), detail);
+        events::emit(Event::StrictFailure {
+            check: check.name().to_string(),
+            detail: detail.clone(),
+        });
+        let port = shared.serial_port.lock().unwrap().clone();
+        serial::run_error_hook(&shared, &port, &format!("--strict {}: {}", check.name(), detail));
+        session.lock().unwrap().stop_for_strict();
+        say!("Exiting...");
+        exit()
+    });
+}
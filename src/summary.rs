@@ -0,0 +1,168 @@
+use serde::Serialize;
+
+use crate::alarm::AlarmCount;
+
+/// One finished run, as listed in the session summary.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub run: i64,
+    pub run_id: String,
+    pub file: String,
+    pub started: String,
+    pub stopped: String,
+    pub rows: u64,
+    pub duration_secs: f64,
+    // Ended before --min-run-duration (--short-run-action mark)
+    pub short: bool,
+    // Times each --alarm was raised in the run
+    pub alarms: Vec<AlarmCount>,
+}
+
+/// Closing report printed on exit and optionally written with `--summary-file`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSummary {
+    pub version: String,
+    pub started: String,
+    pub ended: String,
+    pub runtime_secs: f64,
+    pub runs: Vec<RunSummary>,
+    pub total_rows: u64,
+    pub bytes_written: u64,
+    pub lines_read: u64,
+    pub lines_filtered: u64,
+    // Lines of only whitespace dropped before matching
+    pub blank_lines: u64,
+    pub parse_failures: u64,
+    pub serial_errors: u64,
+    // Times the serial port was lost (one per outage, not per failed read)
+    pub disconnects: u64,
+    pub breaks: u64,
+    pub unquotable_rows: u64,
+    pub compute_errors: u64,
+    // Damaged or garbled binary frames skipped
+    pub bad_frames: u64,
+    // Hex or base64 payloads that didn't decode
+    pub bad_payloads: u64,
+    // Rows with a field that didn't fit its --types type
+    pub type_errors: u64,
+    // Rows dropped as repeats by --dedup-consecutive
+    pub duplicates: u64,
+    // Rows not written as unchanged by --log-on-change
+    pub unchanged: u64,
+    // Rows left out while a run was paused
+    pub paused_rows: u64,
+    // Lines dropped for being longer than --max-line-length
+    pub overflows: u64,
+    // Lines written out of order, later than the --ordered window
+    pub late_rows: u64,
+    // Lines written to the --rejects file, and where
+    pub rejects_written: u64,
+    pub rejects_file: Option<String>,
+    pub disk_full: bool,
+    // The --max-duration or --max-file-size cap that ended recording
+    pub cap_reached: Option<String>,
+    // The --strict check that failed and ended the session, and why
+    pub strict_failure: Option<String>,
+    // Times writing the output kept failing, and rows lost to it
+    pub output_outages: u64,
+    pub rows_lost: u64,
+    // --dry-run: output_files are where rows would have gone
+    pub dry_run: bool,
+    pub output_files: Vec<String>,
+}
+
+impl SessionSummary {
+    pub fn print(&self) {
+        say!("Session summary");
+        say!("  Runtime:         {:.1}s ({} to {})", self.runtime_secs, self.started, self.ended);
+        say!("  Runs:            {}", self.runs.len());
+        for run in &self.runs {
+            say!(
+                "    run {:<4} {:>8} rows  {:>8.1}s  {}{}",
+                run.run,
+                run.rows,
+                run.duration_secs,
+                run.file,
+                if run.short { "  (short)" } else { "" }
+            );
+            let raised: Vec<String> = run
+                .alarms
+                .iter()
+                .filter(|alarm| alarm.count > 0)
+                .map(|alarm| format!("{} x{}", alarm.label, alarm.count))
+                .collect();
+            if !raised.is_empty() {
+                say!("             alarms: {}", raised.join(", "));
+            }
+        }
+        say!("  Rows written:    {}", self.total_rows);
+        say!("  Bytes written:   {}", self.bytes_written);
+        say!("  Lines read:      {}", self.lines_read);
+        say!("  Lines filtered:  {}", self.lines_filtered);
+        if self.blank_lines > 0 {
+            say!("  Blank lines:     {}", self.blank_lines);
+        }
+        say!("  Parse failures:  {}", self.parse_failures);
+        say!("  Serial errors:   {}", self.serial_errors);
+        if self.disconnects > 0 {
+            say!("  Disconnects:     {}", self.disconnects);
+        }
+        if self.breaks > 0 {
+            say!("  Breaks received: {}", self.breaks);
+        }
+        if self.unquotable_rows > 0 {
+            say!("  Rows refused:    {} (would need quoting with --quote-style never)", self.unquotable_rows);
+        }
+        if self.compute_errors > 0 {
+            say!("  Compute errors:  {} (cells left blank)", self.compute_errors);
+        }
+        if self.bad_frames > 0 {
+            say!("  Bad frames:      {}", self.bad_frames);
+        }
+        if self.bad_payloads > 0 {
+            say!("  Bad payloads:    {}", self.bad_payloads);
+        }
+        if self.type_errors > 0 {
+            say!("  Type errors:     {}", self.type_errors);
+        }
+        if self.duplicates > 0 {
+            say!("  Duplicates:      {} (rows dropped as repeats)", self.duplicates);
+        }
+        if self.unchanged > 0 {
+            say!("  Unchanged:       {} (rows not written, --log-on-change)", self.unchanged);
+        }
+        if self.paused_rows > 0 {
+            say!("  Paused rows:     {} (rows not written while paused)", self.paused_rows);
+        }
+        if self.overflows > 0 {
+            say!("  Overflows:       {} (lines over --max-line-length dropped)", self.overflows);
+        }
+        if self.late_rows > 0 {
+            say!("  Late rows:       {} (written out of order, later than --ordered)", self.late_rows);
+        }
+        if let Some(path) = &self.rejects_file {
+            say!("  Rejects written: {} ({})", self.rejects_written, path);
+        }
+        if self.disk_full {
+            say!("  Disk full:       yes, recording was stopped when the output disk filled up");
+        }
+        if let Some(failure) = &self.strict_failure {
+            say!("  Strict failure:  {}", failure);
+        }
+        if let Some(cap) = &self.cap_reached {
+            say!("  Cap reached:     --{}, recording was stopped", cap);
+        }
+        if self.output_outages > 0 {
+            say!("  Output outages:  {} ({} rows lost)", self.output_outages, self.rows_lost);
+        }
+        if self.dry_run {
+            say!("  Dry run:         yes, nothing was written; the files are where output would have gone");
+        }
+        say!("  Output files:    {}", self.output_files.join(", "));
+    }
+
+    pub fn write_json(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).expect("Summary always serializes");
+        std::fs::write(path, json + "\n").map_err(|e| format!("Failed to write summary file {}: {}", path, e))
+    }
+}
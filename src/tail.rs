@@ -0,0 +1,112 @@
+//! The last rows written, for the `tail` command.
+//!
+//! The sink keeps a copy of every row it writes or holds through an outage,
+//! data and markers alike, in a ring of `--tail-buffer` rows; rows refused
+//! (`--quote-style never`, a full disk) never were written and aren't kept.
+//! The ring isn't cleared between runs, so `tail` after a stop shows the end
+//! of the last run with its stop row. Besides the row count it is bounded by
+//! `MAX_BYTES`, so a device sending very long lines can't make it grow
+//! without end, and with `--max-memory` by its capacity (see `memory`).
+
+use collect_acc_data::logfile::Kind;
+use std::collections::VecDeque;
+
+use crate::memory::{self, Buffer};
+
+/// The most `--tail-buffer` accepts.
+pub const MAX_ROWS: usize = 100_000;
+/// Rows shown by a bare `tail`.
+pub const DEFAULT_COUNT: usize = 10;
+
+/// Bytes kept at most, over all rows.
+pub const MAX_BYTES: usize = 16 * 1024 * 1024;
+// Widest a column is shown; longer cells are cut
+const MAX_WIDTH: usize = 24;
+
+pub struct Tail {
+    capacity: usize,
+    // The header of the file being written
+    columns: Vec<String>,
+    rows: VecDeque<Vec<String>>,
+    bytes: usize,
+}
+
+impl Tail {
+    pub fn new(capacity: usize) -> Tail {
+        Tail {
+            capacity,
+            columns: Vec::new(),
+            rows: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Names the columns after the header of a newly opened file.
+    pub fn set_columns(&mut self, columns: &[&str]) {
+        self.columns = columns.iter().map(|column| column.to_string()).collect();
+    }
+
+    pub fn push(&mut self, fields: &[&[u8]]) {
+        let row: Vec<String> = fields.iter().map(|field| String::from_utf8_lossy(field).into_owned()).collect();
+        self.bytes += memory::row_bytes(&row);
+        self.rows.push_back(row);
+        let max_bytes = MAX_BYTES.min(memory::budget(Buffer::Tail));
+        while self.rows.len() > self.capacity || (self.bytes > max_bytes && self.rows.len() > 1) {
+            if let Some(dropped) = self.rows.pop_front() {
+                self.bytes -= memory::row_bytes(&dropped);
+            }
+        }
+        memory::report(Buffer::Tail, self.bytes);
+    }
+
+    /// Prints the last `count` rows as a table; marker rows are flagged with
+    /// `>` in the margin. Columns that are empty in every row shown (RunId
+    /// between runs, the payload of marker-only stretches) are left out.
+    pub fn print(&self, count: usize) {
+        if self.rows.is_empty() {
+            say!("No rows written yet.");
+            return;
+        }
+        let rows: Vec<&Vec<String>> = self.rows.iter().skip(self.rows.len().saturating_sub(count)).collect();
+        let width = rows.iter().map(|row| row.len()).max().unwrap_or(0).max(self.columns.len());
+        let cell = |row: &Vec<String>, i: usize| row.get(i).map_or(String::new(), |text| cut(text));
+        let shown: Vec<usize> = (0..width).filter(|&i| rows.iter().any(|row| !cell(row, i).is_empty())).collect();
+        let name = |i: usize| self.columns.get(i).map_or(String::new(), |name| cut(name));
+        let widths: Vec<usize> = shown
+            .iter()
+            .map(|&i| rows.iter().map(|row| cell(row, i).chars().count()).max().unwrap_or(0).max(name(i).chars().count()))
+            .collect();
+        let line = |cells: Vec<String>| {
+            cells
+                .iter()
+                .zip(&widths)
+                .map(|(text, width)| format!("{:<width$}", text, width = width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        };
+        say!("  {}", line(shown.iter().map(|&i| name(i)).collect()));
+        for row in rows {
+            let margin = if is_data(row) { ' ' } else { '>' };
+            say!("{} {}", margin, line(shown.iter().map(|&i| cell(row, i)).collect()));
+        }
+    }
+}
+
+fn is_data(row: &[String]) -> bool {
+    row.first().is_some_and(|kind| Kind::parse(kind).is_data())
+}
+
+fn cut(text: &str) -> String {
+    if text.chars().count() <= MAX_WIDTH {
+        return text.to_string();
+    }
+    let mut cut: String = text.chars().take(MAX_WIDTH - 1).collect();
+    cut.push('…');
+    cut
+}
@@ -0,0 +1,49 @@
+//! Byte-level access to the serial input.
+//!
+//! Lines are split by a `BufReader` over the port, so by the time a line is
+//! handled its bytes have been regrouped and cleaned up, and the bytes of an
+//! unfinished line are still held back. A `Tap` sits between the port and
+//! that reader and shows every chunk to its observers exactly as the port
+//! returned it, before any line splitting or decoding. Observers move with
+//! the input from port to port, so they see the whole session.
+
+use serialport::SerialPort;
+use std::io::{self, Read};
+
+/// Something that sees the raw input, such as `--hexdump`.
+pub trait Observer: Send {
+    fn observe(&mut self, bytes: &[u8]);
+}
+
+/// The port, with observers of everything read from it.
+pub struct Tap {
+    port: Box<dyn SerialPort>,
+    observers: Vec<Box<dyn Observer>>,
+}
+
+impl Tap {
+    pub fn new(port: Box<dyn SerialPort>, observers: Vec<Box<dyn Observer>>) -> Tap {
+        Tap { port, observers }
+    }
+
+    pub fn port_mut(&mut self) -> &mut dyn SerialPort {
+        self.port.as_mut()
+    }
+
+    /// Removes the observers, to attach them to the next port.
+    pub fn take_observers(&mut self) -> Vec<Box<dyn Observer>> {
+        std::mem::take(&mut self.observers)
+    }
+}
+
+impl Read for Tap {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.port.read(buf)?;
+        if n > 0 {
+            for observer in &mut self.observers {
+                observer.observe(&buf[..n]);
+            }
+        }
+        Ok(n)
+    }
+}
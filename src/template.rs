@@ -0,0 +1,329 @@
+//! Placeholder expansion for output paths.
+//!
+//! Supported placeholders:
+//!
+//! | placeholder  | expands to                                          |
+//! |--------------|-----------------------------------------------------|
+//! | `{date}`     | `YYYY-MM-DD`                                        |
+//! | `{time}`     | `HHMMSS`                                            |
+//! | `{datetime}` | `YYYY-MM-DD_HHMMSS`                                 |
+//! | `{port}`     | port name without its directory, e.g. `ttyUSB0`     |
+//! | `{run}`      | run number, zero-padded to four digits (`--per-run`)|
+//! | `{name}`     | run label given to `start` (`--per-run`)            |
+//! | `{hostname}` | this machine's host name                            |
+//!
+//! `{{` and `}}` produce literal braces.
+//!
+//! A per-run file name can come out the same for two runs: `{name}` of runs
+//! started without a label, or `{run}` numbers starting over with
+//! `--no-state-file`. Such a run gets the first free `NAME-2.csv`,
+//! `NAME-3.csv`, ... instead of overwriting the earlier file.
+
+use chrono::{DateTime, Local};
+
+// Placeholders that only make sense when every run gets its own file
+const PER_RUN_PLACEHOLDERS: [&str; 2] = ["run", "name"];
+
+/// Values available when expanding a template.
+pub struct Context<'a> {
+    pub now: DateTime<Local>,
+    pub port: &'a str,
+    // Run number and label; `None` when expanding a session-long file name
+    pub run: Option<(i64, &'a str)>,
+}
+
+/// Whether the template contains `{run}` or `{name}`.
+pub fn uses_per_run_placeholders(template: &str) -> bool {
+    uses_any(template, &PER_RUN_PLACEHOLDERS)
+}
+
+/// Whether the template contains any of the placeholders `names`.
+pub fn uses_any(template: &str, names: &[&str]) -> bool {
+    placeholders(template).iter().any(|p| names.contains(&p.as_str()))
+}
+
+fn placeholders(template: &str) -> Vec<String> {
+    let mut found = Vec::new();
+    let mut rest = template.replace("{{", "").replace("}}", "");
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else { break };
+        found.push(rest[start + 1..start + len].to_string());
+        rest = rest[start + len + 1..].to_string();
+    }
+    found
+}
+
+/// Directory part of `template` before any placeholder, where files that
+/// belong to the whole output (run state, raw capture) are kept.
+pub fn fixed_dir(template: &str) -> std::path::PathBuf {
+    let fixed = template.split('{').next().unwrap_or("");
+    let dir = if fixed.ends_with(['/', '\\']) {
+        std::path::Path::new(fixed)
+    } else {
+        std::path::Path::new(fixed).parent().unwrap_or(std::path::Path::new(""))
+    };
+    dir.to_path_buf()
+}
+
+/// Expands every placeholder in `template`.
+pub fn expand(template: &str, ctx: &Context) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(format!("Unclosed placeholder in output path '{}'", template)),
+                    }
+                }
+                out.push_str(&value(&name, ctx, template)?);
+            }
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+fn value(name: &str, ctx: &Context, template: &str) -> Result<String, String> {
+    let per_run = || {
+        ctx.run.ok_or_else(|| {
+            format!(
+                "Placeholder {{{}}} in '{}' needs a file per run; add --per-run",
+                name, template
+            )
+        })
+    };
+    Ok(match name {
+        "date" => ctx.now.format("%Y-%m-%d").to_string(),
+        "time" => ctx.now.format("%H%M%S").to_string(),
+        "datetime" => ctx.now.format("%Y-%m-%d_%H%M%S").to_string(),
+        "port" => port_label(ctx.port),
+        "hostname" => sanitize(&gethostname::gethostname().to_string_lossy()),
+        "run" => format!("{:04}", per_run()?.0),
+        "name" => {
+            let label = sanitize(per_run()?.1);
+            if label.is_empty() {
+                "unnamed".to_string()
+            } else {
+                label
+            }
+        }
+        _ => return Err(format!("Unknown placeholder {{{}}} in output path '{}'", name, template)),
+    })
+}
+
+/// Short, file-name-safe form of a port name: `/dev/ttyUSB0` becomes
+/// `ttyUSB0`, `\\.\COM12` becomes `COM12`.
+pub fn port_label(port: &str) -> String {
+    let base = port.rsplit(['/', '\\']).next().unwrap_or(port);
+    sanitize(base)
+}
+
+/// Makes user-supplied text safe as a single path component: path separators
+/// and other characters that are unsafe in file names become `_`, so a run
+/// label can never escape the target directory.
+pub fn sanitize(text: &str) -> String {
+    let cleaned: String = text
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // A component made only of dots would still mean "this" or "parent" directory
+    if cleaned.chars().all(|c| c == '.') {
+        cleaned.replace('.', "_")
+    } else {
+        cleaned
+    }
+}
+
+// What a placeholder can have expanded to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Piece {
+    Char(char),
+    // One ASCII digit
+    Digit,
+    // One or more ASCII digits
+    Digits,
+    // One or more characters within a path component
+    Text,
+}
+
+/// Whether `path` is a name `template` could have expanded to, e.g. for
+/// finding the files written with it; also in the numbered form of
+/// `unused_path`.
+pub fn matches(template: &str, path: &str) -> bool {
+    let Some(pieces) = pieces(template) else { return false };
+    let fits = |path: &str| match_from(&pieces, &path.chars().collect::<Vec<char>>());
+    fits(path) || unnumbered(path).is_some_and(|path| fits(&path))
+}
+
+/// `path` if nothing is there yet (nor a `--compress-completed` copy of it),
+/// otherwise the first free `NAME-2.EXT`, `NAME-3.EXT`, ...
+pub fn unused_path(path: &str) -> String {
+    let taken = |path: &str| std::path::Path::new(path).exists() || std::path::Path::new(&format!("{}.gz", path)).exists();
+    if !taken(path) {
+        return path.to_string();
+    }
+    (2..).map(|n| numbered(path, n)).find(|path| !taken(path)).expect("Some number is free")
+}
+
+// `dir/NAME.EXT` as `dir/NAME-n.EXT`
+fn numbered(path: &str, n: u64) -> String {
+    let (dir, name) = path.split_at(path.rfind(['/', '\\']).map_or(0, |i| i + 1));
+    match name.rfind('.').filter(|dot| *dot > 0) {
+        Some(dot) => format!("{}{}-{}{}", dir, &name[..dot], n, &name[dot..]),
+        None => format!("{}{}-{}", dir, name, n),
+    }
+}
+
+// The path `numbered` made this one from, if it is one
+fn unnumbered(path: &str) -> Option<String> {
+    let (dir, name) = path.split_at(path.rfind(['/', '\\']).map_or(0, |i| i + 1));
+    let (stem, ext) = match name.rfind('.').filter(|dot| *dot > 0) {
+        Some(dot) => name.split_at(dot),
+        None => (name, ""),
+    };
+    let (stem, n) = stem.rsplit_once('-')?;
+    let number: u64 = n.parse().ok().filter(|_| n.bytes().all(|b| b.is_ascii_digit()))?;
+    (number >= 2 && !stem.is_empty()).then(|| format!("{}{}{}", dir, stem, ext))
+}
+
+fn pieces(template: &str) -> Option<Vec<Piece>> {
+    let shape = |shape: &str| -> Vec<Piece> {
+        shape.chars().map(|c| if c == '9' { Piece::Digit } else { Piece::Char(c) }).collect()
+    };
+    let mut pieces = Vec::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                pieces.push(Piece::Char('{'));
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                pieces.push(Piece::Char('}'));
+            }
+            '{' => {
+                let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                match name.as_str() {
+                    "date" => pieces.extend(shape("9999-99-99")),
+                    "time" => pieces.extend(shape("999999")),
+                    "datetime" => pieces.extend(shape("9999-99-99_999999")),
+                    "run" => pieces.push(Piece::Digits),
+                    "port" | "hostname" | "name" => pieces.push(Piece::Text),
+                    _ => return None,
+                }
+            }
+            c => pieces.push(Piece::Char(c)),
+        }
+    }
+    Some(pieces)
+}
+
+fn match_from(pieces: &[Piece], path: &[char]) -> bool {
+    let Some((piece, rest)) = pieces.split_first() else {
+        return path.is_empty();
+    };
+    let Some(&c) = path.first() else { return false };
+    match piece {
+        Piece::Char(expected) => c == *expected && match_from(rest, &path[1..]),
+        Piece::Digit => c.is_ascii_digit() && match_from(rest, &path[1..]),
+        Piece::Digits | Piece::Text => {
+            let fits = |c: &char| match piece {
+                Piece::Digits => c.is_ascii_digit(),
+                _ => !matches!(c, '/' | '\\'),
+            };
+            let longest = path.iter().take_while(|c| fits(c)).count();
+            (1..=longest).any(|len| match_from(rest, &path[len..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn ctx(run: Option<(i64, &str)>) -> Context<'_> {
+        Context {
+            now: Local.with_ymd_and_hms(2025, 1, 14, 10, 21, 7).unwrap(),
+            port: "/dev/ttyUSB0",
+            run,
+        }
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        let path = expand("data/{date}/{port}/run_{run}_{name}_{time}.csv", &ctx(Some((3, "warm up")))).unwrap();
+        assert_eq!(path, "data/2025-01-14/ttyUSB0/run_0003_warm up_102107.csv");
+        assert_eq!(expand("{{date}}_{datetime}", &ctx(None)).unwrap(), "{date}_2025-01-14_102107");
+    }
+
+    #[test]
+    fn per_run_placeholders_need_a_run() {
+        assert!(expand("run_{run}.csv", &ctx(None)).unwrap_err().contains("--per-run"));
+        assert!(uses_per_run_placeholders("x/{name}.csv"));
+        assert!(!uses_per_run_placeholders("x/{{run}}.csv"));
+        assert!(expand("{nope}.csv", &ctx(None)).is_err());
+        assert!(expand("{date.csv", &ctx(None)).is_err());
+    }
+
+    #[test]
+    fn labels_stay_in_the_directory() {
+        assert_eq!(expand("runs/{name}.csv", &ctx(Some((1, "../../etc/passwd")))).unwrap(), "runs/.._.._etc_passwd.csv");
+        assert_eq!(expand("runs/{name}.csv", &ctx(Some((1, "..")))).unwrap(), "runs/__.csv");
+        assert_eq!(expand("runs/{name}.csv", &ctx(Some((1, "")))).unwrap(), "runs/unnamed.csv");
+        assert_eq!(port_label(r"\\.\COM12"), "COM12");
+    }
+
+    #[test]
+    fn numbers_go_before_the_extension() {
+        assert_eq!(numbered("runs/unnamed.csv", 2), "runs/unnamed-2.csv");
+        assert_eq!(numbered("runs.d/log", 3), "runs.d/log-3");
+        assert_eq!(numbered(".hidden", 2), ".hidden-2");
+        assert_eq!(unnumbered("runs/unnamed-12.csv").as_deref(), Some("runs/unnamed.csv"));
+        assert_eq!(unnumbered("runs/unnamed-1.csv"), None);
+        assert_eq!(unnumbered("runs/run-0003.csv").as_deref(), Some("runs/run.csv"));
+        assert_eq!(unnumbered("runs/unnamed.csv"), None);
+    }
+
+    #[test]
+    fn taken_names_get_the_next_number() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run_unnamed.csv").to_string_lossy().to_string();
+        assert_eq!(unused_path(&path), path);
+        std::fs::write(&path, "").unwrap();
+        let second = unused_path(&path);
+        assert!(second.ends_with("run_unnamed-2.csv"));
+        std::fs::write(format!("{}.gz", second), "").unwrap();
+        assert!(unused_path(&path).ends_with("run_unnamed-3.csv"));
+    }
+
+    #[test]
+    fn matching_written_names() {
+        let template = "data/{date}/run_{run}_{name}.csv";
+        assert!(matches(template, "data/2025-01-14/run_0003_warmup.csv"));
+        assert!(matches(template, "data/2025-01-14/run_0003_warmup-2.csv"));
+        assert!(!matches(template, "data/2025-01-14/run_x_warmup.csv"));
+        assert!(!matches(template, "data/2025-1-14/run_0003_warmup.csv"));
+        assert!(!matches(template, "data/2025-01-14/sub/run_0003_warmup.csv"));
+        assert!(!matches("out.csv", "other.csv"));
+    }
+}
@@ -0,0 +1,277 @@
+//! `--time-source nmea:PATH`: the host clock measured against a GPS receiver.
+//!
+//! A thread reads the receiver on a second serial port and compares the UTC
+//! time of every valid RMC sentence with the host clock when the sentence
+//! arrived. A sentence arrives some time after the second it names, so the
+//! offset is the largest of the last few measurements: the least delayed one.
+//! The offset is written to the session metadata (as measured at startup, after
+//! waiting briefly for a fix) and as a `clockoffset` row when each run starts
+//! and every minute while recording. With `--apply-time-offset` it is added to
+//! every timestamp written. Without a fix, or when the receiver goes quiet, the
+//! last offset is kept.
+
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeDelta, Utc};
+use collect_acc_data::logfile::Kind;
+use collect_acc_data::nmea;
+
+use crate::get_timestamp;
+use crate::session::{write_marker, Shared};
+
+// GPS receivers talk at 4800 or 9600 baud; 9600 unless given with @BAUD
+const DEFAULT_BAUD: u32 = 9600;
+// How long startup waits for the first fix
+const FIRST_FIX_WAIT: Duration = Duration::from_secs(3);
+// Measurements the offset is taken from; one per second from most receivers
+const SAMPLES: usize = 16;
+// Without a valid sentence for this long the fix counts as lost
+const FIX_TIMEOUT: Duration = Duration::from_secs(5);
+const REOPEN_DELAY: Duration = Duration::from_secs(5);
+const ROW_INTERVAL: Duration = Duration::from_secs(60);
+
+static SOURCE: OnceLock<String> = OnceLock::new();
+// Host clock to GPS time, in milliseconds; valid once HAS_OFFSET is set
+static OFFSET_MS: AtomicI64 = AtomicI64::new(0);
+static HAS_OFFSET: AtomicBool = AtomicBool::new(false);
+static HAS_FIX: AtomicBool = AtomicBool::new(false);
+static APPLY: AtomicBool = AtomicBool::new(false);
+
+/// The current time for timestamps: the host clock, corrected by the GPS
+/// offset with `--apply-time-offset`.
+pub fn now() -> DateTime<Local> {
+    let now = Local::now();
+    if APPLY.load(Ordering::Relaxed) && HAS_OFFSET.load(Ordering::Relaxed) {
+        now + TimeDelta::milliseconds(OFFSET_MS.load(Ordering::Relaxed))
+    } else {
+        now
+    }
+}
+
+/// Opens the receiver named by a `--time-source` argument (`nmea:PATH` or
+/// `nmea:PATH@BAUD`), starts measuring and waits a few seconds for a fix.
+pub fn open(spec: &str, apply: bool) -> Result<(), String> {
+    let invalid = || format!("Invalid --time-source '{}': expected nmea:PATH or nmea:PATH@BAUD", spec);
+    let target = spec.strip_prefix("nmea:").filter(|path| !path.is_empty()).ok_or_else(invalid)?;
+    let (path, baud) = match target.rsplit_once('@') {
+        Some((path, baud)) => (path, baud.parse().ok().filter(|baud| *baud > 0).ok_or_else(invalid)?),
+        None => (target, DEFAULT_BAUD),
+    };
+    let port = open_port(path, baud)?;
+    let _ = SOURCE.set(spec.to_string());
+    APPLY.store(apply, Ordering::Relaxed);
+    let reader_path = path.to_string();
+    thread::spawn(move || read(port, &reader_path, baud));
+
+    say!("Waiting for a GPS fix on {}...", path);
+    let deadline = Instant::now() + FIRST_FIX_WAIT;
+    while !HAS_OFFSET.load(Ordering::Relaxed) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    if !HAS_OFFSET.load(Ordering::Relaxed) {
+        esay!("Warning: No GPS fix yet on {}; the host clock is used until there is one.", path);
+    }
+    Ok(())
+}
+
+/// Metadata entries for the time source, empty without one.
+pub fn metadata() -> Vec<(String, String)> {
+    let Some(source) = SOURCE.get() else { return Vec::new() };
+    vec![
+        ("time_source".to_string(), source.clone()),
+        ("clock_offset_s".to_string(), offset_text().unwrap_or_else(|| "no fix".to_string())),
+        ("time_offset_applied".to_string(), APPLY.load(Ordering::Relaxed).to_string()),
+    ]
+}
+
+/// Starts writing `clockoffset` rows while recording; a no-op without a
+/// time source.
+pub fn spawn_rows(shared: Arc<Shared>) {
+    if SOURCE.get().is_none() {
+        return;
+    }
+    thread::spawn(move || {
+        let mut last: Option<(i64, Instant)> = None;
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            let mut writer = shared.writer.lock().unwrap();
+            if !shared.recording.load(Ordering::Acquire) {
+                continue;
+            }
+            let run = shared.current_run.load(Ordering::Relaxed);
+            if last.is_some_and(|(last_run, at)| last_run == run && at.elapsed() < ROW_INTERVAL) {
+                continue;
+            }
+            let Some(offset) = offset_text() else { continue };
+            let offset = if HAS_FIX.load(Ordering::Relaxed) { offset } else { format!("{} (no fix)", offset) };
+            if let Some(w) = writer.as_mut() {
+                write_marker(w, Kind::ClockOffset, &[&get_timestamp(), &offset]);
+            }
+            last = Some((run, Instant::now()));
+        }
+    });
+}
+
+// The offset in seconds, e.g. +1.250
+fn offset_text() -> Option<String> {
+    HAS_OFFSET
+        .load(Ordering::Relaxed)
+        .then(|| format!("{:+.3}", OFFSET_MS.load(Ordering::Relaxed) as f64 / 1000.0))
+}
+
+fn open_port(path: &str, baud: u32) -> Result<Box<dyn serialport::SerialPort>, String> {
+    serialport::new(path, baud)
+        .timeout(Duration::from_secs(1))
+        .open()
+        .map_err(|e| format!("Failed to open the --time-source port {}: {}", path, e))
+}
+
+// Reads sentences for as long as the program runs, reopening the port after
+// an error
+fn read(mut port: Box<dyn serialport::SerialPort>, path: &str, baud: u32) {
+    let mut samples = Samples(VecDeque::with_capacity(SAMPLES));
+    let mut line = Vec::new();
+    let mut buf = [0; 256];
+    let mut last_fix = Instant::now();
+    loop {
+        match port.read(&mut buf) {
+            Ok(n) => {
+                let arrived = Utc::now();
+                for &byte in &buf[..n] {
+                    if byte != b'\n' {
+                        line.push(byte);
+                        continue;
+                    }
+                    let text = String::from_utf8_lossy(&line).into_owned();
+                    line.clear();
+                    match nmea::parse_rmc(&text) {
+                        Ok(Some(nmea::Rmc { valid: true, time: Some(time) })) => {
+                            measure(&mut samples, offset(time, arrived));
+                            last_fix = Instant::now();
+                        }
+                        Ok(Some(_)) => lose_fix(path, "lost its fix"),
+                        Ok(None) => {}
+                        // Receivers start with a partial line and send the odd garbled one
+                        Err(_) => {}
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                esay!("Warning: Failed to read the --time-source port {}: {}", path, e);
+                lose_fix(path, "can't be read");
+                loop {
+                    thread::sleep(REOPEN_DELAY);
+                    if let Ok(reopened) = open_port(path, baud) {
+                        port = reopened;
+                        break;
+                    }
+                }
+                line.clear();
+            }
+        }
+        if last_fix.elapsed() > FIX_TIMEOUT {
+            lose_fix(path, "sent no fix for a while");
+        }
+    }
+}
+
+// Host clock to GPS time, from a sentence naming `time` (UTC) that arrived
+// at `arrived` on the host clock
+fn offset(time: NaiveDateTime, arrived: DateTime<Utc>) -> TimeDelta {
+    time.and_utc() - arrived
+}
+
+// The last measurements, in milliseconds
+struct Samples(VecDeque<i64>);
+
+impl Samples {
+    // Adds a measurement and returns the offset: the least delayed of the last
+    // SAMPLES measurements
+    fn add(&mut self, offset: TimeDelta) -> i64 {
+        if self.0.len() == SAMPLES {
+            self.0.pop_front();
+        }
+        self.0.push_back(offset.num_milliseconds());
+        self.0.iter().copied().max().expect("A sample was just added")
+    }
+}
+
+fn measure(samples: &mut Samples, offset: TimeDelta) {
+    let offset = samples.add(offset);
+    OFFSET_MS.store(offset, Ordering::Relaxed);
+    HAS_OFFSET.store(true, Ordering::Relaxed);
+    if !HAS_FIX.swap(true, Ordering::Relaxed) {
+        if let Some(offset) = offset_text() {
+            say!("GPS fix; the host clock is off by {}s.", offset);
+        }
+    }
+}
+
+// Keeps the last offset, warning once per loss
+fn lose_fix(path: &str, reason: &str) {
+    if HAS_FIX.swap(false, Ordering::Relaxed) {
+        match offset_text() {
+            Some(offset) => esay!("Warning: The GPS on {} {}; keeping the last clock offset of {}s.", path, reason, offset),
+            None => esay!("Warning: The GPS on {} {}.", path, reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn utc(date: (i32, u32, u32), time: (u32, u32, u32, u32)) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(date.0, date.1, date.2)
+            .unwrap()
+            .and_hms_milli_opt(time.0, time.1, time.2, time.3)
+            .unwrap()
+    }
+
+    fn gps(line: &str) -> NaiveDateTime {
+        nmea::parse_rmc(line).unwrap().unwrap().time.unwrap()
+    }
+
+    #[test]
+    fn a_fix_just_before_midnight_arriving_after_it() {
+        // 23:59:59.8 on New Year's Eve, read at 00:00:00.1
+        let time = gps("$GPRMC,235959.80,A,,,,,,,311224,,");
+        let arrived = utc((2025, 1, 1), (0, 0, 0, 100)).and_utc();
+        assert_eq!(offset(time, arrived), TimeDelta::milliseconds(-300));
+    }
+
+    #[test]
+    fn a_host_clock_on_the_wrong_day() {
+        // The host still thinks it is the day before
+        let time = gps("$GPRMC,000000.50,A,,,,,,,150125,,");
+        let arrived = utc((2025, 1, 14), (23, 59, 58, 0)).and_utc();
+        assert_eq!(offset(time, arrived), TimeDelta::milliseconds(2500));
+        let arrived = utc((2025, 1, 14), (0, 0, 0, 700)).and_utc();
+        assert_eq!(offset(time, arrived), TimeDelta::days(1) - TimeDelta::milliseconds(200));
+    }
+
+    #[test]
+    fn the_offset_is_the_least_delayed_of_the_last_samples() {
+        let mut samples = Samples(VecDeque::new());
+        assert_eq!(samples.add(TimeDelta::milliseconds(-400)), -400);
+        assert_eq!(samples.add(TimeDelta::milliseconds(-120)), -120);
+        assert_eq!(samples.add(TimeDelta::milliseconds(-900)), -120);
+        // The best sample is forgotten after SAMPLES more
+        for _ in 0..SAMPLES - 1 {
+            samples.add(TimeDelta::milliseconds(-300));
+        }
+        assert_eq!(samples.add(TimeDelta::milliseconds(-350)), -300);
+        assert_eq!(samples.0.len(), SAMPLES);
+    }
+}
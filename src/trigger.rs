@@ -0,0 +1,122 @@
+//! Starting and stopping runs from a modem control line, with `--hw-trigger`.
+//!
+//! A button wired to CTS, DSR or CD is polled from the serial thread between
+//! reads, like `--log-modem-lines` polls the lines. `toggle` starts a run on
+//! one rising edge and stops it on the next; `high` records while the line is
+//! high and `low` while it is low. A new level only counts once it has held
+//! for the `--debounce-ms` time, so a bouncing contact gives one edge. The
+//! level when the port is opened is taken as it is: it doesn't start a run.
+
+use serialport::SerialPort;
+use std::time::{Duration, Instant};
+
+use crate::modem::ModemLine;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Toggle,
+    High,
+    Low,
+}
+
+/// What a trigger edge asks for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Start,
+    Stop,
+    // Start if idle, stop if recording
+    Toggle,
+}
+
+/// The `--hw-trigger` setting and what carries out its edges.
+pub struct HwTrigger {
+    pub line: ModemLine,
+    pub mode: Mode,
+    pub debounce: Duration,
+    // Runs on the serial thread
+    pub handler: Box<dyn Fn(Action) + Send>,
+}
+
+impl HwTrigger {
+    /// Parses a `--hw-trigger` argument such as `cts:toggle`.
+    pub fn parse(arg: &str) -> Result<(ModemLine, Mode), String> {
+        let invalid = || {
+            format!(
+                "Invalid --hw-trigger '{}': expected LINE:MODE with cts, dsr or cd and toggle, high or low",
+                arg
+            )
+        };
+        let (line, mode) = arg.split_once(':').ok_or_else(invalid)?;
+        let line = ModemLine::parse(line.trim()).ok_or_else(invalid)?;
+        let mode = match mode.trim() {
+            "toggle" => Mode::Toggle,
+            "high" => Mode::High,
+            "low" => Mode::Low,
+            _ => return Err(invalid()),
+        };
+        Ok((line, mode))
+    }
+
+    // The action for an accepted change to `level`, if any
+    fn action(&self, level: bool) -> Option<Action> {
+        match (self.mode, level) {
+            (Mode::Toggle, true) => Some(Action::Toggle),
+            (Mode::Toggle, false) => None,
+            (Mode::High, true) | (Mode::Low, false) => Some(Action::Start),
+            (Mode::High, false) | (Mode::Low, true) => Some(Action::Stop),
+        }
+    }
+}
+
+/// The trigger line's state on the port being read.
+#[derive(Debug, Default)]
+pub struct TriggerState {
+    // Debounced level; None until the first read
+    level: Option<bool>,
+    // A different level and when it was first seen
+    pending: Option<(bool, Instant)>,
+    // Set after a read failed, until another port is opened
+    failed: bool,
+}
+
+impl TriggerState {
+    /// Reads the line and carries out the action of an edge that has held
+    /// for the debounce time.
+    pub fn poll(&mut self, trigger: &HwTrigger, port: &mut dyn SerialPort) {
+        if self.failed {
+            return;
+        }
+        let level = match trigger.line.read(port) {
+            Ok(level) => level,
+            Err(e) => {
+                esay!(
+                    "Warning: Can't read {} for --hw-trigger ({}); the trigger is off until the port is reopened.",
+                    trigger.line.name(),
+                    e
+                );
+                self.failed = true;
+                return;
+            }
+        };
+        let Some(current) = self.level else {
+            self.level = Some(level);
+            return;
+        };
+        if level == current {
+            self.pending = None;
+            return;
+        }
+        let since = match self.pending {
+            Some((pending, since)) if pending == level => since,
+            _ => self.pending.insert((level, Instant::now())).1,
+        };
+        if since.elapsed() < trigger.debounce {
+            return;
+        }
+        self.level = Some(level);
+        self.pending = None;
+        if let Some(action) = trigger.action(level) {
+            (trigger.handler)(action);
+        }
+    }
+}
@@ -0,0 +1,367 @@
+//! Uploading finished files with `--upload-url`.
+//!
+//! A run's file once it is closed (`--per-run`), the file `newfile` moves on
+//! from, the session-long file at exit and every `--sidecar` file are sent,
+//! one at a time, from a background thread: to S3 (`s3://bucket/prefix/`)
+//! or with a plain HTTP PUT (`https://host/upload/`).
+//! The object name is the file's path below the output directory, so
+//! `data/2025-01-14/run_0003.csv` goes to `prefix/2025-01-14/run_0003.csv`.
+//! A failed upload is retried with growing pauses and then given up on.
+//!
+//! `uploaded.log` in the output directory records every file queued and
+//! every upload that succeeded. At the next start, files queued but never
+//! uploaded (failed, or cut off at exit) are queued again, unless
+//! `--no-upload` is given for that session. A file that `--compress-completed`
+//! has replaced is uploaded as its `.gz`.
+//!
+//! S3 credentials come from the environment as for the AWS tools:
+//! `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and optionally
+//! `AWS_SESSION_TOKEN`, or else the default profile in `~/.aws/credentials`.
+//! The region is `AWS_REGION` or `AWS_DEFAULT_REGION` (default `us-east-1`),
+//! and `AWS_ENDPOINT_URL` points at an S3-compatible server such as MinIO,
+//! addressed path-style.
+//!
+//! The HTTP and S3 clients are behind the `upload` cargo feature (on by
+//! default).
+
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::compress;
+use crate::events::{self, Event};
+
+pub const LOG_NAME: &str = "uploaded.log";
+
+const ATTEMPTS: u32 = 5;
+
+// Files queued or being uploaded, waited on briefly at exit
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static QUEUE: OnceLock<mpsc::Sender<PathBuf>> = OnceLock::new();
+// Where uploaded.log is
+static LOG: OnceLock<PathBuf> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    S3 { bucket: String, prefix: String },
+    // Base URL ending in `/`
+    Http { base: String },
+}
+
+impl Target {
+    /// Parses an `--upload-url` argument.
+    pub fn parse(url: &str) -> Result<Target, String> {
+        if let Some(rest) = url.strip_prefix("s3://") {
+            let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+            if bucket.is_empty() {
+                return Err(format!("Invalid --upload-url '{}': no bucket after s3://", url));
+            }
+            let prefix = match prefix.trim_matches('/') {
+                "" => String::new(),
+                prefix => format!("{}/", prefix),
+            };
+            return Ok(Target::S3 {
+                bucket: bucket.to_string(),
+                prefix,
+            });
+        }
+        if url.starts_with("http://") || url.starts_with("https://") {
+            let base = if url.ends_with('/') { url.to_string() } else { format!("{}/", url) };
+            return Ok(Target::Http { base });
+        }
+        Err(format!(
+            "Invalid --upload-url '{}': expected s3://bucket/prefix/ or an http:// or https:// URL",
+            url
+        ))
+    }
+}
+
+// What the worker thread needs
+struct Uploader {
+    target: Target,
+    // Paths are named relative to this, the output directory
+    root: PathBuf,
+    bucket: Option<client::Bucket>,
+}
+
+/// Starts the upload thread and queues again the files an earlier session
+/// didn't manage to upload.
+pub fn init(target: Target, root: PathBuf) -> Result<(), String> {
+    client::check()?;
+    let bucket = match &target {
+        Target::S3 { bucket, .. } => Some(client::bucket(bucket)?),
+        Target::Http { .. } => None,
+    };
+    let log = root.join(LOG_NAME);
+    let unfinished = unfinished(&log);
+    LOG.set(log).map_err(|_| "Uploads are already set up".to_string())?;
+    let uploader = Uploader {
+        target,
+        root,
+        bucket,
+    };
+    let (tx, rx) = mpsc::channel::<PathBuf>();
+    thread::spawn(move || {
+        for path in rx {
+            uploader.upload_with_retries(&path);
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+    });
+    QUEUE.set(tx).map_err(|_| "Uploads are already set up".to_string())?;
+    if !unfinished.is_empty() {
+        say!("Resuming {} upload(s) left over from an earlier session.", unfinished.len());
+    }
+    for path in unfinished {
+        enqueue(path);
+    }
+    Ok(())
+}
+
+/// Queues a finished file for upload; a no-op without `--upload-url`.
+pub fn submit(path: &Path) {
+    if QUEUE.get().is_none() {
+        return;
+    }
+    record(&["queued", &path.display().to_string()]);
+    enqueue(path.to_path_buf());
+}
+
+fn enqueue(path: PathBuf) {
+    let Some(queue) = QUEUE.get() else { return };
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    if queue.send(path).is_err() {
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Gives queued uploads up to `timeout` before exit; the rest are resumed at
+/// the next start.
+pub fn wait_for_pending(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while PENDING.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            esay!(
+                "Warning: {} upload(s) not finished before exit; they are resumed at the next start",
+                PENDING.load(Ordering::SeqCst)
+            );
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// Appends a tab-separated line to uploaded.log
+fn record(fields: &[&str]) {
+    let Some(log) = LOG.get() else { return };
+    let line = format!("{}\t{}\n", crate::get_timestamp(), fields.join("\t"));
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = written {
+        esay!("Warning: Failed to write {}: {}", log.display(), e);
+    }
+}
+
+// Files queued in uploaded.log and neither uploaded nor found missing since
+fn unfinished(log: &Path) -> Vec<PathBuf> {
+    let Ok(text) = std::fs::read_to_string(log) else { return Vec::new() };
+    let mut queued: Vec<String> = Vec::new();
+    let mut settled: HashSet<String> = HashSet::new();
+    for line in text.lines() {
+        let mut fields = line.split('\t').skip(1);
+        match (fields.next(), fields.next()) {
+            (Some("queued"), Some(path)) => {
+                settled.remove(path);
+                if !queued.iter().any(|queued| queued == path) {
+                    queued.push(path.to_string());
+                }
+            }
+            (Some("uploaded" | "missing"), Some(path)) => {
+                settled.insert(path.to_string());
+            }
+            _ => {}
+        }
+    }
+    queued.into_iter().filter(|path| !settled.contains(path)).map(PathBuf::from).collect()
+}
+
+impl Uploader {
+    fn upload_with_retries(&self, path: &Path) {
+        let file = path.display().to_string();
+        // The file itself, or the copy --compress-completed left
+        let source = if path.exists() { path.to_path_buf() } else { compress::compressed_path(path) };
+        if !source.exists() {
+            esay!("Warning: {} no longer exists; it is not uploaded.", file);
+            record(&["missing", &file]);
+            events::emit(Event::UploadFailed {
+                file,
+                url: String::new(),
+                attempt: 0,
+                error: "file not found".to_string(),
+                retrying: false,
+            });
+            return;
+        }
+        let name = source.strip_prefix(&self.root).unwrap_or(&source);
+        let name = name.to_string_lossy().replace('\\', "/");
+        for attempt in 1..=ATTEMPTS {
+            let result = self.put(&source, &name);
+            match result {
+                Ok((url, bytes)) => {
+                    say!("Uploaded {} to {}.", source.display(), url);
+                    record(&["uploaded", &file, &url]);
+                    events::emit(Event::UploadCompleted { file, url, bytes });
+                    return;
+                }
+                Err((url, e)) => {
+                    let retrying = attempt < ATTEMPTS;
+                    events::emit(Event::UploadFailed {
+                        file: file.clone(),
+                        url: url.clone(),
+                        attempt,
+                        error: e.clone(),
+                        retrying,
+                    });
+                    if !retrying {
+                        esay!(
+                            "Warning: Giving up on uploading {} after {} attempts: {}; it is retried at the next start",
+                            source.display(),
+                            ATTEMPTS,
+                            e
+                        );
+                        return;
+                    }
+                    // Back off 2s, 4s, 8s, 16s
+                    thread::sleep(Duration::from_secs(1 << attempt));
+                }
+            }
+        }
+    }
+
+    // Sends one file; where it went (as shown to the user) and its size, or
+    // where it was going and the error
+    fn put(&self, source: &Path, name: &str) -> Result<(String, u64), (String, String)> {
+        let (url, result) = match &self.target {
+            Target::S3 { bucket, prefix } => {
+                let key = format!("{}{}", prefix, name);
+                let client = self.bucket.as_ref().expect("S3 uploads have a bucket");
+                (format!("s3://{}/{}", bucket, key), client::put_object(client, &key, source))
+            }
+            Target::Http { base } => {
+                let url = format!("{}{}", base, encode_path(name));
+                let result = client::put(&url, source);
+                (url, result)
+            }
+        };
+        result.map(|bytes| (url.clone(), bytes)).map_err(|e| (url, e))
+    }
+}
+
+// Percent-encodes a name for a URL path, keeping `/` between segments
+fn encode_path(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(feature = "upload")]
+mod client {
+    use s3::creds::Credentials;
+    use s3::Region;
+    use std::{fs::File, path::Path, time::Duration};
+
+    // For the whole transfer, so a stalled connection fails the attempt
+    const TIMEOUT: Duration = Duration::from_secs(600);
+
+    pub type Bucket = Box<s3::Bucket>;
+
+    pub fn check() -> Result<(), String> {
+        Ok(())
+    }
+
+    /// The bucket named in `--upload-url`, with credentials, region and
+    /// endpoint from the environment.
+    pub fn bucket(name: &str) -> Result<Bucket, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let credentials = Credentials::default().map_err(|_| {
+            "--upload-url s3:// needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY in the environment, \
+             or a profile in ~/.aws/credentials"
+                .to_string()
+        })?;
+        let region = var("AWS_REGION")
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = var("AWS_ENDPOINT_URL");
+        let region = match &endpoint {
+            Some(endpoint) => Region::Custom {
+                region,
+                endpoint: endpoint.trim_end_matches('/').to_string(),
+            },
+            None => region.parse().map_err(|e| format!("Invalid AWS region '{}': {}", region, e))?,
+        };
+        let mut bucket = s3::Bucket::new(name, region, credentials).map_err(|e| e.to_string())?;
+        if endpoint.is_some() {
+            bucket = bucket.with_path_style();
+        }
+        bucket.set_request_timeout(Some(TIMEOUT));
+        Ok(bucket)
+    }
+
+    pub fn put(url: &str, path: &Path) -> Result<u64, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let length = file.metadata().map_err(|e| e.to_string())?.len();
+        ureq::put(url)
+            .timeout(TIMEOUT)
+            .set("Content-Length", &length.to_string())
+            .send(file)
+            .map_err(|e| e.to_string())?;
+        Ok(length)
+    }
+
+    /// Uploads the file at `path` as `key`, in parts if it is large.
+    pub fn put_object(bucket: &Bucket, key: &str, path: &Path) -> Result<u64, String> {
+        let mut file = File::open(path).map_err(|e| e.to_string())?;
+        let length = file.metadata().map_err(|e| e.to_string())?.len();
+        bucket.put_object_stream(&mut file, key).map_err(|e| e.to_string())?;
+        Ok(length)
+    }
+}
+
+#[cfg(not(feature = "upload"))]
+mod client {
+    use std::path::Path;
+
+    pub struct Bucket;
+
+    pub fn check() -> Result<(), String> {
+        Err("--upload-url needs a build with the 'upload' feature".to_string())
+    }
+
+    pub fn bucket(_name: &str) -> Result<Bucket, String> {
+        check().map(|()| Bucket)
+    }
+
+    pub fn put(_url: &str, _path: &Path) -> Result<u64, String> {
+        Err("no HTTP client in this build".to_string())
+    }
+
+    pub fn put_object(_bucket: &Bucket, _key: &str, _path: &Path) -> Result<u64, String> {
+        Err("no S3 client in this build".to_string())
+    }
+}
@@ -0,0 +1,368 @@
+//! Checks of the logger's options against each other, before anything is
+//! opened.
+//!
+//! clap already refuses options that never go together (`--no-raw` with
+//! `--raw-file`) or need another (`--keepalive` without `--log-on-change`).
+//! The rules here depend on the values given: the same port listed twice, one
+//! file given for two outputs, `--names` not matching `--fields`. Every rule
+//! is checked and all problems are reported together, so a long command line
+//! or config file can be put right in one go. Each rule is a function in
+//! `RULES` returning its problems.
+
+use std::path::Path;
+
+use clap::{parser::ValueSource, ArgMatches};
+
+use crate::{parser, rotate, template};
+
+type Rule = fn(&ArgMatches) -> Vec<String>;
+
+const RULES: &[Rule] = &[
+    duplicate_ports,
+    several_ports,
+    port_reused,
+    shared_files,
+    per_run_output,
+    rotated_output,
+    names_and_fields,
+    decimal_comma,
+    prompt_notes,
+];
+
+/// Every problem with the combination of options in `matches`; empty if
+/// there is none.
+pub fn check(matches: &ArgMatches) -> Vec<String> {
+    RULES.iter().flat_map(|rule| rule(matches)).collect()
+}
+
+fn value<'a>(matches: &'a ArgMatches, id: &str) -> Option<&'a str> {
+    matches.get_one::<String>(id).map(String::as_str)
+}
+
+// Each --port given, as given
+fn given_ports(matches: &ArgMatches) -> Vec<&str> {
+    matches.get_many::<String>("port").into_iter().flatten().map(String::as_str).collect()
+}
+
+// The ports of --port, primary first, as the logger reads them
+fn ports(matches: &ArgMatches) -> Vec<&str> {
+    given_ports(matches)
+        .into_iter()
+        .flat_map(|ports| ports.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// Windows port and file names ignore case
+fn same_name(a: &str, b: &str) -> bool {
+    if cfg!(windows) {
+        a.eq_ignore_ascii_case(b)
+    } else {
+        a == b
+    }
+}
+
+// A port can't be its own backup
+fn duplicate_ports(matches: &ArgMatches) -> Vec<String> {
+    let ports = ports(matches);
+    let mut problems = Vec::new();
+    for (i, port) in ports.iter().enumerate() {
+        let earlier = &ports[..i];
+        let reported = earlier.iter().any(|other| same_name(other, port));
+        if !reported && ports[i + 1..].iter().any(|other| same_name(other, port)) {
+            problems.push(format!("--port lists {} more than once; give each port once, the primary first", port));
+        }
+    }
+    if given_ports(matches).iter().any(|given| given.split(',').all(|name| name.trim().is_empty())) {
+        problems.push("--port gives no port name".to_string());
+    }
+    problems
+}
+
+// Options that act on the one port being read, or compare a row with the one
+// before it, which comes from another port when several are read
+const SINGLE_PORT: &[&str] = &[
+    "failback",
+    "probe",
+    "wait-for",
+    "device-commands",
+    "hw-trigger",
+    "log-modem-lines",
+    "log-breaks",
+    "hexdump",
+    "pty-mirror",
+    "alarm",
+    "log-on-change",
+    "dedup-consecutive",
+    "dedup-window",
+];
+
+// Options whose value must be the default when several ports are read
+const SINGLE_PORT_VALUES: &[(&str, &str)] = &[("protocol", "text"), ("framing", "plain"), ("format", "csv")];
+
+// --port given more than once reads every port at once (multiport.rs)
+fn several_ports(matches: &ArgMatches) -> Vec<String> {
+    let given = given_ports(matches);
+    if given.len() < 2 {
+        if matches.contains_id("ordered") {
+            return vec!["--ordered puts the rows of several ports in order; give --port once for each port".to_string()];
+        }
+        return Vec::new();
+    }
+    let mut problems = Vec::new();
+    if given.iter().any(|ports| ports.contains(',')) {
+        problems.push("--port is given more than once to read several ports, so it can't also list backups; give one port each time".to_string());
+    }
+    for id in SINGLE_PORT {
+        if matches.value_source(id) == Some(ValueSource::CommandLine) {
+            problems.push(format!("--{} works with a single --port, not while reading {} ports", id, given.len()));
+        }
+    }
+    for (id, single) in SINGLE_PORT_VALUES {
+        if let Some(other) = value(matches, id).filter(|value| value != single) {
+            problems.push(format!("--{} {} works with a single --port, not while reading {} ports", id, other, given.len()));
+        }
+    }
+    if value(matches, "fields") == Some("auto") {
+        problems.push("--fields auto works with a single --port; give the number of fields to read several ports".to_string());
+    }
+    problems
+}
+
+// The GPS receiver and the mirror need ports of their own
+fn port_reused(matches: &ArgMatches) -> Vec<String> {
+    let ports = ports(matches);
+    let reads = |path: &str| ports.iter().any(|port| same_name(port, path));
+    let mut problems = Vec::new();
+    if let Some(source) = value(matches, "time-source") {
+        let path = source.strip_prefix("nmea:").unwrap_or(source);
+        let path = path.rsplit_once('@').map_or(path, |(path, _)| path);
+        if reads(path) {
+            problems.push(format!("--time-source reads {}, which --port reads too; the GPS receiver needs a port of its own", path));
+        }
+    }
+    if let Some(path) = value(matches, "pty-mirror") {
+        if reads(path) {
+            problems.push(format!("--pty-mirror {} is a port that --port reads; give the mirror a new path", path));
+        }
+    }
+    problems
+}
+
+// Two outputs written to one file corrupt each other
+fn shared_files(matches: &ArgMatches) -> Vec<String> {
+    let files: Vec<(&str, &str)> =
+        ["output", "raw-file", "rejects", "fallback-output", "log-file", "summary-file", "pty-mirror"]
+            .into_iter()
+            .filter_map(|id| value(matches, id).map(|path| (id, path)))
+            .collect();
+    let mut problems = Vec::new();
+    for (i, (id, path)) in files.iter().enumerate() {
+        for (other, other_path) in &files[i + 1..] {
+            if same_name(path, other_path) || Path::new(path) == Path::new(other_path) {
+                problems.push(format!("--{} and --{} are both {}; give each its own file", id, other, path));
+            }
+        }
+    }
+    problems
+}
+
+fn per_run_output(matches: &ArgMatches) -> Vec<String> {
+    let output = value(matches, "output").unwrap_or_default();
+    if matches.get_flag("per-run") && !template::uses_per_run_placeholders(output) {
+        return vec![format!(
+            "--per-run needs {{run}} or {{name}} in --output so runs don't overwrite each other (got '{}'), e.g. run_{{run}}.csv",
+            output
+        )];
+    }
+    Vec::new()
+}
+
+// Each file of --rotate-align needs a name of its own
+fn rotated_output(matches: &ArgMatches) -> Vec<String> {
+    let Some(align) = value(matches, "rotate-align").and_then(|unit| rotate::Align::parse(unit).ok()) else {
+        return Vec::new();
+    };
+    let output = value(matches, "output").unwrap_or_default();
+    if template::uses_any(output, align.placeholders()) {
+        return Vec::new();
+    }
+    let wanted: Vec<String> = align.placeholders().iter().map(|name| format!("{{{}}}", name)).collect();
+    vec![format!(
+        "--rotate-align {} needs {} in --output so each {}'s file has a name of its own (got '{}'), e.g. log_{}.csv",
+        align.name(),
+        wanted.join(" or "),
+        align.name(),
+        output,
+        wanted[0]
+    )]
+}
+
+// Only for a count given here; one from the port (auto) or a --frame-spec
+// is checked once known
+fn names_and_fields(matches: &ArgMatches) -> Vec<String> {
+    let (Some(names), Some(Ok(fields))) = (value(matches, "names"), value(matches, "fields").map(str::parse::<usize>))
+    else {
+        return Vec::new();
+    };
+    if matches.contains_id("frame-spec") {
+        return Vec::new();
+    }
+    let count = names.split(',').count();
+    if count == fields {
+        return Vec::new();
+    }
+    vec![format!(
+        "--names gives {} names, but --fields says the payload has {}; give one name per field",
+        count, fields
+    )]
+}
+
+fn decimal_comma(matches: &ArgMatches) -> Vec<String> {
+    let delimiter = value(matches, "delimiter").map(parser::parse_delimiter);
+    if matches.get_flag("decimal-comma") && delimiter == Some(Ok(',')) {
+        return vec!["--decimal-comma needs a field delimiter other than ',' (e.g. --delimiter ';')".to_string()];
+    }
+    Vec::new()
+}
+
+// A run's file is closed before its notes are entered
+fn prompt_notes(matches: &ArgMatches) -> Vec<String> {
+    if matches.get_flag("prompt-notes") && matches.get_flag("per-run") && !matches.get_flag("sidecar") {
+        return vec!["--prompt-notes with --per-run needs --sidecar: a run's file is closed before its notes are entered".to_string()];
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(args: &[&str]) -> ArgMatches {
+        crate::cli().try_get_matches_from(std::iter::once("serial_logger").chain(args.iter().copied())).unwrap()
+    }
+
+    fn problems(rule: Rule, args: &[&str]) -> Vec<String> {
+        rule(&matches(args))
+    }
+
+    #[test]
+    fn a_port_listed_twice_is_a_problem() {
+        assert!(problems(duplicate_ports, &["-p", "/dev/ttyUSB0,/dev/ttyUSB1"]).is_empty());
+        let found = problems(duplicate_ports, &["-p", "/dev/ttyUSB0,/dev/ttyUSB1,/dev/ttyUSB0,/dev/ttyUSB0"]);
+        assert_eq!(found, ["--port lists /dev/ttyUSB0 more than once; give each port once, the primary first"]);
+        assert_eq!(problems(duplicate_ports, &["-p", " , "]), ["--port gives no port name"]);
+    }
+
+    #[test]
+    fn several_ports_are_each_given_once() {
+        assert!(problems(duplicate_ports, &["-p", "/dev/ttyUSB0", "-p", "/dev/ttyUSB1"]).is_empty());
+        let found = problems(duplicate_ports, &["-p", "/dev/ttyUSB0", "-p", "/dev/ttyUSB1", "--port", "/dev/ttyUSB0"]);
+        assert_eq!(found, ["--port lists /dev/ttyUSB0 more than once; give each port once, the primary first"]);
+        assert_eq!(problems(duplicate_ports, &["-p", "/dev/ttyUSB0", "-p", ""]), ["--port gives no port name"]);
+    }
+
+    #[test]
+    fn single_port_options_are_refused_with_several() {
+        assert!(problems(several_ports, &["-p", "/dev/ttyUSB0", "--failback", "--alarm", "f1 > 3"]).is_empty());
+        assert!(problems(several_ports, &["-p", "/dev/ttyUSB0", "-p", "/dev/ttyUSB1", "--skip-lines", "2"]).is_empty());
+        let found = problems(
+            several_ports,
+            &["-p", "/dev/ttyUSB0,/dev/ttyUSB2", "-p", "/dev/ttyUSB1", "--failback", "--dedup-consecutive", "--format", "parquet", "--fields", "auto"],
+        );
+        assert_eq!(
+            found,
+            [
+                "--port is given more than once to read several ports, so it can't also list backups; give one port each time",
+                "--failback works with a single --port, not while reading 2 ports",
+                "--dedup-consecutive works with a single --port, not while reading 2 ports",
+                "--format parquet works with a single --port, not while reading 2 ports",
+                "--fields auto works with a single --port; give the number of fields to read several ports",
+            ]
+        );
+    }
+
+    #[test]
+    fn ordering_needs_several_ports() {
+        assert!(problems(several_ports, &["-p", "/dev/ttyUSB0", "-p", "/dev/ttyUSB1", "--ordered"]).is_empty());
+        assert_eq!(
+            problems(several_ports, &["-p", "/dev/ttyUSB0", "--ordered", "20"]),
+            ["--ordered puts the rows of several ports in order; give --port once for each port"]
+        );
+    }
+
+    #[test]
+    fn the_gps_and_the_mirror_need_ports_of_their_own() {
+        assert!(problems(port_reused, &["-p", "/dev/ttyUSB0", "--time-source", "nmea:/dev/ttyUSB1@9600"]).is_empty());
+        let found = problems(port_reused, &["-p", "/dev/ttyUSB0,/dev/ttyUSB1", "--time-source", "nmea:/dev/ttyUSB1@9600"]);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("--time-source reads /dev/ttyUSB1"), "{}", found[0]);
+        let found = problems(port_reused, &["-p", "/tmp/ttyV0", "--pty-mirror", "/tmp/ttyV0"]);
+        assert_eq!(found, ["--pty-mirror /tmp/ttyV0 is a port that --port reads; give the mirror a new path"]);
+    }
+
+    #[test]
+    fn two_outputs_in_one_file_are_a_problem() {
+        assert!(problems(shared_files, &["-p", "COM3", "-o", "out.csv", "--rejects", "rejects.csv"]).is_empty());
+        let found = problems(shared_files, &["-p", "COM3", "-o", "out.csv", "--rejects", "out.csv", "--log-file", "out.csv"]);
+        assert_eq!(
+            found,
+            [
+                "--output and --rejects are both out.csv; give each its own file",
+                "--output and --log-file are both out.csv; give each its own file",
+                "--rejects and --log-file are both out.csv; give each its own file",
+            ]
+        );
+    }
+
+    #[test]
+    fn per_run_needs_a_templated_output() {
+        assert!(problems(per_run_output, &["-p", "COM3", "--per-run", "-o", "run_{run}.csv"]).is_empty());
+        assert!(problems(per_run_output, &["-p", "COM3", "-o", "fixed.csv"]).is_empty());
+        let found = problems(per_run_output, &["-p", "COM3", "--per-run", "-o", "fixed.csv"]);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].contains("(got 'fixed.csv')"), "{}", found[0]);
+    }
+
+    #[test]
+    fn rotated_files_need_names_of_their_own() {
+        assert!(problems(rotated_output, &["-p", "COM3", "--rotate-align", "day", "-o", "log_{date}.csv"]).is_empty());
+        assert!(problems(rotated_output, &["-p", "COM3", "--rotate-align", "hour", "-o", "{date}/log_{time}.csv"]).is_empty());
+        assert!(problems(rotated_output, &["-p", "COM3", "--rotate-align", "hour", "-o", "log_{datetime}.csv"]).is_empty());
+        let found = problems(rotated_output, &["-p", "COM3", "--rotate-align", "hour", "-o", "log_{date}.csv"]);
+        assert_eq!(found, ["--rotate-align hour needs {time} or {datetime} in --output so each hour's file has a name of its own (got 'log_{date}.csv'), e.g. log_{time}.csv"]);
+        assert!(problems(rotated_output, &["-p", "COM3", "--rotate-align", "day"])[0].contains("needs {date} or {datetime}"));
+    }
+
+    #[test]
+    fn names_must_match_the_field_count() {
+        assert!(problems(names_and_fields, &["-p", "COM3", "--fields", "3", "--names", "x,y,z"]).is_empty());
+        assert!(problems(names_and_fields, &["-p", "COM3", "--fields", "auto", "--names", "x,y"]).is_empty());
+        let found = problems(names_and_fields, &["-p", "COM3", "--fields", "4", "--names", "t,x,y,z,extra"]);
+        assert_eq!(found, ["--names gives 5 names, but --fields says the payload has 4; give one name per field"]);
+    }
+
+    #[test]
+    fn a_decimal_comma_needs_another_delimiter() {
+        assert!(problems(decimal_comma, &["-p", "COM3", "--decimal-comma", "--delimiter", ";"]).is_empty());
+        let found = problems(decimal_comma, &["-p", "COM3", "--decimal-comma", "--delimiter", ","]);
+        assert_eq!(found, ["--decimal-comma needs a field delimiter other than ',' (e.g. --delimiter ';')"]);
+    }
+
+    #[test]
+    fn notes_on_per_run_files_need_a_sidecar() {
+        let per_run = ["-p", "COM3", "--per-run", "-o", "run_{run}.csv", "--prompt-notes"];
+        assert_eq!(problems(prompt_notes, &per_run).len(), 1);
+        assert!(problems(prompt_notes, &[&per_run[..], &["--sidecar"]].concat()).is_empty());
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let all = matches(&[
+            "-p", "COM3,COM3", "--per-run", "-o", "fixed.csv", "--rejects", "fixed.csv", "--fields", "2", "--names", "x",
+        ]);
+        assert_eq!(check(&all).len(), 4);
+        assert!(check(&matches(&["-p", "COM3"])).is_empty());
+    }
+}
@@ -0,0 +1,242 @@
+//! An Excel workbook for every finished run, with `--xlsx`.
+//!
+//! While a run records, its data rows are kept in memory as written. When it
+//! stops they are queued, with the run's field statistics and metadata, for a
+//! background thread that writes the workbook, so the next run can start
+//! straight away. The workbook is named like the run's sidecar
+//! (`run_0003.xlsx` next to `run_0003.csv` with `--per-run`,
+//! `output.run_0003.xlsx` for a session-long `output.csv`) and has three
+//! sheets: Data (the timestamp as an Excel date and time, numbers as numbers,
+//! other fields as text), Summary (the statistics of each numeric column) and
+//! Meta (the session metadata and the run's own). A run with more rows than
+//! `--xlsx-max-rows` (or, with `--max-memory`, more than its share of memory)
+//! gets no workbook: its rows stop being kept once it passes the limit and a
+//! warning at the stop points to its CSV. Needs a build with
+//! the `xlsx` feature.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::memory::{self, Buffer};
+use crate::stats::FieldSummary;
+
+/// Rows of a run kept for a workbook unless `--xlsx-max-rows` says otherwise.
+pub const DEFAULT_MAX_ROWS: usize = 100_000;
+/// The most a worksheet holds, below its header row.
+pub const SHEET_ROWS: usize = 1_048_575;
+
+// Workbooks queued or being written, waited on at exit
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static QUEUE: OnceLock<mpsc::Sender<Workbook>> = OnceLock::new();
+
+/// The data rows of the current run, as written to the output.
+pub struct Collector {
+    max_rows: usize,
+    rows: Vec<Vec<String>>,
+    bytes: usize,
+    // The run passed the limit and its rows were let go
+    overflowed: bool,
+}
+
+impl Collector {
+    pub fn new(max_rows: usize) -> Collector {
+        Collector {
+            max_rows,
+            rows: Vec::new(),
+            bytes: 0,
+            overflowed: false,
+        }
+    }
+
+    pub fn push(&mut self, record: &[&str]) {
+        if self.overflowed {
+            return;
+        }
+        let bytes = memory::row_bytes(record);
+        if self.rows.len() == self.max_rows || self.bytes + bytes > memory::budget(Buffer::Workbook) {
+            self.overflowed = true;
+            self.rows = Vec::new();
+            self.bytes = 0;
+            memory::report(Buffer::Workbook, 0);
+            return;
+        }
+        self.rows.push(record.iter().map(|cell| cell.to_string()).collect());
+        self.bytes += bytes;
+        memory::report(Buffer::Workbook, self.bytes);
+    }
+
+    /// The run's rows, leaving the collector empty for the next run; None if
+    /// there were too many.
+    pub fn take(&mut self) -> Option<Vec<Vec<String>>> {
+        let overflowed = std::mem::take(&mut self.overflowed);
+        let rows = std::mem::take(&mut self.rows);
+        self.bytes = 0;
+        memory::report(Buffer::Workbook, 0);
+        (!overflowed).then_some(rows)
+    }
+
+    pub fn max_rows(&self) -> usize {
+        self.max_rows
+    }
+}
+
+/// A finished run to be written as a workbook.
+// Only the writer reads it, and there is none without the feature
+#[cfg_attr(not(feature = "xlsx"), allow(dead_code))]
+pub struct Workbook {
+    pub path: PathBuf,
+    // Names of the cells of a row after the leading Type, Timestamp, Run/End
+    // and RunId
+    pub columns: Vec<String>,
+    // Data records, leading cells included
+    pub rows: Vec<Vec<String>>,
+    pub fields: Vec<FieldSummary>,
+    pub meta: Vec<(String, String)>,
+}
+
+/// Path of the workbook for `run` written to `output`.
+pub fn workbook_path(output: &str, run: i64, per_run: bool) -> PathBuf {
+    let output = Path::new(output);
+    let stem = output.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let name = if per_run {
+        format!("{}.xlsx", stem)
+    } else {
+        format!("{}.run_{:04}.xlsx", stem, run)
+    };
+    output.with_file_name(name)
+}
+
+/// Starts the thread that writes the workbooks.
+pub fn init() -> Result<(), String> {
+    writer::available()?;
+    let (tx, rx) = mpsc::channel::<Workbook>();
+    thread::spawn(move || {
+        for workbook in rx {
+            match writer::write(&workbook) {
+                Ok(()) => say!("Wrote {} ({} rows).", workbook.path.display(), workbook.rows.len()),
+                Err(e) => esay!("Warning: Failed to write {}: {}", workbook.path.display(), e),
+            }
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+    });
+    QUEUE.set(tx).map_err(|_| "Workbooks are already set up".to_string())
+}
+
+/// Queues a finished run's workbook.
+pub fn submit(workbook: Workbook) {
+    let Some(queue) = QUEUE.get() else { return };
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    if queue.send(workbook).is_err() {
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Gives queued workbooks up to `timeout` to be written before exit.
+pub fn wait_for_pending(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while PENDING.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            esay!("Warning: {} workbook(s) left unwritten at exit", PENDING.load(Ordering::SeqCst));
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[cfg(feature = "xlsx")]
+mod writer {
+    use chrono::NaiveDateTime;
+    use rust_xlsxwriter::{Format, Workbook, Worksheet, XlsxError};
+
+    use collect_acc_data::logfile::LEADING_HEADERS;
+
+    // Width of the timestamp column, for `yyyy-mm-dd hh:mm:ss`
+    const TIMESTAMP_WIDTH: f64 = 20.0;
+
+    pub fn available() -> Result<(), String> {
+        Ok(())
+    }
+
+    pub fn write(run: &super::Workbook) -> Result<(), String> {
+        let mut workbook = Workbook::new();
+        fill(&mut workbook, run).map_err(|e| e.to_string())?;
+        // Written under a temporary name, so a half-written workbook is never
+        // mistaken for a finished one
+        let tmp = run.path.with_extension("xlsx.tmp");
+        workbook.save(&tmp).map_err(|e| e.to_string())?;
+        std::fs::rename(&tmp, &run.path).map_err(|e| e.to_string())
+    }
+
+    fn fill(workbook: &mut Workbook, run: &super::Workbook) -> Result<(), XlsxError> {
+        let bold = Format::new().set_bold();
+        let datetime = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+
+        let data = workbook.add_worksheet().set_name("Data")?;
+        data.write_string_with_format(0, 0, "Timestamp", &bold)?;
+        data.write_row_with_format(0, 1, &run.columns, &bold)?;
+        data.set_column_width(0, TIMESTAMP_WIDTH)?;
+        data.set_freeze_panes(1, 0)?;
+        for (i, record) in run.rows.iter().enumerate() {
+            let row = i as u32 + 1;
+            let timestamp = &record[1];
+            match NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f") {
+                Ok(time) => data.write_datetime_with_format(row, 0, time, &datetime)?,
+                Err(_) => data.write_string(row, 0, timestamp)?,
+            };
+            for (col, cell) in record.iter().skip(LEADING_HEADERS.len()).enumerate() {
+                write_cell(data, row, col as u16 + 1, cell)?;
+            }
+        }
+
+        let summary = workbook.add_worksheet().set_name("Summary")?;
+        summary.write_row_with_format(0, 0, ["Field", "Count", "Min", "Max", "Mean", "Std dev"], &bold)?;
+        for (i, field) in run.fields.iter().enumerate() {
+            let row = i as u32 + 1;
+            summary.write_string(row, 0, &field.name)?;
+            summary.write_number(row, 1, field.count as f64)?;
+            for (col, value) in [field.min, field.max, field.mean, field.stddev].into_iter().enumerate() {
+                if let Some(value) = value {
+                    summary.write_number(row, col as u16 + 2, value)?;
+                }
+            }
+        }
+
+        let meta = workbook.add_worksheet().set_name("Meta")?;
+        meta.write_row_with_format(0, 0, ["Key", "Value"], &bold)?;
+        for (i, (key, value)) in run.meta.iter().enumerate() {
+            meta.write_string(i as u32 + 1, 0, key)?;
+            meta.write_string(i as u32 + 1, 1, value)?;
+        }
+        Ok(())
+    }
+
+    // A number as a number, anything else as text; a blank cell stays empty
+    fn write_cell(sheet: &mut Worksheet, row: u32, col: u16, cell: &str) -> Result<(), XlsxError> {
+        if cell.is_empty() {
+            return Ok(());
+        }
+        match cell.parse::<f64>() {
+            Ok(value) if value.is_finite() => sheet.write_number(row, col, value)?,
+            _ => sheet.write_string(row, col, cell)?,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "xlsx"))]
+mod writer {
+    pub fn available() -> Result<(), String> {
+        Err("--xlsx needs a build with the 'xlsx' feature".to_string())
+    }
+
+    pub fn write(_run: &super::Workbook) -> Result<(), String> {
+        available()
+    }
+}
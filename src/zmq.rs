@@ -0,0 +1,312 @@
+//! `--zmq-pub ENDPOINT`: records published on a ZeroMQ PUB socket.
+//!
+//! Every data row written is published as a two-frame message: the topic
+//! (the run's label from `start <label>`, or `data` without one) and the row
+//! as an object like `convert`'s flat JSON (`type`, `timestamp`, `run`,
+//! `run_id`, then a value per column), encoded as JSON or MessagePack
+//! (`--zmq-encoding`). Runs starting and stopping are published on the
+//! `events` topic as their `recording_started` and `recording_stopped`
+//! events. Subscribers filter by topic prefix as usual.
+//!
+//! The socket is a PUB socket of the `zeromq` crate, in a build with the
+//! `zmq` feature, running on a thread of its own. Messages wait for it in a
+//! queue of up to `--zmq-hwm`; like a PUB socket at its high-water mark, a
+//! message that finds the queue full is dropped, so slow subscribers never
+//! hold up logging.
+
+use serde_json::Value;
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use zeromq::{Socket, SocketSend, ZmqMessage};
+
+use crate::events::{self, Event};
+
+pub const EVENTS_TOPIC: &str = "events";
+const DATA_TOPIC: &str = "data";
+
+/// How messages are encoded (`--zmq-encoding`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    Msgpack,
+}
+
+impl Encoding {
+    pub fn parse(text: &str) -> Result<Encoding, String> {
+        match text {
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::Msgpack),
+            _ => Err(format!("Invalid --zmq-encoding '{}': expected json or msgpack", text)),
+        }
+    }
+}
+
+static PUBLISHER: OnceLock<Publisher> = OnceLock::new();
+// Messages queued for subscribers and not yet sent, waited on briefly at exit
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+struct Publisher {
+    encoding: Encoding,
+    // Names of the columns after the leading ones
+    columns: Vec<String>,
+    queue: mpsc::Sender<ZmqMessage>,
+    // Topic, number and ID of the run being recorded
+    run: Mutex<(String, Option<i64>, String)>,
+    // Messages not sent because the queue was full
+    dropped: AtomicU64,
+}
+
+/// Binds the socket and publishes on it in the background.
+pub fn init(endpoint: &str, encoding: Encoding, hwm: usize, columns: Vec<String>) -> Result<(), String> {
+    let address = endpoint
+        .strip_prefix("tcp://")
+        .ok_or_else(|| format!("Invalid --zmq-pub '{}': only tcp:// endpoints are supported", endpoint))?;
+    // `*` binds every interface, as in ZeroMQ
+    let endpoint = match address.strip_prefix("*:") {
+        Some(port) => format!("tcp://0.0.0.0:{}", port),
+        None => endpoint.to_string(),
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format!("Failed to start the ZeroMQ socket: {}", e))?;
+    let mut socket = zeromq::PubSocket::new();
+    runtime
+        .block_on(socket.bind(&endpoint))
+        .map_err(|e| format!("Failed to bind --zmq-pub {}: {}", endpoint, e))?;
+    let (queue, mut messages) = mpsc::channel(hwm.max(1));
+    let publisher = Publisher {
+        encoding,
+        columns,
+        queue,
+        run: Mutex::new((DATA_TOPIC.to_string(), None, String::new())),
+        dropped: AtomicU64::new(0),
+    };
+    PUBLISHER.set(publisher).map_err(|_| "The ZeroMQ socket is already set up".to_string())?;
+    // Subscribers are accepted while the runtime runs, which is here
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            while let Some(message) = messages.recv().await {
+                if let Err(e) = socket.send(message).await {
+                    esay!("Failed to publish on --zmq-pub: {}", e);
+                }
+                PENDING.fetch_sub(1, Ordering::SeqCst);
+            }
+        })
+    });
+    Ok(())
+}
+
+/// Publishes a data record (as written to the file) on the run's topic.
+pub fn publish_record(record: &[&str]) {
+    let Some(publisher) = PUBLISHER.get() else { return };
+    let (topic, run, run_id) = publisher.run.lock().unwrap().clone();
+    let mut object = vec![
+        ("type".to_string(), Value::from("data")),
+        ("timestamp".to_string(), Value::from(record.get(1).copied().unwrap_or_default())),
+        ("run".to_string(), Value::from(run)),
+        ("run_id".to_string(), Value::from(Some(run_id).filter(|id| !id.is_empty()))),
+    ];
+    for (column, text) in publisher.columns.iter().zip(record.iter().skip(4)) {
+        object.push((column.clone(), crate::convert::value(text)));
+    }
+    let payload = match publisher.encoding {
+        Encoding::Json => json_object(&object),
+        Encoding::Msgpack => {
+            let mut out = Vec::new();
+            msgpack_object(&mut out, &object);
+            out
+        }
+    };
+    publisher.send(&topic, &payload);
+}
+
+/// Follows runs starting and stopping, and publishes those events.
+pub fn on_event(event: &Event) {
+    let Some(publisher) = PUBLISHER.get() else { return };
+    match event {
+        Event::RecordingStarted { run, run_id, label, .. } => {
+            let topic = if label.is_empty() { DATA_TOPIC.to_string() } else { label.clone() };
+            *publisher.run.lock().unwrap() = (topic, Some(*run), run_id.clone());
+        }
+        Event::RecordingStopped { .. } => {}
+        _ => return,
+    }
+    let line = events::to_json_line(event, &crate::get_timestamp());
+    let payload = match publisher.encoding {
+        Encoding::Json => line.into_bytes(),
+        Encoding::Msgpack => {
+            let mut out = Vec::new();
+            msgpack(&mut out, &serde_json::from_str(&line).expect("Event lines are JSON"));
+            out
+        }
+    };
+    publisher.send(EVENTS_TOPIC, &payload);
+}
+
+/// Waits up to `grace` for queued messages to go out, and reports any that
+/// slow subscribers missed.
+pub fn wait_for_pending(grace: Duration) {
+    let Some(publisher) = PUBLISHER.get() else { return };
+    let started = Instant::now();
+    while PENDING.load(Ordering::SeqCst) > 0 && started.elapsed() < grace {
+        thread::sleep(Duration::from_millis(20));
+    }
+    let dropped = publisher.dropped.load(Ordering::Relaxed);
+    if dropped > 0 {
+        say!("{} ZeroMQ messages were dropped as subscribers fell behind (--zmq-hwm).", dropped);
+    }
+}
+
+impl Publisher {
+    fn send(&self, topic: &str, payload: &[u8]) {
+        let mut message = ZmqMessage::from(topic);
+        message.push_back(payload.to_vec().into());
+        PENDING.fetch_add(1, Ordering::SeqCst);
+        if self.queue.try_send(message).is_err() {
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// An object with its keys in order, as JSON
+fn json_object(object: &[(String, Value)]) -> Vec<u8> {
+    let entries: Vec<String> = object
+        .iter()
+        .map(|(key, value)| format!("{}:{}", Value::from(key.as_str()), value))
+        .collect();
+    format!("{{{}}}", entries.join(",")).into_bytes()
+}
+
+fn msgpack_object(out: &mut Vec<u8>, object: &[(String, Value)]) {
+    msgpack_length(out, object.len(), 0x80, 0xdf);
+    for (key, value) in object {
+        msgpack_str(out, key);
+        msgpack(out, value);
+    }
+}
+
+fn msgpack(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(value) => out.push(if *value { 0xc3 } else { 0xc2 }),
+        Value::Number(number) => {
+            if let Some(n) = number.as_i64() {
+                out.push(0xd3);
+                out.extend_from_slice(&n.to_be_bytes());
+            } else if let Some(n) = number.as_u64() {
+                out.push(0xcf);
+                out.extend_from_slice(&n.to_be_bytes());
+            } else {
+                out.push(0xcb);
+                out.extend_from_slice(&number.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        }
+        Value::String(text) => msgpack_str(out, text),
+        Value::Array(items) => {
+            msgpack_length(out, items.len(), 0x90, 0xdd);
+            for item in items {
+                msgpack(out, item);
+            }
+        }
+        Value::Object(map) => {
+            msgpack_length(out, map.len(), 0x80, 0xdf);
+            for (key, value) in map {
+                msgpack_str(out, key);
+                msgpack(out, value);
+            }
+        }
+    }
+}
+
+fn msgpack_str(out: &mut Vec<u8>, text: &str) {
+    if text.len() < 32 {
+        out.push(0xa0 | text.len() as u8);
+    } else {
+        out.push(0xdb);
+        out.extend_from_slice(&(text.len() as u32).to_be_bytes());
+    }
+    out.extend_from_slice(text.as_bytes());
+}
+
+// The short form for up to 15 entries, otherwise the 32-bit one
+fn msgpack_length(out: &mut Vec<u8>, len: usize, fix: u8, long: u8) {
+    if len < 16 {
+        out.push(fix | len as u8);
+    } else {
+        out.push(long);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, sync::atomic::AtomicBool, sync::Arc};
+    use zeromq::{SocketRecv, SubSocket};
+
+    #[test]
+    fn a_zeromq_subscriber_receives_what_it_subscribed_to() {
+        // A free port; the publisher binds it again
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        init(&format!("tcp://127.0.0.1:{}", port), Encoding::Json, 10, vec!["ax".to_string()]).unwrap();
+        let started = |label: &str| Event::RecordingStarted {
+            run: 1,
+            run_id: "0b6f4c1e".to_string(),
+            label: label.to_string(),
+            file: "data/run_001.csv".to_string(),
+        };
+        // Published until the subscriptions have reached the publisher
+        let done = Arc::new(AtomicBool::new(false));
+        let publishing = {
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    // A run without a label, whose rows go to `data`
+                    on_event(&started(""));
+                    publish_record(&["data", "2025-01-14 10:21:06", "run 1", "0b6f4c1e", "0.5"]);
+                    on_event(&started("drop-test"));
+                    publish_record(&["data", "2025-01-14 10:21:07", "run 1", "0b6f4c1e", "1.5"]);
+                    thread::sleep(Duration::from_millis(20));
+                }
+            })
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        let received = runtime.block_on(async {
+            let mut socket = SubSocket::new();
+            socket.connect(&format!("tcp://127.0.0.1:{}", port)).await.unwrap();
+            socket.subscribe("events").await.unwrap();
+            socket.subscribe("drop-").await.unwrap();
+            let mut received = Vec::new();
+            while received.len() < 4 {
+                let message = tokio::time::timeout(Duration::from_secs(10), socket.recv()).await.unwrap().unwrap();
+                let frames: Vec<Vec<u8>> = message.into_vec().into_iter().map(|frame| frame.to_vec()).collect();
+                received.push(frames);
+            }
+            received
+        });
+        done.store(true, Ordering::Relaxed);
+        publishing.join().unwrap();
+        // Rows on the `data` topic were never sent
+        for frames in &received {
+            assert_eq!(frames.len(), 2);
+            let payload: Value = serde_json::from_slice(&frames[1]).unwrap();
+            match frames[0].as_slice() {
+                b"events" => assert_eq!(payload["event"], "recording_started"),
+                b"drop-test" => assert_eq!(
+                    String::from_utf8(frames[1].clone()).unwrap(),
+                    r#"{"type":"data","timestamp":"2025-01-14 10:21:07","run":1,"run_id":"0b6f4c1e","ax":1.5}"#
+                ),
+                topic => panic!("Unsubscribed topic {:?}", String::from_utf8_lossy(topic)),
+            }
+        }
+    }
+}
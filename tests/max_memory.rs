@@ -0,0 +1,43 @@
+//! `--max-memory` against the buffers' worst case, before any port or file
+//! is opened.
+
+use std::process::{Command, Output};
+
+fn logger(dir: &std::path::Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_collect_acc_data"))
+        .args(["-p", "/dev/serial_logger_test_missing", "--no-raw", "--no-state-file", "--no-statusline"])
+        .arg("-o")
+        .arg(dir.join("out.csv"))
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn buffers_over_the_limit_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = logger(dir.path(), &["--prebuffer", "100000", "--max-memory", "1MB"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("more than --max-memory 1.0 MB"), "{}", stderr);
+    assert!(stderr.contains("prebuffer 100000 rows"), "{}", stderr);
+    assert!(!dir.path().join("out.csv").exists());
+}
+
+#[test]
+fn buffers_within_the_limit_are_listed() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = logger(dir.path(), &["--prebuffer", "500", "--max-memory", "1GB"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Buffers: prebuffer 500 rows"), "{}", stdout);
+    // Past the check, the missing port ends it
+    assert_ne!(output.status.code(), Some(2));
+}
+
+#[test]
+fn buffers_are_not_listed_without_a_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = logger(dir.path(), &["--prebuffer", "500"]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Buffers:"));
+}
@@ -0,0 +1,686 @@
+//! The logger against `mock_device` playing a script into a pseudo-terminal:
+//! the rows, the events and the exit code of whole sessions.
+#![cfg(unix)]
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Kills a process left behind by a failed test
+struct Reaper(Option<Child>);
+
+impl Reaper {
+    fn child(&mut self) -> &mut Child {
+        self.0.as_mut().unwrap()
+    }
+
+    fn output(mut self) -> Output {
+        self.0.take().unwrap().wait_with_output().unwrap()
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        if let Some(child) = self.0.as_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn wait_for(what: &str, timeout: Duration, mut done: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(start.elapsed() < timeout, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn start_mock(scenario: &Path, link: &Path, args: &[&str]) -> Reaper {
+    let mock = Command::new(env!("CARGO_BIN_EXE_mock_device"))
+        .arg(scenario)
+        .arg("--pty")
+        .arg(link)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    wait_for("the mock's terminal", Duration::from_secs(5), || link.exists());
+    Reaper(Some(mock))
+}
+
+fn example() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios/example.json")
+}
+
+// A session recording one run of `scenario`, with `args` besides the port
+// and output file: what the logger printed and exited with, and the file
+fn record(dir: &Path, scenario: &Path, args: &[&str]) -> (Output, String) {
+    session(dir, scenario, args, &[(0, "start")])
+}
+
+// A session of `scenario` with `commands` typed at the given milliseconds
+// after the mock started, then `exit` once it is done
+fn session(dir: &Path, scenario: &Path, args: &[&str], commands: &[(u64, &str)]) -> (Output, String) {
+    let mut mock = start_mock(scenario, &dir.join("dev"), &[]);
+    run_logger(dir, &mut mock, args, commands)
+}
+
+// The logger on the terminal of `mock`, as `session`
+fn run_logger(dir: &Path, mock: &mut Reaper, args: &[&str], commands: &[(u64, &str)]) -> (Output, String) {
+    let link = dir.join("dev");
+    let output = dir.join("out.csv");
+    let started = Instant::now();
+    let logger = Command::new(env!("CARGO_BIN_EXE_collect_acc_data"))
+        .arg("-p")
+        .arg(&link)
+        .arg("-o")
+        .arg(&output)
+        .args(["--no-raw", "--no-state-file", "--no-statusline"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut logger = Reaper(Some(logger));
+    let mut stdin = logger.child().stdin.take().unwrap();
+    for (at, command) in commands {
+        thread::sleep(Duration::from_millis(*at).saturating_sub(started.elapsed()));
+        writeln!(stdin, "{}", command).unwrap();
+    }
+    // Scripts end with a pause, so the logger has read everything by the time
+    // the mock exits
+    wait_for("the end of the script", Duration::from_secs(30), || mock.child().try_wait().unwrap().is_some());
+    writeln!(stdin, "exit").unwrap();
+    drop(stdin);
+    let result = logger.output();
+    let file = std::fs::read_to_string(&output).unwrap_or_default();
+    (result, file)
+}
+
+// Writes a script that sends `lines` as they are, after a second for the
+// logger to start recording and before half a second for it to read them
+fn script(dir: &Path, lines: &[String]) -> PathBuf {
+    let mut steps = vec![serde_json::json!({"pause_ms": 1000})];
+    steps.extend(lines.iter().map(|line| serde_json::json!({ "send": line })));
+    steps.push(serde_json::json!({"pause_ms": 500}));
+    write_script(dir, steps)
+}
+
+fn write_script(dir: &Path, steps: Vec<serde_json::Value>) -> PathBuf {
+    let script = serde_json::json!({"match": "UDP packet contents:", "fields": 4, "steps": steps});
+    let path = dir.join("script.json");
+    std::fs::write(&path, script.to_string()).unwrap();
+    path
+}
+
+fn count(text: &str, prefix: &str) -> usize {
+    text.lines().filter(|line| line.starts_with(prefix)).count()
+}
+
+#[test]
+fn example_scenario_is_logged_through_a_disconnect() {
+    let dir = tempfile::tempdir().unwrap();
+    let rejects = dir.path().join("rejects.csv");
+    let (result, file) = record(dir.path(), &example(), &["--events-json", "--rejects", rejects.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(0), "{}", stderr);
+
+    assert_eq!(count(&file, "data,"), 400, "{}", file);
+    assert_eq!(count(&file, "start,"), 1);
+    assert_eq!(count(&file, "stop,"), 1);
+    assert_eq!(count(&file, "reconnect,"), 1);
+    // Every data line arrived once and in order across the disconnect
+    let sequence: Vec<u64> = file
+        .lines()
+        .filter(|line| line.starts_with("data,"))
+        .map(|line| line.split(',').nth(4).unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(sequence, (0..400).collect::<Vec<u64>>());
+
+    let rejected = std::fs::read_to_string(&rejects).unwrap();
+    assert_eq!(count(&rejected, "") - 1, 3, "{}", rejected);
+    assert_eq!(rejected.matches("field-count").count(), 3);
+
+    let events: Vec<serde_json::Value> = String::from_utf8(result.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let names: Vec<&str> = events.iter().map(|event| event["event"].as_str().unwrap()).collect();
+    for name in ["startup", "port_opened", "recording_started", "serial_error", "port_reconnected", "recording_stopped", "run_summary"] {
+        assert!(names.contains(&name), "no {} in {:?}", name, names);
+    }
+    let position = |name| names.iter().position(|event| *event == name).unwrap();
+    assert!(position("serial_error") < position("port_reconnected"));
+    let stopped = events.iter().find(|event| event["event"] == "recording_stopped").unwrap();
+    assert_eq!(stopped["rows"], 400);
+    let shutdown = events.last().unwrap();
+    assert_eq!(shutdown["event"], "shutdown");
+    assert_eq!(shutdown["runs"], 1);
+    assert_eq!(shutdown["exit_code"], 0);
+}
+
+#[test]
+fn alarms_are_written_once_per_crossing() {
+    let dir = tempfile::tempdir().unwrap();
+    // Z dips below -9.5 three times, hovering around the limit on the way
+    let signal = [-1.0, -9.6, -9.4, -9.7, -8.0, -2.0, -9.8, -9.2, -9.6, -8.9, -1.0, -10.0, -9.9, -3.0];
+    let lines: Vec<String> = signal
+        .iter()
+        .enumerate()
+        .map(|(i, z)| format!("UDP packet contents:{},0.1,0.2,{}", i * 10, z))
+        .collect();
+    let script = script(dir.path(), &lines);
+    let (result, file) = record(dir.path(), &script, &["--alarm", "f3 < -9.5:impact", "--alarm-clear", "f3 > -9.0"]);
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(count(&file, "data,"), signal.len());
+    let alarms: Vec<&str> = file.lines().filter(|line| line.starts_with("alarm,")).collect();
+    assert_eq!(alarms.len(), 3, "{}", file);
+    assert!(alarms.iter().all(|line| line.split(',').nth(2) == Some("impact")));
+    // Each alarm row follows the row that raised it, with its values
+    let before: Vec<&str> = file
+        .lines()
+        .zip(file.lines().skip(1))
+        .filter(|(_, next)| next.starts_with("alarm,"))
+        .map(|(row, _)| row.rsplit(',').next().unwrap())
+        .collect();
+    assert_eq!(before, ["-9.6", "-9.8", "-10"]);
+
+    // Without --alarm-clear every return above the limit clears the alarm
+    let dir = tempfile::tempdir().unwrap();
+    let script = self::script(dir.path(), &lines);
+    let (_, file) = record(dir.path(), &script, &["--alarm", "f3 < -9.5:impact"]);
+    assert_eq!(count(&file, "alarm,"), 5, "{}", file);
+}
+
+#[test]
+fn rows_from_before_the_start_come_first_with_their_own_timestamps() {
+    let dir = tempfile::tempdir().unwrap();
+    let send = |x: u32| serde_json::json!({ "send": format!("UDP packet contents:{},1,2,3", x) });
+    let script = write_script(
+        dir.path(),
+        vec![
+            serde_json::json!({"pause_ms": 1000}),
+            send(1),
+            send(2),
+            send(3),
+            // Started at 2.5s
+            serde_json::json!({"pause_ms": 2500}),
+            send(4),
+            send(5),
+            // Stopped at 4.3s and started again at 4.6s with nothing between
+            serde_json::json!({"pause_ms": 2000}),
+        ],
+    );
+    let commands = [(2500, "start"), (4300, "stop"), (4600, "start")];
+    let (result, file) = session(dir.path(), &script, &["--prebuffer", "2"], &commands);
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    let rows: Vec<Vec<&str>> = file
+        .lines()
+        .map(|line| line.split(',').collect::<Vec<&str>>())
+        .filter(|cells| ["pre", "data", "start", "stop"].contains(&cells[0]))
+        .collect();
+    let kinds: Vec<String> = rows.iter().map(|cells| format!("{} {}", cells[0], cells[4])).collect();
+    // The last two rows before the start, then the live ones; none are kept
+    // for the second run
+    assert_eq!(kinds, ["start ", "pre 2", "pre 3", "data 4", "data 5", "stop ", "start ", "stop "], "{}", file);
+    // Sent a second and a half before the start, so stamped with an earlier
+    // second
+    let (pre, start) = (rows[1][1], rows[0][1]);
+    assert!(pre < start, "pre row at {}, start at {}", pre, start);
+    assert_eq!(rows[2][1], pre);
+}
+
+#[test]
+fn the_input_is_mirrored_to_a_pseudo_terminal() {
+    let dir = tempfile::tempdir().unwrap();
+    let lines: Vec<String> = (0..20).map(|i| format!("UDP packet contents:{},1,2,3", i)).collect();
+    let script = script(dir.path(), &lines);
+    let mirror = dir.path().join("mirror");
+    // Another program on the mirror, reading until the logger closes it
+    let reader = {
+        let mirror = mirror.clone();
+        thread::spawn(move || {
+            wait_for("the mirror", Duration::from_secs(5), || mirror.exists());
+            let mut terminal = std::fs::File::open(&mirror).unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0; 4096];
+            while let Ok(n @ 1..) = std::io::Read::read(&mut terminal, &mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            received
+        })
+    };
+    let (result, file) = record(dir.path(), &script, &["--pty-mirror", mirror.to_str().unwrap()]);
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(count(&file, "data,"), 20);
+    // Byte for byte what the device sent
+    let expected: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    assert_eq!(String::from_utf8(reader.join().unwrap()).unwrap(), expected);
+    // The link goes at exit
+    assert!(std::fs::symlink_metadata(&mirror).is_err());
+}
+
+#[test]
+fn the_start_row_is_in_the_file_before_the_next_data_row() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut steps = vec![serde_json::json!({"pause_ms": 1000})];
+    for i in 0..30 {
+        steps.push(serde_json::json!({ "send": format!("UDP packet contents:{},1,2,3", i) }));
+        steps.push(serde_json::json!({"pause_ms": 100}));
+    }
+    steps.push(serde_json::json!({"pause_ms": 500}));
+    let script = write_script(dir.path(), steps);
+    // Like tail -f, every change of the file while the session runs
+    let output = dir.path().join("out.csv");
+    let done = Arc::new(AtomicBool::new(false));
+    let tail = {
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut seen: Vec<String> = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                let text = std::fs::read_to_string(&output).unwrap_or_default();
+                if seen.last() != Some(&text) {
+                    seen.push(text);
+                }
+                thread::sleep(Duration::from_millis(5));
+            }
+            seen
+        })
+    };
+    // Synced only every minute, but the start row doesn't wait for that
+    let (result, file) = session(dir.path(), &script, &["--sync", "interval:60"], &[(2000, "start")]);
+    done.store(true, Ordering::Relaxed);
+    let seen = tail.join().unwrap();
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(count(&file, "data,") > 10, "{}", file);
+    let first = seen.iter().find(|text| count(text, "start,") == 1).expect("the start row was never seen");
+    // Seen with at most the one data row written after it
+    assert!(count(first, "data,") <= 1, "{}", first);
+}
+
+#[test]
+fn injected_faults_are_reported_with_the_line_they_hit() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = write_script(
+        dir.path(),
+        vec![
+            serde_json::json!({"pause_ms": 1000}),
+            serde_json::json!({"data": {"count": 80, "rate_hz": 20}}),
+            serde_json::json!({"pause_ms": 500}),
+        ],
+    );
+    let mut mock = start_mock(&script, &dir.path().join("dev"), &["--faults", "disconnect@1500ms:2s,dup@4s:2"]);
+    let (result, file) = run_logger(dir.path(), &mut mock, &[], &[(0, "start")]);
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    let events: Vec<serde_json::Value> = String::from_utf8(mock.output().stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let at = |fault: &str| {
+        let event = events.iter().find(|event| event["fault"] == fault).unwrap();
+        assert_eq!(event["event"], "fault_injected");
+        event["seq"].as_u64().unwrap()
+    };
+    let (disconnect, dup) = (at("disconnect"), at("dup"));
+    assert_eq!(events.len(), 2);
+
+    let rows: Vec<&str> = file.lines().filter(|line| line.starts_with("data,") || line.starts_with("reconnect,")).collect();
+    let seq = |row: &str| row.split(',').nth(4).unwrap().parse::<u64>().unwrap();
+    // Everything up to the disconnect, then the reconnection, then lines from
+    // after it; about 40 at 20 a second were lost while the device was away
+    let reconnect = rows.iter().position(|row| row.starts_with("reconnect,")).expect("no reconnect row");
+    let before: Vec<u64> = rows[..reconnect].iter().map(|row| seq(row)).collect();
+    assert_eq!(before, (0..disconnect).collect::<Vec<u64>>(), "{}", file);
+    let after: Vec<u64> = rows[reconnect + 1..].iter().map(|row| seq(row)).collect();
+    assert!(after[0] >= disconnect + 30, "{}", file);
+    assert_eq!(after.last(), Some(&79));
+    // The two lines from the dup twice each
+    for n in 0..80 {
+        let times = after.iter().filter(|seq| **seq == n).count();
+        let expected = usize::from(n >= after[0]) * if n == dup || n == dup + 1 { 2 } else { 1 };
+        assert_eq!(times, expected, "line {} in {}", n, file);
+    }
+}
+
+// The logger on the mock's terminal reading `commands` from stdin, which
+// then ends; with none it is /dev/null, as under systemd or nohup
+fn with_commands(dir: &Path, args: &[&str], commands: &str) -> Reaper {
+    let mut logger = start_logger(dir, args);
+    let mut stdin = logger.child().stdin.take().unwrap();
+    stdin.write_all(commands.as_bytes()).unwrap();
+    logger
+}
+
+// The logger on the mock's terminal with its stdin, stdout and stderr piped
+fn start_logger(dir: &Path, args: &[&str]) -> Reaper {
+    let port = ["-p", dir.join("dev").to_str().unwrap()].map(str::to_string);
+    spawn_logger(dir, &[&port.each_ref().map(String::as_str), args].concat())
+}
+
+// The logger writing to out.csv with `args`, and its stdin, stdout and stderr piped
+fn spawn_logger(dir: &Path, args: &[&str]) -> Reaper {
+    let logger = Command::new(env!("CARGO_BIN_EXE_collect_acc_data"))
+        .arg("-o")
+        .arg(dir.join("out.csv"))
+        .args(["--no-raw", "--no-state-file", "--no-statusline"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    Reaper(Some(logger))
+}
+
+fn steady_data(dir: &Path) -> PathBuf {
+    write_script(dir, vec![serde_json::json!({"data": {"count": 1200, "rate_hz": 20}})])
+}
+
+#[test]
+fn closed_stdin_with_nothing_to_start_a_run_exits_at_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let _mock = start_mock(&steady_data(dir.path()), &dir.path().join("dev"), &[]);
+    let mut logger = with_commands(dir.path(), &[], "");
+    wait_for("the logger to exit", Duration::from_secs(10), || logger.child().try_wait().unwrap().is_some());
+    let result = logger.output();
+    let (stdout, stderr) = (String::from_utf8_lossy(&result.stdout), String::from_utf8_lossy(&result.stderr));
+    assert!(stderr.contains("Input ended without 'exit' and nothing else can start a run"), "{}", stderr);
+    assert!(stderr.contains("--auto-start"));
+    // No empty commands read from it, and no prompt shown to nobody
+    assert!(!stderr.contains("Unknown command") && !stdout.contains("Unknown command"), "{}{}", stdout, stderr);
+    assert!(!stdout.contains("idle"), "{}", stdout);
+}
+
+// Seconds of CPU `pid` has used so far
+#[cfg(target_os = "linux")]
+fn cpu_time(pid: u32) -> f64 {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+    // After the command name, which may hold spaces, utime and stime are the
+    // 12th and 13th fields
+    let fields: Vec<&str> = stat.rsplit_once(')').unwrap().1.split_whitespace().collect();
+    let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+    ticks as f64 / unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn stdin_ending_while_recording_logs_on_quietly_until_a_signal() {
+    let dir = tempfile::tempdir().unwrap();
+    let _mock = start_mock(&steady_data(dir.path()), &dir.path().join("dev"), &[]);
+    let mut logger = with_commands(dir.path(), &[], "start\n");
+    let output = dir.path().join("out.csv");
+    wait_for("rows", Duration::from_secs(10), || {
+        count(&std::fs::read_to_string(&output).unwrap_or_default(), "data,") >= 10
+    });
+    // Waiting for a signal rather than spinning on stdin
+    let pid = logger.child().id();
+    let before = cpu_time(pid);
+    thread::sleep(Duration::from_secs(2));
+    let used = cpu_time(pid) - before;
+    assert!(used < 0.4, "{}s of CPU in 2s", used);
+    assert!(logger.child().try_wait().unwrap().is_none(), "the logger exited");
+
+    unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+    wait_for("the logger to exit", Duration::from_secs(10), || logger.child().try_wait().unwrap().is_some());
+    let result = logger.output();
+    let (stdout, stderr) = (String::from_utf8_lossy(&result.stdout), String::from_utf8_lossy(&result.stderr));
+    assert_eq!(result.status.code(), Some(0), "{}", stderr);
+    assert!(stdout.contains("Input ended; logging on without a prompt until stopped by a signal."), "{}", stdout);
+    assert!(!stderr.contains("Unknown command") && !stdout.contains("Unknown command"), "{}{}", stdout, stderr);
+    // The run was closed properly
+    let file = std::fs::read_to_string(&output).unwrap();
+    assert_eq!(count(&file, "start,"), 1);
+    assert_eq!(count(&file, "stop,"), 1, "{}", file);
+}
+
+#[test]
+fn exit_is_not_held_up_by_a_long_read_timeout_on_a_silent_port() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = write_script(dir.path(), vec![serde_json::json!({"pause_ms": 30000})]);
+    let _mock = start_mock(&script, &dir.path().join("dev"), &[]);
+    let mut logger = start_logger(dir.path(), &["--read-timeout", "5000"]);
+    let mut stdin = logger.child().stdin.take().unwrap();
+    writeln!(stdin, "start").unwrap();
+    let output = dir.path().join("out.csv");
+    wait_for("the start row", Duration::from_secs(5), || {
+        count(&std::fs::read_to_string(&output).unwrap_or_default(), "start,") == 1
+    });
+    // Well into a read of the silent port
+    thread::sleep(Duration::from_millis(700));
+    let asked = Instant::now();
+    writeln!(stdin, "exit").unwrap();
+    wait_for("the logger to exit", Duration::from_secs(5), || logger.child().try_wait().unwrap().is_some());
+    let took = asked.elapsed();
+    let result = logger.output();
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(took < Duration::from_millis(250), "exit took {:?}", took);
+    assert_eq!(count(&std::fs::read_to_string(&output).unwrap(), "stop,"), 1);
+}
+
+#[test]
+fn read_timeouts_out_of_range_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    for timeout in ["9", "10001", "soon"] {
+        let result = Command::new(env!("CARGO_BIN_EXE_collect_acc_data"))
+            .arg("-p")
+            .arg(dir.path().join("dev"))
+            .arg("-o")
+            .arg(dir.path().join("out.csv"))
+            .args(["--read-timeout", timeout])
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert_ne!(result.status.code(), Some(0));
+        assert!(String::from_utf8_lossy(&result.stderr).contains("--read-timeout"), "{}", timeout);
+    }
+}
+
+#[test]
+fn unchanged_rows_are_counted_apart_from_rejects() {
+    let dir = tempfile::tempdir().unwrap();
+    let lines: Vec<String> = ["0,1.00,7,OK", "1,1.004,7,OK", "2,0.9999999999999999,7,OK", "3,1.01,7,OK", "4,1.011,7,OK"]
+        .into_iter()
+        .chain(["5,1.0200000000000001,7,OK", "6,1.5", "7,1.03,7,OK", "8,1.03,7,FAULT", "8,1.03,7,FAULT"])
+        .map(|payload| format!("UDP packet contents:{}", payload))
+        .collect();
+    let script = script(dir.path(), &lines);
+    let summary = dir.path().join("summary.json");
+    let args = [
+        "--log-on-change",
+        "1,3",
+        "--change-epsilon",
+        "0.01",
+        "--dedup-consecutive",
+        "--summary-file",
+        summary.to_str().unwrap(),
+    ];
+    let (result, file) = record(dir.path(), &script, &args);
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    let written: Vec<&str> = file
+        .lines()
+        .filter(|line| line.starts_with("data,"))
+        .map(|line| line.split(',').nth(4).unwrap())
+        .collect();
+    assert_eq!(written, ["0", "4", "7", "8"], "{}", file);
+    let summary: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&summary).unwrap()).unwrap();
+    assert_eq!(summary["unchanged"], 4);
+    assert_eq!(summary["duplicates"], 1);
+    assert_eq!(summary["parse_failures"], 1);
+    assert_eq!(summary["total_rows"], 4);
+}
+
+// Sequence numbers of the data rows from `port` in a file of several ports,
+// in the order they were written
+fn port_sequence(file: &str, port: &Path) -> Vec<u64> {
+    let port = port.to_str().unwrap();
+    file.lines()
+        .filter(|line| line.starts_with("data,") && line.split(',').nth(4) == Some(port))
+        .map(|line| line.split(',').nth(5).unwrap().parse().unwrap())
+        .collect()
+}
+
+// The logger reading `links`, the first being the usual `dev`, recording
+// from the start until every mock has ended; what it printed and the file
+fn record_ports(dir: &Path, links: &[PathBuf], mocks: &mut [Reaper], args: &[&str]) -> (Output, String) {
+    let mut given: Vec<&str> = links[1..].iter().flat_map(|link| ["-p", link.to_str().unwrap()]).collect();
+    given.extend(args);
+    record_until_done(dir, start_logger(dir, &given), mocks)
+}
+
+// Records with `logger` from the start until every mock has ended
+fn record_until_done(dir: &Path, mut logger: Reaper, mocks: &mut [Reaper]) -> (Output, String) {
+    let mut stdin = logger.child().stdin.take().unwrap();
+    writeln!(stdin, "start").unwrap();
+    for mock in mocks {
+        wait_for("the end of the script", Duration::from_secs(60), || mock.child().try_wait().unwrap().is_some());
+    }
+    writeln!(stdin, "exit").unwrap();
+    drop(stdin);
+    let result = logger.output();
+    (result, std::fs::read_to_string(dir.join("out.csv")).unwrap_or_default())
+}
+
+fn links(dir: &Path, count: usize) -> Vec<PathBuf> {
+    (0..count).map(|i| dir.join(if i == 0 { "dev".to_string() } else { format!("dev{}", i) })).collect()
+}
+
+#[test]
+fn eight_ports_at_1_khz_are_logged_without_a_loss() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = write_script(
+        dir.path(),
+        vec![
+            serde_json::json!({"pause_ms": 2000}),
+            serde_json::json!({"data": {"count": 2000, "rate_hz": 1000}}),
+            serde_json::json!({"pause_ms": 500}),
+        ],
+    );
+    let links = links(dir.path(), 8);
+    let mut mocks: Vec<Reaper> = links.iter().map(|link| start_mock(&script, link, &[])).collect();
+    let summary = dir.path().join("summary.json");
+    let (result, file) = record_ports(dir.path(), &links, &mut mocks, &["--summary-file", summary.to_str().unwrap()]);
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    let header = file.lines().find(|line| line.starts_with("Type,")).unwrap();
+    assert!(header.starts_with("Type,Timestamp,Run/End,RunId,Port,"), "{}", header);
+    for link in &links {
+        assert_eq!(port_sequence(&file, link), (0..2000).collect::<Vec<u64>>(), "{}", link.display());
+    }
+    let summary: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&summary).unwrap()).unwrap();
+    assert_eq!(summary["total_rows"], 16000);
+}
+
+#[test]
+fn one_of_several_ports_is_reopened_while_the_others_go_on() {
+    let dir = tempfile::tempdir().unwrap();
+    let links = links(dir.path(), 2);
+    let steady = write_script(
+        dir.path(),
+        vec![serde_json::json!({"pause_ms": 1000}), serde_json::json!({"data": {"count": 300, "rate_hz": 50}})],
+    );
+    let mut mocks = vec![start_mock(&example(), &links[0], &[]), start_mock(&steady, &links[1], &[])];
+    let (result, file) = record_ports(dir.path(), &links, &mut mocks, &[]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(0), "{}", stderr);
+    assert_eq!(port_sequence(&file, &links[0]), (0..400).collect::<Vec<u64>>());
+    assert_eq!(port_sequence(&file, &links[1]), (0..300).collect::<Vec<u64>>());
+    let reconnects: Vec<&str> = file.lines().filter(|line| line.starts_with("reconnect,")).collect();
+    assert_eq!(reconnects.len(), 1, "{}", file);
+    assert!(reconnects[0].contains(links[0].to_str().unwrap()), "{}", reconnects[0]);
+}
+
+#[test]
+fn single_port_options_are_refused_with_several_ports() {
+    let dir = tempfile::tempdir().unwrap();
+    let other = dir.path().join("dev1");
+    let result = Command::new(env!("CARGO_BIN_EXE_collect_acc_data"))
+        .args(["-p", "/dev/null", "-p", other.to_str().unwrap(), "--failback", "--alarm", "f1 > 3"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_ne!(result.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("--failback works with a single --port, not while reading 2 ports"), "{}", stderr);
+    assert!(stderr.contains("--alarm works with a single --port"), "{}", stderr);
+}
+
+#[test]
+fn ordered_rows_of_several_ports_have_timestamps_that_never_go_back() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = write_script(
+        dir.path(),
+        vec![
+            serde_json::json!({"pause_ms": 2000}),
+            serde_json::json!({"data": {"count": 1500, "rate_hz": 500}}),
+            serde_json::json!({"pause_ms": 500}),
+        ],
+    );
+    let links = links(dir.path(), 4);
+    let mut mocks: Vec<Reaper> = links.iter().map(|link| start_mock(&script, link, &[])).collect();
+    let summary = dir.path().join("summary.json");
+    let args = ["--ordered", "--summary-file", summary.to_str().unwrap()];
+    let (result, file) = record_ports(dir.path(), &links, &mut mocks, &args);
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    for link in &links {
+        assert_eq!(port_sequence(&file, link), (0..1500).collect::<Vec<u64>>(), "{}", link.display());
+    }
+    let timestamps: Vec<&str> =
+        file.lines().filter(|line| line.starts_with("data,")).map(|line| line.split(',').nth(1).unwrap()).collect();
+    assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+    let summary: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&summary).unwrap()).unwrap();
+    assert_eq!(summary["total_rows"], 6000);
+    assert_eq!(summary["late_rows"], 0);
+}
+
+#[test]
+fn ports_of_config_blocks_are_read_each_with_its_own_format_into_one_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let links = links(dir.path(), 2);
+    let script = |name: &str, script: serde_json::Value| {
+        let path = dir.path().join(name);
+        std::fs::write(&path, script.to_string()).unwrap();
+        path
+    };
+    let imu = script(
+        "imu.json",
+        serde_json::json!({"match": "IMU:", "fields": 4, "steps": [{"pause_ms": 1000}, {"data": {"count": 200, "rate_hz": 200}}]}),
+    );
+    let gps = script(
+        "gps.json",
+        serde_json::json!({"match": "GPS:", "delimiter": ";", "fields": 3, "steps": [{"pause_ms": 1000}, {"data": {"count": 20, "rate_hz": 20}}]}),
+    );
+    let config = dir.path().join("logger.toml");
+    let blocks = format!(
+        "[[port]]\nport = \"{}\"\nlabel = \"imu\"\nbaud = 921600\nmatch = \"IMU:\"\nfields = 4\nnames = \"seq,ax,ay,az\"\n\n\
+         [[port]]\nport = \"{}\"\nlabel = \"gps\"\nbaud = 9600\nmatch = \"GPS:\"\ndelimiter = \";\"\nnames = \"seq,lat,lon\"\n",
+        links[0].display(),
+        links[1].display()
+    );
+    std::fs::write(&config, format!("fields = 3\n\n{}", blocks)).unwrap();
+    let mut mocks = vec![start_mock(&imu, &links[0], &[]), start_mock(&gps, &links[1], &[])];
+    let logger = spawn_logger(dir.path(), &["--config", config.to_str().unwrap()]);
+    let (result, file) = record_until_done(dir.path(), logger, &mut mocks);
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    let header = file.lines().find(|line| line.starts_with("Type,")).unwrap();
+    assert_eq!(header, "Type,Timestamp,Run/End,RunId,Port,seq,ax,ay,az,lat,lon");
+    let rows = |label: &str| -> Vec<Vec<String>> {
+        file.lines()
+            .filter(|line| line.starts_with("data,") && line.split(',').nth(4) == Some(label))
+            .map(|line| line.split(',').skip(5).map(str::to_string).collect())
+            .collect()
+    };
+    let (imu, gps) = (rows("imu"), rows("gps"));
+    assert_eq!(imu.len(), 200, "{}", file);
+    assert_eq!(gps.len(), 20, "{}", file);
+    assert_eq!(imu[7], ["7", "8", "9", "10", "", ""]);
+    // The receiver's fields under its own columns, the others left empty
+    assert_eq!(gps[7], ["7", "", "", "", "8", "9"]);
+}
@@ -0,0 +1,63 @@
+//! `--print-config`: the options in effect, only for options that work
+//! together.
+
+use std::process::{Command, Output};
+
+fn print_config(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_collect_acc_data"))
+        .arg("--print-config")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn config_file_and_command_line_are_merged() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("logger.toml");
+    std::fs::write(&config, "port = \"/dev/ttyUSB0\"\nbaud = 115200\nnames = \"t,x,y,z\"\n").unwrap();
+    let output = print_config(&["--config", config.to_str().unwrap(), "--baud", "9600"]);
+    assert_eq!(output.status.code(), Some(0));
+    let printed = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = printed.lines().collect();
+    assert!(lines.contains(&"port = \"/dev/ttyUSB0\""), "{}", printed);
+    assert!(lines.contains(&"baud = 9600"), "{}", printed);
+    assert!(lines.contains(&"names = \"t,x,y,z\""), "{}", printed);
+    assert!(!printed.contains("config ="), "{}", printed);
+    // What was printed reads back as a config file
+    std::fs::write(&config, &printed).unwrap();
+    let again = print_config(&["--config", config.to_str().unwrap()]);
+    assert_eq!(String::from_utf8(again.stdout).unwrap(), printed);
+}
+
+#[test]
+fn options_that_dont_work_together_fail() {
+    let output = print_config(&["-p", "/dev/ttyUSB0,/dev/ttyUSB0", "--per-run", "-o", "fixed.csv"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--port lists /dev/ttyUSB0 more than once"), "{}", stderr);
+    assert!(stderr.contains("--per-run needs {run} or {name}"), "{}", stderr);
+}
+
+#[test]
+fn port_blocks_whose_columns_conflict_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("logger.toml");
+    let blocks = "[[port]]\nport = \"/dev/ttyUSB0\"\nlabel = \"imu\"\ntypes = \"f64,f64\"\n\n\
+                  [[port]]\nport = \"/dev/ttyUSB1\"\nlabel = \"gps\"\n";
+    std::fs::write(&config, format!("fields = 2\n{}types = \"str,f64\"\n", blocks)).unwrap();
+    let output = print_config(&["--config", config.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Conflicting schemas: column field 1 is f64 on imu but str on gps"), "{}", stderr);
+    // With their own names the columns don't meet
+    std::fs::write(&config, format!("fields = 2\n{}types = \"str,f64\"\nnames = \"fix,lat\"\n", blocks)).unwrap();
+    let output = print_config(&["--config", config.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let printed = String::from_utf8(output.stdout).unwrap();
+    assert!(printed.ends_with("[[port]]\nport = \"/dev/ttyUSB1\"\nlabel = \"gps\"\ntypes = \"str,f64\"\nnames = \"fix,lat\"\n"), "{}", printed);
+    // The blocks give the ports, not a top-level entry
+    let top_level = printed.split("[[port]]").next().unwrap();
+    assert!(!top_level.lines().any(|line| line.starts_with("port =")), "{}", printed);
+}
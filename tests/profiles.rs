@@ -0,0 +1,96 @@
+//! `--profile` and `--list-profiles` with a `profiles.toml` in the user's
+//! config directory.
+#![cfg(target_os = "linux")]
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+const PROFILES: &str = "[imu-v2]
+description = \"IMU v2 board, 6 axes at 100 Hz\"
+baud = 230400
+match = \"IMU:\"
+fields = 6
+names = \"ax,ay,az,gx,gy,gz\"
+
+[gps]
+description = \"GPS puck\"
+baud = 4800
+";
+
+// The logger with `config_home` as its config directory
+fn logger(config_home: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_collect_acc_data"))
+        .env("XDG_CONFIG_HOME", config_home)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn config_home() -> tempfile::TempDir {
+    let home = tempfile::tempdir().unwrap();
+    std::fs::create_dir(home.path().join("serial_logger")).unwrap();
+    std::fs::write(home.path().join("serial_logger/profiles.toml"), PROFILES).unwrap();
+    home
+}
+
+fn printed(output: Output) -> Vec<String> {
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap().lines().map(str::to_string).collect()
+}
+
+#[test]
+fn the_profile_comes_first_then_the_config_file_then_the_command_line() {
+    let home = config_home();
+    let config = home.path().join("logger.toml");
+    std::fs::write(&config, "names = \"x,y,z,a,b,c\"\n").unwrap();
+    let lines = printed(logger(
+        home.path(),
+        &["--print-config", "-p", "/dev/ttyUSB0", "--profile", "imu-v2", "--config", config.to_str().unwrap(), "--baud", "9600"],
+    ));
+    assert!(lines.contains(&"baud = 9600".to_string()), "{:?}", lines);
+    assert!(lines.contains(&"names = \"x,y,z,a,b,c\"".to_string()), "{:?}", lines);
+    assert!(lines.contains(&"match = \"IMU:\"".to_string()), "{:?}", lines);
+}
+
+#[test]
+fn the_config_file_can_choose_the_profile() {
+    let home = config_home();
+    let config = home.path().join("logger.toml");
+    std::fs::write(&config, "profile = \"gps\"\n").unwrap();
+    let lines = printed(logger(home.path(), &["--print-config", "-p", "/dev/ttyUSB0", "--config", config.to_str().unwrap()]));
+    assert!(lines.contains(&"baud = 4800".to_string()), "{:?}", lines);
+    // The command line still chooses over the config file
+    let lines = printed(logger(
+        home.path(),
+        &["--print-config", "-p", "/dev/ttyUSB0", "--config", config.to_str().unwrap(), "--profile", "imu-v2"],
+    ));
+    assert!(lines.contains(&"baud = 230400".to_string()), "{:?}", lines);
+}
+
+#[test]
+fn profiles_are_listed_with_their_descriptions() {
+    let home = config_home();
+    let lines = printed(logger(home.path(), &["--list-profiles"]));
+    assert_eq!(
+        lines,
+        [
+            format!("{}:", home.path().join("serial_logger/profiles.toml").display()),
+            "  imu-v2  IMU v2 board, 6 axes at 100 Hz".to_string(),
+            "  gps     GPS puck".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn an_unknown_profile_names_the_known_ones() {
+    let home = config_home();
+    let output = logger(home.path(), &["-p", "/dev/ttyUSB0", "--profile", "imu-v3"]);
+    assert_ne!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unknown --profile 'imu-v3': expected one of imu-v2, gps"), "{}", stderr);
+    // Without any profiles.toml
+    let empty = tempfile::tempdir().unwrap();
+    let output = logger(empty.path(), &["-p", "/dev/ttyUSB0", "--profile", "imu-v2"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unknown --profile 'imu-v2': no profiles.toml was found in"), "{}", stderr);
+}